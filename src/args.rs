@@ -1,16 +1,31 @@
 use clap::Parser;
 use clap::ValueEnum;
+use ipnet::{IpNet, Ipv4Net};
 use std::fmt::Display;
-use std::net::IpAddr;
+use std::net::{IpAddr, SocketAddr};
 use std::path::PathBuf;
 use std::str::FromStr;
 use strum::IntoEnumIterator;
-use vopono_core::config::providers::VpnProvider;
+use vopono_core::config::providers::{ServerFeature, VpnProvider};
 use vopono_core::config::vpn::Protocol;
+use vopono_core::network::dns_rules::{DnsForward, DnsOverride};
+use vopono_core::network::egress::EgressRule;
 use vopono_core::network::firewall::Firewall;
 use vopono_core::network::network_interface::NetworkInterface;
+use vopono_core::network::obfs4::Obfs4Bridge;
+use vopono_core::network::obfuscation::ObfuscationProtocol;
+use vopono_core::network::openvpn::OpenVpnOption;
+use vopono_core::network::port_publish::PublishedPort;
+use vopono_core::network::rate_limit::RateLimit;
+use vopono_core::network::rotation::RotationInterval;
+use vopono_core::network::stub_resolver::DnsUpstream;
 use vopono_core::network::trojan::TrojanHost;
+use vopono_core::network::upstream_proxy::UpstreamProxy;
+use vopono_core::network::watchdog::WatchdogAction;
+use vopono_core::network::wireguard::WireguardImplementation;
 use vopono_core::util::hostname_to_ip;
+use vopono_core::util::parallel::DEFAULT_SYNC_JOBS;
+use vopono_core::util::server_cache::DEFAULT_SERVER_LIST_TTL;
 
 #[derive(Clone)]
 pub struct WrappedArg<T: IntoEnumIterator + Clone + Display> {
@@ -78,10 +93,21 @@ pub struct App {
     #[clap(short = 'A', long = "askpass")]
     pub askpass: bool,
 
+    /// Format of the log output on stderr, json writes newline-delimited JSON events and log
+    /// messages
+    #[clap(long = "log-format", value_enum, default_value = "text", global = true)]
+    pub log_format: LogFormat,
+
     #[clap(subcommand)]
     pub cmd: Command,
 }
 
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum LogFormat {
+    Text,
+    Json,
+}
+
 #[derive(Parser)]
 pub enum Command {
     #[clap(
@@ -104,6 +130,61 @@ pub enum Command {
         about = "List possible server configs for VPN provider, beginning with prefix"
     )]
     Servers(ServersCommand),
+    #[clap(
+        name = "attach",
+        about = "Execute an application in an existing vopono network namespace"
+    )]
+    Attach(AttachCommand),
+    #[clap(
+        name = "cleanup",
+        about = "Remove namespaces and firewall rules left behind by vopono instances which died"
+    )]
+    Cleanup(CleanupCommand),
+    #[clap(
+        name = "dnsleak",
+        about = "Test a running vopono namespace for DNS queries bypassing the tunnel"
+    )]
+    DnsLeak(DnsLeakCommand),
+    #[clap(
+        name = "status",
+        about = "Show the connection state of running vopono namespaces"
+    )]
+    Status(StatusCommand),
+    #[clap(
+        name = "stop",
+        about = "Stop the applications in a running vopono namespace and tear it down"
+    )]
+    Stop(StopCommand),
+    #[clap(
+        name = "tui",
+        about = "Choose a VPN provider and server interactively and run an application with it"
+    )]
+    Tui(TuiCommand),
+    #[clap(
+        name = "service",
+        about = "Manage systemd units running applications in vopono namespaces"
+    )]
+    Service(ServiceCommand),
+    #[clap(
+        name = "desktop",
+        about = "Add an application menu entry which launches an application through vopono"
+    )]
+    Desktop(DesktopCommand),
+    #[clap(
+        name = "setup",
+        about = "Choose a VPN provider, sync its servers and write a config file, for first use"
+    )]
+    Setup(SetupCommand),
+    #[clap(
+        name = "metrics",
+        about = "Serve Prometheus metrics of the running namespaces over HTTP"
+    )]
+    Metrics(MetricsCommand),
+    #[clap(name = "completions", about = "Print the shell completion script")]
+    Completions(CompletionsCommand),
+    /// Completion candidates for the shell completion scripts
+    #[clap(name = "complete", hide = true)]
+    Complete(CompleteCommand),
 }
 
 #[derive(Parser)]
@@ -115,6 +196,38 @@ pub struct SynchCommand {
     /// VPN Protocol (if not given will try to sync both)
     #[clap(value_enum, long = "protocol", short = 'c', ignore_case = true)]
     pub protocol: Option<WrappedArg<Protocol>>,
+
+    /// Path to external provider plugin executable (see USERGUIDE.md for the JSON protocol)
+    #[clap(long = "custom-plugin", conflicts_with = "vpn_provider")]
+    pub custom_plugin: Option<PathBuf>,
+
+    /// List Wireguard devices registered on the account (Mullvad only)
+    #[clap(long = "list-devices")]
+    pub list_devices: bool,
+
+    /// Revoke Wireguard device on the account by name or public key (Mullvad only)
+    #[clap(long = "revoke-device", conflicts_with = "list_devices")]
+    pub revoke_device: Option<String>,
+
+    /// Move plaintext credentials for the provider to the system keyring (requires secret-tool)
+    #[clap(long = "migrate-credentials", requires = "vpn_provider")]
+    pub migrate_credentials: bool,
+
+    /// Maximum number of configs to download concurrently
+    #[clap(long = "jobs", short = 'j', default_value_t = DEFAULT_SYNC_JOBS)]
+    pub jobs: usize,
+
+    /// Reuse cached provider server lists younger than this many minutes (0 to always fetch)
+    #[clap(long = "server-list-ttl", default_value_t = DEFAULT_SERVER_LIST_TTL.as_secs() / 60)]
+    pub server_list_ttl: u64,
+
+    /// Only keep configs for these countries (comma separated names or codes, e.g. us,de)
+    #[clap(long = "countries", use_value_delimiter = true)]
+    pub countries: Option<Vec<String>>,
+
+    /// Only keep configs for these cities (comma separated, e.g. nyc,losangeles)
+    #[clap(long = "cities", use_value_delimiter = true)]
+    pub cities: Option<Vec<String>>,
 }
 
 #[derive(Parser)]
@@ -135,6 +248,11 @@ pub struct ExecCommand {
     #[clap(long = "server", short = 's')]
     pub server: Option<String>,
 
+    /// Server prefixes to fall back to in order when connecting to --server fails, or when its
+    /// tunnel fails later with --reconnect or --watchdog (comma separated, e.g. se-got,no,de)
+    #[clap(long = "failover", use_value_delimiter = true)]
+    pub failover: Option<Vec<String>>,
+
     /// Application to run (should be on PATH or full path to binary)
     pub application: String,
 
@@ -158,10 +276,85 @@ pub struct ExecCommand {
     #[clap(long = "dns", short = 'd')]
     pub dns: Option<Vec<IpAddr>>,
 
+    /// Resolve DNS with a stub resolver in the network namespace, forwarding to these
+    /// DNS-over-TLS servers given as IP[:port][#server name] (e.g. 9.9.9.9#dns.quad9.net)
+    #[clap(
+        long = "dns-over-tls",
+        use_value_delimiter = true,
+        conflicts_with = "dns"
+    )]
+    pub dns_over_tls: Option<Vec<DnsUpstream>>,
+
+    /// Resolve DNS with a stub resolver in the network namespace, forwarding to these
+    /// DNS-over-HTTPS servers given as URLs, optionally with #IP to skip resolving the server
+    /// name (e.g. https://dns.quad9.net/dns-query#9.9.9.9). Used after any --dns-over-tls servers
+    #[clap(
+        long = "dns-over-https",
+        use_value_delimiter = true,
+        conflicts_with = "dns"
+    )]
+    pub dns_over_https: Option<Vec<DnsUpstream>>,
+
+    /// Resolve DNS with a caching resolver in the network namespace, forwarding to the VPN DNS
+    /// servers (or those given with --dns). Implied by the encrypted DNS, override and forward
+    /// options
+    #[clap(long = "dns-cache")]
+    pub dns_cache: bool,
+
+    /// Fixed addresses for host names in the namespace resolver, given as name=IP (e.g.
+    /// nas.home=192.168.1.5)
+    #[clap(long = "dns-override", use_value_delimiter = true)]
+    pub dns_overrides: Option<Vec<DnsOverride>>,
+
+    /// Send queries for these domains and their subdomains to the given plain DNS server, as
+    /// domain=IP[:port] (e.g. corp.example=10.0.0.53)
+    #[clap(long = "dns-forward", use_value_delimiter = true)]
+    pub dns_forwards: Option<Vec<DnsForward>>,
+
+    /// Resolve these domains and their subdomains (e.g. corp.example,local) with the DNS
+    /// resolver of the host instead of the VPN DNS, so internal names keep resolving in the
+    /// namespace
+    #[clap(long = "split-dns", use_value_delimiter = true)]
+    pub split_dns: Option<Vec<String>>,
+
+    /// Run dnscrypt-proxy in the network namespace and resolve DNS with it
+    #[clap(
+        long = "dnscrypt-proxy",
+        conflicts_with_all = [
+            "dns",
+            "dns_over_tls",
+            "dns_over_https",
+            "dns_cache",
+            "dns_overrides",
+            "dns_forwards",
+            "split_dns"
+        ]
+    )]
+    pub dnscrypt_proxy: bool,
+
+    /// Resolvers for dnscrypt-proxy to use by name from the public resolver list (e.g.
+    /// quad9-dnscrypt-ip4-nofilter-pri), the fastest no-log and non-filtering ones by default
+    #[clap(
+        long = "dnscrypt-servers",
+        use_value_delimiter = true,
+        requires = "dnscrypt_proxy"
+    )]
+    pub dnscrypt_servers: Option<Vec<String>>,
+
+    /// Keep the host's systemd-resolved resolv.conf in the network namespace instead of
+    /// mounting one over it: the namespace resolver answers on 127.0.0.53 and the veth
+    /// interface is registered with systemd-resolved
+    #[clap(long = "systemd-resolved", conflicts_with = "dnscrypt_proxy")]
+    pub systemd_resolved: bool,
+
     /// List of /etc/hosts entries for the network namespace (e.g. "10.0.1.10 webdav.server01.lan","10.0.1.10 vaultwarden.server01.lan"). For a local host you should also provide the open-hosts option.
     #[clap(long = "hosts", use_value_delimiter = true)]
     pub hosts: Option<Vec<String>>,
 
+    /// File of /etc/hosts entries to add for the network namespace, after any --hosts entries
+    #[clap(long = "hosts-file")]
+    pub hosts_file: Option<PathBuf>,
+
     /// List of hostnames or IP addresses to open on the network namespace (comma separated)
     /// hostnames will be resolved locally to IP addresses
     #[clap(
@@ -171,6 +364,26 @@ pub struct ExecCommand {
     )]
     pub open_hosts: Option<Vec<IpAddr>>,
 
+    /// Local subnets the network namespace may reach directly via the host instead of the
+    /// tunnel, e.g. 192.168.1.0/24 for printers and NAS access
+    #[clap(long = "allow-lan", use_value_delimiter = true)]
+    pub allow_lan: Option<Vec<IpNet>>,
+
+    /// Only allow new outbound connections from applications to these ports, given as
+    /// port[-port][/tcp|/udp] (e.g. 443/tcp,51820/udp), both protocols if none is given
+    #[clap(long = "allow-outbound", use_value_delimiter = true)]
+    pub allow_outbound: Option<Vec<EgressRule>>,
+
+    /// Run the application in its own cgroup and only allow its processes to use the tunnel
+    /// (and the open hosts and LAN subnets), even in a namespace shared with other programs
+    #[clap(long = "cgroup-firewall")]
+    pub cgroup_firewall: bool,
+
+    /// Record packets from applications which would leave the network namespace outside the
+    /// tunnel, and report them when the namespace is shut down
+    #[clap(long = "audit-leaks")]
+    pub audit_leaks: bool,
+
     /// Disable killswitch
     #[clap(long = "no-killswitch")]
     pub no_killswitch: bool,
@@ -179,6 +392,10 @@ pub struct ExecCommand {
     #[clap(long = "keep-alive", short = 'k')]
     pub keep_alive: bool,
 
+    /// Run in the background once the namespace is up, keeping it alive until vopono stop
+    #[clap(long = "detach")]
+    pub detach: bool,
+
     /// List of ports to open on network namespace (to allow port forwarding through the tunnel,
     /// e.g. for BitTorrent, etc.)
     #[clap(long = "open-ports", short = 'o')]
@@ -192,14 +409,57 @@ pub struct ExecCommand {
     #[clap(long = "no-proxy")]
     pub no_proxy: bool,
 
+    /// Publish ports from the network namespace on the host and LAN with DNAT rules, given as
+    /// [host_ip:]host_port:namespace_port[/tcp|/udp], e.g. 8080:80
+    #[clap(long = "publish")]
+    pub publish: Option<Vec<PublishedPort>>,
+
+    /// MTU of the VPN tunnel interface, instead of the config value (and for Wireguard, the
+    /// value probed from the path to the server)
+    #[clap(long = "mtu", value_parser = clap::value_parser!(u16).range(1280..))]
+    pub mtu: Option<u16>,
+
+    /// Limit the bandwidth of the network namespace in each direction, e.g. 10mbit or 2mbps
+    #[clap(long = "rate-limit")]
+    pub rate_limit: Option<RateLimit>,
+
+    /// Relay mDNS and SSDP between the network namespace and the host LAN, so applications can
+    /// discover Chromecasts, DLNA servers and other local devices
+    #[clap(long = "relay-discovery")]
+    pub relay_discovery: bool,
+
     /// VPN Protocol (if not given will use default)
     #[clap(value_enum, long = "firewall", ignore_case = true)]
     pub firewall: Option<WrappedArg<Firewall>>,
 
-    /// Block all IPv6 traffic
+    /// Disable IPv6 on all interfaces in the network namespace and block all IPv6 traffic
     #[clap(long = "disable-ipv6")]
     pub disable_ipv6: bool,
 
+    /// Route IPv6 between the namespace and host (NAT66 on the host), so IPv6 VPN endpoints and
+    /// open hosts can be reached. IPv6 inside the tunnel works without this
+    #[clap(long = "ipv6", conflicts_with = "disable_ipv6")]
+    pub ipv6: bool,
+
+    /// /16 range to assign network namespace veth subnets from (default 10.200.0.0/16), e.g. to
+    /// avoid collisions with Docker networks or other VPNs
+    #[clap(long = "veth-subnet")]
+    pub veth_subnet: Option<Ipv4Net>,
+
+    /// Attach the network namespace to a shared host bridge (vopono_br0, 10.201.0.0/16) instead
+    /// of giving it its own veth subnet - useful when running many namespaces
+    #[clap(long = "bridge", conflicts_with = "ipv6")]
+    pub bridge: bool,
+
+    /// Allow traffic between namespaces on the bridge through the namespace firewall
+    #[clap(long = "bridge-peers", requires = "bridge")]
+    pub bridge_peers: bool,
+
+    /// Path or alias to executable PreUp script or binary for commands to run on the host after
+    /// creating the namespace and before starting the VPN (vopono aborts if it fails)
+    #[clap(long = "preup")]
+    pub preup: Option<String>,
+
     /// Path or alias to executable PostUp script or binary for commands to run on the host after
     /// bringing up the namespace
     #[clap(long = "postup")]
@@ -210,15 +470,35 @@ pub struct ExecCommand {
     #[clap(long = "predown")]
     pub predown: Option<String>,
 
+    /// Path or alias to executable PostDown script or binary for commands to run on the host after
+    /// the namespace has been deleted
+    #[clap(long = "postdown")]
+    pub postdown: Option<String>,
+
     /// Path to vopono config TOML file (will be created if it does not exist)
     /// Default: ~/.config/vopono/config.toml
     #[clap(long = "vopono-config")]
     pub vopono_config: Option<PathBuf>,
 
+    /// Profile from the [profile.<name>] section of the vopono config file, its settings override
+    /// the top-level ones
+    #[clap(long = "profile")]
+    pub profile: Option<String>,
+
     /// Custom name for the generated network namespace
     /// Will use this network namespace directly if it exists
     #[clap(long = "custom-netns-name")]
     pub custom_netns_name: Option<String>,
+
+    /// Name of the host end of the veth pair (default: {namespace}_d), at most 15 characters
+    #[clap(long = "veth-host-name")]
+    pub veth_host_name: Option<String>,
+
+    /// Name of the network namespace end of the veth pair (default: {namespace}_s), at most 15
+    /// characters
+    #[clap(long = "veth-netns-name")]
+    pub veth_netns_name: Option<String>,
+
     /// Allow access to host from network namespace
     /// Useful for accessing services on the host locally
     #[clap(long = "allow-host-access")]
@@ -241,6 +521,11 @@ pub struct ExecCommand {
     #[clap(long = "create-netns-only")]
     pub create_netns_only: bool,
 
+    /// Print the selected server and the namespace, firewall, DNS and VPN commands vopono would
+    /// run, without running them
+    #[clap(long = "dry-run")]
+    pub dry_run: bool,
+
     /// Trojan server address - hostname or IP, will not verify SSL if IP address is given.
     /// Port is optional (default is 443).
     #[clap(long = "trojan-host")]
@@ -259,6 +544,228 @@ pub struct ExecCommand {
     /// Trojan config file (will override other settings)
     #[clap(long = "trojan-config")]
     pub trojan_config: Option<PathBuf>,
+
+    /// Entry server prefix for multihop - the server given by --server is used as the exit
+    /// (Mullvad and iVPN Wireguard only)
+    #[clap(long = "entry-server")]
+    pub entry_server: Option<String>,
+
+    /// Only use servers owned by the VPN provider, not rented (Mullvad Wireguard only)
+    #[clap(long = "owned-only")]
+    pub owned_only: bool,
+
+    /// Only use diskless servers running from RAM (Mullvad Wireguard only)
+    #[clap(long = "ram-only")]
+    pub ram_only: bool,
+
+    /// Only use servers supporting DAITA traffic analysis protection (Mullvad Wireguard only)
+    #[clap(long = "daita")]
+    pub daita: bool,
+
+    /// Negotiate a post-quantum PresharedKey after connecting (Mullvad Wireguard only)
+    #[clap(long = "quantum-resistant")]
+    pub quantum_resistant: bool,
+
+    /// Only use Secure Core servers, routing via a Secure Core country first (ProtonVPN only)
+    #[clap(long = "secure-core")]
+    pub secure_core: bool,
+
+    /// Pick a random server starting with --server (if given) and matching the server filters,
+    /// other than the one picked last time
+    #[clap(long = "random-server")]
+    pub random_server: bool,
+
+    /// Only use servers in these countries (comma separated names or codes, e.g. us,de)
+    #[clap(long = "countries", use_value_delimiter = true)]
+    pub countries: Option<Vec<String>>,
+
+    /// Only use servers in these cities (comma separated, e.g. nyc,losangeles)
+    #[clap(long = "cities", use_value_delimiter = true)]
+    pub cities: Option<Vec<String>>,
+
+    /// Only use servers with all of these features recorded at sync (comma separated, e.g.
+    /// PortForwarding,Owned)
+    #[clap(
+        value_enum,
+        long = "features",
+        use_value_delimiter = true,
+        ignore_case = true
+    )]
+    pub features: Option<Vec<WrappedArg<ServerFeature>>>,
+
+    /// Only use servers with at most this load in percent (ProtonVPN publishes server load)
+    #[clap(long = "max-load", value_parser = clap::value_parser!(u8).range(0..=100))]
+    pub max_load: Option<u8>,
+
+    /// Only use the least loaded of the matching servers
+    #[clap(long = "prefer-low-load")]
+    pub prefer_low_load: bool,
+
+    /// Verify access to the Mullvad SOCKS5 proxy inside the tunnel and export it to the
+    /// application as VOPONO_SOCKS5_PROXY (Mullvad only)
+    #[clap(long = "mullvad-socks")]
+    pub mullvad_socks: bool,
+
+    /// Register a freshly generated Wireguard keypair for this connection instead of the
+    /// key saved at sync time (PrivateInternetAccess Wireguard only)
+    #[clap(long = "ephemeral-key")]
+    pub ephemeral_key: bool,
+
+    /// Wireguard implementation, Userspace runs boringtun (or wireguard-go if boringtun is not
+    /// installed) in the namespace for hosts without the wireguard kernel module, WireguardGo
+    /// always runs wireguard-go (default: Kernel, falling back to userspace if the module is
+    /// missing)
+    #[clap(value_enum, long = "wireguard-implementation", ignore_case = true)]
+    pub wireguard_implementation: Option<WrappedArg<WireguardImplementation>>,
+
+    /// Connect OpenVPN over TCP, using the provider's TCP config if there is one, for networks
+    /// blocking UDP (OpenVPN only). Without it, UDP connections fall back to TCP after
+    /// repeated handshake timeouts
+    #[clap(long = "tcp")]
+    pub tcp: bool,
+
+    /// Disable OpenVPN data channel offload, which OpenVPN 2.6 and newer use when the ovpn-dco
+    /// kernel module is available (OpenVPN only)
+    #[clap(long = "no-dco")]
+    pub no_dco: bool,
+
+    /// Extra OpenVPN directive, passed after the config so it overrides the config's value
+    /// (e.g. "tun-mtu 1400" or "verb 4"), may be given several times (OpenVPN only)
+    #[clap(long = "openvpn-option")]
+    pub openvpn_options: Option<Vec<OpenVpnOption>>,
+
+    /// PEM client certificate for OpenVPN, replacing the config's, with --openvpn-key
+    #[clap(long = "openvpn-cert", requires = "openvpn_key")]
+    pub openvpn_cert: Option<PathBuf>,
+
+    /// PEM private key of --openvpn-cert, the passphrase is asked for if it is encrypted
+    #[clap(long = "openvpn-key", requires = "openvpn_cert")]
+    pub openvpn_key: Option<PathBuf>,
+
+    /// PKCS#12 file with the OpenVPN client certificate and key, replacing the config's
+    #[clap(long = "openvpn-pkcs12", conflicts_with_all = ["openvpn_cert", "pkcs11_id"])]
+    pub openvpn_pkcs12: Option<PathBuf>,
+
+    /// PKCS#11 module for the OpenVPN client certificate on a token or smartcard (e.g.
+    /// /usr/lib/opensc-pkcs11.so), with --pkcs11-id
+    #[clap(long = "pkcs11-providers", requires = "pkcs11_id")]
+    pub pkcs11_providers: Option<PathBuf>,
+
+    /// Id of the certificate on the token, as listed by openvpn --show-pkcs11-ids <module>. The
+    /// PIN is asked for
+    #[clap(
+        long = "pkcs11-id",
+        requires = "pkcs11_providers",
+        conflicts_with = "openvpn_cert"
+    )]
+    pub pkcs11_id: Option<String>,
+
+    /// Prompt for the provider username and password instead of using stored credentials.
+    /// They are only kept in memory (credentials may also be given as
+    /// VOPONO_<PROVIDER>_USERNAME and VOPONO_<PROVIDER>_PASSWORD environment variables)
+    #[clap(long = "prompt-credentials")]
+    pub prompt_credentials: bool,
+
+    /// Wireguard obfuscation proxy to run in the namespace for networks blocking Wireguard
+    /// (Udp2Tcp and Shadowsocks are Mullvad only, requiring udp2tcp or shadowsocks-rust sslocal.
+    /// Wstunnel, Udp2raw and Shadowsocks work with any provider through your own server, with
+    /// --obfuscation-server)
+    #[clap(value_enum, long = "obfuscation", ignore_case = true)]
+    pub obfuscation: Option<WrappedArg<ObfuscationProtocol>>,
+
+    /// Remote port for the obfuscation proxy (default: 80 for Udp2Tcp, 51900 for Shadowsocks)
+    #[clap(long = "obfuscation-port")]
+    pub obfuscation_port: Option<u16>,
+
+    /// wstunnel or udp2raw server forwarding to the Wireguard endpoint, as host:port (or a
+    /// ws:// or wss:// URL for wstunnel), or a Shadowsocks server as an ss:// URL (for
+    /// Wireguard and OpenVPN)
+    #[clap(long = "obfuscation-server")]
+    pub obfuscation_server: Option<String>,
+
+    /// Key of the obfuscation server: the udp2raw password, or the wstunnel path prefix
+    #[clap(long = "obfuscation-key")]
+    pub obfuscation_key: Option<String>,
+
+    /// obfs4 bridge in front of the OpenVPN server to connect through with obfs4proxy, as in a
+    /// Tor bridge line: "203.0.113.5:443 cert=... iat-mode=0" (OpenVPN only, over TCP)
+    #[clap(long = "obfs4")]
+    pub obfs4: Option<Obfs4Bridge>,
+
+    /// Upstream SOCKS5 proxy to redirect the namespace's traffic through with redsocks instead
+    /// of a VPN, as socks5://[user:password@]host:port (e.g. an ssh -D tunnel)
+    #[clap(long = "socks-upstream")]
+    pub socks_upstream: Option<UpstreamProxy>,
+
+    /// Upstream HTTP proxy to redirect the namespace's traffic through with CONNECT requests
+    /// instead of a VPN, as http://[user:password@]host:port
+    #[clap(long = "http-upstream", conflicts_with = "socks_upstream")]
+    pub http_upstream: Option<UpstreamProxy>,
+
+    /// Re-run vopono sync for the provider before connecting if its configs are older than
+    /// this many days
+    #[clap(long = "config-max-age")]
+    pub config_max_age: Option<u64>,
+
+    /// Reconnect the VPN tunnel when it fails (no recent Wireguard handshake, or OpenVPN has
+    /// exited), keeping the application running
+    #[clap(long = "reconnect")]
+    pub reconnect: bool,
+
+    /// Consecutive failed reconnect attempts before giving up (default: 5)
+    #[clap(long = "reconnect-retries", value_parser = clap::value_parser!(u32).range(1..))]
+    pub reconnect_retries: Option<u32>,
+
+    /// Seconds between tunnel checks and reconnect attempts (default: 30)
+    #[clap(long = "reconnect-interval", value_parser = clap::value_parser!(u64).range(1..))]
+    pub reconnect_interval: Option<u64>,
+
+    /// Seconds without a Wireguard handshake after which the tunnel has failed (default: 180)
+    #[clap(long = "reconnect-handshake-timeout")]
+    pub reconnect_handshake_timeout: Option<u64>,
+
+    /// Check the exit IP from inside the namespace once the tunnel is up, failing if it is the
+    /// host's public IP
+    #[clap(long = "verify-ip")]
+    pub verify_ip: bool,
+
+    /// IP-check endpoint answering with JSON with an ip field (and optionally country) or the
+    /// plain address (default: am.i.mullvad.net for Mullvad, ipinfo.io otherwise)
+    #[clap(long = "verify-ip-url")]
+    pub verify_ip_url: Option<String>,
+
+    /// Also fail if the exit IP is not in the country of the chosen server (implies --verify-ip)
+    #[clap(long = "verify-country")]
+    pub verify_country: bool,
+
+    /// Move to another server matching --server and the server filters at this interval, e.g.
+    /// 30m or 6h, keeping the application running
+    #[clap(long = "rotate")]
+    pub rotate: Option<RotationInterval>,
+
+    /// Ping a target through the tunnel and take the watchdog action when it stops answering
+    #[clap(long = "watchdog")]
+    pub watchdog: bool,
+
+    /// Host or IP the watchdog pings (default: 1.1.1.1)
+    #[clap(long = "watchdog-target")]
+    pub watchdog_target: Option<String>,
+
+    /// Seconds between watchdog pings (default: 30)
+    #[clap(long = "watchdog-interval", value_parser = clap::value_parser!(u64).range(1..))]
+    pub watchdog_interval: Option<u64>,
+
+    /// Consecutive failed pings before the watchdog acts (default: 3)
+    #[clap(long = "watchdog-failures", value_parser = clap::value_parser!(u32).range(1..))]
+    pub watchdog_failures: Option<u32>,
+
+    /// What the watchdog does when the target stops answering (default: Reconnect)
+    #[clap(value_enum, long = "watchdog-action", ignore_case = true)]
+    pub watchdog_action: Option<WrappedArg<WatchdogAction>>,
+
+    /// Command run on the host by the Callback watchdog action
+    #[clap(long = "watchdog-callback")]
+    pub watchdog_callback: Option<String>,
 }
 
 #[derive(Parser)]
@@ -266,6 +773,216 @@ pub struct ListCommand {
     /// VPN Provider
     #[clap(value_parser(clap::builder::PossibleValuesParser::from(&["namespaces", "applications"])))]
     pub list_type: Option<String>,
+
+    /// Output format, json for scripting
+    #[clap(long = "output", short = 'o', default_value = "table", value_parser(clap::builder::PossibleValuesParser::from(&["table", "json"])))]
+    pub output: String,
+}
+
+#[derive(Parser)]
+pub struct AttachCommand {
+    /// Name of the running vopono network namespace (see vopono list namespaces)
+    pub namespace: String,
+
+    /// Application to run in the network namespace
+    pub application: String,
+
+    /// User with which to run the application (default is current user)
+    #[clap(long = "user", short = 'u')]
+    pub user: Option<String>,
+
+    /// Group with which to run the application
+    #[clap(long = "group", short = 'g')]
+    pub group: Option<String>,
+
+    /// Working directory in which to run the application (default is current working directory)
+    #[clap(long = "working-directory", short = 'w')]
+    pub working_directory: Option<String>,
+
+    /// Keep-alive - do not release the network namespace when the application terminates
+    #[clap(long = "keep-alive", short = 'k')]
+    pub keep_alive: bool,
+}
+
+#[derive(Parser)]
+pub struct DnsLeakCommand {
+    /// Name of the running vopono network namespace (see vopono list namespaces)
+    pub namespace: String,
+}
+
+#[derive(Parser)]
+pub struct StatusCommand {
+    /// Only show this network namespace (default is all running namespaces)
+    pub namespace: Option<String>,
+}
+
+#[derive(Parser)]
+pub struct StopCommand {
+    /// Name of the running vopono network namespace (see vopono list namespaces)
+    pub namespace: String,
+
+    /// Seconds to wait for the vopono instances to exit
+    #[clap(long = "timeout", default_value = "10")]
+    pub timeout: u64,
+
+    /// Kill instances still running after the timeout and remove their state
+    #[clap(long = "force")]
+    pub force: bool,
+}
+
+#[derive(Parser)]
+pub struct TuiCommand {
+    /// Application to run (prompted for if not given)
+    pub application: Option<String>,
+
+    /// Ping the servers of the chosen country and sort them by latency
+    #[clap(long = "ping")]
+    pub ping: bool,
+}
+
+#[derive(Parser)]
+pub struct ServiceCommand {
+    #[clap(subcommand)]
+    pub cmd: ServiceAction,
+}
+
+#[derive(Parser)]
+pub enum ServiceAction {
+    #[clap(
+        name = "generate",
+        about = "Write a systemd service running an application with a vopono profile at boot"
+    )]
+    Generate(ServiceGenerateCommand),
+}
+
+#[derive(Parser)]
+pub struct ServiceGenerateCommand {
+    /// Profile from the [profile.<name>] section of the vopono config file
+    #[clap(long = "profile")]
+    pub profile: String,
+
+    /// Application to run, with its arguments
+    #[clap(long = "app")]
+    pub app: String,
+
+    /// Unit name (default vopono-<profile>)
+    #[clap(long = "name")]
+    pub name: Option<String>,
+
+    /// User to run the application as (default current user)
+    #[clap(long = "user", short = 'u')]
+    pub user: Option<String>,
+
+    /// Also write a socket unit listening on this port on the host, forwarding connections to
+    /// the application in the namespace
+    #[clap(long = "socket")]
+    pub socket: Option<u16>,
+
+    /// Directory to write the units to
+    #[clap(long = "output-dir", default_value = "/etc/systemd/system")]
+    pub output_dir: PathBuf,
+
+    /// Print the units instead of writing them
+    #[clap(long = "stdout")]
+    pub stdout: bool,
+}
+
+#[derive(Parser)]
+pub struct DesktopCommand {
+    /// Application to launch, with its arguments
+    pub application: String,
+
+    /// VPN Provider
+    #[clap(value_enum, long = "provider", short = 'p', ignore_case = true)]
+    pub provider: Option<WrappedArg<VpnProvider>>,
+
+    /// VPN Protocol
+    #[clap(value_enum, long = "protocol", short = 'c', ignore_case = true)]
+    pub protocol: Option<WrappedArg<Protocol>>,
+
+    /// VPN Server prefix
+    #[clap(long = "server", short = 's')]
+    pub server: Option<String>,
+
+    /// Profile from the [profile.<name>] section of the vopono config file, instead of a provider
+    #[clap(long = "profile", conflicts_with = "provider")]
+    pub profile: Option<String>,
+
+    /// Name in the application menu (default e.g. "Firefox (via Mullvad DE)")
+    #[clap(long = "name")]
+    pub name: Option<String>,
+
+    /// Icon name or path (default the application's own icon)
+    #[clap(long = "icon")]
+    pub icon: Option<String>,
+
+    /// Desktop file id of the application to take the name and icon from (default the
+    /// executable name)
+    #[clap(long = "desktop-id")]
+    pub desktop_id: Option<String>,
+
+    /// Program sudo uses to ask for the password (default $SUDO_ASKPASS or a common askpass)
+    #[clap(long = "askpass")]
+    pub askpass: Option<PathBuf>,
+
+    /// Directory to write the entry to (default ~/.local/share/applications)
+    #[clap(long = "output-dir")]
+    pub output_dir: Option<PathBuf>,
+
+    /// Print the entry instead of writing it
+    #[clap(long = "stdout")]
+    pub stdout: bool,
+}
+
+#[derive(Parser)]
+pub struct CompletionsCommand {
+    /// Shell to complete vopono in
+    #[clap(value_enum)]
+    pub shell: clap_complete::Shell,
+}
+
+#[derive(Parser)]
+pub struct CompleteCommand {
+    #[clap(subcommand)]
+    pub target: CompleteTarget,
+}
+
+#[derive(Parser)]
+pub enum CompleteTarget {
+    /// Synced server config names of a provider
+    Servers {
+        provider: String,
+        protocol: Option<String>,
+        /// Only names starting with this
+        #[clap(long = "prefix")]
+        prefix: Option<String>,
+    },
+    /// Running vopono namespaces
+    Namespaces,
+    /// Profiles in the vopono config file
+    Profiles,
+}
+
+#[derive(Parser)]
+pub struct SetupCommand {
+    /// Path of the vopono config TOML file to write
+    /// Default: ~/.config/vopono/config.toml
+    #[clap(long = "vopono-config")]
+    pub vopono_config: Option<PathBuf>,
+}
+
+#[derive(Parser)]
+pub struct MetricsCommand {
+    /// Address to serve the metrics on, at /metrics
+    #[clap(long = "listen", default_value = "127.0.0.1:9617")]
+    pub listen: SocketAddr,
+}
+
+#[derive(Parser)]
+pub struct CleanupCommand {
+    /// Only list the orphaned state, do not remove it
+    #[clap(long = "dry-run")]
+    pub dry_run: bool,
 }
 
 #[derive(Parser)]
@@ -281,6 +998,10 @@ pub struct ServersCommand {
     /// VPN Server prefix
     #[clap(long = "prefix", short = 's')]
     pub prefix: Option<String>,
+
+    /// Output format, json for scripting
+    #[clap(long = "output", short = 'o', default_value = "table", value_parser(clap::builder::PossibleValuesParser::from(&["table", "json"])))]
+    pub output: String,
 }
 
 // TODO: Handle multiple addresses
@@ -1,18 +1,39 @@
 // Handles using the args from either the CLI or config file
 
-use std::{net::IpAddr, path::PathBuf, str::FromStr};
+use std::{net::IpAddr, path::PathBuf, str::FromStr, time::Duration};
 
 use anyhow::anyhow;
 use config::Config;
+use ipnet::{IpNet, Ipv4Net};
 use log::warn;
 use vopono_core::{
     config::{providers::VpnProvider, vpn::Protocol},
     network::{
+        dns_config::{read_hosts_entries, validate_hosts_entry},
+        dns_rules::{DnsForward, DnsOverride},
+        egress::EgressRule,
         firewall::Firewall,
+        ip_check::default_ip_check_url,
+        netns::validate_namespace_name,
         network_interface::{NetworkInterface, get_active_interfaces},
+        obfs4::Obfs4Bridge,
+        obfuscation::ObfuscationProtocol,
+        openvpn::{ClientCert, OpenVpnOption},
+        port_publish::PublishedPort,
+        rate_limit::RateLimit,
+        reconnect::ReconnectPolicy,
+        rotation::RotationInterval,
+        shadowsocks::ShadowsocksServer,
+        stub_resolver::DnsUpstream,
         trojan::TrojanHost,
+        upstream_proxy::{UpstreamProxy, UpstreamProxyKind},
+        veth_pair::{MAX_INTERFACE_NAME_LEN, validate_interface_name},
+        watchdog::{WatchdogAction, WatchdogPolicy},
+        wireguard::WireguardImplementation,
+    },
+    util::{
+        get_config_file_protocol, server_filter::ServerFilter, sync_filter::SyncFilter, vopono_dir,
     },
-    util::{get_config_file_protocol, vopono_dir},
 };
 
 use crate::args::ExecCommand;
@@ -85,30 +106,100 @@ pub struct ArgsConfig {
     pub working_directory: Option<String>,
     pub custom: Option<PathBuf>,
     pub dns: Option<Vec<IpAddr>>,
+    pub dns_over_tls: Option<Vec<DnsUpstream>>,
+    pub dns_over_https: Option<Vec<DnsUpstream>>,
+    pub dns_cache: bool,
+    pub dns_overrides: Option<Vec<DnsOverride>>,
+    pub dns_forwards: Option<Vec<DnsForward>>,
+    pub dnscrypt_proxy: bool,
+    pub dnscrypt_servers: Option<Vec<String>>,
+    pub systemd_resolved: bool,
     pub hosts: Option<Vec<String>>,
     pub open_hosts: Option<Vec<IpAddr>>,
+    pub allow_lan: Option<Vec<IpNet>>,
     pub no_killswitch: bool,
+    pub audit_leaks: bool,
+    pub cgroup_firewall: bool,
+    pub allow_outbound: Option<Vec<EgressRule>>,
     pub keep_alive: bool,
+    pub detach: bool,
     pub open_ports: Option<Vec<u16>>,
     pub forward: Option<Vec<u16>>,
     pub no_proxy: bool,
+    pub publish: Option<Vec<PublishedPort>>,
+    pub mtu: Option<u16>,
+    pub rate_limit: Option<RateLimit>,
+    pub relay_discovery: bool,
     pub firewall: Firewall,
     pub disable_ipv6: bool,
+    pub ipv6: bool,
+    pub veth_subnet: Option<Ipv4Net>,
+    pub bridge: bool,
+    pub bridge_peers: bool,
+    pub preup: Option<String>,
     pub postup: Option<String>,
     pub predown: Option<String>,
+    pub postdown: Option<String>,
     pub custom_netns_name: Option<String>,
+    pub veth_host_name: Option<String>,
+    pub veth_netns_name: Option<String>,
     pub allow_host_access: bool,
     pub port_forwarding: bool,
     pub custom_port_forwarding: Option<VpnProvider>,
     pub port_forwarding_callback: Option<String>,
     pub create_netns_only: bool,
+    pub dry_run: bool,
+    /// Profile of the config file the settings came from
+    pub profile: Option<String>,
     pub trojan_host: Option<TrojanHost>,
     pub trojan_password: Option<String>,
     pub trojan_no_verify: bool,
     pub trojan_config: Option<PathBuf>,
+    pub entry_server: Option<String>,
+    pub owned_only: bool,
+    pub ram_only: bool,
+    pub daita: bool,
+    pub quantum_resistant: bool,
+    pub secure_core: bool,
+    pub random_server: bool,
+    pub server_filter: ServerFilter,
+    pub mullvad_socks: bool,
+    pub ephemeral_key: bool,
+    pub wireguard_implementation: WireguardImplementation,
+    pub tcp: bool,
+    pub no_dco: bool,
+    pub openvpn_options: Option<Vec<OpenVpnOption>>,
+    pub client_cert: Option<ClientCert>,
+    pub prompt_credentials: bool,
+    pub obfuscation: Option<ObfuscationProtocol>,
+    pub obfuscation_port: Option<u16>,
+    pub obfuscation_server: Option<String>,
+    pub obfuscation_key: Option<String>,
+    pub obfs4: Option<Obfs4Bridge>,
+    pub upstream_proxy: Option<UpstreamProxy>,
+    pub config_max_age: Option<u64>,
+    pub reconnect: Option<ReconnectPolicy>,
+    pub watchdog: Option<WatchdogPolicy>,
+    pub rotate: Option<RotationInterval>,
+    pub failover: Vec<String>,
+    /// IP-check endpoint for --verify-ip
+    pub verify_ip: Option<String>,
+    pub verify_country: bool,
+}
+
+/// The server followed by the failover servers
+pub fn failover_order<'a>(server: &'a str, failover: &'a [String]) -> Vec<&'a str> {
+    std::iter::once(server)
+        .chain(failover.iter().map(|x| x.as_str()))
+        .collect()
 }
 
 impl ArgsConfig {
+    /// --server followed by the --failover servers, in the order to try them
+    pub fn servers(&self) -> Vec<&str> {
+        failover_order(&self.server, &self.failover)
+    }
+
     /// Return new ExecCommand with args from config file if missing in CLI but present there
     /// Also handle CLI args consistency errors
     pub fn get_cli_or_config_args(command: ExecCommand, config: Config) -> anyhow::Result<Self> {
@@ -131,18 +222,96 @@ impl ArgsConfig {
             })
             .map(|c| c.to_string())?;
         let custom_netns_name = command_else_config_option!(custom_netns_name, command, config);
+        if let Some(name) = custom_netns_name.as_ref() {
+            validate_namespace_name(name)?;
+        }
+        let veth_host_name = command_else_config_option!(veth_host_name, command, config);
+        let veth_netns_name = command_else_config_option!(veth_netns_name, command, config);
+        for name in [veth_host_name.as_ref(), veth_netns_name.as_ref()]
+            .into_iter()
+            .flatten()
+        {
+            validate_interface_name(name)?;
+        }
+        // The default veth names add a 2 character suffix to the namespace name
+        if custom_netns_name
+            .as_ref()
+            .is_some_and(|x| x.len() + 2 > MAX_INTERFACE_NAME_LEN)
+            && (veth_host_name.is_none() || veth_netns_name.is_none())
+        {
+            error_and_bail!(
+                "custom_netns_name longer than 13 characters requires veth_host_name and veth_netns_name to be set"
+            );
+        }
         let open_hosts = command_else_config_option!(open_hosts, command, config);
-        let hosts = command_else_config_option!(hosts, command, config);
+        let allow_lan = command_else_config_option!(allow_lan, command, config);
+        let mut hosts = command_else_config_option!(hosts, command, config);
+        for entry in hosts.iter().flatten() {
+            validate_hosts_entry(entry)?;
+        }
+        let hosts_file: Option<PathBuf> = command_else_config_option!(hosts_file, command, config)
+            .and_then(|p| {
+                shellexpand::full(&p.to_string_lossy())
+                    .ok()
+                    .and_then(|s| PathBuf::from_str(s.as_ref()).ok())
+            });
+        if let Some(path) = hosts_file {
+            hosts
+                .get_or_insert_with(Vec::new)
+                .extend(read_hosts_entries(&path)?);
+        }
         let open_ports = command_else_config_option!(open_ports, command, config);
         let forward = command_else_config_option!(forward, command, config);
+        let preup = command_else_config_option!(preup, command, config)
+            .and_then(|p| shellexpand::full(&p).ok().map(|s| s.into_owned()));
         let postup = command_else_config_option!(postup, command, config)
             .and_then(|p| shellexpand::full(&p).ok().map(|s| s.into_owned()));
         let predown = command_else_config_option!(predown, command, config)
             .and_then(|p| shellexpand::full(&p).ok().map(|s| s.into_owned()));
+        let postdown = command_else_config_option!(postdown, command, config)
+            .and_then(|p| shellexpand::full(&p).ok().map(|s| s.into_owned()));
         let group = command_else_config_option!(group, command, config);
         let working_directory = command_else_config_option!(working_directory, command, config)
             .and_then(|p| shellexpand::full(&p).ok().map(|s| s.into_owned()));
         let dns = command_else_config_option!(dns, command, config);
+        let dns_over_tls = command_else_config_option!(dns_over_tls, command, config);
+        let dns_over_https = command_else_config_option!(dns_over_https, command, config);
+        if dns_over_tls.iter().flatten().any(|x| !x.is_tls()) {
+            error_and_bail!("dns_over_tls servers must be given as IP[:port][#server name]");
+        }
+        if dns_over_https.iter().flatten().any(|x| !x.is_https()) {
+            error_and_bail!("dns_over_https servers must be given as https:// URLs");
+        }
+        let dns_cache = command_else_config_bool!(dns_cache, command, config);
+        let dns_overrides = command_else_config_option!(dns_overrides, command, config);
+        let mut dns_forwards = command_else_config_option!(dns_forwards, command, config);
+        // Split DNS domains are forwarded to the host resolver
+        if let Some(domains) = command_else_config_option!(split_dns, command, config) {
+            let host_forwards = domains
+                .iter()
+                .map(|x| DnsForward::host(x))
+                .collect::<anyhow::Result<Vec<_>>>()?;
+            dns_forwards
+                .get_or_insert_with(Vec::new)
+                .extend(host_forwards);
+        }
+        let dnscrypt_proxy = command_else_config_bool!(dnscrypt_proxy, command, config);
+        let dnscrypt_servers = command_else_config_option!(dnscrypt_servers, command, config);
+        if dnscrypt_proxy
+            && (dns_over_tls.is_some()
+                || dns_over_https.is_some()
+                || dns_cache
+                || dns_overrides.is_some()
+                || dns_forwards.is_some())
+        {
+            error_and_bail!(
+                "dnscrypt_proxy cannot be combined with the built-in namespace resolver options"
+            );
+        }
+        let systemd_resolved = command_else_config_bool!(systemd_resolved, command, config);
+        if systemd_resolved && dnscrypt_proxy {
+            error_and_bail!("systemd_resolved cannot be combined with dnscrypt_proxy");
+        }
         let user = command_else_config_option!(user, command, config)
             .or_else(|| std::env::var("SUDO_USER").ok());
         let port_forwarding_callback =
@@ -150,16 +319,44 @@ impl ArgsConfig {
                 .and_then(|p| shellexpand::full(&p).ok().map(|s| s.into_owned()));
 
         let no_proxy = command_else_config_bool!(no_proxy, command, config);
-        let keep_alive = command_else_config_bool!(keep_alive, command, config);
+        let publish = command_else_config_option!(publish, command, config);
+        let mtu = command_else_config_option!(mtu, command, config);
+        let rate_limit = command_else_config_option!(rate_limit, command, config);
+        let relay_discovery = command_else_config_bool!(relay_discovery, command, config);
+        let detach = command_else_config_bool!(detach, command, config);
+        // The namespace of a detached instance stays up until vopono stop
+        let keep_alive = command_else_config_bool!(keep_alive, command, config) || detach;
         let port_forwarding = command_else_config_bool!(port_forwarding, command, config);
         let allow_host_access = command_else_config_bool!(allow_host_access, command, config);
         let create_netns_only = command_else_config_bool!(create_netns_only, command, config);
+        let dry_run = command.dry_run;
+        let profile = command.profile.clone();
         let disable_ipv6 = command_else_config_bool!(disable_ipv6, command, config);
+        let ipv6 = command_else_config_bool!(ipv6, command, config);
+        if ipv6 && disable_ipv6 {
+            error_and_bail!("ipv6 and disable_ipv6 cannot both be set");
+        }
+        let veth_subnet = command_else_config_option!(veth_subnet, command, config);
+        if veth_subnet.is_some_and(|x| x.prefix_len() != 16) {
+            error_and_bail!("veth_subnet must be a /16 range, e.g. 10.200.0.0/16");
+        }
+        let bridge = command_else_config_bool!(bridge, command, config);
+        let bridge_peers = command_else_config_bool!(bridge_peers, command, config);
+        if bridge && ipv6 {
+            error_and_bail!("ipv6 is not supported in bridge mode");
+        }
+        if bridge_peers && !bridge {
+            error_and_bail!("bridge_peers requires bridge mode");
+        }
         let no_killswitch = command_else_config_bool!(no_killswitch, command, config);
+        let audit_leaks = command_else_config_bool!(audit_leaks, command, config);
+        let cgroup_firewall = command_else_config_bool!(cgroup_firewall, command, config);
+        let allow_outbound = command_else_config_option!(allow_outbound, command, config);
 
         let firewall = command_else_config_option_variant!(firewall, command, config)
             .ok_or_else(|| anyhow!("Failed to get Firewall variant from args"))
             .or_else(|_| vopono_core::util::get_firewall())?;
+        firewall.check_available()?;
         let custom_port_forwarding =
             command_else_config_option_variant!(custom_port_forwarding, command, config);
 
@@ -203,9 +400,37 @@ impl ArgsConfig {
         }?;
         log::debug!("Interface: {}", &interface.name);
 
+        let random_server = command_else_config_bool!(random_server, command, config);
         let provider: VpnProvider;
         let server: String;
         let protocol: Protocol;
+        let socks_upstream: Option<UpstreamProxy> =
+            command_else_config_option!(socks_upstream, command, config);
+        let http_upstream: Option<UpstreamProxy> =
+            command_else_config_option!(http_upstream, command, config);
+        if socks_upstream
+            .as_ref()
+            .is_some_and(|x| x.kind != UpstreamProxyKind::Socks5)
+        {
+            error_and_bail!("--socks-upstream must be a socks5:// URL");
+        }
+        if http_upstream
+            .as_ref()
+            .is_some_and(|x| x.kind != UpstreamProxyKind::HttpConnect)
+        {
+            error_and_bail!("--http-upstream must be an http:// URL");
+        }
+        let upstream_proxy = socks_upstream.or(http_upstream);
+        if upstream_proxy.is_some() && custom.is_some() {
+            error_and_bail!("An upstream proxy cannot be used with a custom config");
+        }
+
+        let tor = command
+            .protocol
+            .as_ref()
+            .map(|x| x.to_variant())
+            .or_else(|| config.get("protocol").ok())
+            == Some(Protocol::Tor);
 
         // Assign protocol and server from args or vopono config file or custom config if used
         if let Some(path) = &custom {
@@ -228,6 +453,23 @@ impl ArgsConfig {
                 // set an empty-string if it isn't provided.
                 server = command_else_config_option!(server, command, config).unwrap_or_default();
             }
+        } else if let Some(upstream) = &upstream_proxy {
+            protocol = match upstream.kind {
+                UpstreamProxyKind::Socks5 => Protocol::Socks5,
+                UpstreamProxyKind::HttpConnect => Protocol::HttpProxy,
+            };
+            if command.protocol.is_some_and(|x| x.to_variant() != protocol) {
+                error_and_bail!("The protocol does not match the upstream proxy");
+            }
+            provider = VpnProvider::Custom;
+            // Named after the proxy, as for custom configs
+            let sname = bs58::encode(format!("{}:{}", upstream.host, upstream.port)).into_string();
+            server = sname[0..std::cmp::min(11, sname.len())].to_string();
+        } else if tor {
+            // vopono runs its own Tor client
+            protocol = Protocol::Tor;
+            provider = VpnProvider::Custom;
+            server = "tor".to_owned();
         } else {
             // Get server and provider
             provider = command_else_config_option_variant!(provider, command, config).ok_or_else(
@@ -247,6 +489,8 @@ impl ArgsConfig {
         // Work-around for providers which do not need a server - TODO: Clean this
          .or_else(|| if provider == VpnProvider::Warp {Some("warp".to_owned())} else {None})
          .or_else(|| if provider == VpnProvider::None {Some("none".to_owned())} else {None})
+            // Any server, once chosen the namespace is named after it
+            .or_else(|| random_server.then(String::new))
             .ok_or_else(|| {
                 let msg = "VPN server prefix must be provided as a command-line argument or in the vopono config.toml file";
                 log::error!("{msg}"); anyhow!(msg)})?;
@@ -268,6 +512,275 @@ impl ArgsConfig {
                     .and_then(|s| PathBuf::from_str(s.as_ref()).ok())
             });
 
+        let entry_server = command_else_config_option!(entry_server, command, config);
+        let owned_only = command_else_config_bool!(owned_only, command, config);
+        let ram_only = command_else_config_bool!(ram_only, command, config);
+        let daita = command_else_config_bool!(daita, command, config);
+        let quantum_resistant = command_else_config_bool!(quantum_resistant, command, config);
+        let secure_core = command_else_config_bool!(secure_core, command, config);
+        let server_filter = ServerFilter {
+            location: SyncFilter {
+                countries: command_else_config_option!(countries, command, config)
+                    .unwrap_or_default(),
+                cities: command_else_config_option!(cities, command, config).unwrap_or_default(),
+            },
+            features: command
+                .features
+                .as_ref()
+                .map(|x| x.iter().map(|f| f.to_variant()).collect())
+                .or_else(|| config.get("features").ok())
+                .unwrap_or_default(),
+            max_load: command_else_config_option!(max_load, command, config),
+            prefer_low_load: command_else_config_bool!(prefer_low_load, command, config),
+        };
+        if secure_core && provider != VpnProvider::ProtonVPN {
+            error_and_bail!("Secure Core servers are only available for ProtonVPN provider");
+        }
+        let mullvad_socks = command_else_config_bool!(mullvad_socks, command, config);
+        if mullvad_socks && provider != VpnProvider::Mullvad {
+            error_and_bail!("Mullvad SOCKS5 proxy is only available for Mullvad provider");
+        }
+        let ephemeral_key = command_else_config_bool!(ephemeral_key, command, config);
+        let prompt_credentials = command_else_config_bool!(prompt_credentials, command, config);
+        if ephemeral_key
+            && !(provider == VpnProvider::PrivateInternetAccess && protocol == Protocol::Wireguard)
+        {
+            error_and_bail!(
+                "Ephemeral Wireguard keys are only supported for PrivateInternetAccess Wireguard"
+            );
+        }
+        let wireguard_implementation: WireguardImplementation =
+            command_else_config_option_variant!(wireguard_implementation, command, config)
+                .unwrap_or_default();
+        if wireguard_implementation != WireguardImplementation::Kernel
+            && protocol != Protocol::Wireguard
+        {
+            error_and_bail!("--wireguard-implementation is only used for Wireguard");
+        }
+        let tcp = command_else_config_bool!(tcp, command, config);
+        if tcp && protocol != Protocol::OpenVpn {
+            error_and_bail!("--tcp is only used for OpenVPN");
+        }
+        let no_dco = command_else_config_bool!(no_dco, command, config);
+        if no_dco && protocol != Protocol::OpenVpn {
+            error_and_bail!("--no-dco is only used for OpenVPN");
+        }
+        let openvpn_options = command_else_config_option!(openvpn_options, command, config);
+        if openvpn_options.is_some() && protocol != Protocol::OpenVpn {
+            error_and_bail!("--openvpn-option is only used for OpenVPN");
+        }
+        // OpenVPN runs in the config's directory, so relative paths would not be found
+        let client_cert = ClientCert {
+            cert: Self::client_cert_file(
+                command_else_config_option!(openvpn_cert, command, config),
+                "--openvpn-cert",
+            )?,
+            key: Self::client_cert_file(
+                command_else_config_option!(openvpn_key, command, config),
+                "--openvpn-key",
+            )?,
+            pkcs12: Self::client_cert_file(
+                command_else_config_option!(openvpn_pkcs12, command, config),
+                "--openvpn-pkcs12",
+            )?,
+            pkcs11_providers: Self::client_cert_file(
+                command_else_config_option!(pkcs11_providers, command, config),
+                "--pkcs11-providers",
+            )?,
+            pkcs11_id: command_else_config_option!(pkcs11_id, command, config),
+            passphrase: None,
+        };
+        // Also checked by clap for the command line, but not for the config file
+        if client_cert.cert.is_some() != client_cert.key.is_some() {
+            error_and_bail!("--openvpn-cert and --openvpn-key must be given together");
+        }
+        if client_cert.pkcs11_id.is_some() != client_cert.pkcs11_providers.is_some() {
+            error_and_bail!("--pkcs11-id and --pkcs11-providers must be given together");
+        }
+        let methods = [
+            client_cert.cert.is_some(),
+            client_cert.pkcs12.is_some(),
+            client_cert.pkcs11_id.is_some(),
+        ];
+        let client_cert = match methods.iter().filter(|x| **x).count() {
+            0 => None,
+            1 => Some(client_cert),
+            _ => {
+                error_and_bail!(
+                    "Only one of --openvpn-cert, --openvpn-pkcs12 and --pkcs11-id can be given"
+                );
+            }
+        };
+        if client_cert.is_some() && protocol != Protocol::OpenVpn {
+            error_and_bail!("Client certificates are only used for OpenVPN");
+        }
+        let obfuscation = command_else_config_option_variant!(obfuscation, command, config);
+        let obfuscation_port = command_else_config_option!(obfuscation_port, command, config);
+        let obfuscation_server = command_else_config_option!(obfuscation_server, command, config);
+        let obfuscation_key = command_else_config_option!(obfuscation_key, command, config);
+        let obfs4 = command_else_config_option!(obfs4, command, config);
+        let config_max_age = command_else_config_option!(config_max_age, command, config);
+        let reconnect = if command_else_config_bool!(reconnect, command, config) {
+            let default = ReconnectPolicy::default();
+            let retries: Option<u32> =
+                command_else_config_option!(reconnect_retries, command, config);
+            let interval: Option<u64> =
+                command_else_config_option!(reconnect_interval, command, config);
+            let handshake_timeout: Option<u64> =
+                command_else_config_option!(reconnect_handshake_timeout, command, config);
+            Some(ReconnectPolicy {
+                retries: retries.unwrap_or(default.retries).max(1),
+                interval: interval
+                    .map(|x| Duration::from_secs(x.max(1)))
+                    .unwrap_or(default.interval),
+                handshake_timeout: handshake_timeout
+                    .map(Duration::from_secs)
+                    .unwrap_or(default.handshake_timeout),
+            })
+        } else {
+            None
+        };
+        if reconnect.is_some() && !matches!(protocol, Protocol::Wireguard | Protocol::OpenVpn) {
+            error_and_bail!("--reconnect is only supported for Wireguard and OpenVPN");
+        }
+        let watchdog = if command_else_config_bool!(watchdog, command, config) {
+            let default = WatchdogPolicy::default();
+            let target: Option<String> =
+                command_else_config_option!(watchdog_target, command, config);
+            let interval: Option<u64> =
+                command_else_config_option!(watchdog_interval, command, config);
+            let failures: Option<u32> =
+                command_else_config_option!(watchdog_failures, command, config);
+            let action: Option<WatchdogAction> =
+                command_else_config_option_variant!(watchdog_action, command, config);
+            Some(WatchdogPolicy {
+                target: target.unwrap_or(default.target),
+                interval: interval
+                    .map(|x| Duration::from_secs(x.max(1)))
+                    .unwrap_or(default.interval),
+                failures: failures.unwrap_or(default.failures).max(1),
+                action: action.unwrap_or(default.action),
+                callback: command_else_config_option!(watchdog_callback, command, config),
+            })
+        } else {
+            None
+        };
+        let rotate = command_else_config_option!(rotate, command, config);
+        let verify_country = command_else_config_bool!(verify_country, command, config);
+        let verify_ip = (command_else_config_bool!(verify_ip, command, config) || verify_country)
+            .then(|| {
+                command_else_config_option!(verify_ip_url, command, config)
+                    .unwrap_or_else(|| default_ip_check_url(&provider).to_string())
+            });
+        if verify_ip.is_some() && which::which("curl").is_err() {
+            error_and_bail!("--verify-ip requires curl to be installed");
+        }
+        if verify_country && matches!(provider, VpnProvider::Custom | VpnProvider::None) {
+            error_and_bail!("--verify-country needs a provider server list");
+        }
+        let failover: Vec<String> =
+            command_else_config_option!(failover, command, config).unwrap_or_default();
+        if !failover.is_empty()
+            && (!matches!(protocol, Protocol::Wireguard | Protocol::OpenVpn)
+                || provider == VpnProvider::Custom)
+        {
+            error_and_bail!(
+                "--failover is only supported for Wireguard and OpenVPN with a provider server list"
+            );
+        }
+        if rotate.is_some()
+            && (!matches!(protocol, Protocol::Wireguard | Protocol::OpenVpn)
+                || provider == VpnProvider::Custom)
+        {
+            error_and_bail!(
+                "--rotate is only supported for Wireguard and OpenVPN with a provider server list"
+            );
+        }
+        if let Some(watchdog) = watchdog.as_ref() {
+            if which::which("ping").is_err() {
+                error_and_bail!("--watchdog requires ping to be installed");
+            }
+            match watchdog.action {
+                WatchdogAction::Reconnect | WatchdogAction::SwitchServer
+                    if !matches!(protocol, Protocol::Wireguard | Protocol::OpenVpn) =>
+                {
+                    error_and_bail!(
+                        "The Reconnect and SwitchServer watchdog actions are only supported for Wireguard and OpenVPN"
+                    );
+                }
+                WatchdogAction::SwitchServer if provider == VpnProvider::Custom => {
+                    error_and_bail!(
+                        "The SwitchServer watchdog action needs a provider server list"
+                    );
+                }
+                WatchdogAction::Callback if watchdog.callback.is_none() => {
+                    error_and_bail!("The Callback watchdog action needs --watchdog-callback");
+                }
+                _ => {}
+            }
+        }
+        if entry_server.is_some()
+            && !((provider == VpnProvider::Mullvad || provider == VpnProvider::IVPN)
+                && protocol == Protocol::Wireguard)
+        {
+            error_and_bail!("Multihop is only supported for Mullvad and iVPN Wireguard");
+        }
+        // Through the provider's relays, not a server of the user
+        let provider_obfuscation = obfuscation.is_some() && obfuscation_server.is_none();
+        if (owned_only || ram_only || daita || quantum_resistant || provider_obfuscation)
+            && !(provider == VpnProvider::Mullvad && protocol == Protocol::Wireguard)
+        {
+            error_and_bail!(
+                "Server relay filters, quantum-resistant tunnels and obfuscation are only supported for Mullvad Wireguard"
+            );
+        }
+        if daita {
+            warn!(
+                "DAITA filter restricts to DAITA-capable servers, but the traffic padding itself requires the Mullvad app's Wireguard implementation and is not applied by kernel Wireguard"
+            );
+        }
+
+        let shadowsocks_server =
+            obfuscation == Some(ObfuscationProtocol::Shadowsocks) && obfuscation_server.is_some();
+        if obfuscation.is_some()
+            && protocol != Protocol::Wireguard
+            && !(shadowsocks_server && protocol == Protocol::OpenVpn)
+        {
+            error_and_bail!(
+                "Obfuscation is only supported for Wireguard, and Shadowsocks servers for OpenVPN"
+            );
+        }
+        if obfuscation.is_some_and(|x| x.needs_server()) && obfuscation_server.is_none() {
+            error_and_bail!("Wstunnel and Udp2raw obfuscation need --obfuscation-server");
+        }
+        if obfuscation_server.is_some()
+            && !(obfuscation.is_some_and(|x| x.needs_server()) || shadowsocks_server)
+        {
+            error_and_bail!(
+                "--obfuscation-server is only used by Wstunnel, Udp2raw and Shadowsocks obfuscation"
+            );
+        }
+        if shadowsocks_server {
+            obfuscation_server
+                .as_deref()
+                .unwrap_or_default()
+                .parse::<ShadowsocksServer>()?;
+        }
+        if obfuscation == Some(ObfuscationProtocol::Udp2raw) && obfuscation_key.is_none() {
+            error_and_bail!("Udp2raw obfuscation needs the server's key, with --obfuscation-key");
+        }
+        if obfs4.is_some() && protocol != Protocol::OpenVpn {
+            error_and_bail!("--obfs4 is only supported for OpenVPN");
+        }
+        if obfs4.is_some() && obfuscation.is_some() {
+            error_and_bail!("--obfs4 cannot be used together with obfuscation");
+        }
+        if obfuscation.is_some() && entry_server.is_some() {
+            error_and_bail!("Obfuscation cannot be used together with multihop");
+        }
+        if obfuscation.is_some() && (trojan_host.is_some() || trojan_config.is_some()) {
+            error_and_bail!("Obfuscation cannot be used together with Trojan forwarding");
+        }
         if (trojan_host.is_some() || trojan_config.is_some()) && protocol != Protocol::Wireguard {
             error_and_bail!("Trojan is currently only supported for Wireguard forwarding");
         }
@@ -286,6 +799,23 @@ impl ArgsConfig {
             error_and_bail!("Cloudflare Warp protocol must use Warp provider");
         }
 
+        if protocol == Protocol::Socks5 && upstream_proxy.is_none() {
+            error_and_bail!("Socks5 protocol needs an upstream proxy given with --socks-upstream");
+        }
+        if protocol == Protocol::HttpProxy && upstream_proxy.is_none() {
+            error_and_bail!(
+                "HttpProxy protocol needs an upstream proxy given with --http-upstream"
+            );
+        }
+
+        if protocol == Protocol::Xray && provider != VpnProvider::Custom {
+            error_and_bail!("Xray protocol must use a custom config");
+        }
+
+        if protocol == Protocol::Ikev2 && provider != VpnProvider::Custom {
+            error_and_bail!("IKEv2 protocol must use a custom profile");
+        }
+
         if provider == VpnProvider::None && custom.is_some() {
             error_and_bail!("Custom config cannot be set when using None provider");
         }
@@ -310,27 +840,83 @@ impl ArgsConfig {
             working_directory,
             custom,
             dns,
+            dns_over_tls,
+            dns_over_https,
+            dns_cache,
+            dns_overrides,
+            dns_forwards,
+            dnscrypt_proxy,
+            dnscrypt_servers,
+            systemd_resolved,
             hosts,
             open_hosts,
+            allow_lan,
             no_killswitch,
+            audit_leaks,
+            cgroup_firewall,
+            allow_outbound,
             keep_alive,
+            detach,
             open_ports,
             forward,
             no_proxy,
+            publish,
+            mtu,
+            rate_limit,
+            relay_discovery,
             firewall,
             disable_ipv6,
+            ipv6,
+            veth_subnet,
+            bridge,
+            bridge_peers,
+            preup,
             postup,
             predown,
+            postdown,
             custom_netns_name,
+            veth_host_name,
+            veth_netns_name,
             allow_host_access,
             port_forwarding,
             custom_port_forwarding,
             port_forwarding_callback,
             create_netns_only,
+            dry_run,
+            profile,
             trojan_host,
             trojan_password,
             trojan_no_verify,
             trojan_config,
+            entry_server,
+            owned_only,
+            ram_only,
+            daita,
+            quantum_resistant,
+            secure_core,
+            random_server,
+            server_filter,
+            mullvad_socks,
+            ephemeral_key,
+            wireguard_implementation,
+            tcp,
+            no_dco,
+            openvpn_options,
+            client_cert,
+            prompt_credentials,
+            obfuscation,
+            obfuscation_port,
+            obfuscation_server,
+            obfuscation_key,
+            obfs4,
+            upstream_proxy,
+            config_max_age,
+            reconnect,
+            watchdog,
+            rotate,
+            failover,
+            verify_ip,
+            verify_country,
         })
     }
 
@@ -351,12 +937,93 @@ impl ArgsConfig {
         }
         let vopono_config_settings_builder =
             config::Config::builder().add_source(config::File::from(config_path.clone()));
-        vopono_config_settings_builder.build().map_err(|e| {
+        let config = vopono_config_settings_builder.build().map_err(|e| {
             anyhow!(
                 "Failed to parse config from: {} , err: {}",
                 config_path.to_string_lossy(),
                 e
             )
-        })
+        })?;
+        match command.profile.as_deref() {
+            Some(profile) => Self::apply_profile(config, profile).map_err(|e| {
+                anyhow!(
+                    "Failed to apply profile {profile} from: {} , err: {e}",
+                    config_path.to_string_lossy()
+                )
+            }),
+            None => Ok(config),
+        }
+    }
+
+    /// Absolute path of a client certificate file, with ~ and variables expanded
+    fn client_cert_file(path: Option<PathBuf>, option: &str) -> anyhow::Result<Option<PathBuf>> {
+        let Some(path) = path else {
+            return Ok(None);
+        };
+        let expanded = shellexpand::full(&path.to_string_lossy())
+            .map_err(|e| anyhow!("Shell expansion error for {option}: {path:?}, error: {e:?}"))?
+            .to_string();
+        let file = PathBuf::from(&expanded)
+            .canonicalize()
+            .map_err(|e| anyhow!("{option} file not found: {expanded}: {e}"))?;
+        if !file.is_file() {
+            return Err(anyhow!("{option} is not a file: {}", file.display()));
+        }
+        Ok(Some(file))
+    }
+
+    /// Settings of the [profile.<name>] section on top of the top-level ones
+    fn apply_profile(config: Config, profile: &str) -> anyhow::Result<Config> {
+        let settings = config
+            .get_table(&format!("profile.{profile}"))
+            .map_err(|_| anyhow!("No [profile.{profile}] section"))?;
+        let mut builder = Config::builder().add_source(config);
+        for (key, value) in settings {
+            builder = builder.set_override(key, value)?;
+        }
+        Ok(builder.build()?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use config::{File, FileFormat};
+
+    #[test]
+    fn profile_overrides_top_level_settings() {
+        let toml = "provider = \"mullvad\"\nserver = \"sweden\"\nfirewall = \"nftables\"\n\n[profile.work]\nserver = \"usa\"\nprotocol = \"openvpn\"\n";
+        let config = Config::builder()
+            .add_source(File::from_str(toml, FileFormat::Toml))
+            .build()
+            .unwrap();
+        let profile = ArgsConfig::apply_profile(config.clone(), "work").unwrap();
+        assert_eq!(profile.get_string("server").unwrap(), "usa");
+        assert_eq!(profile.get_string("protocol").unwrap(), "openvpn");
+        // Settings the profile does not set are kept
+        assert_eq!(profile.get_string("provider").unwrap(), "mullvad");
+        assert_eq!(profile.get_string("firewall").unwrap(), "nftables");
+        assert!(ArgsConfig::apply_profile(config, "home").is_err());
+    }
+
+    #[test]
+    fn client_cert_files_are_absolute() {
+        let dir = std::env::temp_dir().join(format!("vopono_cert_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let cert = dir.join("client.crt");
+        std::fs::write(&cert, "").unwrap();
+        let given = dir.join(".").join("client.crt");
+        assert_eq!(
+            ArgsConfig::client_cert_file(Some(given), "--openvpn-cert").unwrap(),
+            Some(cert.canonicalize().unwrap())
+        );
+        let missing = ArgsConfig::client_cert_file(Some(dir.join("client.key")), "--openvpn-key");
+        assert!(missing.unwrap_err().to_string().contains("--openvpn-key"));
+        assert!(ArgsConfig::client_cert_file(Some(dir.clone()), "--openvpn-pkcs12").is_err());
+        assert_eq!(
+            ArgsConfig::client_cert_file(None, "--openvpn-cert").unwrap(),
+            None
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
     }
 }
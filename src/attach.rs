@@ -0,0 +1,78 @@
+use super::args::AttachCommand;
+use super::exec::stay_alive;
+use anyhow::bail;
+use log::{info, warn};
+use signal_hook::{consts::SIGINT, iterator::Signals};
+use std::io::{self, Write};
+use std::path::PathBuf;
+use vopono_core::network::application_wrapper::ApplicationWrapper;
+use vopono_core::network::netns::{NamespaceSetupLock, NetworkNamespace};
+use vopono_core::network::stub_resolver::StubResolver;
+use vopono_core::util::{get_existing_namespaces, get_lock_namespaces};
+
+/// Join a network namespace set up by another vopono instance. Our own lockfile keeps the
+/// namespace alive until the returned namespace is dropped, and the last instance to exit tears
+/// it down as usual.
+pub fn join_namespace(name: String, command: &str) -> anyhow::Result<NetworkNamespace> {
+    // Held until our lockfile is written, so the namespace cannot be torn down in between
+    let setup_lock = NamespaceSetupLock::acquire(&name)?;
+    let locks = get_lock_namespaces()?;
+    let running = locks.contains_key(&name);
+    // Avoid triggering Drop for these namespaces
+    std::mem::forget(locks);
+    if !running || !get_existing_namespaces()?.contains(&name) {
+        bail!(
+            "No running vopono namespace named {}, see vopono list namespaces",
+            name
+        );
+    }
+
+    let ns = NetworkNamespace::from_existing(name)?;
+    let ns = ns.write_lockfile(command, None, None)?;
+    drop(setup_lock);
+    Ok(ns)
+}
+
+/// Run an application in a network namespace set up by another vopono instance
+pub fn attach(command: AttachCommand, silent: bool) -> anyhow::Result<()> {
+    let signals = Signals::new([SIGINT])?;
+    let ns = join_namespace(command.namespace, &command.application)?;
+    let _stub_resolver = StubResolver::start(&ns)?;
+
+    let application = ApplicationWrapper::new(
+        &ns,
+        &command.application,
+        command.user.or_else(|| std::env::var("SUDO_USER").ok()),
+        command.group,
+        command.working_directory.map(PathBuf::from),
+        None,
+        silent,
+        None,
+    )?;
+    let pid = application.handle.id();
+    info!(
+        "Application {} launched in network namespace {} with pid {}",
+        &command.application, &ns.name, pid
+    );
+    if let Err(e) = ns.record_application_pid(pid) {
+        warn!("Failed to record application PID in lockfile: {e:?}");
+    }
+    let output = application.wait_with_output()?;
+    io::stdout().write_all(output.stdout.as_slice())?;
+
+    // Allow daemons to leave namespace open
+    if vopono_core::util::check_process_running(pid) {
+        info!(
+            "Process {} still running, assumed to be daemon - will leave network namespace {} alive until ctrl+C received",
+            pid, &ns.name
+        );
+        stay_alive(Some(pid), signals);
+    } else if command.keep_alive {
+        info!(
+            "Keep-alive flag active - will leave network namespace {} alive until ctrl+C received",
+            &ns.name
+        );
+        stay_alive(None, signals);
+    }
+    Ok(())
+}
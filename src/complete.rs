@@ -0,0 +1,209 @@
+use super::args::{App, CompleteCommand, CompleteTarget, CompletionsCommand};
+use clap::CommandFactory;
+use clap_complete::Shell;
+use std::collections::BTreeSet;
+use std::io::Write;
+use strum::IntoEnumIterator;
+use vopono_core::config::providers::VpnProvider;
+use vopono_core::config::vpn::Protocol;
+use vopono_core::util::{get_configs_from_alias, get_lock_namespaces, vopono_dir};
+
+/// Completes synced servers, profiles and namespaces with vopono complete, and everything else
+/// (subcommands, options, providers and protocols) with the completion generated by clap
+const BASH_DYNAMIC: &str = r#"
+_vopono_dynamic() {
+    local cur=${COMP_WORDS[COMP_CWORD]} prev=${COMP_WORDS[COMP_CWORD - 1]}
+    local provider protocol i
+    if [[ ${COMP_WORDS[1]} == servers && $COMP_CWORD -gt 2 ]]; then
+        provider=${COMP_WORDS[2]}
+    fi
+    for ((i = 2; i < COMP_CWORD; i++)); do
+        case ${COMP_WORDS[i]} in
+            --provider | -p) provider=${COMP_WORDS[i + 1]} ;;
+            --protocol | -c) protocol=${COMP_WORDS[i + 1]} ;;
+        esac
+    done
+    case $prev in
+        --server | -s | --entry-server | --prefix)
+            if [[ -n $provider ]]; then
+                mapfile -t COMPREPLY < <(vopono complete servers "$provider" ${protocol:+"$protocol"} --prefix "$cur")
+                return
+            fi
+            ;;
+        --profile)
+            mapfile -t COMPREPLY < <(compgen -W "$(vopono complete profiles)" -- "$cur")
+            return
+            ;;
+    esac
+    if [[ $COMP_CWORD -eq 2 && $cur != -* ]]; then
+        case ${COMP_WORDS[1]} in
+            attach | dnsleak | status | stop)
+                mapfile -t COMPREPLY < <(compgen -W "$(vopono complete namespaces)" -- "$cur")
+                return
+                ;;
+        esac
+    fi
+    _vopono "$@"
+}
+
+complete -F _vopono_dynamic -o bashdefault -o default vopono
+"#;
+
+const FISH_DYNAMIC: &str = r#"
+function __vopono_option_value
+    set -l tokens (commandline -opc)
+    for i in (seq (count $tokens))
+        if contains -- $tokens[$i] $argv; and set -q tokens[(math $i + 1)]
+            echo $tokens[(math $i + 1)]
+        end
+    end
+end
+
+function __vopono_servers
+    set -l provider (__vopono_option_value --provider -p)[-1]
+    set -l protocol (__vopono_option_value --protocol -c)[-1]
+    set -l tokens (commandline -opc)
+    if test "$tokens[2]" = servers; and set -q tokens[3]
+        set provider $tokens[3]
+    end
+    test -n "$provider"; and vopono complete servers $provider $protocol
+end
+
+complete -c vopono -n "__fish_seen_subcommand_from exec desktop" -l server -s s -x -a "(__vopono_servers)"
+complete -c vopono -n "__fish_seen_subcommand_from exec" -l entry-server -x -a "(__vopono_servers)"
+complete -c vopono -n "__fish_seen_subcommand_from servers" -l prefix -s s -x -a "(__vopono_servers)"
+complete -c vopono -n "__fish_seen_subcommand_from exec desktop service" -l profile -x -a "(vopono complete profiles)"
+complete -c vopono -n "__fish_seen_subcommand_from attach dnsleak status stop" -f -a "(vopono complete namespaces)"
+"#;
+
+/// Completion script for the shell, generated from the command line definition
+fn completion_script(shell: Shell) -> String {
+    let mut script = Vec::new();
+    clap_complete::generate(shell, &mut App::command(), "vopono", &mut script);
+    let mut script = String::from_utf8_lossy(&script).into_owned();
+    match shell {
+        Shell::Bash => script.push_str(BASH_DYNAMIC),
+        Shell::Fish => script.push_str(FISH_DYNAMIC),
+        _ => {}
+    }
+    script
+}
+
+pub fn completions(command: CompletionsCommand) {
+    print!("{}", completion_script(command.shell));
+}
+
+/// Print completion candidates, one per line. Failures print nothing, as the output goes
+/// straight into the shell's completion list.
+pub fn complete(command: CompleteCommand) -> anyhow::Result<()> {
+    let candidates = match command.target {
+        CompleteTarget::Servers {
+            provider,
+            protocol,
+            prefix,
+        } => servers(
+            &provider,
+            protocol.as_deref(),
+            prefix.as_deref().unwrap_or_default(),
+        ),
+        CompleteTarget::Namespaces => namespaces(),
+        CompleteTarget::Profiles => profiles(),
+    };
+    let mut stdout = std::io::stdout().lock();
+    for candidate in candidates {
+        // The shell may stop reading early
+        if writeln!(stdout, "{candidate}").is_err() {
+            break;
+        }
+    }
+    Ok(())
+}
+
+fn parse_variant<T: IntoEnumIterator + std::fmt::Display>(name: &str) -> Option<T> {
+    T::iter().find(|x| x.to_string().eq_ignore_ascii_case(name))
+}
+
+/// Names of the synced configs, as given to --server
+fn servers(provider: &str, protocol: Option<&str>, prefix: &str) -> Vec<String> {
+    let Some(provider) = parse_variant::<VpnProvider>(provider) else {
+        return Vec::new();
+    };
+    let protocol = protocol.and_then(parse_variant::<Protocol>);
+    let mut dirs = Vec::new();
+    if protocol.as_ref().is_none_or(|x| *x == Protocol::OpenVpn)
+        && let Ok(dir) = provider
+            .get_dyn_openvpn_provider()
+            .and_then(|x| x.openvpn_dir())
+    {
+        dirs.push(dir);
+    }
+    if protocol.as_ref().is_none_or(|x| *x == Protocol::Wireguard)
+        && let Ok(dir) = provider
+            .get_dyn_wireguard_provider()
+            .and_then(|x| x.wireguard_dir())
+    {
+        dirs.push(dir);
+    }
+    dirs.iter()
+        .flat_map(|dir| get_configs_from_alias(dir, ""))
+        .filter_map(|x| x.file_stem().map(|x| x.to_string_lossy().to_string()))
+        .filter(|x| x.starts_with(prefix))
+        .collect::<BTreeSet<_>>()
+        .into_iter()
+        .collect()
+}
+
+fn namespaces() -> Vec<String> {
+    let Ok(namespaces) = get_lock_namespaces() else {
+        return Vec::new();
+    };
+    let mut names: Vec<String> = namespaces.keys().cloned().collect();
+    // Dropping the deserialized namespaces would tear them down
+    std::mem::forget(namespaces);
+    names.sort();
+    names
+}
+
+/// Sections of [profile.<name>] in the config file
+fn profiles() -> Vec<String> {
+    let Ok(path) = vopono_dir().map(|x| x.join("config.toml")) else {
+        return Vec::new();
+    };
+    let mut names: Vec<String> = config::Config::builder()
+        .add_source(config::File::from(path).required(false))
+        .build()
+        .and_then(|x| x.get_table("profile"))
+        .map(|x| x.into_keys().collect())
+        .unwrap_or_default();
+    names.sort();
+    names
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn generated_scripts() {
+        let bash = completion_script(Shell::Bash);
+        assert!(bash.contains("_vopono()"));
+        assert!(bash.contains("--server"));
+        assert!(bash.contains("PrivateInternetAccess Mullvad"));
+        assert!(bash.ends_with("complete -F _vopono_dynamic -o bashdefault -o default vopono\n"));
+        let fish = completion_script(Shell::Fish);
+        assert!(fish.contains("-l custom-netns-name"));
+        assert!(fish.contains("(vopono complete namespaces)"));
+        let zsh = completion_script(Shell::Zsh);
+        assert!(zsh.starts_with("#compdef vopono"));
+        assert!(!zsh.contains("_vopono_dynamic"));
+    }
+
+    #[test]
+    fn variant_names_ignore_case() {
+        assert_eq!(
+            parse_variant::<Protocol>("WIREGUARD"),
+            Some(Protocol::Wireguard)
+        );
+        assert_eq!(parse_variant::<VpnProvider>("nope"), None);
+    }
+}
@@ -0,0 +1,127 @@
+use super::args::DesktopCommand;
+use anyhow::{Context, anyhow, bail};
+use log::{info, warn};
+use std::path::PathBuf;
+use vopono_core::util::capabilities::capability_mode;
+use vopono_core::util::desktop_entry::{DesktopEntry, find_app_entry};
+
+/// Common askpass programs of desktop environments
+const ASKPASS_PROGRAMS: &[&str] = &[
+    "ssh-askpass",
+    "ksshaskpass",
+    "lxqt-openssh-askpass",
+    "/usr/lib/ssh/ssh-askpass",
+    "/usr/libexec/openssh/ssh-askpass",
+];
+
+fn find_askpass() -> Option<PathBuf> {
+    std::env::var("SUDO_ASKPASS")
+        .ok()
+        .map(PathBuf::from)
+        .or_else(|| ASKPASS_PROGRAMS.iter().find_map(|x| which::which(x).ok()))
+}
+
+/// File name of the launcher, e.g. vopono-firefox-mullvad-de.desktop
+fn file_name(parts: &[&str]) -> String {
+    let slug = parts
+        .iter()
+        .filter(|x| !x.is_empty())
+        .map(|x| {
+            x.to_lowercase()
+                .chars()
+                .map(|c| if c.is_ascii_alphanumeric() { c } else { '-' })
+                .collect::<String>()
+        })
+        .collect::<Vec<_>>()
+        .join("-");
+    format!("vopono-{slug}.desktop")
+}
+
+pub fn desktop(command: DesktopCommand) -> anyhow::Result<()> {
+    if command.profile.is_none() && command.provider.is_none() {
+        bail!("Either --profile or --provider must be given");
+    }
+    let words = shell_words::split(&command.application)?;
+    let executable = words
+        .first()
+        .ok_or_else(|| anyhow!("No application given"))?;
+    let app_id = command
+        .desktop_id
+        .as_deref()
+        .unwrap_or_else(|| executable.rsplit('/').next().unwrap_or(executable.as_str()));
+    let app_entry = find_app_entry(app_id).unwrap_or_default();
+    let app_name = app_entry.name.unwrap_or_else(|| app_id.to_string());
+
+    let mut exec = vec![std::env::current_exe()?.to_string_lossy().to_string()];
+    if !capability_mode() {
+        match command.askpass.or_else(find_askpass) {
+            Some(askpass) => {
+                exec.splice(
+                    0..0,
+                    [
+                        "env".to_string(),
+                        format!("SUDO_ASKPASS={}", askpass.display()),
+                    ],
+                );
+                exec.push("-A".to_string());
+            }
+            None => warn!(
+                "No askpass program found (set SUDO_ASKPASS or use --askpass), the launcher needs passwordless sudo for vopono"
+            ),
+        }
+    }
+    exec.push("exec".to_string());
+    let via = if let Some(profile) = &command.profile {
+        exec.extend(["--profile".to_string(), profile.clone()]);
+        profile.clone()
+    } else {
+        let provider = command.provider.as_ref().unwrap().to_variant();
+        exec.extend(["--provider".to_string(), provider.to_string()]);
+        match &command.server {
+            Some(server) => format!("{provider} {}", server.to_uppercase()),
+            None => provider.to_string(),
+        }
+    };
+    if let Some(protocol) = &command.protocol {
+        exec.extend(["--protocol".to_string(), protocol.to_variant().to_string()]);
+    }
+    if let Some(server) = &command.server {
+        exec.extend(["--server".to_string(), server.clone()]);
+    }
+    exec.push(command.application.clone());
+
+    let entry = DesktopEntry {
+        name: command
+            .name
+            .unwrap_or_else(|| format!("{app_name} (via {via})")),
+        comment: format!("Run {app_name} through vopono"),
+        icon: command.icon.or(app_entry.icon),
+        exec,
+    };
+    if command.stdout {
+        print!("{entry}");
+        return Ok(());
+    }
+    let output_dir = match command.output_dir {
+        Some(dir) => dir,
+        None => directories_next::BaseDirs::new()
+            .ok_or_else(|| anyhow!("Failed to find the home directory"))?
+            .data_dir()
+            .join("applications"),
+    };
+    std::fs::create_dir_all(&output_dir)?;
+    let path = output_dir.join(file_name(&[
+        app_id,
+        command.profile.as_deref().unwrap_or_default(),
+        &command
+            .provider
+            .map(|x| x.to_variant().to_string())
+            .unwrap_or_default(),
+        command.server.as_deref().unwrap_or_default(),
+    ]));
+    std::fs::write(&path, entry.to_string())
+        .with_context(|| format!("Failed to write {}", path.display()))?;
+    info!("Wrote {}", path.display());
+    println!("Added {} to the application menu", entry.name);
+    Ok(())
+}
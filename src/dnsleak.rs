@@ -0,0 +1,70 @@
+use super::args::DnsLeakCommand;
+use super::attach::join_namespace;
+use anyhow::bail;
+use std::net::IpAddr;
+use vopono_core::network::dns_leak::dns_leak_test;
+
+fn join_addresses(addresses: &[IpAddr]) -> String {
+    if addresses.is_empty() {
+        return "no answer".to_string();
+    }
+    addresses
+        .iter()
+        .map(|x| x.to_string())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Run the DNS leak test in a running namespace and print the results, failing if a leak is found
+pub fn dnsleak(command: DnsLeakCommand) -> anyhow::Result<()> {
+    let ns = join_namespace(command.namespace, "vopono dnsleak")?;
+    let report = dns_leak_test(&ns)?;
+
+    println!("DNS leak test for network namespace {}", ns.name);
+    for resolver in report.resolvers.iter() {
+        println!(
+            "Resolver in resolv.conf:\t{} (via {})",
+            resolver.server,
+            resolver.interface.as_deref().unwrap_or("no route")
+        );
+    }
+    if !report.upstreams.is_empty() {
+        println!(
+            "Stub resolver upstreams:\t{}",
+            report
+                .upstreams
+                .iter()
+                .map(|x| x.to_string())
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+    }
+    println!(
+        "Tunnel interface:\t\t{}",
+        report.tunnel_interface.as_deref().unwrap_or("none")
+    );
+    println!(
+        "Public address:\t\t\tnamespace {}; host {}",
+        join_addresses(&report.namespace_public),
+        join_addresses(&report.host_public)
+    );
+    println!(
+        "Resolvers seen by services:\tnamespace {}; host {}",
+        join_addresses(&report.namespace_resolvers_seen),
+        join_addresses(&report.host_resolvers_seen)
+    );
+
+    let leaks = report.leaks();
+    for leak in leaks.iter() {
+        println!("LEAK: {leak}");
+    }
+    if !leaks.is_empty() {
+        bail!("DNS leak detected in network namespace {}", ns.name);
+    }
+    if report.conclusive() {
+        println!("No DNS leak detected");
+    } else {
+        println!("Inconclusive: the leak test services did not answer from the namespace");
+    }
+    Ok(())
+}
@@ -0,0 +1,320 @@
+use super::args_config::ArgsConfig;
+use super::exec::{add_killswitches, candidate_configs, killswitch_allowed, provider_config_dir};
+use std::mem::ManuallyDrop;
+use std::net::{IpAddr, Ipv4Addr};
+use std::path::PathBuf;
+use vopono_core::config::providers::VpnProvider;
+use vopono_core::config::vpn::Protocol;
+use vopono_core::network::egress::egress_allowlist;
+use vopono_core::network::netns::{NetworkNamespace, VethPairIPs};
+use vopono_core::network::network_interface::NetworkInterface;
+use vopono_core::network::sysctl::SysCtl;
+use vopono_core::network::veth_pair::VethPair;
+use vopono_core::network::wireguard::Wireguard;
+use vopono_core::util::dry_run::{command_line, set_dry_run, take_plan};
+use vopono_core::util::{
+    choose_config, get_existing_namespaces, get_target_subnet, get_username, open_hosts,
+    open_subnets, set_veth_subnet, veth_address, vopono_dir,
+};
+
+/// Print the commands recorded so far and the given ones under a heading
+fn section(title: &str, extra: &[String]) {
+    println!("\n# {title}");
+    for command in take_plan().iter().chain(extra) {
+        println!("{command}");
+    }
+}
+
+/// Config files of the exit (and entry) server
+fn planned_configs(parsed_command: &ArgsConfig) -> anyhow::Result<Vec<PathBuf>> {
+    if parsed_command.provider == VpnProvider::Custom {
+        return Ok(parsed_command.custom.iter().cloned().collect());
+    }
+    if matches!(
+        parsed_command.provider,
+        VpnProvider::None | VpnProvider::Warp
+    ) {
+        return Ok(Vec::new());
+    }
+    let cdir = provider_config_dir(parsed_command)?;
+    let select_config = |alias: &str| -> anyhow::Result<PathBuf> {
+        choose_config(&candidate_configs(parsed_command, &cdir, alias)?, alias)
+    };
+    let mut configs = vec![select_config(&parsed_command.server)?];
+    if let Some(entry_server) = parsed_command.entry_server.as_ref() {
+        configs.push(select_config(entry_server)?);
+    }
+    Ok(configs)
+}
+
+/// Print what vopono exec would do for the command, without changing anything
+pub fn print_plan(parsed_command: &ArgsConfig, ns_name: &str) -> anyhow::Result<()> {
+    let configs = planned_configs(parsed_command)?;
+    println!("Provider:\t{}", parsed_command.provider);
+    println!("Protocol:\t{}", parsed_command.protocol);
+    println!("Server:\t\t{}", parsed_command.server);
+    for (config, role) in configs.iter().zip(["Config", "Entry config"]) {
+        println!("{role}:\t{}", config.display());
+    }
+    println!("Namespace:\t{ns_name}");
+    println!("Interface:\t{}", parsed_command.interface.name);
+    println!("Firewall:\t{}", parsed_command.firewall);
+    let application = command_line(&[
+        "ip",
+        "netns",
+        "exec",
+        ns_name,
+        "sudo",
+        "-Eu",
+        &parsed_command.user.clone().map_or_else(get_username, Ok)?,
+        &parsed_command.application,
+    ]);
+
+    if get_existing_namespaces()?.contains(&ns_name.to_string()) {
+        println!("\nNamespace {ns_name} is already running, vopono would attach to it and run:");
+        println!("{application}");
+        return Ok(());
+    }
+
+    set_dry_run(true);
+    let result = print_setup(parsed_command, ns_name, configs.first());
+    set_dry_run(false);
+    result?;
+    if !parsed_command.create_netns_only {
+        section("Application", &[application]);
+    }
+    Ok(())
+}
+
+fn print_setup(
+    parsed_command: &ArgsConfig,
+    ns_name: &str,
+    config: Option<&PathBuf>,
+) -> anyhow::Result<()> {
+    // Never dropped, which would tear down a namespace of the same name
+    let mut ns = ManuallyDrop::new(NetworkNamespace::new(
+        ns_name.to_string(),
+        parsed_command.provider.clone(),
+        parsed_command.protocol.clone(),
+        parsed_command.firewall,
+        None,
+        None,
+        None,
+    )?);
+    if let Some(subnet) = parsed_command.veth_subnet {
+        set_veth_subnet(subnet)?;
+    }
+    let target_subnet = get_target_subnet()?;
+    let source = parsed_command
+        .veth_netns_name
+        .clone()
+        .unwrap_or_else(|| format!("{ns_name}_s"));
+    let dest = parsed_command
+        .veth_host_name
+        .clone()
+        .unwrap_or_else(|| format!("{ns_name}_d"));
+    let host_ip = veth_address(target_subnet, 1);
+    let namespace_ip = veth_address(target_subnet, 2);
+    let line = |args: &[&str]| command_line(args);
+    let mut setup = vec![
+        line(&[
+            "ip",
+            "-n",
+            ns_name,
+            "addr",
+            "add",
+            "127.0.0.1/8",
+            "dev",
+            "lo",
+        ]),
+        line(&["ip", "-n", ns_name, "link", "set", "lo", "up"]),
+        line(&[
+            "ip", "link", "add", &dest, "type", "veth", "peer", "name", &source,
+        ]),
+        line(&["ip", "link", "set", &source, "netns", ns_name]),
+        line(&["ip", "link", "set", &dest, "up"]),
+        line(&["ip", "-n", ns_name, "link", "set", &source, "up"]),
+    ];
+    if parsed_command.bridge {
+        setup.push("(--bridge: the host end is attached to the vopono bridge instead)".to_string());
+    } else {
+        let host_net = format!("{host_ip}/24");
+        let namespace_net = format!("{namespace_ip}/24");
+        let gateway = host_ip.to_string();
+        setup.push(line(&["ip", "addr", "add", &host_net, "dev", &dest]));
+        setup.push(line(&[
+            "ip",
+            "-n",
+            ns_name,
+            "addr",
+            "add",
+            &namespace_net,
+            "dev",
+            &source,
+        ]));
+        setup.push(line(&[
+            "ip", "-n", ns_name, "route", "add", "default", "via", &gateway, "dev", &source,
+        ]));
+        for host in parsed_command.open_hosts.iter().flatten() {
+            let host = host.to_string();
+            setup.push(line(&[
+                "ip", "-n", ns_name, "route", "add", &host, "via", &gateway, "dev", &source,
+            ]));
+        }
+    }
+    if parsed_command.disable_ipv6 {
+        setup.push(line(&[
+            "ip",
+            "netns",
+            "exec",
+            ns_name,
+            "sysctl",
+            "-q",
+            "net.ipv6.conf.all.disable_ipv6=1",
+        ]));
+    }
+    section("Namespace", &setup);
+
+    ns.veth_pair = Some(VethPair {
+        source: source.clone(),
+        dest: dest.clone(),
+        nm_unmanaged: None,
+        networkd_unmanaged: None,
+    });
+    let veth_ips = VethPairIPs {
+        host_ip: IpAddr::V4(host_ip),
+        namespace_ip: IpAddr::V4(namespace_ip),
+        host_ipv6: None,
+        namespace_ipv6: None,
+    };
+    let allowed = killswitch_allowed(parsed_command, &veth_ips);
+    ns.veth_pair_ips = Some(veth_ips);
+    if !parsed_command.no_killswitch {
+        add_killswitches(parsed_command, &ns, &allowed)?;
+    }
+    if let Some(ref rules) = parsed_command.allow_outbound {
+        egress_allowlist(&ns, rules, parsed_command.firewall)?;
+    }
+    if !parsed_command.bridge {
+        ns.add_host_masquerade(
+            target_subnet,
+            parsed_command.interface.clone(),
+            parsed_command.firewall,
+        )?;
+        ns.add_firewall_exception(
+            parsed_command.interface.clone(),
+            NetworkInterface::new(dest.clone())?,
+            parsed_command.firewall,
+        )?;
+    }
+    std::mem::forget(SysCtl::enable_ipv4_forwarding()?);
+    section("Firewall", &[]);
+
+    let wireguard = match (config, &parsed_command.protocol) {
+        (Some(config), Protocol::Wireguard) => Some(Wireguard::config_from_file(config)?),
+        _ => None,
+    };
+    let dns = parsed_command
+        .dns
+        .clone()
+        .or_else(|| wireguard.as_ref().and_then(|x| x.interface.dns.clone()))
+        .or_else(|| {
+            parsed_command
+                .provider
+                .get_dyn_openvpn_provider()
+                .ok()
+                .and_then(|x| x.provider_dns())
+        })
+        .unwrap_or_else(|| vec![IpAddr::V4(Ipv4Addr::new(8, 8, 8, 8))]);
+    let mut dns_lines: Vec<String> = dns
+        .iter()
+        .map(|x| format!("/etc/netns/{ns_name}/resolv.conf: nameserver {x}"))
+        .collect();
+    if parsed_command.dns_over_tls.is_some()
+        || parsed_command.dns_over_https.is_some()
+        || parsed_command.dns_cache
+        || parsed_command.systemd_resolved
+    {
+        dns_lines.push("(stub resolver in the namespace answering on the nameserver)".to_string());
+    }
+    section("DNS", &dns_lines);
+
+    let mut vpn = Vec::new();
+    match (&parsed_command.protocol, config) {
+        (Protocol::Wireguard, Some(config)) => {
+            let wg = wireguard.expect("Wireguard config parsed above");
+            let if_name = &ns_name[ns_name.len().saturating_sub(13)..];
+            vpn.push(line(&[
+                "ip",
+                "netns",
+                "exec",
+                ns_name,
+                "ip",
+                "link",
+                "add",
+                if_name,
+                "type",
+                "wireguard",
+            ]));
+            vpn.push(line(&[
+                "ip",
+                "netns",
+                "exec",
+                ns_name,
+                "wg",
+                "setconf",
+                if_name,
+                &config.to_string_lossy(),
+            ]));
+            for address in wg.interface.address.iter() {
+                let address = address.to_string();
+                vpn.push(line(&[
+                    "ip", "netns", "exec", ns_name, "ip", "addr", "add", &address, "dev", if_name,
+                ]));
+            }
+            vpn.push(line(&[
+                "ip", "netns", "exec", ns_name, "ip", "link", "set", if_name, "up",
+            ]));
+            vpn.push(format!(
+                "(default route via {if_name} to endpoint {}, with the Wireguard killswitch)",
+                wg.peer.endpoint
+            ));
+        }
+        (Protocol::OpenVpn, Some(config)) => {
+            let log = vopono_dir()?.join(format!("logs/{ns_name}_openvpn.log"));
+            vpn.push(line(&[
+                "ip",
+                "netns",
+                "exec",
+                ns_name,
+                "openvpn",
+                "--config",
+                &config.to_string_lossy(),
+                "--machine-readable-output",
+                "--log",
+                &log.to_string_lossy(),
+            ]));
+            vpn.push("(with the provider credentials and the OpenVPN killswitch)".to_string());
+        }
+        (Protocol::None, _) => {}
+        (protocol, config) => vpn.push(format!(
+            "{protocol} client in {ns_name}{}",
+            config
+                .map(|x| format!(" with {}", x.display()))
+                .unwrap_or_default()
+        )),
+    }
+    section("VPN", &vpn);
+
+    // Exceptions in the VPN killswitch, once it is up
+    if parsed_command.open_hosts.is_some() || parsed_command.allow_lan.is_some() {
+        if let Some(ref hosts) = parsed_command.open_hosts {
+            open_hosts(ns_name, hosts, parsed_command.firewall)?;
+        }
+        if let Some(ref subnets) = parsed_command.allow_lan {
+            open_subnets(ns_name, subnets, parsed_command.firewall)?;
+        }
+        section("Open hosts", &[]);
+    }
+    Ok(())
+}
@@ -1,33 +1,71 @@
 use crate::args_config::ArgsConfig;
 
 use super::args::ExecCommand;
+use super::cli_client::CliClient;
 use super::sync::synch;
 use anyhow::{anyhow, bail};
+use ipnet::IpNet;
 use log::{debug, error, info, warn};
 use signal_hook::iterator::SignalsInfo;
 use signal_hook::{consts::SIGINT, iterator::Signals};
-use std::net::{IpAddr, Ipv4Addr};
-use std::path::PathBuf;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::{Duration, Instant};
 use std::{
     fs::create_dir_all,
     io::{self, Write},
 };
-use vopono_core::config::providers::{UiClient, VpnProvider};
+use vopono_core::config::providers::ivpn::IVPN;
+use vopono_core::config::providers::mullvad::{Mullvad, RelayFilter};
+use vopono_core::config::providers::protonvpn::ProtonVPN;
+use vopono_core::config::providers::{NonInteractiveClient, Password, UiClient, VpnProvider};
 use vopono_core::config::vpn::{Protocol, verify_auth};
+use vopono_core::network::app_cgroup::AppCgroup;
 use vopono_core::network::application_wrapper::ApplicationWrapper;
-use vopono_core::network::netns::NetworkNamespace;
+use vopono_core::network::bridge::Bridge;
+use vopono_core::network::discovery_relay::{DISCOVERY_GROUPS, DiscoveryRelay};
+use vopono_core::network::dns_config::DnsConfig;
+use vopono_core::network::egress::egress_allowlist;
+use vopono_core::network::firewall::{
+    Firewall, disable_ipv6, inbound_killswitch, iptables_snapshot, iptables_undo_since,
+    protocol_drops_ipv6, veth_killswitch,
+};
+use vopono_core::network::ip_check::verify_exit_ip;
+use vopono_core::network::leak_audit::LeakAudit;
+use vopono_core::network::mtu;
+use vopono_core::network::netns::{Lockfile, NamespaceSetupLock, NetworkNamespace, VethPairIPs};
 use vopono_core::network::network_interface::NetworkInterface;
+use vopono_core::network::obfuscation::ObfuscationProtocol;
+use vopono_core::network::openvpn::{OpenVpnAuthFailed, OpenVpnUdpTimeout};
 use vopono_core::network::port_forwarding::Forwarder;
 use vopono_core::network::port_forwarding::azirevpn::AzireVpnPortForwarding;
 use vopono_core::network::port_forwarding::natpmpc::Natpmpc;
 use vopono_core::network::port_forwarding::piapf::Piapf;
-use vopono_core::network::shadowsocks::uses_shadowsocks;
+use vopono_core::network::port_publish::PortPublish;
+use vopono_core::network::reconnect::ReconnectMonitor;
+use vopono_core::network::rotation::ServerRotation;
+use vopono_core::network::shadowsocks::{ShadowsocksServer, uses_shadowsocks};
+use vopono_core::network::stub_resolver::StubResolver;
 use vopono_core::network::sysctl::SysCtl;
+use vopono_core::network::tor::TOR_SOCKS_PORT;
 use vopono_core::network::trojan::trojan_config::TrojanConfig;
+use vopono_core::network::watchdog::Watchdog;
 use vopono_core::network::wireguard::Wireguard;
+use vopono_core::util::credentials::{set_session_credentials, supplied_credentials};
 use vopono_core::util::env_vars::set_env_vars;
-use vopono_core::util::{get_config_from_alias, get_existing_namespaces, get_target_subnet};
-use vopono_core::util::{parse_command_str, vopono_dir};
+use vopono_core::util::events::{Event, emit};
+use vopono_core::util::hooks::{Hook, hook_command, run_blocking};
+use vopono_core::util::keyring::is_runtime_auth_file;
+use vopono_core::util::server_cache::warn_if_stale;
+use vopono_core::util::server_filter::choose_random_server;
+use vopono_core::util::sync_filter::SyncFilter;
+use vopono_core::util::vopono_dir;
+use vopono_core::util::{
+    choose_config, configs_age, get_configs_from_alias, get_existing_namespaces,
+    get_lock_namespaces, get_target_subnet, set_veth_subnet,
+};
 
 pub fn exec(
     command: ExecCommand,
@@ -46,6 +84,23 @@ pub fn exec(
 
     let mut parsed_command = ArgsConfig::get_cli_or_config_args(command, vopono_config_settings)?;
 
+    if parsed_command.detach && !parsed_command.dry_run && std::env::var(DETACHED_ENV).is_err() {
+        if parsed_command.prompt_credentials {
+            bail!("--prompt-credentials cannot be used with --detach");
+        }
+        return detach();
+    }
+
+    if parsed_command.prompt_credentials {
+        let provider = parsed_command.provider.get_dyn_openvpn_provider().map_err(|_| {
+            anyhow!(
+                "--prompt-credentials requires a provider with username and password authentication"
+            )
+        })?;
+        let (user, pass) = provider.prompt_for_auth(uiclient)?;
+        set_session_credentials(&provider.alias(), &user, &pass);
+    }
+
     if parsed_command.provider != VpnProvider::Custom
         && parsed_command.provider != VpnProvider::None
         && parsed_command.protocol != Protocol::Warp
@@ -61,23 +116,66 @@ pub fn exec(
                 .get_dyn_wireguard_provider()?
                 .wireguard_dir(),
             Protocol::Warp => unreachable!("Unreachable, Warp must use Warp provider"),
+            Protocol::Xray => bail!("Xray must use Custom provider"),
+            Protocol::Socks5 | Protocol::HttpProxy => {
+                bail!("Upstream proxies must use Custom provider")
+            }
+            Protocol::Tor => bail!("Tor must use Custom provider"),
+            Protocol::Ikev2 => bail!("IKEv2 must use Custom provider"),
             Protocol::OpenConnect => bail!("OpenConnect must use Custom provider"),
             Protocol::OpenFortiVpn => bail!("OpenFortiVpn must use Custom provider"),
             Protocol::None => bail!("None protocol must use None provider"),
         }?;
-        if !cdir.exists() || cdir.read_dir()?.next().is_none() {
-            info!(
-                "Config files for {} {} do not exist, running vopono sync",
-                parsed_command.provider, parsed_command.protocol
+        let missing = !cdir.exists() || cdir.read_dir()?.next().is_none();
+        let stale = !missing
+            && parsed_command.config_max_age.is_some_and(|days| {
+                configs_age(&cdir).is_some_and(|age| age > Duration::from_secs(days * 24 * 60 * 60))
+            });
+        if missing && parsed_command.dry_run {
+            bail!(
+                "Config files for {} {} do not exist, run vopono sync first",
+                parsed_command.provider,
+                parsed_command.protocol
             );
-            synch(
-                parsed_command.provider.clone(),
-                &Some(parsed_command.protocol.clone()),
-                uiclient,
-            )?;
+        }
+        if (missing || stale) && !parsed_command.dry_run {
+            if missing {
+                info!(
+                    "Config files for {} {} do not exist, running vopono sync",
+                    parsed_command.provider, parsed_command.protocol
+                );
+                synch(
+                    parsed_command.provider.clone(),
+                    &Some(parsed_command.protocol.clone()),
+                    uiclient,
+                    &SyncFilter::default(),
+                )?;
+            } else {
+                info!(
+                    "Config files for {} {} are older than {} days, running vopono sync",
+                    parsed_command.provider,
+                    parsed_command.protocol,
+                    parsed_command.config_max_age.unwrap_or_default()
+                );
+                // Keeps the filters from the last sync, and never prompts in the middle of exec
+                if let Err(e) = synch(
+                    parsed_command.provider.clone(),
+                    &Some(parsed_command.protocol.clone()),
+                    &NonInteractiveClient {},
+                    &SyncFilter::load(&cdir),
+                ) {
+                    warn!(
+                        "Failed to re-sync stale configs ({e:#}), using the existing configs. Run vopono sync to update them"
+                    );
+                }
+            }
         }
     }
 
+    if parsed_command.random_server {
+        parsed_command.server = random_server(&parsed_command)?;
+    }
+
     let alias = match parsed_command.provider {
         VpnProvider::Custom => "c".to_string(),
         VpnProvider::None => "none".to_string(),
@@ -95,19 +193,53 @@ pub fn exec(
         format!("vo_{alias}_{short_name}")
     };
 
+    if parsed_command.dry_run {
+        return super::dry_run::print_plan(&parsed_command, &ns_name);
+    }
+
     let mut ns;
     let _sysctl;
+    let _sysctl_ipv6;
 
     let _using_existing_netns;
     let forwarder;
+    let _stub_resolver;
+    // Held until our lockfile is written, so a concurrent run for the same namespace waits and
+    // then attaches to it
+    let setup_lock = NamespaceSetupLock::acquire(&ns_name)?;
     if get_existing_namespaces()?.contains(&ns_name) {
         // If namespace exists, read its lock config
+        let instances = get_lock_namespaces()?
+            .get(&ns_name)
+            .map(|x| x.len())
+            .unwrap_or_default();
         info!(
-            "Using existing namespace: {}, will not modify firewall rules",
-            &ns_name
+            "Attaching to existing namespace: {} (used by {} other vopono instances), will not modify firewall rules",
+            &ns_name, instances
         );
         ns = NetworkNamespace::from_existing(ns_name)?;
         _using_existing_netns = true;
+        emit(Event::NamespaceAttached {
+            namespace: &ns.name,
+        });
+
+        let same_config = match (&ns.config_file, &parsed_command.custom) {
+            (Some(existing), Some(custom)) if parsed_command.provider == VpnProvider::Custom => {
+                existing.canonicalize().ok() == custom.canonicalize().ok()
+            }
+            _ => true,
+        };
+        if ns.provider != parsed_command.provider
+            || ns.protocol != parsed_command.protocol
+            || !same_config
+        {
+            bail!(
+                "Namespace {} is already running a {} {} connection, use --custom-netns-name to run this in a separate namespace",
+                &ns.name,
+                ns.provider,
+                ns.protocol
+            );
+        }
 
         if parsed_command.port_forwarding || parsed_command.custom_port_forwarding.is_some() {
             warn!(
@@ -117,6 +249,7 @@ pub fn exec(
         }
 
         forwarder = None;
+        _stub_resolver = StubResolver::start(&ns)?;
     } else {
         // Create new network namespace
         _using_existing_netns = false;
@@ -129,9 +262,24 @@ pub fn exec(
             parsed_command.user.clone(),
             parsed_command.group.clone(),
         )?;
+        emit(Event::NamespaceCreated {
+            namespace: &ns.name,
+            provider: ns.provider.to_string(),
+            protocol: ns.protocol.to_string(),
+        });
+        if let Some(subnet) = parsed_command.veth_subnet {
+            set_veth_subnet(subnet)?;
+        }
         let target_subnet = get_target_subnet()?;
+        ns.postdown = parsed_command.postdown.clone();
         ns.add_loopback()?;
-        ns.add_veth_pair()?;
+        ns.add_veth_pair(
+            parsed_command.veth_host_name.clone(),
+            parsed_command.veth_netns_name.clone(),
+        )?;
+        if let Some(rate_limit) = parsed_command.rate_limit {
+            rate_limit.apply(&ns)?;
+        }
 
         // Allow direct access to Trojan forwarding server if using Trojan
         if parsed_command.trojan_host.is_some() || parsed_command.trojan_config.is_some() {
@@ -150,11 +298,20 @@ pub fn exec(
 
         // Note here open_hosts is passed in so that direct routes are added
         // Note this is not wanted when the traffic needs to be routed through the VPN
-        ns.add_routing(
-            target_subnet,
-            parsed_command.open_hosts.as_ref(),
-            parsed_command.allow_host_access,
-        )?;
+        if parsed_command.bridge {
+            ns.add_bridge_routing(
+                parsed_command.open_hosts.as_ref(),
+                parsed_command.interface.clone(),
+                parsed_command.firewall,
+            )?;
+        } else {
+            ns.add_routing(
+                target_subnet,
+                parsed_command.open_hosts.as_ref(),
+                parsed_command.allow_host_access,
+                parsed_command.ipv6,
+            )?;
+        }
 
         // Add local host to open hosts if allow_host_access enabled
         if parsed_command.allow_host_access {
@@ -167,62 +324,182 @@ pub fn exec(
             }
         }
 
-        ns.add_host_masquerade(
-            target_subnet,
-            parsed_command.interface.clone(),
-            parsed_command.firewall,
-        )?;
-        ns.add_firewall_exception(
-            parsed_command.interface.clone(),
-            NetworkInterface::new(ns.veth_pair.as_ref().unwrap().dest.clone())?,
-            parsed_command.firewall,
-        )?;
+        if let Some(ref subnets) = parsed_command.allow_lan {
+            ns.add_lan_routes(subnets)?;
+        }
+
+        // Run PreUp script (if any), once the veth routing is set up (so VOPONO_NS_IP and
+        // VOPONO_HOST_IP are set) but before the killswitch and VPN
+        if let Some(ref pucmd) = parsed_command.preup {
+            let mut cmd = hook_command(
+                Hook::PreUp,
+                pucmd,
+                parsed_command.user.as_deref(),
+                parsed_command.group.as_deref(),
+            )?;
+            set_env_vars(&ns, None, &mut cmd);
+            run_blocking(cmd, Hook::PreUp)?;
+        }
+
+        // Block applications from leaving via the veth before the VPN is up, so nothing leaks
+        // while it connects or if it dies
+        let allowed = killswitch_allowed(&parsed_command, ns.veth_pair_ips.as_ref().unwrap());
+        if !parsed_command.no_killswitch {
+            add_killswitches(&parsed_command, &ns, &allowed)?;
+        }
+        if parsed_command.disable_ipv6 {
+            ns.disable_ipv6()?;
+            if !protocol_drops_ipv6(&parsed_command.protocol, !parsed_command.no_killswitch) {
+                disable_ipv6(&ns, parsed_command.firewall)?;
+            }
+        }
+        if let Some(ref rules) = parsed_command.allow_outbound {
+            egress_allowlist(&ns, rules, parsed_command.firewall)?;
+        }
+        // Added after the killswitch so its rules come first
+        if parsed_command.audit_leaks {
+            // Root-owned sockets are only the VPN client's if the application runs as another user
+            let app_is_root = parsed_command.user.as_deref().is_none_or(|user| {
+                nix::unistd::User::from_name(user)
+                    .ok()
+                    .flatten()
+                    .is_none_or(|u| u.uid.is_root())
+            });
+            ns.leak_audit = Some(LeakAudit::start(
+                &ns,
+                &ns.veth_pair.as_ref().unwrap().source,
+                &allowed,
+                !app_is_root,
+                parsed_command.firewall,
+            )?);
+        }
+
+        // The bridge has its own masquerade rule and firewall exceptions
+        if !parsed_command.bridge {
+            ns.add_host_masquerade(
+                target_subnet,
+                parsed_command.interface.clone(),
+                parsed_command.firewall,
+            )?;
+            ns.add_firewall_exception(
+                parsed_command.interface.clone(),
+                NetworkInterface::new(ns.veth_pair.as_ref().unwrap().dest.clone())?,
+                parsed_command.firewall,
+            )?;
+        }
         _sysctl = SysCtl::enable_ipv4_forwarding();
+        _sysctl_ipv6 = if parsed_command.ipv6 {
+            Some(SysCtl::enable_ipv6_forwarding(
+                &parsed_command.interface.name,
+            )?)
+        } else {
+            None
+        };
 
-        let config_file = run_protocol_in_netns(&parsed_command, &mut ns, uiclient, verbose)?;
+        let config_file =
+            run_protocol_with_failover(&parsed_command, &mut ns, uiclient, verbose, 0, None)?;
         ns.set_config_file(config_file);
+        let upstreams: Vec<_> = parsed_command
+            .dns_over_tls
+            .iter()
+            .chain(parsed_command.dns_over_https.iter())
+            .flatten()
+            .cloned()
+            .collect();
+        if !upstreams.is_empty()
+            || parsed_command.systemd_resolved
+            || parsed_command.dns_cache
+            || parsed_command.dns_overrides.is_some()
+            || parsed_command.dns_forwards.is_some()
+        {
+            ns.use_stub_resolver(
+                upstreams,
+                parsed_command.dns_overrides.clone().unwrap_or_default(),
+                parsed_command.dns_forwards.clone().unwrap_or_default(),
+                parsed_command.hosts.as_ref(),
+                parsed_command.allow_host_access,
+            )?;
+        }
+        if parsed_command.systemd_resolved {
+            ns.use_systemd_resolved()?;
+        }
+        if parsed_command.dnscrypt_proxy {
+            ns.run_dnscrypt_proxy(
+                parsed_command
+                    .dnscrypt_servers
+                    .as_deref()
+                    .unwrap_or_default(),
+                parsed_command.hosts.as_ref(),
+                parsed_command.allow_host_access,
+            )?;
+        }
+        _stub_resolver = StubResolver::start(&ns)?;
+        if parsed_command.provider != VpnProvider::None {
+            tune_tunnel_mtu(&parsed_command, &ns)?;
+            verify_ip(&parsed_command, &ns)?;
+            emit(Event::TunnelUp {
+                namespace: &ns.name,
+                protocol: ns.protocol.to_string(),
+                interface: mtu::tunnel_interface(&ns.name).ok(),
+            });
+        }
 
         if let Some(ref hosts) = parsed_command.open_hosts {
             vopono_core::util::open_hosts(&ns.name, hosts, parsed_command.firewall)?;
         }
+        if let Some(ref subnets) = parsed_command.allow_lan {
+            vopono_core::util::open_subnets(&ns.name, subnets, parsed_command.firewall)?;
+        }
+        if parsed_command.bridge_peers {
+            Bridge::allow_peers(&ns, parsed_command.firewall)?;
+        }
 
         forwarder = provider_port_forwarding(&parsed_command, &ns)?;
+        if let Some(fwd) = forwarder.as_ref() {
+            emit(Event::PortForwarded {
+                namespace: &ns.name,
+                port: fwd.forwarded_port(),
+            });
+        }
 
         // Run PostUp script (if any)
         // Temporarily set env var referring to this network namespace name
-        if let Some(pucmd) = parsed_command.postup.clone() {
-            let mut sudo_args = Vec::new();
-            if let Some(ref user) = parsed_command.user {
-                sudo_args.push("--user");
-                sudo_args.push(user);
-            }
-            if let Some(ref group) = parsed_command.group {
-                sudo_args.push("--group");
-                sudo_args.push(group);
-            }
-
-            let parsed_pucmd = parse_command_str(&pucmd)?;
-            let parsed_pucmd_ptrs: Vec<&str> = parsed_pucmd.iter().map(|s| s.as_str()).collect();
-
-            if !sudo_args.is_empty() {
-                let mut args = vec!["--preserve-env"];
-                args.append(&mut sudo_args);
-                args.extend(parsed_pucmd_ptrs);
-
-                let mut cmd = std::process::Command::new("sudo");
-                cmd.args(args);
-                set_env_vars(&ns, forwarder.as_deref(), &mut cmd);
-                cmd.spawn()?;
-            } else {
-                let mut cmd = std::process::Command::new(parsed_pucmd_ptrs[0]);
-                cmd.args(parsed_pucmd_ptrs[1..].iter());
-                set_env_vars(&ns, forwarder.as_deref(), &mut cmd);
-                cmd.spawn()?;
-            };
+        if let Some(ref pucmd) = parsed_command.postup {
+            let mut cmd = hook_command(
+                Hook::PostUp,
+                pucmd,
+                parsed_command.user.as_deref(),
+                parsed_command.group.as_deref(),
+            )?;
+            set_env_vars(&ns, forwarder.as_deref(), &mut cmd);
+            cmd.spawn()?;
         }
     }
 
-    let ns = ns.write_lockfile(&parsed_command.application)?;
+    let ns = ns.write_lockfile(
+        &parsed_command.application,
+        forwarder.as_ref().map(|f| f.forwarded_port()),
+        parsed_command.profile.clone(),
+    )?;
+    drop(setup_lock);
+    ns.record_external_ip();
+
+    // DNAT rules for published ports are removed when this instance exits
+    let _publish = match parsed_command.publish.clone() {
+        Some(ports) if !ports.is_empty() => {
+            Some(PortPublish::new(&ns, ports, parsed_command.firewall)?)
+        }
+        _ => None,
+    };
+    let _relay = if parsed_command.relay_discovery {
+        Some(DiscoveryRelay::new(
+            &ns,
+            &parsed_command.interface,
+            parsed_command.firewall,
+        )?)
+    } else {
+        None
+    };
 
     // Port forwarding for ProtonVPN and PIA which require loop to keep it active
     // Forwarder is returned so it isn't dropped
@@ -247,21 +524,240 @@ pub fn exec(
         }
     }
 
+    // Shared with the threads replacing the tunnel for --reconnect, --watchdog and --rotate
+    let ns_name = ns.name.clone();
+    let ns = Arc::new(Mutex::new(ns));
+    let parsed_command = Arc::new(parsed_command);
+    let forwarder = Arc::new(Mutex::new(forwarder));
+    let _monitor = match parsed_command.reconnect {
+        // Only the instance which set up the tunnel looks after it
+        Some(policy) if !_using_existing_netns => {
+            let parsed_command = Arc::clone(&parsed_command);
+            let forwarder = Arc::clone(&forwarder);
+            Some(ReconnectMonitor::start(
+                Arc::clone(&ns),
+                policy,
+                move |ns| reconnect_tunnel(&parsed_command, ns, &forwarder, &CliClient {}, false),
+            ))
+        }
+        _ => None,
+    };
+    let _watchdog = match parsed_command.watchdog.clone() {
+        Some(policy) if !_using_existing_netns => {
+            let parsed_command = Arc::clone(&parsed_command);
+            let forwarder = Arc::clone(&forwarder);
+            Some(Watchdog::start(
+                Arc::clone(&ns),
+                policy,
+                move |ns, switch_server| {
+                    reconnect_tunnel(
+                        &parsed_command,
+                        ns,
+                        &forwarder,
+                        &CliClient {},
+                        switch_server,
+                    )
+                },
+            ))
+        }
+        _ => None,
+    };
+    let _rotation = match parsed_command.rotate {
+        Some(interval) if !_using_existing_netns => {
+            let parsed_command = Arc::clone(&parsed_command);
+            let forwarder = Arc::clone(&forwarder);
+            Some(ServerRotation::start(
+                Arc::clone(&ns),
+                interval.0,
+                move |ns| reconnect_tunnel(&parsed_command, ns, &forwarder, &CliClient {}, true),
+            ))
+        }
+        _ => None,
+    };
+
     if !parsed_command.create_netns_only {
-        run_application(&parsed_command, forwarder, &ns, signals, silent)?;
+        run_application(&parsed_command, &forwarder, &ns, signals, silent)?;
     } else {
-        info!(
-            "Created netns {} - will leave network namespace alive until ctrl+C received",
-            &ns.name
-        );
+        info!("Created netns {ns_name} - will leave network namespace alive until ctrl+C received");
         stay_alive(None, signals);
     }
 
     Ok(())
 }
 
+/// Set up the tunnel of the namespace again, after it failed, as when it was created, on another
+/// server if switch_server is set
+fn reconnect_tunnel(
+    parsed_command: &ArgsConfig,
+    ns: &mut NetworkNamespace,
+    forwarder: &Mutex<Option<Box<dyn Forwarder>>>,
+    uiclient: &dyn UiClient,
+    switch_server: bool,
+) -> anyhow::Result<()> {
+    // A failed tunnel moves on to the next failover server, switching stays on the same one
+    let current = ns
+        .config_file
+        .as_deref()
+        .and_then(|x| failover_position(parsed_command, x))
+        .unwrap_or_default();
+    let (start, avoid) = if switch_server {
+        (current, ns.config_file.clone())
+    } else {
+        (current + 1, None)
+    };
+    // A local resolver (the stub resolver or dnscrypt-proxy) keeps answering in the namespace
+    let local_dns: Vec<IpAddr> = DnsConfig::nameservers(&ns.name);
+    let local_dns =
+        (!local_dns.is_empty() && local_dns.iter().all(|x| x.is_loopback())).then_some(local_dns);
+    // The DNS config stays until the new tunnel replaces it, which keeps its files
+    reset_tunnel(ns);
+
+    let config_file =
+        run_protocol_with_failover(parsed_command, ns, uiclient, false, start, avoid.as_deref())?;
+    if let Some(servers) = local_dns {
+        ns.dns_config(
+            &servers,
+            &[],
+            parsed_command.hosts.as_ref(),
+            parsed_command.allow_host_access,
+        )?;
+    }
+    tune_tunnel_mtu(parsed_command, ns)?;
+    // The exceptions are in the killswitch of the new tunnel
+    if let Some(ref hosts) = parsed_command.open_hosts {
+        vopono_core::util::open_hosts(&ns.name, hosts, parsed_command.firewall)?;
+    }
+    if let Some(ref subnets) = parsed_command.allow_lan {
+        vopono_core::util::open_subnets(&ns.name, subnets, parsed_command.firewall)?;
+    }
+    if parsed_command.bridge_peers {
+        Bridge::allow_peers(ns, parsed_command.firewall)?;
+    }
+    if let Some(config) = config_file.as_ref() {
+        info!("Reconnected with {}", config.display());
+    }
+    NetworkNamespace::update_lockfile(&ns.name, |lock| lock.ns.config_file = config_file.clone())?;
+    ns.set_config_file(config_file);
+    refresh_port_forwarding(parsed_command, ns, forwarder)?;
+    verify_ip(parsed_command, ns)?;
+    emit(Event::TunnelUp {
+        namespace: &ns.name,
+        protocol: ns.protocol.to_string(),
+        interface: mtu::tunnel_interface(&ns.name).ok(),
+    });
+    ns.record_external_ip();
+    Ok(())
+}
+
+/// Destinations applications may reach via the veth instead of the tunnel
+pub fn killswitch_allowed(parsed_command: &ArgsConfig, veth_ips: &VethPairIPs) -> Vec<IpNet> {
+    let mut allowed: Vec<IpNet> = [veth_ips.host_ip]
+        .into_iter()
+        .chain(veth_ips.host_ipv6)
+        .chain(parsed_command.open_hosts.iter().flatten().copied())
+        .map(IpNet::from)
+        .collect();
+    allowed.extend(parsed_command.allow_lan.iter().flatten());
+    if parsed_command.bridge_peers {
+        allowed.push(IpNet::V4(Bridge::subnet()));
+    }
+    if parsed_command.relay_discovery {
+        allowed.extend(DISCOVERY_GROUPS.into_iter().map(|g| IpNet::V4(g.into())));
+    }
+    allowed
+}
+
+/// Block applications from leaving via the veth, and inbound connections on it
+pub fn add_killswitches(
+    parsed_command: &ArgsConfig,
+    ns: &NetworkNamespace,
+    allowed: &[IpNet],
+) -> anyhow::Result<()> {
+    let veth = &ns.veth_pair.as_ref().unwrap().source;
+    veth_killswitch(ns, veth, allowed, parsed_command.firewall)?;
+    let inbound_allowed: Vec<IpNet> = if parsed_command.bridge_peers {
+        vec![IpNet::V4(Bridge::subnet())]
+    } else {
+        Vec::new()
+    };
+    inbound_killswitch(ns, veth, &inbound_allowed, parsed_command.firewall)
+}
+
+/// Set in the environment of the background instance started by --detach
+const DETACHED_ENV: &str = "VOPONO_DETACHED";
+
+/// Longest wait for the background instance to connect and launch the application
+const DETACH_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// Run this vopono exec again in a new session in the background, with its output going to a log
+/// file, and return once it has set up the namespace and launched the application
+fn detach() -> anyhow::Result<()> {
+    let logs = vopono_dir()?.join("logs");
+    create_dir_all(&logs)?;
+    let log_path = logs.join(format!("detached_{}.log", std::process::id()));
+    let log = std::fs::File::create(&log_path)?;
+    let mut command = std::process::Command::new(std::env::current_exe()?);
+    command
+        .args(std::env::args().skip(1))
+        .env(DETACHED_ENV, "1")
+        .stdin(std::process::Stdio::null())
+        .stdout(log.try_clone()?)
+        .stderr(log);
+    unsafe {
+        command.pre_exec(|| {
+            nix::unistd::setsid()?;
+            Ok(())
+        });
+    }
+    let mut child = command.spawn()?;
+    let pid = child.id();
+    info!(
+        "Started detached vopono instance (pid {pid}), logging to {}",
+        log_path.display()
+    );
+
+    // The instance writes its lockfile once the namespace is up, and the application's PID to it
+    // once launched
+    let locks = vopono_core::util::config_dir()?.join("vopono/locks");
+    let deadline = Instant::now() + DETACH_TIMEOUT;
+    loop {
+        if let Some(status) = child.try_wait()? {
+            bail!(
+                "Detached vopono instance exited during setup ({status}), see {}",
+                log_path.display()
+            );
+        }
+        let launched = locks.read_dir().ok().and_then(|dirs| {
+            dirs.flatten().find_map(|x| {
+                Lockfile::read_application_pid(&x.path().join(pid.to_string()))
+                    .map(|app| (x.file_name().to_string_lossy().to_string(), app))
+            })
+        });
+        if let Some((namespace, app)) = launched {
+            println!(
+                "Network namespace {namespace} is running in the background (pid {pid}, application pid {app}), stop it with: vopono stop {namespace}"
+            );
+            return Ok(());
+        }
+        if Instant::now() >= deadline {
+            // Torn down as on Ctrl+C
+            nix::sys::signal::kill(
+                nix::unistd::Pid::from_raw(pid as i32),
+                nix::sys::signal::Signal::SIGINT,
+            )
+            .ok();
+            bail!(
+                "Detached vopono instance did not launch the application within {}s and was stopped, see {}",
+                DETACH_TIMEOUT.as_secs(),
+                log_path.display()
+            );
+        }
+        std::thread::sleep(Duration::from_millis(250));
+    }
+}
+
 // Block waiting for SIGINT
-fn stay_alive(pid: Option<u32>, mut signals: Signals) {
+pub fn stay_alive(pid: Option<u32>, mut signals: Signals) {
     let (sender, receiver) = std::sync::mpsc::channel();
 
     // discard old signals
@@ -294,11 +790,123 @@ fn stay_alive(pid: Option<u32>, mut signals: Signals) {
     thread.join().unwrap();
 }
 
+/// Apply the --mtu override or the probed Wireguard path MTU, and clamp the TCP MSS to it
+fn tune_tunnel_mtu(parsed_command: &ArgsConfig, ns: &NetworkNamespace) -> anyhow::Result<()> {
+    if ns.xray.is_some() || ns.redsocks.is_some() || ns.tor.is_some() {
+        // No tunnel interface
+        return Ok(());
+    }
+    let tunnel = match mtu::tunnel_interface(&ns.name) {
+        Ok(tunnel) => tunnel,
+        Err(e) => {
+            warn!("Could not find tunnel interface to set MTU: {e:?}");
+            return Ok(());
+        }
+    };
+    if let Some(value) = parsed_command.mtu {
+        mtu::set_mtu(&ns.name, &tunnel, value)?;
+    } else if let Some(wg) = ns.wireguard.as_ref()
+        && ns.trojan.is_none()
+        && ns.obfuscation.is_none()
+    {
+        // Through a local proxy the path to the server is not known
+        let endpoint = Wireguard::config_from_file(&wg.config_file)?
+            .peer
+            .endpoint
+            .resolve_ip()?;
+        let current = mtu::interface_mtu(&ns.name, &tunnel)?;
+        if let Some(value) = mtu::auto_wireguard_mtu(endpoint, current) {
+            warn!(
+                "Path to {endpoint} cannot carry Wireguard packets with MTU {current}, lowering it"
+            );
+            mtu::set_mtu(&ns.name, &tunnel, value)?;
+        }
+    }
+    mtu::clamp_mss(ns, &tunnel, parsed_command.firewall)
+}
+
+/// Drop the VPN processes, interfaces and killswitch rules of the namespace so a new tunnel can
+/// be set up. The DNS config stays: the new tunnel's config replaces its files, and dropping it
+/// would delete them (and the new resolv.conf if it were dropped later).
+fn reset_tunnel(ns: &mut NetworkNamespace) {
+    ns.remove_protocol_firewall();
+    ns.wireguard = None;
+    ns.openvpn = None;
+    ns.shadowsocks = None;
+    ns.trojan = None;
+    ns.obfuscation = None;
+    ns.obfs4 = None;
+    ns.xray = None;
+    ns.redsocks = None;
+    ns.tor = None;
+    ns.ikev2 = None;
+}
+
+/// Connect to --server, or to the --failover servers after it in order if that fails, starting
+/// from the entry at start
+fn run_protocol_with_failover(
+    parsed_command: &ArgsConfig,
+    ns: &mut NetworkNamespace,
+    uiclient: &dyn UiClient,
+    verbose: bool,
+    start: usize,
+    avoid: Option<&Path>,
+) -> anyhow::Result<Option<PathBuf>> {
+    let servers = parsed_command.servers();
+    let mut order = servers
+        .iter()
+        .cycle()
+        .skip(start)
+        .take(servers.len())
+        .peekable();
+    while let Some(server) = order.next() {
+        let before = (parsed_command.firewall == Firewall::IpTables).then(|| iptables_snapshot(ns));
+        let result = run_protocol_in_netns(parsed_command, ns, uiclient, verbose, server, avoid);
+        if let Some(before) = before {
+            ns.protocol_firewall_undo = iptables_undo_since(ns, &before);
+        }
+        match result {
+            Ok(config_file) => return Ok(config_file),
+            Err(e) => match order.peek() {
+                Some(next) => {
+                    warn!("Failed to connect to {server}: {e:#}, trying {next}");
+                    reset_tunnel(ns);
+                }
+                None => return Err(e),
+            },
+        }
+    }
+    unreachable!("--server is always in the servers to try")
+}
+
+/// Entry of --server and --failover the config was chosen from
+fn failover_position(parsed_command: &ArgsConfig, config_file: &Path) -> Option<usize> {
+    let cdir = provider_config_dir(parsed_command).ok()?;
+    server_position(&parsed_command.servers(), config_file, |server| {
+        candidate_configs(parsed_command, &cdir, server)
+    })
+}
+
+/// Position of the first server whose candidate configs include the config
+fn server_position(
+    servers: &[&str],
+    config_file: &Path,
+    candidates: impl Fn(&str) -> anyhow::Result<Vec<PathBuf>>,
+) -> Option<usize> {
+    servers
+        .iter()
+        .position(|server| candidates(server).is_ok_and(|x| x.iter().any(|y| y == config_file)))
+}
+
+/// server is the alias to connect to, --server or a --failover server. avoid is a config not to
+/// choose for the exit server unless it is the only one, to switch server
 fn run_protocol_in_netns(
     parsed_command: &ArgsConfig,
     ns: &mut NetworkNamespace,
     uiclient: &dyn UiClient,
     verbose: bool,
+    server: &str,
+    avoid: Option<&Path>,
 ) -> anyhow::Result<Option<PathBuf>> {
     if parsed_command.provider == VpnProvider::None {
         log::warn!(
@@ -316,24 +924,50 @@ fn run_protocol_in_netns(
         return Ok(None);
     }
 
-    let config_file = if parsed_command.protocol == Protocol::Warp {
+    let config_file = if [
+        Protocol::Warp,
+        Protocol::Socks5,
+        Protocol::HttpProxy,
+        Protocol::Tor,
+    ]
+    .contains(&parsed_command.protocol)
+    {
         None
     } else if parsed_command.provider != VpnProvider::Custom {
-        let cdir = match parsed_command.protocol {
-            Protocol::OpenVpn => parsed_command
-                .provider
-                .get_dyn_openvpn_provider()?
-                .openvpn_dir(),
-            Protocol::Wireguard => parsed_command
-                .provider
-                .get_dyn_wireguard_provider()?
-                .wireguard_dir(),
-            Protocol::OpenConnect => bail!("OpenConnect must use Custom provider"),
-            Protocol::OpenFortiVpn => bail!("OpenFortiVpn must use Custom provider"),
-            Protocol::Warp => unreachable!(),
-            Protocol::None => unreachable!(),
-        }?;
-        Some(get_config_from_alias(&cdir, &parsed_command.server)?)
+        let cdir = provider_config_dir(parsed_command)?;
+        let provider = parsed_command.provider.get_dyn_provider();
+        warn_if_stale(&provider.provider_dir()?, &provider.alias());
+        let select_config = |alias: &str| -> anyhow::Result<PathBuf> {
+            choose_config(&candidate_configs(parsed_command, &cdir, alias)?, alias)
+        };
+        let exit_config = match avoid {
+            Some(avoid) => {
+                let configs = candidate_configs(parsed_command, &cdir, server)?;
+                let others: Vec<PathBuf> =
+                    configs.iter().filter(|x| *x != avoid).cloned().collect();
+                if others.is_empty() {
+                    warn!(
+                        "No other server for {server}, using {} again",
+                        avoid.display()
+                    );
+                    choose_config(&configs, server)?
+                } else {
+                    choose_config(&others, server)?
+                }
+            }
+            None => select_config(server)?,
+        };
+        if let Some(entry_server) = parsed_command.entry_server.as_ref() {
+            let entry_config = select_config(entry_server)?;
+            let (multihop_config, hops) = match parsed_command.provider {
+                VpnProvider::IVPN => IVPN {}.create_multihop_config(&entry_config, &exit_config)?,
+                _ => Mullvad {}.create_multihop_config(&entry_config, &exit_config)?,
+            };
+            ns.multihop = Some(hops);
+            Some(multihop_config)
+        } else {
+            Some(exit_config)
+        }
     } else {
         // TODO: Improve error here
         Some(
@@ -361,6 +995,9 @@ fn run_protocol_in_netns(
             } else {
                 None
             };
+            if let Some(path) = auth_file.as_ref().filter(|x| is_runtime_auth_file(x)) {
+                ns.temp_files.push(path.clone());
+            }
 
             let dns = parsed_command
                 .dns
@@ -382,11 +1019,30 @@ fn run_protocol_in_netns(
                 parsed_command.allow_host_access,
             )?;
             // Check if using Shadowsocks
-            if let Some((ss_host, ss_lport)) = uses_shadowsocks(
+            let ss_config = uses_shadowsocks(
                 config_file
                     .as_ref()
                     .expect("No OpenVPN config file provided"),
-            )? {
+            )?;
+            if let Some(bridge) = parsed_command.obfs4.as_ref() {
+                if ss_config.is_some() {
+                    return Err(anyhow!(
+                        "OpenVPN config already uses a socks-proxy, cannot use obfs4 with it"
+                    ));
+                }
+                ns.run_obfs4(bridge)?;
+            } else if let Some(url) = parsed_command
+                .obfuscation_server
+                .as_deref()
+                .filter(|_| parsed_command.obfuscation == Some(ObfuscationProtocol::Shadowsocks))
+            {
+                if ss_config.is_some() {
+                    return Err(anyhow!(
+                        "OpenVPN config already uses a socks-proxy, cannot use a Shadowsocks server with it"
+                    ));
+                }
+                ns.run_shadowsocks_server(&url.parse::<ShadowsocksServer>()?)?;
+            } else if let Some((ss_host, ss_lport)) = ss_config {
                 if parsed_command.provider == VpnProvider::Custom {
                     warn!(
                         "Custom provider specifies socks-proxy, if this is local you must run it yourself (e.g. shadowsocks)"
@@ -407,19 +1063,85 @@ fn run_protocol_in_netns(
                 }
             }
 
-            ns.run_openvpn(
-                config_file
-                    .clone()
-                    .expect("No OpenVPN config file provided"),
-                auth_file,
-                &dns,
-                !parsed_command.no_killswitch,
-                parsed_command.open_ports.as_ref(),
-                parsed_command.forward.as_ref(),
-                parsed_command.firewall,
-                parsed_command.disable_ipv6,
-                verbose,
-            )?;
+            // Only kept in memory, asked for once for the retries below
+            let client_cert = match parsed_command.client_cert.clone() {
+                Some(mut cert) if cert.needs_passphrase() => {
+                    let passphrase = uiclient.get_password(Password {
+                        prompt: "Passphrase of the OpenVPN client key or PIN of the token (empty if none)"
+                            .to_string(),
+                        confirm: false,
+                    })?;
+                    cert.passphrase = Some(passphrase).filter(|x| !x.is_empty());
+                    Some(cert)
+                }
+                x => x,
+            };
+            let run_openvpn = |ns: &mut NetworkNamespace, auth_file: Option<PathBuf>, tcp: bool| {
+                ns.run_openvpn(
+                    config_file
+                        .clone()
+                        .expect("No OpenVPN config file provided"),
+                    auth_file,
+                    &dns,
+                    !parsed_command.no_killswitch,
+                    parsed_command.open_ports.as_ref(),
+                    parsed_command.forward.as_ref(),
+                    parsed_command.firewall,
+                    parsed_command.disable_ipv6,
+                    verbose,
+                    tcp,
+                    parsed_command.no_dco,
+                    parsed_command
+                        .openvpn_options
+                        .as_deref()
+                        .unwrap_or_default(),
+                    client_cert.as_ref(),
+                )
+            };
+            let mut tcp = parsed_command.tcp;
+            let result = match run_openvpn(ns, auth_file.clone(), tcp) {
+                // Networks blocking UDP, the killswitch is not applied yet
+                Err(e) if e.downcast_ref::<OpenVpnUdpTimeout>().is_some() && !tcp => {
+                    warn!("{e}");
+                    warn!("Falling back to OpenVPN over TCP");
+                    tcp = true;
+                    run_openvpn(ns, auth_file.clone(), tcp)
+                }
+                x => x,
+            };
+            match result {
+                // Providers may rotate credentials, so refresh them once and retry
+                // (unless they were given on the command line or environment)
+                Err(e)
+                    if e.downcast_ref::<OpenVpnAuthFailed>().is_some()
+                        && parsed_command.provider != VpnProvider::Custom
+                        && auth_file.is_some()
+                        && supplied_credentials(
+                            &parsed_command.provider.get_dyn_provider().alias(),
+                        )
+                        .is_none() =>
+                {
+                    warn!("{e}");
+                    warn!("Refreshing OpenVPN credentials and retrying");
+                    parsed_command
+                        .provider
+                        .get_dyn_openvpn_provider()?
+                        .refresh_openvpn_auth(uiclient)?;
+                    // Credentials from the keyring are written to a new runtime auth file
+                    let auth_file = verify_auth(
+                        parsed_command.provider.get_dyn_openvpn_provider()?,
+                        uiclient,
+                    )?;
+                    if let Some(path) = auth_file
+                        .as_ref()
+                        .filter(|x| is_runtime_auth_file(x) && !ns.temp_files.contains(x))
+                    {
+                        ns.temp_files.push(path.clone());
+                    }
+                    run_openvpn(ns, auth_file, tcp)?;
+                }
+                x => x?,
+            }
             debug!(
                 "Checking that OpenVPN is running in namespace: {}",
                 &ns.name
@@ -434,19 +1156,25 @@ fn run_protocol_in_netns(
                 ));
             }
 
-            // Set DNS with OpenVPN server response if present
-            if let Some(newdns) = ns.openvpn.as_ref().unwrap().openvpn_dns {
-                if parsed_command.dns.is_none() {
-                    let old_dns = ns.dns_config.take();
-                    std::mem::forget(old_dns);
-                    // TODO: DNS suffixes?
-                    ns.dns_config(
-                        &[newdns],
-                        &[],
-                        parsed_command.hosts.as_ref(),
-                        parsed_command.allow_host_access,
-                    )?;
-                }
+            // Set DNS with the servers pushed by the OpenVPN server, unless given with --dns
+            let pushed = ns.openvpn.as_ref().unwrap().pushed_dns.clone();
+            if !pushed.servers.is_empty() && parsed_command.dns.is_none() {
+                info!(
+                    "Using DNS servers pushed by OpenVPN: {}",
+                    pushed
+                        .servers
+                        .iter()
+                        .map(|x| x.to_string())
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                );
+                let suffixes: Vec<&str> = pushed.domains.iter().map(|x| x.as_str()).collect();
+                ns.dns_config(
+                    &pushed.servers,
+                    &suffixes,
+                    parsed_command.hosts.as_ref(),
+                    parsed_command.allow_host_access,
+                )?;
             }
         }
         Protocol::Wireguard => {
@@ -468,6 +1196,26 @@ fn run_protocol_in_netns(
                 )?;
             }
 
+            if let Some(obfuscation) = parsed_command.obfuscation {
+                let (password, method) = if obfuscation == ObfuscationProtocol::Shadowsocks {
+                    let dyn_ss_provider = parsed_command.provider.get_dyn_shadowsocks_provider()?;
+                    (dyn_ss_provider.password(), dyn_ss_provider.encrypt_method())
+                } else {
+                    Default::default()
+                };
+                ns.run_obfuscation(
+                    obfuscation,
+                    config_file
+                        .as_ref()
+                        .expect("No Wireguard config file provided"),
+                    parsed_command.obfuscation_port,
+                    &password,
+                    &method,
+                    parsed_command.obfuscation_server.as_deref(),
+                    parsed_command.obfuscation_key.as_deref(),
+                )?;
+            }
+
             ns.run_wireguard(
                 config_file
                     .clone()
@@ -480,7 +1228,16 @@ fn run_protocol_in_netns(
                 parsed_command.dns.as_ref(),
                 parsed_command.hosts.as_ref(),
                 parsed_command.allow_host_access,
+                parsed_command.ephemeral_key,
+                parsed_command.wireguard_implementation,
             )?;
+
+            if parsed_command.quantum_resistant {
+                let wireguard = ns.wireguard.as_ref().ok_or_else(|| {
+                    anyhow!("Wireguard is not running for the quantum-resistant key")
+                })?;
+                Mullvad {}.negotiate_quantum_psk(&ns.name, wireguard)?;
+            }
         }
         Protocol::OpenConnect => {
             let dns = parsed_command
@@ -518,10 +1275,176 @@ fn run_protocol_in_netns(
                 parsed_command.allow_host_access,
             )?;
         }
+        Protocol::Ikev2 => {
+            let dns = parsed_command
+                .dns
+                .clone()
+                .unwrap_or_else(|| vec![IpAddr::V4(Ipv4Addr::new(8, 8, 8, 8))]);
+            ns.dns_config(
+                &dns,
+                &[],
+                parsed_command.hosts.as_ref(),
+                parsed_command.allow_host_access,
+            )?;
+            ns.run_ikev2(
+                config_file.as_ref().expect("No IKEv2 profile provided"),
+                parsed_command.open_ports.as_ref(),
+                parsed_command.forward.as_ref(),
+                parsed_command.firewall,
+                verbose,
+                uiclient,
+            )?;
+        }
+        Protocol::Xray => {
+            let dns = parsed_command
+                .dns
+                .clone()
+                .unwrap_or_else(|| vec![IpAddr::V4(Ipv4Addr::new(8, 8, 8, 8))]);
+            ns.dns_config(
+                &dns,
+                &[],
+                parsed_command.hosts.as_ref(),
+                parsed_command.allow_host_access,
+            )?;
+            ns.run_xray(
+                config_file.as_ref().expect("No Xray config file provided"),
+                dns[0],
+                parsed_command.open_ports.as_ref(),
+                parsed_command.firewall,
+                verbose,
+            )?;
+        }
+        Protocol::Socks5 | Protocol::HttpProxy => {
+            let dns = parsed_command
+                .dns
+                .clone()
+                .unwrap_or_else(|| vec![IpAddr::V4(Ipv4Addr::new(8, 8, 8, 8))]);
+            ns.dns_config(
+                &dns,
+                &[],
+                parsed_command.hosts.as_ref(),
+                parsed_command.allow_host_access,
+            )?;
+            ns.run_upstream_proxy(
+                parsed_command
+                    .upstream_proxy
+                    .as_ref()
+                    .expect("No upstream proxy provided"),
+                parsed_command.open_ports.as_ref(),
+                parsed_command.firewall,
+                verbose,
+            )?;
+        }
+        Protocol::Tor => {
+            let dns = parsed_command
+                .dns
+                .clone()
+                .unwrap_or_else(|| vec![IpAddr::V4(Ipv4Addr::new(8, 8, 8, 8))]);
+            // Queries are answered by tor whichever server is given
+            ns.dns_config(
+                &dns,
+                &[],
+                parsed_command.hosts.as_ref(),
+                parsed_command.allow_host_access,
+            )?;
+            ns.run_tor(parsed_command.open_ports.as_ref(), parsed_command.firewall)?;
+            ns.socks_proxy = Some(SocketAddr::new(
+                IpAddr::V4(Ipv4Addr::LOCALHOST),
+                TOR_SOCKS_PORT,
+            ));
+        }
+    }
+
+    if parsed_command.mullvad_socks {
+        let mullvad = Mullvad {};
+        let proxy = mullvad.socks_proxy_addr(&parsed_command.protocol);
+        mullvad.verify_socks_proxy(&ns.name, proxy)?;
+        ns.socks_proxy = Some(proxy);
     }
     Ok(config_file)
 }
 
+/// Directory of the synced configs for the provider and protocol
+pub fn provider_config_dir(parsed_command: &ArgsConfig) -> anyhow::Result<PathBuf> {
+    match parsed_command.protocol {
+        Protocol::OpenVpn => parsed_command
+            .provider
+            .get_dyn_openvpn_provider()?
+            .openvpn_dir(),
+        Protocol::Wireguard => parsed_command
+            .provider
+            .get_dyn_wireguard_provider()?
+            .wireguard_dir(),
+        Protocol::OpenConnect => bail!("OpenConnect must use Custom provider"),
+        Protocol::OpenFortiVpn => bail!("OpenFortiVpn must use Custom provider"),
+        Protocol::Xray => bail!("Xray must use Custom provider"),
+        Protocol::Socks5 | Protocol::HttpProxy => {
+            bail!("Upstream proxies must use Custom provider")
+        }
+        Protocol::Tor => bail!("Tor must use Custom provider"),
+        Protocol::Ikev2 => bail!("IKEv2 must use Custom provider"),
+        Protocol::Warp => unreachable!(),
+        Protocol::None => unreachable!(),
+    }
+}
+
+/// Configs for the server prefix which pass the provider specific and server filters
+pub fn candidate_configs(
+    parsed_command: &ArgsConfig,
+    cdir: &Path,
+    alias: &str,
+) -> anyhow::Result<Vec<PathBuf>> {
+    let relay_filter = RelayFilter {
+        owned_only: parsed_command.owned_only,
+        ram_only: parsed_command.ram_only,
+        daita: parsed_command.daita,
+    };
+    let mut configs = get_configs_from_alias(cdir, alias);
+    if parsed_command.provider == VpnProvider::ProtonVPN {
+        configs = ProtonVPN {}.filter_configs_for_tier(configs, alias)?;
+        if parsed_command.secure_core {
+            configs = ProtonVPN::filter_secure_core_configs(configs);
+            if configs.is_empty() {
+                bail!(
+                    "No ProtonVPN Secure Core configs found for {}, run vopono sync with a Plus account and the SecureCore config set",
+                    alias
+                );
+            }
+        }
+    } else if !relay_filter.is_empty() {
+        configs = Mullvad {}.filter_wireguard_configs(configs, &relay_filter)?;
+    }
+    if !parsed_command.server_filter.is_empty() {
+        configs = parsed_command
+            .server_filter
+            .apply(&*parsed_command.provider.get_dyn_provider(), configs);
+        if configs.is_empty() {
+            bail!("No servers for {alias} match the server filters");
+        }
+    }
+    Ok(configs)
+}
+
+/// Resolve --random-server to the name of a randomly chosen config
+fn random_server(parsed_command: &ArgsConfig) -> anyhow::Result<String> {
+    if matches!(
+        parsed_command.provider,
+        VpnProvider::Custom | VpnProvider::None | VpnProvider::Warp
+    ) {
+        bail!("--random-server needs a provider with synced server lists");
+    }
+    let cdir = provider_config_dir(parsed_command)?;
+    let config = choose_random_server(&candidate_configs(
+        parsed_command,
+        &cdir,
+        &parsed_command.server,
+    )?)?;
+    config
+        .file_stem()
+        .map(|x| x.to_string_lossy().to_string())
+        .ok_or_else(|| anyhow!("Invalid config file name: {}", config.display()))
+}
+
 fn provider_port_forwarding(
     parsed_command: &ArgsConfig,
     ns: &NetworkNamespace,
@@ -574,11 +1497,6 @@ fn provider_port_forwarding(
                 let azirevpn = vopono_core::config::providers::azirevpn::AzireVPN {};
                 let access_token = azirevpn.read_access_token()?;
 
-                if parsed_command.port_forwarding_callback.is_some() {
-                    warn!(
-                        "Port forwarding callback not supported for AzireVPN - ignoring --port-forwarding-callback"
-                    );
-                }
                 if ns.wireguard.is_none() {
                     log::error!(
                         "AzireVPN Port Forwarding in vopono is only supported for Wireguard"
@@ -588,10 +1506,24 @@ fn provider_port_forwarding(
                 // TODO: Is OpenVPN possible? Could not get it to work manually
 
                 endpoint_ip
-                    .map(|ip| AzireVpnPortForwarding::new(ns, &access_token, ip))
+                    .map(|ip| {
+                        AzireVpnPortForwarding::new(
+                            ns,
+                            &access_token,
+                            ip,
+                            parsed_command.port_forwarding_callback.as_ref(),
+                        )
+                    })
                     .transpose()?
                     .map(|fwd| Box::new(fwd) as Box<dyn Forwarder>)
             }
+            Some(VpnProvider::IVPN) => {
+                // iVPN removed port forwarding for all plans, so there is no API to implement
+                error!(
+                    "iVPN no longer offers port forwarding (see https://www.ivpn.net/blog/gradual-removal-of-port-forwarding ) - ignoring --port-forwarding"
+                );
+                None
+            }
             Some(p) => {
                 error!(
                     "Port forwarding not supported for the selected provider: {p} - ignoring --port-forwarding"
@@ -616,21 +1548,104 @@ fn provider_port_forwarding(
     Ok(forwarder)
 }
 
-fn run_application(
+/// Fail unless the exit IP of the namespace passes --verify-ip
+fn verify_ip(parsed_command: &ArgsConfig, ns: &NetworkNamespace) -> anyhow::Result<()> {
+    let Some(url) = parsed_command.verify_ip.as_deref() else {
+        return Ok(());
+    };
+    // The multihop config is named after neither server
+    let config_name = ns
+        .config_file
+        .as_ref()
+        .filter(|_| parsed_command.verify_country && ns.multihop.is_none())
+        .and_then(|x| x.file_stem())
+        .map(|x| x.to_string_lossy().to_string());
+    verify_exit_ip(ns, url, config_name.as_deref())?;
+    Ok(())
+}
+
+/// Stop the port forwarding of the previous tunnel and set it up again in the new one
+fn refresh_port_forwarding(
     parsed_command: &ArgsConfig,
-    forwarder: Option<Box<dyn Forwarder>>,
     ns: &NetworkNamespace,
+    forwarder: &Mutex<Option<Box<dyn Forwarder>>>,
+) -> anyhow::Result<()> {
+    let mut forwarder = forwarder.lock().unwrap_or_else(PoisonError::into_inner);
+    let Some(old) = forwarder.take() else {
+        return Ok(());
+    };
+    // The old forwarder is kept if this fails, so the next reconnect tries again
+    match provider_port_forwarding(parsed_command, ns) {
+        Ok(new) => {
+            // Stops its refresh thread, releasing the port
+            drop(old);
+            *forwarder = new;
+        }
+        Err(e) => {
+            *forwarder = Some(old);
+            return Err(e);
+        }
+    }
+    if let Some(fwd) = forwarder.as_ref() {
+        let port = fwd.forwarded_port();
+        info!("Port Forwarding on port {port}");
+        NetworkNamespace::update_lockfile(&ns.name, |lock| lock.forwarded_port = Some(port))?;
+        emit(Event::PortForwarded {
+            namespace: &ns.name,
+            port,
+        });
+    }
+    Ok(())
+}
+
+fn run_application(
+    parsed_command: &ArgsConfig,
+    forwarder: &Mutex<Option<Box<dyn Forwarder>>>,
+    ns: &Mutex<NetworkNamespace>,
     signals: SignalsInfo,
     silent: bool,
 ) -> anyhow::Result<()> {
+    // Not held while the application runs, so the tunnel can be reconnected meanwhile
+    let ns = ns.lock().unwrap_or_else(PoisonError::into_inner);
+    let forwarder = forwarder.lock().unwrap_or_else(PoisonError::into_inner);
+    // Kept until the application and any daemon it left running have exited
+    let cgroup = if parsed_command.cgroup_firewall {
+        let veth_ips = ns.veth_pair_ips.as_ref().unwrap();
+        let mut allowed: Vec<IpNet> = parsed_command
+            .open_hosts
+            .iter()
+            .flatten()
+            .copied()
+            .map(IpNet::from)
+            .collect();
+        allowed.extend(parsed_command.allow_lan.iter().flatten());
+        if parsed_command.allow_host_access {
+            allowed.extend(
+                [veth_ips.host_ip]
+                    .into_iter()
+                    .chain(veth_ips.host_ipv6)
+                    .map(IpNet::from),
+            );
+        }
+        Some(AppCgroup::new(
+            &ns,
+            &mtu::tunnel_interface(&ns.name)?,
+            &ns.veth_pair.as_ref().unwrap().source,
+            &allowed,
+            parsed_command.firewall,
+        )?)
+    } else {
+        None
+    };
     let application = ApplicationWrapper::new(
-        ns,
+        &ns,
         &parsed_command.application,
         parsed_command.user.clone(),
         parsed_command.group.clone(),
         parsed_command.working_directory.clone().map(PathBuf::from),
-        forwarder,
+        forwarder.as_deref(),
         silent,
+        cgroup.as_ref(),
     )?;
 
     let pid = application.handle.id();
@@ -638,26 +1653,89 @@ fn run_application(
         "Application {} launched in network namespace {} with pid {}",
         &parsed_command.application, &ns.name, pid
     );
+    emit(Event::ApplicationStarted {
+        namespace: &ns.name,
+        application: &parsed_command.application,
+        pid,
+    });
+    if let Err(e) = ns.record_application_pid(pid) {
+        warn!("Failed to record application PID in lockfile: {e:?}");
+    }
 
-    if let Some(fwd) = application.port_forwarding.as_ref() {
+    if let Some(fwd) = forwarder.as_ref() {
         info!("Port Forwarding on port {}", fwd.forwarded_port())
     }
+    let ns_name = ns.name.clone();
+    drop(forwarder);
+    drop(ns);
     let output = application.wait_with_output()?;
     io::stdout().write_all(output.stdout.as_slice())?;
+    emit(Event::ApplicationExited {
+        namespace: &ns_name,
+        application: &parsed_command.application,
+        pid,
+        exit_code: output.status.code(),
+    });
 
     // Allow daemons to leave namespace open
     if vopono_core::util::check_process_running(pid) {
         info!(
             "Process {} still running, assumed to be daemon - will leave network namespace {} alive until ctrl+C received",
-            pid, &ns.name
+            pid, &ns_name
         );
         stay_alive(Some(pid), signals);
     } else if parsed_command.keep_alive {
         info!(
             "Keep-alive flag active - will leave network namespace {} alive until ctrl+C received",
-            &ns.name
+            &ns_name
         );
         stay_alive(None, signals);
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::args::{App, Command};
+    use crate::args_config::failover_order;
+    use clap::Parser;
+
+    #[test]
+    fn servers_in_failover_order() {
+        let args = [
+            "vopono",
+            "exec",
+            "--provider",
+            "mullvad",
+            "--server",
+            "sweden",
+            "--failover",
+            "usa,germany",
+            "firefox",
+        ];
+        let Command::Exec(command) = App::try_parse_from(args).unwrap().cmd else {
+            unreachable!()
+        };
+        let (server, failover) = (command.server.unwrap(), command.failover.unwrap());
+        assert_eq!(
+            failover_order(&server, &failover),
+            ["sweden", "usa", "germany"]
+        );
+    }
+
+    #[test]
+    fn position_of_failover_server() {
+        let servers = ["sweden", "usa", "germany"];
+        let candidates = |server: &str| -> anyhow::Result<Vec<PathBuf>> {
+            match server {
+                "usa" => bail!("No servers for usa match the server filters"),
+                _ => Ok(vec![PathBuf::from(format!("/c/{server}-1.conf"))]),
+            }
+        };
+        let position = |config: &str| server_position(&servers, Path::new(config), candidates);
+        assert_eq!(position("/c/sweden-1.conf"), Some(0));
+        assert_eq!(position("/c/germany-1.conf"), Some(2));
+        assert_eq!(position("/c/japan-1.conf"), None);
+    }
+}
@@ -1,80 +1,221 @@
 use super::args::ListCommand;
 use anyhow::anyhow;
 use chrono::prelude::*;
+use serde::Serialize;
+use std::net::IpAddr;
+use vopono_core::network::netns::NetworkNamespace;
+use vopono_core::network::veth_pair::format_bytes;
 use vopono_core::util::get_lock_namespaces;
 
 pub fn output_list(listcmd: ListCommand) -> anyhow::Result<()> {
+    let json = listcmd.output == "json";
     match listcmd.list_type.as_deref() {
-        Some("namespaces") => print_namespaces()?,
-        _ => print_applications()?,
+        Some("namespaces") => print_namespaces(json)?,
+        _ => print_applications(json)?,
     }
 
     Ok(())
 }
 
-pub fn print_applications() -> anyhow::Result<()> {
+#[derive(Serialize)]
+struct ApplicationEntry {
+    namespace: String,
+    provider: String,
+    protocol: String,
+    server: String,
+    application: String,
+    pid: Option<u32>,
+    forwarded_port: Option<u16>,
+    profile: Option<String>,
+    external_ip: Option<IpAddr>,
+    /// Unix timestamp
+    started: u64,
+    uptime_secs: u64,
+}
+
+#[derive(Serialize)]
+struct NamespaceEntry {
+    namespace: String,
+    provider: String,
+    protocol: String,
+    server: String,
+    applications: usize,
+    forwarded_ports: Vec<u16>,
+    profiles: Vec<String>,
+    external_ip: Option<IpAddr>,
+    /// Addresses of the veth pair, on the host and in the namespace
+    host_ip: Option<IpAddr>,
+    namespace_ip: Option<IpAddr>,
+    started: u64,
+    uptime_secs: u64,
+    /// Bytes moved through the veth interface
+    download: Option<u64>,
+    upload: Option<u64>,
+}
+
+fn uptime_secs(now: DateTime<Utc>, start: u64) -> anyhow::Result<u64> {
+    let datetime = DateTime::from_timestamp(start as i64, 0)
+        .ok_or_else(|| anyhow!("Timestamp parsing failed"))?;
+    Ok((now - datetime).to_std().unwrap_or_default().as_secs())
+}
+
+pub fn print_applications(json: bool) -> anyhow::Result<()> {
     let namespaces = get_lock_namespaces()?;
 
     let mut keys = namespaces.keys().collect::<Vec<&String>>();
     keys.sort();
 
-    if !keys.is_empty() {
-        println!("namespace\tprovider\tprotocol\tapplication\tuptime");
-        let now = Utc::now();
-        for ns in keys {
-            for lock in namespaces.get(ns).unwrap() {
-                let datetime = DateTime::from_timestamp(lock.start as i64, 0)
-                    .ok_or_else(|| anyhow!("Timestamp parsing failed"))?;
-                let diff = now - datetime;
-                println!(
-                    "{}\t{}\t{}\t{}\t{}",
-                    &ns,
-                    &lock.ns.provider,
-                    &lock.ns.protocol,
-                    &lock.command,
-                    compound_duration::format_wdhms(diff.to_std().unwrap().as_secs())
-                );
-            }
+    let now = Utc::now();
+    let mut entries = Vec::new();
+    for ns in keys {
+        for lock in namespaces.get(ns).unwrap() {
+            entries.push(ApplicationEntry {
+                namespace: ns.clone(),
+                provider: lock.ns.provider.to_string(),
+                protocol: lock.ns.protocol.to_string(),
+                server: server_description(&lock.ns),
+                application: lock.command.clone(),
+                pid: lock.application_pid,
+                forwarded_port: lock.forwarded_port,
+                profile: lock.profile.clone(),
+                external_ip: lock.external_ip,
+                started: lock.start,
+                uptime_secs: uptime_secs(now, lock.start)?,
+            });
         }
     }
     // Avoid triggering Drop for these namespaces
     std::mem::forget(namespaces);
+
+    if json {
+        println!("{}", serde_json::to_string_pretty(&entries)?);
+    } else if !entries.is_empty() {
+        println!(
+            "namespace\tprovider\tprotocol\tserver\tprofile\texternal_ip\tforwarded_port\tapplication\tuptime"
+        );
+        for entry in entries {
+            println!(
+                "{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}",
+                entry.namespace,
+                entry.provider,
+                entry.protocol,
+                entry.server,
+                entry.profile.as_deref().unwrap_or("-"),
+                or_dash(entry.external_ip),
+                or_dash(entry.forwarded_port),
+                entry.application,
+                compound_duration::format_wdhms(entry.uptime_secs)
+            );
+        }
+    }
     Ok(())
 }
 
-pub fn print_namespaces() -> anyhow::Result<()> {
+pub fn print_namespaces(json: bool) -> anyhow::Result<()> {
     let namespaces = get_lock_namespaces()?;
 
     let mut keys = namespaces.keys().collect::<Vec<&String>>();
     keys.sort();
 
-    if !keys.is_empty() {
-        let now = Utc::now();
-        println!("namespace\tprovider\tprotocol\tnum_applications\tuptime");
-        for ns in keys {
-            let first_lock = &namespaces.get(ns).as_ref().unwrap()[0];
-
-            let min_time = namespaces
-                .get(ns)
-                .unwrap()
-                .iter()
-                .map(|x| x.start)
-                .min()
-                .unwrap();
-            let datetime = DateTime::from_timestamp(min_time as i64, 0)
-                .ok_or_else(|| anyhow!("Timestamp parsing failed"))?;
-            let diff = now - datetime;
+    let now = Utc::now();
+    let mut entries = Vec::new();
+    for ns in keys {
+        let locks = namespaces.get(ns).unwrap();
+        let first_lock = &locks[0];
+        let min_time = locks.iter().map(|x| x.start).min().unwrap();
+        let mut forwarded_ports = locks
+            .iter()
+            .filter_map(|x| x.forwarded_port)
+            .collect::<Vec<_>>();
+        forwarded_ports.sort();
+        forwarded_ports.dedup();
+        let mut profiles = locks
+            .iter()
+            .filter_map(|x| x.profile.clone())
+            .collect::<Vec<_>>();
+        profiles.sort();
+        profiles.dedup();
+        let veth_ips = first_lock.ns.veth_pair_ips.as_ref();
+        let traffic = first_lock
+            .ns
+            .veth_pair
+            .as_ref()
+            .and_then(|x| x.traffic(&first_lock.ns));
+        entries.push(NamespaceEntry {
+            namespace: ns.clone(),
+            provider: first_lock.ns.provider.to_string(),
+            protocol: first_lock.ns.protocol.to_string(),
+            server: server_description(&first_lock.ns),
+            applications: locks.len(),
+            forwarded_ports,
+            profiles,
+            external_ip: locks.iter().find_map(|x| x.external_ip),
+            host_ip: veth_ips.map(|x| x.host_ip),
+            namespace_ip: veth_ips.map(|x| x.namespace_ip),
+            started: min_time,
+            uptime_secs: uptime_secs(now, min_time)?,
+            download: traffic.map(|x| x.download),
+            upload: traffic.map(|x| x.upload),
+        });
+    }
+    // Avoid triggering Drop for these namespaces
+    std::mem::forget(namespaces);
+
+    if json {
+        println!("{}", serde_json::to_string_pretty(&entries)?);
+    } else if !entries.is_empty() {
+        println!(
+            "namespace\tprovider\tprotocol\tserver\tprofiles\texternal_ip\tveth_ips\tforwarded_ports\tnum_applications\tuptime\tdownload\tupload"
+        );
+        for entry in entries {
             println!(
-                "{}\t{}\t{}\t{}\t{}",
-                ns,
-                first_lock.ns.provider,
-                first_lock.ns.protocol,
-                namespaces.get(ns).unwrap().len(),
-                compound_duration::format_wdhms(diff.to_std().unwrap().as_secs())
+                "{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}",
+                entry.namespace,
+                entry.provider,
+                entry.protocol,
+                entry.server,
+                join_or_dash(&entry.profiles),
+                or_dash(entry.external_ip),
+                match (entry.host_ip, entry.namespace_ip) {
+                    (Some(host), Some(namespace)) => format!("{host} <-> {namespace}"),
+                    _ => "-".to_string(),
+                },
+                join_or_dash(&entry.forwarded_ports),
+                entry.applications,
+                compound_duration::format_wdhms(entry.uptime_secs),
+                entry.download.map_or("-".to_string(), format_bytes),
+                entry.upload.map_or("-".to_string(), format_bytes),
             );
         }
     }
-    // Avoid triggering Drop for these namespaces
-    std::mem::forget(namespaces);
     Ok(())
 }
+
+fn or_dash<T: ToString>(value: Option<T>) -> String {
+    value.map_or("-".to_string(), |x| x.to_string())
+}
+
+fn join_or_dash<T: ToString>(values: &[T]) -> String {
+    if values.is_empty() {
+        "-".to_string()
+    } else {
+        values
+            .iter()
+            .map(|x| x.to_string())
+            .collect::<Vec<_>>()
+            .join(",")
+    }
+}
+
+/// Both hops for multihop connections, otherwise the config file name
+pub fn server_description(ns: &NetworkNamespace) -> String {
+    if let Some(multihop) = ns.multihop.as_ref() {
+        multihop.to_string()
+    } else {
+        ns.config_file
+            .as_ref()
+            .and_then(|p| p.file_stem())
+            .map(|s| s.to_string_lossy().to_string())
+            .unwrap_or_else(|| "-".to_string())
+    }
+}
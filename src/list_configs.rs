@@ -1,9 +1,24 @@
 use super::args::ServersCommand;
 use anyhow::bail;
+use serde::Serialize;
+use std::path::PathBuf;
 use vopono_core::config::providers::VpnProvider;
 use vopono_core::config::vpn::Protocol;
 use vopono_core::util::get_configs_from_alias;
 
+#[derive(Serialize)]
+struct ServerEntry {
+    provider: String,
+    protocol: String,
+    config_file: String,
+    country: Option<String>,
+    city: Option<String>,
+    /// Percent, if published by the provider
+    load: Option<u8>,
+    latency_ms: Option<u32>,
+    features: Vec<String>,
+}
+
 pub fn print_configs(cmd: ServersCommand) -> anyhow::Result<()> {
     let provider = cmd.vpn_provider.to_variant();
     if provider == VpnProvider::Custom {
@@ -24,6 +39,12 @@ pub fn print_configs(cmd: ServersCommand) -> anyhow::Result<()> {
         Protocol::Warp => bail!("Config listing not implemented for Cloudflare Warp"),
         Protocol::OpenConnect => bail!("Config listing not implemented for OpenConnect"),
         Protocol::OpenFortiVpn => bail!("Config listing not implemented for OpenFortiVPN"),
+        Protocol::Xray => bail!("Config listing not implemented for Xray"),
+        Protocol::Socks5 | Protocol::HttpProxy => {
+            bail!("Config listing not implemented for upstream proxies")
+        }
+        Protocol::Tor => bail!("Config listing not implemented for Tor"),
+        Protocol::Ikev2 => bail!("Config listing not implemented for IKEv2"),
         Protocol::None => bail!("Config listing not implemented for None Protocol"),
     }?;
     if !cdir.exists() || cdir.read_dir()?.next().is_none() {
@@ -36,43 +57,66 @@ pub fn print_configs(cmd: ServersCommand) -> anyhow::Result<()> {
 
     // Use get_configs_from_alias
     let prefix = cmd.prefix.unwrap_or_default();
-    println!("provider\tprotocol\tconfig_file");
+    let dyn_provider = provider.get_dyn_provider();
+    let mut servers = Vec::new();
+    let mut add_servers = |protocol: &str, mut configs: Vec<PathBuf>| {
+        configs.sort_by_key(|c| c.file_name().unwrap().to_str().unwrap().to_owned());
+        for info in dyn_provider.server_info(&configs) {
+            servers.push(ServerEntry {
+                provider: provider.to_string(),
+                protocol: protocol.to_string(),
+                config_file: info.file_name().to_string(),
+                country: info.country,
+                city: info.city,
+                load: info.load,
+                latency_ms: info.latency_ms,
+                features: info.features.iter().map(|x| x.to_string()).collect(),
+            });
+        }
+    };
     if (cmd.protocol.is_none() && provider.get_dyn_openvpn_provider().is_ok())
         || cmd.protocol.clone().map(|x| x.to_variant()) == Some(Protocol::OpenVpn)
     {
-        let mut openvpn_configs = get_configs_from_alias(
+        let openvpn_configs = get_configs_from_alias(
             &provider.get_dyn_openvpn_provider()?.openvpn_dir()?,
             &prefix,
         );
-
-        openvpn_configs.sort_by_key(|c| c.file_name().unwrap().to_str().unwrap().to_owned());
-
-        for config in openvpn_configs {
-            println!(
-                "{}\topenvpn\t{}",
-                provider,
-                config.file_name().unwrap().to_str().unwrap()
-            );
-        }
+        add_servers("openvpn", openvpn_configs);
     };
 
     if (cmd.protocol.is_none() && provider.get_dyn_wireguard_provider().is_ok())
         || cmd.protocol.map(|x| x.to_variant()) == Some(Protocol::Wireguard)
     {
-        let mut wg_configs = get_configs_from_alias(
+        let wg_configs = get_configs_from_alias(
             &provider.get_dyn_wireguard_provider()?.wireguard_dir()?,
             &prefix,
         );
-
-        wg_configs.sort_by_key(|c| c.file_name().unwrap().to_str().unwrap().to_owned());
-
-        for config in wg_configs {
-            println!(
-                "{}\twireguard\t{}",
-                provider,
-                config.file_name().unwrap().to_str().unwrap()
-            );
-        }
+        add_servers("wireguard", wg_configs);
     };
+
+    if cmd.output == "json" {
+        println!("{}", serde_json::to_string_pretty(&servers)?);
+        return Ok(());
+    }
+    println!("provider\tprotocol\tconfig_file\tcountry\tcity\tload\tfeatures");
+    for server in servers {
+        println!(
+            "{}\t{}\t{}\t{}\t{}\t{}\t{}",
+            server.provider,
+            server.protocol,
+            server.config_file,
+            server.country.as_deref().unwrap_or("-"),
+            server.city.as_deref().unwrap_or("-"),
+            server
+                .load
+                .map(|x| format!("{x}%"))
+                .unwrap_or("-".to_string()),
+            if server.features.is_empty() {
+                "-".to_string()
+            } else {
+                server.features.join(",")
+            }
+        );
+    }
     Ok(())
 }
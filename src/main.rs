@@ -4,21 +4,38 @@
 
 mod args;
 mod args_config;
+mod attach;
 mod cli_client;
+mod complete;
+mod desktop;
+mod dnsleak;
+mod dry_run;
 mod exec;
 mod list;
 mod list_configs;
+mod metrics;
+mod service;
+mod setup;
+mod status;
 mod sync;
+mod tui;
 
 use clap::Parser;
 use cli_client::CliClient;
 use list::output_list;
 use list_configs::print_configs;
 use log::{LevelFilter, warn};
-use sync::{sync_menu, synch};
-use vopono_core::util::clean_dead_locks;
-use vopono_core::util::clean_dead_namespaces;
+use std::io::Write;
+use std::time::Duration;
+use sync::{migrate_credentials, mullvad_devices, sync_menu, synch, synch_plugin};
+use vopono_core::config::providers::VpnProvider;
+use vopono_core::util::cleanup::{clean_orphans, find_orphans, remove_orphan};
 use vopono_core::util::elevate_privileges;
+use vopono_core::util::events::{Event, emit, event_line, set_json_events};
+use vopono_core::util::parallel::set_sync_jobs;
+use vopono_core::util::server_cache::set_server_list_ttl;
+use vopono_core::util::stop::stop_namespace;
+use vopono_core::util::sync_filter::SyncFilter;
 
 fn main() -> anyhow::Result<()> {
     // Get struct of args using structopt
@@ -29,6 +46,17 @@ fn main() -> anyhow::Result<()> {
     if app.verbose {
         builder.filter_level(LevelFilter::Debug);
     }
+    if app.log_format == args::LogFormat::Json {
+        builder.format(|buf, record| {
+            let event = Event::Log {
+                level: record.level().to_string(),
+                target: record.target(),
+                message: record.args().to_string(),
+            };
+            writeln!(buf, "{}", event_line(&event))
+        });
+        set_json_events(!app.silent);
+    }
     if app.silent {
         if app.verbose {
             warn!("Verbose and silent flags are mutually exclusive, ignoring verbose flag");
@@ -37,28 +65,120 @@ fn main() -> anyhow::Result<()> {
     }
     builder.init();
 
+    let json = app.log_format == args::LogFormat::Json;
+    let result = run(app);
+    // The error would otherwise be the only line which is not JSON
+    if json && let Err(e) = &result {
+        emit(Event::Error {
+            message: format!("{e:#}"),
+        });
+        std::process::exit(1);
+    }
+    result
+}
+
+fn run(app: args::App) -> anyhow::Result<()> {
     let uiclient = CliClient {};
     match app.cmd {
         args::Command::Exec(cmd) => {
-            clean_dead_locks()?;
             let verbose = app.verbose && !app.silent;
-            elevate_privileges(app.askpass)?;
-            clean_dead_namespaces()?;
+            // A dry run only reads the configs and host state
+            if !cmd.dry_run {
+                elevate_privileges(app.askpass)?;
+                clean_orphans()?;
+            }
             exec::exec(cmd, &uiclient, verbose, app.silent)?
         }
+        args::Command::Attach(cmd) => {
+            elevate_privileges(app.askpass)?;
+            clean_orphans()?;
+            attach::attach(cmd, app.silent)?
+        }
         args::Command::List(listcmd) => {
-            clean_dead_locks()?;
             output_list(listcmd)?;
         }
+        args::Command::DnsLeak(cmd) => {
+            elevate_privileges(app.askpass)?;
+            dnsleak::dnsleak(cmd)?;
+        }
+        args::Command::Status(cmd) => {
+            elevate_privileges(app.askpass)?;
+            status::print_status(cmd)?;
+        }
+        args::Command::Stop(cmd) => {
+            elevate_privileges(app.askpass)?;
+            stop_namespace(&cmd.namespace, Duration::from_secs(cmd.timeout), cmd.force)?;
+        }
+        args::Command::Tui(cmd) => {
+            tui::tui(cmd, app.verbose, app.silent, app.askpass)?;
+        }
+        args::Command::Service(cmd) => {
+            let args::ServiceAction::Generate(generate) = &cmd.cmd;
+            if !generate.stdout {
+                elevate_privileges(app.askpass)?;
+            }
+            service::service(cmd)?;
+        }
+        args::Command::Setup(cmd) => {
+            setup::setup(cmd, &uiclient)?;
+        }
+        args::Command::Metrics(cmd) => {
+            elevate_privileges(app.askpass)?;
+            metrics::serve_metrics(cmd)?;
+        }
+        args::Command::Completions(cmd) => {
+            complete::completions(cmd);
+        }
+        args::Command::Complete(cmd) => {
+            complete::complete(cmd)?;
+        }
+        args::Command::Desktop(cmd) => {
+            desktop::desktop(cmd)?;
+        }
+        args::Command::Cleanup(cleanupcmd) => {
+            elevate_privileges(app.askpass)?;
+            cleanup(cleanupcmd.dry_run)?;
+        }
         args::Command::Synch(synchcmd) => {
+            set_sync_jobs(synchcmd.jobs);
+            set_server_list_ttl(Duration::from_secs(synchcmd.server_list_ttl * 60));
+            let filter = SyncFilter {
+                countries: synchcmd.countries.unwrap_or_default(),
+                cities: synchcmd.cities.unwrap_or_default(),
+            };
             // If provider given then sync that, else prompt with menu
-            if synchcmd.vpn_provider.is_none() {
-                sync_menu(&uiclient, synchcmd.protocol.map(|x| x.to_variant()))?;
+            if synchcmd.migrate_credentials {
+                migrate_credentials(synchcmd.vpn_provider.unwrap().to_variant())?;
+            } else if synchcmd.list_devices || synchcmd.revoke_device.is_some() {
+                let provider = synchcmd
+                    .vpn_provider
+                    .map(|x| x.to_variant())
+                    .unwrap_or(VpnProvider::Mullvad);
+                mullvad_devices(
+                    provider,
+                    synchcmd.list_devices,
+                    synchcmd.revoke_device.as_deref(),
+                    &uiclient,
+                )?;
+            } else if let Some(plugin_path) = synchcmd.custom_plugin {
+                synch_plugin(
+                    &plugin_path,
+                    &synchcmd.protocol.map(|x| x.to_variant()),
+                    &uiclient,
+                    &filter,
+                )?;
+            } else if synchcmd.vpn_provider.is_none() {
+                sync_menu(
+                    &uiclient,
+                    synchcmd.protocol.map(|x| x.to_variant()),
+                    &filter,
+                )?;
             } else {
                 synch(
                     synchcmd.vpn_provider.unwrap().to_variant(),
                     &synchcmd.protocol.map(|x| x.to_variant()),
                     &uiclient,
+                    &filter,
                 )?;
             }
         }
@@ -68,3 +188,20 @@ fn main() -> anyhow::Result<()> {
     }
     Ok(())
 }
+
+fn cleanup(dry_run: bool) -> anyhow::Result<()> {
+    let orphans = find_orphans()?;
+    if orphans.is_empty() {
+        println!("Nothing to clean up");
+        return Ok(());
+    }
+    for orphan in orphans.iter() {
+        if dry_run {
+            println!("Would remove {orphan}");
+        } else {
+            remove_orphan(orphan)?;
+            println!("Removed {orphan}");
+        }
+    }
+    Ok(())
+}
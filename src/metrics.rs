@@ -0,0 +1,65 @@
+use super::args::MetricsCommand;
+use anyhow::Context;
+use log::{debug, info, warn};
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::time::Duration;
+use vopono_core::network::metrics::{collect_metrics, render_metrics};
+use vopono_core::util::get_lock_namespaces;
+
+/// Serve a single HTTP request, with the metrics at /metrics
+fn respond(stream: TcpStream) -> anyhow::Result<()> {
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    let mut reader = BufReader::new(&stream);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    // Skip the headers
+    let mut header = String::new();
+    while reader.read_line(&mut header)? > 2 {
+        header.clear();
+    }
+    let mut parts = request_line.split_whitespace();
+    let (status, body) = match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/metrics")) => {
+            let namespaces = get_lock_namespaces()?;
+            let body = render_metrics(&collect_metrics(&namespaces));
+            // Dropping the deserialized namespaces would tear them down
+            std::mem::forget(namespaces);
+            ("200 OK", body)
+        }
+        (Some("GET"), Some("/")) => (
+            "200 OK",
+            "vopono metrics exporter, see /metrics\n".to_string(),
+        ),
+        _ => ("404 Not Found", "Not found\n".to_string()),
+    };
+    let mut stream = &stream;
+    write!(
+        stream,
+        "HTTP/1.1 {status}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    )?;
+    Ok(())
+}
+
+/// Serve Prometheus metrics of the running namespaces until interrupted
+pub fn serve_metrics(command: MetricsCommand) -> anyhow::Result<()> {
+    let listener = TcpListener::bind(command.listen)
+        .with_context(|| format!("Failed to listen on {}", command.listen))?;
+    info!(
+        "Serving vopono metrics on http://{}/metrics",
+        command.listen
+    );
+    for stream in listener.incoming() {
+        match stream {
+            Ok(stream) => {
+                debug!("Metrics request from {:?}", stream.peer_addr());
+                if let Err(e) = respond(stream) {
+                    warn!("Failed to serve metrics request: {e:?}");
+                }
+            }
+            Err(e) => warn!("Failed to accept metrics connection: {e}"),
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,87 @@
+use super::args::{ServiceCommand, ServiceGenerateCommand};
+use anyhow::{Context, anyhow};
+use log::info;
+use std::path::{Path, PathBuf};
+use vopono_core::util::service_unit::ServiceUnit;
+use vopono_core::util::{config_dir, get_username, vopono_dir};
+
+pub fn service(command: ServiceCommand) -> anyhow::Result<()> {
+    match command.cmd {
+        super::args::ServiceAction::Generate(cmd) => generate(cmd),
+    }
+}
+
+/// Namespace name of the service, short enough for the veth interface names
+fn netns_name(profile: &str) -> String {
+    let short_name = if profile.len() > 7 {
+        bs58::encode(profile).into_string()[0..7].to_string()
+    } else {
+        profile.replace('-', "")
+    };
+    format!("vo_sv_{short_name}")
+}
+
+fn generate(command: ServiceGenerateCommand) -> anyhow::Result<()> {
+    let config_path = vopono_dir()?.join("config.toml");
+    let config = config::Config::builder()
+        .add_source(config::File::from(config_path.clone()))
+        .build()
+        .with_context(|| format!("Failed to parse config from {}", config_path.display()))?;
+    config
+        .get_table(&format!("profile.{}", command.profile))
+        .map_err(|_| {
+            anyhow!(
+                "No [profile.{}] section in {}",
+                command.profile,
+                config_path.display()
+            )
+        })?;
+    let home = config_dir()?
+        .parent()
+        .map(Path::to_path_buf)
+        .ok_or_else(|| anyhow!("Failed to find the home directory of the vopono config"))?;
+    let socket_proxyd = ["/usr/lib/systemd", "/lib/systemd"]
+        .iter()
+        .map(|x| Path::new(x).join("systemd-socket-proxyd"))
+        .find(|x| x.exists())
+        .unwrap_or_else(|| PathBuf::from("/usr/lib/systemd/systemd-socket-proxyd"));
+    let unit = ServiceUnit {
+        name: command
+            .name
+            .unwrap_or_else(|| format!("vopono-{}", command.profile)),
+        vopono: std::env::current_exe()?,
+        application: command.app,
+        user: command.user.map_or_else(get_username, Ok)?,
+        home,
+        netns_name: netns_name(&command.profile),
+        socket_port: command.socket,
+        ip: which::which("ip").unwrap_or_else(|_| PathBuf::from("/usr/bin/ip")),
+        socket_proxyd,
+        profile: command.profile,
+    };
+
+    let mut files = vec![(format!("{}.service", unit.name), unit.service())];
+    if let (Some(socket), Some(proxy)) = (unit.socket(), unit.proxy_service()) {
+        files.push((format!("{}.socket", unit.proxy_name()), socket));
+        files.push((format!("{}.service", unit.proxy_name()), proxy));
+    }
+    if command.stdout {
+        for (name, contents) in files {
+            println!("# {name}\n{contents}");
+        }
+        return Ok(());
+    }
+    for (name, contents) in files.iter() {
+        let path = command.output_dir.join(name);
+        std::fs::write(&path, contents)
+            .with_context(|| format!("Failed to write {}", path.display()))?;
+        info!("Wrote {}", path.display());
+    }
+    let enable = if unit.socket_port.is_some() {
+        format!("{0}.service {1}.socket", unit.name, unit.proxy_name())
+    } else {
+        format!("{}.service", unit.name)
+    };
+    println!("Enable it with: systemctl daemon-reload && systemctl enable --now {enable}");
+    Ok(())
+}
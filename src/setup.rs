@@ -0,0 +1,168 @@
+use super::args::{SetupCommand, WrappedArg};
+use super::sync::synch;
+use anyhow::{Context, bail};
+use clap::ValueEnum;
+use dialoguer::{Confirm, Select};
+use strum::IntoEnumIterator;
+use vopono_core::config::providers::{UiClient, VpnProvider};
+use vopono_core::config::vpn::Protocol;
+use vopono_core::network::firewall::Firewall;
+use vopono_core::util::config_toml::set_top_level_keys;
+use vopono_core::util::sync_filter::SyncFilter;
+use vopono_core::util::{get_configs_from_alias, vopono_dir};
+
+/// Config directories of the protocols the provider supports
+fn provider_protocols(provider: &VpnProvider) -> Vec<(Protocol, std::path::PathBuf)> {
+    let mut protocols = Vec::new();
+    if let Ok(dir) = provider
+        .get_dyn_wireguard_provider()
+        .and_then(|x| x.wireguard_dir())
+    {
+        protocols.push((Protocol::Wireguard, dir));
+    }
+    if let Ok(dir) = provider
+        .get_dyn_openvpn_provider()
+        .and_then(|x| x.openvpn_dir())
+    {
+        protocols.push((Protocol::OpenVpn, dir));
+    }
+    protocols
+}
+
+/// Server prefixes of the synced configs, e.g. se for se-got-wg-001, with their counts
+fn server_prefixes(dir: &std::path::Path) -> Vec<(String, usize)> {
+    let mut prefixes: Vec<(String, usize)> = Vec::new();
+    let mut names: Vec<String> = get_configs_from_alias(dir, "")
+        .iter()
+        .filter_map(|x| x.file_stem().map(|x| x.to_string_lossy().to_string()))
+        .collect();
+    names.sort();
+    for name in names {
+        let prefix = name.split('-').next().unwrap_or(&name).to_string();
+        match prefixes.last_mut() {
+            Some((last, count)) if *last == prefix => *count += 1,
+            _ => prefixes.push((prefix, 1)),
+        }
+    }
+    prefixes
+}
+
+/// Choose a provider, sync its servers and write the defaults to config.toml
+pub fn setup(command: SetupCommand, uiclient: &dyn UiClient) -> anyhow::Result<()> {
+    let providers: Vec<VpnProvider> = WrappedArg::<VpnProvider>::value_variants()
+        .iter()
+        .map(|x| x.to_variant())
+        .filter(|x| ![VpnProvider::Custom, VpnProvider::None].contains(x))
+        .collect();
+    let selection = Select::new()
+        .with_prompt("VPN provider")
+        .items(&providers.iter().map(|x| x.to_string()).collect::<Vec<_>>())
+        .default(0)
+        .interact()?;
+    let provider = providers[selection].clone();
+    let mut values = vec![("provider", format!("{provider:?}"))];
+
+    // Warp has no server list to sync or choose from
+    if provider != VpnProvider::Warp {
+        let protocols = provider_protocols(&provider);
+        let default_protocol = provider.get_dyn_provider().default_protocol();
+        let selection = Select::new()
+            .with_prompt("Protocol")
+            .items(
+                &protocols
+                    .iter()
+                    .map(|(x, _)| x.to_string())
+                    .collect::<Vec<_>>(),
+            )
+            .default(
+                protocols
+                    .iter()
+                    .position(|(x, _)| *x == default_protocol)
+                    .unwrap_or(0),
+            )
+            .interact()?;
+        let (protocol, dir) = &protocols[selection];
+
+        let synced = dir.read_dir().is_ok_and(|mut x| x.next().is_some());
+        if !synced
+            || Confirm::new()
+                .with_prompt(format!(
+                    "{provider} {protocol} servers are already synced, sync them again?"
+                ))
+                .default(false)
+                .interact()?
+        {
+            // Prompts for the account credentials
+            synch(
+                provider.clone(),
+                &Some(protocol.clone()),
+                uiclient,
+                &SyncFilter::default(),
+            )?;
+        }
+
+        let prefixes = server_prefixes(dir);
+        if prefixes.is_empty() {
+            bail!("No {provider} {protocol} configs were synced");
+        }
+        let selection = Select::new()
+            .with_prompt("Default server (a random server with this prefix is used)")
+            .items(
+                &prefixes
+                    .iter()
+                    .map(|(prefix, count)| format!("{prefix} ({count})"))
+                    .collect::<Vec<_>>(),
+            )
+            .default(0)
+            .max_length(20)
+            .interact()?;
+        values.push(("protocol", format!("{protocol:?}")));
+        values.push(("server", prefixes[selection].0.clone()));
+    }
+
+    let firewalls: Vec<Firewall> = Firewall::iter().collect();
+    let detected = Firewall::detect().ok();
+    let selection = Select::new()
+        .with_prompt("Firewall")
+        .items(
+            &firewalls
+                .iter()
+                .map(|x| {
+                    if Some(*x) == detected {
+                        format!("{x} (detected)")
+                    } else {
+                        x.to_string()
+                    }
+                })
+                .collect::<Vec<_>>(),
+        )
+        .default(
+            detected
+                .and_then(|x| firewalls.iter().position(|y| *y == x))
+                .unwrap_or(0),
+        )
+        .interact()?;
+    values.push(("firewall", format!("{:?}", firewalls[selection])));
+
+    let path = match command.vopono_config {
+        Some(path) => path,
+        None => vopono_dir()?.join("config.toml"),
+    };
+    let existing = std::fs::read_to_string(&path).unwrap_or_default();
+    let contents = set_top_level_keys(&existing, &values);
+    println!("\n{contents}");
+    if !Confirm::new()
+        .with_prompt(format!("Write this to {}?", path.display()))
+        .default(true)
+        .interact()?
+    {
+        return Ok(());
+    }
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    std::fs::write(&path, contents)
+        .with_context(|| format!("Failed to write {}", path.display()))?;
+    println!("Saved, run an application with e.g.: vopono exec firefox");
+    Ok(())
+}
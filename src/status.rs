@@ -0,0 +1,95 @@
+use super::args::StatusCommand;
+use super::list::server_description;
+use anyhow::{anyhow, bail};
+use chrono::prelude::*;
+use vopono_core::network::status::tunnel_status;
+use vopono_core::network::veth_pair::format_bytes;
+use vopono_core::util::get_lock_namespaces;
+
+fn format_age(now: DateTime<Utc>, timestamp: u64) -> anyhow::Result<String> {
+    let datetime = DateTime::from_timestamp(timestamp as i64, 0)
+        .ok_or_else(|| anyhow!("Timestamp parsing failed"))?;
+    Ok(compound_duration::format_wdhms(
+        (now - datetime).to_std().unwrap_or_default().as_secs(),
+    ))
+}
+
+/// Print the connection state of each running namespace, aggregated over the instances using it
+pub fn print_status(command: StatusCommand) -> anyhow::Result<()> {
+    let namespaces = get_lock_namespaces()?;
+    let mut keys = namespaces
+        .keys()
+        .filter(|x| command.namespace.as_ref().is_none_or(|ns| ns == *x))
+        .collect::<Vec<&String>>();
+    keys.sort();
+    if keys.is_empty() {
+        if let Some(ns) = command.namespace {
+            std::mem::forget(namespaces);
+            bail!("No running vopono namespace {ns}, see vopono list namespaces");
+        }
+        println!("No running vopono namespaces");
+    }
+
+    let now = Utc::now();
+    for (i, name) in keys.iter().enumerate() {
+        let locks = namespaces.get(*name).unwrap();
+        let ns = &locks[0].ns;
+        let status = tunnel_status(ns);
+        let mut forwarded = locks
+            .iter()
+            .filter_map(|x| x.forwarded_port)
+            .collect::<Vec<_>>();
+        forwarded.sort();
+        forwarded.dedup();
+        let start = locks.iter().map(|x| x.start).min().unwrap();
+
+        if i > 0 {
+            println!();
+        }
+        println!("{name}");
+        println!("  provider:\t\t{}", ns.provider);
+        println!("  server:\t\t{}", server_description(ns));
+        println!("  protocol:\t\t{}", ns.protocol);
+        println!(
+            "  tunnel:\t\t{} ({})",
+            status.interface.as_deref().unwrap_or("none"),
+            status.state.as_deref().unwrap_or("down")
+        );
+        if ns.wireguard.is_some() {
+            println!(
+                "  last handshake:\t{}",
+                match status.last_handshake {
+                    Some(handshake) => format!("{} ago", format_age(now, handshake)?),
+                    None => "never".to_string(),
+                }
+            );
+        }
+        if let Some(state) = status.openvpn_state {
+            println!("  OpenVPN state:\t{state}");
+        }
+        println!(
+            "  forwarded ports:\t{}",
+            if forwarded.is_empty() {
+                "none".to_string()
+            } else {
+                forwarded
+                    .iter()
+                    .map(|x| x.to_string())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            }
+        );
+        if let Some(traffic) = ns.veth_pair.as_ref().and_then(|x| x.traffic(ns)) {
+            println!(
+                "  traffic:\t\t{} down, {} up",
+                format_bytes(traffic.download),
+                format_bytes(traffic.upload)
+            );
+        }
+        println!("  applications:\t\t{}", locks.len());
+        println!("  uptime:\t\t{}", format_age(now, start)?);
+    }
+    // Avoid triggering Drop for these namespaces
+    std::mem::forget(namespaces);
+    Ok(())
+}
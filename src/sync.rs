@@ -1,14 +1,25 @@
 use anyhow::bail;
 use clap::ValueEnum;
 use dialoguer::MultiSelect;
-use log::{error, info};
-use vopono_core::config::providers::{UiClient, VpnProvider};
+use log::{error, info, warn};
+use std::path::Path;
+use vopono_core::config::providers::azirevpn::AzireVPN;
+use vopono_core::config::providers::mullvad::Mullvad;
+use vopono_core::config::providers::pia::PrivateInternetAccess;
+use vopono_core::config::providers::plugin::PluginProvider;
+use vopono_core::config::providers::{OpenVpnProvider, UiClient, VpnProvider, WireguardProvider};
 use vopono_core::config::vpn::Protocol;
 use vopono_core::util::set_config_permissions;
+use vopono_core::util::sync_filter::SyncFilter;
+use vopono_core::util::verify_configs::verify_configs;
 
 use crate::args::WrappedArg;
 
-pub fn sync_menu(uiclient: &dyn UiClient, protocol: Option<Protocol>) -> anyhow::Result<()> {
+pub fn sync_menu(
+    uiclient: &dyn UiClient,
+    protocol: Option<Protocol>,
+    filter: &SyncFilter,
+) -> anyhow::Result<()> {
     let variants = WrappedArg::<VpnProvider>::value_variants()
         .iter()
         .filter(|x| {
@@ -30,7 +41,7 @@ pub fn sync_menu(uiclient: &dyn UiClient, protocol: Option<Protocol>) -> anyhow:
         .into_iter()
         .flat_map(|x| WrappedArg::<VpnProvider>::from_str(&variants[x], true))
     {
-        synch(provider.to_variant(), &protocol, uiclient)?;
+        synch(provider.to_variant(), &protocol, uiclient, filter)?;
     }
 
     Ok(())
@@ -40,6 +51,7 @@ pub fn synch(
     provider: VpnProvider,
     protocol: &Option<Protocol>,
     uiclient: &dyn UiClient,
+    filter: &SyncFilter,
 ) -> anyhow::Result<()> {
     // TODO: Separate availability from functionality, so we can filter disabled protocols from the UI
     match protocol {
@@ -47,12 +59,14 @@ pub fn synch(
             info!("Starting OpenVPN configuration...");
             let provider = provider.get_dyn_openvpn_provider()?;
             provider.create_openvpn_config(uiclient)?;
+            post_sync(filter, &provider.openvpn_dir()?)?;
             // downcast?
         }
         Some(Protocol::Wireguard) => {
             info!("Starting Wireguard configuration...");
             let provider = provider.get_dyn_wireguard_provider()?;
             provider.create_wireguard_config(uiclient)?;
+            post_sync(filter, &provider.wireguard_dir()?)?;
         }
         Some(Protocol::OpenConnect) => {
             error!("vopono sync not supported for OpenConnect protocol");
@@ -63,6 +77,18 @@ pub fn synch(
         Some(Protocol::Warp) => {
             error!("vopono sync not supported for Cloudflare Warp protocol");
         }
+        Some(Protocol::Xray) => {
+            error!("vopono sync not supported for Xray protocol");
+        }
+        Some(Protocol::Socks5 | Protocol::HttpProxy) => {
+            error!("vopono sync not supported for upstream proxies");
+        }
+        Some(Protocol::Tor) => {
+            error!("vopono sync not supported for Tor protocol");
+        }
+        Some(Protocol::Ikev2) => {
+            error!("vopono sync not supported for IKEv2 protocol");
+        }
         Some(Protocol::None) => {
             error!("vopono sync not supported for None protocol");
         }
@@ -72,10 +98,46 @@ pub fn synch(
             if let Ok(p) = provider.get_dyn_wireguard_provider() {
                 info!("Starting Wireguard configuration...");
                 p.create_wireguard_config(uiclient)?;
+                post_sync(filter, &p.wireguard_dir()?)?;
             }
             if let Ok(p) = provider.get_dyn_openvpn_provider() {
                 info!("Starting OpenVPN configuration...");
                 p.create_openvpn_config(uiclient)?;
+                post_sync(filter, &p.openvpn_dir()?)?;
+            }
+        }
+    }
+
+    set_config_permissions()?;
+    Ok(())
+}
+
+pub fn synch_plugin(
+    plugin_path: &Path,
+    protocol: &Option<Protocol>,
+    uiclient: &dyn UiClient,
+    filter: &SyncFilter,
+) -> anyhow::Result<()> {
+    let plugin = PluginProvider::new(plugin_path)?;
+    let protocols = match protocol {
+        Some(p) => vec![p.clone()],
+        None => plugin.protocols().to_vec(),
+    };
+
+    for protocol in protocols {
+        match protocol {
+            Protocol::Wireguard => {
+                info!("Starting Wireguard configuration...");
+                plugin.create_wireguard_config(uiclient)?;
+                post_sync(filter, &plugin.wireguard_dir()?)?;
+            }
+            Protocol::OpenVpn => {
+                info!("Starting OpenVPN configuration...");
+                plugin.create_openvpn_config(uiclient)?;
+                post_sync(filter, &plugin.openvpn_dir()?)?;
+            }
+            p => {
+                error!("vopono sync via plugin not supported for {p} protocol");
             }
         }
     }
@@ -83,3 +145,59 @@ pub fn synch(
     set_config_permissions()?;
     Ok(())
 }
+
+/// Verify the synced configs in dir, then remove those not matching the filter
+fn post_sync(filter: &SyncFilter, dir: &Path) -> anyhow::Result<()> {
+    verify_configs(dir)?;
+    filter.save(dir);
+    if !filter.is_empty() && filter.apply(dir)? == 0 {
+        warn!(
+            "No configs in {} match the given --countries / --cities filters",
+            dir.display()
+        );
+    }
+    Ok(())
+}
+
+pub fn mullvad_devices(
+    provider: VpnProvider,
+    list_devices: bool,
+    revoke_device: Option<&str>,
+    uiclient: &dyn UiClient,
+) -> anyhow::Result<()> {
+    if provider != VpnProvider::Mullvad {
+        bail!("Device management is only supported for Mullvad");
+    }
+    let mullvad = Mullvad {};
+    if let Some(device) = revoke_device {
+        mullvad.revoke_device(uiclient, device)?;
+    } else if list_devices {
+        let devices = mullvad.list_devices(uiclient)?;
+        println!("name\tpubkey\tipv4_address\tcreated");
+        for device in devices {
+            println!(
+                "{}\t{}\t{}\t{}",
+                device.name, device.pubkey, device.ipv4_address, device.created
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Move plaintext provider credentials to the system keyring
+pub fn migrate_credentials(provider: VpnProvider) -> anyhow::Result<()> {
+    let mut migrated = false;
+    if let Ok(openvpn_provider) = provider.get_dyn_openvpn_provider() {
+        migrated |= openvpn_provider.migrate_openvpn_auth()?;
+    }
+    if provider == VpnProvider::PrivateInternetAccess {
+        migrated |= PrivateInternetAccess {}.migrate_wireguard_auth()?;
+    }
+    if provider == VpnProvider::AzireVPN {
+        migrated |= AzireVPN {}.migrate_access_token()?;
+    }
+    if !migrated {
+        info!("No plaintext credentials found for {provider}");
+    }
+    Ok(())
+}
@@ -0,0 +1,310 @@
+use super::args::{TuiCommand, WrappedArg};
+use anyhow::bail;
+use clap::ValueEnum;
+use dialoguer::{Input, Select};
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use vopono_core::config::providers::{ServerInfo, VpnProvider};
+use vopono_core::config::vpn::Protocol;
+use vopono_core::network::openvpn::get_remotes_from_config;
+use vopono_core::network::wireguard::Wireguard;
+use vopono_core::util::get_configs_from_alias;
+use vopono_core::util::parallel::parallel_map;
+
+/// Synced config directories for the protocols of the provider which have any
+fn synced_protocols(provider: &VpnProvider) -> Vec<(Protocol, PathBuf)> {
+    let mut protocols = Vec::new();
+    if let Ok(dir) = provider
+        .get_dyn_openvpn_provider()
+        .and_then(|x| x.openvpn_dir())
+    {
+        protocols.push((Protocol::OpenVpn, dir));
+    }
+    if let Ok(dir) = provider
+        .get_dyn_wireguard_provider()
+        .and_then(|x| x.wireguard_dir())
+    {
+        protocols.push((Protocol::Wireguard, dir));
+    }
+    protocols.retain(|(_, dir)| dir.read_dir().is_ok_and(|mut x| x.next().is_some()));
+    protocols
+}
+
+/// Host of the VPN server in a config file
+fn server_host(protocol: &Protocol, config: &Path) -> Option<String> {
+    match protocol {
+        Protocol::Wireguard => Wireguard::config_from_file(config)
+            .ok()
+            .map(|x| endpoint_host(&x.peer.endpoint.to_string())),
+        _ => get_remotes_from_config(config)
+            .ok()?
+            .first()
+            .map(|x| x.host.to_string()),
+    }
+}
+
+/// Host of a Wireguard endpoint, without the port and the brackets of IPv6 addresses
+fn endpoint_host(endpoint: &str) -> String {
+    endpoint
+        .rsplit_once(':')
+        .map_or(endpoint, |(host, _)| host)
+        .trim_matches(|c| c == '[' || c == ']')
+        .to_string()
+}
+
+/// Round-trip time of a single ping, for the latency column
+fn ping_ms(host: &str) -> Option<u32> {
+    let output = Command::new("ping")
+        .args(["-c", "1", "-W", "1", host])
+        .stderr(Stdio::null())
+        .output()
+        .ok()?;
+    parse_ping_ms(&String::from_utf8_lossy(&output.stdout))
+}
+
+fn parse_ping_ms(output: &str) -> Option<u32> {
+    output
+        .split_whitespace()
+        .find_map(|x| x.strip_prefix("time="))
+        .and_then(|x| x.parse::<f64>().ok())
+        .map(|x| x.round() as u32)
+}
+
+/// Name of the server, the config file name without the extension
+fn server_name(info: &ServerInfo) -> String {
+    info.config_file
+        .file_stem()
+        .map(|x| x.to_string_lossy().to_string())
+        .unwrap_or_default()
+}
+
+fn server_row(info: &ServerInfo, width: usize) -> String {
+    format!(
+        "{:width$}  {:20}  {:>5}  {:>7}  {}",
+        server_name(info),
+        info.city.as_deref().unwrap_or("-"),
+        info.load.map_or("-".to_string(), |x| format!("{x}%")),
+        info.latency_ms
+            .map_or("-".to_string(), |x| format!("{x}ms")),
+        info.features
+            .iter()
+            .map(|x| x.to_string())
+            .collect::<Vec<_>>()
+            .join(",")
+    )
+}
+
+/// Sorted countries of the servers with their number of servers, - for unknown
+fn countries(servers: &[ServerInfo]) -> Vec<(String, usize)> {
+    let mut countries: Vec<(String, usize)> = Vec::new();
+    for server in servers {
+        let country = server.country.as_deref().unwrap_or("-");
+        match countries.iter_mut().find(|(x, _)| x == country) {
+            Some((_, count)) => *count += 1,
+            None => countries.push((country.to_string(), 1)),
+        }
+    }
+    countries.sort();
+    countries
+}
+
+/// Pick a provider, country and server from the synced configs, then replace this process with
+/// vopono exec for the chosen server
+pub fn tui(command: TuiCommand, verbose: bool, silent: bool, askpass: bool) -> anyhow::Result<()> {
+    let providers: Vec<(VpnProvider, Vec<(Protocol, PathBuf)>)> =
+        WrappedArg::<VpnProvider>::value_variants()
+            .iter()
+            .map(|x| x.to_variant())
+            .filter(|x| ![VpnProvider::Custom, VpnProvider::None, VpnProvider::Warp].contains(x))
+            .map(|x| {
+                let protocols = synced_protocols(&x);
+                (x, protocols)
+            })
+            .filter(|(_, protocols)| !protocols.is_empty())
+            .collect();
+    if providers.is_empty() {
+        bail!("No synced server lists, run vopono sync first");
+    }
+    let selection = Select::new()
+        .with_prompt("VPN provider")
+        .items(
+            &providers
+                .iter()
+                .map(|(x, _)| x.to_string())
+                .collect::<Vec<_>>(),
+        )
+        .default(0)
+        .interact()?;
+    let (provider, protocols) = &providers[selection];
+
+    let (protocol, dir) = if protocols.len() == 1 {
+        &protocols[0]
+    } else {
+        let default_protocol = provider.get_dyn_provider().default_protocol();
+        let selection = Select::new()
+            .with_prompt("Protocol")
+            .items(
+                &protocols
+                    .iter()
+                    .map(|(x, _)| x.to_string())
+                    .collect::<Vec<_>>(),
+            )
+            .default(
+                protocols
+                    .iter()
+                    .position(|(x, _)| *x == default_protocol)
+                    .unwrap_or(0),
+            )
+            .interact()?;
+        &protocols[selection]
+    };
+
+    let mut configs = get_configs_from_alias(dir, "");
+    configs.sort();
+    let servers = provider.get_dyn_provider().server_info(&configs);
+    let countries = countries(&servers);
+    let items = countries
+        .iter()
+        .map(|(country, count)| format!("{country} ({count})"))
+        .collect::<Vec<_>>();
+    let selection = Select::new()
+        .with_prompt("Country")
+        .items(&items)
+        .default(0)
+        .max_length(20)
+        .interact()?;
+    let country = &countries[selection].0;
+    let mut servers: Vec<ServerInfo> = servers
+        .into_iter()
+        .filter(|x| x.country.as_deref().unwrap_or("-") == country)
+        .collect();
+
+    if command.ping {
+        let latencies = parallel_map(&servers, "Pinging servers", |info| {
+            Ok(server_host(protocol, &info.config_file).and_then(|host| ping_ms(&host)))
+        });
+        for (info, latency) in servers.iter_mut().zip(latencies) {
+            if let Ok(Some(latency)) = latency {
+                info.latency_ms = Some(latency);
+            }
+        }
+        servers.sort_by_key(|x| x.latency_ms.unwrap_or(u32::MAX));
+    }
+    let width = servers
+        .iter()
+        .map(|x| server_name(x).len())
+        .max()
+        .unwrap_or_default();
+    let selection = Select::new()
+        .with_prompt(format!(
+            "Server ({:width$}  {:20}  {:>5}  {:>7}  features)",
+            "name", "city", "load", "latency"
+        ))
+        .items(
+            &servers
+                .iter()
+                .map(|x| server_row(x, width))
+                .collect::<Vec<_>>(),
+        )
+        .default(0)
+        .max_length(20)
+        .interact()?;
+    let server = server_name(&servers[selection]);
+    if server.is_empty() {
+        bail!("Invalid config file name");
+    }
+
+    let application = match command.application {
+        Some(application) => application,
+        None => Input::<String>::new()
+            .with_prompt("Application to run")
+            .interact_text()?,
+    };
+
+    let mut exec = Command::new(std::env::current_exe()?);
+    if verbose {
+        exec.arg("--verbose");
+    }
+    if silent {
+        exec.arg("--silent");
+    }
+    if askpass {
+        exec.arg("--askpass");
+    }
+    exec.args([
+        "exec",
+        "--provider",
+        &provider.to_string(),
+        "--protocol",
+        &protocol.to_string(),
+        "--server",
+        &server,
+        &application,
+    ]);
+    log::debug!("Running: {exec:?}");
+    // Only returns on failure
+    Err(exec.exec().into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use vopono_core::config::providers::ServerFeature;
+
+    fn server(config: &str, country: Option<&str>) -> ServerInfo {
+        ServerInfo {
+            config_file: PathBuf::from(config),
+            country: country.map(String::from),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn endpoint_and_ping_parsing() {
+        assert_eq!(endpoint_host("185.213.154.68:51820"), "185.213.154.68");
+        assert_eq!(
+            endpoint_host("[2a03:1b20:1:f011::a01f]:51820"),
+            "2a03:1b20:1:f011::a01f"
+        );
+        assert_eq!(
+            endpoint_host("se-got-wg-001.relays.mullvad.net"),
+            "se-got-wg-001.relays.mullvad.net"
+        );
+        let ping = "PING 1.1.1.1 (1.1.1.1) 56(84) bytes of data.\n64 bytes from 1.1.1.1: icmp_seq=1 ttl=57 time=12.6 ms\n";
+        assert_eq!(parse_ping_ms(ping), Some(13));
+        assert_eq!(
+            parse_ping_ms("1 packets transmitted, 0 received, 100% packet loss"),
+            None
+        );
+    }
+
+    #[test]
+    fn country_counts_and_rows() {
+        let servers = [
+            server("/c/se-got.conf", Some("se")),
+            server("/c/ch-zrh.conf", Some("ch")),
+            server("/c/se-sto.conf", Some("se")),
+            server("/c/unknown.conf", None),
+        ];
+        assert_eq!(
+            countries(&servers),
+            [
+                ("-".to_string(), 1),
+                ("ch".to_string(), 1),
+                ("se".to_string(), 2)
+            ]
+        );
+        let mut info = server("/c/se-got.conf", Some("se"));
+        info.load = Some(42);
+        info.features = vec![ServerFeature::Owned, ServerFeature::Daita];
+        assert_eq!(server_name(&info), "se-got");
+        assert_eq!(
+            server_row(&info, 8),
+            format!(
+                "se-got    {:20}  {:>5}  {:>7}  Owned,Daita",
+                "-", "42%", "-"
+            )
+        );
+    }
+}
@@ -2,12 +2,15 @@ mod wireguard;
 
 use super::{ConfigurationChoice, Input, Password, Provider, UiClient, WireguardProvider};
 use crate::config::vpn::Protocol;
+use crate::util::keyring;
+use crate::util::private_files::replace_private_file;
 use anyhow::Context;
 use serde::Deserialize;
 use serde_json::json;
-use std::io::Write;
 use std::{net::IpAddr, path::PathBuf};
 
+const TOKEN_KEY: &str = "token";
+
 // AzireVPN details: https://www.azirevpn.com/docs/servers
 // servers: https://www.azirevpn.com/service/servers#openvpn
 pub struct AzireVPN {}
@@ -208,18 +211,30 @@ impl AzireVPN {
         Ok((username.to_string(), password.to_string()))
     }
 
-    fn token_file_path(&self) -> PathBuf {
-        self.provider_dir().unwrap().join("token.txt")
+    fn token_file_path(&self) -> anyhow::Result<PathBuf> {
+        Ok(self.provider_dir()?.join("token.txt"))
     }
 
-    pub fn get_access_token(&self, uiclient: &dyn UiClient) -> anyhow::Result<String> {
-        let token_file_path = self.token_file_path();
+    /// API token from the keyring, or the token file on systems without a keyring
+    fn cached_access_token(&self) -> anyhow::Result<Option<String>> {
+        if let Some(token) = keyring::lookup_secret(&self.alias(), TOKEN_KEY) {
+            log::debug!("AzireVPN Auth Token read from the system keyring");
+            return Ok(Some(token));
+        }
+        let token_file_path = self.token_file_path()?;
         if token_file_path.exists() {
             let token = std::fs::read_to_string(&token_file_path)?;
             log::debug!(
                 "AzireVPN Auth Token read from {}",
-                self.token_file_path().display()
+                token_file_path.display()
             );
+            return Ok(Some(token));
+        }
+        Ok(None)
+    }
+
+    pub fn get_access_token(&self, uiclient: &dyn UiClient) -> anyhow::Result<String> {
+        if let Some(token) = self.cached_access_token()? {
             return Ok(token);
         }
         let (username, password) = self.request_userpass(uiclient)?;
@@ -248,30 +263,55 @@ impl AzireVPN {
             ))
         }?;
 
-        // log::debug!("auth_response: {:?}", &auth_response);
-        let mut outfile = std::fs::File::create(self.token_file_path())?;
-        write!(outfile, "{}", auth_response_data.key)?;
+        let token = auth_response_data.key;
+        if keyring::keyring_available()
+            && keyring::store_secret(&self.alias(), TOKEN_KEY, &token).is_ok()
+        {
+            log::debug!("AzireVPN Auth Token stored in the system keyring");
+            return Ok(token);
+        }
+        let token_file_path = self.token_file_path()?;
+        replace_private_file(&token_file_path, token.as_bytes())?;
         log::debug!(
             "AzireVPN Auth Token written to {}",
-            self.token_file_path().display()
+            token_file_path.display()
         );
-
-        Ok(auth_response_data.key)
+        Ok(token)
     }
 
     pub fn read_access_token(&self) -> anyhow::Result<String> {
-        let token_file_path = self.token_file_path();
-        if token_file_path.exists() {
-            let token = std::fs::read_to_string(&token_file_path)?;
-            log::debug!(
-                "AzireVPN Auth Token read from {}",
-                self.token_file_path().display()
-            );
-            return Ok(token);
+        self.cached_access_token()?.ok_or_else(|| {
+            anyhow::anyhow!("AzireVPN Auth Token not found, run vopono sync azirevpn")
+        })
+    }
+
+    /// Remove the cached token, e.g. if it was rejected by the API
+    pub fn clear_access_token(&self) -> anyhow::Result<()> {
+        if keyring::keyring_available() {
+            keyring::clear_secret(&self.alias(), TOKEN_KEY)?;
         }
-        Err(anyhow::anyhow!(
-            "AzireVPN Auth Token not found at {}",
-            token_file_path.display()
-        ))
+        // Already gone if the token did not come from the file or was renewed meanwhile
+        match std::fs::remove_file(self.token_file_path()?) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+
+    /// Move the API token in token.txt to the system keyring. Returns false if there was no
+    /// token file.
+    pub fn migrate_access_token(&self) -> anyhow::Result<bool> {
+        let token_file_path = self.token_file_path()?;
+        let Ok(token) = std::fs::read_to_string(&token_file_path) else {
+            return Ok(false);
+        };
+        if !keyring::keyring_available() {
+            return Err(anyhow::anyhow!(
+                "No system keyring available, secret-tool (libsecret) and a session bus are required"
+            ));
+        }
+        keyring::store_secret(&self.alias(), TOKEN_KEY, token.trim())?;
+        std::fs::remove_file(&token_file_path)?;
+        log::info!("Moved the AzireVPN API token to the system keyring");
+        Ok(true)
     }
 }
@@ -3,17 +3,19 @@ use super::{LocationsResponse, WireguardProvider};
 use crate::config::providers::azirevpn::{
     ExistingDevicesResponse, LocationResponse, ReplaceKeyResponse, UserProfileResponse,
 };
-use crate::config::providers::{BoolChoice, UiClient};
+use crate::config::providers::{BoolChoice, Provider, UiClient};
 use crate::network::wireguard::{
     WireguardConfig, WireguardEndpoint, WireguardInterface, WireguardPeer,
 };
 use crate::util::country_map::code_to_country_map;
 use crate::util::delete_all_files_in_dir;
+use crate::util::server_cache::cached_server_list;
 use crate::util::wireguard::{WgKey, generate_keypair, generate_public_key};
 use anyhow::Context;
 use ipnet::IpNet;
 use log::{debug, info};
 use regex::Regex;
+use reqwest::StatusCode;
 use reqwest::blocking::Client;
 use serde::{Deserialize, Serialize};
 use serde_json::json;
@@ -122,11 +124,25 @@ impl WireguardProvider for AzireVPN {
         let country_map = code_to_country_map();
 
         // This creates an API token for the user if we do not have one cached
-        let token = self.get_access_token(uiclient)?;
-        let user_profile_response: UserProfileResponse = client
+        let mut token = self.get_access_token(uiclient)?;
+        let mut response = client
             .get("https://api.azirevpn.com/v3/users/me")
-            .header("Authorization", format!("Bearer {token}"))
-            .send()?
+            .bearer_auth(&token)
+            .send()?;
+        if matches!(
+            response.status(),
+            StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN
+        ) {
+            // Cached token has expired or been revoked, so log in again
+            log::warn!("AzireVPN access token rejected, requesting a new token");
+            self.clear_access_token()?;
+            token = self.get_access_token(uiclient)?;
+            response = client
+                .get("https://api.azirevpn.com/v3/users/me")
+                .bearer_auth(&token)
+                .send()?;
+        }
+        let user_profile_response: UserProfileResponse = response
             .json().with_context(|| "Failed to parse AzireVPN user profile response - if this persists try deleting cached data at ~/.config/vopono/azire/ and/or manually deleting access tokens at https://manager.azirevpn.com/account/token")?;
 
         if !user_profile_response.data.is_active {
@@ -264,7 +280,17 @@ impl WireguardProvider for AzireVPN {
         }
 
         // This gets locations data from token
-        let location_resp: LocationsResponse = client.get(self.locations_url()).send()?.json()?;
+        let location_resp: LocationsResponse = serde_json::from_value(cached_server_list(
+            &self.provider_dir()?,
+            "locations",
+            || {
+                Ok(client
+                    .get(self.locations_url())
+                    .send()?
+                    .error_for_status()?
+                    .json()?)
+            },
+        )?)?;
 
         debug!("locations_response: {:?}", &location_resp);
         let locations: Vec<LocationResponse> = location_resp.locations;
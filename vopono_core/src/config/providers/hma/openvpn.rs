@@ -1,7 +1,8 @@
 use super::HMA;
-use super::{ConfigurationChoice, OpenVpnProvider};
+use super::{ConfigurationChoice, OpenVpnProvider, Provider};
 use crate::config::providers::{Input, Password, UiClient};
 use crate::util::delete_all_files_in_dir;
+use crate::util::sync_cache::SyncCache;
 use log::{debug, info};
 use std::fmt::Display;
 use std::fs::File;
@@ -44,8 +45,9 @@ impl OpenVpnProvider for HMA {
         debug!("Requesting ConfigType");
         let config_choice = uiclient.get_configuration_choice(&ConfigType::default())?;
         let url = "https://vpn.hidemyass.com/vpn-config/vpn-configs.zip";
-        let zipfile = reqwest::blocking::get(url)?;
-        let mut zip = ZipArchive::new(Cursor::new(zipfile.bytes()?))?;
+        let cache = SyncCache::open(&self.provider_dir()?)?;
+        let zipfile = cache.fetch(url, reqwest::blocking::Client::new().get(url))?;
+        let mut zip = ZipArchive::new(Cursor::new(zipfile))?;
         let openvpn_dir = self.openvpn_dir()?;
         create_dir_all(&openvpn_dir)?;
         for i in 0..zip.len() {
@@ -98,12 +100,8 @@ impl OpenVpnProvider for HMA {
 
         // Write OpenVPN credentials file
         let (user, pass) = self.prompt_for_auth(uiclient)?;
-        let auth_file = self.auth_file_path()?;
-        if auth_file.is_some() {
-            let mut outfile = File::create(auth_file.unwrap())?;
-            write!(outfile, "{user}\n{pass}")?;
-            info!("HMA OpenVPN config written to {}", openvpn_dir.display());
-        }
+        self.store_openvpn_auth(&user, &pass)?;
+        info!("HMA OpenVPN config written to {}", openvpn_dir.display());
         Ok(())
     }
 }
@@ -1,8 +1,11 @@
 mod openvpn;
 mod wireguard;
 
-use super::{ConfigurationChoice, OpenVpnProvider, Provider, WireguardProvider};
+use super::{
+    ConfigurationChoice, OpenVpnProvider, Provider, ServerFeature, ServerInfo, WireguardProvider,
+};
 use crate::config::vpn::Protocol;
+use std::path::PathBuf;
 
 #[allow(clippy::upper_case_acronyms)]
 pub struct IVPN {}
@@ -19,4 +22,27 @@ impl Provider for IVPN {
     fn default_protocol(&self) -> Protocol {
         Protocol::Wireguard
     }
+
+    fn server_info(&self, configs: &[PathBuf]) -> Vec<ServerInfo> {
+        let metadata = self.relay_metadata().unwrap_or_default();
+        configs
+            .iter()
+            .map(|config| {
+                let mut info = ServerInfo::from_config(config);
+                if let Some(relay) = metadata.get(info.file_name()) {
+                    // Wireguard configs are named {country}-{code}-{city}.conf
+                    info.city = info
+                        .file_name()
+                        .trim_end_matches(".conf")
+                        .split('-')
+                        .nth(2)
+                        .map(|x| x.to_string());
+                    if relay.multihop_port != 0 {
+                        info.features.push(ServerFeature::Multihop);
+                    }
+                }
+                info
+            })
+            .collect()
+    }
 }
@@ -1,9 +1,10 @@
 use super::IVPN;
-use super::OpenVpnProvider;
+use super::{OpenVpnProvider, Provider};
 use crate::config::providers::Input;
 use crate::config::providers::UiClient;
 use crate::config::vpn::OpenVpnProtocol;
 use crate::util::delete_all_files_in_dir;
+use crate::util::sync_cache::SyncCache;
 use log::{debug, info};
 use reqwest::Url;
 use std::fs::File;
@@ -50,8 +51,12 @@ impl OpenVpnProvider for IVPN {
         delete_all_files_in_dir(&openvpn_dir)?;
         let protocol = uiclient.get_configuration_choice(&OpenVpnProtocol::default())?;
         let url = self.build_url(&OpenVpnProtocol::index_to_variant(protocol))?;
-        let zipfile = reqwest::blocking::get(url)?;
-        let mut zip = ZipArchive::new(Cursor::new(zipfile.bytes()?))?;
+        let cache = SyncCache::open(&self.provider_dir()?)?;
+        let zipfile = cache.fetch(
+            url.as_str(),
+            reqwest::blocking::Client::new().get(url.clone()),
+        )?;
+        let mut zip = ZipArchive::new(Cursor::new(zipfile))?;
         let openvpn_dir = self.openvpn_dir()?;
         create_dir_all(&openvpn_dir)?;
         for i in 0..zip.len() {
@@ -118,12 +123,8 @@ impl OpenVpnProvider for IVPN {
 
         // Write OpenVPN credentials file
         let (user, pass) = self.prompt_for_auth(uiclient)?;
-        let auth_file = self.auth_file_path()?;
-        if auth_file.is_some() {
-            let mut outfile = File::create(auth_file.unwrap())?;
-            write!(outfile, "{user}\n{pass}")?;
-            info!("IVPN OpenVPN config written to {}", openvpn_dir.display());
-        }
+        self.store_openvpn_auth(&user, &pass)?;
+        info!("IVPN OpenVPN config written to {}", openvpn_dir.display());
         Ok(())
     }
 }
@@ -3,19 +3,25 @@ use super::IVPN;
 use super::WireguardProvider;
 use crate::config::providers::Input;
 use crate::config::providers::InputNumericu16;
+use crate::config::providers::Provider;
 use crate::config::providers::UiClient;
-use crate::network::wireguard::WireguardEndpoint;
+use crate::network::netns::MultihopServers;
+use crate::network::wireguard::{Wireguard, WireguardEndpoint};
 use crate::network::wireguard::{WireguardConfig, WireguardInterface, WireguardPeer};
 use crate::util::delete_all_files_in_dir;
+use crate::util::server_cache::cached_server_list;
 use crate::util::wireguard::{WgKey, generate_keypair, generate_public_key};
+use anyhow::{Context, anyhow};
 use ipnet::{IpNet, Ipv4Net};
-use log::info;
+use log::{info, warn};
 use regex::Regex;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::fmt::Display;
 use std::fs::create_dir_all;
 use std::io::Write;
 use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
 use strum::IntoEnumIterator;
 use strum_macros::EnumIter;
@@ -132,13 +138,15 @@ impl WireguardProvider for IVPN {
         create_dir_all(&wireguard_dir)?;
         delete_all_files_in_dir(&wireguard_dir)?;
 
-        let relays_str = include_str!("./ivpn_wg_hosts.csv");
-        let mut reader = csv::Reader::from_reader(relays_str.as_bytes());
-        let mut relays = Vec::new();
-        for record in reader.deserialize() {
-            let relay: WireguardRelay = record?;
-            relays.push(relay);
-        }
+        let relays = match fetch_relays(&self.provider_dir()?) {
+            Ok(relays) => relays,
+            Err(e) => {
+                warn!(
+                    "Failed to get iVPN server list, using bundled list (multihop unavailable): {e}"
+                );
+                bundled_relays()?
+            }
+        };
 
         let wg_key_choice = WgKeyChoice::index_to_variant(
             uiclient.get_configuration_choice(&WgKeyChoice::default())?,
@@ -189,6 +197,7 @@ impl WireguardProvider for IVPN {
         let allowed_ips = vec![IpNet::from_str("0.0.0.0/0")?];
 
         let code_map = crate::util::country_map::code_to_country_map();
+        let mut metadata: HashMap<String, RelayMetadata> = HashMap::new();
         // TODO: avoid hacky regex for TOML -> wireguard config conversion
         let re = Regex::new(r"=\s\[(?P<value>[^\]]+)\]")?;
         for relay in relays.iter() {
@@ -204,14 +213,32 @@ impl WireguardProvider for IVPN {
                 peer: wireguard_peer,
             };
 
-            let mut strsplit = relay.country.split('-');
-            let country_code = strsplit.next().unwrap();
-            let city = strsplit.next().unwrap();
-            let country_name = code_map
-                .get(country_code)
-                .unwrap_or_else(|| panic!("Could not find code in map: {country_code}"));
+            // e.g. us-ny, skipping relays we cannot name
+            let Some((country_code, city)) = relay.country.split_once('-') else {
+                warn!(
+                    "Skipping iVPN relay {} with unexpected location: {}",
+                    relay.hostname, relay.country
+                );
+                continue;
+            };
+            let city = city.split('-').next().unwrap_or(city);
+            let Some(country_name) = code_map.get(country_code) else {
+                warn!(
+                    "Skipping iVPN relay {} with unknown country code: {country_code}",
+                    relay.hostname
+                );
+                continue;
+            };
 
-            let path = wireguard_dir.join(format!("{country_name}-{country_code}-{city}.conf"));
+            let filename = format!("{country_name}-{country_code}-{city}.conf");
+            metadata.insert(
+                filename.clone(),
+                RelayMetadata {
+                    hostname: relay.hostname.clone(),
+                    multihop_port: relay.multihop_port,
+                },
+            );
+            let path = wireguard_dir.join(filename);
 
             let mut toml = toml::to_string(&wireguard_conf)?;
             toml.retain(|c| c != '"');
@@ -224,6 +251,11 @@ impl WireguardProvider for IVPN {
             }
         }
 
+        std::fs::write(
+            self.relay_metadata_path()?,
+            serde_json::to_string(&metadata)?,
+        )?;
+
         info!(
             "iVPN Wireguard config written to {}",
             wireguard_dir.display()
@@ -275,13 +307,149 @@ fn prompt_for_wg_key(uiclient: &dyn UiClient) -> anyhow::Result<WgKey> {
     })
 }
 
-#[allow(dead_code)]
 #[derive(Deserialize, Debug)]
 struct WireguardRelay {
     country: String,
     hostname: String,
     ip: IpAddr,
     pubkey: String,
+    #[serde(default)]
+    multihop_port: u16,
+}
+
+/// Relay details not present in the Wireguard config files, saved during sync
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RelayMetadata {
+    pub hostname: String,
+    pub multihop_port: u16,
+}
+
+#[derive(Deserialize, Debug)]
+struct ServerList {
+    wireguard: Vec<ServerLocation>,
+}
+
+#[derive(Deserialize, Debug)]
+struct ServerLocation {
+    country_code: String,
+    city: String,
+    hosts: Vec<ServerHost>,
+}
+
+#[derive(Deserialize, Debug)]
+struct ServerHost {
+    hostname: String,
+    host: IpAddr,
+    public_key: String,
+    #[serde(default)]
+    multihop_port: u16,
+}
+
+/// Get the current Wireguard servers (including multihop ports) from the iVPN API
+fn fetch_relays(provider_dir: &Path) -> anyhow::Result<Vec<WireguardRelay>> {
+    let servers: ServerList =
+        serde_json::from_value(cached_server_list(provider_dir, "servers", || {
+            Ok(
+                reqwest::blocking::get("https://api.ivpn.net/v5/servers.json")?
+                    .error_for_status()?
+                    .json()?,
+            )
+        })?)?;
+    Ok(servers
+        .wireguard
+        .into_iter()
+        .flat_map(|location| {
+            // Match the bundled list format, e.g. us-losangeles-ca
+            let country = format!("{},{}", location.country_code, location.city)
+                .replace(",", "-")
+                .replace(" ", "")
+                .to_lowercase()
+                .replace("gb-", "uk-");
+            location.hosts.into_iter().map(move |host| WireguardRelay {
+                country: country.clone(),
+                hostname: host.hostname,
+                ip: host.host,
+                pubkey: host.public_key,
+                multihop_port: host.multihop_port,
+            })
+        })
+        .collect())
+}
+
+fn bundled_relays() -> anyhow::Result<Vec<WireguardRelay>> {
+    let relays_str = include_str!("./ivpn_wg_hosts.csv");
+    let mut reader = csv::Reader::from_reader(relays_str.as_bytes());
+    let mut relays = Vec::new();
+    for record in reader.deserialize() {
+        let relay: WireguardRelay = record?;
+        relays.push(relay);
+    }
+    Ok(relays)
+}
+
+impl IVPN {
+    fn relay_metadata_path(&self) -> anyhow::Result<PathBuf> {
+        Ok(self.wireguard_dir()?.join("relays.json"))
+    }
+
+    /// Relay metadata written during Wireguard sync, keyed by config file name
+    pub(super) fn relay_metadata(&self) -> anyhow::Result<HashMap<String, RelayMetadata>> {
+        Ok(serde_json::from_str(
+            &std::fs::read_to_string(self.relay_metadata_path()?)
+                .context("No iVPN relay metadata found, re-run vopono sync")?,
+        )?)
+    }
+
+    /// Create a Wireguard config connecting to the exit server via the entry server using
+    /// iVPN port-based multihop
+    pub fn create_multihop_config(
+        &self,
+        entry_config: &Path,
+        exit_config: &Path,
+    ) -> anyhow::Result<(PathBuf, MultihopServers)> {
+        let file_name = |p: &Path| -> anyhow::Result<String> {
+            Ok(p.file_name()
+                .and_then(|x| x.to_str())
+                .ok_or_else(|| anyhow!("Invalid config file name: {}", p.display()))?
+                .to_string())
+        };
+        let entry_name = file_name(entry_config)?;
+        let exit_name = file_name(exit_config)?;
+        if entry_name == exit_name {
+            return Err(anyhow!(
+                "Multihop entry and exit servers must differ, both chose: {}",
+                entry_name
+            ));
+        }
+
+        let metadata = self.relay_metadata()?;
+        let exit_meta = metadata
+            .get(&exit_name)
+            .ok_or_else(|| anyhow!("No relay metadata for {}, re-run vopono sync", exit_name))?;
+        let entry_meta = metadata
+            .get(&entry_name)
+            .ok_or_else(|| anyhow!("No relay metadata for {}, re-run vopono sync", entry_name))?;
+        if exit_meta.multihop_port == 0 {
+            return Err(anyhow!(
+                "No multihop port known for {}, re-run vopono sync",
+                exit_name
+            ));
+        }
+
+        let hops = MultihopServers {
+            entry: entry_meta.hostname.clone(),
+            exit: exit_meta.hostname.clone(),
+        };
+        let path = Wireguard::write_multihop_config(
+            &self.alias(),
+            entry_config,
+            exit_config,
+            exit_meta.multihop_port,
+            &hops,
+        )?;
+        info!("Using iVPN multihop: {hops}");
+        Ok((path, hops))
+    }
 }
 
 fn request_port(uiclient: &dyn UiClient) -> anyhow::Result<u16> {
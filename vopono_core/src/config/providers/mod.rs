@@ -1,22 +1,25 @@
 mod airvpn;
 pub mod azirevpn;
 mod hma;
-mod ivpn;
+pub mod ivpn;
 mod mozilla;
-mod mullvad;
+pub mod mullvad;
 mod nordvpn;
 pub mod pia;
-mod protonvpn;
+pub mod plugin;
+pub mod protonvpn;
 mod ui;
 mod warp;
 
 use crate::config::vpn::Protocol;
-use crate::util::vopono_dir;
+use crate::util::{credentials, keyring, vopono_dir};
 use anyhow::anyhow;
+use log::{info, warn};
 use serde::{Deserialize, Serialize};
 use std::{
+    collections::HashMap,
     fs::File,
-    io::{BufRead, BufReader},
+    io::{BufRead, BufReader, Write},
     net::IpAddr,
     path::{Path, PathBuf},
 };
@@ -92,6 +95,7 @@ impl VpnProvider {
         match self {
             Self::PrivateInternetAccess => Ok(Box::new(pia::PrivateInternetAccess {})),
             Self::Mullvad => Ok(Box::new(mullvad::Mullvad {})),
+            Self::ProtonVPN => Ok(Box::new(protonvpn::ProtonVPN {})),
             Self::MozillaVPN => Ok(Box::new(mozilla::MozillaVPN {})),
             Self::AzireVPN => Ok(Box::new(azirevpn::AzireVPN {})),
             Self::IVPN => Ok(Box::new(ivpn::IVPN {})),
@@ -124,6 +128,74 @@ pub trait Provider {
     fn provider_dir(&self) -> anyhow::Result<PathBuf> {
         Ok(vopono_dir()?.join(self.alias()))
     }
+
+    /// Typed metadata for the given synced config files
+    /// By default only the country is derived from the filename, providers override this with
+    /// the server details saved during sync
+    fn server_info(&self, configs: &[PathBuf]) -> Vec<ServerInfo> {
+        configs.iter().map(|x| ServerInfo::from_config(x)).collect()
+    }
+
+    /// Current load in percent of the servers behind the given configs, fetched from the
+    /// provider API for load-aware server selection
+    fn live_server_load(&self, _configs: &[PathBuf]) -> anyhow::Result<HashMap<PathBuf, u8>> {
+        Err(anyhow!("{} does not publish server load", self.alias()))
+    }
+}
+
+/// Server features which may be used to select servers
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize, Clone, Copy, Display, EnumIter)]
+pub enum ServerFeature {
+    PortForwarding,
+    Multihop,
+    SecureCore,
+    Free,
+    Owned,
+    RamOnly,
+    Daita,
+    Streaming,
+    VirtualLocation,
+}
+
+/// Details of the server behind a synced config file
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ServerInfo {
+    pub config_file: PathBuf,
+    pub country: Option<String>,
+    pub city: Option<String>,
+    /// Server load in percent, if published by the provider
+    pub load: Option<u8>,
+    /// Measured round-trip time to the server, if known
+    pub latency_ms: Option<u32>,
+    pub features: Vec<ServerFeature>,
+}
+
+impl ServerInfo {
+    /// Server info with the country taken from the {country}-{host_alias} filename
+    pub fn from_config(config_file: &Path) -> Self {
+        let country = config_file
+            .file_stem()
+            .and_then(|x| x.to_str())
+            .and_then(|x| x.split('-').next())
+            .filter(|x| !x.is_empty())
+            .map(|x| x.to_string());
+        Self {
+            config_file: config_file.to_path_buf(),
+            country,
+            ..Default::default()
+        }
+    }
+
+    pub fn file_name(&self) -> &str {
+        self.config_file
+            .file_name()
+            .and_then(|x| x.to_str())
+            .unwrap_or_default()
+    }
+
+    pub fn has_feature(&self, feature: ServerFeature) -> bool {
+        self.features.contains(&feature)
+    }
 }
 
 /// This trait is implemented if the VPN provider has Wireguard support
@@ -138,6 +210,15 @@ pub trait WireguardProvider: Provider {
     fn wireguard_preup(&self, _config_file: &Path) -> anyhow::Result<()> {
         Ok(())
     }
+
+    /// Register a newly generated keypair with the provider for a single connection, returning
+    /// the path of a temporary config using it (the synced config is left unchanged)
+    fn wireguard_ephemeral_preup(&self, _config_file: &Path) -> anyhow::Result<PathBuf> {
+        Err(anyhow!(
+            "{} does not support ephemeral Wireguard keys",
+            self.alias()
+        ))
+    }
 }
 
 /// This trait is implemented if the VPN provider has OpenVPN support
@@ -149,6 +230,9 @@ pub trait OpenVpnProvider: Provider {
     fn auth_file_path(&self) -> anyhow::Result<Option<PathBuf>>;
 
     fn load_openvpn_auth(&self) -> anyhow::Result<(String, String)> {
+        if let Some(credentials) = credentials::load_credentials(&self.alias()) {
+            return Ok(credentials);
+        }
         let auth_file = self.auth_file_path()?;
         if let Some(auth_file) = auth_file {
             let mut reader = BufReader::new(File::open(auth_file)?);
@@ -162,6 +246,69 @@ pub trait OpenVpnProvider: Provider {
         }
     }
 
+    /// Called when the OpenVPN server rejects the saved credentials - by default the user is
+    /// prompted for new credentials which are saved with store_openvpn_auth
+    fn refresh_openvpn_auth(&self, uiclient: &dyn UiClient) -> anyhow::Result<()> {
+        if self.auth_file_path()?.is_none() {
+            return Err(anyhow!("Provider does not use an OpenVPN auth file"));
+        }
+        let (user, pass) = self.prompt_for_auth(uiclient)?;
+        self.store_openvpn_auth(&user, &pass)
+    }
+
+    /// Save the OpenVPN credentials in the system keyring if one is available, otherwise in
+    /// the plaintext auth file
+    fn store_openvpn_auth(&self, user: &str, pass: &str) -> anyhow::Result<()> {
+        let Some(auth_file) = self.auth_file_path()? else {
+            return Ok(());
+        };
+        if keyring::keyring_available() {
+            match keyring::store_credentials(&self.alias(), user, pass) {
+                Ok(()) => {
+                    // Do not leave a stale plaintext copy behind
+                    if auth_file.exists() {
+                        std::fs::remove_file(&auth_file)?;
+                    }
+                    info!("{} credentials saved in the system keyring", self.alias());
+                    return Ok(());
+                }
+                Err(e) => warn!(
+                    "Failed to save credentials in the system keyring, writing them to {} instead: {e:?}",
+                    auth_file.display()
+                ),
+            }
+        }
+        let mut outfile = File::create(&auth_file)?;
+        write!(outfile, "{user}\n{pass}")?;
+        Ok(())
+    }
+
+    /// Move credentials from the plaintext auth file to the system keyring.
+    /// Returns false if there was no auth file to migrate.
+    fn migrate_openvpn_auth(&self) -> anyhow::Result<bool> {
+        let Some(auth_file) = self.auth_file_path()?.filter(|x| x.exists()) else {
+            return Ok(false);
+        };
+        if !keyring::keyring_available() {
+            return Err(anyhow!(
+                "No system keyring available, secret-tool (libsecret) and a session bus are required"
+            ));
+        }
+        let mut reader = BufReader::new(File::open(&auth_file)?);
+        let mut user = String::new();
+        reader.read_line(&mut user)?;
+        let mut pass = String::new();
+        reader.read_line(&mut pass)?;
+        keyring::store_credentials(&self.alias(), user.trim(), pass.trim())?;
+        std::fs::remove_file(&auth_file)?;
+        info!(
+            "Moved {} credentials from {} to the system keyring",
+            self.alias(),
+            auth_file.display()
+        );
+        Ok(true)
+    }
+
     fn openvpn_dir(&self) -> anyhow::Result<PathBuf> {
         Ok(self.provider_dir()?.join("openvpn"))
     }
@@ -1,15 +1,25 @@
 mod openvpn;
+mod quantum_resistant;
 mod wireguard;
 
 use std::fmt::Display;
 
 use super::{
-    ConfigurationChoice, Input, OpenVpnProvider, Provider, ShadowsocksProvider, UiClient,
-    WireguardProvider,
+    ConfigurationChoice, Input, OpenVpnProvider, Provider, ServerFeature, ServerInfo,
+    ShadowsocksProvider, UiClient, WireguardProvider,
 };
 use crate::config::vpn::Protocol;
+use crate::util::run_in_netns;
 use anyhow::anyhow;
-use serde::Deserialize;
+use log::info;
+use reqwest::blocking::Client;
+use reqwest::header::AUTHORIZATION;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::io::{Read, Write};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, TcpStream};
+use std::path::PathBuf;
+use std::time::Duration;
 
 #[derive(Deserialize, Debug)]
 struct AccessToken {
@@ -24,12 +34,13 @@ struct UserInfo {
 }
 
 #[derive(Deserialize, Debug, Clone)]
-struct Device {
-    name: String,
-    pubkey: String,
-    created: String,
-    ipv4_address: String,
-    ipv6_address: String,
+pub struct Device {
+    pub id: String,
+    pub name: String,
+    pub pubkey: String,
+    pub created: String,
+    pub ipv4_address: String,
+    pub ipv6_address: String,
 }
 
 impl Display for Device {
@@ -42,6 +53,45 @@ impl Display for Device {
     }
 }
 
+/// Relay details saved at sync time, so servers can be filtered at exec time
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RelayMetadata {
+    pub hostname: String,
+    /// Server is owned by Mullvad (rather than rented)
+    pub owned: bool,
+    /// Server runs from RAM only (diskless, stboot)
+    pub ram_only: bool,
+    pub provider: String,
+    /// Server supports DAITA (Defense against AI-guided Traffic Analysis)
+    #[serde(default)]
+    pub daita: bool,
+    /// Port on the entry relay which forwards to this relay when used as multihop exit
+    #[serde(default)]
+    pub multihop_port: u16,
+    #[serde(default)]
+    pub city: String,
+}
+
+/// Requirements for relays chosen at exec time
+#[derive(Debug, Clone, Default)]
+pub struct RelayFilter {
+    pub owned_only: bool,
+    pub ram_only: bool,
+    pub daita: bool,
+}
+
+impl RelayFilter {
+    pub fn is_empty(&self) -> bool {
+        !self.owned_only && !self.ram_only && !self.daita
+    }
+
+    pub fn matches(&self, relay: &RelayMetadata) -> bool {
+        (!self.owned_only || relay.owned)
+            && (!self.ram_only || relay.ram_only)
+            && (!self.daita || relay.daita)
+    }
+}
+
 pub struct Mullvad {}
 
 impl Provider for Mullvad {
@@ -56,6 +106,33 @@ impl Provider for Mullvad {
     fn default_protocol(&self) -> Protocol {
         Protocol::Wireguard
     }
+
+    fn server_info(&self, configs: &[PathBuf]) -> Vec<ServerInfo> {
+        // Relay metadata is only saved for Wireguard configs
+        let metadata = self.wireguard_relay_metadata().unwrap_or_default();
+        configs
+            .iter()
+            .map(|config| {
+                let mut info = ServerInfo::from_config(config);
+                if let Some(relay) = metadata.get(info.file_name()) {
+                    if !relay.city.is_empty() {
+                        info.city = Some(relay.city.clone());
+                    }
+                    for (enabled, feature) in [
+                        (relay.owned, ServerFeature::Owned),
+                        (relay.ram_only, ServerFeature::RamOnly),
+                        (relay.daita, ServerFeature::Daita),
+                        (relay.multihop_port != 0, ServerFeature::Multihop),
+                    ] {
+                        if enabled {
+                            info.features.push(feature);
+                        }
+                    }
+                }
+                info
+            })
+            .collect()
+    }
 }
 
 impl Mullvad {
@@ -81,6 +158,141 @@ impl Mullvad {
         }
         Ok(username)
     }
+
+    /// Address of the SOCKS5 proxy available inside the Mullvad tunnel
+    pub fn socks_proxy_addr(&self, protocol: &Protocol) -> SocketAddr {
+        let ip = match protocol {
+            Protocol::OpenVpn => Ipv4Addr::new(10, 8, 0, 1),
+            _ => Ipv4Addr::new(10, 64, 0, 1),
+        };
+        SocketAddr::new(IpAddr::V4(ip), 1080)
+    }
+
+    /// Check that the Mullvad SOCKS5 proxy accepts connections from within the network namespace
+    pub fn verify_socks_proxy(&self, ns_name: &str, addr: SocketAddr) -> anyhow::Result<()> {
+        run_in_netns(ns_name, move || {
+            let mut stream =
+                TcpStream::connect_timeout(&addr, Duration::from_secs(5)).map_err(|e| {
+                    anyhow!("Could not connect to Mullvad SOCKS5 proxy {}: {}", addr, e)
+                })?;
+            stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+            // SOCKS5 greeting offering only "no authentication"
+            stream.write_all(&[5, 1, 0])?;
+            let mut reply = [0u8; 2];
+            stream.read_exact(&mut reply)?;
+            if reply != [5, 0] {
+                return Err(anyhow!(
+                    "Unexpected SOCKS5 greeting reply from {}: {:?}",
+                    addr,
+                    reply
+                ));
+            }
+            Ok(())
+        })?;
+        info!("Mullvad SOCKS5 proxy available at {}", addr);
+        Ok(())
+    }
+
+    fn relay_metadata_path(&self) -> anyhow::Result<PathBuf> {
+        Ok(self.wireguard_dir()?.join("relays.json"))
+    }
+
+    /// Relay metadata written during Wireguard sync, keyed by config file name
+    pub fn wireguard_relay_metadata(&self) -> anyhow::Result<HashMap<String, RelayMetadata>> {
+        let path = self.relay_metadata_path()?;
+        let file = std::fs::File::open(&path).map_err(|e| {
+            anyhow!(
+                "Could not read Mullvad relay metadata from {}, try running vopono sync mullvad again: {}",
+                path.display(),
+                e
+            )
+        })?;
+        Ok(serde_json::from_reader(file)?)
+    }
+
+    /// Filter Wireguard config files to those matching the relay requirements
+    pub fn filter_wireguard_configs(
+        &self,
+        configs: Vec<PathBuf>,
+        filter: &RelayFilter,
+    ) -> anyhow::Result<Vec<PathBuf>> {
+        if filter.is_empty() {
+            return Ok(configs);
+        }
+        let metadata = self.wireguard_relay_metadata()?;
+        Ok(configs
+            .into_iter()
+            .filter(|path| {
+                path.file_name()
+                    .and_then(|x| x.to_str())
+                    .and_then(|x| metadata.get(x))
+                    .is_some_and(|m| filter.matches(m))
+            })
+            .collect())
+    }
+
+    fn write_relay_metadata(
+        &self,
+        metadata: &HashMap<String, RelayMetadata>,
+    ) -> anyhow::Result<()> {
+        let file = std::fs::File::create(self.relay_metadata_path()?)?;
+        serde_json::to_writer(file, metadata)?;
+        Ok(())
+    }
+
+    fn get_access_token(client: &Client, username: &str) -> anyhow::Result<String> {
+        let mut map = HashMap::new();
+        map.insert("account_number", username.to_string());
+
+        let auth: AccessToken = client
+            .post("https://api.mullvad.net/auth/v1/token")
+            .json(&map)
+            .send()?
+            .error_for_status()?
+            .json()?;
+        Ok(auth.access_token)
+    }
+
+    fn get_devices(client: &Client, access_token: &str) -> anyhow::Result<Vec<Device>> {
+        let devices: Vec<Device> = client
+            .get("https://api.mullvad.net/accounts/v1/devices")
+            .header(AUTHORIZATION, format!("Bearer {access_token}"))
+            .send()?
+            .error_for_status()?
+            .json()?;
+        Ok(devices)
+    }
+
+    /// List the Wireguard devices (keys) registered on the Mullvad account
+    pub fn list_devices(&self, uiclient: &dyn UiClient) -> anyhow::Result<Vec<Device>> {
+        let client = Client::new();
+        let username = self.request_mullvad_username(uiclient)?;
+        let access_token = Mullvad::get_access_token(&client, &username)?;
+        Mullvad::get_devices(&client, &access_token)
+    }
+
+    /// Revoke a Wireguard device on the Mullvad account, matched by device name or public key
+    pub fn revoke_device(&self, uiclient: &dyn UiClient, device: &str) -> anyhow::Result<()> {
+        let client = Client::new();
+        let username = self.request_mullvad_username(uiclient)?;
+        let access_token = Mullvad::get_access_token(&client, &username)?;
+        let devices = Mullvad::get_devices(&client, &access_token)?;
+        let device = devices
+            .iter()
+            .find(|x| x.name.eq_ignore_ascii_case(device) || x.pubkey == device)
+            .ok_or_else(|| anyhow!("No Mullvad device found matching: {}", device))?;
+
+        client
+            .delete(format!(
+                "https://api.mullvad.net/accounts/v1/devices/{}",
+                device.id
+            ))
+            .header(AUTHORIZATION, format!("Bearer {access_token}"))
+            .send()?
+            .error_for_status()?;
+        info!("Revoked Mullvad device {}", device);
+        Ok(())
+    }
 }
 
 impl ShadowsocksProvider for Mullvad {
@@ -1,8 +1,9 @@
 use super::Mullvad;
 use super::{ConfigurationChoice, OpenVpnProvider};
-use crate::config::providers::{BoolChoice, UiClient};
+use crate::config::providers::{BoolChoice, Provider, UiClient};
 use crate::config::vpn::OpenVpnProtocol;
 use crate::util::delete_all_files_in_dir;
+use crate::util::server_cache::cached_server_list;
 use anyhow::Context;
 use log::warn;
 use rand::seq::SliceRandom;
@@ -63,10 +64,17 @@ impl OpenVpnProvider for Mullvad {
         delete_all_files_in_dir(&openvpn_dir)?;
 
         let client = Client::new();
-        let relays: Vec<OpenVpnRelay> = client
-            .get("https://api.mullvad.net/www/relays/openvpn/")
-            .send()?
-            .json()?;
+        let relays: Vec<OpenVpnRelay> = serde_json::from_value(cached_server_list(
+            &self.provider_dir()?,
+            "openvpn_relays",
+            || {
+                Ok(client
+                    .get("https://api.mullvad.net/www/relays/openvpn/")
+                    .send()?
+                    .error_for_status()?
+                    .json()?)
+            },
+        )?)?;
 
         let mut config_choice = ConfigType::index_to_variant(
             uiclient.get_configuration_choice(&ConfigType::default())?,
@@ -128,10 +136,17 @@ impl OpenVpnProvider for Mullvad {
         }
 
         let bridge_vec = if use_bridges {
-            let bridges: Vec<OpenVpnRelay> = client
-                .get("https://api.mullvad.net/www/relays/bridge/")
-                .send()?
-                .json()?;
+            let bridges: Vec<OpenVpnRelay> = serde_json::from_value(cached_server_list(
+                &self.provider_dir()?,
+                "bridge_relays",
+                || {
+                    Ok(client
+                        .get("https://api.mullvad.net/www/relays/bridge/")
+                        .send()?
+                        .error_for_status()?
+                        .json()?)
+                },
+            )?)?;
             bridges
                 .into_iter()
                 .filter(|x| x.active)
@@ -176,12 +191,7 @@ impl OpenVpnProvider for Mullvad {
 
         // Write OpenVPN credentials file
         let (user, pass) = self.prompt_for_auth(uiclient)?;
-        let auth_file = self.auth_file_path()?;
-        if auth_file.is_some() {
-            let mut outfile = File::create(auth_file.unwrap())?;
-            write!(outfile, "{user}\n{pass}")?;
-        }
-        Ok(())
+        self.store_openvpn_auth(&user, &pass)
     }
 }
 
@@ -0,0 +1,318 @@
+// Mullvad quantum-resistant tunnels
+// Once the Wireguard tunnel is up with the device key, we register an ephemeral peer via the
+// gRPC service on 10.64.0.1:1337 inside the tunnel, sending Classic McEliece and ML-KEM public
+// keys. The relay returns a ciphertext for each KEM, and the XOR of the decapsulated shared
+// secrets is used as the Wireguard PresharedKey together with the ephemeral private key.
+// See: https://github.com/mullvad/mullvadvpn-app/tree/main/talpid-tunnel-config-client
+
+use super::Mullvad;
+use crate::network::netns::NetworkNamespace;
+use crate::network::wireguard::Wireguard;
+use crate::util::private_files::{private_temp_dir, write_private_file};
+use crate::util::run_in_netns;
+use crate::util::wireguard::{WgKey, generate_keypair, generate_public_key};
+use anyhow::{Context, anyhow};
+use base64::{Engine as _, engine::general_purpose};
+use classic_mceliece_rust::{CRYPTO_CIPHERTEXTBYTES, decapsulate_boxed, keypair_boxed};
+use log::{debug, info};
+use ml_kem::kem::Decapsulate;
+use ml_kem::{EncodedSizeUser, KemCore, MlKem1024};
+use reqwest::blocking::Client;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+const EPHEMERAL_PEER_URL: &str = "http://10.64.0.1:1337/ephemeralpeer.EphemeralPeer/RegisterPeerV1";
+const MCELIECE_ALGORITHM: &str = "Classic-McEliece-460896f-round3";
+const MLKEM_ALGORITHM: &str = "ML-KEM-1024";
+
+impl Mullvad {
+    /// Negotiate a post-quantum PresharedKey with the connected relay and reconfigure the
+    /// Wireguard interface to use it (with a new ephemeral keypair)
+    pub fn negotiate_quantum_psk(
+        &self,
+        ns_name: &str,
+        wireguard: &Wireguard,
+    ) -> anyhow::Result<()> {
+        let config = Wireguard::config_from_file(&wireguard.config_file)?;
+        let parent_pubkey = generate_public_key(&config.interface.private_key)?;
+        let ephemeral = generate_keypair()?;
+        info!("Negotiating quantum-resistant PresharedKey with Mullvad relay");
+
+        let ephemeral_pubkey = ephemeral.public.clone();
+        let psk = run_in_netns(ns_name, move || {
+            request_psk(&parent_pubkey, &ephemeral_pubkey)
+        })?;
+
+        apply_psk(
+            ns_name,
+            &wireguard.if_name,
+            &config.peer.public_key,
+            &ephemeral,
+            &psk,
+        )?;
+        info!("Quantum-resistant tunnel established");
+        Ok(())
+    }
+}
+
+fn request_psk(parent_pubkey: &str, ephemeral_pubkey: &str) -> anyhow::Result<[u8; 32]> {
+    let mut rng = rand_core::OsRng;
+    let (mceliece_public, mceliece_secret) = keypair_boxed(&mut rng);
+    let (mlkem_secret, mlkem_public) = MlKem1024::generate(&mut rng);
+
+    let pq_request = [
+        encode_kem_pubkey(MCELIECE_ALGORITHM, mceliece_public.as_array()),
+        encode_kem_pubkey(MLKEM_ALGORITHM, &mlkem_public.as_bytes()),
+    ]
+    .iter()
+    .fold(Vec::new(), |mut acc, x| {
+        encode_bytes_field(&mut acc, 1, x);
+        acc
+    });
+
+    let mut request = Vec::with_capacity(pq_request.len() + 128);
+    encode_bytes_field(
+        &mut request,
+        1,
+        &general_purpose::STANDARD.decode(parent_pubkey)?,
+    );
+    encode_bytes_field(
+        &mut request,
+        2,
+        &general_purpose::STANDARD.decode(ephemeral_pubkey)?,
+    );
+    encode_bytes_field(&mut request, 3, &pq_request);
+
+    // gRPC message framing: uncompressed flag + big-endian length prefix
+    let mut body = Vec::with_capacity(request.len() + 5);
+    body.push(0);
+    body.extend_from_slice(&(request.len() as u32).to_be_bytes());
+    body.extend_from_slice(&request);
+
+    let client = Client::builder()
+        .http2_prior_knowledge()
+        .timeout(Duration::from_secs(15))
+        .build()?;
+    let response = client
+        .post(EPHEMERAL_PEER_URL)
+        .header("content-type", "application/grpc")
+        .header("te", "trailers")
+        .body(body)
+        .send()
+        .context("Failed to contact Mullvad ephemeral peer service on 10.64.0.1:1337")?
+        .error_for_status()?;
+
+    // Errors are returned in trailers-only responses as headers
+    if let Some(status) = response.headers().get("grpc-status") {
+        if status != "0" {
+            return Err(anyhow!(
+                "Mullvad ephemeral peer service returned gRPC status {:?}: {:?}",
+                status,
+                response.headers().get("grpc-message")
+            ));
+        }
+    }
+    let response = response.bytes()?;
+    if response.len() < 5 {
+        return Err(anyhow!(
+            "Empty response from Mullvad ephemeral peer service"
+        ));
+    }
+    let message = &response[5..];
+    debug!("Received {} byte ephemeral peer response", message.len());
+
+    let pq_response = decode_fields(message)?
+        .into_iter()
+        .find(|(field, _)| *field == 1)
+        .map(|(_, data)| data)
+        .ok_or_else(|| anyhow!("Mullvad relay did not return a post-quantum response"))?;
+    let ciphertexts: Vec<&[u8]> = decode_fields(pq_response)?
+        .into_iter()
+        .filter(|(field, _)| *field == 1)
+        .map(|(_, data)| data)
+        .collect();
+    if ciphertexts.len() != 2 {
+        return Err(anyhow!(
+            "Expected 2 KEM ciphertexts from Mullvad relay, received {}",
+            ciphertexts.len()
+        ));
+    }
+
+    let mceliece_ciphertext: [u8; CRYPTO_CIPHERTEXTBYTES] = ciphertexts[0]
+        .try_into()
+        .map_err(|_| anyhow!("Invalid Classic McEliece ciphertext length"))?;
+    let mceliece_shared = decapsulate_boxed(&mceliece_ciphertext.into(), &mceliece_secret);
+
+    let mlkem_ciphertext = ml_kem::Ciphertext::<MlKem1024>::try_from(ciphertexts[1])
+        .map_err(|_| anyhow!("Invalid ML-KEM ciphertext length"))?;
+    let mlkem_shared = mlkem_secret
+        .decapsulate(&mlkem_ciphertext)
+        .map_err(|_| anyhow!("ML-KEM decapsulation failed"))?;
+
+    let mut psk = [0u8; 32];
+    for (i, byte) in psk.iter_mut().enumerate() {
+        *byte = mceliece_shared.as_array()[i] ^ mlkem_shared[i];
+    }
+    Ok(psk)
+}
+
+/// Writes the ephemeral private key and the PresharedKey for wg set to new files in dir
+fn write_key_files(
+    dir: &Path,
+    private_key: &str,
+    psk: &[u8; 32],
+) -> anyhow::Result<(PathBuf, PathBuf)> {
+    let private_key_path = dir.join("ephemeral.key");
+    let psk_path = dir.join("psk.key");
+    write_private_file(&private_key_path, private_key.as_bytes())?;
+    write_private_file(&psk_path, general_purpose::STANDARD.encode(psk).as_bytes())?;
+    Ok((private_key_path, psk_path))
+}
+
+fn apply_psk(
+    ns_name: &str,
+    if_name: &str,
+    peer_pubkey: &str,
+    ephemeral: &WgKey,
+    psk: &[u8; 32],
+) -> anyhow::Result<()> {
+    let dir = private_temp_dir("wg_psk")?;
+    let result = write_key_files(&dir, &ephemeral.private, psk).and_then(|(key, psk)| {
+        NetworkNamespace::exec(
+            ns_name,
+            &[
+                "wg",
+                "set",
+                if_name,
+                "private-key",
+                &key.to_string_lossy(),
+                "peer",
+                peer_pubkey,
+                "preshared-key",
+                &psk.to_string_lossy(),
+            ],
+        )
+    });
+    std::fs::remove_dir_all(&dir).ok();
+    result.context("Failed to set Wireguard PresharedKey")
+}
+
+// Minimal protobuf encoding - we only need length-delimited fields here
+fn encode_varint(buf: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        buf.push((value as u8) | 0x80);
+        value >>= 7;
+    }
+    buf.push(value as u8);
+}
+
+fn encode_bytes_field(buf: &mut Vec<u8>, field: u64, data: &[u8]) {
+    encode_varint(buf, (field << 3) | 2);
+    encode_varint(buf, data.len() as u64);
+    buf.extend_from_slice(data);
+}
+
+fn encode_kem_pubkey(algorithm: &str, key: &[u8]) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(key.len() + 64);
+    encode_bytes_field(&mut buf, 1, algorithm.as_bytes());
+    encode_bytes_field(&mut buf, 2, key);
+    buf
+}
+
+fn decode_varint(data: &[u8], pos: &mut usize) -> anyhow::Result<u64> {
+    let mut value = 0u64;
+    for shift in (0..64).step_by(7) {
+        let byte = *data
+            .get(*pos)
+            .ok_or_else(|| anyhow!("Truncated protobuf varint"))?;
+        *pos += 1;
+        value |= u64::from(byte & 0x7f) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    Err(anyhow!("Invalid protobuf varint"))
+}
+
+/// The next len bytes of the message, which comes from the relay so the length is untrusted
+fn take_bytes<'a>(data: &'a [u8], pos: &mut usize, len: u64) -> anyhow::Result<&'a [u8]> {
+    let field = usize::try_from(len)
+        .ok()
+        .and_then(|len| pos.checked_add(len))
+        .and_then(|end| data.get(*pos..end))
+        .ok_or_else(|| anyhow!("Truncated protobuf field"))?;
+    *pos += field.len();
+    Ok(field)
+}
+
+/// Returns the length-delimited fields of a protobuf message, skipping other wire types
+fn decode_fields(data: &[u8]) -> anyhow::Result<Vec<(u64, &[u8])>> {
+    let mut fields = Vec::new();
+    let mut pos = 0;
+    while pos < data.len() {
+        let key = decode_varint(data, &mut pos)?;
+        match key & 7 {
+            0 => {
+                decode_varint(data, &mut pos)?;
+            }
+            1 => {
+                take_bytes(data, &mut pos, 8)?;
+            }
+            2 => {
+                let len = decode_varint(data, &mut pos)?;
+                fields.push((key >> 3, take_bytes(data, &mut pos, len)?));
+            }
+            5 => {
+                take_bytes(data, &mut pos, 4)?;
+            }
+            x => return Err(anyhow!("Unsupported protobuf wire type: {}", x)),
+        }
+    }
+    Ok(fields)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+
+    #[test]
+    fn protobuf_fields_roundtrip() {
+        let mut message = Vec::new();
+        encode_bytes_field(
+            &mut message,
+            1,
+            &encode_kem_pubkey(MLKEM_ALGORITHM, &[7; 300]),
+        );
+        encode_bytes_field(&mut message, 3, b"ciphertext");
+        let fields = decode_fields(&message).unwrap();
+        assert_eq!(fields.len(), 2);
+        assert_eq!(fields[1], (3, b"ciphertext".as_slice()));
+        let kem = decode_fields(fields[0].1).unwrap();
+        assert_eq!(kem[0], (1, MLKEM_ALGORITHM.as_bytes()));
+        assert_eq!(kem[1].1.len(), 300);
+        assert!(decode_fields(&message[..message.len() - 1]).is_err());
+        // Field 1 with a length of u64::MAX, and a fixed64 field with 4 bytes
+        let mut hostile = vec![0x0a];
+        encode_varint(&mut hostile, u64::MAX);
+        assert!(decode_fields(&hostile).is_err());
+        assert!(decode_fields(&[0x09, 1, 2, 3, 4]).is_err());
+    }
+
+    #[test]
+    fn key_files_are_private() {
+        let dir = std::env::temp_dir().join(format!("vopono_test_psk_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let (key, psk) = write_key_files(&dir, "private", &[0; 32]).unwrap();
+        assert_eq!(std::fs::read_to_string(&key).unwrap(), "private");
+        assert_eq!(
+            std::fs::read_to_string(&psk).unwrap(),
+            general_purpose::STANDARD.encode([0; 32])
+        );
+        let mode = std::fs::metadata(&psk).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        // Existing files are not reused
+        assert!(write_key_files(&dir, "private", &[0; 32]).is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
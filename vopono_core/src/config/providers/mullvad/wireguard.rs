@@ -1,13 +1,17 @@
 use super::Mullvad;
 use super::WireguardProvider;
 use crate::config::providers::BoolChoice;
-use crate::config::providers::mullvad::AccessToken;
+use crate::config::providers::Provider;
 use crate::config::providers::mullvad::Device;
+use crate::config::providers::mullvad::RelayMetadata;
 use crate::config::providers::mullvad::UserInfo;
 use crate::config::providers::{ConfigurationChoice, Input, InputNumericu16, UiClient};
+use crate::network::netns::MultihopServers;
+use crate::network::wireguard::Wireguard;
 use crate::network::wireguard::WireguardEndpoint;
 use crate::network::wireguard::{WireguardConfig, WireguardInterface, WireguardPeer};
 use crate::util::delete_all_files_in_dir;
+use crate::util::server_cache::cached_server_list;
 use crate::util::wireguard::generate_keypair;
 use crate::util::wireguard::{WgKey, generate_public_key};
 use anyhow::{Context, anyhow};
@@ -25,6 +29,7 @@ use std::collections::HashMap;
 use std::fs::create_dir_all;
 use std::io::Write;
 use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -85,18 +90,11 @@ impl Mullvad {
             let client = Client::new();
             let username = self.request_mullvad_username(uiclient)?;
 
-            let mut map = HashMap::new();
-            map.insert("account_number", username.clone());
-
-            let auth: AccessToken = client
-                .post("https://api.mullvad.net/auth/v1/token".to_owned())
-                .json(&map)
-                .send()?
-                .json()?;
+            let access_token = Mullvad::get_access_token(&client, &username)?;
 
             let user_info: UserInfo = client
                 .get("https://api.mullvad.net/accounts/v1/accounts/me")
-                .header(AUTHORIZATION, format!("Bearer {}", &auth.access_token))
+                .header(AUTHORIZATION, format!("Bearer {}", &access_token))
                 .send()?
                 .json()?;
 
@@ -113,11 +111,7 @@ impl Mullvad {
 
             debug!("Received user info: {user_info:?}");
 
-            let existing_devices: Vec<Device> = client
-                .get("https://api.mullvad.net/accounts/v1/devices")
-                .header(AUTHORIZATION, format!("Bearer {}", &auth.access_token))
-                .send()?
-                .json()?;
+            let existing_devices = Mullvad::get_devices(&client, &access_token)?;
 
             if !existing_devices.is_empty() {
         let existing = Devices { devices: existing_devices.clone()};
@@ -128,10 +122,10 @@ impl Mullvad {
             if existing_devices.len() >= user_info.max_devices as usize
                 || !user_info.can_add_devices
             {
-                return Err(anyhow!("Cannot add more Wireguard keypairs to this account. Try to delete existing keypairs."));
+                return Err(anyhow!("Cannot add more Wireguard keypairs to this account. Try to delete existing keypairs with: vopono sync mullvad --list-devices / --revoke-device <name>"));
             }
             let keypair = generate_keypair()?;
-            let dev = Mullvad::upload_wg_key(&client, &auth.access_token, &keypair)?;
+            let dev = Mullvad::upload_wg_key(&client, &access_token, &keypair)?;
 
             // Save keypair
             let path = self.wireguard_dir()?.join("wireguard_device.json");
@@ -191,7 +185,7 @@ impl Mullvad {
     })?
              {
                 let keypair = generate_keypair()?;
-                let dev = Mullvad::upload_wg_key(&client, &auth.access_token, &keypair)?;
+                let dev = Mullvad::upload_wg_key(&client, &access_token, &keypair)?;
 
            // Save keypair
             let path = self.wireguard_dir()?.join("wireguard_device.json");
@@ -231,6 +225,59 @@ impl Mullvad {
     }
 }
 
+impl Mullvad {
+    /// Create a Wireguard config connecting to the exit relay via the entry relay, using the
+    /// exit relay's public key and the entry relay's IP with the exit relay's multihop port
+    pub fn create_multihop_config(
+        &self,
+        entry_config: &Path,
+        exit_config: &Path,
+    ) -> anyhow::Result<(PathBuf, MultihopServers)> {
+        let file_name = |p: &Path| -> anyhow::Result<String> {
+            Ok(p.file_name()
+                .and_then(|x| x.to_str())
+                .ok_or_else(|| anyhow!("Invalid config file name: {}", p.display()))?
+                .to_string())
+        };
+        let entry_name = file_name(entry_config)?;
+        let exit_name = file_name(exit_config)?;
+        if entry_name == exit_name {
+            return Err(anyhow!(
+                "Multihop entry and exit servers must differ, both chose: {}",
+                entry_name
+            ));
+        }
+
+        let metadata = self.wireguard_relay_metadata()?;
+        let exit_meta = metadata
+            .get(&exit_name)
+            .ok_or_else(|| anyhow!("No relay metadata for {}, re-run vopono sync", exit_name))?;
+        let entry_meta = metadata
+            .get(&entry_name)
+            .ok_or_else(|| anyhow!("No relay metadata for {}, re-run vopono sync", entry_name))?;
+        if exit_meta.multihop_port == 0 {
+            return Err(anyhow!(
+                "No multihop port known for {}, re-run vopono sync",
+                exit_name
+            ));
+        }
+
+        let hops = MultihopServers {
+            entry: entry_meta.hostname.clone(),
+            exit: exit_meta.hostname.clone(),
+        };
+        let path = Wireguard::write_multihop_config(
+            &self.alias(),
+            entry_config,
+            exit_config,
+            exit_meta.multihop_port,
+            &hops,
+        )?;
+        info!("Using Mullvad multihop: {hops}");
+        Ok((path, hops))
+    }
+}
+
 impl WireguardProvider for Mullvad {
     fn create_wireguard_config(&self, uiclient: &dyn UiClient) -> anyhow::Result<()> {
         let wireguard_dir = self.wireguard_dir()?;
@@ -238,10 +285,14 @@ impl WireguardProvider for Mullvad {
         delete_all_files_in_dir(&wireguard_dir)?;
 
         let client = Client::new();
-        let relays: Vec<WireguardRelay> = client
-            .get("https://api.mullvad.net/www/relays/wireguard/")
-            .send()?
-            .json().with_context(|| "Failed to parse Mullvad relays response - try again after a few minutes or report an issue if it is persistent")?;
+        let relays = cached_server_list(&self.provider_dir()?, "wireguard_relays", || {
+            Ok(client
+                .get("https://api.mullvad.net/www/relays/wireguard/")
+                .send()?
+                .error_for_status()?
+                .json()?)
+        })?;
+        let relays: Vec<WireguardRelay> = serde_json::from_value(relays).with_context(|| "Failed to parse Mullvad relays response - try again after a few minutes or report an issue if it is persistent")?;
 
         let (keypair, ipv4_net, ipv6_net) = self.prompt_for_wg_key(uiclient)?;
 
@@ -262,6 +313,7 @@ impl WireguardProvider for Mullvad {
 
         // TODO: avoid hacky regex for TOML -> wireguard config conversion
         let re = Regex::new(r"=\s\[(?P<value>[^\]]+)\]")?;
+        let mut metadata: HashMap<String, RelayMetadata> = HashMap::with_capacity(relays.len());
         for relay in relays.iter().filter(|x| x.active) {
             let wireguard_peer = WireguardPeer {
                 public_key: relay.pubkey.clone(),
@@ -294,7 +346,20 @@ impl WireguardProvider for Mullvad {
             };
 
             let country = relay.country_name.to_lowercase().replace(' ', "_");
-            let path = wireguard_dir.join(format!("{country}-{host}.conf"));
+            let file_name = format!("{country}-{host}.conf");
+            let path = wireguard_dir.join(&file_name);
+            metadata.insert(
+                file_name,
+                RelayMetadata {
+                    hostname: relay.hostname.clone(),
+                    owned: relay.owned,
+                    ram_only: relay.stboot,
+                    provider: relay.provider.clone(),
+                    daita: relay.daita,
+                    multihop_port: relay.multihop_port,
+                    city: relay.city_name.clone(),
+                },
+            );
 
             let mut toml = toml::to_string(&wireguard_conf)?;
             toml.retain(|c| c != '"');
@@ -307,6 +372,8 @@ impl WireguardProvider for Mullvad {
             }
         }
 
+        self.write_relay_metadata(&metadata)?;
+
         info!(
             "Mullvad Wireguard config written to {}",
             wireguard_dir.display()
@@ -331,6 +398,10 @@ struct WireguardRelay {
     ipv6_addr_in: std::net::Ipv6Addr,
     pubkey: String,
     multihop_port: u16,
+    #[serde(default)]
+    stboot: bool,
+    #[serde(default)]
+    daita: bool,
 }
 
 struct Devices {
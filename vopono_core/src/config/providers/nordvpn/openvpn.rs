@@ -1,8 +1,9 @@
 use super::NordVPN;
-use super::{ConfigurationChoice, OpenVpnProvider};
+use super::{ConfigurationChoice, OpenVpnProvider, Provider};
 use crate::config::providers::{Input, Password, UiClient};
 use crate::config::vpn::OpenVpnProtocol;
 use crate::util::delete_all_files_in_dir;
+use crate::util::sync_cache::SyncCache;
 use log::debug;
 use regex::Regex;
 use std::fmt::Display;
@@ -49,8 +50,9 @@ impl OpenVpnProvider for NordVPN {
         let config_choice = ConfigType::index_to_variant(
             uiclient.get_configuration_choice(&ConfigType::default())?,
         );
-        let zipfile = reqwest::blocking::get(url)?;
-        let mut zip = ZipArchive::new(Cursor::new(zipfile.bytes()?))?;
+        let cache = SyncCache::open(&self.provider_dir()?)?;
+        let zipfile = cache.fetch(url, reqwest::blocking::Client::new().get(url))?;
+        let mut zip = ZipArchive::new(Cursor::new(zipfile))?;
         let protocol_dir = match config_choice.get_protocol() {
             OpenVpnProtocol::TCP => "ovpn_tcp",
             OpenVpnProtocol::UDP => "ovpn_udp",
@@ -128,12 +130,7 @@ impl OpenVpnProvider for NordVPN {
 
         // Write OpenVPN credentials file
         let (user, pass) = self.prompt_for_auth(uiclient)?;
-        let auth_file = self.auth_file_path()?;
-        if auth_file.is_some() {
-            let mut outfile = File::create(auth_file.unwrap())?;
-            write!(outfile, "{user}\n{pass}")?;
-        }
-        Ok(())
+        self.store_openvpn_auth(&user, &pass)
     }
 }
 
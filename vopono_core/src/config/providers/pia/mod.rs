@@ -1,10 +1,15 @@
 mod openvpn;
+mod serverlist;
 mod wireguard;
 
+pub use serverlist::RegionMetadata;
+
 use super::{
-    ConfigurationChoice, Input, OpenVpnProvider, Password, Provider, UiClient, WireguardProvider,
+    ConfigurationChoice, Input, OpenVpnProvider, Password, Provider, ServerInfo, UiClient,
+    WireguardProvider,
 };
 use crate::config::vpn::Protocol;
+use std::path::PathBuf;
 
 pub struct PrivateInternetAccess {}
 
@@ -20,6 +25,10 @@ impl Provider for PrivateInternetAccess {
     fn default_protocol(&self) -> Protocol {
         Protocol::OpenVpn
     }
+
+    fn server_info(&self, configs: &[PathBuf]) -> Vec<ServerInfo> {
+        self.region_server_info(configs)
+    }
 }
 
 impl PrivateInternetAccess {
@@ -1,7 +1,10 @@
 use super::PrivateInternetAccess;
-use super::{ConfigurationChoice, OpenVpnProvider};
+use super::serverlist::RegionMetadata;
+use super::{ConfigurationChoice, OpenVpnProvider, Provider};
 use crate::config::providers::UiClient;
+use crate::config::vpn::Protocol;
 use crate::util::delete_all_files_in_dir;
+use crate::util::sync_cache::SyncCache;
 use anyhow::Context;
 use log::info;
 use log::{debug, warn};
@@ -32,6 +35,10 @@ impl PrivateInternetAccess {
 
     //This only works if openvpn was sync'd
     pub fn hostname_for_openvpn_conf(&self, config_file: &String) -> anyhow::Result<String> {
+        if let Some(region) = self.region_for_config(&Protocol::OpenVpn, config_file) {
+            return Ok(region.hostname);
+        }
+        // Configs synced before region metadata was saved
         let pia_config_file = File::open(self.openvpn_config_file_path()?)?;
         let pia_config: Config = serde_json::from_reader(pia_config_file)?;
 
@@ -66,8 +73,13 @@ impl OpenVpnProvider for PrivateInternetAccess {
         let config_choice = ConfigType::index_to_variant(
             uiclient.get_configuration_choice(&ConfigType::default())?,
         );
-        let zipfile = reqwest::blocking::get(config_choice.url()?)?;
-        let mut zip = ZipArchive::new(Cursor::new(zipfile.bytes()?))?;
+        let url = config_choice.url()?;
+        let cache = SyncCache::open(&self.provider_dir()?)?;
+        let zipfile = cache.fetch(
+            url.as_str(),
+            reqwest::blocking::Client::new().get(url.clone()),
+        )?;
+        let mut zip = ZipArchive::new(Cursor::new(zipfile))?;
         let openvpn_dir = self.openvpn_dir()?;
         let country_map = crate::util::country_map::country_to_code_map();
         create_dir_all(&openvpn_dir)?;
@@ -76,6 +88,20 @@ impl OpenVpnProvider for PrivateInternetAccess {
         let mut config = Config {
             hostname_lookup: HashMap::new(),
         };
+        // Region metadata is matched to the configs by the remote hostname
+        let regions: HashMap<String, RegionMetadata> = match self
+            .fetch_regions(&reqwest::blocking::Client::new())
+        {
+            Ok(regions) => regions
+                .iter()
+                .map(|x| (x.dns.clone(), x.metadata()))
+                .collect(),
+            Err(e) => {
+                warn!("Failed to get PIA region list, region metadata will not be saved: {e:?}");
+                HashMap::new()
+            }
+        };
+        let mut metadata = HashMap::new();
 
         let re =
             Regex::new(r"\n *remote +([^ ]+) +\d+ *\n").expect("Failed to compile hostname regex");
@@ -122,6 +148,9 @@ impl OpenVpnProvider for PrivateInternetAccess {
                     .to_string();
 
                 info!("Associating {filename} with hostname {hostname}");
+                if let Some(region) = regions.get(&hostname) {
+                    metadata.insert(filename.to_lowercase().replace(' ', "_"), region.clone());
+                }
                 config.hostname_lookup.insert(filename.clone(), hostname);
             } else {
                 warn!(
@@ -137,15 +166,12 @@ impl OpenVpnProvider for PrivateInternetAccess {
 
         // Write OpenVPN credentials file
         let (user, pass) = self.prompt_for_auth(uiclient)?;
-        let auth_file = self.auth_file_path()?;
-        if let Some(auth_file) = auth_file {
-            let mut outfile = File::create(auth_file)?;
-            write!(outfile, "{user}\n{pass}")?;
-        }
+        self.store_openvpn_auth(&user, &pass)?;
 
         // Write PrivateInternetAccess openvpn config file
         let pia_config_file = File::create(self.openvpn_config_file_path()?)?;
         serde_json::to_writer(pia_config_file, &config)?;
+        self.write_region_metadata(&Protocol::OpenVpn, &metadata)?;
 
         // Write PIA certificate
         self.write_pia_cert()?;
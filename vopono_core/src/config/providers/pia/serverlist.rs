@@ -0,0 +1,169 @@
+// PIA next-gen server list (https://serverlist.piaservers.net/vpninfo/servers/v6)
+// The first line of the response is the JSON region list, followed by a signature.
+// Region metadata is saved per config file during sync as regions.json in the protocol
+// directory, so hostnames and port forwarding support do not need to be parsed from configs.
+
+use super::PrivateInternetAccess;
+use crate::config::providers::{
+    OpenVpnProvider, Provider, ServerFeature, ServerInfo, WireguardProvider,
+};
+use crate::config::vpn::Protocol;
+use crate::util::server_cache::cached_server_list;
+use anyhow::{Context, anyhow};
+use reqwest::blocking::Client;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::path::PathBuf;
+
+const SERVER_LIST_URL: &str = "https://serverlist.piaservers.net/vpninfo/servers/v6";
+
+#[derive(Debug, Deserialize)]
+pub struct VpnInfo {
+    pub regions: Vec<Region>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Region {
+    pub id: String,
+    pub name: String,
+    pub country: String,
+    #[allow(unused)]
+    pub auto_region: bool,
+    pub dns: String,
+    pub port_forward: bool,
+    pub geo: bool,
+    pub offline: bool,
+    pub servers: Servers,
+}
+
+impl Region {
+    pub fn metadata(&self) -> RegionMetadata {
+        RegionMetadata {
+            id: self.id.clone(),
+            name: self.name.clone(),
+            country: self.country.to_lowercase(),
+            hostname: self.dns.clone(),
+            port_forward: self.port_forward,
+            geo: self.geo,
+            // PIA only marks streaming optimized regions by name, e.g. "DE Streaming Optimized"
+            streaming: self.name.to_lowercase().contains("streaming"),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Servers {
+    pub wg: Option<Vec<WireguardServer>>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct WireguardServer {
+    pub ip: IpAddr,
+    pub cn: String,
+}
+
+/// Region details saved at sync time, keyed by config file name
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RegionMetadata {
+    pub id: String,
+    pub name: String,
+    /// Two letter country code
+    pub country: String,
+    pub hostname: String,
+    pub port_forward: bool,
+    /// Virtual location (server is not physically in the region's country)
+    pub geo: bool,
+    pub streaming: bool,
+}
+
+impl PrivateInternetAccess {
+    /// Get the current PIA regions (cached for the server list TTL)
+    pub fn fetch_regions(&self, client: &Client) -> anyhow::Result<Vec<Region>> {
+        let vpn_info = cached_server_list(&self.provider_dir()?, "servers_v6", || {
+            let vpn_info: String = client
+                .get(SERVER_LIST_URL)
+                .send()?
+                .error_for_status()?
+                .text()?;
+            // JSON response on first line
+            Ok(serde_json::from_str(
+                vpn_info.lines().next().context("Invalid response")?,
+            )?)
+        })?;
+        let vpn_info: VpnInfo = serde_json::from_value(vpn_info)?;
+        Ok(vpn_info.regions)
+    }
+
+    fn region_metadata_path(&self, protocol: &Protocol) -> anyhow::Result<PathBuf> {
+        let dir = match protocol {
+            Protocol::OpenVpn => self.openvpn_dir()?,
+            Protocol::Wireguard => self.wireguard_dir()?,
+            p => return Err(anyhow!("PIA does not support {} protocol", p)),
+        };
+        Ok(dir.join("regions.json"))
+    }
+
+    pub(super) fn write_region_metadata(
+        &self,
+        protocol: &Protocol,
+        metadata: &HashMap<String, RegionMetadata>,
+    ) -> anyhow::Result<()> {
+        let file = std::fs::File::create(self.region_metadata_path(protocol)?)?;
+        serde_json::to_writer(file, metadata)?;
+        Ok(())
+    }
+
+    /// Region metadata written during sync, keyed by config file name
+    pub fn region_metadata(
+        &self,
+        protocol: &Protocol,
+    ) -> anyhow::Result<HashMap<String, RegionMetadata>> {
+        let path = self.region_metadata_path(protocol)?;
+        let contents = std::fs::read_to_string(&path).with_context(|| {
+            format!(
+                "Could not read PIA region metadata from {}, try running vopono sync pia again",
+                path.display()
+            )
+        })?;
+        Ok(serde_json::from_str(&contents)?)
+    }
+
+    /// Region for the given config file, if the configs were synced with region metadata
+    pub fn region_for_config(
+        &self,
+        protocol: &Protocol,
+        config_file: &str,
+    ) -> Option<RegionMetadata> {
+        self.region_metadata(protocol)
+            .ok()
+            .and_then(|mut x| x.remove(config_file))
+    }
+
+    pub(super) fn region_server_info(&self, configs: &[PathBuf]) -> Vec<ServerInfo> {
+        let metadata: HashMap<String, RegionMetadata> = [Protocol::OpenVpn, Protocol::Wireguard]
+            .iter()
+            .flat_map(|p| self.region_metadata(p).unwrap_or_default())
+            .collect();
+        configs
+            .iter()
+            .map(|config| {
+                let mut info = ServerInfo::from_config(config);
+                if let Some(region) = metadata.get(info.file_name()) {
+                    info.country = Some(region.country.clone());
+                    info.city = Some(region.name.clone());
+                    for (enabled, feature) in [
+                        (region.port_forward, ServerFeature::PortForwarding),
+                        (region.streaming, ServerFeature::Streaming),
+                        (region.geo, ServerFeature::VirtualLocation),
+                    ] {
+                        if enabled {
+                            info.features.push(feature);
+                        }
+                    }
+                }
+                info
+            })
+            .collect()
+    }
+}
@@ -1,13 +1,16 @@
 use super::{PrivateInternetAccess, Provider, WireguardProvider};
 use crate::config::providers::{BoolChoice, UiClient};
+use crate::config::vpn::Protocol;
 use crate::network::wireguard::{
     WireguardConfig, WireguardEndpoint, WireguardInterface, WireguardPeer,
 };
 use crate::util::delete_all_files_in_dir;
+use crate::util::private_files::{private_temp_dir, replace_private_file, write_private_file};
 use crate::util::wireguard::generate_keypair;
+use crate::util::{credentials, keyring};
 use anyhow::{Context, anyhow};
 use ipnet::IpNet;
-use log::info;
+use log::{debug, info, warn};
 use reqwest::Url;
 use reqwest::blocking::Client;
 use reqwest::blocking::ClientBuilder;
@@ -24,40 +27,6 @@ use std::path::PathBuf;
 
 use std::str::FromStr;
 
-#[derive(Debug, Deserialize)]
-pub struct VpnInfo {
-    pub regions: Vec<Region>,
-}
-
-#[derive(Debug, Deserialize)]
-pub struct Region {
-    pub id: String,
-    #[allow(unused)]
-    pub name: String,
-    #[allow(unused)]
-    pub country: String,
-    #[allow(unused)]
-    pub auto_region: bool,
-    pub dns: String,
-    pub port_forward: bool,
-    #[allow(unused)]
-    pub geo: bool,
-    #[allow(unused)]
-    pub offline: bool,
-    pub servers: Servers,
-}
-
-#[derive(Debug, Deserialize)]
-pub struct Servers {
-    pub wg: Option<Vec<WireguardServer>>,
-}
-
-#[derive(Debug, Deserialize)]
-pub struct WireguardServer {
-    pub ip: IpAddr,
-    pub cn: String,
-}
-
 #[derive(Debug, Deserialize)]
 #[serde(tag = "status")]
 pub enum PiaToken {
@@ -98,11 +67,40 @@ pub struct Config {
     pub hostname_lookup: HashMap<String, String>,
 }
 
+/// Keyring entry for the cached PIA token
+const TOKEN_KEY: &str = "token";
+
+/// PIA token cached (in the keyring or on disk) so we do not re-authenticate on every connection
+#[derive(Debug, Deserialize, Serialize)]
+struct CachedToken {
+    user: String,
+    token: String,
+    /// Unix timestamp (seconds) after which the token must not be used
+    expires: i64,
+}
+
 impl PrivateInternetAccess {
     const PORT: u16 = 1337;
     const CERT: &'static [u8] = include_bytes!("ca.rsa.4096.crt");
+    // PIA tokens are valid for 24 hours, keep a margin so a token does not expire mid-use
+    const TOKEN_LIFETIME_SECS: i64 = 23 * 60 * 60;
 
+    /// Returns a cached PIA token if one is still valid for this user, otherwise
+    /// requests a new token and caches it
     pub fn get_pia_token(user: &str, pass: &str) -> anyhow::Result<String> {
+        let now = chrono::Utc::now().timestamp();
+        let pia = PrivateInternetAccess {};
+        let cache_path = pia.token_cache_path()?;
+        if let Some(cached) = keyring::lookup_secret(&pia.alias(), TOKEN_KEY)
+            .or_else(|| std::fs::read_to_string(&cache_path).ok())
+            .and_then(|x| serde_json::from_str::<CachedToken>(&x).ok())
+        {
+            if cached.user == user && cached.expires > now {
+                debug!("Using cached PIA token");
+                return Ok(cached.token);
+            }
+        }
+
         let token: PiaToken = Client::new()
             .get("https://www.privateinternetaccess.com/gtoken/generateToken")
             .basic_auth(user, Some(pass))
@@ -110,11 +108,49 @@ impl PrivateInternetAccess {
             .json()?;
 
         match token {
-            PiaToken::Ok { token } => Ok(token),
+            PiaToken::Ok { token } => {
+                let cached = CachedToken {
+                    user: user.to_string(),
+                    token: token.clone(),
+                    expires: now + Self::TOKEN_LIFETIME_SECS,
+                };
+                let cached = serde_json::to_string(&cached)?;
+                if keyring::keyring_available()
+                    && keyring::store_secret(&pia.alias(), TOKEN_KEY, &cached).is_ok()
+                {
+                    return Ok(token);
+                }
+                // Failing to cache the token should not prevent connecting
+                if let Err(e) = pia
+                    .provider_dir()
+                    .and_then(|dir| Ok(std::fs::create_dir_all(dir)?))
+                    .and_then(|_| replace_private_file(&cache_path, cached.as_bytes()))
+                {
+                    warn!("Failed to cache PIA token: {:?}", e);
+                }
+                Ok(token)
+            }
             PiaToken::Err { message } => Err(anyhow!("{}", message)),
         }
     }
 
+    /// Remove the cached token, e.g. if it was rejected by the API
+    pub fn clear_pia_token() -> anyhow::Result<()> {
+        let pia = PrivateInternetAccess {};
+        if keyring::keyring_available() {
+            keyring::clear_secret(&pia.alias(), TOKEN_KEY)?;
+        }
+        let cache_path = pia.token_cache_path()?;
+        if cache_path.exists() {
+            std::fs::remove_file(cache_path)?;
+        }
+        Ok(())
+    }
+
+    fn token_cache_path(&self) -> anyhow::Result<PathBuf> {
+        Ok(self.provider_dir()?.join("token.json"))
+    }
+
     pub fn pia_cert_path(&self) -> anyhow::Result<PathBuf> {
         Ok(self.provider_dir()?.join("ca.rsa.4096.crt"))
     }
@@ -158,13 +194,48 @@ impl PrivateInternetAccess {
     }
 
     pub fn load_wireguard_auth(&self) -> anyhow::Result<(String, String)> {
+        if let Some(credentials) = credentials::load_credentials(&self.alias()) {
+            return Ok(credentials);
+        }
         let config_file = File::open(self.wireguard_config_file_path()?)?;
         let config: Config = serde_json::from_reader(config_file)?;
         Ok((config.user, config.pass))
     }
 
+    /// Move the Wireguard credentials in config.txt to the system keyring and remove the
+    /// cached token file. Returns false if there were no plaintext credentials.
+    pub fn migrate_wireguard_auth(&self) -> anyhow::Result<bool> {
+        let token_cache = self.token_cache_path()?;
+        if token_cache.exists() {
+            std::fs::remove_file(&token_cache)?;
+        }
+        let Ok(config_file) = File::open(self.wireguard_config_file_path()?) else {
+            return Ok(false);
+        };
+        let mut config: Config = serde_json::from_reader(config_file)?;
+        if config.user.is_empty() {
+            return Ok(false);
+        }
+        if !keyring::keyring_available() {
+            return Err(anyhow!(
+                "No system keyring available, secret-tool (libsecret) and a session bus are required"
+            ));
+        }
+        keyring::store_credentials(&self.alias(), &config.user, &config.pass)?;
+        config.user.clear();
+        config.pass.clear();
+        let config_file = File::create(self.wireguard_config_file_path()?)?;
+        serde_json::to_writer(config_file, &config)?;
+        info!("Moved PIA Wireguard credentials to the system keyring");
+        Ok(true)
+    }
+
     //This only works if wireguard was sync'd
     pub fn hostname_for_wireguard_conf(&self, config_file: &String) -> anyhow::Result<String> {
+        if let Some(region) = self.region_for_config(&Protocol::Wireguard, config_file) {
+            return Ok(region.hostname);
+        }
+        // Configs synced before region metadata was saved
         let pia_config_file = File::open(self.wireguard_config_file_path()?)?;
         let pia_config: Config = serde_json::from_reader(pia_config_file)?;
 
@@ -188,14 +259,7 @@ impl WireguardProvider for PrivateInternetAccess {
         let (user, pass) = self.prompt_for_auth(uiclient)?;
 
         let client = Client::new();
-        let vpn_info: String = client
-            .get("https://serverlist.piaservers.net/vpninfo/servers/v6")
-            .send()?
-            .text()?;
-
-        // JSON response on first line
-        let vpn_info: VpnInfo =
-            serde_json::from_str(vpn_info.lines().next().context("Invalid response")?)?;
+        let regions = self.fetch_regions(&client)?;
 
         let only_port_forwarding = uiclient.get_bool_choice(BoolChoice {
             prompt: "Only use servers that have port forwarding enabled?".into(),
@@ -217,6 +281,15 @@ impl WireguardProvider for PrivateInternetAccess {
         // We need to call PIA's addKey API on connect which needs the user, pass, pubkey, and common name.
         // Wireguard's config doesn't allow us to save the common name so we need a map to look up the
         // common name later
+        // Credentials are only kept in config.txt if there is no system keyring
+        let (user, pass) = if keyring::keyring_available()
+            && keyring::store_credentials(&self.alias(), &user, &pass).is_ok()
+        {
+            info!("PIA credentials saved in the system keyring");
+            (String::new(), String::new())
+        } else {
+            (user, pass)
+        };
         let mut config = Config {
             user,
             pass,
@@ -225,8 +298,9 @@ impl WireguardProvider for PrivateInternetAccess {
             hostname_lookup: HashMap::new(),
         };
 
-        for region in vpn_info.regions {
-            let id = region.id;
+        let mut metadata = HashMap::with_capacity(regions.len());
+        for region in regions.iter().filter(|x| !x.offline) {
+            let id = &region.id;
             if only_port_forwarding && !region.port_forward {
                 continue;
             }
@@ -234,7 +308,7 @@ impl WireguardProvider for PrivateInternetAccess {
             info!("Associating {id} with hostname {}", region.dns);
             config
                 .hostname_lookup
-                .insert(format!("{id}.conf"), region.dns);
+                .insert(format!("{id}.conf"), region.dns.clone());
 
             // The servers are randomized on each request so we can just use the first one
             if let Some(wg_server) = region.servers.wg.as_ref().and_then(|s| s.first()) {
@@ -260,8 +334,10 @@ impl WireguardProvider for PrivateInternetAccess {
                 f.write_all(wireguard_conf.as_bytes())?;
 
                 config.cn_lookup.insert(wg_server.ip, wg_server.cn.clone());
+                metadata.insert(format!("{id}.conf"), region.metadata());
             }
         }
+        self.write_region_metadata(&Protocol::Wireguard, &metadata)?;
 
         info!(
             "PrivateInternetAccess Wireguard config written to {}",
@@ -281,8 +357,48 @@ impl WireguardProvider for PrivateInternetAccess {
     fn wireguard_preup(&self, wg_config_file: &Path) -> anyhow::Result<()> {
         let pia_config_file = File::open(self.wireguard_config_file_path()?)?;
         let pia_config: Config = serde_json::from_reader(pia_config_file)?;
+        let wg_config = self.register_key(wg_config_file, &pia_config, &pia_config.pubkey)?;
 
-        let token = PrivateInternetAccess::get_pia_token(&pia_config.user, &pia_config.pass)?;
+        // Overwrite the existing invalid wg config with a new one that is now valid
+        let new_wg_config: String = wg_config.try_into()?;
+        let mut f = File::create(wg_config_file)?;
+        f.write_all(new_wg_config.as_bytes())?;
+
+        Ok(())
+    }
+
+    fn wireguard_ephemeral_preup(&self, wg_config_file: &Path) -> anyhow::Result<PathBuf> {
+        let pia_config_file = File::open(self.wireguard_config_file_path()?)?;
+        let pia_config: Config = serde_json::from_reader(pia_config_file)?;
+
+        let keypair = generate_keypair()?;
+        let mut wg_config = self.register_key(wg_config_file, &pia_config, &keypair.public)?;
+        wg_config.interface.private_key = keypair.private;
+        info!("Registered ephemeral Wireguard key with PIA");
+
+        // Written outside the provider directory so the synced config keeps its placeholder values
+        let stem = wg_config_file
+            .file_stem()
+            .and_then(|x| x.to_str())
+            .unwrap_or("pia");
+        let path = private_temp_dir("pia_ephemeral")?.join(format!("{stem}.conf"));
+        let new_wg_config: String = wg_config.try_into()?;
+        write_private_file(&path, new_wg_config.as_bytes())?;
+        Ok(path)
+    }
+}
+
+impl PrivateInternetAccess {
+    /// Call addKey for the given public key, returning the synced config with the server's
+    /// peer details filled in
+    fn register_key(
+        &self,
+        wg_config_file: &Path,
+        pia_config: &Config,
+        pubkey: &str,
+    ) -> anyhow::Result<WireguardConfig> {
+        let (user, pass) = self.load_wireguard_auth()?;
+        let token = PrivateInternetAccess::get_pia_token(&user, &pass)?;
 
         let mut wg_config: WireguardConfig = std::fs::read_to_string(wg_config_file)?.parse()?;
         let ip = &wg_config.peer.endpoint.resolve_ip()?;
@@ -291,7 +407,16 @@ impl WireguardProvider for PrivateInternetAccess {
             .get(ip)
             .with_context(|| format!("Could not find matching common name for IP {ip}"))?;
 
-        let server_info = PrivateInternetAccess::add_key(ip, cn, &token, &pia_config.pubkey)?;
+        let server_info = match PrivateInternetAccess::add_key(ip, cn, &token, pubkey) {
+            Ok(info) => info,
+            Err(e) => {
+                // Cached token may have been revoked early, so retry once with a fresh token
+                warn!("PIA addKey failed, retrying with new token: {:?}", e);
+                PrivateInternetAccess::clear_pia_token()?;
+                let token = PrivateInternetAccess::get_pia_token(&user, &pass)?;
+                PrivateInternetAccess::add_key(ip, cn, &token, pubkey)?
+            }
+        };
 
         wg_config.interface.address = vec![IpNet::new(server_info.peer_ip, 32)?];
         wg_config.interface.dns = Some(
@@ -305,11 +430,6 @@ impl WireguardProvider for PrivateInternetAccess {
         wg_config.peer.endpoint =
             format!("{}:{}", server_info.server_ip, server_info.server_port).parse()?;
 
-        // Overwrite the existing invalid wg config with a new one that is now valid
-        let new_wg_config: String = wg_config.try_into()?;
-        let mut f = File::create(wg_config_file)?;
-        f.write_all(new_wg_config.as_bytes())?;
-
-        Ok(())
+        Ok(wg_config)
     }
 }
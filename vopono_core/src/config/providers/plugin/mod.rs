@@ -0,0 +1,353 @@
+// External provider plugins
+// A plugin is any executable that reads a single JSON request from stdin and writes a single JSON
+// response to stdout. stderr is passed through to the user so plugins can log or prompt via /dev/tty.
+//
+// Requests always contain the protocol version and a command:
+//   {"version": 1, "command": "info"}
+//     -> {"alias": "myvpn", "alias_2char": "my", "protocols": ["Wireguard", "OpenVpn"], "requires_auth": false}
+//   {"version": 1, "command": "list_servers", "protocol": "Wireguard"}
+//     -> {"servers": [{"name": "us-nyc1", "country": "us", "city": "nyc"}]}
+//   {"version": 1, "command": "get_config", "protocol": "Wireguard", "server": "us-nyc1"}
+//     -> {"contents": "[Interface]\n..."}
+// Any response may instead be {"error": "message"} to abort the sync (for get_config, only
+// that server is skipped). get_config requests are sent concurrently, so plugins must not
+// assume they are called one at a time.
+
+use super::{Input, OpenVpnProvider, Password, Provider, UiClient, VpnProvider, WireguardProvider};
+use crate::config::vpn::Protocol;
+use crate::util::delete_all_files_in_dir;
+use crate::util::parallel::{parallel_map, report_failures};
+use anyhow::{Context, anyhow};
+use log::{debug, info};
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
+use std::fs::{File, create_dir_all};
+use std::io::Write;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use strum::IntoEnumIterator;
+
+pub const PLUGIN_PROTOCOL_VERSION: u32 = 1;
+
+#[derive(Serialize, Debug)]
+#[serde(tag = "command", rename_all = "snake_case")]
+enum PluginCommand<'a> {
+    Info,
+    ListServers {
+        protocol: &'a Protocol,
+    },
+    GetConfig {
+        protocol: &'a Protocol,
+        server: &'a str,
+    },
+}
+
+#[derive(Serialize, Debug)]
+struct PluginRequest<'a> {
+    version: u32,
+    #[serde(flatten)]
+    command: PluginCommand<'a>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(untagged)]
+enum PluginResponse<T> {
+    Error { error: String },
+    Ok(T),
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct PluginInfo {
+    pub alias: String,
+    pub alias_2char: String,
+    pub protocols: Vec<Protocol>,
+    #[serde(default)]
+    pub requires_auth: bool,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct PluginServer {
+    pub name: String,
+    pub country: Option<String>,
+    pub city: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+struct PluginServerList {
+    servers: Vec<PluginServer>,
+}
+
+#[derive(Deserialize, Debug)]
+struct PluginConfig {
+    contents: String,
+}
+
+/// Provider backed by an external executable speaking the JSON plugin protocol
+pub struct PluginProvider {
+    path: PathBuf,
+    info: PluginInfo,
+}
+
+/// Checks the aliases of a plugin, which name its config directory (deleted on sync) and its
+/// namespaces, so they must not be those of a built-in provider
+fn validate_info(info: &PluginInfo) -> anyhow::Result<()> {
+    if info.alias.is_empty()
+        || !info
+            .alias
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+    {
+        return Err(anyhow!("invalid alias: {:?}", info.alias));
+    }
+    if info.alias_2char.chars().count() != 2 {
+        return Err(anyhow!("invalid 2 character alias: {:?}", info.alias_2char));
+    }
+    for provider in VpnProvider::iter() {
+        let name = provider.to_string().to_lowercase();
+        let (alias, alias_2char) = match provider {
+            VpnProvider::Custom | VpnProvider::None => (name.clone(), String::new()),
+            _ => {
+                let dyn_provider = provider.get_dyn_provider();
+                (dyn_provider.alias(), dyn_provider.alias_2char())
+            }
+        };
+        if [name, alias].contains(&info.alias.to_lowercase()) {
+            return Err(anyhow!(
+                "alias {:?} clashes with the built-in provider {provider}",
+                info.alias
+            ));
+        }
+        if info.alias_2char.to_lowercase() == alias_2char {
+            return Err(anyhow!(
+                "2 character alias {:?} clashes with the built-in provider {provider}",
+                info.alias_2char
+            ));
+        }
+    }
+    Ok(())
+}
+
+impl PluginProvider {
+    pub fn new(path: &Path) -> anyhow::Result<Self> {
+        let info: PluginInfo = call_plugin(path, PluginCommand::Info)?;
+        validate_info(&info).map_err(|e| anyhow!("Plugin {}: {e}", path.display()))?;
+        debug!("Loaded plugin {}: {:?}", path.display(), info);
+        Ok(Self {
+            path: path.to_path_buf(),
+            info,
+        })
+    }
+
+    pub fn protocols(&self) -> &[Protocol] {
+        &self.info.protocols
+    }
+
+    pub fn list_servers(&self, protocol: &Protocol) -> anyhow::Result<Vec<PluginServer>> {
+        let list: PluginServerList =
+            call_plugin(&self.path, PluginCommand::ListServers { protocol })?;
+        Ok(list.servers)
+    }
+
+    fn get_config(&self, protocol: &Protocol, server: &str) -> anyhow::Result<String> {
+        let config: PluginConfig =
+            call_plugin(&self.path, PluginCommand::GetConfig { protocol, server })?;
+        Ok(config.contents)
+    }
+
+    fn write_configs(
+        &self,
+        protocol: &Protocol,
+        dir: &Path,
+        extension: &str,
+    ) -> anyhow::Result<()> {
+        if !self.info.protocols.contains(protocol) {
+            return Err(anyhow!(
+                "Plugin {} does not support {} protocol",
+                self.info.alias,
+                protocol
+            ));
+        }
+        create_dir_all(dir)?;
+        delete_all_files_in_dir(dir)?;
+        let servers = self.list_servers(protocol)?;
+        let results = parallel_map(
+            &servers,
+            &format!("Fetching {protocol} configs"),
+            |server| self.get_config(protocol, &server.name),
+        );
+
+        let mut written = 0;
+        let mut failures = Vec::new();
+        for (server, result) in servers.iter().zip(results) {
+            let contents = match result {
+                Ok(contents) => contents,
+                Err(e) => {
+                    failures.push((server.name.clone(), e));
+                    continue;
+                }
+            };
+            let filename = match (&server.country, &server.city) {
+                (Some(country), Some(city)) => format!("{country}-{city}-{}", server.name),
+                (Some(country), None) => format!("{country}-{}", server.name),
+                _ => server.name.clone(),
+            };
+            let filename = sanitize_filename(&filename);
+            let outpath = dir.join(format!("{filename}.{extension}"));
+            debug!("Writing file: {}", outpath.display());
+            let mut outfile = File::create(outpath)?;
+            write!(outfile, "{contents}")?;
+            written += 1;
+        }
+        report_failures("servers", &failures);
+        if written == 0 && !failures.is_empty() {
+            return Err(anyhow!(
+                "Failed to fetch any {} configs from plugin {}",
+                protocol,
+                self.info.alias
+            ));
+        }
+        info!(
+            "{} {} configs written to {}",
+            written,
+            protocol,
+            dir.display()
+        );
+        Ok(())
+    }
+}
+
+fn sanitize_filename(name: &str) -> String {
+    name.to_lowercase()
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '-' || c == '_' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+fn call_plugin<T: DeserializeOwned>(path: &Path, command: PluginCommand) -> anyhow::Result<T> {
+    let request = serde_json::to_string(&PluginRequest {
+        version: PLUGIN_PROTOCOL_VERSION,
+        command,
+    })?;
+    debug!("Plugin request to {}: {}", path.display(), request);
+    let mut child = Command::new(path)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::inherit())
+        .spawn()
+        .with_context(|| format!("Failed to launch plugin: {}", path.display()))?;
+    {
+        let mut stdin = child.stdin.take().expect("No stdin for plugin");
+        stdin.write_all(request.as_bytes())?;
+        stdin.write_all(b"\n")?;
+    }
+    let output = child.wait_with_output()?;
+    if !output.status.success() {
+        return Err(anyhow!(
+            "Plugin {} exited with {}",
+            path.display(),
+            output.status
+        ));
+    }
+    let response: PluginResponse<T> = serde_json::from_slice(&output.stdout)
+        .with_context(|| format!("Invalid JSON response from plugin: {}", path.display()))?;
+    match response {
+        PluginResponse::Ok(x) => Ok(x),
+        PluginResponse::Error { error } => Err(anyhow!(
+            "Plugin {} returned error: {}",
+            path.display(),
+            error
+        )),
+    }
+}
+
+impl Provider for PluginProvider {
+    fn alias(&self) -> String {
+        self.info.alias.clone()
+    }
+
+    fn alias_2char(&self) -> String {
+        self.info.alias_2char.clone()
+    }
+
+    fn default_protocol(&self) -> Protocol {
+        self.info
+            .protocols
+            .first()
+            .cloned()
+            .unwrap_or(Protocol::Wireguard)
+    }
+}
+
+impl WireguardProvider for PluginProvider {
+    fn create_wireguard_config(&self, _uiclient: &dyn UiClient) -> anyhow::Result<()> {
+        self.write_configs(&Protocol::Wireguard, &self.wireguard_dir()?, "conf")
+    }
+}
+
+impl OpenVpnProvider for PluginProvider {
+    fn provider_dns(&self) -> Option<Vec<IpAddr>> {
+        None
+    }
+
+    fn prompt_for_auth(&self, uiclient: &dyn UiClient) -> anyhow::Result<(String, String)> {
+        let username = uiclient.get_input(Input {
+            prompt: format!("{} OpenVPN username", self.info.alias),
+            validator: None,
+        })?;
+        let password = uiclient.get_password(Password {
+            prompt: format!("{} OpenVPN password", self.info.alias),
+            confirm: true,
+        })?;
+        Ok((username.trim().to_string(), password.trim().to_string()))
+    }
+
+    fn auth_file_path(&self) -> anyhow::Result<Option<PathBuf>> {
+        if self.info.requires_auth {
+            Ok(Some(self.openvpn_dir()?.join("auth.txt")))
+        } else {
+            Ok(None)
+        }
+    }
+
+    fn create_openvpn_config(&self, uiclient: &dyn UiClient) -> anyhow::Result<()> {
+        let openvpn_dir = self.openvpn_dir()?;
+        self.write_configs(&Protocol::OpenVpn, &openvpn_dir, "ovpn")?;
+        if self.auth_file_path()?.is_some() {
+            let (user, pass) = self.prompt_for_auth(uiclient)?;
+            self.store_openvpn_auth(&user, &pass)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn info(alias: &str, alias_2char: &str) -> PluginInfo {
+        PluginInfo {
+            alias: alias.to_string(),
+            alias_2char: alias_2char.to_string(),
+            protocols: vec![Protocol::Wireguard],
+            requires_auth: false,
+        }
+    }
+
+    #[test]
+    fn reject_builtin_aliases() {
+        assert!(validate_info(&info("myvpn", "my")).is_ok());
+        assert!(validate_info(&info("mullvad", "my")).is_err());
+        assert!(validate_info(&info("Proton", "my")).is_err());
+        assert!(validate_info(&info("azire", "my")).is_err());
+        assert!(validate_info(&info("custom", "my")).is_err());
+        assert!(validate_info(&info("myvpn", "mv")).is_err());
+        assert!(validate_info(&info("my/vpn", "my")).is_err());
+        assert!(validate_info(&info("myvpn", "m")).is_err());
+    }
+}
@@ -1,10 +1,31 @@
 mod openvpn;
+mod wireguard;
 
-use super::{ConfigurationChoice, OpenVpnProvider, Provider};
+use super::{ConfigurationChoice, OpenVpnProvider, Provider, ServerFeature, ServerInfo};
 use crate::config::vpn::Protocol;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
 
 pub struct ProtonVPN {}
 
+impl ProtonVPN {
+    /// Secure Core configs are labelled with a -securecore suffix during sync
+    pub fn is_secure_core_config(config_file: &Path) -> bool {
+        config_file
+            .file_stem()
+            .and_then(|x| x.to_str())
+            .is_some_and(|x| x.ends_with("-securecore"))
+    }
+
+    /// Restrict the given configs to Secure Core servers
+    pub fn filter_secure_core_configs(configs: Vec<PathBuf>) -> Vec<PathBuf> {
+        configs
+            .into_iter()
+            .filter(|x| Self::is_secure_core_config(x))
+            .collect()
+    }
+}
+
 impl Provider for ProtonVPN {
     fn alias(&self) -> String {
         "proton".to_string()
@@ -17,4 +38,34 @@ impl Provider for ProtonVPN {
     fn default_protocol(&self) -> Protocol {
         Protocol::OpenVpn
     }
+
+    fn server_info(&self, configs: &[PathBuf]) -> Vec<ServerInfo> {
+        let cached = self.cached_wireguard_server_info();
+        configs
+            .iter()
+            .map(|config| {
+                let mut info = ServerInfo::from_config(config);
+                if let Some(details) = cached.get(info.file_name()) {
+                    info.load = details.load;
+                    info.city = details.city.clone();
+                    info.features = details.features.clone();
+                }
+                if Self::is_free_config(config) {
+                    info.features.push(ServerFeature::Free);
+                }
+                if Self::is_secure_core_config(config) {
+                    info.features.push(ServerFeature::SecureCore);
+                }
+                info
+            })
+            .collect()
+    }
+
+    fn live_server_load(&self, configs: &[PathBuf]) -> anyhow::Result<HashMap<PathBuf, u8>> {
+        let loads = Self::fetch_server_load()?;
+        Ok(configs
+            .iter()
+            .filter_map(|config| Some((config.clone(), wireguard::config_load(&loads, config)?)))
+            .collect())
+    }
 }
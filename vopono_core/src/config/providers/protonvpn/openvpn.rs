@@ -1,19 +1,22 @@
 use super::ProtonVPN;
-use super::{ConfigurationChoice, OpenVpnProvider};
-use crate::config::providers::{Input, Password, UiClient};
+use super::{ConfigurationChoice, OpenVpnProvider, Provider};
+use crate::config::providers::{BoolChoice, Input, Password, UiClient};
 use crate::config::vpn::OpenVpnProtocol;
 use crate::util::delete_all_files_in_dir;
-use anyhow::anyhow;
-use log::{debug, info};
+use crate::util::parallel::parallel_map;
+use crate::util::sync_cache::SyncCache;
+use anyhow::{Context, anyhow};
+use log::{debug, info, warn};
 use regex::Regex;
 use reqwest::Url;
-use reqwest::header::{COOKIE, HeaderMap, HeaderName, HeaderValue};
+use reqwest::header::HeaderMap;
+use serde::Deserialize;
 use std::fmt::Display;
 use std::fs::File;
 use std::fs::create_dir_all;
 use std::io::{Cursor, Read, Write};
 use std::net::IpAddr;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use strum::IntoEnumIterator;
 use strum_macros::EnumIter;
 use zip::ZipArchive;
@@ -21,7 +24,7 @@ use zip::ZipArchive;
 impl ProtonVPN {
     fn build_url(
         &self,
-        category: &ConfigType,
+        category: &ConfigCategory,
         tier: &Tier,
         protocol: &OpenVpnProtocol,
     ) -> anyhow::Result<Url> {
@@ -82,6 +85,110 @@ impl ProtonVPN {
         Ok((auth_cookie, leaked_uid))
     }
 }
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "PascalCase")]
+struct VpnInfoResponse {
+    #[serde(rename = "VPN")]
+    vpn: VpnCredentials,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "PascalCase")]
+struct VpnCredentials {
+    name: String,
+    password: String,
+    max_tier: Option<u8>,
+}
+
+impl ProtonVPN {
+    fn get_vpn_info(
+        client: &reqwest::blocking::Client,
+        headers: &HeaderMap,
+    ) -> anyhow::Result<VpnCredentials> {
+        let info: VpnInfoResponse = client
+            .get("https://account.protonvpn.com/api/vpn")
+            .headers(headers.clone())
+            .send()?
+            .error_for_status()
+            .context("Failed to get ProtonVPN account details - check AUTH cookie")?
+            .json()?;
+        Ok(info.vpn)
+    }
+
+    /// Get the current OpenVPN / IKEv2 credentials for the account
+    fn get_openvpn_credentials(
+        client: &reqwest::blocking::Client,
+        headers: &HeaderMap,
+    ) -> anyhow::Result<(String, String)> {
+        let info = Self::get_vpn_info(client, headers)?;
+        Ok((info.name, info.password))
+    }
+
+    fn tier_file_path(&self) -> anyhow::Result<PathBuf> {
+        Ok(self.provider_dir()?.join("tier.txt"))
+    }
+
+    /// Detect the account plan from the API, falling back to asking the user, and save it so
+    /// server selection can be restricted to servers the account can use
+    pub(super) fn get_account_tier(
+        &self,
+        uiclient: &dyn UiClient,
+        client: &reqwest::blocking::Client,
+        headers: &HeaderMap,
+    ) -> anyhow::Result<Tier> {
+        let tier = match Self::get_vpn_info(client, headers).map(|x| x.max_tier) {
+            Ok(Some(max_tier)) => {
+                let tier = Tier::from_level(max_tier);
+                info!("Detected ProtonVPN {tier} account (tier {max_tier})");
+                tier
+            }
+            Ok(None) | Err(_) => {
+                warn!("Could not detect ProtonVPN account tier");
+                Tier::index_to_variant(uiclient.get_configuration_choice(&Tier::default())?)
+            }
+        };
+        create_dir_all(self.provider_dir()?)?;
+        std::fs::write(self.tier_file_path()?, tier.to_string())?;
+        Ok(tier)
+    }
+
+    /// Free servers have "free" in their name, e.g. jp-free-01
+    pub fn is_free_config(config_file: &Path) -> bool {
+        config_file
+            .file_stem()
+            .and_then(|x| x.to_str())
+            .is_some_and(|x| x.contains("free"))
+    }
+
+    /// Restrict the given configs to the servers available to the account tier detected
+    /// during sync (if known)
+    pub fn filter_configs_for_tier(
+        &self,
+        configs: Vec<PathBuf>,
+        alias: &str,
+    ) -> anyhow::Result<Vec<PathBuf>> {
+        let tier = std::fs::read_to_string(self.tier_file_path()?).ok();
+        if tier.as_deref().map(|x| x.trim()) != Some("Free") {
+            return Ok(configs);
+        }
+        let (free, paid): (Vec<PathBuf>, Vec<PathBuf>) =
+            configs.into_iter().partition(|x| Self::is_free_config(x));
+        if free.is_empty() && !paid.is_empty() {
+            return Err(anyhow!(
+                "ProtonVPN servers matching {} require a paid plan, but your account is on the Free tier - choose a free server (see vopono servers protonvpn) or re-run vopono sync after upgrading",
+                alias
+            ));
+        }
+        if !paid.is_empty() {
+            debug!(
+                "Ignoring {} ProtonVPN servers not available on Free tier",
+                paid.len()
+            );
+        }
+        Ok(free)
+    }
+}
+
 impl OpenVpnProvider for ProtonVPN {
     fn provider_dns(&self) -> Option<Vec<IpAddr>> {
         // None will use DNS from OpenVPN headers if present
@@ -126,12 +233,38 @@ impl OpenVpnProvider for ProtonVPN {
         Ok(Some(self.openvpn_dir()?.join("auth.txt")))
     }
 
+    fn refresh_openvpn_auth(&self, uiclient: &dyn UiClient) -> anyhow::Result<()> {
+        let use_api = uiclient.get_bool_choice(BoolChoice {
+            prompt: "ProtonVPN may have rotated your OpenVPN credentials. Fetch the current credentials from your account (requires AUTH cookie)?".to_string(),
+            default: true,
+        })?;
+        let (user, pass) = if use_api {
+            let headers = Self::auth_headers(uiclient)?;
+            let client = reqwest::blocking::Client::new();
+            let (user, pass) = Self::get_openvpn_credentials(&client, &headers)?;
+            // Keep the port forwarding suffix if it was used before
+            let old_user = self.load_openvpn_auth().map(|x| x.0).unwrap_or_default();
+            if old_user.ends_with("+pmp") && !user.ends_with("+pmp") {
+                (format!("{user}+pmp"), pass)
+            } else {
+                (user, pass)
+            }
+        } else {
+            self.prompt_for_auth(uiclient)?
+        };
+        self.store_openvpn_auth(&user, &pass)?;
+        info!("ProtonVPN OpenVPN credentials updated");
+        Ok(())
+    }
+
     fn create_openvpn_config(&self, uiclient: &dyn UiClient) -> anyhow::Result<()> {
         let openvpn_dir = self.openvpn_dir()?;
         let code_map = crate::util::country_map::code_to_country_map();
         create_dir_all(&openvpn_dir)?;
         delete_all_files_in_dir(&openvpn_dir)?;
-        let tier = Tier::index_to_variant(uiclient.get_configuration_choice(&Tier::default())?);
+        let headers = Self::auth_headers(uiclient)?;
+        let client = reqwest::blocking::Client::new();
+        let tier = self.get_account_tier(uiclient, &client, &headers)?;
         let config_choice = if tier != Tier::Free {
             ConfigType::index_to_variant(uiclient.get_configuration_choice(&ConfigType::default())?)
         } else {
@@ -142,81 +275,81 @@ impl OpenVpnProvider for ProtonVPN {
             uiclient.get_configuration_choice(&OpenVpnProtocol::default())?,
         );
 
-        // Create the standardized form
-        let (auth_cookie, uid) = Self::parse_auth_cookie(uiclient)?;
-
-        let url = self.build_url(&config_choice, &tier, &protocol)?;
-
-        let mut headers = HeaderMap::new();
-        headers.insert(COOKIE, HeaderValue::from_static(auth_cookie));
-
-        headers.insert(
-            HeaderName::from_static("x-pm-uid"),
-            HeaderValue::from_static(uid),
-        );
-        let client = reqwest::blocking::Client::new();
-
-        let zipfile = client.get(url).headers(headers).send()?;
+        let categories = match tier {
+            Tier::Free => vec![ConfigCategory::Standard],
+            _ => config_choice.categories(),
+        };
 
-        let mut zip = ZipArchive::new(Cursor::new(zipfile.bytes()?))?;
-        let openvpn_dir = self.openvpn_dir()?;
-        create_dir_all(&openvpn_dir)?;
-        for i in 0..zip.len() {
-            // Modify auth line for config
-            // Write to config dir
-            let mut file_contents: Vec<u8> = Vec::with_capacity(2048);
-            let mut file = zip.by_index(i).unwrap();
-            file.read_to_end(&mut file_contents)?;
-
-            let file_contents = std::str::from_utf8(&file_contents)?;
-            let file_contents = file_contents
-                .split('\n')
-                .filter(|&x| !(x.starts_with("up ") || x.starts_with("down ")))
-                .collect::<Vec<&str>>()
-                .join("\n");
-
-            // TODO: sanitized_name is now deprecated but there is not a simple alternative
-            #[allow(deprecated)]
-            let filename = if let Some("ovpn") = file
-                .sanitized_name()
-                .extension()
-                .map(|x| x.to_str().expect("Could not convert OsStr"))
-            {
-                // Also handle server case from free servers
-                let mut hostname: Option<String> = None;
-                let mut code = file.name().split('.').next().unwrap();
-                if code.contains("free") {
-                    // Free case
-                    let mut iter_split = code.split('-');
-                    let fcode = iter_split.next().unwrap();
-                    hostname = Some(iter_split.next().unwrap().to_owned());
-                    code = fcode;
-                } else if code.contains('-') {
-                    // SecureCore
-                    let mut iter_split = code.split('-');
-                    let start = iter_split.next().unwrap();
-                    let end = iter_split.next().unwrap();
-                    let number = iter_split.next().unwrap();
-                    hostname = Some(format!("{start}_{number}"));
-                    code = end;
-                }
-                let country = code_map
-                    .get(code)
-                    .unwrap_or_else(|| panic!("Could not find code in map: {code}"));
-                let host_str = if let Some(host) = hostname {
-                    format!("-{host}")
+        let cache = SyncCache::open(&self.provider_dir()?)?;
+        let zipfiles = parallel_map(&categories, "Downloading ProtonVPN configs", |category| {
+            let url = self.build_url(category, &tier, &protocol)?;
+            cache.fetch(
+                url.as_str(),
+                client.get(url.clone()).headers(headers.clone()),
+            )
+        });
+        for (category, zipfile) in categories.iter().zip(zipfiles) {
+            let mut zip = ZipArchive::new(Cursor::new(zipfile?))?;
+            // Secure Core servers are labelled in the filename so they can be selected with --secure-core
+            let secure_core = category == &ConfigCategory::SecureCore && tier != Tier::Free;
+            for i in 0..zip.len() {
+                // Modify auth line for config
+                // Write to config dir
+                let mut file_contents: Vec<u8> = Vec::with_capacity(2048);
+                let mut file = zip.by_index(i).unwrap();
+                file.read_to_end(&mut file_contents)?;
+
+                let file_contents = std::str::from_utf8(&file_contents)?;
+                let file_contents = file_contents
+                    .split('\n')
+                    .filter(|&x| !(x.starts_with("up ") || x.starts_with("down ")))
+                    .collect::<Vec<&str>>()
+                    .join("\n");
+
+                // TODO: sanitized_name is now deprecated but there is not a simple alternative
+                #[allow(deprecated)]
+                let filename = if let Some("ovpn") = file
+                    .sanitized_name()
+                    .extension()
+                    .map(|x| x.to_str().expect("Could not convert OsStr"))
+                {
+                    // Also handle server case from free servers
+                    let mut hostname: Option<String> = None;
+                    let mut code = file.name().split('.').next().unwrap();
+                    if code.contains("free") {
+                        // Free case
+                        let mut iter_split = code.split('-');
+                        let fcode = iter_split.next().unwrap();
+                        hostname = Some(iter_split.next().unwrap().to_owned());
+                        code = fcode;
+                    } else if code.contains('-') {
+                        // SecureCore
+                        let mut iter_split = code.split('-');
+                        let start = iter_split.next().unwrap();
+                        let end = iter_split.next().unwrap();
+                        let number = iter_split.next().unwrap();
+                        hostname = Some(format!("{start}_{number}"));
+                        code = end;
+                    }
+                    let country = code_map
+                        .get(code)
+                        .unwrap_or_else(|| panic!("Could not find code in map: {code}"));
+                    let host_str = if let Some(host) = hostname {
+                        format!("-{host}")
+                    } else {
+                        String::new()
+                    };
+                    let label = if secure_core { "-securecore" } else { "" };
+                    format!("{}-{}{}{}.ovpn", country, code, &host_str, label)
                 } else {
-                    String::new()
+                    file.name().to_string()
                 };
-                format!("{}-{}{}.ovpn", country, code, &host_str)
-            } else {
-                file.name().to_string()
-            };
 
-            debug!("Reading file: {}", file.name());
-            let mut outfile =
-                File::create(openvpn_dir.join(filename.to_lowercase().replace(' ', "_")))?;
-            write!(outfile, "{file_contents}")?;
+                debug!("Reading file: {}", file.name());
+                let mut outfile =
+                    File::create(openvpn_dir.join(filename.to_lowercase().replace(' ', "_")))?;
+                write!(outfile, "{file_contents}")?;
+            }
         }
 
         // TODO: ProtonVPN DNS servers do not connect
@@ -231,21 +364,17 @@ impl OpenVpnProvider for ProtonVPN {
 
         // Write OpenVPN credentials file
         let (user, pass) = self.prompt_for_auth(uiclient)?;
-        let auth_file = self.auth_file_path()?;
-        if auth_file.is_some() {
-            let mut outfile = File::create(auth_file.unwrap())?;
-            write!(outfile, "{user}\n{pass}")?;
-            info!(
-                "ProtonVPN OpenVPN config written to {}",
-                openvpn_dir.display()
-            );
-        }
+        self.store_openvpn_auth(&user, &pass)?;
+        info!(
+            "ProtonVPN OpenVPN config written to {}",
+            openvpn_dir.display()
+        );
         Ok(())
     }
 }
 
 #[derive(EnumIter, PartialEq)]
-enum Tier {
+pub(super) enum Tier {
     Plus,
     Free,
 }
@@ -257,7 +386,22 @@ impl Tier {
             Self::Free => "0".to_string(),
         }
     }
-    fn index_to_variant(index: usize) -> Self {
+    /// Tier of an account from its max_tier in the API
+    pub(super) fn from_level(max_tier: u8) -> Self {
+        if max_tier == 0 {
+            Self::Free
+        } else {
+            Self::Plus
+        }
+    }
+    /// Maximum server tier available to the account
+    pub(super) fn level(&self) -> u8 {
+        match self {
+            Self::Plus => 2,
+            Self::Free => 0,
+        }
+    }
+    pub(super) fn index_to_variant(index: usize) -> Self {
         Self::iter().nth(index).expect("Invalid index")
     }
 }
@@ -302,21 +446,40 @@ impl ConfigurationChoice for Tier {
 }
 
 #[derive(EnumIter, PartialEq)]
-enum ConfigType {
+pub(super) enum ConfigType {
     SecureCore,
     Standard,
+    All,
 }
 
 impl ConfigType {
+    /// Categories of configuration files to download for the choice
+    fn categories(&self) -> Vec<ConfigCategory> {
+        match self {
+            Self::SecureCore => vec![ConfigCategory::SecureCore],
+            Self::Standard => vec![ConfigCategory::Standard],
+            Self::All => vec![ConfigCategory::Standard, ConfigCategory::SecureCore],
+        }
+    }
+    pub(super) fn index_to_variant(index: usize) -> Self {
+        Self::iter().nth(index).expect("Invalid index")
+    }
+}
+
+/// Category of configuration files in the ProtonVPN API
+#[derive(PartialEq)]
+enum ConfigCategory {
+    SecureCore,
+    Standard,
+}
+
+impl ConfigCategory {
     fn url_part(&self) -> String {
         match self {
             Self::SecureCore => "SecureCore".to_string(),
             Self::Standard => "Country".to_string(),
         }
     }
-    fn index_to_variant(index: usize) -> Self {
-        Self::iter().nth(index).expect("Invalid index")
-    }
 }
 
 impl Display for ConfigType {
@@ -324,6 +487,7 @@ impl Display for ConfigType {
         let s = match self {
             Self::SecureCore => "SecureCore",
             Self::Standard => "Standard",
+            Self::All => "All",
         };
         write!(f, "{s}")
     }
@@ -337,7 +501,7 @@ impl Default for ConfigType {
 
 impl ConfigurationChoice for ConfigType {
     fn prompt(&self) -> String {
-        "Please choose the set of OpenVPN configuration files you wish to install".to_string()
+        "Please choose the set of server configuration files you wish to install".to_string()
     }
 
     fn all_names(&self) -> Vec<String> {
@@ -352,8 +516,9 @@ impl ConfigurationChoice for ConfigType {
                 Self::SecureCore => {
                     "Connect via SecureCore bridge for additional security (Plus accounts only)"
                 }
-                Self::Standard => {
-                    "Standard OpenVPN connection (available servers depend on account tier)"
+                Self::Standard => "Standard connection (available servers depend on account tier)",
+                Self::All => {
+                    "Both Standard and SecureCore configuration files (Plus accounts only)"
                 }
             }
             .to_string(),
@@ -0,0 +1,602 @@
+// ProtonVPN Wireguard configs are generated the same way as the web dashboard does it:
+// an Ed25519 keypair is registered via the VPN certificate endpoint (with the chosen feature
+// flags), and the corresponding X25519 key is used as the Wireguard private key for all servers.
+
+use super::ProtonVPN;
+use super::openvpn::{ConfigType, Tier};
+use crate::config::providers::{
+    BoolChoice, ConfigurationChoice, Provider, ServerFeature, ServerInfo, UiClient,
+    WireguardProvider,
+};
+use crate::network::wireguard::{
+    WireguardConfig, WireguardEndpoint, WireguardInterface, WireguardPeer,
+};
+use crate::util::delete_all_files_in_dir;
+use crate::util::server_cache::{cached_server_list, read_server_list};
+use crate::util::wireguard::generate_public_key;
+use anyhow::{Context, anyhow};
+use base64::{Engine as _, engine::general_purpose};
+use curve25519_dalek::edwards::EdwardsPoint;
+use ipnet::IpNet;
+use log::{debug, info, warn};
+use rand_core::RngCore;
+use reqwest::blocking::Client;
+use reqwest::header::{COOKIE, HeaderMap, HeaderName, HeaderValue};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha512};
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::fs::create_dir_all;
+use std::io::Write;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::{Mutex, PoisonError};
+use std::time::{Duration, Instant};
+use strum::IntoEnumIterator;
+use strum_macros::EnumIter;
+
+const LOGICALS_URL: &str = "https://account.protonvpn.com/api/vpn/logicals";
+/// Server list with current loads which can be fetched without logging in
+const PUBLIC_LOGICALS_URL: &str = "https://api.protonvpn.ch/vpn/logicals";
+/// How long fetched server loads are used for, so a later reconnect sees current loads
+const SERVER_LOAD_TTL: Duration = Duration::from_secs(300);
+const CERTIFICATE_URL: &str = "https://account.protonvpn.com/api/vpn/v1/certificate";
+// DER prefix of an Ed25519 SubjectPublicKeyInfo (RFC 8410)
+const ED25519_SPKI_PREFIX: [u8; 12] = [
+    0x30, 0x2a, 0x30, 0x05, 0x06, 0x03, 0x2b, 0x65, 0x70, 0x03, 0x21, 0x00,
+];
+const FEATURE_SECURE_CORE: u32 = 1;
+const FEATURE_P2P: u32 = 4;
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "PascalCase")]
+pub(super) struct LogicalServers {
+    pub logical_servers: Vec<LogicalServer>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "PascalCase")]
+pub(super) struct LogicalServer {
+    pub name: String,
+    pub exit_country: String,
+    pub tier: u8,
+    pub features: u32,
+    pub status: u8,
+    #[serde(default)]
+    pub load: Option<u8>,
+    #[serde(default)]
+    pub city: Option<String>,
+    pub servers: Vec<PhysicalServer>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "PascalCase")]
+pub(super) struct PhysicalServer {
+    #[serde(rename = "EntryIP")]
+    pub entry_ip: IpAddr,
+    #[serde(rename = "X25519PublicKey")]
+    pub x25519_public_key: Option<String>,
+    pub status: u8,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "PascalCase")]
+struct CertificateRequest {
+    client_public_key: String,
+    mode: &'static str,
+    device_name: String,
+    features: CertificateFeatures,
+}
+
+#[derive(Serialize, Debug)]
+struct CertificateFeatures {
+    #[serde(rename = "peerName")]
+    peer_name: String,
+    platform: &'static str,
+    #[serde(rename = "NetShieldLevel")]
+    netshield_level: u8,
+    #[serde(rename = "PortForwarding")]
+    port_forwarding: bool,
+    #[serde(rename = "RandomNAT")]
+    random_nat: bool,
+    #[serde(rename = "SplitTCP")]
+    split_tcp: bool,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "PascalCase")]
+struct CertificateResponse {
+    code: u32,
+    expiration_time: Option<i64>,
+}
+
+impl LogicalServer {
+    pub fn is_secure_core(&self) -> bool {
+        self.features & FEATURE_SECURE_CORE != 0
+    }
+
+    /// P2P servers support port forwarding via NAT-PMP
+    pub fn is_p2p(&self) -> bool {
+        self.features & FEATURE_P2P != 0
+    }
+}
+
+impl ProtonVPN {
+    /// Server details from the server list cached during Wireguard sync, keyed by config file name
+    pub(super) fn cached_wireguard_server_info(&self) -> HashMap<String, ServerInfo> {
+        let logicals: Vec<LogicalServer> = self
+            .provider_dir()
+            .ok()
+            .and_then(|dir| read_server_list(&dir, "logicals"))
+            .and_then(|x| serde_json::from_value::<LogicalServers>(x).ok())
+            .map(|x| x.logical_servers)
+            .unwrap_or_default();
+        let code_map = crate::util::country_map::code_to_country_map();
+        logicals
+            .iter()
+            .map(|logical| {
+                let mut info = ServerInfo {
+                    load: logical.load,
+                    city: logical.city.clone(),
+                    ..Default::default()
+                };
+                if logical.is_p2p() {
+                    info.features.push(ServerFeature::PortForwarding);
+                }
+                (config_filename(&code_map, logical), info)
+            })
+            .collect()
+    }
+
+    pub(super) fn auth_headers(uiclient: &dyn UiClient) -> anyhow::Result<HeaderMap> {
+        let (auth_cookie, uid) = Self::parse_auth_cookie(uiclient)?;
+        let mut headers = HeaderMap::new();
+        headers.insert(COOKIE, HeaderValue::from_static(auth_cookie));
+        headers.insert(
+            HeaderName::from_static("x-pm-uid"),
+            HeaderValue::from_static(uid),
+        );
+        Ok(headers)
+    }
+
+    /// Current server loads from the public server list, keyed by server name. Kept for
+    /// SERVER_LOAD_TTL, as every server selection (e.g. each failover attempt) asks for them.
+    pub(super) fn fetch_server_load() -> anyhow::Result<HashMap<String, ServerLoad>> {
+        static LOADS: Mutex<Option<(Instant, HashMap<String, ServerLoad>)>> = Mutex::new(None);
+        let mut cached = LOADS.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some((fetched, loads)) = cached.as_ref()
+            && fetched.elapsed() < SERVER_LOAD_TTL
+        {
+            return Ok(loads.clone());
+        }
+        let logicals: LogicalServers = Client::new()
+            .get(PUBLIC_LOGICALS_URL)
+            .send()?
+            .error_for_status()
+            .context("Failed to get ProtonVPN server loads")?
+            .json()?;
+        let code_map = crate::util::country_map::code_to_country_map();
+        let loads: HashMap<String, ServerLoad> = logicals
+            .logical_servers
+            .iter()
+            .filter_map(|logical| {
+                Some((
+                    logical.name.clone(),
+                    ServerLoad {
+                        country_code: logical.exit_country.to_lowercase(),
+                        secure_core: logical.is_secure_core(),
+                        free: logical.tier == 0,
+                        load: logical.load?,
+                        wireguard_config: config_filename(&code_map, logical),
+                    },
+                ))
+            })
+            .collect();
+        *cached = Some((Instant::now(), loads.clone()));
+        Ok(loads)
+    }
+
+    pub(super) fn get_logicals(
+        client: &Client,
+        headers: &HeaderMap,
+    ) -> anyhow::Result<Vec<LogicalServer>> {
+        let logicals = cached_server_list(&ProtonVPN {}.provider_dir()?, "logicals", || {
+            Ok(client
+                .get(LOGICALS_URL)
+                .headers(headers.clone())
+                .send()?
+                .error_for_status()
+                .context("Failed to get ProtonVPN server list - check AUTH cookie")?
+                .json()?)
+        })?;
+        let logicals: LogicalServers = serde_json::from_value(logicals)?;
+        Ok(logicals.logical_servers)
+    }
+
+    fn register_certificate(
+        client: &Client,
+        headers: &HeaderMap,
+        ed25519_public: &[u8; 32],
+        device_name: &str,
+        netshield: &NetShieldLevel,
+        port_forwarding: bool,
+    ) -> anyhow::Result<()> {
+        let mut der = ED25519_SPKI_PREFIX.to_vec();
+        der.extend_from_slice(ed25519_public);
+        let client_public_key = pem::encode(&pem::Pem::new("PUBLIC KEY", der));
+
+        let request = CertificateRequest {
+            client_public_key,
+            mode: "persistent",
+            device_name: device_name.to_string(),
+            features: CertificateFeatures {
+                peer_name: device_name.to_string(),
+                platform: "Linux",
+                netshield_level: netshield.level(),
+                port_forwarding,
+                random_nat: !port_forwarding,
+                split_tcp: true,
+            },
+        };
+        debug!("ProtonVPN certificate request: {request:?}");
+        let response: CertificateResponse = client
+            .post(CERTIFICATE_URL)
+            .headers(headers.clone())
+            .json(&request)
+            .send()?
+            .error_for_status()
+            .context("Failed to register ProtonVPN Wireguard key")?
+            .json()?;
+        if response.code != 1000 {
+            return Err(anyhow!(
+                "ProtonVPN Wireguard key registration failed with code {}",
+                response.code
+            ));
+        }
+        if let Some(expiry) = response
+            .expiration_time
+            .and_then(|t| chrono::DateTime::from_timestamp(t, 0))
+        {
+            info!("ProtonVPN Wireguard key registered as {device_name}, expires {expiry}");
+        }
+        Ok(())
+    }
+}
+
+/// Generate an Ed25519 keypair and the equivalent X25519 (Wireguard) private key
+fn generate_ed25519_keypair() -> ([u8; 32], String) {
+    let mut seed = [0u8; 32];
+    rand_core::OsRng.fill_bytes(&mut seed);
+    let (public, scalar) = ed25519_keys_from_seed(&seed);
+    (public, general_purpose::STANDARD.encode(scalar))
+}
+
+/// Returns the Ed25519 public key and the (unclamped) secret scalar for the given seed
+/// X25519 clamps the scalar itself, so it can be used directly as the Wireguard private key
+fn ed25519_keys_from_seed(seed: &[u8; 32]) -> ([u8; 32], [u8; 32]) {
+    let hash = Sha512::digest(seed);
+    let mut scalar = [0u8; 32];
+    scalar.copy_from_slice(&hash[..32]);
+    let public = EdwardsPoint::mul_base_clamped(scalar).compress().to_bytes();
+    (public, scalar)
+}
+
+/// Current load of a server in the public server list
+#[derive(Debug, Clone)]
+pub(super) struct ServerLoad {
+    country_code: String,
+    secure_core: bool,
+    free: bool,
+    load: u8,
+    /// File name of the server's Wireguard config
+    wireguard_config: String,
+}
+
+/// Load of the server behind a synced config. Wireguard configs are generated per server.
+/// OpenVPN Secure Core configs name their server ({country}-{code}-{entry}_{number}-securecore
+/// for {ENTRY}-{CODE}#{number}), the other OpenVPN configs cover every free or standard server
+/// of the country, for which the average load is used.
+pub(super) fn config_load(loads: &HashMap<String, ServerLoad>, config: &Path) -> Option<u8> {
+    let file_name = config.file_name()?.to_str()?;
+    let stem = config.file_stem()?.to_str()?;
+    if config.extension().is_some_and(|x| x == "conf") {
+        return loads
+            .values()
+            .find(|x| x.wireguard_config == file_name)
+            .map(|x| x.load);
+    }
+    let parts: Vec<&str> = stem.split('-').collect();
+    let code = *parts.get(1)?;
+    if let Some(host) = stem
+        .strip_suffix("-securecore")
+        .and_then(|x| x.split('-').nth(2))
+    {
+        let (entry, number) = host.split_once('_')?;
+        let name = format!(
+            "{}-{}#{}",
+            entry.to_uppercase(),
+            code.to_uppercase(),
+            number.parse::<u32>().ok()?
+        );
+        return loads.get(&name).map(|x| x.load);
+    }
+    let free = ProtonVPN::is_free_config(config);
+    let country_loads: Vec<u32> = loads
+        .values()
+        .filter(|x| x.country_code == code && !x.secure_core && x.free == free)
+        .map(|x| u32::from(x.load))
+        .collect();
+    if country_loads.is_empty() {
+        return None;
+    }
+    Some((country_loads.iter().sum::<u32>() / country_loads.len() as u32) as u8)
+}
+
+fn config_filename(
+    code_map: &std::collections::HashMap<&str, &str>,
+    logical: &LogicalServer,
+) -> String {
+    let code = logical.exit_country.to_lowercase();
+    let country = code_map
+        .get(code.as_str())
+        .copied()
+        .unwrap_or(code.as_str());
+    let name = logical.name.to_lowercase().replace(['#', '-', ' '], "_");
+    let label = if logical.is_secure_core() {
+        "-securecore"
+    } else {
+        ""
+    };
+    format!("{country}-{code}-{name}{label}.conf")
+}
+
+impl WireguardProvider for ProtonVPN {
+    fn create_wireguard_config(&self, uiclient: &dyn UiClient) -> anyhow::Result<()> {
+        let wireguard_dir = self.wireguard_dir()?;
+        create_dir_all(&wireguard_dir)?;
+        delete_all_files_in_dir(&wireguard_dir)?;
+        let code_map = crate::util::country_map::code_to_country_map();
+
+        let headers = Self::auth_headers(uiclient)?;
+        let client = Client::new();
+        let tier = self.get_account_tier(uiclient, &client, &headers)?;
+        let config_choice = if tier != Tier::Free {
+            ConfigType::index_to_variant(uiclient.get_configuration_choice(&ConfigType::default())?)
+        } else {
+            ConfigType::Standard
+        };
+        let netshield = NetShieldLevel::index_to_variant(
+            uiclient.get_configuration_choice(&NetShieldLevel::default())?,
+        );
+        let port_forwarding = uiclient.get_bool_choice(BoolChoice {
+            prompt: "Enable port forwarding (NAT-PMP) for this key? (only on P2P servers)"
+                .to_string(),
+            default: false,
+        })?;
+
+        let logicals = Self::get_logicals(&client, &headers)?;
+
+        let (ed25519_public, private_key) = generate_ed25519_keypair();
+        let device_name = format!("vopono-{}", chrono::Utc::now().format("%Y%m%d%H%M%S"));
+        Self::register_certificate(
+            &client,
+            &headers,
+            &ed25519_public,
+            &device_name,
+            &netshield,
+            port_forwarding,
+        )?;
+        debug!(
+            "ProtonVPN Wireguard public key: {}",
+            generate_public_key(&private_key)?
+        );
+
+        let interface = WireguardInterface {
+            private_key,
+            address: vec![IpNet::from_str("10.2.0.2/32")?],
+            dns: Some(vec![IpAddr::V4(Ipv4Addr::new(10, 2, 0, 1))]),
+            mtu: None,
+        };
+        let allowed_ips = vec![IpNet::from_str("0.0.0.0/0")?];
+
+        let mut count = 0;
+        for logical in logicals.iter().filter(|x| {
+            x.status == 1
+                && x.tier <= tier.level()
+                && match config_choice {
+                    ConfigType::Standard => !x.is_secure_core(),
+                    ConfigType::SecureCore => x.is_secure_core(),
+                    ConfigType::All => true,
+                }
+        }) {
+            let Some((server, public_key)) = logical
+                .servers
+                .iter()
+                .filter(|s| s.status == 1)
+                .find_map(|s| s.x25519_public_key.clone().map(|k| (s, k)))
+            else {
+                continue;
+            };
+            let wireguard_conf = WireguardConfig {
+                interface: interface.clone(),
+                peer: WireguardPeer {
+                    public_key,
+                    allowed_ips: allowed_ips.clone(),
+                    endpoint: WireguardEndpoint::IpWithPort(SocketAddr::new(
+                        server.entry_ip,
+                        51820,
+                    )),
+                    keepalive: None,
+                },
+            };
+            let contents: String = wireguard_conf.try_into()?;
+            let path = wireguard_dir.join(config_filename(&code_map, logical));
+            let mut f = std::fs::File::create(path)?;
+            write!(f, "{contents}")?;
+            count += 1;
+        }
+        if count == 0 {
+            warn!("No ProtonVPN Wireguard servers available for the chosen tier");
+        }
+
+        info!(
+            "{} ProtonVPN Wireguard configs written to {}",
+            count,
+            wireguard_dir.display()
+        );
+        Ok(())
+    }
+}
+
+#[derive(EnumIter, PartialEq)]
+enum NetShieldLevel {
+    Off,
+    Malware,
+    AdsMalware,
+}
+
+impl NetShieldLevel {
+    fn level(&self) -> u8 {
+        match self {
+            Self::Off => 0,
+            Self::Malware => 1,
+            Self::AdsMalware => 2,
+        }
+    }
+    fn index_to_variant(index: usize) -> Self {
+        Self::iter().nth(index).expect("Invalid index")
+    }
+}
+
+impl Display for NetShieldLevel {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let s = match self {
+            Self::Off => "Off",
+            Self::Malware => "Malware",
+            Self::AdsMalware => "AdsMalware",
+        };
+        write!(f, "{s}")
+    }
+}
+
+impl Default for NetShieldLevel {
+    fn default() -> Self {
+        Self::Malware
+    }
+}
+
+impl ConfigurationChoice for NetShieldLevel {
+    fn prompt(&self) -> String {
+        "Choose the NetShield DNS filtering level".to_string()
+    }
+
+    fn all_names(&self) -> Vec<String> {
+        Self::iter().map(|x| format!("{x}")).collect()
+    }
+    fn all_descriptions(&self) -> Option<Vec<String>> {
+        Some(Self::iter().map(|x| x.description().unwrap()).collect())
+    }
+    fn description(&self) -> Option<String> {
+        Some(
+            match self {
+                Self::Off => "No DNS filtering",
+                Self::Malware => "Block malware domains",
+                Self::AdsMalware => "Block malware, ads and trackers",
+            }
+            .to_string(),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn live_load_of_configs() {
+        let load = |country: &str, secure_core, free, load, config: &str| ServerLoad {
+            country_code: country.to_string(),
+            secure_core,
+            free,
+            load,
+            wireguard_config: config.to_string(),
+        };
+        let loads = HashMap::from([
+            (
+                "CH#10".to_string(),
+                load("ch", false, false, 30, "switzerland-ch-ch_10.conf"),
+            ),
+            (
+                "CH#11".to_string(),
+                load("ch", false, false, 50, "switzerland-ch-ch_11.conf"),
+            ),
+            (
+                "CH-US#1".to_string(),
+                load(
+                    "us",
+                    true,
+                    false,
+                    80,
+                    "united_states-us-ch_us_1-securecore.conf",
+                ),
+            ),
+            (
+                "CH-FREE#1".to_string(),
+                load("ch", false, true, 95, "switzerland-ch-ch_free_1.conf"),
+            ),
+        ]);
+        let path = |x: &str| Path::new("/p").join(x);
+        assert_eq!(
+            config_load(&loads, &path("switzerland-ch-ch_11.conf")),
+            Some(50)
+        );
+        assert_eq!(
+            config_load(&loads, &path("united_states-us-ch_01-securecore.ovpn")),
+            Some(80)
+        );
+        assert_eq!(config_load(&loads, &path("switzerland-ch.ovpn")), Some(40));
+        assert_eq!(
+            config_load(&loads, &path("switzerland-ch-free.ovpn")),
+            Some(95)
+        );
+        assert_eq!(config_load(&loads, &path("germany-de.ovpn")), None);
+    }
+
+    #[test]
+    fn test_ed25519_public_key_rfc8032() {
+        // RFC 8032 section 7.1 test 1
+        let seed: [u8; 32] = [
+            0x9d, 0x61, 0xb1, 0x9d, 0xef, 0xfd, 0x5a, 0x60, 0xba, 0x84, 0x4a, 0xf4, 0x92, 0xec,
+            0x2c, 0xc4, 0x44, 0x49, 0xc5, 0x69, 0x7b, 0x32, 0x69, 0x19, 0x70, 0x3b, 0xac, 0x03,
+            0x1c, 0xae, 0x7f, 0x60,
+        ];
+        let expected: [u8; 32] = [
+            0xd7, 0x5a, 0x98, 0x01, 0x82, 0xb1, 0x0a, 0xb7, 0xd5, 0x4b, 0xfe, 0xd3, 0xc9, 0x64,
+            0x07, 0x3a, 0x0e, 0xe1, 0x72, 0xf3, 0xda, 0xa6, 0x23, 0x25, 0xaf, 0x02, 0x1a, 0x68,
+            0xf7, 0x07, 0x51, 0x1a,
+        ];
+        let (public, _) = ed25519_keys_from_seed(&seed);
+        assert_eq!(public, expected);
+    }
+
+    #[test]
+    fn test_secure_core_config_filename() {
+        let code_map = crate::util::country_map::code_to_country_map();
+        let logical = LogicalServer {
+            name: "CH-US#1".to_string(),
+            exit_country: "US".to_string(),
+            tier: 2,
+            features: FEATURE_SECURE_CORE,
+            status: 1,
+            load: None,
+            city: None,
+            servers: vec![],
+        };
+        let filename = config_filename(&code_map, &logical);
+        assert_eq!(filename, "united_states-us-ch_us_1-securecore.conf");
+        assert!(ProtonVPN::is_secure_core_config(std::path::Path::new(
+            &filename
+        )));
+    }
+}
@@ -55,3 +55,36 @@ pub trait UiClient {
     fn get_input_numeric_u16(&self, input: InputNumericu16) -> anyhow::Result<u16>;
     fn get_password(&self, password: Password) -> anyhow::Result<String>;
 }
+
+/// UiClient which never prompts: the default option is used where there is one, and anything
+/// else that would need input is an error
+pub struct NonInteractiveClient {}
+
+impl UiClient for NonInteractiveClient {
+    fn get_configuration_choice(
+        &self,
+        _conf_choice: &dyn ConfigurationChoice,
+    ) -> anyhow::Result<usize> {
+        Ok(0)
+    }
+    fn get_bool_choice(&self, bool_choice: BoolChoice) -> anyhow::Result<bool> {
+        Ok(bool_choice.default)
+    }
+    fn get_input(&self, input: Input) -> anyhow::Result<String> {
+        Err(anyhow::anyhow!(
+            "Input required in non-interactive mode: {}",
+            input.prompt
+        ))
+    }
+    fn get_input_numeric_u16(&self, input: InputNumericu16) -> anyhow::Result<u16> {
+        input.default.ok_or_else(|| {
+            anyhow::anyhow!("Input required in non-interactive mode: {}", input.prompt)
+        })
+    }
+    fn get_password(&self, password: Password) -> anyhow::Result<String> {
+        Err(anyhow::anyhow!(
+            "Password required in non-interactive mode: {}",
+            password.prompt
+        ))
+    }
+}
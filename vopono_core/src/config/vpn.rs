@@ -1,11 +1,12 @@
 use super::providers::OpenVpnProvider;
 use super::providers::{ConfigurationChoice, UiClient};
+use crate::util::{credentials, keyring};
 use anyhow::{Context, anyhow};
 use log::{debug, info};
 use serde::{Deserialize, Serialize};
 use std::fmt::Display;
 use std::fs::File;
-use std::io::{BufRead, BufReader, Write};
+use std::io::{BufRead, BufReader};
 use std::path::PathBuf;
 use std::str::FromStr;
 use strum::IntoEnumIterator;
@@ -77,6 +78,11 @@ pub enum Protocol {
     OpenConnect,
     OpenFortiVpn,
     Warp,
+    Xray,
+    Socks5,
+    HttpProxy,
+    Tor,
+    Ikev2,
     None,
 }
 
@@ -89,9 +95,11 @@ pub struct VpnServer {
     pub protocol: Option<OpenVpnProtocol>,
 }
 
-// TODO: Can we avoid storing plaintext passwords?
 // TODO: Allow not storing credentials
 // OpenVPN only
+/// Returns the auth file to pass to OpenVPN. Credentials supplied at exec time (see
+/// util::credentials) or stored in the system keyring are written to a temporary file (see
+/// util::keyring::is_runtime_auth_file) which the caller should remove on shutdown.
 pub fn verify_auth(
     provider: Box<dyn OpenVpnProvider>,
     uiclient: &dyn UiClient,
@@ -101,6 +109,17 @@ pub fn verify_auth(
         return Ok(None);
     }
     let auth_file = auth_file.unwrap();
+    if let Some((user, pass)) = credentials::load_credentials(&provider.alias()) {
+        debug!(
+            "Using {} credentials from environment, prompt or system keyring",
+            provider.alias()
+        );
+        return Ok(Some(keyring::write_runtime_auth_file(
+            &provider.alias(),
+            &user,
+            &pass,
+        )?));
+    }
     let file = File::open(&auth_file);
     match file {
         Ok(f) => {
@@ -110,6 +129,13 @@ pub fn verify_auth(
             // TODO: If thise fail, re-gen auth file
             let _username = iter.next().with_context(|| "No username")??;
             let _password = iter.next().with_context(|| "No password")??;
+            if keyring::keyring_available() {
+                info!(
+                    "Credentials are stored in plaintext in {}, use vopono sync --migrate-credentials {} to move them to the system keyring",
+                    auth_file.display(),
+                    provider.alias()
+                );
+            }
             Ok(Some(auth_file))
         }
         Err(_) => {
@@ -118,13 +144,18 @@ pub fn verify_auth(
                 auth_file.to_string_lossy()
             );
 
-            // Write OpenVPN credentials file
             let (user, pass) = provider.prompt_for_auth(uiclient)?;
-            let mut outfile = File::create(provider.auth_file_path()?.unwrap())?;
-            write!(outfile, "{user}\n{pass}")?;
-
-            info!("Credentials written to: {}", auth_file.to_string_lossy());
-            Ok(Some(auth_file))
+            provider.store_openvpn_auth(&user, &pass)?;
+            if auth_file.exists() {
+                info!("Credentials written to: {}", auth_file.to_string_lossy());
+                Ok(Some(auth_file))
+            } else {
+                Ok(Some(keyring::write_runtime_auth_file(
+                    &provider.alias(),
+                    &user,
+                    &pass,
+                )?))
+            }
         }
     }
 }
@@ -0,0 +1,226 @@
+// cgroup-scoped firewall rules for the launched application
+// With --cgroup-firewall, the application is started in its own cgroup (v2) under
+// /sys/fs/cgroup/vopono, which every process it spawns inherits. Firewall rules in the namespace
+// match sockets by that cgroup, so the application and its helpers may only send traffic over
+// loopback, the tunnel interface, or to the allowed hosts (open hosts, LAN subnets and the host
+// with --allow-host-access) via the veth. Other processes in a shared namespace are not affected.
+// The rules and cgroup belong to the vopono instance which launched the application and are
+// removed when it exits.
+
+use super::firewall::Firewall;
+use super::netns::NetworkNamespace;
+use anyhow::{Context, anyhow};
+use ipnet::IpNet;
+use log::{debug, warn};
+use std::fs::File;
+use std::os::fd::{AsRawFd, RawFd};
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+const CGROUP_ROOT: &str = "/sys/fs/cgroup";
+const CGROUP_PARENT: &str = "vopono";
+
+pub struct AppCgroup {
+    ns_name: String,
+    /// Relative to the cgroup root, as the firewall rules match it
+    name: String,
+    firewall: Firewall,
+    chain: String,
+    procs: File,
+}
+
+impl AppCgroup {
+    pub fn new(
+        netns: &NetworkNamespace,
+        tunnel: &str,
+        veth: &str,
+        allowed: &[IpNet],
+        firewall: Firewall,
+    ) -> anyhow::Result<Self> {
+        if !Path::new(CGROUP_ROOT).join("cgroup.controllers").exists() {
+            return Err(anyhow!(
+                "cgroup v2 is not mounted at {CGROUP_ROOT}, which --cgroup-firewall needs"
+            ));
+        }
+        let name = format!("{CGROUP_PARENT}/{}-{}", netns.name, std::process::id());
+        let path = Path::new(CGROUP_ROOT).join(&name);
+        std::fs::create_dir_all(&path)
+            .with_context(|| format!("Failed to create cgroup {}", path.display()))?;
+        let procs = File::options()
+            .write(true)
+            .open(path.join("cgroup.procs"))
+            .with_context(|| format!("Failed to open cgroup {}", path.display()))?;
+        // Removed on drop if adding the rules fails
+        let cgroup = Self {
+            ns_name: netns.name.clone(),
+            name,
+            firewall,
+            chain: format!("vopono_cgroup_{}", std::process::id()),
+            procs,
+        };
+        cgroup.add_rules(tunnel, veth, allowed)?;
+        debug!(
+            "Application cgroup {} may only use lo, {tunnel} and {veth} to {}",
+            cgroup.name,
+            allowed
+                .iter()
+                .map(|x| x.to_string())
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+        Ok(cgroup)
+    }
+
+    fn add_rules(&self, tunnel: &str, veth: &str, allowed: &[IpNet]) -> anyhow::Result<()> {
+        let chain = self.chain.as_str();
+        match self.firewall {
+            Firewall::IpTables => {
+                for (ipcmd, ipv6) in [("iptables", false), ("ip6tables", true)] {
+                    let append = |args: &[&str]| -> anyhow::Result<()> {
+                        let mut command = vec![ipcmd, "-A", chain];
+                        command.extend(args);
+                        NetworkNamespace::exec(&self.ns_name, &command)
+                    };
+                    NetworkNamespace::exec(&self.ns_name, &[ipcmd, "-N", chain])?;
+                    append(&["-o", "lo", "-j", "RETURN"])?;
+                    append(&["-o", tunnel, "-j", "RETURN"])?;
+                    for host in allowed.iter().filter(|x| matches!(x, IpNet::V6(_)) == ipv6) {
+                        append(&["-o", veth, "-d", &host.to_string(), "-j", "RETURN"])?;
+                    }
+                    append(&["-j", "REJECT"])?;
+                    NetworkNamespace::exec(&self.ns_name, &self.iptables_jump(ipcmd, "-I"))?;
+                }
+            }
+            Firewall::NfTables => {
+                let add = |args: &[&str]| -> anyhow::Result<()> {
+                    let mut command = vec!["nft", "add"];
+                    command.extend(args);
+                    NetworkNamespace::exec(&self.ns_name, &command)
+                };
+                let level = self.name.split('/').count().to_string();
+                let path = format!("\"{}\"", self.name);
+                let rule = |args: &[&str]| -> anyhow::Result<()> {
+                    let mut command = vec![
+                        "rule", "inet", chain, "output", "socket", "cgroupv2", "level", &level,
+                        &path,
+                    ];
+                    command.extend(args);
+                    add(&command)
+                };
+                add(&["table", "inet", chain])?;
+                add(&[
+                    "chain",
+                    "inet",
+                    chain,
+                    "output",
+                    "{ type filter hook output priority -400 ; policy accept; }",
+                ])?;
+                rule(&["oifname", "lo", "return"])?;
+                rule(&["oifname", tunnel, "return"])?;
+                for host in allowed {
+                    let family = match host {
+                        IpNet::V4(_) => "ip",
+                        IpNet::V6(_) => "ip6",
+                    };
+                    rule(&[
+                        "oifname",
+                        veth,
+                        family,
+                        "daddr",
+                        &host.to_string(),
+                        "return",
+                    ])?;
+                }
+                rule(&["counter", "reject"])?;
+            }
+        }
+        Ok(())
+    }
+
+    fn iptables_jump<'a>(&'a self, ipcmd: &'a str, action: &'a str) -> Vec<&'a str> {
+        let mut jump = vec![ipcmd, action, "OUTPUT"];
+        if action == "-I" {
+            jump.push("1");
+        }
+        jump.extend([
+            "-m",
+            "cgroup",
+            "--path",
+            self.name.as_str(),
+            "-j",
+            self.chain.as_str(),
+        ]);
+        jump
+    }
+
+    fn path(&self) -> PathBuf {
+        Path::new(CGROUP_ROOT).join(&self.name)
+    }
+
+    /// Move the command into the cgroup when it is started, before it can spawn anything
+    pub fn enter(&self, command: &mut Command) {
+        let fd: RawFd = self.procs.as_raw_fd();
+        unsafe {
+            command.pre_exec(move || {
+                // 0 is the writing process
+                if libc::write(fd, b"0".as_ptr() as *const libc::c_void, 1) != 1 {
+                    return Err(std::io::Error::last_os_error());
+                }
+                Ok(())
+            });
+        }
+    }
+}
+
+impl Drop for AppCgroup {
+    fn drop(&mut self) {
+        match self.firewall {
+            Firewall::IpTables => {
+                for ipcmd in ["iptables", "ip6tables"] {
+                    NetworkNamespace::exec(&self.ns_name, &self.iptables_jump(ipcmd, "-D")).ok();
+                    NetworkNamespace::exec(&self.ns_name, &[ipcmd, "-F", &self.chain]).ok();
+                    NetworkNamespace::exec(&self.ns_name, &[ipcmd, "-X", &self.chain]).ok();
+                }
+            }
+            Firewall::NfTables => {
+                NetworkNamespace::exec(
+                    &self.ns_name,
+                    &["nft", "delete", "table", "inet", &self.chain],
+                )
+                .ok();
+            }
+        }
+        // Fails while processes are left in it, e.g. daemons which outlive vopono
+        match std::fs::remove_dir(self.path()) {
+            Ok(()) => debug!("Removed application cgroup {}", self.name),
+            Err(e) => warn!("Failed to remove cgroup {}: {e}", self.path().display()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn iptables_cgroup_jump() {
+        let cgroup = AppCgroup {
+            ns_name: "vo_test".to_string(),
+            name: "vopono/vo_test-42".to_string(),
+            firewall: Firewall::IpTables,
+            chain: "vopono_cgroup_42".to_string(),
+            procs: File::open("/dev/null").unwrap(),
+        };
+        assert_eq!(
+            cgroup.iptables_jump("iptables", "-I").join(" "),
+            "iptables -I OUTPUT 1 -m cgroup --path vopono/vo_test-42 -j vopono_cgroup_42"
+        );
+        assert_eq!(
+            cgroup.iptables_jump("ip6tables", "-D").join(" "),
+            "ip6tables -D OUTPUT -m cgroup --path vopono/vo_test-42 -j vopono_cgroup_42"
+        );
+        // Dropping it would try to remove the rules and cgroup
+        std::mem::forget(cgroup);
+    }
+}
@@ -5,23 +5,25 @@ use std::{
 
 use log::debug;
 
-use super::{netns::NetworkNamespace, port_forwarding::Forwarder};
+use super::{app_cgroup::AppCgroup, netns::NetworkNamespace, port_forwarding::Forwarder};
+use crate::util::capabilities::drop_capabilities_prefix;
 use crate::util::{env_vars::set_env_vars, get_all_running_process_names, parse_command_str};
 
 pub struct ApplicationWrapper {
     pub handle: std::process::Child,
-    pub port_forwarding: Option<Box<dyn Forwarder>>,
 }
 
 impl ApplicationWrapper {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         netns: &NetworkNamespace,
         application: &str,
         user: Option<String>,
         group: Option<String>,
         working_directory: Option<PathBuf>,
-        port_forwarding: Option<Box<dyn Forwarder>>,
+        port_forwarding: Option<&dyn Forwarder>,
         silent: bool,
+        cgroup: Option<&AppCgroup>,
     ) -> anyhow::Result<Self> {
         let running_processes = get_all_running_process_names();
         let app_vec = parse_command_str(application)?;
@@ -57,12 +59,10 @@ impl ApplicationWrapper {
             false,
             false,
             working_directory,
-            port_forwarding.as_deref(),
-        )?;
-        Ok(Self {
-            handle,
             port_forwarding,
-        })
+            cgroup,
+        )?;
+        Ok(Self { handle })
     }
 
     pub fn wait_with_output(self) -> anyhow::Result<std::process::Output> {
@@ -81,9 +81,13 @@ impl ApplicationWrapper {
         capture_input: bool,
         set_dir: Option<PathBuf>,
         forwarder: Option<&dyn Forwarder>,
+        cgroup: Option<&AppCgroup>,
     ) -> anyhow::Result<std::process::Child> {
         let mut handle = Command::new("ip");
         set_env_vars(netns, forwarder, &mut handle);
+        if let Some(cgroup) = cgroup {
+            cgroup.enter(&mut handle);
+        }
         handle.args(["netns", "exec", netns.name.as_str()]);
         if let Some(cdir) = set_dir {
             handle.current_dir(cdir);
@@ -126,7 +130,10 @@ impl ApplicationWrapper {
             sudo_string.unwrap_or_else(|| String::from("")),
             command.join(" ")
         );
-        let handle = handle.args(command).spawn()?;
+        let handle = handle
+            .args(drop_capabilities_prefix())
+            .args(command)
+            .spawn()?;
         Ok(handle)
     }
 }
@@ -0,0 +1,408 @@
+// Bridge networking mode
+// Instead of a separate veth subnet per namespace, the host side of each namespace's veth pair
+// is attached to a single host bridge with the address 10.201.0.1/16, and each namespace is
+// given a free address from that subnet. The bridge carries one masquerade rule and one set of
+// forward exceptions for all namespaces, and is removed when the last namespace leaves it.
+
+use super::firewall::Firewall;
+use super::netlink::Netlink;
+use super::netns::{NamespaceSetupLock, NetworkNamespace};
+use super::network_interface::NetworkInterface;
+use crate::util::{get_existing_namespaces, sudo_command};
+use anyhow::{Context, anyhow};
+use ipnet::{IpNet, Ipv4Net};
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+use std::net::Ipv4Addr;
+use std::path::Path;
+use std::process::Command;
+use std::str::FromStr;
+
+pub const BRIDGE_NAME: &str = "vopono_br0";
+pub const BRIDGE_GATEWAY: Ipv4Addr = Ipv4Addr::new(10, 201, 0, 1);
+const BRIDGE_PREFIX_LEN: u8 = 16;
+const NFT_TABLE: &str = "vopono_bridge_nat";
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Bridge {
+    pub name: String,
+    interface: NetworkInterface,
+    firewall: Firewall,
+}
+
+impl Bridge {
+    pub fn subnet() -> Ipv4Net {
+        Ipv4Net::new(BRIDGE_GATEWAY, BRIDGE_PREFIX_LEN)
+            .expect("Invalid bridge subnet")
+            .trunc()
+    }
+
+    fn exists() -> bool {
+        Path::new("/sys/class/net").join(BRIDGE_NAME).exists()
+    }
+
+    /// Number of veth devices attached to the bridge
+    fn attached_ports() -> usize {
+        Path::new("/sys/class/net")
+            .join(BRIDGE_NAME)
+            .join("brif")
+            .read_dir()
+            .map(|x| x.count())
+            .unwrap_or(0)
+    }
+
+    /// Whether the bridge exists with no namespaces attached, i.e. was left behind by an instance
+    /// which exited without tearing down
+    pub fn is_orphaned() -> bool {
+        Self::exists() && Self::attached_ports() == 0
+    }
+
+    /// Remove a bridge left behind by an exited instance, with its iptables rules (the nftables
+    /// tables are collected with the other shared tables)
+    pub fn remove_orphaned() -> anyhow::Result<()> {
+        let _lock = NamespaceSetupLock::acquire(BRIDGE_NAME)?;
+        // A namespace may have attached to it since it was found
+        if !Self::is_orphaned() {
+            debug!("Bridge {BRIDGE_NAME} is now in use, skipping cleanup");
+            return Ok(());
+        }
+        if let Ok(output) = Command::new("iptables-save").output() {
+            let subnet = Self::subnet().to_string();
+            for args in orphaned_iptables_rules(&String::from_utf8_lossy(&output.stdout), &subnet) {
+                let mut command = vec!["iptables"];
+                command.extend(args.iter().map(|x| x.as_str()));
+                sudo_command(&command)?;
+            }
+        }
+        Netlink::host()?.delete_link(BRIDGE_NAME)
+    }
+
+    /// Attach the host side of a veth pair to the bridge (creating the bridge and its firewall
+    /// rules if this is the first namespace), and return the free address assigned to the
+    /// namespace side.
+    pub fn attach(
+        netns: &NetworkNamespace,
+        host_veth: &str,
+        namespace_veth: &str,
+        interface: NetworkInterface,
+        firewall: Firewall,
+    ) -> anyhow::Result<(Self, Ipv4Addr)> {
+        // Serialise address allocation between vopono instances
+        let _lock = NamespaceSetupLock::acquire(BRIDGE_NAME)?;
+
+        let bridge = Self {
+            name: BRIDGE_NAME.to_string(),
+            interface,
+            firewall,
+        };
+        if Self::exists() {
+            debug!("Attaching to existing bridge {BRIDGE_NAME}");
+        } else {
+            bridge.create()?;
+        }
+
+        Netlink::host()?.set_controller(host_veth, BRIDGE_NAME)?;
+
+        let address = Self::free_address()?;
+        Netlink::in_namespace(&netns.name)?
+            .add_address(
+                namespace_veth,
+                IpNet::V4(Ipv4Net::new(address, BRIDGE_PREFIX_LEN)?),
+                false,
+            )
+            .with_context(|| format!("Failed to assign bridge address to {namespace_veth}"))?;
+        Ok((bridge, address))
+    }
+
+    fn create(&self) -> anyhow::Result<()> {
+        info!("Creating bridge {BRIDGE_NAME} for vopono namespaces");
+        let netlink = Netlink::host()?;
+        netlink.add_bridge(BRIDGE_NAME)?;
+        netlink
+            .add_address(
+                BRIDGE_NAME,
+                IpNet::V4(Ipv4Net::new(BRIDGE_GATEWAY, BRIDGE_PREFIX_LEN)?),
+                false,
+            )
+            .with_context(|| format!("Failed to assign address to bridge {BRIDGE_NAME}"))?;
+        netlink.set_up(BRIDGE_NAME)?;
+
+        let subnet = Self::subnet().to_string();
+        let interface = self.interface.name.as_str();
+        match self.firewall {
+            Firewall::IpTables => {
+                for rule in Self::iptables_rules(&subnet, interface) {
+                    let mut args = vec!["iptables", "-t", rule.0, rule.1, rule.2];
+                    args.extend(rule.3.iter());
+                    sudo_command(&args).with_context(|| {
+                        format!("Failed to add iptables rule for bridge {BRIDGE_NAME}")
+                    })?;
+                }
+            }
+            Firewall::NfTables => {
+                sudo_command(&["nft", "add", "table", "ip", NFT_TABLE])
+                    .with_context(|| format!("Failed to create nft table {NFT_TABLE}"))?;
+                sudo_command(&[
+                    "nft",
+                    "add chain ip vopono_bridge_nat postrouting { type nat hook postrouting priority 100 ; }",
+                ])
+                .with_context(|| format!("Failed to create nft postrouting chain in {NFT_TABLE}"))?;
+                sudo_command(&[
+                    "nft",
+                    "add chain ip vopono_bridge_nat forward { type filter hook forward priority -10 ; }",
+                ])
+                .with_context(|| format!("Failed to create nft forward chain in {NFT_TABLE}"))?;
+                for rule in [
+                    vec![
+                        "postrouting",
+                        "oifname",
+                        interface,
+                        "ip",
+                        "saddr",
+                        &subnet,
+                        "counter",
+                        "masquerade",
+                    ],
+                    vec![
+                        "forward",
+                        "iifname",
+                        BRIDGE_NAME,
+                        "oifname",
+                        interface,
+                        "counter",
+                        "accept",
+                    ],
+                    vec![
+                        "forward",
+                        "iifname",
+                        interface,
+                        "oifname",
+                        BRIDGE_NAME,
+                        "counter",
+                        "accept",
+                    ],
+                    vec![
+                        "forward",
+                        "iifname",
+                        BRIDGE_NAME,
+                        "oifname",
+                        BRIDGE_NAME,
+                        "counter",
+                        "accept",
+                    ],
+                ] {
+                    let mut args = vec!["nft", "add", "rule", "ip", NFT_TABLE];
+                    args.extend(rule);
+                    sudo_command(&args).with_context(|| {
+                        format!("Failed to add nftables rule for bridge {BRIDGE_NAME}")
+                    })?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// (table, action, chain, rule) for the masquerade and forward rules of the bridge
+    fn iptables_rules<'a>(
+        subnet: &'a str,
+        interface: &'a str,
+    ) -> Vec<(&'static str, &'static str, &'static str, Vec<&'a str>)> {
+        vec![
+            (
+                "nat",
+                "-A",
+                "POSTROUTING",
+                vec!["-s", subnet, "-o", interface, "-j", "MASQUERADE"],
+            ),
+            (
+                "filter",
+                "-I",
+                "FORWARD",
+                vec!["-i", BRIDGE_NAME, "-o", interface, "-j", "ACCEPT"],
+            ),
+            (
+                "filter",
+                "-I",
+                "FORWARD",
+                vec!["-i", interface, "-o", BRIDGE_NAME, "-j", "ACCEPT"],
+            ),
+            // Between namespaces on the bridge (if br_netfilter is loaded)
+            (
+                "filter",
+                "-I",
+                "FORWARD",
+                vec!["-i", BRIDGE_NAME, "-o", BRIDGE_NAME, "-j", "ACCEPT"],
+            ),
+        ]
+    }
+
+    /// Lowest address in the bridge subnet not used by the host or any network namespace
+    fn free_address() -> anyhow::Result<Ipv4Addr> {
+        let mut used = vec![BRIDGE_GATEWAY];
+        for ns in get_existing_namespaces()? {
+            let output = Command::new("ip")
+                .args(["netns", "exec", &ns, "ip", "-4", "-o", "addr", "show"])
+                .output()?;
+            for address in String::from_utf8_lossy(&output.stdout)
+                .split_whitespace()
+                .filter_map(|x| Ipv4Net::from_str(x).ok())
+            {
+                used.push(address.addr());
+            }
+        }
+        Self::subnet()
+            .hosts()
+            .find(|x| !used.contains(x))
+            .ok_or_else(|| anyhow!("No free addresses left on bridge {BRIDGE_NAME}"))
+    }
+
+    /// Allow traffic to and from other namespaces on the bridge through the namespace firewall
+    pub fn allow_peers(netns: &NetworkNamespace, firewall: Firewall) -> anyhow::Result<()> {
+        let subnet = Self::subnet().to_string();
+        match firewall {
+            Firewall::IpTables => {
+                NetworkNamespace::exec(
+                    &netns.name,
+                    &[
+                        "iptables", "-I", "OUTPUT", "1", "-d", &subnet, "-j", "ACCEPT",
+                    ],
+                )?;
+                NetworkNamespace::exec(
+                    &netns.name,
+                    &[
+                        "iptables", "-I", "INPUT", "1", "-s", &subnet, "-j", "ACCEPT",
+                    ],
+                )?;
+            }
+            Firewall::NfTables => {
+                NetworkNamespace::exec(
+                    &netns.name,
+                    &[
+                        "nft",
+                        "insert",
+                        "rule",
+                        "inet",
+                        &netns.name,
+                        "output",
+                        "ip",
+                        "daddr",
+                        &subnet,
+                        "counter",
+                        "accept",
+                    ],
+                )?;
+                NetworkNamespace::exec(
+                    &netns.name,
+                    &[
+                        "nft",
+                        "insert",
+                        "rule",
+                        "inet",
+                        &netns.name,
+                        "input",
+                        "ip",
+                        "saddr",
+                        &subnet,
+                        "counter",
+                        "accept",
+                    ],
+                )?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Bridge {
+    fn drop(&mut self) {
+        let _lock = NamespaceSetupLock::acquire(BRIDGE_NAME);
+        // Only remove the bridge once no veth devices are attached to it
+        let ports = Self::attached_ports();
+        if ports > 0 {
+            debug!(
+                "Leaving bridge {} with {} attached namespaces",
+                self.name, ports
+            );
+            return;
+        }
+        info!("Removing bridge {}", self.name);
+        match self.firewall {
+            Firewall::IpTables => {
+                let subnet = Self::subnet().to_string();
+                for rule in Self::iptables_rules(&subnet, &self.interface.name) {
+                    let mut args = vec!["iptables", "-t", rule.0, "-D", rule.2];
+                    args.extend(rule.3.iter());
+                    if let Err(e) = sudo_command(&args) {
+                        warn!(
+                            "Failed to delete iptables rule for bridge {}: {:?}",
+                            self.name, e
+                        );
+                    }
+                }
+            }
+            Firewall::NfTables => {
+                if let Err(e) = sudo_command(&["nft", "delete", "table", "ip", NFT_TABLE]) {
+                    warn!("Failed to delete nft table {NFT_TABLE}: {e:?}");
+                }
+            }
+        }
+        if let Err(e) = Netlink::host().and_then(|netlink| netlink.delete_link(&self.name)) {
+            warn!("Failed to delete bridge {}: {:?}", self.name, e);
+        }
+    }
+}
+
+/// iptables arguments deleting the bridge's rules found in iptables-save output, i.e. those
+/// using the bridge device or masquerading its subnet
+fn orphaned_iptables_rules(save_output: &str, subnet: &str) -> Vec<Vec<String>> {
+    let mut table = "filter";
+    let mut rules = Vec::new();
+    for line in save_output.lines() {
+        if let Some(name) = line.strip_prefix('*') {
+            table = name.trim();
+            continue;
+        }
+        let Some(rule) = line.strip_prefix("-A ") else {
+            continue;
+        };
+        let words: Vec<&str> = rule.split_whitespace().collect();
+        if words.contains(&BRIDGE_NAME) || (table == "nat" && words.contains(&subnet)) {
+            let mut args = vec!["-t".to_string(), table.to_string(), "-D".to_string()];
+            args.extend(words.iter().map(|x| x.to_string()));
+            rules.push(args);
+        }
+    }
+    rules
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn orphaned_rules_from_iptables_save() {
+        let output = "*nat\n\
+            :POSTROUTING ACCEPT [0:0]\n\
+            -A POSTROUTING -s 10.201.0.0/16 -o eth0 -j MASQUERADE\n\
+            -A POSTROUTING -s 10.200.1.0/24 -o eth0 -j MASQUERADE\n\
+            COMMIT\n\
+            *filter\n\
+            -A FORWARD -i vopono_br0 -o eth0 -j ACCEPT\n\
+            -A FORWARD -i eth0 -o docker0 -j ACCEPT\n\
+            COMMIT\n";
+        let rules = orphaned_iptables_rules(output, &Bridge::subnet().to_string());
+        assert_eq!(
+            rules,
+            vec![
+                "-t nat -D POSTROUTING -s 10.201.0.0/16 -o eth0 -j MASQUERADE"
+                    .split(' ')
+                    .map(String::from)
+                    .collect::<Vec<_>>(),
+                "-t filter -D FORWARD -i vopono_br0 -o eth0 -j ACCEPT"
+                    .split(' ')
+                    .map(String::from)
+                    .collect(),
+            ]
+        );
+    }
+}
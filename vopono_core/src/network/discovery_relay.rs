@@ -0,0 +1,277 @@
+// mDNS and SSDP relay between the network namespace and the host LAN
+// Multicast discovery traffic cannot cross the veth pair (and would be sent into the tunnel), so
+// apps in the namespace cannot find Chromecasts, DLNA servers, printers etc. The relay joins the
+// multicast groups on the host interface and on the namespace side of the veth pair, and
+// forwards datagrams between them. SSDP search responses are unicast to the relay on the host,
+// so they are passed on to the last client in the namespace that sent a search.
+
+use super::firewall::Firewall;
+use super::netns::NetworkNamespace;
+use super::network_interface::NetworkInterface;
+use crate::util::{open_ports_with_protocol, open_subnets, run_in_netns};
+use anyhow::Context;
+use ipnet::IpNet;
+use log::{debug, info, warn};
+use socket2::{Domain, Protocol, Socket, Type};
+use std::collections::VecDeque;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4, UdpSocket};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone, Copy)]
+struct DiscoveryProtocol {
+    name: &'static str,
+    group: Ipv4Addr,
+    port: u16,
+}
+
+const MDNS: DiscoveryProtocol = DiscoveryProtocol {
+    name: "mDNS",
+    group: Ipv4Addr::new(224, 0, 0, 251),
+    port: 5353,
+};
+
+const SSDP: DiscoveryProtocol = DiscoveryProtocol {
+    name: "SSDP",
+    group: Ipv4Addr::new(239, 255, 255, 250),
+    port: 1900,
+};
+
+/// Multicast groups relayed, which must be reachable via the veth in the namespace
+pub const DISCOVERY_GROUPS: [Ipv4Addr; 2] = [MDNS.group, SSDP.group];
+
+/// Packets relayed recently, so copies looped back to the relay are not relayed again
+const SEEN_EXPIRY: Duration = Duration::from_secs(2);
+
+#[derive(Default)]
+struct RelayState {
+    seen: VecDeque<(Instant, u64)>,
+    /// Last client in the namespace to send an SSDP search, which receives unicast responses
+    last_client: Option<SocketAddr>,
+}
+
+impl RelayState {
+    fn digest(data: &[u8]) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        data.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    /// Record a packet being relayed, returns false if it was already relayed recently
+    fn check_and_record(&mut self, data: &[u8]) -> bool {
+        let now = Instant::now();
+        while self
+            .seen
+            .front()
+            .is_some_and(|(t, _)| now.duration_since(*t) > SEEN_EXPIRY)
+        {
+            self.seen.pop_front();
+        }
+        let digest = Self::digest(data);
+        if self.seen.iter().any(|(_, d)| *d == digest) {
+            return false;
+        }
+        self.seen.push_back((now, digest));
+        true
+    }
+}
+
+pub struct DiscoveryRelay {
+    stop: Arc<AtomicBool>,
+    handles: Vec<JoinHandle<()>>,
+}
+
+impl DiscoveryRelay {
+    pub fn new(
+        netns: &NetworkNamespace,
+        host_interface: &NetworkInterface,
+        firewall: Firewall,
+    ) -> anyhow::Result<Self> {
+        let host_ip = host_interface.ipv4_address()?;
+        let namespace_ip = match netns
+            .veth_pair_ips
+            .as_ref()
+            .context("Network namespace has no veth pair for the discovery relay")?
+            .namespace_ip
+        {
+            std::net::IpAddr::V4(ip) => ip,
+            std::net::IpAddr::V6(_) => anyhow::bail!("Discovery relay requires an IPv4 veth pair"),
+        };
+        let veth = netns
+            .veth_pair
+            .as_ref()
+            .context("Network namespace has no veth pair for the discovery relay")?
+            .source
+            .clone();
+
+        // Send discovery traffic from apps via the veth rather than the tunnel, and let it
+        // through the namespace firewall
+        for group in DISCOVERY_GROUPS {
+            NetworkNamespace::exec(
+                &netns.name,
+                &[
+                    "ip",
+                    "route",
+                    "replace",
+                    &format!("{group}/32"),
+                    "dev",
+                    &veth,
+                ],
+            )
+            .with_context(|| format!("Failed to add multicast route for {group}"))?;
+        }
+        let groups: Vec<IpNet> = DISCOVERY_GROUPS
+            .into_iter()
+            .map(|g| IpNet::V4(g.into()))
+            .collect();
+        open_subnets(&netns.name, &groups, firewall)?;
+        open_ports_with_protocol(netns, &[MDNS.port, SSDP.port], "udp", firewall)?;
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let mut handles = Vec::new();
+        for protocol in [MDNS, SSDP] {
+            let host_socket = multicast_socket(protocol, host_ip, false)
+                .with_context(|| format!("Failed to open {} socket on host", protocol.name))?;
+            let namespace_socket = run_in_netns(&netns.name, move || {
+                multicast_socket(protocol, namespace_ip, true)
+            })
+            .with_context(|| format!("Failed to open {} socket in namespace", protocol.name))?;
+            let state = Arc::new(Mutex::new(RelayState::default()));
+
+            // LAN -> namespace
+            handles.push(spawn_relay(
+                protocol,
+                host_socket.try_clone()?,
+                namespace_socket.try_clone()?,
+                Arc::clone(&state),
+                Arc::clone(&stop),
+                Direction::ToNamespace,
+            ));
+            // Namespace -> LAN
+            handles.push(spawn_relay(
+                protocol,
+                namespace_socket,
+                host_socket,
+                state,
+                Arc::clone(&stop),
+                Direction::ToHost,
+            ));
+        }
+        info!(
+            "Relaying mDNS and SSDP between {} and network namespace {}",
+            host_interface.name, netns.name
+        );
+        Ok(Self { stop, handles })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Direction {
+    ToNamespace,
+    ToHost,
+}
+
+/// UDP socket bound to the protocol port and joined to its group on the interface with the
+/// given address. Shares the port with other discovery daemons (e.g. avahi).
+fn multicast_socket(
+    protocol: DiscoveryProtocol,
+    interface_ip: Ipv4Addr,
+    multicast_loop: bool,
+) -> anyhow::Result<UdpSocket> {
+    let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_reuse_address(true)?;
+    socket.set_reuse_port(true)?;
+    socket.bind(&SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, protocol.port).into())?;
+    socket.join_multicast_v4(&protocol.group, &interface_ip)?;
+    socket.set_multicast_if_v4(&interface_ip)?;
+    // Loop back in the namespace so apps there receive the relayed packets
+    socket.set_multicast_loop_v4(multicast_loop)?;
+    socket.set_read_timeout(Some(Duration::from_secs(1)))?;
+    Ok(socket.into())
+}
+
+fn spawn_relay(
+    protocol: DiscoveryProtocol,
+    from: UdpSocket,
+    to: UdpSocket,
+    state: Arc<Mutex<RelayState>>,
+    stop: Arc<AtomicBool>,
+    direction: Direction,
+) -> JoinHandle<()> {
+    std::thread::spawn(move || {
+        let group = SocketAddr::V4(SocketAddrV4::new(protocol.group, protocol.port));
+        let mut buf = [0u8; 9000];
+        while !stop.load(Ordering::Relaxed) {
+            let (len, source) = match from.recv_from(&mut buf) {
+                Ok(x) => x,
+                Err(e)
+                    if matches!(
+                        e.kind(),
+                        std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut
+                    ) =>
+                {
+                    continue;
+                }
+                Err(e) => {
+                    warn!("{} relay receive failed: {:?}", protocol.name, e);
+                    continue;
+                }
+            };
+            let data = &buf[..len];
+            let destination = {
+                let mut state = state.lock().unwrap_or_else(|e| e.into_inner());
+                if !state.check_and_record(data) {
+                    continue;
+                }
+                let ssdp_response = protocol.port == SSDP.port && data.starts_with(b"HTTP/");
+                match direction {
+                    Direction::ToHost => {
+                        if protocol.port == SSDP.port && data.starts_with(b"M-SEARCH") {
+                            state.last_client = Some(source);
+                        }
+                        group
+                    }
+                    // Search responses go back to the client which searched
+                    Direction::ToNamespace if ssdp_response => match state.last_client {
+                        Some(client) => client,
+                        None => continue,
+                    },
+                    Direction::ToNamespace => group,
+                }
+            };
+            debug!(
+                "Relaying {} packet from {} to {} ({:?})",
+                protocol.name, source, destination, direction
+            );
+            if let Err(e) = to.send_to(data, destination) {
+                warn!("{} relay send failed: {:?}", protocol.name, e);
+            }
+        }
+    })
+}
+
+impl Drop for DiscoveryRelay {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        for handle in self.handles.drain(..) {
+            handle.join().ok();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn relay_state_skips_looped_packets() {
+        let mut state = RelayState::default();
+        assert!(state.check_and_record(b"M-SEARCH * HTTP/1.1"));
+        assert!(!state.check_and_record(b"M-SEARCH * HTTP/1.1"));
+        assert!(state.check_and_record(b"NOTIFY * HTTP/1.1"));
+    }
+}
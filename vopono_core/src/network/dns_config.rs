@@ -1,4 +1,4 @@
-use anyhow::Context;
+use anyhow::{Context, anyhow};
 use log::{debug, warn};
 use regex::{Captures, Regex};
 use serde::{Deserialize, Serialize};
@@ -6,6 +6,7 @@ use std::io::BufRead;
 use std::io::Write;
 use std::net::IpAddr;
 use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
 
 use crate::util::open_hosts;
 
@@ -14,9 +15,74 @@ use super::firewall::Firewall;
 #[derive(Serialize, Deserialize, Debug)]
 pub struct DnsConfig {
     ns_name: String,
+    /// Set when replaced by a new config, which has overwritten the files
+    #[serde(skip)]
+    keep_files: bool,
+}
+
+/// Name servers in a resolv.conf file
+pub fn read_nameservers(path: &str) -> Vec<IpAddr> {
+    std::fs::read_to_string(path)
+        .unwrap_or_default()
+        .lines()
+        .filter_map(|line| line.strip_prefix("nameserver"))
+        .filter_map(|x| x.trim().parse().ok())
+        .collect()
+}
+
+/// Check a hosts entry is an IP address followed by at least one host name
+pub fn validate_hosts_entry(entry: &str) -> anyhow::Result<()> {
+    let mut fields = entry.split_whitespace();
+    let address = fields.next().unwrap_or_default();
+    if address.parse::<IpAddr>().is_err() {
+        return Err(anyhow!("Invalid address in hosts entry: {entry}"));
+    }
+    if fields.next().is_none() {
+        return Err(anyhow!("No host name in hosts entry: {entry}"));
+    }
+    Ok(())
+}
+
+/// Entries of a file in hosts format, without comments and blank lines
+pub fn read_hosts_entries(path: &Path) -> anyhow::Result<Vec<String>> {
+    let contents = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read hosts file: {}", path.display()))?;
+    contents
+        .lines()
+        .map(|line| line.split('#').next().unwrap_or_default().trim())
+        .filter(|line| !line.is_empty())
+        .map(|line| {
+            validate_hosts_entry(line)
+                .with_context(|| format!("Invalid hosts file: {}", path.display()))?;
+            Ok(line.to_string())
+        })
+        .collect()
+}
+
+/// The namespace entries followed by the host's hosts file, so they override host entries as the
+/// first match for a name is used
+fn namespace_hosts(entries: &[String], host_hosts: &str) -> String {
+    let mut hosts = String::from("# Added by vopono\n");
+    for entry in entries {
+        hosts.push_str(entry);
+        hosts.push('\n');
+    }
+    hosts.push_str(host_hosts);
+    hosts
 }
 
 impl DnsConfig {
+    /// Name servers currently in the resolv.conf of the namespace, which is the host's one if
+    /// the namespace has none
+    pub fn nameservers(ns_name: &str) -> Vec<IpAddr> {
+        let path = format!("/etc/netns/{ns_name}/resolv.conf");
+        if std::path::Path::new(&path).exists() {
+            read_nameservers(&path)
+        } else {
+            read_nameservers("/etc/resolv.conf")
+        }
+    }
+
     pub fn new(
         ns_name: String,
         servers: &[IpAddr],
@@ -83,16 +149,11 @@ impl DnsConfig {
 
         if let Some(my_hosts_entries) = effective_hosts_entries {
             let hosts_path = format!("/etc/netns/{ns_name}/hosts");
-            let mut hosts = std::fs::File::create(&hosts_path)
-                .with_context(|| format!("Failed to open hosts: {}", &hosts_path))?;
+            let host_hosts = std::fs::read_to_string("/etc/hosts").unwrap_or_default();
+            std::fs::write(&hosts_path, namespace_hosts(&my_hosts_entries, &host_hosts))
+                .with_context(|| format!("Failed to overwrite hosts: {}", &hosts_path))?;
             std::fs::set_permissions(&hosts_path, PermissionsExt::from_mode(0o644))
                 .with_context(|| format!("Failed to set file permissions for {}", &hosts_path))?;
-
-            for hosts_entry in my_hosts_entries {
-                writeln!(hosts, "{hosts_entry}").with_context(|| {
-                    format!("Failed to overwrite hosts: /etc/netns/{ns_name}/hosts")
-                })?;
-            }
         }
 
         if std::path::Path::new("/etc/nsswitch.conf").exists() {
@@ -125,12 +186,24 @@ impl DnsConfig {
         open_hosts(&ns_name, servers, firewall)
             .with_context(|| format!("Failed to open hosts in network namespace: {}", &ns_name))?;
 
-        Ok(Self { ns_name })
+        Ok(Self {
+            ns_name,
+            keep_files: false,
+        })
+    }
+
+    /// Drop the config without removing the files in /etc/netns, for when a new config for
+    /// the same namespace replaces it
+    pub fn keep(mut self) {
+        self.keep_files = true;
     }
 }
 
 impl Drop for DnsConfig {
     fn drop(&mut self) {
+        if self.keep_files {
+            return;
+        }
         let path = format!("/etc/netns/{}", self.ns_name);
         match std::fs::remove_dir_all(&path) {
             Ok(_) => {}
@@ -141,3 +214,23 @@ impl Drop for DnsConfig {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn namespace_entries_before_host_entries() {
+        let hosts = namespace_hosts(
+            &["10.0.1.10 tracker.example".to_string()],
+            "127.0.0.1 localhost\n",
+        );
+        assert_eq!(
+            hosts,
+            "# Added by vopono\n10.0.1.10 tracker.example\n127.0.0.1 localhost\n"
+        );
+        assert!(validate_hosts_entry("10.0.1.10 tracker.example").is_ok());
+        assert!(validate_hosts_entry("tracker.example 10.0.1.10").is_err());
+        assert!(validate_hosts_entry("10.0.1.10").is_err());
+    }
+}
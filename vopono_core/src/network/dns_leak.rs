@@ -0,0 +1,238 @@
+// DNS leak test for a running network namespace
+// Resolvers are identified with whoami names. Akamai's whoami.akamai.net and Google's
+// o-o.myaddr.l.google.com answer with the address of the recursive resolver which asked them.
+// Cloudflare's whoami.cloudflare (a CHAOS TXT query to 1.1.1.1) answers with the address of the
+// client. The same queries are made through the resolv.conf of the namespace and of the host. A
+// namespace query which reaches a resolver the host uses, or leaves from the host's public
+// address, has bypassed the tunnel. The large public resolvers are anycast, so the host and the
+// VPN server may reach the same one; sharing those is not counted as a leak.
+
+use super::dns_config::{DnsConfig, read_nameservers};
+use super::mtu::{route_interface, tunnel_interface};
+use super::netns::NetworkNamespace;
+use super::stub_resolver::{
+    CLASS_CHAOS, CLASS_IN, DnsUpstream, TYPE_A, TYPE_TXT, lookup, response_addresses,
+    response_texts,
+};
+use crate::util::run_in_netns;
+use anyhow::anyhow;
+use ipnet::IpNet;
+use log::debug;
+use std::net::{IpAddr, SocketAddr};
+
+const AKAMAI_WHOAMI: &str = "whoami.akamai.net";
+const GOOGLE_WHOAMI: &str = "o-o.myaddr.l.google.com";
+const CLOUDFLARE_WHOAMI: &str = "whoami.cloudflare";
+const CLOUDFLARE_DNS: IpAddr = IpAddr::V4(std::net::Ipv4Addr::new(1, 1, 1, 1));
+/// Networks the anycast public resolvers (Google, Cloudflare, Quad9, OpenDNS) query from
+const PUBLIC_RESOLVER_NETWORKS: &[&str] = &[
+    "74.125.0.0/16",
+    "172.217.0.0/16",
+    "172.253.0.0/16",
+    "173.194.0.0/16",
+    "2001:4860::/32",
+    "2404:6800::/32",
+    "108.162.192.0/18",
+    "141.101.64.0/18",
+    "162.158.0.0/15",
+    "172.64.0.0/13",
+    "2400:cb00::/32",
+    "2a06:98c0::/29",
+    "9.9.9.0/24",
+    "149.112.112.0/24",
+    "2620:fe::/48",
+    "146.112.0.0/16",
+    "208.67.216.0/21",
+    "2620:119::/32",
+];
+
+/// Whether the address belongs to one of the anycast public resolvers
+fn is_public_resolver(ip: &IpAddr) -> bool {
+    PUBLIC_RESOLVER_NETWORKS
+        .iter()
+        .filter_map(|x| x.parse::<IpNet>().ok())
+        .any(|net| net.contains(ip))
+}
+
+/// Name server in resolv.conf and the interface the namespace reaches it through
+pub struct ResolverRoute {
+    pub server: IpAddr,
+    pub interface: Option<String>,
+}
+
+pub struct DnsLeakReport {
+    pub resolvers: Vec<ResolverRoute>,
+    pub tunnel_interface: Option<String>,
+    pub veth_interface: Option<String>,
+    pub upstreams: Vec<DnsUpstream>,
+    /// Addresses of the recursive resolvers seen by the leak test services
+    pub namespace_resolvers_seen: Vec<IpAddr>,
+    pub host_resolvers_seen: Vec<IpAddr>,
+    pub namespace_public: Vec<IpAddr>,
+    pub host_public: Vec<IpAddr>,
+}
+
+impl DnsLeakReport {
+    /// Reasons the namespace leaks DNS, empty if no leak was found
+    pub fn leaks(&self) -> Vec<String> {
+        let mut leaks = Vec::new();
+        for ip in self
+            .namespace_public
+            .iter()
+            .filter(|x| self.host_public.contains(x))
+        {
+            leaks.push(format!(
+                "Traffic from the namespace leaves from the public address of the host ({ip}), the tunnel is not in use"
+            ));
+        }
+        for ip in self.namespace_resolvers_seen.iter() {
+            // Anycast public resolvers are shared by everyone nearby, so only a resolver of the
+            // host's own network (e.g. its ISP) shows the query left outside the tunnel
+            if self.host_resolvers_seen.contains(ip) && !is_public_resolver(ip) {
+                leaks.push(format!(
+                    "DNS queries from the namespace reached resolver {ip}, which the host also uses"
+                ));
+            } else if self.host_public.contains(ip) {
+                leaks.push(format!(
+                    "DNS queries from the namespace leave from the public address of the host ({ip})"
+                ));
+            }
+        }
+        // Only meaningful if the namespace has a tunnel besides its veth interface
+        if self.tunnel_interface.is_some() && self.tunnel_interface != self.veth_interface {
+            for resolver in self.resolvers.iter().filter(|x| {
+                !x.server.is_loopback()
+                    && x.interface.is_some()
+                    && x.interface == self.veth_interface
+            }) {
+                leaks.push(format!(
+                    "Resolver {} is reached through {}, outside the tunnel",
+                    resolver.server,
+                    resolver.interface.as_deref().unwrap_or_default()
+                ));
+            }
+        }
+        leaks
+    }
+
+    /// Whether the leak test services answered at all, otherwise the result is inconclusive
+    pub fn conclusive(&self) -> bool {
+        !self.namespace_resolvers_seen.is_empty()
+    }
+}
+
+/// Resolver addresses reported by the whoami services, asking each of the servers
+fn resolvers_seen(servers: &[IpAddr]) -> Vec<IpAddr> {
+    let mut seen = Vec::new();
+    for server in servers {
+        let server = SocketAddr::new(*server, 53);
+        match lookup(server, AKAMAI_WHOAMI, TYPE_A, CLASS_IN) {
+            Ok((id, response)) => seen.extend(response_addresses(&response, id)),
+            Err(e) => debug!("{AKAMAI_WHOAMI} via {server} failed: {e:?}"),
+        }
+        match lookup(server, GOOGLE_WHOAMI, TYPE_TXT, CLASS_IN) {
+            // Also answers with the EDNS client subnet, if any, in another string
+            Ok((id, response)) => seen.extend(
+                response_texts(&response, id)
+                    .iter()
+                    .filter_map(|x| x.parse::<IpAddr>().ok()),
+            ),
+            Err(e) => debug!("{GOOGLE_WHOAMI} via {server} failed: {e:?}"),
+        }
+    }
+    seen.sort();
+    seen.dedup();
+    seen
+}
+
+pub fn public_addresses() -> Vec<IpAddr> {
+    match lookup(
+        SocketAddr::new(CLOUDFLARE_DNS, 53),
+        CLOUDFLARE_WHOAMI,
+        TYPE_TXT,
+        CLASS_CHAOS,
+    ) {
+        Ok((id, response)) => response_texts(&response, id)
+            .iter()
+            .filter_map(|x| x.parse().ok())
+            .collect(),
+        Err(e) => {
+            debug!("{CLOUDFLARE_WHOAMI} failed: {e:?}");
+            Vec::new()
+        }
+    }
+}
+
+/// Run the leak test queries from the namespace and the host, must be called from a thread in
+/// the host network namespace
+pub fn dns_leak_test(netns: &NetworkNamespace) -> anyhow::Result<DnsLeakReport> {
+    let servers = DnsConfig::nameservers(&netns.name);
+    if servers.is_empty() {
+        return Err(anyhow!(
+            "No name servers in /etc/netns/{}/resolv.conf",
+            netns.name
+        ));
+    }
+    let resolvers = servers
+        .iter()
+        .map(|server| ResolverRoute {
+            server: *server,
+            interface: route_interface(&netns.name, *server).ok(),
+        })
+        .collect();
+
+    let namespace_servers = servers.clone();
+    let (namespace_resolvers_seen, namespace_public) = run_in_netns(&netns.name, move || {
+        Ok((resolvers_seen(&namespace_servers), public_addresses()))
+    })?;
+    let host_servers = read_nameservers("/etc/resolv.conf");
+
+    Ok(DnsLeakReport {
+        resolvers,
+        tunnel_interface: tunnel_interface(&netns.name).ok(),
+        veth_interface: netns.veth_pair.as_ref().map(|x| x.source.clone()),
+        upstreams: netns.dns_upstreams.clone(),
+        namespace_resolvers_seen,
+        host_resolvers_seen: resolvers_seen(&host_servers),
+        namespace_public,
+        host_public: public_addresses(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn detect_leaks() {
+        let mut report = DnsLeakReport {
+            resolvers: vec![ResolverRoute {
+                server: "10.64.0.1".parse().unwrap(),
+                interface: Some("wg0".to_string()),
+            }],
+            tunnel_interface: Some("wg0".to_string()),
+            veth_interface: Some("vopono_s".to_string()),
+            upstreams: Vec::new(),
+            namespace_resolvers_seen: vec!["185.65.135.1".parse().unwrap()],
+            host_resolvers_seen: vec![
+                "74.125.0.1".parse().unwrap(),
+                "198.51.100.53".parse().unwrap(),
+            ],
+            namespace_public: vec!["185.65.134.2".parse().unwrap()],
+            host_public: vec!["203.0.113.7".parse().unwrap()],
+        };
+        assert!(report.leaks().is_empty());
+
+        // The same anycast public resolver is not a leak
+        report
+            .namespace_resolvers_seen
+            .push("74.125.0.1".parse().unwrap());
+        assert!(report.leaks().is_empty());
+
+        report.resolvers[0].interface = Some("vopono_s".to_string());
+        report
+            .namespace_resolvers_seen
+            .push("198.51.100.53".parse().unwrap());
+        assert_eq!(report.leaks().len(), 2);
+    }
+}
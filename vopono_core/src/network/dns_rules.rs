@@ -0,0 +1,427 @@
+// Cache, fixed addresses and conditional forwarding for the namespace stub resolver
+// Names given with --dns-override are answered directly. Queries for domains given with
+// --dns-forward go to their plain DNS server, and those for --split-dns domains to the resolver of
+// the host, outside the tunnel. Any other query goes to the upstreams. Answers are cached for
+// their TTL, so repeated lookups do not go through the tunnel again.
+
+use super::stub_resolver::{HEADER_LEN, TYPE_A, TYPE_AAAA, question_end, skip_name};
+use anyhow::{Context, anyhow};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+const TYPE_OPT: u16 = 41;
+const OVERRIDE_TTL: u32 = 60;
+/// TTL for negative answers without an SOA record
+const NEGATIVE_TTL: u32 = 60;
+const MAX_TTL: u32 = 24 * 60 * 60;
+const MAX_CACHE_ENTRIES: usize = 4096;
+
+fn normalize_name(name: &str) -> String {
+    name.trim_end_matches('.').to_lowercase()
+}
+
+/// Fixed address for a name, given as name=IP. A name can be given several times, e.g. for both
+/// an A and an AAAA record.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(try_from = "String", into = "String")]
+pub struct DnsOverride {
+    pub name: String,
+    pub address: IpAddr,
+}
+
+impl FromStr for DnsOverride {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, address) = s
+            .split_once('=')
+            .ok_or_else(|| anyhow!("Invalid DNS override, expected name=IP: {s}"))?;
+        let name = normalize_name(name.trim());
+        if name.is_empty() {
+            return Err(anyhow!("Empty name in DNS override: {s}"));
+        }
+        let address = address
+            .trim()
+            .parse()
+            .with_context(|| format!("Invalid address in DNS override: {s}"))?;
+        Ok(Self { name, address })
+    }
+}
+
+impl TryFrom<String> for DnsOverride {
+    type Error = anyhow::Error;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl Display for DnsOverride {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}={}", self.name, self.address)
+    }
+}
+
+impl From<DnsOverride> for String {
+    fn from(value: DnsOverride) -> Self {
+        value.to_string()
+    }
+}
+
+/// Where queries for a forwarded domain are sent
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ForwardTarget {
+    /// Plain DNS server, reached from the namespace
+    Server(SocketAddr),
+    /// Name servers of the host's resolv.conf, queried from the host network namespace
+    Host,
+}
+
+/// DNS server for a domain and its subdomains, given as domain=IP[:port] or domain=host
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(try_from = "String", into = "String")]
+pub struct DnsForward {
+    pub domain: String,
+    pub target: ForwardTarget,
+}
+
+impl DnsForward {
+    /// Forward to the host resolver, for --split-dns
+    pub fn host(domain: &str) -> anyhow::Result<Self> {
+        format!("{domain}=host").parse()
+    }
+}
+
+impl FromStr for DnsForward {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (domain, server) = s
+            .split_once('=')
+            .ok_or_else(|| anyhow!("Invalid DNS forward, expected domain=IP[:port]: {s}"))?;
+        let domain = normalize_name(domain.trim().trim_start_matches("*."));
+        if domain.is_empty() {
+            return Err(anyhow!("Empty domain in DNS forward: {s}"));
+        }
+        let server = server.trim();
+        if server == "host" {
+            return Ok(Self {
+                domain,
+                target: ForwardTarget::Host,
+            });
+        }
+        let server = server
+            .parse::<SocketAddr>()
+            .or_else(|_| {
+                server
+                    .trim_matches(|c| c == '[' || c == ']')
+                    .parse::<IpAddr>()
+                    .map(|ip| SocketAddr::new(ip, 53))
+            })
+            .map_err(|_| anyhow!("Invalid server in DNS forward: {s}"))?;
+        Ok(Self {
+            domain,
+            target: ForwardTarget::Server(server),
+        })
+    }
+}
+
+impl TryFrom<String> for DnsForward {
+    type Error = anyhow::Error;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl Display for DnsForward {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.target {
+            ForwardTarget::Server(server) => write!(f, "{}={}", self.domain, server),
+            ForwardTarget::Host => write!(f, "{}=host", self.domain),
+        }
+    }
+}
+
+impl From<DnsForward> for String {
+    fn from(value: DnsForward) -> Self {
+        value.to_string()
+    }
+}
+
+impl DnsForward {
+    fn matches(&self, name: &str) -> bool {
+        name == self.domain
+            || name
+                .strip_suffix(&self.domain)
+                .is_some_and(|x| x.ends_with('.'))
+    }
+}
+
+/// Target of the most specific forwarded domain matching the name
+pub fn forward_target(forwards: &[DnsForward], name: &str) -> Option<ForwardTarget> {
+    forwards
+        .iter()
+        .filter(|x| x.matches(name))
+        .max_by_key(|x| x.domain.len())
+        .map(|x| x.target)
+}
+
+/// Question of a query: lower case name and record type
+pub struct Question {
+    pub name: String,
+    pub record_type: u16,
+    end: usize,
+}
+
+impl Question {
+    pub fn parse(query: &[u8]) -> Option<Self> {
+        let end = question_end(query)?;
+        let mut labels = Vec::new();
+        let mut i = HEADER_LEN;
+        while query[i] != 0 {
+            let len = query[i] as usize;
+            labels.push(String::from_utf8_lossy(query.get(i + 1..i + 1 + len)?).to_lowercase());
+            i += 1 + len;
+        }
+        Some(Self {
+            name: labels.join("."),
+            record_type: u16::from_be_bytes([query[end - 4], query[end - 3]]),
+            end,
+        })
+    }
+}
+
+/// Answer for a name with fixed addresses, with no records if the type does not match
+pub fn override_response(
+    overrides: &[DnsOverride],
+    query: &[u8],
+    question: &Question,
+) -> Option<Vec<u8>> {
+    let addresses: Vec<IpAddr> = overrides
+        .iter()
+        .filter(|x| x.name == question.name)
+        .map(|x| x.address)
+        .collect();
+    if addresses.is_empty() {
+        return None;
+    }
+    let records: Vec<Vec<u8>> = addresses
+        .iter()
+        .filter_map(|address| match (address, question.record_type) {
+            (IpAddr::V4(ip), TYPE_A) => Some(ip.octets().to_vec()),
+            (IpAddr::V6(ip), TYPE_AAAA) => Some(ip.octets().to_vec()),
+            _ => None,
+        })
+        .collect();
+    let mut response = query[..2].to_vec();
+    // Response, keeping the opcode and recursion desired, authoritative
+    response.push(0x80 | 0x04 | (query[2] & 0x79));
+    // Recursion available, no error
+    response.push(0x80);
+    response.extend_from_slice(&[0, 1]);
+    response.extend_from_slice(&(records.len() as u16).to_be_bytes());
+    response.extend_from_slice(&[0, 0, 0, 0]);
+    response.extend_from_slice(&query[HEADER_LEN..question.end]);
+    for data in records {
+        // Pointer to the question name
+        response.extend_from_slice(&[0xC0, 0x0C]);
+        response.extend_from_slice(&question.record_type.to_be_bytes());
+        response.extend_from_slice(&[0, 1]);
+        response.extend_from_slice(&OVERRIDE_TTL.to_be_bytes());
+        response.extend_from_slice(&(data.len() as u16).to_be_bytes());
+        response.extend_from_slice(&data);
+    }
+    Some(response)
+}
+
+/// Offsets of the TTL of each record in a response, except for the EDNS OPT record
+fn ttl_offsets(response: &[u8]) -> Option<Vec<usize>> {
+    let count = |i: usize| u16::from_be_bytes([response[i], response[i + 1]]) as usize;
+    if response.len() < HEADER_LEN {
+        return None;
+    }
+    let mut i = HEADER_LEN;
+    for _ in 0..count(4) {
+        i = skip_name(response, i)? + 4;
+    }
+    let mut offsets = Vec::new();
+    for _ in 0..count(6) + count(8) + count(10) {
+        let end = skip_name(response, i)?;
+        let fixed = response.get(end..end + 10)?;
+        if u16::from_be_bytes([fixed[0], fixed[1]]) != TYPE_OPT {
+            offsets.push(end + 4);
+        }
+        i = end + 10 + u16::from_be_bytes([fixed[8], fixed[9]]) as usize;
+    }
+    (i <= response.len()).then_some(offsets)
+}
+
+fn read_ttl(response: &[u8], offset: usize) -> u32 {
+    u32::from_be_bytes(response[offset..offset + 4].try_into().unwrap())
+}
+
+struct CacheEntry {
+    response: Vec<u8>,
+    stored: Instant,
+    expires: Instant,
+}
+
+/// Responses by question (and whether the query used EDNS), kept for their lowest TTL
+#[derive(Default)]
+pub struct DnsCache {
+    entries: Mutex<HashMap<(Vec<u8>, bool), CacheEntry>>,
+}
+
+impl DnsCache {
+    fn key(query: &[u8], question: &Question) -> (Vec<u8>, bool) {
+        (
+            query[HEADER_LEN..question.end].to_ascii_lowercase(),
+            query[10..12] != [0, 0],
+        )
+    }
+
+    pub fn get(&self, query: &[u8], question: &Question) -> Option<Vec<u8>> {
+        let entries = self.entries.lock().ok()?;
+        let entry = entries.get(&Self::key(query, question))?;
+        let now = Instant::now();
+        if now >= entry.expires {
+            return None;
+        }
+        let mut response = entry.response.clone();
+        // Same ID and name case (which may be randomised) as the query
+        response[..2].copy_from_slice(&query[..2]);
+        response[HEADER_LEN..question.end].copy_from_slice(&query[HEADER_LEN..question.end]);
+        let elapsed = now.duration_since(entry.stored).as_secs() as u32;
+        for offset in ttl_offsets(&response)? {
+            let ttl = read_ttl(&response, offset).saturating_sub(elapsed);
+            response[offset..offset + 4].copy_from_slice(&ttl.to_be_bytes());
+        }
+        Some(response)
+    }
+
+    pub fn insert(&self, query: &[u8], question: &Question, response: &[u8]) {
+        // Only complete answers and NXDOMAIN, for the same question
+        let rcode = response.get(3).map(|x| x & 0x0F);
+        if !matches!(rcode, Some(0) | Some(3))
+            || response[2] & 0x02 != 0
+            || response
+                .get(HEADER_LEN..question.end)
+                .map(|x| x.to_ascii_lowercase())
+                != Some(query[HEADER_LEN..question.end].to_ascii_lowercase())
+        {
+            return;
+        }
+        let Some(offsets) = ttl_offsets(response) else {
+            return;
+        };
+        let ttl = offsets
+            .iter()
+            .map(|x| read_ttl(response, *x))
+            .min()
+            .unwrap_or(NEGATIVE_TTL)
+            .min(MAX_TTL);
+        if ttl == 0 {
+            return;
+        }
+        let Ok(mut entries) = self.entries.lock() else {
+            return;
+        };
+        let now = Instant::now();
+        if entries.len() >= MAX_CACHE_ENTRIES {
+            entries.retain(|_, x| x.expires > now);
+            if entries.len() >= MAX_CACHE_ENTRIES {
+                entries.clear();
+            }
+        }
+        entries.insert(
+            Self::key(query, question),
+            CacheEntry {
+                response: response.to_vec(),
+                stored: now,
+                expires: now + Duration::from_secs(ttl as u64),
+            },
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn query(name: &[u8], record_type: u8) -> Vec<u8> {
+        let mut query = vec![0x12, 0x34, 0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0];
+        query.extend_from_slice(name);
+        query.extend_from_slice(&[0, record_type, 0, 1]);
+        query
+    }
+
+    #[test]
+    fn answer_overrides_and_match_forwards() {
+        let overrides = vec!["nas.home=192.168.1.5".parse::<DnsOverride>().unwrap()];
+        let query = query(b"\x03NAS\x04home\x00", 1);
+        let question = Question::parse(&query).unwrap();
+        assert_eq!(question.name, "nas.home");
+        let response = override_response(&overrides, &query, &question).unwrap();
+        assert_eq!(response[..2], query[..2]);
+        assert_eq!(response[7], 1);
+        assert_eq!(response[response.len() - 4..], [192, 168, 1, 5]);
+
+        let forwards = vec![
+            "corp.example=10.0.0.1".parse::<DnsForward>().unwrap(),
+            "*.dev.corp.example=10.0.0.2:5353"
+                .parse::<DnsForward>()
+                .unwrap(),
+            DnsForward::host("local").unwrap(),
+        ];
+        assert_eq!(
+            forward_target(&forwards, "git.dev.corp.example"),
+            Some(ForwardTarget::Server("10.0.0.2:5353".parse().unwrap()))
+        );
+        assert_eq!(
+            forward_target(&forwards, "corp.example"),
+            Some(ForwardTarget::Server("10.0.0.1:53".parse().unwrap()))
+        );
+        assert_eq!(
+            forward_target(&forwards, "printer.local"),
+            Some(ForwardTarget::Host)
+        );
+        assert_eq!(forward_target(&forwards, "notcorp.example"), None);
+        assert_eq!(
+            forwards[2].to_string().parse::<DnsForward>().unwrap(),
+            forwards[2]
+        );
+    }
+
+    #[test]
+    fn cache_responses_for_their_ttl() {
+        let cache = DnsCache::default();
+        let query = query(b"\x07example\x03com\x00", 1);
+        let question = Question::parse(&query).unwrap();
+        let mut response = query.clone();
+        response[2] = 0x81;
+        response[3] = 0x80;
+        response[7] = 1;
+        response.extend_from_slice(&[0xC0, 0x0C, 0, 1, 0, 1, 0, 0, 0x0E, 0x10, 0, 4, 1, 2, 3, 4]);
+        cache.insert(&query, &question, &response);
+
+        let mut other_case = query.clone();
+        other_case[0] = 0x56;
+        other_case[HEADER_LEN + 1] = b'E';
+        let cached = cache.get(&other_case, &question).unwrap();
+        assert_eq!(cached[..2], [0x56, 0x34]);
+        assert_eq!(cached[HEADER_LEN + 1], b'E');
+        assert_eq!(cached[cached.len() - 4..], [1, 2, 3, 4]);
+        let other_name = self::query(b"\x07example\x03org\x00", 1);
+        assert!(
+            cache
+                .get(&other_name, &Question::parse(&other_name).unwrap())
+                .is_none()
+        );
+    }
+}
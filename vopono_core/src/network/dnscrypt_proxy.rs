@@ -0,0 +1,208 @@
+// dnscrypt-proxy in the network namespace
+// With --dnscrypt-proxy, vopono starts dnscrypt-proxy in the namespace once the VPN is up. It
+// listens on 127.0.0.1:53 and uses a config generated in a private directory under /run/vopono
+// (not /etc/netns/{namespace}, which ip netns exec would bind mount over /etc). The
+// provider's DNS servers act as its bootstrap resolvers, so the resolver list and server names
+// are fetched through the tunnel. Like the VPN clients it belongs to the namespace, and it is
+// stopped when the last vopono instance using the namespace exits.
+
+use super::netns::NetworkNamespace;
+use super::stub_resolver::STUB_ADDRESS;
+use crate::util::private_files::{private_temp_dir, write_private_file};
+use crate::util::run_in_netns;
+use anyhow::{Context, anyhow};
+use log::{debug, error, info};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::net::{IpAddr, SocketAddr, TcpStream};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+const PUBLIC_RESOLVERS: &[&str] = &[
+    "https://raw.githubusercontent.com/DNSCrypt/dnscrypt-resolvers/master/v3/public-resolvers.md",
+    "https://download.dnscrypt.info/resolvers-list/v3/public-resolvers.md",
+];
+const PUBLIC_RESOLVERS_KEY: &str = "RWQf6LRCGA9i53mlYecO4IzT51TGPpvWucNSCh1CBM0QTaLn73Y7GFO3";
+/// Fetching the resolver list and probing servers can take a while over a slow tunnel
+const STARTUP_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Serialize)]
+struct Source {
+    urls: &'static [&'static str],
+    cache_file: PathBuf,
+    minisign_key: &'static str,
+    refresh_delay: u32,
+}
+
+#[derive(Serialize)]
+struct Config<'a> {
+    listen_addresses: Vec<String>,
+    #[serde(skip_serializing_if = "<[_]>::is_empty")]
+    server_names: &'a [String],
+    ipv4_servers: bool,
+    ipv6_servers: bool,
+    require_nolog: bool,
+    require_nofilter: bool,
+    bootstrap_resolvers: Vec<String>,
+    ignore_system_dns: bool,
+    netprobe_address: String,
+    netprobe_timeout: u32,
+    cache: bool,
+    sources: BTreeMap<&'static str, Source>,
+}
+
+/// dnscrypt-proxy config for the namespace: server_names empty lets dnscrypt-proxy pick the
+/// fastest public no-log, non-filtering resolvers
+fn config(
+    server_names: &[String],
+    bootstrap: &[IpAddr],
+    ipv6: bool,
+    dir: &Path,
+) -> anyhow::Result<String> {
+    let bootstrap: Vec<String> = bootstrap
+        .iter()
+        .map(|ip| SocketAddr::new(*ip, 53).to_string())
+        .collect();
+    let netprobe_address = bootstrap
+        .first()
+        .cloned()
+        .unwrap_or_else(|| "9.9.9.9:53".to_string());
+    let config = Config {
+        listen_addresses: vec![SocketAddr::new(STUB_ADDRESS, 53).to_string()],
+        server_names,
+        ipv4_servers: true,
+        ipv6_servers: ipv6,
+        require_nolog: true,
+        require_nofilter: true,
+        bootstrap_resolvers: bootstrap,
+        ignore_system_dns: true,
+        netprobe_address,
+        netprobe_timeout: STARTUP_TIMEOUT.as_secs() as u32,
+        cache: true,
+        sources: BTreeMap::from([(
+            "public-resolvers",
+            Source {
+                urls: PUBLIC_RESOLVERS,
+                cache_file: dir.join("public-resolvers.md"),
+                minisign_key: PUBLIC_RESOLVERS_KEY,
+                refresh_delay: 72,
+            },
+        )]),
+    };
+    Ok(toml::to_string(&config)?)
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct DnscryptProxy {
+    pid: u32,
+    config_dir: PathBuf,
+}
+
+impl DnscryptProxy {
+    pub fn run(
+        netns: &NetworkNamespace,
+        server_names: &[String],
+        bootstrap: &[IpAddr],
+    ) -> anyhow::Result<Self> {
+        which::which("dnscrypt-proxy")
+            .context("Cannot find dnscrypt-proxy, is it installed and on PATH?")?;
+        let config_dir = private_temp_dir(&format!("{}_dnscrypt", netns.name))?;
+        let config_path = config_dir.join("dnscrypt-proxy.toml");
+        let ipv6 = netns
+            .veth_pair_ips
+            .as_ref()
+            .is_some_and(|x| x.namespace_ipv6.is_some());
+        write_private_file(
+            &config_path,
+            config(server_names, bootstrap, ipv6, &config_dir)?.as_bytes(),
+        )?;
+
+        info!("Launching dnscrypt-proxy");
+        let config_str = config_path.to_string_lossy();
+        let mut handle = NetworkNamespace::exec_no_block(
+            &netns.name,
+            &["dnscrypt-proxy", "-config", &config_str],
+            None,
+            None,
+            true,
+            false,
+            false,
+            None,
+        )
+        .context("Failed to launch dnscrypt-proxy")?;
+        // Killed on drop if it does not come up
+        let proxy = Self {
+            pid: handle.id(),
+            config_dir,
+        };
+
+        // It only listens once it has found a working server
+        let start = std::time::Instant::now();
+        loop {
+            if let Some(status) = handle.try_wait()? {
+                return Err(anyhow!("dnscrypt-proxy exited during startup: {status}"));
+            }
+            let listening = run_in_netns(&netns.name, || {
+                Ok(TcpStream::connect_timeout(
+                    &SocketAddr::new(STUB_ADDRESS, 53),
+                    Duration::from_secs(1),
+                )
+                .is_ok())
+            })?;
+            if listening {
+                break;
+            }
+            if start.elapsed() > STARTUP_TIMEOUT {
+                return Err(anyhow!(
+                    "dnscrypt-proxy did not start listening within {}s",
+                    STARTUP_TIMEOUT.as_secs()
+                ));
+            }
+            std::thread::sleep(Duration::from_millis(500));
+        }
+        debug!("dnscrypt-proxy listening (pid: {})", proxy.pid);
+        Ok(proxy)
+    }
+}
+
+impl Drop for DnscryptProxy {
+    fn drop(&mut self) {
+        match nix::sys::signal::kill(
+            nix::unistd::Pid::from_raw(self.pid as i32),
+            nix::sys::signal::Signal::SIGKILL,
+        ) {
+            Ok(_) => debug!("Killed dnscrypt-proxy (pid: {})", self.pid),
+            Err(e) => error!("Failed to kill dnscrypt-proxy (pid: {}): {:?}", self.pid, e),
+        }
+        std::fs::remove_dir_all(&self.config_dir).ok();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn generate_config() {
+        let config = config(
+            &["quad9-dnscrypt-ip4-nofilter-pri".to_string()],
+            &["10.64.0.1".parse().unwrap()],
+            false,
+            Path::new("/run/vopono/test_dnscrypt_0"),
+        )
+        .unwrap();
+        let parsed: toml::Table = config.parse().unwrap();
+        assert_eq!(
+            parsed["listen_addresses"].as_array().unwrap()[0].as_str(),
+            Some("127.0.0.1:53")
+        );
+        assert_eq!(
+            parsed["bootstrap_resolvers"].as_array().unwrap()[0].as_str(),
+            Some("10.64.0.1:53")
+        );
+        assert_eq!(
+            parsed["sources"]["public-resolvers"]["cache_file"].as_str(),
+            Some("/run/vopono/test_dnscrypt_0/public-resolvers.md")
+        );
+    }
+}
@@ -0,0 +1,200 @@
+// Outbound port allowlist for applications in the network namespace
+// With --allow-outbound, new connections from applications may only go to the given ports and
+// protocols, on any interface but loopback. Like the veth killswitch, root-owned sockets (the VPN
+// client) and kernel traffic are not restricted, and replies on established connections (e.g. to
+// published ports) are always allowed.
+
+use super::firewall::Firewall;
+use super::netns::NetworkNamespace;
+use super::port_publish::PublishProtocol;
+use anyhow::{Context, anyhow};
+use log::debug;
+use serde::{Deserialize, Serialize};
+use std::fmt::Display;
+use std::str::FromStr;
+
+/// iptables chain (and nftables table) for the outbound allowlist
+const EGRESS_CHAIN: &str = "vopono_egress";
+
+/// Allowed destination port or port range, given as port[-port][/tcp|/udp], both protocols if
+/// none is given
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(try_from = "String")]
+pub struct EgressRule {
+    pub first_port: u16,
+    pub last_port: u16,
+    pub protocol: Option<PublishProtocol>,
+}
+
+impl EgressRule {
+    fn protocols(&self) -> Vec<PublishProtocol> {
+        match self.protocol {
+            Some(protocol) => vec![protocol],
+            None => vec![PublishProtocol::Tcp, PublishProtocol::Udp],
+        }
+    }
+
+    /// Port or range as iptables (first:last) or nftables (first-last) expect it
+    fn ports(&self, separator: &str) -> String {
+        if self.first_port == self.last_port {
+            self.first_port.to_string()
+        } else {
+            format!("{}{separator}{}", self.first_port, self.last_port)
+        }
+    }
+}
+
+impl FromStr for EgressRule {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (ports, protocol) = match s.rsplit_once('/') {
+            Some((ports, "tcp")) => (ports, Some(PublishProtocol::Tcp)),
+            Some((ports, "udp")) => (ports, Some(PublishProtocol::Udp)),
+            Some((_, p)) => return Err(anyhow!("Invalid protocol {p} in outbound rule {s}")),
+            None => (s, None),
+        };
+        let (first, last) = ports.split_once('-').unwrap_or((ports, ports));
+        let first_port: u16 = first
+            .parse()
+            .with_context(|| format!("Invalid port in outbound rule {s}"))?;
+        let last_port: u16 = last
+            .parse()
+            .with_context(|| format!("Invalid port in outbound rule {s}"))?;
+        if first_port > last_port {
+            return Err(anyhow!("Invalid port range in outbound rule {s}"));
+        }
+        Ok(Self {
+            first_port,
+            last_port,
+            protocol,
+        })
+    }
+}
+
+impl TryFrom<String> for EgressRule {
+    type Error = anyhow::Error;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl Display for EgressRule {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.ports("-"))?;
+        if let Some(protocol) = self.protocol {
+            write!(f, "/{protocol}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Reject new outbound connections from applications except to the allowed ports. The rules
+/// belong to the namespace and go away with it.
+pub fn egress_allowlist(
+    netns: &NetworkNamespace,
+    rules: &[EgressRule],
+    firewall: Firewall,
+) -> anyhow::Result<()> {
+    debug!(
+        "Restricting outbound traffic in {} to {}",
+        netns.name,
+        rules
+            .iter()
+            .map(|x| x.to_string())
+            .collect::<Vec<_>>()
+            .join(", ")
+    );
+    match firewall {
+        Firewall::IpTables => {
+            for ipcmd in ["iptables", "ip6tables"] {
+                let append = |args: &[&str]| -> anyhow::Result<()> {
+                    let mut command = vec![ipcmd, "-A", EGRESS_CHAIN];
+                    command.extend(args);
+                    NetworkNamespace::exec(&netns.name, &command)
+                };
+                NetworkNamespace::exec(&netns.name, &[ipcmd, "-N", EGRESS_CHAIN])?;
+                append(&["-o", "lo", "-j", "RETURN"])?;
+                append(&[
+                    "-m",
+                    "conntrack",
+                    "--ctstate",
+                    "ESTABLISHED,RELATED",
+                    "-j",
+                    "RETURN",
+                ])?;
+                append(&["-m", "owner", "!", "--socket-exists", "-j", "RETURN"])?;
+                append(&["-m", "owner", "--uid-owner", "0", "-j", "RETURN"])?;
+                for rule in rules {
+                    let ports = rule.ports(":");
+                    for protocol in rule.protocols() {
+                        let protocol = protocol.to_string();
+                        append(&["-p", &protocol, "--dport", &ports, "-j", "RETURN"])?;
+                    }
+                }
+                append(&["-j", "REJECT"])?;
+                NetworkNamespace::exec(
+                    &netns.name,
+                    &[ipcmd, "-I", "OUTPUT", "1", "-j", EGRESS_CHAIN],
+                )?;
+            }
+        }
+        Firewall::NfTables => {
+            let add = |args: &[&str]| -> anyhow::Result<()> {
+                let mut command = vec!["nft", "add"];
+                command.extend(args);
+                NetworkNamespace::exec(&netns.name, &command)
+            };
+            let rule = |args: &[&str]| -> anyhow::Result<()> {
+                let mut command = vec!["rule", "inet", EGRESS_CHAIN, "output"];
+                command.extend(args);
+                add(&command)
+            };
+            add(&["table", "inet", EGRESS_CHAIN])?;
+            add(&[
+                "chain",
+                "inet",
+                EGRESS_CHAIN,
+                "output",
+                "{ type filter hook output priority -500 ; policy accept; }",
+            ])?;
+            rule(&["oifname", "lo", "return"])?;
+            rule(&["ct", "state", "established,related", "return"])?;
+            for egress in rules {
+                let ports = egress.ports("-");
+                for protocol in egress.protocols() {
+                    let protocol = protocol.to_string();
+                    rule(&[&protocol, "dport", &ports, "return"])?;
+                }
+            }
+            // Packets without a socket do not match skuid
+            rule(&["meta", "skuid", "!=", "0", "counter", "reject"])?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_egress_rules() {
+        let rule: EgressRule = "443/tcp".parse().unwrap();
+        assert_eq!(
+            rule,
+            EgressRule {
+                first_port: 443,
+                last_port: 443,
+                protocol: Some(PublishProtocol::Tcp),
+            }
+        );
+        let rule: EgressRule = "6881-6889".parse().unwrap();
+        assert_eq!(rule.protocols().len(), 2);
+        assert_eq!(rule.ports(":"), "6881:6889");
+        assert_eq!(rule.to_string(), "6881-6889");
+        assert!("443/icmp".parse::<EgressRule>().is_err());
+        assert!("6889-6881".parse::<EgressRule>().is_err());
+    }
+}
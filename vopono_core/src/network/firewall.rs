@@ -1,6 +1,17 @@
 use super::netns::NetworkNamespace;
+use crate::config::vpn::Protocol;
+use anyhow::anyhow;
+use ipnet::IpNet;
+use log::{debug, warn};
 use serde::{Deserialize, Serialize};
+use std::process::Command;
 use strum_macros::{Display, EnumIter};
+use which::which;
+
+/// iptables chain (and nftables table) for the veth killswitch in the namespace
+const VETH_KILLSWITCH_CHAIN: &str = "vopono_killswitch";
+/// iptables chain (and nftables table) for inbound connections via the veth
+const INBOUND_KILLSWITCH_CHAIN: &str = "vopono_inbound";
 
 #[derive(Debug, PartialEq, Eq, Serialize, Deserialize, Clone, Copy, Display, EnumIter)]
 pub enum Firewall {
@@ -8,6 +19,480 @@ pub enum Firewall {
     NfTables,
 }
 
+impl Firewall {
+    /// Detect the firewall backend used on the host. nftables is preferred, unless iptables is
+    /// the legacy (x_tables) backend with rules already loaded (e.g. from ufw or Docker), in which
+    /// case vopono's rules must be added alongside them with iptables.
+    pub fn detect() -> anyhow::Result<Self> {
+        let firewall = match (which("nft").is_ok(), which("iptables").is_ok()) {
+            (true, false) => Self::NfTables,
+            (false, true) => Self::IpTables,
+            (false, false) => return Err(anyhow!("Neither nftables nor iptables is installed!")),
+            (true, true) => {
+                let version = Command::new("iptables")
+                    .arg("-V")
+                    .output()
+                    .map(|x| String::from_utf8_lossy(&x.stdout).to_string())
+                    .unwrap_or_default();
+                if iptables_is_legacy(&version) && legacy_rules_loaded() {
+                    debug!("iptables legacy backend has rules loaded, using iptables");
+                    Self::IpTables
+                } else {
+                    Self::NfTables
+                }
+            }
+        };
+        debug!("Detected firewall: {firewall}");
+        Ok(firewall)
+    }
+
+    /// Check that the commands needed for this backend are installed, so a firewall forced in the
+    /// arguments or config file fails early instead of when adding the killswitch
+    pub fn check_available(&self) -> anyhow::Result<()> {
+        let command = match self {
+            Self::IpTables => "iptables",
+            Self::NfTables => "nft",
+        };
+        which(command).map_err(|_| {
+            anyhow!(
+                "Firewall {self} was chosen but {command} is not installed, install it or choose another firewall with --firewall"
+            )
+        })?;
+        Ok(())
+    }
+}
+
+/// iptables and ip6tables with whether they are for IPv6, skipping ip6tables if it is not
+/// installed
+fn iptables_commands() -> Vec<(&'static str, bool)> {
+    let mut commands = vec![("iptables", false)];
+    if which("ip6tables").is_ok() {
+        commands.push(("ip6tables", true));
+    } else {
+        warn!("ip6tables not found, not adding IPv6 killswitch rules");
+    }
+    commands
+}
+
+/// Default-deny for traffic from applications leaving the namespace via its veth interface,
+/// installed before the VPN is started. Only traffic from root-owned sockets (the VPN client
+/// itself) and kernel traffic (e.g. Wireguard) may use the veth, except to the allowed hosts
+/// (the host side of the veth pair, open hosts and bridge peers), so nothing can escape to the
+/// host's default route if the VPN process dies or fails to start. These rules only ever restrict
+/// traffic, the protocol killswitch decides what is allowed through.
+pub fn veth_killswitch(
+    netns: &NetworkNamespace,
+    veth: &str,
+    allowed: &[IpNet],
+    firewall: Firewall,
+) -> anyhow::Result<()> {
+    debug!("Setting veth killswitch on {veth}....");
+    match firewall {
+        Firewall::IpTables => {
+            for (ipcmd, ipv6) in iptables_commands() {
+                NetworkNamespace::exec(&netns.name, &[ipcmd, "-N", VETH_KILLSWITCH_CHAIN])?;
+                for host in allowed.iter().filter(|x| matches!(x, IpNet::V6(_)) == ipv6) {
+                    NetworkNamespace::exec(
+                        &netns.name,
+                        &[
+                            ipcmd,
+                            "-A",
+                            VETH_KILLSWITCH_CHAIN,
+                            "-d",
+                            &host.to_string(),
+                            "-j",
+                            "RETURN",
+                        ],
+                    )?;
+                }
+                NetworkNamespace::exec(
+                    &netns.name,
+                    &[
+                        ipcmd,
+                        "-A",
+                        VETH_KILLSWITCH_CHAIN,
+                        "-m",
+                        "owner",
+                        "--socket-exists",
+                        "!",
+                        "--uid-owner",
+                        "0",
+                        "-j",
+                        "REJECT",
+                    ],
+                )?;
+                NetworkNamespace::exec(
+                    &netns.name,
+                    &[
+                        ipcmd,
+                        "-I",
+                        "OUTPUT",
+                        "1",
+                        "-o",
+                        veth,
+                        "-j",
+                        VETH_KILLSWITCH_CHAIN,
+                    ],
+                )?;
+            }
+        }
+        Firewall::NfTables => {
+            NetworkNamespace::exec(
+                &netns.name,
+                &["nft", "add", "table", "inet", VETH_KILLSWITCH_CHAIN],
+            )?;
+            NetworkNamespace::exec(
+                &netns.name,
+                &[
+                    "nft",
+                    "add",
+                    "chain",
+                    "inet",
+                    VETH_KILLSWITCH_CHAIN,
+                    "output",
+                    "{ type filter hook output priority -600 ; policy accept; }",
+                ],
+            )?;
+            for host in allowed {
+                let family = match host {
+                    IpNet::V4(_) => "ip",
+                    IpNet::V6(_) => "ip6",
+                };
+                NetworkNamespace::exec(
+                    &netns.name,
+                    &[
+                        "nft",
+                        "add",
+                        "rule",
+                        "inet",
+                        VETH_KILLSWITCH_CHAIN,
+                        "output",
+                        "oifname",
+                        veth,
+                        family,
+                        "daddr",
+                        &host.to_string(),
+                        "return",
+                    ],
+                )?;
+            }
+            // Packets without a socket do not match skuid, so kernel traffic is not rejected
+            NetworkNamespace::exec(
+                &netns.name,
+                &[
+                    "nft",
+                    "add",
+                    "rule",
+                    "inet",
+                    VETH_KILLSWITCH_CHAIN,
+                    "output",
+                    "oifname",
+                    veth,
+                    "meta",
+                    "skuid",
+                    "!=",
+                    "0",
+                    "counter",
+                    "reject",
+                ],
+            )?;
+        }
+    }
+    Ok(())
+}
+
+/// Rules of the iptables inbound killswitch chain for one address family
+fn inbound_iptables_rules(ipv6: bool, allowed: &[IpNet]) -> Vec<Vec<String>> {
+    let icmp = if ipv6 { "ipv6-icmp" } else { "icmp" };
+    let mut rules: Vec<Vec<&str>> = vec![
+        vec![
+            "-m",
+            "conntrack",
+            "--ctstate",
+            "RELATED,ESTABLISHED",
+            "-j",
+            "RETURN",
+        ],
+        // Neighbour discovery and path MTU discovery
+        vec!["-p", icmp, "-j", "RETURN"],
+    ];
+    let sources: Vec<String> = allowed
+        .iter()
+        .filter(|x| matches!(x, IpNet::V6(_)) == ipv6)
+        .map(|x| x.to_string())
+        .collect();
+    rules.extend(sources.iter().map(|x| vec!["-s", x, "-j", "RETURN"]));
+    rules.push(vec!["-j", "DROP"]);
+    rules
+        .into_iter()
+        .map(|rule| rule.into_iter().map(String::from).collect())
+        .collect()
+}
+
+/// Drop new connections arriving in the namespace via its veth interface, except to the ports
+/// opened with open_ports (forwarded and published ports, relays) and from the allowed sources
+/// (the bridge subnet with --bridge-peers). Inbound connections through the tunnel are left to
+/// the protocol killswitch.
+pub fn inbound_killswitch(
+    netns: &NetworkNamespace,
+    veth: &str,
+    allowed: &[IpNet],
+    firewall: Firewall,
+) -> anyhow::Result<()> {
+    debug!("Setting inbound killswitch on {veth}....");
+    match firewall {
+        Firewall::IpTables => {
+            for (ipcmd, ipv6) in iptables_commands() {
+                NetworkNamespace::exec(&netns.name, &[ipcmd, "-N", INBOUND_KILLSWITCH_CHAIN])?;
+                for rule in inbound_iptables_rules(ipv6, allowed) {
+                    let mut command = vec![ipcmd, "-A", INBOUND_KILLSWITCH_CHAIN];
+                    command.extend(rule.iter().map(String::as_str));
+                    NetworkNamespace::exec(&netns.name, &command)?;
+                }
+                // Opened ports are inserted at the top of INPUT, in front of this
+                NetworkNamespace::exec(
+                    &netns.name,
+                    &[
+                        ipcmd,
+                        "-I",
+                        "INPUT",
+                        "1",
+                        "-i",
+                        veth,
+                        "-j",
+                        INBOUND_KILLSWITCH_CHAIN,
+                    ],
+                )?;
+            }
+        }
+        Firewall::NfTables => {
+            let add = |args: &[&str]| -> anyhow::Result<()> {
+                let mut command = vec!["nft", "add"];
+                command.extend(args);
+                NetworkNamespace::exec(&netns.name, &command)
+            };
+            let rule = |args: &[&str]| -> anyhow::Result<()> {
+                let mut command = vec!["rule", "inet", INBOUND_KILLSWITCH_CHAIN, "input"];
+                command.extend(args);
+                add(&command)
+            };
+            add(&["table", "inet", INBOUND_KILLSWITCH_CHAIN])?;
+            // An accept in another table does not override a drop here, so opened ports are
+            // added to these sets too
+            for protocol in ["tcp", "udp"] {
+                add(&[
+                    "set",
+                    "inet",
+                    INBOUND_KILLSWITCH_CHAIN,
+                    &format!("{protocol}_ports"),
+                    "{ type inet_service ; }",
+                ])?;
+            }
+            add(&[
+                "chain",
+                "inet",
+                INBOUND_KILLSWITCH_CHAIN,
+                "input",
+                "{ type filter hook input priority -600 ; policy accept; }",
+            ])?;
+            rule(&["iifname", "!=", veth, "return"])?;
+            rule(&["ct", "state", "related,established", "return"])?;
+            rule(&["meta", "l4proto", "{ icmp, ipv6-icmp }", "return"])?;
+            for source in allowed {
+                let family = match source {
+                    IpNet::V4(_) => "ip",
+                    IpNet::V6(_) => "ip6",
+                };
+                rule(&[family, "saddr", &source.to_string(), "return"])?;
+            }
+            rule(&["tcp", "dport", "@tcp_ports", "return"])?;
+            rule(&["udp", "dport", "@udp_ports", "return"])?;
+            rule(&["counter", "drop"])?;
+        }
+    }
+    Ok(())
+}
+
+/// Let an opened port through the nftables inbound killswitch, if the namespace has one
+pub fn allow_inbound_port(
+    netns: &NetworkNamespace,
+    port: u16,
+    protocol: &str,
+) -> anyhow::Result<()> {
+    let set = format!("{protocol}_ports");
+    let exists = NetworkNamespace::exec_with_output(
+        &netns.name,
+        &["nft", "list", "set", "inet", INBOUND_KILLSWITCH_CHAIN, &set],
+    )?
+    .status
+    .success();
+    if exists {
+        NetworkNamespace::exec(
+            &netns.name,
+            &[
+                "nft",
+                "add",
+                "element",
+                "inet",
+                INBOUND_KILLSWITCH_CHAIN,
+                &set,
+                &format!("{{ {port} }}"),
+            ],
+        )?;
+    }
+    Ok(())
+}
+
+/// Whether the output of iptables -V is for the legacy (x_tables) backend rather than the
+/// nf_tables compatibility layer
+fn iptables_is_legacy(version: &str) -> bool {
+    // Old versions without a backend in the version string are always legacy
+    !version.contains("nf_tables")
+}
+
+fn legacy_rules_loaded() -> bool {
+    let command = if which("iptables-legacy-save").is_ok() {
+        "iptables-legacy-save"
+    } else {
+        "iptables-save"
+    };
+    Command::new(command)
+        .output()
+        .map(|x| {
+            String::from_utf8_lossy(&x.stdout)
+                .lines()
+                .any(|line| line.starts_with("-A "))
+        })
+        .unwrap_or(false)
+}
+
+/// iptables-save output of the namespace for each of iptables and ip6tables, taken before a VPN
+/// client adds its killswitch so the rules it added can be removed when switching server
+pub fn iptables_snapshot(netns: &NetworkNamespace) -> Vec<(&'static str, String)> {
+    ["iptables", "ip6tables"]
+        .into_iter()
+        .filter(|ipcmd| which(ipcmd).is_ok())
+        .filter_map(|ipcmd| {
+            let output =
+                NetworkNamespace::exec_with_output(&netns.name, &[&format!("{ipcmd}-save")])
+                    .ok()?;
+            output
+                .status
+                .success()
+                .then(|| (ipcmd, String::from_utf8_lossy(&output.stdout).to_string()))
+        })
+        .collect()
+}
+
+/// Commands undoing the changes to iptables since the snapshot
+pub fn iptables_undo_since(
+    netns: &NetworkNamespace,
+    before: &[(&'static str, String)],
+) -> Vec<Vec<String>> {
+    let after = iptables_snapshot(netns);
+    before
+        .iter()
+        .filter_map(|(ipcmd, old)| {
+            let (_, new) = after.iter().find(|x| x.0 == *ipcmd)?;
+            Some(iptables_undo_commands(ipcmd, old, new))
+        })
+        .flatten()
+        .collect()
+}
+
+/// Chain policies, chains and rules of each table in iptables-save output
+#[derive(Default)]
+struct SavedTables {
+    policies: Vec<(String, String, String)>,
+    chains: Vec<(String, String)>,
+    rules: Vec<(String, String)>,
+}
+
+fn parse_iptables_save(save: &str) -> SavedTables {
+    let mut saved = SavedTables::default();
+    let mut table = String::new();
+    for line in save.lines() {
+        if let Some(name) = line.strip_prefix('*') {
+            table = name.trim().to_string();
+        } else if let Some(chain) = line.strip_prefix(':') {
+            let mut fields = chain.split_whitespace();
+            let (Some(name), Some(policy)) = (fields.next(), fields.next()) else {
+                continue;
+            };
+            if policy == "-" {
+                saved.chains.push((table.clone(), name.to_string()));
+            } else {
+                saved
+                    .policies
+                    .push((table.clone(), name.to_string(), policy.to_string()));
+            }
+        } else if let Some(rule) = line.strip_prefix("-A ") {
+            saved.rules.push((table.clone(), rule.to_string()));
+        }
+    }
+    saved
+}
+
+/// ipcmd commands undoing the changes from the before to the after iptables-save output: deleting
+/// the added rules and chains, and restoring the policies of the built-in chains
+pub fn iptables_undo_commands(ipcmd: &str, before: &str, after: &str) -> Vec<Vec<String>> {
+    let before = parse_iptables_save(before);
+    let after = parse_iptables_save(after);
+    let mut old_rules = before.rules.clone();
+    let mut commands = Vec::new();
+    // Latest first, so jumps to a new chain are gone before the chain
+    for (table, rule) in after.rules.iter().rev() {
+        if let Some(i) = old_rules.iter().position(|x| x.0 == *table && x.1 == *rule) {
+            old_rules.remove(i);
+            continue;
+        }
+        let Ok(args) = shell_words::split(rule) else {
+            warn!("Failed to parse iptables rule: {rule}");
+            continue;
+        };
+        let mut command = vec![
+            ipcmd.to_string(),
+            "-t".to_string(),
+            table.clone(),
+            "-D".into(),
+        ];
+        command.extend(args);
+        commands.push(command);
+    }
+    for (table, chain) in after.chains.iter().filter(|x| !before.chains.contains(x)) {
+        commands.push(
+            [ipcmd, "-t", table, "-X", chain]
+                .iter()
+                .map(|x| x.to_string())
+                .collect(),
+        );
+    }
+    for (table, chain, policy) in &before.policies {
+        if !after
+            .policies
+            .contains(&(table.clone(), chain.clone(), policy.clone()))
+        {
+            commands.push(
+                [ipcmd, "-t", table, "-P", chain, policy]
+                    .iter()
+                    .map(|x| x.to_string())
+                    .collect(),
+            );
+        }
+    }
+    commands
+}
+
+/// Whether the protocol adds the IPv6 drop rules itself with --disable-ipv6: Wireguard always
+/// does, OpenVPN along with its killswitch
+pub fn protocol_drops_ipv6(protocol: &Protocol, killswitch: bool) -> bool {
+    match protocol {
+        Protocol::Wireguard => true,
+        Protocol::OpenVpn => killswitch,
+        _ => false,
+    }
+}
+
 pub fn disable_ipv6(netns: &NetworkNamespace, firewall: Firewall) -> anyhow::Result<()> {
     match firewall {
         Firewall::IpTables => {
@@ -60,3 +545,60 @@ pub fn disable_ipv6(netns: &NetworkNamespace, firewall: Firewall) -> anyhow::Res
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn inbound_rules_per_family() {
+        let allowed: Vec<IpNet> = vec![
+            "10.201.0.0/24".parse().unwrap(),
+            "fd00::/64".parse().unwrap(),
+        ];
+        let rules = inbound_iptables_rules(false, &allowed);
+        assert_eq!(rules.len(), 4);
+        assert_eq!(rules[1], ["-p", "icmp", "-j", "RETURN"]);
+        assert_eq!(rules[2], ["-s", "10.201.0.0/24", "-j", "RETURN"]);
+        assert_eq!(rules[3], ["-j", "DROP"]);
+        let rules = inbound_iptables_rules(true, &allowed);
+        assert_eq!(rules[1], ["-p", "ipv6-icmp", "-j", "RETURN"]);
+        assert_eq!(rules[2], ["-s", "fd00::/64", "-j", "RETURN"]);
+        assert_eq!(inbound_iptables_rules(true, &[]).len(), 3);
+    }
+
+    #[test]
+    fn ipv6_drop_rules_by_protocol() {
+        assert!(protocol_drops_ipv6(&Protocol::Wireguard, false));
+        assert!(protocol_drops_ipv6(&Protocol::OpenVpn, true));
+        assert!(!protocol_drops_ipv6(&Protocol::OpenVpn, false));
+        assert!(!protocol_drops_ipv6(&Protocol::OpenConnect, true));
+    }
+
+    #[test]
+    fn iptables_backend_from_version() {
+        assert!(!iptables_is_legacy("iptables v1.8.10 (nf_tables)"));
+        assert!(iptables_is_legacy("iptables v1.8.10 (legacy)"));
+        assert!(iptables_is_legacy("iptables v1.6.1"));
+    }
+
+    #[test]
+    fn undo_protocol_killswitch() {
+        let before = "*filter\n:INPUT ACCEPT [0:0]\n:OUTPUT ACCEPT [0:0]\n:vopono_killswitch - [0:0]\n-A OUTPUT -j vopono_killswitch\nCOMMIT\n";
+        let after = "*filter\n:INPUT DROP [0:0]\n:OUTPUT DROP [0:0]\n:vopono_killswitch - [0:0]\n:vpn - [0:0]\n-A INPUT -i lo -j ACCEPT\n-A OUTPUT -j vopono_killswitch\n-A OUTPUT -j vpn\n-A vpn -m comment --comment \"vopono vpn\" -j ACCEPT\nCOMMIT\n";
+        let commands = iptables_undo_commands("iptables", before, after);
+        let commands: Vec<String> = commands.iter().map(|x| x.join(" ")).collect();
+        assert_eq!(
+            commands,
+            [
+                "iptables -t filter -D vpn -m comment --comment vopono vpn -j ACCEPT",
+                "iptables -t filter -D OUTPUT -j vpn",
+                "iptables -t filter -D INPUT -i lo -j ACCEPT",
+                "iptables -t filter -X vpn",
+                "iptables -t filter -P INPUT ACCEPT",
+                "iptables -t filter -P OUTPUT ACCEPT",
+            ]
+        );
+        assert!(iptables_undo_commands("iptables", before, before).is_empty());
+    }
+}
@@ -2,12 +2,15 @@ use super::firewall::Firewall;
 use super::network_interface::NetworkInterface;
 use crate::util::sudo_command;
 use anyhow::Context;
-use log::debug;
+use log::{debug, warn};
 use serde::{Deserialize, Serialize};
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct HostMasquerade {
     ip_mask: String,
+    /// Namespace IPv6 subnet, if IPv6 is enabled on the veth pair
+    #[serde(default)]
+    ipv6_mask: Option<String>,
     interface: NetworkInterface,
     firewall: Firewall,
 }
@@ -16,6 +19,7 @@ impl HostMasquerade {
     /// Add masquerade rule to route traffic from network namespace to active network interface
     pub fn add_masquerade_rule(
         ip_mask: String,
+        ipv6_mask: Option<String>,
         interface: NetworkInterface,
         firewall: Firewall,
     ) -> anyhow::Result<Self> {
@@ -40,6 +44,27 @@ impl HostMasquerade {
                         &ip_mask, &interface.name
                     )
                 })?;
+                if let Some(ipv6_mask) = ipv6_mask.as_ref() {
+                    sudo_command(&[
+                        "ip6tables",
+                        "-t",
+                        "nat",
+                        "-A",
+                        "POSTROUTING",
+                        "-s",
+                        ipv6_mask,
+                        "-o",
+                        &interface.name,
+                        "-j",
+                        "MASQUERADE",
+                    ])
+                    .with_context(|| {
+                        format!(
+                            "Failed to add ip6tables masquerade rule, ip_mask: {}, interface: {}",
+                            ipv6_mask, &interface.name
+                        )
+                    })?;
+                }
             }
             Firewall::NfTables => {
                 sudo_command(&["nft", "add", "table", "inet", "vopono_nat"])
@@ -69,10 +94,34 @@ impl HostMasquerade {
                         &ip_mask, &interface.name
                     )
                 })?;
+                if let Some(ipv6_mask) = ipv6_mask.as_ref() {
+                    sudo_command(&[
+                        "nft",
+                        "add",
+                        "rule",
+                        "inet",
+                        "vopono_nat",
+                        "postrouting",
+                        "oifname",
+                        &interface.name,
+                        "ip6",
+                        "saddr",
+                        ipv6_mask,
+                        "counter",
+                        "masquerade",
+                    ])
+                    .with_context(|| {
+                        format!(
+                            "Failed to add nftables IPv6 masquerade rule, ip_mask: {}, interface: {}",
+                            ipv6_mask, &interface.name
+                        )
+                    })?;
+                }
             }
         }
         Ok(HostMasquerade {
             ip_mask,
+            ipv6_mask,
             interface,
             firewall,
         })
@@ -106,6 +155,26 @@ impl Drop for HostMasquerade {
                             &self.ip_mask, &self.interface.name
                         )
                     });
+                    if let Some(ipv6_mask) = self.ipv6_mask.as_ref() {
+                        if let Err(e) = sudo_command(&[
+                            "ip6tables",
+                            "-t",
+                            "nat",
+                            "-D",
+                            "POSTROUTING",
+                            "-s",
+                            ipv6_mask,
+                            "-o",
+                            &self.interface.name,
+                            "-j",
+                            "MASQUERADE",
+                        ]) {
+                            warn!(
+                                "Failed to delete ip6tables masquerade rule, ip_mask: {}, interface: {}: {:?}",
+                                ipv6_mask, &self.interface.name, e
+                            );
+                        }
+                    }
                 }
                 Firewall::NfTables => {
                     sudo_command(&["nft", "delete", "table", "inet", "vopono_nat"]).unwrap_or_else(
@@ -127,6 +196,9 @@ pub struct FirewallException {
     host_interface: NetworkInterface,
     ns_interface: NetworkInterface,
     firewall: Firewall,
+    /// Also add ip6tables exceptions (nftables rules are in an inet table so cover both)
+    #[serde(default)]
+    ipv6: bool,
 }
 
 impl FirewallException {
@@ -136,9 +208,26 @@ impl FirewallException {
         ns_interface: NetworkInterface,
         host_interface: NetworkInterface,
         firewall: Firewall,
+        ipv6: bool,
     ) -> anyhow::Result<Self> {
         match firewall {
             Firewall::IpTables => {
+                if ipv6 {
+                    for (input, output) in [
+                        (&host_interface.name, &ns_interface.name),
+                        (&ns_interface.name, &host_interface.name),
+                    ] {
+                        sudo_command(&[
+                            "ip6tables", "-I", "FORWARD", "-i", input, "-o", output, "-j",
+                            "ACCEPT",
+                        ])
+                        .with_context(|| {
+                            format!(
+                                "Failed to add ip6tables forward exception, input interface: {input}, output interface: {output}"
+                            )
+                        })?;
+                    }
+                }
                 sudo_command(&[
                     "iptables",
                     "-I",
@@ -231,6 +320,7 @@ impl FirewallException {
             host_interface,
             ns_interface,
             firewall,
+            ipv6,
         })
     }
 }
@@ -243,6 +333,28 @@ impl Drop for FirewallException {
         if namespaces.is_ok() && namespaces.unwrap().is_empty() {
             match self.firewall {
                 Firewall::IpTables => {
+                    if self.ipv6 {
+                        for (input, output) in [
+                            (&self.host_interface.name, &self.ns_interface.name),
+                            (&self.ns_interface.name, &self.host_interface.name),
+                        ] {
+                            if let Err(e) = sudo_command(&[
+                                "ip6tables",
+                                "-D",
+                                "FORWARD",
+                                "-i",
+                                input,
+                                "-o",
+                                output,
+                                "-j",
+                                "ACCEPT",
+                            ]) {
+                                warn!(
+                                    "Failed to delete ip6tables forward rule, input interface: {input}, output interface: {output}: {e:?}"
+                                );
+                            }
+                        }
+                    }
                     sudo_command(&[
                     "iptables",
                     "-D",
@@ -0,0 +1,485 @@
+// IKEv2/IPsec with strongSwan, for --protocol ikev2 with --custom
+// The custom config is a TOML profile with the server and EAP credentials of the provider's
+// IKEv2 offering. vopono runs its own charon daemon in the namespace, configured through a
+// strongswan.conf of its own (STRONGSWAN_CONF) with the VICI socket in the config directory, and
+// loads and initiates the connection with swanctl over that socket. The SAs are bound to an XFRM
+// interface (ipsec0, if_id 42) carrying the virtual IP, so the tunnel is route based and treated
+// like the other tunnel interfaces: the default route goes through ipsec0, the server is given a
+// route through the veth, and the killswitch only allows IKE and ESP to the server outside it.
+// charon keeps its pid file in the compiled in directory, so strongSwan must not be running on
+// the host at the same time.
+
+use super::firewall::Firewall;
+use super::netns::NetworkNamespace;
+use crate::config::providers::{Password, UiClient};
+use crate::util::vopono_dir;
+use anyhow::{Context, anyhow};
+use log::{debug, error, info, warn};
+use serde::{Deserialize, Serialize};
+use std::net::{IpAddr, ToSocketAddrs};
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+pub const IKEV2_INTERFACE: &str = "ipsec0";
+const IF_ID: &str = "42";
+const CONNECTION: &str = "vopono";
+const CHARON_PATHS: [&str; 4] = [
+    "/usr/lib/ipsec/charon",
+    "/usr/libexec/ipsec/charon",
+    "/usr/lib/strongswan/charon",
+    "/usr/libexec/strongswan/charon",
+];
+const SYSTEM_CA_DIR: &str = "/etc/ssl/certs";
+
+/// IKEv2 profile given as the custom config
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Ikev2Profile {
+    /// Host name or IP address of the server
+    pub server: String,
+    /// Identity in the server's certificate, the server by default
+    pub remote_id: Option<String>,
+    pub username: String,
+    /// Asked for if not given
+    pub password: Option<String>,
+    /// CA certificate of the server, the system's CAs are trusted by default
+    pub ca: Option<PathBuf>,
+    /// EAP method, eap-mschapv2 by default
+    pub eap: Option<String>,
+}
+
+impl Ikev2Profile {
+    pub fn from_file(path: &Path) -> anyhow::Result<Self> {
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("Reading IKEv2 profile: {}", path.display()))?;
+        toml::from_str(&content)
+            .with_context(|| format!("Invalid IKEv2 profile: {}", path.display()))
+    }
+}
+
+/// Quoted string in the strongSwan config format
+fn quote(s: &str) -> String {
+    format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+fn swanctl_conf(profile: &Ikev2Profile, server: IpAddr, password: &str) -> String {
+    let remote_id = profile.remote_id.as_deref().unwrap_or(&profile.server);
+    let eap = profile.eap.as_deref().unwrap_or("eap-mschapv2");
+    let cacerts = match profile.ca.as_ref() {
+        Some(ca) => format!("\n            cacerts = {}", quote(&ca.to_string_lossy())),
+        None => String::new(),
+    };
+    format!(
+        r#"connections {{
+    {CONNECTION} {{
+        version = 2
+        remote_addrs = {server}
+        vips = 0.0.0.0
+        if_id_in = {IF_ID}
+        if_id_out = {IF_ID}
+        local {{
+            auth = {eap}
+            eap_id = {username}
+        }}
+        remote {{
+            auth = pubkey
+            id = {remote_id}{cacerts}
+        }}
+        children {{
+            {CONNECTION} {{
+                remote_ts = 0.0.0.0/0
+                if_id_in = {IF_ID}
+                if_id_out = {IF_ID}
+            }}
+        }}
+    }}
+}}
+secrets {{
+    eap-{CONNECTION} {{
+        id = {username}
+        secret = {password}
+    }}
+}}
+"#,
+        username = quote(&profile.username),
+        remote_id = quote(remote_id),
+        password = quote(password),
+    )
+}
+
+fn strongswan_conf(socket: &str) -> String {
+    format!(
+        "charon {{\n    install_routes = no\n    install_virtual_ip_on = {IKEV2_INTERFACE}\n    \
+        filelog {{\n        stdout {{\n            default = 1\n        }}\n    }}\n    \
+        plugins {{\n        resolve {{\n            load = no\n        }}\n        \
+        vici {{\n            socket = {socket}\n        }}\n    }}\n}}\n"
+    )
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Ikev2 {
+    pid: u32,
+    /// Server reached outside the tunnel
+    pub server: IpAddr,
+    config_dir: PathBuf,
+    ns_name: String,
+    /// Of the killswitch, removed on drop
+    #[serde(default)]
+    firewall: Option<Firewall>,
+}
+
+impl Ikev2 {
+    #[allow(clippy::too_many_arguments)]
+    pub fn run(
+        netns: &NetworkNamespace,
+        config_file: &Path,
+        open_ports: Option<&Vec<u16>>,
+        forward_ports: Option<&Vec<u16>>,
+        firewall: Firewall,
+        verbose: bool,
+        uiclient: &dyn UiClient,
+    ) -> anyhow::Result<Self> {
+        let charon = CHARON_PATHS
+            .into_iter()
+            .find(|x| Path::new(x).exists())
+            .ok_or_else(|| anyhow!("Cannot find charon, is strongSwan installed?"))?;
+        which::which("swanctl")
+            .map_err(|e| anyhow!("Cannot find swanctl, is strongSwan installed?: {e:?}"))?;
+        let profile = Ikev2Profile::from_file(config_file)?;
+        let server = (profile.server.as_str(), 500)
+            .to_socket_addrs()
+            .with_context(|| format!("Failed to resolve IKEv2 server {}", profile.server))?
+            .map(|x| x.ip())
+            .find(|x| x.is_ipv4())
+            .ok_or_else(|| anyhow!("No IPv4 address for IKEv2 server {}", profile.server))?;
+        let password = match profile.password.clone() {
+            Some(password) => password,
+            None => uiclient
+                .get_password(Password {
+                    prompt: format!("IKEv2 password for {}", profile.username),
+                    confirm: false,
+                })?
+                .trim()
+                .to_string(),
+        };
+
+        let config_dir = vopono_dir()?.join(format!("logs/{}_ikev2", netns.name));
+        std::fs::create_dir_all(&config_dir)?;
+        std::fs::set_permissions(&config_dir, PermissionsExt::from_mode(0o700))?;
+        let socket_path = config_dir.join("charon.vici");
+        let socket = format!("unix://{}", socket_path.display());
+        let strongswan_file = config_dir.join("strongswan.conf");
+        std::fs::write(&strongswan_file, strongswan_conf(&socket))?;
+        std::fs::write(
+            config_dir.join("swanctl.conf"),
+            swanctl_conf(&profile, server, &password),
+        )?;
+        if profile.ca.is_none() && !config_dir.join("x509ca").exists() {
+            std::os::unix::fs::symlink(SYSTEM_CA_DIR, config_dir.join("x509ca"))?;
+        }
+
+        // Route based tunnel, the namespace side of the veth carries the IKE and ESP packets
+        let veth = netns
+            .veth_pair
+            .as_ref()
+            .map(|x| x.dest.as_str())
+            .ok_or_else(|| anyhow!("No veth pair in {}", netns.name))?;
+        NetworkNamespace::exec(
+            &netns.name,
+            &[
+                "ip",
+                "link",
+                "add",
+                IKEV2_INTERFACE,
+                "type",
+                "xfrm",
+                "dev",
+                veth,
+                "if_id",
+                IF_ID,
+            ],
+        )
+        .context("Failed to create the XFRM interface, is the kernel built with it?")?;
+        NetworkNamespace::exec(&netns.name, &["ip", "link", "set", IKEV2_INTERFACE, "up"])?;
+
+        info!("Launching charon...");
+        let conf_env = format!("STRONGSWAN_CONF={}", strongswan_file.display());
+        let handle = NetworkNamespace::exec_no_block(
+            &netns.name,
+            &["env", &conf_env, charon],
+            None,
+            None,
+            !verbose,
+            false,
+            false,
+            None,
+        )
+        .context("Failed to launch charon")?;
+        // charon is stopped on drop if we return early due to an error below
+        let ikev2 = Self {
+            pid: handle.id(),
+            server,
+            config_dir,
+            ns_name: netns.name.clone(),
+            firewall: Some(firewall),
+        };
+        let mut waited = Duration::ZERO;
+        while !socket_path.exists() {
+            if waited > Duration::from_secs(10) || !crate::util::check_process_running(ikev2.pid) {
+                return Err(anyhow!(
+                    "charon did not start, is strongSwan running on the host? (use -v for its log)"
+                ));
+            }
+            std::thread::sleep(Duration::from_millis(200));
+            waited += Duration::from_millis(200);
+        }
+
+        // Missing or unreadable CA files are reported by swanctl, the connection may not need them
+        if let Err(e) = ikev2.swanctl(&["--load-creds"]) {
+            warn!("Failed to load some IKEv2 credentials: {e:?}");
+        }
+        ikev2.swanctl(&["--load-conns"])?;
+        if let Some(veth_ips) = netns.veth_pair_ips.as_ref() {
+            NetworkNamespace::exec(
+                &netns.name,
+                &[
+                    "ip",
+                    "route",
+                    "add",
+                    &server.to_string(),
+                    "via",
+                    &veth_ips.host_ip.to_string(),
+                ],
+            )?;
+        }
+        info!(
+            "Connecting to IKEv2 server {} ({server})...",
+            profile.server
+        );
+        ikev2
+            .swanctl(&["--initiate", "--child", CONNECTION, "--timeout", "30"])
+            .context("Failed to connect to the IKEv2 server")?;
+        NetworkNamespace::exec(
+            &netns.name,
+            &["ip", "route", "replace", "default", "dev", IKEV2_INTERFACE],
+        )?;
+
+        killswitch(netns, server, firewall)?;
+        if let Some(opens) = open_ports {
+            crate::util::open_ports(netns, opens.as_slice(), firewall)?;
+        }
+        if let Some(forwards) = forward_ports {
+            crate::util::open_ports(netns, forwards.as_slice(), firewall)?;
+        }
+        info!("IKEv2 connected to {}", profile.server);
+        Ok(ikev2)
+    }
+
+    fn swanctl(&self, args: &[&str]) -> anyhow::Result<()> {
+        let dir_env = format!("SWANCTL_DIR={}", self.config_dir.display());
+        let uri = format!("unix://{}", self.config_dir.join("charon.vici").display());
+        let mut command = vec!["env", &dir_env, "swanctl", "--uri", &uri];
+        command.extend(args);
+        let output = NetworkNamespace::exec_with_output(&self.ns_name, &command)?;
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        debug!("swanctl {args:?}: {stdout}");
+        if !output.status.success() {
+            let last = stdout.lines().rev().find(|x| !x.trim().is_empty());
+            return Err(anyhow!(
+                "swanctl {} failed: {}",
+                args[0],
+                last.unwrap_or_default()
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// iptables or ip6tables killswitch rules, as chain and match, in the order they are appended
+fn iptables_rules(ipv6: bool, server: &str) -> Vec<Vec<&str>> {
+    let mut rules = vec![vec![
+        "INPUT",
+        "-m",
+        "conntrack",
+        "--ctstate",
+        "RELATED,ESTABLISHED",
+        "-j",
+        "ACCEPT",
+    ]];
+    for (chain, direction) in [("INPUT", "-i"), ("OUTPUT", "-o")] {
+        rules.push(vec![chain, direction, "lo", "-j", "ACCEPT"]);
+        rules.push(vec![chain, direction, IKEV2_INTERFACE, "-j", "ACCEPT"]);
+    }
+    if !ipv6 {
+        rules.push(vec![
+            "OUTPUT",
+            "-d",
+            server,
+            "-p",
+            "udp",
+            "-m",
+            "multiport",
+            "--dports",
+            "500,4500",
+            "-j",
+            "ACCEPT",
+        ]);
+        rules.push(vec!["OUTPUT", "-d", server, "-p", "esp", "-j", "ACCEPT"]);
+        rules.push(vec!["INPUT", "-s", server, "-p", "esp", "-j", "ACCEPT"]);
+    }
+    rules.push(vec!["OUTPUT", "-j", "REJECT"]);
+    rules
+}
+
+/// Only IKE and ESP to the server may leave outside the tunnel interface
+fn killswitch(netns: &NetworkNamespace, server: IpAddr, firewall: Firewall) -> anyhow::Result<()> {
+    debug!("Setting IKEv2 killswitch....");
+    let server = server.to_string();
+    match firewall {
+        Firewall::IpTables => {
+            for ipcmd in ["iptables", "ip6tables"] {
+                let rule = |args: &[&str]| -> anyhow::Result<()> {
+                    let mut command = vec![ipcmd];
+                    command.extend(args);
+                    NetworkNamespace::exec(&netns.name, &command)
+                        .with_context(|| format!("Executing {ipcmd}"))
+                };
+                for chain in ["INPUT", "FORWARD", "OUTPUT"] {
+                    rule(&["-P", chain, "DROP"])?;
+                }
+                for args in iptables_rules(ipcmd == "ip6tables", &server) {
+                    rule(&[&["-A"], args.as_slice()].concat())?;
+                }
+            }
+        }
+        Firewall::NfTables => {
+            let table = format!("{}_ikev2", netns.name);
+            let nft = |args: &[&str]| -> anyhow::Result<()> {
+                let mut command = vec!["nft", "add"];
+                command.extend(args);
+                NetworkNamespace::exec(&netns.name, &command).context("Executing nft")
+            };
+            nft(&["table", "inet", &table])?;
+            for chain in ["input", "forward", "output"] {
+                let hook = format!("{{ type filter hook {chain} priority 100 ; policy drop; }}");
+                nft(&["chain", "inet", &table, chain, &hook])?;
+            }
+            let rules: [&[&str]; 8] = [
+                &["input", "ct", "state", "related,established", "accept"],
+                &["input", "iifname", "\"lo\"", "accept"],
+                &["input", "iifname", IKEV2_INTERFACE, "accept"],
+                &[
+                    "input", "ip", "saddr", &server, "meta", "l4proto", "esp", "accept",
+                ],
+                &["output", "oifname", "\"lo\"", "accept"],
+                &["output", "oifname", IKEV2_INTERFACE, "accept"],
+                &[
+                    "output",
+                    "ip",
+                    "daddr",
+                    &server,
+                    "udp",
+                    "dport",
+                    "{ 500, 4500 }",
+                    "accept",
+                ],
+                &[
+                    "output", "ip", "daddr", &server, "meta", "l4proto", "esp", "accept",
+                ],
+            ];
+            for rule in rules {
+                let mut args = vec!["rule", "inet", &table];
+                args.extend(rule);
+                nft(&args)?;
+            }
+            nft(&["rule", "inet", &table, "output", "counter", "reject"])?;
+        }
+    }
+    Ok(())
+}
+
+impl Drop for Ikev2 {
+    fn drop(&mut self) {
+        self.swanctl(&["--terminate", "--ike", CONNECTION, "--timeout", "5"])
+            .ok();
+        match nix::sys::signal::kill(
+            nix::unistd::Pid::from_raw(self.pid as i32),
+            nix::sys::signal::Signal::SIGTERM,
+        ) {
+            Ok(_) => debug!("Stopped charon (pid: {})", self.pid),
+            Err(e) => error!("Failed to stop charon (pid: {}): {:?}", self.pid, e),
+        }
+        std::fs::remove_dir_all(&self.config_dir).ok();
+        // For a reconnect in the same namespace, otherwise they go with it
+        NetworkNamespace::exec(&self.ns_name, &["ip", "link", "delete", IKEV2_INTERFACE]).ok();
+        NetworkNamespace::exec(
+            &self.ns_name,
+            &["ip", "route", "del", &self.server.to_string()],
+        )
+        .ok();
+        if let Some(firewall) = self.firewall {
+            remove_killswitch(&self.ns_name, self.server, firewall);
+        }
+    }
+}
+
+/// Remove the killswitch, for a reconnect in the same namespace
+fn remove_killswitch(ns_name: &str, server: IpAddr, firewall: Firewall) {
+    match firewall {
+        Firewall::IpTables => {
+            let server = server.to_string();
+            for ipcmd in ["iptables", "ip6tables"] {
+                for args in iptables_rules(ipcmd == "ip6tables", &server) {
+                    NetworkNamespace::exec(ns_name, &[&[ipcmd, "-D"], args.as_slice()].concat())
+                        .ok();
+                }
+                for chain in ["INPUT", "FORWARD", "OUTPUT"] {
+                    NetworkNamespace::exec(ns_name, &[ipcmd, "-P", chain, "ACCEPT"]).ok();
+                }
+            }
+        }
+        Firewall::NfTables => {
+            let table = format!("{ns_name}_ikev2");
+            NetworkNamespace::exec(ns_name, &["nft", "delete", "table", "inet", &table]).ok();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ikev2_profile_to_swanctl() {
+        let profile: Ikev2Profile = toml::from_str(
+            "server = \"us1.ikev2.example.com\"\nusername = \"user\"\npassword = \"pa\\\"ss\"\n",
+        )
+        .unwrap();
+        assert_eq!(profile.remote_id, None);
+        let conf = swanctl_conf(&profile, IpAddr::from([203, 0, 113, 5]), "pa\"ss");
+        assert!(conf.contains("remote_addrs = 203.0.113.5\n"));
+        assert!(conf.contains("id = \"us1.ikev2.example.com\"\n"));
+        assert!(conf.contains("auth = eap-mschapv2\n"));
+        assert!(conf.contains("secret = \"pa\\\"ss\"\n"));
+        assert!(!conf.contains("cacerts"));
+        assert!(toml::from_str::<Ikev2Profile>("server = \"x\"").is_err());
+    }
+
+    #[test]
+    fn killswitch_rules_per_family() {
+        let ipv4 = iptables_rules(false, "203.0.113.5");
+        assert_eq!(ipv4.len(), 9);
+        assert!(ipv4.contains(&vec![
+            "OUTPUT",
+            "-d",
+            "203.0.113.5",
+            "-p",
+            "esp",
+            "-j",
+            "ACCEPT"
+        ]));
+        let ipv6 = iptables_rules(true, "203.0.113.5");
+        assert!(!ipv6.iter().any(|x| x.contains(&"203.0.113.5")));
+        assert_eq!(ipv6.last().unwrap(), &vec!["OUTPUT", "-j", "REJECT"]);
+    }
+}
@@ -0,0 +1,162 @@
+// Exit IP verification after the tunnel comes up, for --verify-ip
+// The IP-check endpoint is queried with curl from the namespace and from the host, over IPv4 and
+// then IPv6 so each address is compared with the host's of the same family. The check fails if
+// the namespace leaves from the host's public address of either family, if the endpoint reports another
+// country than that of the chosen server (with --verify-country), or if it says the address is
+// not one of the provider's exits (Mullvad's am.i.mullvad.net reports this). vopono exec then
+// fails before the application is started.
+
+use super::netns::NetworkNamespace;
+use crate::config::providers::VpnProvider;
+use crate::util::sync_filter::SyncFilter;
+use anyhow::{Context, anyhow};
+use log::{debug, info};
+use std::net::IpAddr;
+use std::process::{Command, Output};
+
+pub const DEFAULT_IP_CHECK_URL: &str = "https://ipinfo.io/json";
+const MULLVAD_IP_CHECK_URL: &str = "https://am.i.mullvad.net/json";
+
+/// Answer of the IP-check endpoint
+#[derive(Debug, PartialEq, Eq)]
+pub struct IpCheck {
+    pub ip: IpAddr,
+    /// Country name or code
+    pub country: Option<String>,
+    /// Whether the address is one of the provider's exits, if the endpoint says
+    pub provider_exit: Option<bool>,
+}
+
+/// Endpoint to use for the provider, unless one is given
+pub fn default_ip_check_url(provider: &VpnProvider) -> &'static str {
+    match provider {
+        VpnProvider::Mullvad => MULLVAD_IP_CHECK_URL,
+        _ => DEFAULT_IP_CHECK_URL,
+    }
+}
+
+/// Parse a JSON answer with an ip field, or a plain text address
+pub fn parse_ip_check(body: &str) -> anyhow::Result<IpCheck> {
+    if let Ok(ip) = body.trim().parse() {
+        return Ok(IpCheck {
+            ip,
+            country: None,
+            provider_exit: None,
+        });
+    }
+    let value: serde_json::Value =
+        serde_json::from_str(body).with_context(|| format!("Invalid IP check answer: {body}"))?;
+    let ip = value["ip"]
+        .as_str()
+        .and_then(|x| x.parse().ok())
+        .ok_or_else(|| anyhow!("No ip in IP check answer: {body}"))?;
+    Ok(IpCheck {
+        ip,
+        country: value["country"].as_str().map(|x| x.to_string()),
+        provider_exit: value["mullvad_exit_ip"].as_bool(),
+    })
+}
+
+fn curl_output(output: Output, url: &str) -> anyhow::Result<IpCheck> {
+    if !output.status.success() {
+        return Err(anyhow!(
+            "Failed to query {url}: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    parse_ip_check(&String::from_utf8_lossy(&output.stdout))
+}
+
+const CURL_ARGS: [&str; 4] = ["curl", "-sS", "--max-time", "10"];
+
+/// Exit address of the namespace and public address of the host over one family, family is -4
+/// or -6
+fn family_checks(
+    ns: &NetworkNamespace,
+    url: &str,
+    family: &str,
+) -> (anyhow::Result<IpCheck>, anyhow::Result<IpCheck>) {
+    let mut args = CURL_ARGS.to_vec();
+    args.extend([family, url]);
+    let exit = NetworkNamespace::exec_with_output(&ns.name, &args)
+        .and_then(|output| curl_output(output, url));
+    let host = Command::new(args[0])
+        .args(&args[1..])
+        .output()
+        .map_err(anyhow::Error::from)
+        .and_then(|output| curl_output(output, url));
+    (exit, host)
+}
+
+/// Verify the exit address of the namespace, config_name is the chosen server for the country
+/// check
+pub fn verify_exit_ip(
+    ns: &NetworkNamespace,
+    url: &str,
+    config_name: Option<&str>,
+) -> anyhow::Result<IpCheck> {
+    let (exit, host) = family_checks(ns, url, "-4");
+    let exit = exit.context("Failed to check the exit IP of the namespace")?;
+    let host = host.context("Failed to check the public IP of the host")?;
+    debug!("Exit IP check: namespace {exit:?}, host {host:?}");
+    // Without IPv6 in the namespace or on the host there is nothing to compare
+    let (exit_v6, host_v6) = family_checks(ns, url, "-6");
+    debug!("Exit IPv6 check: namespace {exit_v6:?}, host {host_v6:?}");
+    let same_v6 = match (exit_v6, host_v6) {
+        (Ok(exit_v6), Ok(host_v6)) => (exit_v6.ip == host_v6.ip).then_some(host_v6.ip),
+        _ => None,
+    };
+    if let Some(ip) = (exit.ip == host.ip).then_some(host.ip).or(same_v6) {
+        return Err(anyhow!(
+            "Network namespace {} leaves from the host's public IP {}, the tunnel is not in use",
+            ns.name,
+            ip
+        ));
+    }
+    if exit.provider_exit == Some(false) {
+        return Err(anyhow!(
+            "Exit IP {} of {} is not a {} exit",
+            exit.ip,
+            ns.name,
+            ns.provider
+        ));
+    }
+    if let Some(name) = config_name {
+        let country = exit
+            .country
+            .as_deref()
+            .ok_or_else(|| anyhow!("{url} does not report the country of {}", exit.ip))?;
+        let filter = SyncFilter {
+            countries: vec![country.to_string()],
+            cities: Vec::new(),
+        };
+        if !filter.matches(name) {
+            return Err(anyhow!(
+                "Exit IP {} of {} is in {country}, not in the country of server {name}",
+                exit.ip,
+                ns.name
+            ));
+        }
+    }
+    info!("Verified exit IP {} of {}", exit.ip, ns.name);
+    Ok(exit)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_ip_check_answers() {
+        let check = parse_ip_check(
+            r#"{"ip":"185.65.135.1","country":"Sweden","city":"Gothenburg","mullvad_exit_ip":true}"#,
+        )
+        .unwrap();
+        assert_eq!(check.ip, "185.65.135.1".parse::<IpAddr>().unwrap());
+        assert_eq!(check.country.as_deref(), Some("Sweden"));
+        assert_eq!(check.provider_exit, Some(true));
+        let check = parse_ip_check("2001:db8::1\n").unwrap();
+        assert_eq!(check.country, None);
+        assert!(parse_ip_check("<html>").is_err());
+    }
+}
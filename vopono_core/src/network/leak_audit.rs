@@ -0,0 +1,383 @@
+// Leak audit for the namespace killswitch and routing
+// With --audit-leaks, vopono records every packet from an application in the namespace which
+// would leave via the veth interface instead of the tunnel, to a host other than the allowed
+// ones (the same exceptions as the veth killswitch). The rules sit in front of the killswitch, so
+// packets it rejects are recorded too. Packets without a socket (the kernel's, e.g. Wireguard)
+// are never recorded. Root-owned sockets are only exempt when the application runs as another
+// user, as they are then the VPN client; otherwise the VPN client's own connection to the server
+// shows up among the destinations. IPv6 is skipped with iptables if ip6tables is not installed.
+// Matching packets are logged to the kernel log (which only
+// shows packets from network namespaces with net.netfilter.nf_log_all_netns = 1) and their
+// destinations kept in an nftables set or an xt_recent list, which are read back for a report
+// when the namespace is shut down.
+
+use super::firewall::Firewall;
+use super::netns::NetworkNamespace;
+use crate::util::vopono_dir;
+use anyhow::{Context, anyhow};
+use ipnet::IpNet;
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+use std::fmt::Display;
+use std::path::PathBuf;
+
+/// iptables chain, nftables table and xt_recent list of the audit rules
+const AUDIT_CHAIN: &str = "vopono_audit";
+const LOG_PREFIX: &str = "vopono leak: ";
+
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct LeakAuditReport {
+    pub packets: u64,
+    /// Destination addresses, with the protocol for nftables
+    pub destinations: Vec<String>,
+}
+
+impl Display for LeakAuditReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(
+            f,
+            "Packets which tried to leave outside the tunnel: {}",
+            self.packets
+        )?;
+        for destination in &self.destinations {
+            writeln!(f, "  {destination}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Packet count of the first counter in nft list output
+fn parse_nft_counter(output: &str) -> u64 {
+    output
+        .split_whitespace()
+        .skip_while(|x| *x != "packets")
+        .nth(1)
+        .and_then(|x| x.parse().ok())
+        .unwrap_or_default()
+}
+
+/// Elements of a set in nft list output, e.g. 10.0.0.1 . tcp as 10.0.0.1/tcp
+fn parse_nft_set(output: &str) -> Vec<String> {
+    let Some(start) = output.find("elements = {") else {
+        return Vec::new();
+    };
+    let elements = &output[start + "elements = {".len()..];
+    let elements = &elements[..elements.find('}').unwrap_or(elements.len())];
+    elements
+        .split(',')
+        .map(|x| x.split_whitespace().collect::<Vec<_>>().join(" "))
+        .filter(|x| !x.is_empty())
+        .map(|x| x.replace(" . ", "/"))
+        .collect()
+}
+
+/// Addresses in an xt_recent list, which are given as src= even with --rdest
+fn parse_recent(output: &str) -> Vec<String> {
+    output
+        .lines()
+        .filter_map(|line| line.strip_prefix("src="))
+        .filter_map(|x| x.split_whitespace().next())
+        .map(|x| x.to_string())
+        .collect()
+}
+
+/// Packet count of the LOG rule in iptables -L -v -x -n output for the audit chain
+fn parse_iptables_counter(output: &str) -> u64 {
+    output
+        .lines()
+        .filter(|line| line.contains("LOG"))
+        .filter_map(|line| line.split_whitespace().next())
+        .filter_map(|x| x.parse::<u64>().ok())
+        .sum()
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct LeakAudit {
+    ns_name: String,
+    firewall: Firewall,
+    /// Whether the IPv6 rules were added
+    ipv6: bool,
+}
+
+impl LeakAudit {
+    pub fn start(
+        netns: &NetworkNamespace,
+        veth: &str,
+        allowed: &[IpNet],
+        exempt_root: bool,
+        firewall: Firewall,
+    ) -> anyhow::Result<Self> {
+        debug!("Adding leak audit rules on {veth}");
+        let ipv6 = firewall == Firewall::NfTables || which::which("ip6tables").is_ok();
+        match firewall {
+            Firewall::IpTables => {
+                for (ipcmd, ipv6) in [("iptables", false), ("ip6tables", true)] {
+                    if ipv6 && which::which(ipcmd).is_err() {
+                        warn!("ip6tables not found, IPv6 is not covered by the leak audit");
+                        continue;
+                    }
+                    NetworkNamespace::exec(&netns.name, &[ipcmd, "-N", AUDIT_CHAIN])?;
+                    for host in allowed.iter().filter(|x| matches!(x, IpNet::V6(_)) == ipv6) {
+                        NetworkNamespace::exec(
+                            &netns.name,
+                            &[
+                                ipcmd,
+                                "-A",
+                                AUDIT_CHAIN,
+                                "-d",
+                                &host.to_string(),
+                                "-j",
+                                "RETURN",
+                            ],
+                        )?;
+                    }
+                    // Root-owned sockets are the VPN client if the application is not root
+                    if exempt_root {
+                        NetworkNamespace::exec(
+                            &netns.name,
+                            &[
+                                ipcmd,
+                                "-A",
+                                AUDIT_CHAIN,
+                                "-m",
+                                "owner",
+                                "--uid-owner",
+                                "0",
+                                "-j",
+                                "RETURN",
+                            ],
+                        )?;
+                    }
+                    NetworkNamespace::exec(
+                        &netns.name,
+                        &[
+                            ipcmd,
+                            "-A",
+                            AUDIT_CHAIN,
+                            "-m",
+                            "owner",
+                            "--socket-exists",
+                            "-m",
+                            "recent",
+                            "--name",
+                            AUDIT_CHAIN,
+                            "--rdest",
+                            "--set",
+                            "-j",
+                            "LOG",
+                            "--log-prefix",
+                            LOG_PREFIX,
+                        ],
+                    )?;
+                    // In front of the veth killswitch
+                    NetworkNamespace::exec(
+                        &netns.name,
+                        &[ipcmd, "-I", "OUTPUT", "1", "-o", veth, "-j", AUDIT_CHAIN],
+                    )?;
+                }
+            }
+            Firewall::NfTables => {
+                let exec = |args: &[&str]| -> anyhow::Result<()> {
+                    let mut command = vec!["nft", "add"];
+                    command.extend(args);
+                    NetworkNamespace::exec(&netns.name, &command)
+                };
+                exec(&["table", "inet", AUDIT_CHAIN])?;
+                for (set, key) in [
+                    ("destinations4", "ipv4_addr . inet_proto"),
+                    ("destinations6", "ipv6_addr . inet_proto"),
+                ] {
+                    exec(&[
+                        "set",
+                        "inet",
+                        AUDIT_CHAIN,
+                        set,
+                        &format!("{{ type {key} ; flags dynamic ; size 4096 ; }}"),
+                    ])?;
+                }
+                exec(&[
+                    "chain",
+                    "inet",
+                    AUDIT_CHAIN,
+                    "output",
+                    "{ type filter hook output priority -700 ; policy accept ; }",
+                ])?;
+                for host in allowed {
+                    let family = match host {
+                        IpNet::V4(_) => "ip",
+                        IpNet::V6(_) => "ip6",
+                    };
+                    exec(&[
+                        "rule",
+                        "inet",
+                        AUDIT_CHAIN,
+                        "output",
+                        "oifname",
+                        veth,
+                        family,
+                        "daddr",
+                        &host.to_string(),
+                        "return",
+                    ])?;
+                }
+                // Packets without a socket do not match skuid at all
+                let skuid = if exempt_root {
+                    ["!=", "0"]
+                } else {
+                    [">=", "0"]
+                };
+                let leak = ["oifname", veth, "meta", "skuid", skuid[0], skuid[1]];
+                let rule = |args: &[&str]| -> anyhow::Result<()> {
+                    let mut command = vec!["rule", "inet", AUDIT_CHAIN, "output"];
+                    command.extend(leak);
+                    command.extend(args);
+                    exec(&command)
+                };
+                rule(&["counter", "log", "prefix", &format!("\"{LOG_PREFIX}\"")])?;
+                rule(&[
+                    "add",
+                    "@destinations4",
+                    "{",
+                    "ip",
+                    "daddr",
+                    ".",
+                    "meta",
+                    "l4proto",
+                    "}",
+                ])?;
+                rule(&[
+                    "add",
+                    "@destinations6",
+                    "{",
+                    "ip6",
+                    "daddr",
+                    ".",
+                    "meta",
+                    "l4proto",
+                    "}",
+                ])?;
+            }
+        }
+        Ok(Self {
+            ns_name: netns.name.clone(),
+            firewall,
+            ipv6,
+        })
+    }
+
+    /// Read back the packets recorded so far
+    pub fn report(&self) -> anyhow::Result<LeakAuditReport> {
+        let output = |command: &[&str]| -> anyhow::Result<String> {
+            let output = NetworkNamespace::exec_with_output(&self.ns_name, command)?;
+            if !output.status.success() {
+                return Err(anyhow!(
+                    "{} failed: {}",
+                    command.join(" "),
+                    String::from_utf8_lossy(&output.stderr).trim()
+                ));
+            }
+            Ok(String::from_utf8_lossy(&output.stdout).to_string())
+        };
+        let mut report = LeakAuditReport::default();
+        match self.firewall {
+            Firewall::IpTables => {
+                let ipcmds: &[&str] = if self.ipv6 {
+                    &["iptables", "ip6tables"]
+                } else {
+                    &["iptables"]
+                };
+                for ipcmd in ipcmds {
+                    report.packets += parse_iptables_counter(&output(&[
+                        ipcmd,
+                        "-L",
+                        AUDIT_CHAIN,
+                        "-v",
+                        "-x",
+                        "-n",
+                    ])?);
+                }
+                for list in ["/proc/net/xt_recent", "/proc/net/ip6t_recent"]
+                    .into_iter()
+                    .take(ipcmds.len())
+                {
+                    report.destinations.extend(parse_recent(&output(&[
+                        "cat",
+                        &format!("{list}/{AUDIT_CHAIN}"),
+                    ])?));
+                }
+            }
+            Firewall::NfTables => {
+                report.packets = parse_nft_counter(&output(&[
+                    "nft",
+                    "list",
+                    "chain",
+                    "inet",
+                    AUDIT_CHAIN,
+                    "output",
+                ])?);
+                for set in ["destinations4", "destinations6"] {
+                    report.destinations.extend(parse_nft_set(&output(&[
+                        "nft",
+                        "list",
+                        "set",
+                        "inet",
+                        AUDIT_CHAIN,
+                        set,
+                    ])?));
+                }
+            }
+        }
+        Ok(report)
+    }
+
+    fn report_path(&self) -> anyhow::Result<PathBuf> {
+        let logs = vopono_dir()?.join("logs");
+        std::fs::create_dir_all(&logs)?;
+        Ok(logs.join(format!("{}_leak_audit.txt", self.ns_name)))
+    }
+}
+
+impl Drop for LeakAudit {
+    fn drop(&mut self) {
+        let report = match self.report() {
+            Ok(report) => report,
+            Err(e) => {
+                warn!("Failed to read leak audit of {}: {e:?}", self.ns_name);
+                return;
+            }
+        };
+        if report.packets == 0 {
+            info!(
+                "Leak audit: no packets left {} outside the tunnel",
+                self.ns_name
+            );
+        } else {
+            warn!("Leak audit of {}:\n{report}", self.ns_name);
+        }
+        match self.report_path().and_then(|path| {
+            std::fs::write(&path, report.to_string())
+                .map(|_| path)
+                .context("Failed to write leak audit report")
+        }) {
+            Ok(path) => info!("Leak audit report written to {}", path.display()),
+            Err(e) => warn!("{e:?}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_audit_output() {
+        let chain = "table inet vopono_audit {\n\tchain output {\n\t\toifname \"vo_s\" meta skuid != 0 counter packets 3 bytes 180 log prefix \"vopono leak: \"\n\t}\n}\n";
+        assert_eq!(parse_nft_counter(chain), 3);
+        let set = "table inet vopono_audit {\n\tset destinations4 {\n\t\ttype ipv4_addr . inet_proto\n\t\tsize 4096\n\t\tflags dynamic\n\t\telements = { 1.1.1.1 . udp, 93.184.216.34 . tcp }\n\t}\n}\n";
+        assert_eq!(parse_nft_set(set), ["1.1.1.1/udp", "93.184.216.34/tcp"]);
+        let recent = "src=93.184.216.34 ttl: 64 last_seen: 4295 oldest_pkt: 2 4295, 4296\n";
+        assert_eq!(parse_recent(recent), ["93.184.216.34"]);
+        let iptables = "Chain vopono_audit (1 references)\n    pkts      bytes target     prot opt in     out     source               destination\n       0        0 RETURN     all  --  *      *       0.0.0.0/0            10.200.1.1\n       2      120 LOG        all  --  *      *       0.0.0.0/0            0.0.0.0/0            owner socket exists recent: SET name: vopono_audit side: dest mask: 255.255.255.255 LOG flags 0 level 4 prefix \"vopono leak: \"\n";
+        assert_eq!(parse_iptables_counter(iptables), 2);
+    }
+}
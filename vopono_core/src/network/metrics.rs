@@ -0,0 +1,217 @@
+// Prometheus metrics of running network namespaces, for vopono metrics
+// The values are gathered on each scrape from the lockfiles and the tunnel status (see status.rs),
+// with the bytes transferred read from the tunnel interface statistics in the namespace. A tunnel
+// is up if its interface is up and, for OpenVPN, the connection is established.
+
+use super::netns::{Lockfile, NetworkNamespace};
+use super::status::tunnel_status;
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+#[derive(Debug, Default)]
+pub struct NamespaceMetrics {
+    pub namespace: String,
+    pub provider: String,
+    pub protocol: String,
+    pub up: bool,
+    /// Seconds since the last Wireguard handshake
+    pub handshake_age: Option<u64>,
+    pub forwarded_ports: Vec<u16>,
+    /// Received and transmitted bytes of the tunnel interface
+    pub bytes: Option<(u64, u64)>,
+    pub refresh_failures: u32,
+    pub reconnects: u32,
+    pub applications: usize,
+}
+
+/// Received and transmitted bytes in the statistics of the interface
+fn interface_bytes(netns_name: &str, interface: &str) -> Option<(u64, u64)> {
+    let rx = format!("/sys/class/net/{interface}/statistics/rx_bytes");
+    let tx = format!("/sys/class/net/{interface}/statistics/tx_bytes");
+    let output = NetworkNamespace::exec_with_output(netns_name, &["cat", &rx, &tx]).ok()?;
+    let mut values = String::from_utf8_lossy(&output.stdout)
+        .split_whitespace()
+        .filter_map(|x| x.parse::<u64>().ok())
+        .collect::<Vec<_>>()
+        .into_iter();
+    Some((values.next()?, values.next()?))
+}
+
+/// Metrics of each namespace, from the lockfiles of the instances using it
+pub fn collect_metrics(namespaces: &HashMap<String, Vec<Lockfile>>) -> Vec<NamespaceMetrics> {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|x| x.as_secs())
+        .unwrap_or_default();
+    let mut metrics: Vec<NamespaceMetrics> = namespaces
+        .iter()
+        .filter_map(|(name, locks)| {
+            let ns = &locks.first()?.ns;
+            let status = tunnel_status(ns);
+            let mut forwarded_ports = locks
+                .iter()
+                .filter_map(|x| x.forwarded_port)
+                .collect::<Vec<_>>();
+            forwarded_ports.sort();
+            forwarded_ports.dedup();
+            Some(NamespaceMetrics {
+                namespace: name.clone(),
+                provider: ns.provider.to_string(),
+                protocol: ns.protocol.to_string(),
+                up: status
+                    .state
+                    .as_deref()
+                    .is_some_and(|x| x == "UP" || x == "UNKNOWN")
+                    && status.openvpn_state.is_none_or(|x| x == "connected"),
+                handshake_age: status.last_handshake.map(|x| now.saturating_sub(x)),
+                forwarded_ports,
+                bytes: status
+                    .interface
+                    .as_deref()
+                    .and_then(|x| interface_bytes(&ns.name, x)),
+                refresh_failures: locks.iter().map(|x| x.refresh_failures).sum(),
+                reconnects: locks.iter().map(|x| x.reconnects).sum(),
+                applications: locks.len(),
+            })
+        })
+        .collect();
+    metrics.sort_by(|a, b| a.namespace.cmp(&b.namespace));
+    metrics
+}
+
+/// Escape a label value of the text exposition format
+fn label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Metrics in the Prometheus text exposition format
+pub fn render_metrics(metrics: &[NamespaceMetrics]) -> String {
+    let mut out = String::new();
+    let mut family =
+        |name: &str, kind: &str, help: &str, values: &dyn Fn(&NamespaceMetrics) -> Vec<String>| {
+            let _ = writeln!(out, "# HELP {name} {help}");
+            let _ = writeln!(out, "# TYPE {name} {kind}");
+            for m in metrics {
+                for sample in values(m) {
+                    let _ = writeln!(out, "{name}{sample}");
+                }
+            }
+        };
+    let ns = |m: &NamespaceMetrics| format!("namespace=\"{}\"", label(&m.namespace));
+    family(
+        "vopono_tunnel_up",
+        "gauge",
+        "Whether the VPN tunnel of the namespace is up",
+        &|m| {
+            vec![format!(
+                "{{{},provider=\"{}\",protocol=\"{}\"}} {}",
+                ns(m),
+                label(&m.provider),
+                label(&m.protocol),
+                u8::from(m.up)
+            )]
+        },
+    );
+    family(
+        "vopono_wireguard_handshake_age_seconds",
+        "gauge",
+        "Seconds since the last Wireguard handshake",
+        &|m| {
+            m.handshake_age
+                .map(|x| format!("{{{}}} {x}", ns(m)))
+                .into_iter()
+                .collect()
+        },
+    );
+    family(
+        "vopono_forwarded_port",
+        "gauge",
+        "Port forwarded by the VPN provider",
+        &|m| {
+            m.forwarded_ports
+                .iter()
+                .map(|x| format!("{{{}}} {x}", ns(m)))
+                .collect()
+        },
+    );
+    family(
+        "vopono_tunnel_receive_bytes_total",
+        "counter",
+        "Bytes received on the tunnel interface",
+        &|m| {
+            m.bytes
+                .map(|(rx, _)| format!("{{{}}} {rx}", ns(m)))
+                .into_iter()
+                .collect()
+        },
+    );
+    family(
+        "vopono_tunnel_transmit_bytes_total",
+        "counter",
+        "Bytes transmitted on the tunnel interface",
+        &|m| {
+            m.bytes
+                .map(|(_, tx)| format!("{{{}}} {tx}", ns(m)))
+                .into_iter()
+                .collect()
+        },
+    );
+    family(
+        "vopono_port_forwarding_refresh_failures_total",
+        "counter",
+        "Failed refreshes of the forwarded port",
+        &|m| vec![format!("{{{}}} {}", ns(m), m.refresh_failures)],
+    );
+    family(
+        "vopono_tunnel_reconnects_total",
+        "counter",
+        "Times the tunnel was reconnected after failing",
+        &|m| vec![format!("{{{}}} {}", ns(m), m.reconnects)],
+    );
+    family(
+        "vopono_applications",
+        "gauge",
+        "vopono instances running applications in the namespace",
+        &|m| vec![format!("{{{}}} {}", ns(m), m.applications)],
+    );
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn render_prometheus_metrics() {
+        let metrics = [NamespaceMetrics {
+            namespace: "mu_se_got_wg_001".to_string(),
+            provider: "Mullvad".to_string(),
+            protocol: "Wireguard".to_string(),
+            up: true,
+            handshake_age: Some(42),
+            forwarded_ports: vec![51413],
+            bytes: Some((1000, 2000)),
+            refresh_failures: 1,
+            reconnects: 0,
+            applications: 2,
+        }];
+        let out = render_metrics(&metrics);
+        assert!(out.contains("# TYPE vopono_tunnel_up gauge\nvopono_tunnel_up{namespace=\"mu_se_got_wg_001\",provider=\"Mullvad\",protocol=\"Wireguard\"} 1\n"));
+        assert!(out.contains(
+            "vopono_wireguard_handshake_age_seconds{namespace=\"mu_se_got_wg_001\"} 42\n"
+        ));
+        assert!(
+            out.contains(
+                "vopono_tunnel_transmit_bytes_total{namespace=\"mu_se_got_wg_001\"} 2000\n"
+            )
+        );
+        assert!(out.contains(
+            "vopono_port_forwarding_refresh_failures_total{namespace=\"mu_se_got_wg_001\"} 1\n"
+        ));
+        assert_eq!(label("a\"b"), "a\\\"b");
+    }
+}
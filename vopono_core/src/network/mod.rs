@@ -1,16 +1,43 @@
+pub mod app_cgroup;
 pub mod application_wrapper;
+pub mod bridge;
+pub mod discovery_relay;
 pub mod dns_config;
+pub mod dns_leak;
+pub mod dns_rules;
+pub mod dnscrypt_proxy;
+pub mod egress;
 pub mod firewall;
 pub mod host_masquerade;
+pub mod ikev2;
+pub mod ip_check;
+pub mod leak_audit;
+pub mod metrics;
+pub mod mtu;
+pub mod netlink;
 pub mod netns;
 pub mod network_interface;
+pub mod obfs4;
+pub mod obfuscation;
 pub mod openconnect;
 pub mod openfortivpn;
 pub mod openvpn;
 pub mod port_forwarding;
+pub mod port_publish;
+pub mod rate_limit;
+pub mod reconnect;
+pub mod resolved;
+pub mod rotation;
 pub mod shadowsocks;
+pub mod status;
+pub mod stub_resolver;
 pub mod sysctl;
+pub mod tor;
+pub mod transparent_proxy;
 pub mod trojan;
+pub mod upstream_proxy;
 pub mod veth_pair;
 pub mod warp;
+pub mod watchdog;
 pub mod wireguard;
+pub mod xray;
@@ -0,0 +1,222 @@
+// Tunnel MTU and TCP MSS clamping
+// If the path to the VPN server cannot carry full size tunnel packets, large packets are dropped
+// and some sites hang. For Wireguard the path MTU to the endpoint is probed from the host with
+// ping (with the Don't Fragment bit set) and the tunnel MTU lowered to fit, unless --mtu is
+// given. The MSS of TCP connections leaving through the tunnel is always clamped to its MTU.
+
+use super::firewall::Firewall;
+use super::netns::NetworkNamespace;
+use anyhow::{Context, anyhow};
+use log::{debug, info};
+use std::net::IpAddr;
+use std::process::{Command, Stdio};
+
+/// Smallest MTU required for IPv6, tunnels are never set below this
+pub const MIN_MTU: u16 = 1280;
+const NFT_TABLE: &str = "vopono_mss";
+
+/// Outer IP, UDP and Wireguard headers added to each tunnel packet
+pub fn wireguard_overhead(endpoint: IpAddr) -> u16 {
+    match endpoint {
+        IpAddr::V4(_) => 20 + 8 + 32,
+        IpAddr::V6(_) => 40 + 8 + 32,
+    }
+}
+
+/// Interface used for traffic to the internet in the namespace, i.e. the tunnel once the VPN is up
+pub fn tunnel_interface(netns_name: &str) -> anyhow::Result<String> {
+    route_interface(netns_name, IpAddr::from([1, 1, 1, 1]))
+        .map_err(|_| anyhow!("No route to the internet in network namespace {netns_name}"))
+}
+
+/// Interface the namespace routes traffic to the destination through
+pub fn route_interface(netns_name: &str, destination: IpAddr) -> anyhow::Result<String> {
+    let output = NetworkNamespace::exec_with_output(
+        netns_name,
+        &["ip", "route", "get", &destination.to_string()],
+    )?;
+    let output = String::from_utf8_lossy(&output.stdout);
+    output
+        .split_whitespace()
+        .skip_while(|x| *x != "dev")
+        .nth(1)
+        .map(|x| x.to_string())
+        .ok_or_else(|| anyhow!("No route to {destination} in network namespace {netns_name}"))
+}
+
+pub fn interface_mtu(netns_name: &str, interface: &str) -> anyhow::Result<u16> {
+    let output = NetworkNamespace::exec_with_output(
+        netns_name,
+        &["ip", "-o", "link", "show", "dev", interface],
+    )?;
+    String::from_utf8_lossy(&output.stdout)
+        .split_whitespace()
+        .skip_while(|x| *x != "mtu")
+        .nth(1)
+        .and_then(|x| x.parse().ok())
+        .ok_or_else(|| anyhow!("Failed to read MTU of {interface}"))
+}
+
+pub fn set_mtu(netns_name: &str, interface: &str, mtu: u16) -> anyhow::Result<()> {
+    if mtu < MIN_MTU {
+        return Err(anyhow!("MTU must be at least {MIN_MTU}"));
+    }
+    let status = Command::new("ip")
+        .args(["netns", "exec", netns_name, "ip", "link", "set", "dev"])
+        .args([interface, "mtu", &mtu.to_string()])
+        .status()?;
+    if !status.success() {
+        return Err(anyhow!("Failed to set MTU of {interface} to {mtu}"));
+    }
+    info!("Set MTU of {interface} to {mtu}");
+    Ok(())
+}
+
+/// Whether a packet of the given size reaches the host without being fragmented
+fn ping_fits(host: IpAddr, packet_size: u16) -> bool {
+    let header = match host {
+        IpAddr::V4(_) => 20 + 8,
+        IpAddr::V6(_) => 40 + 8,
+    };
+    Command::new("ping")
+        .args(["-M", "do", "-c", "1", "-W", "1", "-s"])
+        .arg((packet_size - header).to_string())
+        .arg(host.to_string())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .is_ok_and(|x| x.success())
+}
+
+/// Largest packet size up to max reaching the host unfragmented, or None if the host does not
+/// answer pings at all
+pub fn probe_path_mtu(host: IpAddr, max: u16) -> Option<u16> {
+    let path_mtu = search_path_mtu(max, |size| ping_fits(host, size));
+    if path_mtu.is_none() {
+        debug!("No ping reply from {host}, cannot probe path MTU");
+    }
+    path_mtu
+}
+
+/// Largest size from MIN_MTU up to max which fits, or None if not even MIN_MTU fits
+fn search_path_mtu(max: u16, fits: impl Fn(u16) -> bool) -> Option<u16> {
+    if fits(max) {
+        return Some(max);
+    }
+    if max <= MIN_MTU || !fits(MIN_MTU) {
+        return None;
+    }
+    // Binary search between a size that fits and one that does not
+    let (mut low, mut high) = (MIN_MTU, max);
+    while high - low > 1 {
+        let mid = low + (high - low) / 2;
+        if fits(mid) {
+            low = mid;
+        } else {
+            high = mid;
+        }
+    }
+    Some(low)
+}
+
+/// Lower tunnel MTU if the path to the Wireguard endpoint cannot carry packets of the current one
+pub fn auto_wireguard_mtu(endpoint: IpAddr, current: u16) -> Option<u16> {
+    which::which("ping").ok()?;
+    let overhead = wireguard_overhead(endpoint);
+    let path_mtu = probe_path_mtu(endpoint, current.saturating_add(overhead))?;
+    debug!("Path MTU to {endpoint}: {path_mtu}");
+    let mtu = path_mtu.saturating_sub(overhead).max(MIN_MTU);
+    (mtu < current).then_some(mtu)
+}
+
+/// Clamp the MSS of TCP SYN packets leaving through the tunnel to its MTU
+pub fn clamp_mss(
+    netns: &NetworkNamespace,
+    interface: &str,
+    firewall: Firewall,
+) -> anyhow::Result<()> {
+    match firewall {
+        Firewall::IpTables => {
+            for iptables in ["iptables", "ip6tables"] {
+                NetworkNamespace::exec(
+                    &netns.name,
+                    &[
+                        iptables,
+                        "-t",
+                        "mangle",
+                        "-A",
+                        "POSTROUTING",
+                        "-o",
+                        interface,
+                        "-p",
+                        "tcp",
+                        "--tcp-flags",
+                        "SYN,RST",
+                        "SYN",
+                        "-j",
+                        "TCPMSS",
+                        "--clamp-mss-to-pmtu",
+                    ],
+                )
+                .with_context(|| format!("Failed to add {iptables} MSS clamping rule"))?;
+            }
+        }
+        Firewall::NfTables => {
+            NetworkNamespace::exec(&netns.name, &["nft", "add", "table", "inet", NFT_TABLE])?;
+            NetworkNamespace::exec(
+                &netns.name,
+                &[
+                    "nft",
+                    "add",
+                    "chain",
+                    "inet",
+                    NFT_TABLE,
+                    "postrouting",
+                    "{ type filter hook postrouting priority mangle ; }",
+                ],
+            )?;
+            NetworkNamespace::exec(
+                &netns.name,
+                &[
+                    "nft",
+                    "add",
+                    "rule",
+                    "inet",
+                    NFT_TABLE,
+                    "postrouting",
+                    "oifname",
+                    interface,
+                    "tcp",
+                    "flags",
+                    "syn",
+                    "/",
+                    "syn,rst",
+                    "tcp",
+                    "option",
+                    "maxseg",
+                    "size",
+                    "set",
+                    "rt",
+                    "mtu",
+                ],
+            )
+            .context("Failed to add nftables MSS clamping rule")?;
+        }
+    }
+    debug!("Clamping TCP MSS to the MTU of {interface}");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn path_mtu_search() {
+        assert_eq!(search_path_mtu(1480, |size| size <= 1500), Some(1480));
+        assert_eq!(search_path_mtu(1480, |size| size <= 1400), Some(1400));
+        assert_eq!(search_path_mtu(1480, |size| size <= MIN_MTU), Some(MIN_MTU));
+        assert_eq!(search_path_mtu(1480, |_| false), None);
+        assert_eq!(search_path_mtu(u16::MAX, |size| size <= 9000), Some(9000));
+    }
+}
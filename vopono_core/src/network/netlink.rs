@@ -0,0 +1,265 @@
+// Network namespace, link, address and route setup over rtnetlink instead of the ip binary
+// Requests are sent on a netlink socket which belongs to the network namespace it was opened in,
+// so Netlink::in_namespace opens it on a thread which has joined that namespace. Namespaces
+// themselves are created as ip netns does: a bind mount of the namespace of a thread which
+// unshared its network namespace, under /run/netns.
+
+use crate::util::run_in_netns;
+use anyhow::{Context, anyhow};
+use futures::TryStreamExt;
+use ipnet::IpNet;
+use log::debug;
+use nix::errno::Errno;
+use nix::mount::{MntFlags, MsFlags, mount, umount2};
+use nix::sched::{CloneFlags, unshare};
+use rtnetlink::packet_route::address::{AddressAttribute, AddressFlags};
+use rtnetlink::{Handle, LinkBridge, LinkUnspec, LinkVeth, RouteMessageBuilder};
+use std::fs::File;
+use std::net::IpAddr;
+use std::os::fd::AsRawFd;
+use std::path::{Path, PathBuf};
+use tokio::runtime::Runtime;
+
+pub const NETNS_RUN_DIR: &str = "/run/netns";
+
+/// Blocking wrapper around an rtnetlink connection
+pub struct Netlink {
+    runtime: Runtime,
+    handle: Handle,
+}
+
+impl Netlink {
+    /// Connection in the network namespace of the calling thread, i.e. the host
+    pub fn host() -> anyhow::Result<Self> {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_io()
+            .build()?;
+        let handle = {
+            // The socket is registered with the runtime when it is created
+            let _guard = runtime.enter();
+            let (connection, handle, _) =
+                rtnetlink::new_connection().context("Failed to open netlink socket")?;
+            runtime.spawn(connection);
+            handle
+        };
+        Ok(Self { runtime, handle })
+    }
+
+    /// Connection in the given network namespace
+    pub fn in_namespace(netns_name: &str) -> anyhow::Result<Self> {
+        run_in_netns(netns_name, Self::host)
+            .with_context(|| format!("Failed to open netlink socket in {netns_name}"))
+    }
+
+    pub fn link_index(&self, name: &str) -> anyhow::Result<u32> {
+        self.runtime
+            .block_on(
+                self.handle
+                    .link()
+                    .get()
+                    .match_name(name.to_string())
+                    .execute()
+                    .try_next(),
+            )
+            .with_context(|| format!("Failed to find interface {name}"))?
+            .map(|link| link.header.index)
+            .ok_or_else(|| anyhow!("No interface named {name}"))
+    }
+
+    pub fn set_up(&self, name: &str) -> anyhow::Result<()> {
+        let index = self.link_index(name)?;
+        self.runtime
+            .block_on(
+                self.handle
+                    .link()
+                    .set(LinkUnspec::new_with_index(index).up().build())
+                    .execute(),
+            )
+            .with_context(|| format!("Failed to bring up {name}"))
+    }
+
+    pub fn add_veth_pair(&self, name: &str, peer: &str) -> anyhow::Result<()> {
+        self.runtime
+            .block_on(
+                self.handle
+                    .link()
+                    .add(LinkVeth::new(name, peer).build())
+                    .execute(),
+            )
+            .with_context(|| format!("Failed to create veth pair {name}, {peer}"))
+    }
+
+    pub fn add_bridge(&self, name: &str) -> anyhow::Result<()> {
+        self.runtime
+            .block_on(
+                self.handle
+                    .link()
+                    .add(LinkBridge::new(name).build())
+                    .execute(),
+            )
+            .with_context(|| format!("Failed to create bridge {name}"))
+    }
+
+    /// Attach the interface to a bridge, as ip link set master
+    pub fn set_controller(&self, name: &str, bridge: &str) -> anyhow::Result<()> {
+        let index = self.link_index(name)?;
+        let bridge_index = self.link_index(bridge)?;
+        self.runtime
+            .block_on(
+                self.handle
+                    .link()
+                    .set(
+                        LinkUnspec::new_with_index(index)
+                            .controller(bridge_index)
+                            .build(),
+                    )
+                    .execute(),
+            )
+            .with_context(|| format!("Failed to attach {name} to bridge {bridge}"))
+    }
+
+    pub fn move_to_namespace(&self, name: &str, netns_name: &str) -> anyhow::Result<()> {
+        let index = self.link_index(name)?;
+        let netns = File::open(namespace_path(netns_name))
+            .with_context(|| format!("Failed to open network namespace {netns_name}"))?;
+        self.runtime
+            .block_on(
+                self.handle
+                    .link()
+                    .set(
+                        LinkUnspec::new_with_index(index)
+                            .setns_by_fd(netns.as_raw_fd())
+                            .build(),
+                    )
+                    .execute(),
+            )
+            .with_context(|| format!("Failed to move {name} to network namespace {netns_name}"))
+    }
+
+    pub fn delete_link(&self, name: &str) -> anyhow::Result<()> {
+        let index = self.link_index(name)?;
+        self.runtime
+            .block_on(self.handle.link().del(index).execute())
+            .with_context(|| format!("Failed to delete interface {name}"))
+    }
+
+    /// Add an address, with nodad so IPv6 addresses are usable immediately
+    pub fn add_address(&self, name: &str, address: IpNet, nodad: bool) -> anyhow::Result<()> {
+        let index = self.link_index(name)?;
+        let mut request = self
+            .handle
+            .address()
+            .add(index, address.addr(), address.prefix_len());
+        if nodad {
+            request
+                .message_mut()
+                .attributes
+                .push(AddressAttribute::Flags(AddressFlags::Nodad));
+        }
+        self.runtime
+            .block_on(request.execute())
+            .with_context(|| format!("Failed to add address {address} to {name}"))
+    }
+
+    /// Route destination (the default route if None) via gateway on the interface
+    pub fn add_route(
+        &self,
+        name: &str,
+        destination: Option<IpNet>,
+        gateway: IpAddr,
+    ) -> anyhow::Result<()> {
+        let index = self.link_index(name)?;
+        let destination = destination.unwrap_or(match gateway {
+            IpAddr::V4(_) => "0.0.0.0/0".parse()?,
+            IpAddr::V6(_) => "::/0".parse()?,
+        });
+        let route = RouteMessageBuilder::<IpAddr>::new()
+            .destination_prefix(destination.addr(), destination.prefix_len())?
+            .gateway(gateway)?
+            .output_interface(index)
+            .build();
+        self.runtime
+            .block_on(self.handle.route().add(route).execute())
+            .with_context(|| format!("Failed to add route {destination} via {gateway} on {name}"))
+    }
+}
+
+pub fn namespace_path(netns_name: &str) -> PathBuf {
+    Path::new(NETNS_RUN_DIR).join(netns_name)
+}
+
+/// Make /run/netns a shared mount point, so namespaces mounted in it are also visible in other
+/// mount namespaces
+fn share_run_dir() -> anyhow::Result<()> {
+    std::fs::create_dir_all(NETNS_RUN_DIR)?;
+    let shared = MsFlags::MS_SHARED | MsFlags::MS_REC;
+    match mount(
+        None::<&str>,
+        NETNS_RUN_DIR,
+        Some("none"),
+        shared,
+        None::<&str>,
+    ) {
+        // Not a mount point yet
+        Err(Errno::EINVAL) => {
+            mount(
+                Some(NETNS_RUN_DIR),
+                NETNS_RUN_DIR,
+                Some("none"),
+                MsFlags::MS_BIND | MsFlags::MS_REC,
+                None::<&str>,
+            )?;
+            mount(
+                None::<&str>,
+                NETNS_RUN_DIR,
+                Some("none"),
+                shared,
+                None::<&str>,
+            )?;
+        }
+        result => result?,
+    }
+    Ok(())
+}
+
+pub fn add_namespace(netns_name: &str) -> anyhow::Result<()> {
+    share_run_dir().with_context(|| format!("Failed to mount {NETNS_RUN_DIR}"))?;
+    let path = namespace_path(netns_name);
+    File::create_new(&path)
+        .with_context(|| format!("Network namespace {netns_name} already exists"))?;
+    let target = path.clone();
+    // unshare only moves the calling thread to the new namespace
+    let result = std::thread::spawn(move || -> anyhow::Result<()> {
+        unshare(CloneFlags::CLONE_NEWNET)?;
+        mount(
+            Some("/proc/thread-self/ns/net"),
+            &target,
+            Some("none"),
+            MsFlags::MS_BIND,
+            None::<&str>,
+        )?;
+        Ok(())
+    })
+    .join()
+    .map_err(|_| anyhow!("Thread creating network namespace panicked"))?;
+    if result.is_err() {
+        std::fs::remove_file(&path).ok();
+    }
+    result.with_context(|| format!("Failed to create network namespace {netns_name}"))
+}
+
+pub fn delete_namespace(netns_name: &str) -> anyhow::Result<()> {
+    let path = namespace_path(netns_name);
+    // The namespace is freed once no processes are left in it
+    match umount2(&path, MntFlags::MNT_DETACH) {
+        Ok(()) | Err(Errno::EINVAL) => {}
+        Err(e) => {
+            return Err(e)
+                .with_context(|| format!("Failed to unmount network namespace {netns_name}"));
+        }
+    }
+    std::fs::remove_file(&path)
+        .with_context(|| format!("Failed to delete network namespace {netns_name}"))?;
+    debug!("Deleted network namespace {netns_name}");
+    Ok(())
+}
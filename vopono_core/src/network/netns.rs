@@ -1,29 +1,49 @@
+use super::bridge::{BRIDGE_GATEWAY, BRIDGE_NAME, Bridge};
 use super::dns_config::DnsConfig;
+use super::dns_leak::public_addresses;
+use super::dns_rules::{DnsForward, DnsOverride};
+use super::dnscrypt_proxy::DnscryptProxy;
 use super::firewall::Firewall;
 use super::host_masquerade::HostMasquerade;
+use super::ikev2::Ikev2;
+use super::leak_audit::LeakAudit;
+use super::netlink::{self, Netlink};
 use super::network_interface::NetworkInterface;
+use super::obfs4::{Obfs4Bridge, Obfs4Proxy};
+use super::obfuscation::{Obfuscation, ObfuscationProtocol};
 use super::openconnect::OpenConnect;
 use super::openfortivpn::OpenFortiVpn;
-use super::openvpn::OpenVpn;
-use super::shadowsocks::Shadowsocks;
+use super::openvpn::{ClientCert, OpenVpn, OpenVpnAuthFailed, OpenVpnOption, SocksProxy};
+use super::resolved::{ResolvedLink, host_uses_resolved};
+use super::shadowsocks::{SHADOWSOCKS_LOCAL_PORT, Shadowsocks, ShadowsocksServer};
+use super::stub_resolver::{DnsUpstream, STUB_ADDRESS, bootstrap_upstreams};
+use super::tor::Tor;
 use super::trojan::TrojanHost;
 use super::trojan::trojan_exec::Trojan;
+use super::upstream_proxy::{Redsocks, UpstreamProxy};
 use super::veth_pair::VethPair;
 use super::warp::Warp;
-use super::wireguard::{Wireguard, WireguardPeer};
+use super::wireguard::{Wireguard, WireguardImplementation, WireguardPeer};
+use super::xray::Xray;
 use crate::config::providers::{UiClient, VpnProvider};
 use crate::config::vpn::Protocol;
 use crate::network::host_masquerade::FirewallException;
-use crate::util::{config_dir, parse_command_str, set_config_permissions, sudo_command};
+use crate::util::events::{Event, emit};
+use crate::util::hooks::{Hook, hook_command};
+use crate::util::private_files::{is_private_temp_file, remove_private_temp_file};
+use crate::util::{config_dir, dry_run, run_in_netns, set_config_permissions, veth_address};
 use anyhow::{Context, anyhow};
+use ipnet::IpNet;
 use log::{debug, info, warn};
 use nix::unistd;
 use serde::{Deserialize, Serialize};
 use std::fs::File;
 use std::io::Write;
-use std::net::IpAddr;
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
+use std::os::unix::process::ExitStatusExt;
 use std::path::{Path, PathBuf};
-use std::process::{Command, Output, Stdio};
+use std::process::{Command, ExitStatus, Output, Stdio};
+use std::sync::{Mutex, PoisonError};
 use std::time::{SystemTime, UNIX_EPOCH};
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -46,14 +66,89 @@ pub struct NetworkNamespace {
     pub predown: Option<String>,
     pub predown_user: Option<String>,
     pub predown_group: Option<String>,
+    #[serde(default)]
+    pub postdown: Option<String>,
     pub config_file: Option<PathBuf>, // Used to save config file path in lockfile
     pub trojan: Option<Trojan>,
+    pub obfuscation: Option<Obfuscation>,
+    #[serde(default)]
+    pub obfs4: Option<Obfs4Proxy>,
+    #[serde(default)]
+    pub xray: Option<Xray>,
+    #[serde(default)]
+    pub redsocks: Option<Redsocks>,
+    #[serde(default)]
+    pub tor: Option<Tor>,
+    #[serde(default)]
+    pub ikev2: Option<Ikev2>,
+    pub multihop: Option<MultihopServers>,
+    pub socks_proxy: Option<SocketAddr>,
+    /// Encrypted DNS upstreams used by the stub resolver of each instance
+    #[serde(default)]
+    pub dns_upstreams: Vec<DnsUpstream>,
+    #[serde(default)]
+    pub dns_overrides: Vec<DnsOverride>,
+    #[serde(default)]
+    pub dns_forwards: Vec<DnsForward>,
+    #[serde(default)]
+    pub dnscrypt_proxy: Option<DnscryptProxy>,
+    /// Host veth registered with systemd-resolved, when using --systemd-resolved
+    #[serde(default)]
+    pub resolved_link: Option<ResolvedLink>,
+    /// Rules recording packets leaving outside the tunnel, when using --audit-leaks
+    #[serde(default)]
+    pub leak_audit: Option<LeakAudit>,
+    /// Temporary files holding secrets (ephemeral Wireguard configs, OpenVPN credentials from
+    /// the keyring), removed on shutdown
+    #[serde(default)]
+    pub temp_files: Vec<PathBuf>,
+    /// Host bridge the veth pair is attached to, when using --bridge
+    #[serde(default)]
+    pub bridge: Option<Bridge>,
+    /// iptables commands removing the killswitch rules of the VPN client, run when switching
+    /// server
+    #[serde(skip)]
+    pub protocol_firewall_undo: Vec<Vec<String>>,
+}
+
+/// Entry and exit servers when connected via multihop
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct MultihopServers {
+    pub entry: String,
+    pub exit: String,
+}
+
+impl std::fmt::Display for MultihopServers {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} -> {}", self.entry, self.exit)
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct VethPairIPs {
     pub host_ip: IpAddr,
     pub namespace_ip: IpAddr,
+    /// IPv6 (ULA) addresses on the veth pair, only set with --ipv6
+    #[serde(default)]
+    pub host_ipv6: Option<IpAddr>,
+    #[serde(default)]
+    pub namespace_ipv6: Option<IpAddr>,
+}
+
+/// Check a network namespace name is usable as a file name in /run/netns
+pub fn validate_namespace_name(name: &str) -> anyhow::Result<()> {
+    if name.is_empty() || name.len() > 255 || name == "." || name == ".." {
+        return Err(anyhow!("Invalid network namespace name: {name:?}"));
+    }
+    if !name
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.'))
+    {
+        return Err(anyhow!(
+            "Network namespace name may only contain letters, digits, '_', '-' and '.': {name}"
+        ));
+    }
+    Ok(())
 }
 
 impl NetworkNamespace {
@@ -76,8 +171,7 @@ impl NetworkNamespace {
                 "No lockfile found for namespace: {} - deleting namespace",
                 &name
             );
-            sudo_command(&["ip", "netns", "delete", &name])
-                .with_context(|| format!("Failed to delete network namespace: {}", &name))?;
+            netlink::delete_namespace(&name)?;
             Err(anyhow!(
                 "No lockfile found for namespace: {} - deleting namespace",
                 &name
@@ -94,9 +188,12 @@ impl NetworkNamespace {
         predown_user: Option<String>,
         predown_group: Option<String>,
     ) -> anyhow::Result<Self> {
-        sudo_command(&["ip", "netns", "add", name.as_str()])
-            .with_context(|| format!("Failed to create network namespace: {}", &name))?;
-        info!("Created new network namespace: {}", &name);
+        if dry_run::is_dry_run() {
+            dry_run::record(dry_run::command_line(&["ip", "netns", "add", &name]));
+        } else {
+            netlink::add_namespace(&name)?;
+            info!("Created new network namespace: {}", &name);
+        }
 
         Ok(Self {
             name,
@@ -117,11 +214,65 @@ impl NetworkNamespace {
             predown,
             predown_user,
             predown_group,
+            postdown: None,
             config_file: None,
             trojan: None,
+            obfuscation: None,
+            obfs4: None,
+            xray: None,
+            redsocks: None,
+            tor: None,
+            ikev2: None,
+            multihop: None,
+            socks_proxy: None,
+            dns_upstreams: Vec::new(),
+            dns_overrides: Vec::new(),
+            dns_forwards: Vec::new(),
+            dnscrypt_proxy: None,
+            resolved_link: None,
+            leak_audit: None,
+            temp_files: Vec::new(),
+            bridge: None,
+            protocol_firewall_undo: Vec::new(),
         })
     }
 
+    /// Remove the killswitch rules of the VPN client so the next tunnel adds its own. With
+    /// nftables they are in the table named after the namespace, along with the exceptions for
+    /// open hosts, the LAN and forwarded ports which are added again for the new tunnel.
+    pub fn remove_protocol_firewall(&mut self) {
+        match self.firewall {
+            Firewall::NfTables => {
+                if let Err(e) =
+                    Self::exec_checked(&self.name, &["nft", "delete", "table", "inet", &self.name])
+                {
+                    debug!("No killswitch table to remove: {e:#}");
+                }
+            }
+            Firewall::IpTables => {
+                for command in self.protocol_firewall_undo.drain(..) {
+                    let command: Vec<&str> = command.iter().map(|x| x.as_str()).collect();
+                    if let Err(e) = Self::exec_checked(&self.name, &command) {
+                        warn!("Failed to remove killswitch rule: {e:#}");
+                    }
+                }
+            }
+        }
+    }
+
+    /// Runs the command, failing with its stderr if it exits unsuccessfully
+    fn exec_checked(netns_name: &str, command: &[&str]) -> anyhow::Result<()> {
+        let output = Self::exec_with_output(netns_name, command)?;
+        if !output.status.success() {
+            return Err(anyhow!(
+                "{} failed: {}",
+                command.join(" "),
+                String::from_utf8_lossy(&output.stderr).trim()
+            ));
+        }
+        Ok(())
+    }
+
     pub fn set_config_file(&mut self, config_file: Option<PathBuf>) {
         self.config_file = config_file;
     }
@@ -185,32 +336,77 @@ impl NetworkNamespace {
     }
 
     pub fn exec(netns_name: &str, command: &[&str]) -> anyhow::Result<()> {
+        if dry_run::is_dry_run() {
+            Self::record_exec(netns_name, command);
+            return Ok(());
+        }
         Self::exec_no_block(netns_name, command, None, None, false, false, false, None)?.wait()?;
         Ok(())
     }
 
     pub fn exec_with_output(netns_name: &str, command: &[&str]) -> anyhow::Result<Output> {
+        if dry_run::is_dry_run() {
+            Self::record_exec(netns_name, command);
+            return Ok(Output {
+                status: ExitStatus::from_raw(0),
+                stdout: Vec::new(),
+                stderr: Vec::new(),
+            });
+        }
         Self::exec_no_block(netns_name, command, None, None, false, true, false, None)?
             .wait_with_output()
             .map_err(|e| anyhow!("Process Output error: {e:?}"))
     }
 
+    fn record_exec(netns_name: &str, command: &[&str]) {
+        let mut line = vec!["ip", "netns", "exec", netns_name];
+        line.extend(command);
+        dry_run::record(dry_run::command_line(&line));
+    }
+
     pub fn add_loopback(&self) -> anyhow::Result<()> {
-        Self::exec(
-            &self.name,
-            &["ip", "addr", "add", "127.0.0.1/8", "dev", "lo"],
-        )
-        .with_context(|| format!("Failed to add loopback adapter in netns: {}", &self.name))?;
-        Self::exec(&self.name, &["ip", "link", "set", "lo", "up"])
+        let netlink = Netlink::in_namespace(&self.name)?;
+        netlink
+            .add_address("lo", "127.0.0.1/8".parse()?, false)
+            .with_context(|| format!("Failed to add loopback adapter in netns: {}", &self.name))?;
+        netlink
+            .set_up("lo")
             .with_context(|| format!("Failed to start networking in netns: {}", &self.name))?;
         Ok(())
     }
 
-    pub fn add_veth_pair(&mut self) -> anyhow::Result<()> {
-        // TODO: Handle if name taken?
-        let source = format!("{}_s", self.name);
-        let dest = format!("{}_d", self.name);
-        self.veth_pair = Some(VethPair::new(source, dest, self)?);
+    /// Disable IPv6 on all interfaces of the namespace, including those created later (e.g. the
+    /// VPN tunnel)
+    pub fn disable_ipv6(&self) -> anyhow::Result<()> {
+        run_in_netns(&self.name, || {
+            for conf in ["all", "default"] {
+                let path = format!("/proc/sys/net/ipv6/conf/{conf}/disable_ipv6");
+                std::fs::write(&path, "1").with_context(|| format!("Failed to write {path}"))?;
+            }
+            Ok(())
+        })
+        .with_context(|| format!("Failed to disable IPv6 in netns: {}", &self.name))?;
+        debug!("Disabled IPv6 in netns: {}", &self.name);
+        Ok(())
+    }
+
+    /// Create the veth pair, named {namespace}_d (host end) and {namespace}_s (namespace end)
+    /// unless names are given
+    pub fn add_veth_pair(
+        &mut self,
+        host_name: Option<String>,
+        netns_name: Option<String>,
+    ) -> anyhow::Result<()> {
+        let source = netns_name.unwrap_or_else(|| format!("{}_s", self.name));
+        let dest = host_name.unwrap_or_else(|| format!("{}_d", self.name));
+        let veth_pair = VethPair::new(source, dest, self)?;
+        if let Err(e) = veth_pair.add_traffic_counters(self) {
+            warn!(
+                "Failed to add traffic counters for {}: {e:?}",
+                veth_pair.source
+            );
+        }
+        self.veth_pair = Some(veth_pair);
         Ok(())
     }
 
@@ -219,6 +415,7 @@ impl NetworkNamespace {
         target_subnet: u8,
         hosts: Option<&Vec<IpAddr>>,
         allow_host_access: bool,
+        ipv6: bool,
     ) -> anyhow::Result<()> {
         // TODO: Handle case where IP address taken in better way i.e. don't just change subnet
         let veth_dest = &self
@@ -233,71 +430,78 @@ impl NetworkNamespace {
             .expect("Source veth undefined")
             .source;
 
-        let ip = format!("10.200.{target_subnet}.1/24");
-        let ip_nosub = format!("10.200.{target_subnet}.1");
-        let veth_source_ip = format!("10.200.{target_subnet}.2/24");
-        let veth_source_ip_nosub = format!("10.200.{target_subnet}.2");
-
-        sudo_command(&["ip", "addr", "add", &ip, "dev", veth_dest]).with_context(|| {
-            format!("Failed to assign static IP to veth destination: {veth_dest}")
-        })?;
-
-        Self::exec(
-            &self.name,
-            &["ip", "addr", "add", &veth_source_ip, "dev", veth_source],
-        )
-        .with_context(|| format!("Failed to assign static IP to veth source: {veth_source}"))?;
-        Self::exec(
-            &self.name,
-            &[
-                "ip",
-                "route",
-                "add",
-                "default",
-                "via",
-                &ip_nosub,
-                "dev",
-                veth_source,
-            ],
-        )
-        .with_context(|| format!("Failed to assign static IP to veth source: {veth_source}"))?;
+        let ip_nosub = veth_address(target_subnet, 1).to_string();
+        let ip = format!("{ip_nosub}/24");
+        let veth_source_ip_nosub = veth_address(target_subnet, 2).to_string();
+        let veth_source_ip = format!("{veth_source_ip_nosub}/24");
+
+        let host_netlink = Netlink::host()?;
+        let netlink = Netlink::in_namespace(&self.name)?;
+        host_netlink
+            .add_address(veth_dest, ip.parse()?, false)
+            .with_context(|| {
+                format!("Failed to assign static IP to veth destination: {veth_dest}")
+            })?;
+
+        netlink
+            .add_address(veth_source, veth_source_ip.parse()?, false)
+            .with_context(|| format!("Failed to assign static IP to veth source: {veth_source}"))?;
+        netlink
+            .add_route(veth_source, None, ip_nosub.parse()?)
+            .with_context(|| format!("Failed to assign static IP to veth source: {veth_source}"))?;
+
+        // IPv6 uses a ULA subnet per namespace, NATed on the host like IPv4
+        let ipv6_gateway = if ipv6 {
+            let ip6 = format!("{}/64", Self::veth_ipv6(target_subnet, 1));
+            let veth_source_ip6 = format!("{}/64", Self::veth_ipv6(target_subnet, 2));
+            // nodad so the addresses are usable immediately
+            host_netlink
+                .add_address(veth_dest, ip6.parse()?, true)
+                .with_context(|| {
+                    format!("Failed to assign static IPv6 address to veth destination: {veth_dest}")
+                })?;
+            netlink
+                .add_address(veth_source, veth_source_ip6.parse()?, true)
+                .with_context(|| {
+                    format!("Failed to assign static IPv6 address to veth source: {veth_source}")
+                })?;
+            let gateway = Self::veth_ipv6(target_subnet, 1).to_string();
+            netlink
+                .add_route(veth_source, None, gateway.parse()?)
+                .with_context(|| format!("Failed to add IPv6 default route via: {gateway}"))?;
+            Some(gateway)
+        } else {
+            None
+        };
 
         // Here we add direct routes for open_hosts
         // Note this is not done for all open_hosts calls as the ProtonVPN port forwarding
         // traffic still needs to go through the VPN
         if let Some(my_hosts) = hosts {
             for host in my_hosts {
-                Self::exec(
-                    &self.name,
-                    &[
-                        "ip",
-                        "route",
-                        "add",
-                        &host.to_string(),
-                        "via",
-                        &ip_nosub,
-                        "dev",
-                        veth_source,
-                    ],
-                )
-                .with_context(|| {
-                    format!("Failed to assign hosts route {host} to veth source: {veth_source}")
-                })?;
+                let gateway = match (host, ipv6_gateway.as_ref()) {
+                    (IpAddr::V4(_), _) => &ip_nosub,
+                    (IpAddr::V6(_), Some(gateway)) => gateway,
+                    (IpAddr::V6(_), None) => {
+                        warn!(
+                            "Skipping route for IPv6 host {host}, use --ipv6 to route IPv6 via the host"
+                        );
+                        continue;
+                    }
+                };
+                netlink
+                    .add_route(veth_source, Some(IpNet::from(*host)), gateway.parse()?)
+                    .with_context(|| {
+                        format!("Failed to assign hosts route {host} to veth source: {veth_source}")
+                    })?;
             }
         }
 
         if allow_host_access {
-            Self::exec(&self.name, &[
-                "ip",
-                "route",
-                "add",
-                &ip_nosub,
-                "via",
-                &ip_nosub,
-                "dev",
-                veth_source,
-            ])
-            .with_context(|| {
+            let host_ip: IpAddr = ip_nosub.parse()?;
+            netlink
+                .add_route(veth_source, Some(IpNet::from(host_ip)), host_ip)
+                .with_context(|| {
                 format!(
                     "Failed to assign hosts route for local host {ip_nosub} to veth source: {veth_source}"
                 )
@@ -306,13 +510,104 @@ impl NetworkNamespace {
 
         info!("IP address of namespace as seen from host: {veth_source_ip_nosub}");
         info!("IP address of host as seen from namespace: {ip_nosub}");
+        if ipv6 {
+            info!(
+                "IPv6 address of namespace as seen from host: {}",
+                Self::veth_ipv6(target_subnet, 2)
+            );
+        }
         self.veth_pair_ips = Some(VethPairIPs {
             host_ip: ip_nosub.parse()?,
             namespace_ip: veth_source_ip_nosub.parse()?,
+            host_ipv6: ipv6.then(|| IpAddr::V6(Self::veth_ipv6(target_subnet, 1))),
+            namespace_ipv6: ipv6.then(|| IpAddr::V6(Self::veth_ipv6(target_subnet, 2))),
+        });
+        Ok(())
+    }
+
+    /// Route local subnets (--allow-lan) via the host instead of the tunnel
+    pub fn add_lan_routes(&self, subnets: &[IpNet]) -> anyhow::Result<()> {
+        let veth_source = &self
+            .veth_pair
+            .as_ref()
+            .expect("Source veth undefined")
+            .source;
+        let veth_ips = self.veth_pair_ips.as_ref().expect("veth IPs undefined");
+        let netlink = Netlink::in_namespace(&self.name)?;
+        for subnet in subnets {
+            let gateway = match subnet {
+                IpNet::V4(_) => veth_ips.host_ip,
+                IpNet::V6(_) => match veth_ips.host_ipv6 {
+                    Some(gateway) => gateway,
+                    None => {
+                        warn!(
+                            "Skipping route for IPv6 LAN subnet {subnet}, use --ipv6 to route IPv6 via the host"
+                        );
+                        continue;
+                    }
+                },
+            };
+            netlink
+                .add_route(veth_source, Some(*subnet), gateway)
+                .with_context(|| format!("Failed to add route for LAN subnet {subnet}"))?;
+            info!("Allowing LAN access to {subnet} from network namespace");
+        }
+        Ok(())
+    }
+
+    /// Attach the veth pair to the shared vopono bridge instead of assigning it its own subnet.
+    /// The bridge handles masquerading and firewall exceptions for all attached namespaces.
+    pub fn add_bridge_routing(
+        &mut self,
+        hosts: Option<&Vec<IpAddr>>,
+        interface: NetworkInterface,
+        firewall: Firewall,
+    ) -> anyhow::Result<()> {
+        let veth_pair = self.veth_pair.as_ref().expect("veth pair undefined");
+        let veth_source = veth_pair.source.clone();
+        let (bridge, namespace_ip) =
+            Bridge::attach(self, &veth_pair.dest, &veth_source, interface, firewall)?;
+        self.bridge = Some(bridge);
+
+        let gateway = IpAddr::V4(BRIDGE_GATEWAY);
+        let netlink = Netlink::in_namespace(&self.name)?;
+        netlink
+            .add_route(&veth_source, None, gateway)
+            .with_context(|| format!("Failed to add default route via bridge: {gateway}"))?;
+
+        // Direct routes for open hosts, as in add_routing
+        if let Some(my_hosts) = hosts {
+            for host in my_hosts {
+                if host.is_ipv6() {
+                    warn!(
+                        "Skipping route for IPv6 host {host}, IPv6 is not routed over the bridge"
+                    );
+                    continue;
+                }
+                netlink
+                    .add_route(&veth_source, Some(IpNet::from(*host)), gateway)
+                    .with_context(|| {
+                        format!("Failed to assign hosts route {host} to veth source: {veth_source}")
+                    })?;
+            }
+        }
+
+        info!("IP address of namespace on bridge {BRIDGE_NAME}: {namespace_ip}");
+        info!("IP address of host as seen from namespace: {gateway}");
+        self.veth_pair_ips = Some(VethPairIPs {
+            host_ip: IpAddr::V4(BRIDGE_GATEWAY),
+            namespace_ip: IpAddr::V4(namespace_ip),
+            host_ipv6: None,
+            namespace_ipv6: None,
         });
         Ok(())
     }
 
+    /// Address in the namespace's IPv6 veth subnet fd00:200:{subnet}::/64
+    fn veth_ipv6(target_subnet: u8, host: u16) -> Ipv6Addr {
+        Ipv6Addr::new(0xfd00, 0x200, target_subnet as u16, 0, 0, 0, 0, host)
+    }
+
     pub fn dns_config(
         &mut self,
         server: &[IpAddr],
@@ -320,6 +615,10 @@ impl NetworkNamespace {
         hosts_entries: Option<&Vec<String>>,
         allow_host_access: bool,
     ) -> anyhow::Result<()> {
+        // The new config overwrites the files of the old one, which must not remove them
+        if let Some(old) = self.dns_config.take() {
+            old.keep();
+        }
         self.dns_config = Some(DnsConfig::new(
             self.name.clone(),
             server,
@@ -335,6 +634,81 @@ impl NetworkNamespace {
         Ok(())
     }
 
+    /// Point resolv.conf at the stub resolver, which forwards to the encrypted upstreams, or to
+    /// the current DNS servers if there are none
+    pub fn use_stub_resolver(
+        &mut self,
+        upstreams: Vec<DnsUpstream>,
+        overrides: Vec<DnsOverride>,
+        forwards: Vec<DnsForward>,
+        hosts_entries: Option<&Vec<String>>,
+        allow_host_access: bool,
+    ) -> anyhow::Result<()> {
+        // DoH server names are resolved with the DNS servers set up for the VPN first
+        let bootstrap = DnsConfig::nameservers(&self.name);
+        let upstreams = if upstreams.is_empty() {
+            if bootstrap.is_empty() {
+                return Err(anyhow!(
+                    "No DNS servers in network namespace {} to forward to",
+                    self.name
+                ));
+            }
+            bootstrap
+                .iter()
+                .map(|ip| DnsUpstream::Plain {
+                    address: SocketAddr::new(*ip, 53),
+                })
+                .collect()
+        } else {
+            bootstrap_upstreams(&self.name, upstreams, &bootstrap)?
+        };
+        self.dns_config(&[STUB_ADDRESS], &[], hosts_entries, allow_host_access)?;
+        self.dns_upstreams = upstreams;
+        self.dns_overrides = overrides;
+        self.dns_forwards = forwards;
+        Ok(())
+    }
+
+    /// Leave resolv.conf to systemd-resolved: the stub resolver answers on resolved's stub
+    /// address in the namespace and resolved stops multicast queries on the host veth
+    pub fn use_systemd_resolved(&mut self) -> anyhow::Result<()> {
+        if !host_uses_resolved() {
+            return Err(anyhow!(
+                "/etc/resolv.conf does not point at the systemd-resolved stub listener (127.0.0.53)"
+            ));
+        }
+        if self.dns_upstreams.is_empty() {
+            return Err(anyhow!(
+                "systemd-resolved integration needs the stub resolver of network namespace {}",
+                self.name
+            ));
+        }
+        let interface = &self
+            .veth_pair
+            .as_ref()
+            .ok_or_else(|| anyhow!("Network namespace {} has no veth pair", self.name))?
+            .dest;
+        let ifindex = Netlink::host()?.link_index(interface)?;
+        self.resolved_link = Some(ResolvedLink::new(interface, ifindex)?);
+        // ip netns exec only bind mounts the files which exist
+        let path = format!("/etc/netns/{}/resolv.conf", self.name);
+        std::fs::remove_file(&path).with_context(|| format!("Failed to remove {path}"))?;
+        Ok(())
+    }
+
+    /// Start dnscrypt-proxy in the namespace and point resolv.conf at it
+    pub fn run_dnscrypt_proxy(
+        &mut self,
+        server_names: &[String],
+        hosts_entries: Option<&Vec<String>>,
+        allow_host_access: bool,
+    ) -> anyhow::Result<()> {
+        let bootstrap = DnsConfig::nameservers(&self.name);
+        self.dnscrypt_proxy = Some(DnscryptProxy::run(self, server_names, &bootstrap)?);
+        self.dns_config(&[STUB_ADDRESS], &[], hosts_entries, allow_host_access)?;
+        Ok(())
+    }
+
     #[allow(clippy::too_many_arguments)]
     pub fn run_openvpn(
         &mut self,
@@ -347,19 +721,43 @@ impl NetworkNamespace {
         firewall: Firewall,
         disable_ipv6: bool,
         verbose: bool,
+        tcp: bool,
+        disable_dco: bool,
+        extra_options: &[OpenVpnOption],
+        client_cert: Option<&ClientCert>,
     ) -> anyhow::Result<()> {
-        self.openvpn = Some(OpenVpn::run(
-            self,
-            config_file,
-            auth_file,
-            dns,
-            use_killswitch,
-            open_ports,
-            forward_ports,
-            firewall,
-            disable_ipv6,
-            verbose,
-        )?);
+        let run = |ns: &Self| {
+            OpenVpn::run(
+                ns,
+                config_file.clone(),
+                auth_file.clone(),
+                dns,
+                use_killswitch,
+                open_ports,
+                forward_ports,
+                firewall,
+                disable_ipv6,
+                verbose,
+                // obfs4 only carries TCP
+                tcp || ns.obfs4.is_some(),
+                ns.socks_proxy(),
+                disable_dco,
+                extra_options,
+                client_cert,
+            )
+        };
+        let openvpn = match run(self) {
+            // The token was removed, so this uses the credentials
+            Err(e)
+                if e.downcast_ref::<OpenVpnAuthFailed>()
+                    .is_some_and(|x| x.auth_token) =>
+            {
+                warn!("{e}, authenticating with the credentials");
+                run(self)?
+            }
+            x => x?,
+        };
+        self.openvpn = Some(openvpn);
         Ok(())
     }
 
@@ -405,6 +803,28 @@ impl NetworkNamespace {
         Ok(())
     }
 
+    #[allow(clippy::too_many_arguments)]
+    pub fn run_ikev2(
+        &mut self,
+        config_file: &Path,
+        open_ports: Option<&Vec<u16>>,
+        forward_ports: Option<&Vec<u16>>,
+        firewall: Firewall,
+        verbose: bool,
+        uiclient: &dyn UiClient,
+    ) -> anyhow::Result<()> {
+        self.ikev2 = Some(Ikev2::run(
+            self,
+            config_file,
+            open_ports,
+            forward_ports,
+            firewall,
+            verbose,
+            uiclient,
+        )?);
+        Ok(())
+    }
+
     pub fn run_warp(
         &mut self,
         open_ports: Option<&Vec<u16>>,
@@ -415,6 +835,47 @@ impl NetworkNamespace {
         Ok(())
     }
 
+    pub fn run_xray(
+        &mut self,
+        config_file: &Path,
+        dns: IpAddr,
+        open_ports: Option<&Vec<u16>>,
+        firewall: Firewall,
+        verbose: bool,
+    ) -> anyhow::Result<()> {
+        self.xray = Some(Xray::run(
+            self,
+            config_file,
+            dns,
+            open_ports,
+            firewall,
+            verbose,
+        )?);
+        Ok(())
+    }
+
+    pub fn run_tor(
+        &mut self,
+        open_ports: Option<&Vec<u16>>,
+        firewall: Firewall,
+    ) -> anyhow::Result<()> {
+        self.tor = Some(Tor::run(self, open_ports, firewall)?);
+        Ok(())
+    }
+
+    pub fn run_upstream_proxy(
+        &mut self,
+        upstream: &UpstreamProxy,
+        open_ports: Option<&Vec<u16>>,
+        firewall: Firewall,
+        verbose: bool,
+    ) -> anyhow::Result<()> {
+        self.redsocks = Some(Redsocks::run(
+            self, upstream, open_ports, firewall, verbose,
+        )?);
+        Ok(())
+    }
+
     pub fn run_shadowsocks(
         &mut self,
         config_file: &Path,
@@ -434,6 +895,40 @@ impl NetworkNamespace {
         Ok(())
     }
 
+    /// SOCKS proxy for OpenVPN to connect through, if obfs4 or a Shadowsocks server of the user
+    /// is running
+    fn socks_proxy(&self) -> Option<SocksProxy> {
+        if let Some(obfs4) = self.obfs4.as_ref() {
+            return Some(SocksProxy {
+                port: obfs4.port,
+                server: obfs4.server,
+                auth_file: Some(obfs4.auth_file.clone()),
+                remote: Some(obfs4.server),
+            });
+        }
+        self.shadowsocks
+            .as_ref()
+            .and_then(|x| x.server)
+            .map(|server| SocksProxy {
+                port: SHADOWSOCKS_LOCAL_PORT,
+                server,
+                auth_file: None,
+                remote: None,
+            })
+    }
+
+    /// Launch obfs4proxy for the bridge, used by OpenVPN as a SOCKS proxy
+    pub fn run_obfs4(&mut self, bridge: &Obfs4Bridge) -> anyhow::Result<()> {
+        self.obfs4 = Some(Obfs4Proxy::run_in_netns(self, bridge)?);
+        Ok(())
+    }
+
+    /// Launch Shadowsocks for a server of the user, used by OpenVPN as a SOCKS proxy
+    pub fn run_shadowsocks_server(&mut self, server: &ShadowsocksServer) -> anyhow::Result<()> {
+        self.shadowsocks = Some(Shadowsocks::run_server(self, server)?);
+        Ok(())
+    }
+
     #[allow(clippy::too_many_arguments)]
     pub fn run_wireguard(
         &mut self,
@@ -446,11 +941,25 @@ impl NetworkNamespace {
         dns: Option<&Vec<IpAddr>>,
         hosts_entries: Option<&Vec<String>>,
         allow_host_access: bool,
+        ephemeral_key: bool,
+        implementation: WireguardImplementation,
     ) -> anyhow::Result<()> {
+        let mut config_file = config_file;
         if let Ok(wgprov) = self.provider.get_dyn_wireguard_provider() {
-            wgprov.wireguard_preup(config_file.as_path())?;
+            if ephemeral_key {
+                config_file = wgprov.wireguard_ephemeral_preup(config_file.as_path())?;
+                self.temp_files.push(config_file.clone());
+            } else {
+                wgprov.wireguard_preup(config_file.as_path())?;
+            }
         }
 
+        let endpoint_override = match (self.trojan.as_ref(), self.obfuscation.as_ref()) {
+            (Some(t), _) => Some(t.config.get_local_socketaddr()?),
+            (None, Some(o)) => Some(o.local_addr),
+            (None, None) => None,
+        };
+
         self.wireguard = Some(Wireguard::run(
             self,
             config_file,
@@ -462,7 +971,41 @@ impl NetworkNamespace {
             dns,
             hosts_entries,
             allow_host_access,
-            self.trojan.as_ref().map(|t| t.config.clone()),
+            endpoint_override,
+            implementation,
+        )?);
+        Ok(())
+    }
+
+    /// Launch obfuscation proxy for the Wireguard endpoint in the given config
+    /// Must be run before run_wireguard
+    #[allow(clippy::too_many_arguments)]
+    pub fn run_obfuscation(
+        &mut self,
+        protocol: ObfuscationProtocol,
+        config_file: &Path,
+        port: Option<u16>,
+        shadowsocks_password: &str,
+        shadowsocks_method: &str,
+        server: Option<&str>,
+        key: Option<&str>,
+    ) -> anyhow::Result<()> {
+        let config = Wireguard::config_from_file(config_file)?;
+        let endpoint = SocketAddr::new(
+            config.peer.endpoint.resolve_ip()?,
+            config.peer.endpoint.port(),
+        );
+        // TODO: Here we hardcode default Wireguard fwmark as in Wireguard::run
+        self.obfuscation = Some(Obfuscation::run_in_netns(
+            self,
+            protocol,
+            endpoint,
+            port,
+            "51820",
+            shadowsocks_password,
+            shadowsocks_method,
+            server,
+            key,
         )?);
         Ok(())
     }
@@ -492,8 +1035,14 @@ impl NetworkNamespace {
         interface: NetworkInterface,
         firewall: Firewall,
     ) -> anyhow::Result<()> {
+        let ipv6_mask = self
+            .veth_pair_ips
+            .as_ref()
+            .and_then(|x| x.host_ipv6)
+            .map(|_| format!("{}/64", Self::veth_ipv6(target_subnet, 0)));
         self.host_masquerade = Some(HostMasquerade::add_masquerade_rule(
-            format!("10.200.{target_subnet}.0/24"),
+            format!("{}/24", veth_address(target_subnet, 0)),
+            ipv6_mask,
             interface,
             firewall,
         )?);
@@ -507,10 +1056,15 @@ impl NetworkNamespace {
         ns_interface: NetworkInterface,
         firewall: Firewall,
     ) -> anyhow::Result<()> {
+        let ipv6 = self
+            .veth_pair_ips
+            .as_ref()
+            .is_some_and(|x| x.host_ipv6.is_some());
         self.firewall_exception = Some(FirewallException::add_firewall_exception(
             host_interface,
             ns_interface,
             firewall,
+            ipv6,
         )?);
 
         Ok(())
@@ -525,10 +1079,62 @@ impl NetworkNamespace {
         if let Some(ref veth_pair_ips) = self.veth_pair_ips {
             cmd.env("VOPONO_NS_IP", veth_pair_ips.namespace_ip.to_string());
             cmd.env("VOPONO_HOST_IP", veth_pair_ips.host_ip.to_string());
+            if let Some(ip) = veth_pair_ips.namespace_ipv6 {
+                cmd.env("VOPONO_NS_IPV6", ip.to_string());
+            }
+        }
+        if let Some(proxy) = self.socks_proxy.as_ref() {
+            cmd.env("VOPONO_SOCKS5_PROXY", format!("socks5://{proxy}"));
+        }
+    }
+
+    /// Forget the VPN client and helper processes without killing them by their recorded PIDs,
+    /// for namespaces whose processes have already been killed (when cleaning up after a crash
+    /// the PIDs may have been reused)
+    pub fn forget_processes(&mut self) {
+        std::mem::forget(self.openvpn.take());
+        std::mem::forget(self.warp.take());
+        std::mem::forget(self.shadowsocks.take());
+        std::mem::forget(self.openconnect.take());
+        std::mem::forget(self.openfortivpn.take());
+        std::mem::forget(self.trojan.take());
+        std::mem::forget(self.obfuscation.take());
+        std::mem::forget(self.obfs4.take());
+        std::mem::forget(self.xray.take());
+        std::mem::forget(self.redsocks.take());
+        std::mem::forget(self.tor.take());
+        std::mem::forget(self.ikev2.take());
+        std::mem::forget(self.dnscrypt_proxy.take());
+    }
+
+    /// Spawn a teardown hook script, a failure to start it must not stop the teardown
+    fn spawn_hook(&self, hook: Hook, command: &str) {
+        match hook_command(
+            hook,
+            command,
+            self.predown_user.as_deref(),
+            self.predown_group.as_deref(),
+        ) {
+            Ok(mut cmd) => {
+                self.add_env_vars_to_cmd(&mut cmd);
+                if let Err(e) = cmd.spawn() {
+                    log::error!("Failed to run {hook} command: {command}: {e:?}");
+                }
+            }
+            Err(e) => {
+                log::error!(
+                    "Failed to parse {hook} command: {command} in shutdown state - skipped {hook} execution, error: {e:?}"
+                )
+            }
         }
     }
 
-    pub fn write_lockfile(self, command: &str) -> anyhow::Result<Self> {
+    pub fn write_lockfile(
+        self,
+        command: &str,
+        forwarded_port: Option<u16>,
+        profile: Option<String>,
+    ) -> anyhow::Result<Self> {
         let mut lockfile_path = config_dir()?;
         lockfile_path.push(format!("vopono/locks/{}", self.name));
         std::fs::create_dir_all(&lockfile_path)?;
@@ -541,6 +1147,12 @@ impl NetworkNamespace {
             ns: self,
             command: command.to_string(),
             start: since_the_epoch.as_secs(),
+            forwarded_port,
+            application_pid: None,
+            refresh_failures: 0,
+            reconnects: 0,
+            profile,
+            external_ip: None,
         };
         let lock_string = ron::ser::to_string(&lock)?;
         let mut f = File::create(&lockfile_path)?;
@@ -550,6 +1162,58 @@ impl NetworkNamespace {
         set_config_permissions()?;
         Ok(lock.ns)
     }
+
+    /// Save the PID of the launched application in the lockfile of this instance
+    pub fn record_application_pid(&self, pid: u32) -> anyhow::Result<()> {
+        Self::update_lockfile(&self.name, |lock| lock.application_pid = Some(pid))
+    }
+
+    /// Look up the public address of the namespace in the background and save it in the lockfile
+    pub fn record_external_ip(&self) {
+        let name = self.name.clone();
+        std::thread::spawn(move || {
+            let netns_name = name.clone();
+            match run_in_netns(&netns_name, || Ok(public_addresses())) {
+                Ok(addresses) => {
+                    if let Some(ip) = addresses.first().copied() {
+                        debug!("Public address of network namespace {name}: {ip}");
+                        Self::update_lockfile(&name, |lock| lock.external_ip = Some(ip)).ok();
+                    }
+                }
+                Err(e) => debug!("Failed to look up public address of {name}: {e:?}"),
+            }
+        });
+    }
+
+    /// Change the lockfile of this instance for the namespace. Updates from the threads of this
+    /// instance are serialized, and the new contents are renamed over the lockfile, so readers
+    /// never see it half-written.
+    pub fn update_lockfile(name: &str, update: impl FnOnce(&mut Lockfile)) -> anyhow::Result<()> {
+        let _guard = LOCKFILE_UPDATE
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        let pid = unistd::getpid();
+        let dir = config_dir()?.join(format!("vopono/locks/{name}"));
+        let lockfile_path = dir.join(pid.to_string());
+        let lock: Lockfile = ron::de::from_reader(File::open(&lockfile_path)?)?;
+        // The namespace read back must not be torn down
+        let mut lock = std::mem::ManuallyDrop::new(lock);
+        update(&mut lock);
+        // Not a lockfile name for readers of the directory
+        let temp_path = dir.join(format!(".{pid}.tmp"));
+        // A file left behind would keep the namespace from being torn down
+        if let Err(e) = std::fs::write(&temp_path, ron::ser::to_string(&*lock)?) {
+            std::fs::remove_file(&temp_path).ok();
+            return Err(e.into());
+        }
+        // Not recreated if this instance removed it in the meantime
+        if !lockfile_path.exists() {
+            std::fs::remove_file(&temp_path).ok();
+            return Ok(());
+        }
+        std::fs::rename(&temp_path, &lockfile_path)?;
+        Ok(())
+    }
 }
 
 impl Drop for NetworkNamespace {
@@ -557,6 +1221,10 @@ impl Drop for NetworkNamespace {
         let mut lockfile_path = config_dir().expect("Failed to get config dir");
         // Each instance responsible for deleting their own lockfile
         lockfile_path.push(format!("vopono/locks/{}/{}", self.name, unistd::getpid()));
+        // Not while a thread is updating it
+        let guard = LOCKFILE_UPDATE
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
         if lockfile_path.exists() {
             match std::fs::remove_file(&lockfile_path) {
                 Ok(_) => {}
@@ -569,6 +1237,7 @@ impl Drop for NetworkNamespace {
                 }
             };
         }
+        drop(guard);
 
         let mut lockfile_path = config_dir().expect("Failed to get config dir");
         lockfile_path.push(format!("vopono/locks/{}", self.name));
@@ -595,58 +1264,47 @@ impl Drop for NetworkNamespace {
             info!("Shutting down vopono namespace - as there are no processes left running inside");
             // Run PreDown script (if any)
             if let Some(pdcmd) = self.predown.as_ref() {
-                // Extra check so we don't panic on bad predown command
-                match parse_command_str(pdcmd) {
-                    Ok(parsed_pdcmd) => {
-                        let parsed_pdcmd_ptrs: Vec<&str> =
-                            parsed_pdcmd.iter().map(|s| s.as_str()).collect();
-
-                        let mut sudo_args = Vec::new();
-                        if let Some(ref predown_user) = self.predown_user {
-                            sudo_args.push("--user");
-                            sudo_args.push(predown_user);
-                        }
-                        if let Some(ref predown_group) = self.predown_group {
-                            sudo_args.push("--group");
-                            sudo_args.push(predown_group);
-                        }
-
-                        if !sudo_args.is_empty() {
-                            let mut args = vec!["--preserve-env"];
-                            args.append(&mut sudo_args);
-                            args.extend(parsed_pdcmd_ptrs);
-
-                            let mut cmd = std::process::Command::new("sudo");
-                            cmd.args(args);
-                            self.add_env_vars_to_cmd(&mut cmd);
-                            cmd.spawn().ok();
-                        } else {
-                            let mut cmd = std::process::Command::new(parsed_pdcmd_ptrs[0]);
-                            cmd.args(parsed_pdcmd_ptrs[1..].iter());
-                            self.add_env_vars_to_cmd(&mut cmd);
-                            cmd.spawn().ok();
-                        }
-                    }
-                    Err(e) => {
-                        log::error!(
-                            "Failed to parse postdown command: {} in shutdown state - skipped postdown execution, error: {:?}",
-                            &pdcmd,
-                            e
-                        )
-                    }
-                }
+                self.spawn_hook(Hook::PreDown, pdcmd);
             }
 
+            // Read before the VPN client and namespace are gone
+            self.leak_audit = None;
+            self.dnscrypt_proxy = None;
+            self.resolved_link = None;
             self.trojan = None;
+            self.obfuscation = None;
+            self.obfs4 = None;
             self.shadowsocks = None;
             self.openvpn = None;
             self.veth_pair = None;
             self.dns_config = None;
             self.warp = None;
+            self.xray = None;
+            self.redsocks = None;
+            self.tor = None;
+            self.ikev2 = None;
             self.wireguard = None;
+            for path in self.temp_files.drain(..) {
+                // Private files are removed with their directory (which may already be gone if
+                // the Wireguard config was one of them)
+                let result = if is_private_temp_file(&path) {
+                    remove_private_temp_file(&path)
+                } else {
+                    std::fs::remove_file(&path)
+                };
+                if let Err(e) = result {
+                    warn!(
+                        "Failed to remove temporary file: {}, {:?}",
+                        path.display(),
+                        e
+                    );
+                }
+            }
             self.host_masquerade = None;
             self.firewall_exception = None;
-            let delete_result = sudo_command(&["ip", "netns", "delete", &self.name]);
+            // After the veth pair, so the bridge is removed if this was the last namespace on it
+            self.bridge = None;
+            let delete_result = netlink::delete_namespace(&self.name);
             if delete_result.is_err() {
                 warn!(
                     "Failed to delete network namespace: {} - will retry once",
@@ -654,7 +1312,7 @@ impl Drop for NetworkNamespace {
                 );
                 std::thread::sleep(std::time::Duration::from_secs(4));
 
-                sudo_command(&["ip", "netns", "delete", &self.name]).unwrap_or_else(|e| {
+                netlink::delete_namespace(&self.name).unwrap_or_else(|e| {
                     log::error!(
                         "Failed to delete network namespace: {}: {:?}",
                         &self.name,
@@ -662,8 +1320,18 @@ impl Drop for NetworkNamespace {
                     )
                 });
             }
+            emit(Event::NamespaceDestroyed {
+                namespace: &self.name,
+            });
+            // Run PostDown script (if any)
+            if let Some(pdcmd) = self.postdown.as_ref() {
+                self.spawn_hook(Hook::PostDown, pdcmd);
+            }
         } else {
-            debug!("Skipping destructors since other vopono instance using this namespace!");
+            info!(
+                "Leaving namespace {} running for the other vopono instances using it",
+                self.name
+            );
             debug!(
                 "Existing lockfiles using this namespace: {:?}",
                 lockfile_path.read_dir().unwrap().collect::<Vec<_>>()
@@ -671,6 +1339,11 @@ impl Drop for NetworkNamespace {
             std::mem::forget(self.openvpn.take());
             std::mem::forget(self.warp.take());
             std::mem::forget(self.shadowsocks.take());
+            std::mem::forget(self.obfs4.take());
+            std::mem::forget(self.xray.take());
+            std::mem::forget(self.redsocks.take());
+            std::mem::forget(self.tor.take());
+            std::mem::forget(self.ikev2.take());
             std::mem::forget(self.veth_pair.take());
             std::mem::forget(self.dns_config.take());
             std::mem::forget(self.wireguard.take());
@@ -679,13 +1352,71 @@ impl Drop for NetworkNamespace {
             std::mem::forget(self.openconnect.take());
             std::mem::forget(self.openfortivpn.take());
             std::mem::forget(self.trojan.take());
+            std::mem::forget(self.obfuscation.take());
+            std::mem::forget(self.dnscrypt_proxy.take());
+            std::mem::forget(self.resolved_link.take());
+            std::mem::forget(self.leak_audit.take());
+            std::mem::forget(self.bridge.take());
+        }
+    }
+}
+
+/// Exclusive lock held while checking for, creating and setting up a namespace, so concurrent
+/// vopono instances for the same namespace attach to it once it is ready instead of colliding
+pub struct NamespaceSetupLock {
+    _file: File,
+}
+
+impl NamespaceSetupLock {
+    pub fn acquire(name: &str) -> anyhow::Result<Self> {
+        // Kept outside the locks directory, which must only contain lockfiles
+        let dir = config_dir()?.join("vopono/setup_locks");
+        std::fs::create_dir_all(&dir)?;
+        let path = dir.join(name);
+        let file = File::create(&path)
+            .with_context(|| format!("Failed to create setup lock: {}", path.display()))?;
+        if file.try_lock().is_err() {
+            info!("Waiting for another vopono instance to finish setting up namespace {name}");
+            file.lock()
+                .with_context(|| format!("Failed to lock: {}", path.display()))?;
         }
+        Ok(Self { _file: file })
     }
 }
 
+/// Held while this instance changes or removes its lockfile
+static LOCKFILE_UPDATE: Mutex<()> = Mutex::new(());
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Lockfile {
     pub ns: NetworkNamespace,
     pub start: u64,
     pub command: String,
+    /// Port forwarded by the provider to this instance, if any
+    #[serde(default)]
+    pub forwarded_port: Option<u16>,
+    /// Application launched by this instance, so vopono stop can terminate it
+    #[serde(default)]
+    pub application_pid: Option<u32>,
+    /// Failed refreshes of the forwarded port
+    #[serde(default)]
+    pub refresh_failures: u32,
+    /// Times the tunnel was reconnected with --reconnect
+    #[serde(default)]
+    pub reconnects: u32,
+    /// Profile of the config file used by this instance
+    #[serde(default)]
+    pub profile: Option<String>,
+    /// Public address traffic from the namespace leaves from
+    #[serde(default)]
+    pub external_ip: Option<IpAddr>,
+}
+
+impl Lockfile {
+    /// Application PID recorded in a lockfile, if it could be read
+    pub fn read_application_pid(path: &Path) -> Option<u32> {
+        let lock: Lockfile = ron::de::from_reader(File::open(path).ok()?).ok()?;
+        // Avoid triggering Drop for the namespace
+        std::mem::ManuallyDrop::new(lock).application_pid
+    }
 }
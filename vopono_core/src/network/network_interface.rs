@@ -1,6 +1,7 @@
 use anyhow::{Context, anyhow};
 use log::{debug, warn};
 use serde::{Deserialize, Serialize};
+use std::net::Ipv4Addr;
 use std::process::Command;
 use std::str::FromStr;
 
@@ -13,6 +14,22 @@ impl NetworkInterface {
     pub fn new(name: String) -> anyhow::Result<Self> {
         Ok(Self { name })
     }
+
+    /// First IPv4 address assigned to the interface
+    pub fn ipv4_address(&self) -> anyhow::Result<Ipv4Addr> {
+        let output = Command::new("ip")
+            .args(["-4", "-o", "addr", "show", "dev", &self.name])
+            .output()
+            .with_context(|| format!("Failed to get addresses of interface {}", self.name))?
+            .stdout;
+        String::from_utf8_lossy(&output)
+            .split_whitespace()
+            .skip_while(|x| *x != "inet")
+            .nth(1)
+            .and_then(|x| x.split('/').next())
+            .and_then(|x| Ipv4Addr::from_str(x).ok())
+            .ok_or_else(|| anyhow!("No IPv4 address on interface {}", self.name))
+    }
 }
 
 impl FromStr for NetworkInterface {
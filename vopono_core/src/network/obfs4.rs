@@ -0,0 +1,215 @@
+// obfs4 pluggable transport for OpenVPN, for --obfs4
+// obfs4proxy is run in the namespace as a managed client transport (configured by the TOR_PT_*
+// environment variables of the Tor pluggable transport protocol) and reports the local SOCKS5
+// port it listens on. OpenVPN connects over TCP through it with --socks-proxy, giving the bridge
+// arguments (cert and iat-mode) as the SOCKS username and password, which obfs4proxy joins back
+// together. The bridge is an obfs4 server run by the user in front of the OpenVPN server:
+//   obfs4proxy (server) with TOR_PT_SERVER_BINDADDR=obfs4-0.0.0.0:443 and
+//   TOR_PT_ORPORT=<OpenVPN server>:1194
+// The bridge address is given a net_gateway route and allowed by the killswitch, as a remote.
+
+use super::netns::NetworkNamespace;
+use crate::util::vopono_dir;
+use anyhow::{Context, anyhow};
+use log::{debug, error, info};
+use serde::{Deserialize, Serialize};
+use std::io::{BufRead, BufReader};
+use std::net::SocketAddr;
+use std::os::unix::fs::PermissionsExt;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+/// obfs4 bridge, as in a Tor bridge line: 203.0.113.5:443 cert=... iat-mode=0
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(try_from = "String")]
+pub struct Obfs4Bridge {
+    pub address: SocketAddr,
+    pub cert: String,
+    pub iat_mode: u8,
+}
+
+impl FromStr for Obfs4Bridge {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut address = None;
+        let mut cert = None;
+        let mut iat_mode = 0;
+        for word in s.split_whitespace() {
+            if let Some(value) = word.strip_prefix("cert=") {
+                cert = Some(value.to_string());
+            } else if let Some(value) = word.strip_prefix("iat-mode=") {
+                iat_mode =
+                    value.parse().ok().filter(|x| *x <= 2).ok_or_else(|| {
+                        anyhow!("Invalid obfs4 iat-mode {value}, must be 0, 1 or 2")
+                    })?;
+            } else if let Ok(addr) = word.parse() {
+                address = Some(addr);
+            } else if word != "obfs4" && !word.chars().all(|c| c.is_ascii_hexdigit()) {
+                // Bridge lines may have the Tor fingerprint after the address
+                return Err(anyhow!("Unknown obfs4 bridge option {word} in: {s}"));
+            }
+        }
+        Ok(Self {
+            address: address.ok_or_else(|| anyhow!("No address in obfs4 bridge: {s}"))?,
+            cert: cert.ok_or_else(|| anyhow!("No cert in obfs4 bridge: {s}"))?,
+            iat_mode,
+        })
+    }
+}
+
+impl TryFrom<String> for Obfs4Bridge {
+    type Error = anyhow::Error;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+/// Port of the SOCKS5 listener in a CMETHOD line of obfs4proxy, or the error it reports
+fn parse_cmethod(line: &str) -> Option<anyhow::Result<u16>> {
+    let mut words = line.split_whitespace();
+    match words.next()? {
+        "CMETHOD" => {
+            let port = words
+                .nth(2)
+                .and_then(|x| x.parse::<SocketAddr>().ok())
+                .map(|x| x.port())
+                .ok_or_else(|| anyhow!("Invalid obfs4proxy method: {line}"));
+            Some(port)
+        }
+        "CMETHOD-ERROR" | "ENV-ERROR" | "VERSION-ERROR" | "PROXY-ERROR" => {
+            Some(Err(anyhow!("obfs4proxy failed: {line}")))
+        }
+        "CMETHODS" => Some(Err(anyhow!("obfs4proxy reported no obfs4 method"))),
+        _ => None,
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Obfs4Proxy {
+    pid: u32,
+    /// Local SOCKS5 port
+    pub port: u16,
+    /// Bridge reached outside the tunnel
+    pub server: SocketAddr,
+    /// SOCKS credentials carrying the bridge arguments, for OpenVPN
+    pub auth_file: PathBuf,
+    state_dir: PathBuf,
+}
+
+impl Obfs4Proxy {
+    pub fn run_in_netns(netns: &NetworkNamespace, bridge: &Obfs4Bridge) -> anyhow::Result<Self> {
+        which::which("obfs4proxy")
+            .map_err(|e| anyhow!("Cannot find obfs4proxy, is obfs4proxy installed?: {e:?}"))?;
+        let state_dir = vopono_dir()?.join(format!("logs/{}_obfs4", netns.name));
+        std::fs::create_dir_all(&state_dir)?;
+        std::fs::set_permissions(&state_dir, PermissionsExt::from_mode(0o700))?;
+
+        // obfs4proxy joins the username and password, split so neither is empty
+        let args = format!("cert={};iat-mode={}", bridge.cert, bridge.iat_mode);
+        let (username, password) = args.split_at(args.len() - 1);
+        let auth_file = state_dir.join("socks_auth");
+        std::fs::write(&auth_file, format!("{username}\n{password}\n"))?;
+        std::fs::set_permissions(&auth_file, PermissionsExt::from_mode(0o600))?;
+
+        let state_env = format!("TOR_PT_STATE_LOCATION={}", state_dir.display());
+        let mut handle = NetworkNamespace::exec_no_block(
+            &netns.name,
+            &[
+                "env",
+                "TOR_PT_MANAGED_TRANSPORT_VER=1",
+                "TOR_PT_CLIENT_TRANSPORTS=obfs4",
+                &state_env,
+                "obfs4proxy",
+            ],
+            None,
+            None,
+            false,
+            true,
+            false,
+            None,
+        )
+        .context("Failed to launch obfs4proxy")?;
+        // obfs4proxy is killed on drop if we return early due to an error below
+        let mut proxy = Self {
+            pid: handle.id(),
+            port: 0,
+            server: bridge.address,
+            auth_file,
+            state_dir,
+        };
+        let stdout = handle
+            .stdout
+            .take()
+            .ok_or_else(|| anyhow!("No output from obfs4proxy"))?;
+        let mut lines = BufReader::new(stdout).lines();
+        proxy.port = loop {
+            let line = lines
+                .next()
+                .ok_or_else(|| anyhow!("obfs4proxy exited before reporting its port"))??;
+            debug!("obfs4proxy: {line}");
+            if let Some(port) = parse_cmethod(&line) {
+                break port?;
+            }
+        };
+        // Keep reading so obfs4proxy can still write to its output
+        std::thread::spawn(move || {
+            for line in lines.map_while(Result::ok) {
+                debug!("obfs4proxy: {line}");
+            }
+        });
+        info!(
+            "obfs4 proxy running on port {} for bridge {}",
+            proxy.port, proxy.server
+        );
+        Ok(proxy)
+    }
+}
+
+impl Drop for Obfs4Proxy {
+    fn drop(&mut self) {
+        match nix::sys::signal::kill(
+            nix::unistd::Pid::from_raw(self.pid as i32),
+            nix::sys::signal::Signal::SIGTERM,
+        ) {
+            Ok(_) => debug!("Stopped obfs4proxy (pid: {})", self.pid),
+            Err(e) => error!("Failed to stop obfs4proxy (pid: {}): {:?}", self.pid, e),
+        }
+        std::fs::remove_dir_all(&self.state_dir).ok();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_bridge_and_cmethod() {
+        let bridge: Obfs4Bridge =
+            "obfs4 203.0.113.5:443 0123456789ABCDEF0123456789ABCDEF01234567 cert=AbC+/1 iat-mode=1"
+                .parse()
+                .unwrap();
+        assert_eq!(bridge.address, "203.0.113.5:443".parse().unwrap());
+        assert_eq!(bridge.cert, "AbC+/1");
+        assert_eq!(bridge.iat_mode, 1);
+        assert!("203.0.113.5:443".parse::<Obfs4Bridge>().is_err());
+        assert!(
+            "203.0.113.5:443 cert=x iat-mode=3"
+                .parse::<Obfs4Bridge>()
+                .is_err()
+        );
+        assert_eq!(
+            parse_cmethod("CMETHOD obfs4 socks5 127.0.0.1:38291")
+                .unwrap()
+                .unwrap(),
+            38291
+        );
+        assert!(parse_cmethod("VERSION 1").is_none());
+        assert!(
+            parse_cmethod("CMETHOD-ERROR obfs4 no such method")
+                .unwrap()
+                .is_err()
+        );
+    }
+}
@@ -0,0 +1,243 @@
+// Wireguard obfuscation proxies run inside the network namespace
+// The Wireguard endpoint is replaced with the local proxy address, and the proxy sends its
+// traffic with the Wireguard fwmark so it is routed outside the tunnel (and allowed by the killswitch)
+//
+// udp2tcp: https://github.com/mullvad/udp-over-tcp
+//   udp2tcp --udp-listen 127.0.0.1:51821 --tcp-forward <relay>:80 --fwmark 51820
+// Shadowsocks (shadowsocks-rust):
+//   sslocal --protocol tunnel -U -b 127.0.0.1:51821 -s <relay>:<port> --forward-addr <relay>:<wg port>
+//     -k <password> -m <method> --outbound-fwmark 51820
+//   with a server of the user, the address and key come from its ss:// URL and the server
+//   forwards to the Wireguard endpoint
+//
+// wstunnel and udp2raw work with any provider, through a server run by the user:
+// wstunnel (https://github.com/erebe/wstunnel), the server forwards to the Wireguard endpoint:
+//   wstunnel client -L udp://127.0.0.1:51821:<endpoint>?timeout_sec=0 wss://<server>
+//     --socket-so-mark 51820 [--http-upgrade-path-prefix <key>]
+// udp2raw (https://github.com/wangyu-/udp2raw), the server is started with the endpoint as -r:
+//   udp2raw -c -l 127.0.0.1:51821 -r <server> -k <key> --raw-mode faketcp -a --lower-level auto
+// udp2raw has no fwmark option, but sends at layer 2 with --lower-level, on the veth found when
+// it starts (before the Wireguard interface exists), which the routing and killswitch do not see
+
+use super::netns::NetworkNamespace;
+use super::shadowsocks::ShadowsocksServer;
+use anyhow::{Context, anyhow};
+use log::{debug, error, info};
+use serde::{Deserialize, Serialize};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use strum_macros::{Display, EnumIter};
+
+/// Local port the obfuscation proxy listens on for the Wireguard client
+const OBFUSCATION_LOCAL_PORT: u16 = 51821;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Display, EnumIter)]
+pub enum ObfuscationProtocol {
+    Udp2Tcp,
+    Shadowsocks,
+    Wstunnel,
+    Udp2raw,
+}
+
+impl ObfuscationProtocol {
+    /// Default remote port used by Mullvad relays for this obfuscation
+    pub fn default_port(&self) -> u16 {
+        match self {
+            Self::Udp2Tcp => 80,
+            Self::Shadowsocks => 51900,
+            Self::Wstunnel => 443,
+            Self::Udp2raw => 4096,
+        }
+    }
+
+    /// Whether this runs through an obfuscation server given by the user, instead of the
+    /// provider's relays
+    pub fn needs_server(&self) -> bool {
+        matches!(self, Self::Wstunnel | Self::Udp2raw)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Obfuscation {
+    pid: u32,
+    pub protocol: ObfuscationProtocol,
+    pub local_addr: SocketAddr,
+}
+
+impl Obfuscation {
+    #[allow(clippy::too_many_arguments)]
+    pub fn run_in_netns(
+        netns: &NetworkNamespace,
+        protocol: ObfuscationProtocol,
+        wg_endpoint: SocketAddr,
+        port: Option<u16>,
+        fwmark: &str,
+        shadowsocks_password: &str,
+        shadowsocks_method: &str,
+        server: Option<&str>,
+        key: Option<&str>,
+    ) -> anyhow::Result<Self> {
+        let local_addr = SocketAddr::new(
+            IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)),
+            OBFUSCATION_LOCAL_PORT,
+        );
+        let remote_addr = SocketAddr::new(
+            wg_endpoint.ip(),
+            port.unwrap_or_else(|| protocol.default_port()),
+        );
+        // Server of the user instead of the provider's relay, from an ss:// URL
+        let shadowsocks_server = match (protocol, server) {
+            (ObfuscationProtocol::Shadowsocks, Some(url)) => {
+                Some(url.parse::<ShadowsocksServer>()?)
+            }
+            _ => None,
+        };
+        let (shadowsocks_password, shadowsocks_method) = match shadowsocks_server.as_ref() {
+            Some(x) => (x.password.as_str(), x.method.as_str()),
+            None => (shadowsocks_password, shadowsocks_method),
+        };
+        let local_str = local_addr.to_string();
+        let remote_str = match shadowsocks_server.as_ref() {
+            Some(x) => x.resolve()?.to_string(),
+            None => remote_addr.to_string(),
+        };
+        let endpoint_str = wg_endpoint.to_string();
+        let server = match (protocol.needs_server(), server) {
+            (true, None) => {
+                return Err(anyhow!(
+                    "{protocol} obfuscation needs the address of the server, with --obfuscation-server"
+                ));
+            }
+            (true, Some(x)) if x.contains(':') || x.contains("://") => x.to_string(),
+            (true, Some(x)) => format!("{x}:{}", port.unwrap_or_else(|| protocol.default_port())),
+            (false, _) => String::new(),
+        };
+        let wstunnel_forward = format!("udp://{local_str}:{endpoint_str}?timeout_sec=0");
+        let wstunnel_server = if server.contains("://") {
+            server.clone()
+        } else {
+            format!("wss://{server}")
+        };
+        let remote_addr = if protocol.needs_server() {
+            server.clone()
+        } else {
+            remote_str.clone()
+        };
+
+        let command_vec = match protocol {
+            ObfuscationProtocol::Udp2Tcp => {
+                which::which("udp2tcp").map_err(|e| {
+                    anyhow!("Cannot find udp2tcp, is mullvad/udp-over-tcp installed?: {e:?}")
+                })?;
+                vec![
+                    "udp2tcp",
+                    "--udp-listen",
+                    &local_str,
+                    "--tcp-forward",
+                    &remote_str,
+                    "--fwmark",
+                    fwmark,
+                ]
+            }
+            ObfuscationProtocol::Shadowsocks => {
+                which::which("sslocal").map_err(|e| {
+                    anyhow!("Cannot find sslocal, is shadowsocks-rust installed?: {e:?}")
+                })?;
+                vec![
+                    "sslocal",
+                    "--protocol",
+                    "tunnel",
+                    "-U",
+                    "-b",
+                    &local_str,
+                    "-s",
+                    &remote_str,
+                    "--forward-addr",
+                    &endpoint_str,
+                    "-k",
+                    shadowsocks_password,
+                    "-m",
+                    shadowsocks_method,
+                    "--outbound-fwmark",
+                    fwmark,
+                ]
+            }
+            ObfuscationProtocol::Wstunnel => {
+                which::which("wstunnel")
+                    .map_err(|e| anyhow!("Cannot find wstunnel, is wstunnel installed?: {e:?}"))?;
+                let mut command = vec![
+                    "wstunnel",
+                    "client",
+                    "-L",
+                    &wstunnel_forward,
+                    "--socket-so-mark",
+                    fwmark,
+                ];
+                if let Some(key) = key {
+                    command.extend(["--http-upgrade-path-prefix", key]);
+                }
+                command.push(&wstunnel_server);
+                command
+            }
+            ObfuscationProtocol::Udp2raw => {
+                which::which("udp2raw")
+                    .map_err(|e| anyhow!("Cannot find udp2raw, is udp2raw installed?: {e:?}"))?;
+                let key = key.ok_or_else(|| {
+                    anyhow!("udp2raw obfuscation needs the server's key, with --obfuscation-key")
+                })?;
+                vec![
+                    "udp2raw",
+                    "-c",
+                    "-l",
+                    &local_str,
+                    "-r",
+                    &server,
+                    "-k",
+                    key,
+                    "--raw-mode",
+                    "faketcp",
+                    "-a",
+                    "--lower-level",
+                    "auto",
+                ]
+            }
+        };
+
+        debug!(
+            "Launching {protocol} obfuscation: {}",
+            command_vec.join(" ")
+        );
+        let handle = NetworkNamespace::exec_no_block(
+            &netns.name,
+            &command_vec,
+            None,
+            None,
+            true,
+            false,
+            false,
+            None,
+        )
+        .with_context(|| format!("Failed to launch {protocol} obfuscation proxy"))?;
+
+        info!("{protocol} obfuscation proxy running: {local_addr} -> {remote_addr}");
+        Ok(Self {
+            pid: handle.id(),
+            protocol,
+            local_addr,
+        })
+    }
+}
+
+impl Drop for Obfuscation {
+    fn drop(&mut self) {
+        match nix::sys::signal::kill(
+            nix::unistd::Pid::from_raw(self.pid as i32),
+            nix::sys::signal::Signal::SIGKILL,
+        ) {
+            Ok(_) => debug!("Killed {} obfuscation (pid: {})", self.protocol, self.pid),
+            Err(e) => error!(
+                "Failed to kill {} obfuscation (pid: {}): {:?}",
+                self.protocol, self.pid, e
+            ),
+        }
+    }
+}
@@ -32,9 +32,7 @@ impl OpenConnect {
             ));
         }
 
-        let pass = request_creds(uiclient);
-
-        let password = pass.expect("Provide password via Stdin!");
+        let password = request_creds(uiclient).context("Failed to read OpenConnect password")?;
 
         info!("Launching OpenConnect...");
         let mut command_vec = [
@@ -1,22 +1,552 @@
 use super::firewall::Firewall;
 use super::netns::NetworkNamespace;
 use crate::config::vpn::OpenVpnProtocol;
-use crate::util::{check_process_running, set_config_permissions, vopono_dir};
+use crate::util::{check_process_running, netns_state_dir, set_config_permissions, vopono_dir};
 use anyhow::{Context, anyhow};
-use log::{debug, error, info};
+use log::{debug, error, info, warn};
 use regex::Regex;
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use std::fs::File;
 use std::io::{BufRead, BufReader, Seek, SeekFrom};
-use std::net::IpAddr;
+use std::net::{IpAddr, SocketAddr};
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::net::UnixStream;
 use std::path::{Path, PathBuf};
 use std::str::FromStr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+/// Error returned when the OpenVPN server rejects the credentials, so the caller can refresh
+/// them and retry
+#[derive(Debug)]
+pub struct OpenVpnAuthFailed {
+    pub auth_file: Option<PathBuf>,
+    /// A saved auth token was rejected (and removed), the credentials may still be valid
+    pub auth_token: bool,
+}
+
+impl std::fmt::Display for OpenVpnAuthFailed {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.auth_token {
+            return write!(f, "OpenVPN server rejected the saved auth token");
+        }
+        match self.auth_file.as_ref() {
+            Some(auth_file) => write!(
+                f,
+                "OpenVPN authentication failed, use -v for full log output. Modify your username and/or password in {}",
+                auth_file.display()
+            ),
+            None => write!(
+                f,
+                "OpenVPN authentication failed, use -v for full log output"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for OpenVpnAuthFailed {}
+
+/// Failed TLS handshakes over UDP before giving up, so the caller can retry over TCP
+const UDP_TIMEOUTS: usize = 2;
+
+/// Error returned when the TLS handshake repeatedly times out over UDP, as on networks blocking
+/// UDP
+#[derive(Debug)]
+pub struct OpenVpnUdpTimeout;
+
+impl std::fmt::Display for OpenVpnUdpTimeout {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "OpenVPN TLS handshake timed out over UDP, use --tcp if the network blocks UDP"
+        )
+    }
+}
+
+impl std::error::Error for OpenVpnUdpTimeout {}
+
+/// Local SOCKS proxy in the namespace for OpenVPN to connect through (Shadowsocks or obfs4), and
+/// its server, which is reached outside the tunnel
+#[derive(Debug, Clone)]
+pub struct SocksProxy {
+    pub port: u16,
+    pub server: SocketAddr,
+    pub auth_file: Option<PathBuf>,
+    /// Remote to connect to through the proxy instead of those of the config: the obfs4 bridge,
+    /// which runs the OpenVPN server
+    pub remote: Option<SocketAddr>,
+}
+
+/// DNS servers and search domains pushed by the OpenVPN server
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq, Eq)]
+pub struct PushedDns {
+    pub servers: Vec<IpAddr>,
+    pub domains: Vec<String>,
+}
+
+/// Address of a pushed DNS server, which may include a port with the dns option of OpenVPN 2.6
+fn parse_server_address(s: &str) -> Option<IpAddr> {
+    s.parse::<IpAddr>()
+        .ok()
+        .or_else(|| s.parse::<std::net::SocketAddr>().ok().map(|x| x.ip()))
+}
+
+impl PushedDns {
+    fn add_server(&mut self, server: IpAddr) {
+        if !self.servers.contains(&server) {
+            self.servers.push(server);
+        }
+    }
+
+    fn add_domain(&mut self, domain: &str) {
+        if !self.domains.iter().any(|x| x == domain) {
+            self.domains.push(domain.to_string());
+        }
+    }
+
+    /// A pushed option, e.g. dhcp-option DNS 10.8.0.1 or dns server 0 address 10.8.0.1
+    fn add_option(&mut self, option: &str) {
+        let words: Vec<&str> = option.split_whitespace().collect();
+        match words.as_slice() {
+            ["dhcp-option", "DNS" | "DNS6", server] => {
+                if let Some(server) = parse_server_address(server) {
+                    self.add_server(server);
+                }
+            }
+            ["dhcp-option", "DOMAIN" | "DOMAIN-SEARCH", domain] => self.add_domain(domain),
+            ["dns", "server", _, "address", servers @ ..] => {
+                for server in servers.iter().filter_map(|x| parse_server_address(x)) {
+                    self.add_server(server);
+                }
+            }
+            ["dns", "search-domains", domains @ ..] => {
+                for domain in domains {
+                    self.add_domain(domain);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// From the environment of the up script: foreign_option_N for dhcp-option, and the dns_*
+    /// variables of OpenVPN 2.6
+    pub fn from_env(env: &str) -> Self {
+        let mut pushed = Self::default();
+        for (key, value) in env.lines().filter_map(|x| x.split_once('=')) {
+            if key.starts_with("foreign_option_") {
+                pushed.add_option(value);
+            } else if key.starts_with("dns_server_") && key.contains("_address_") {
+                if let Some(server) = parse_server_address(value) {
+                    pushed.add_server(server);
+                }
+            } else if key.starts_with("dns_search_domain_") {
+                pushed.add_domain(value);
+            }
+        }
+        pushed
+    }
+
+    /// From the PUSH_REPLY message in the OpenVPN log
+    pub fn from_log(log: &str) -> Self {
+        let mut pushed = Self::default();
+        for line in log.lines() {
+            let Some((_, reply)) = line.split_once("PUSH_REPLY,") else {
+                continue;
+            };
+            let reply = reply.split('\'').next().unwrap_or(reply);
+            for option in reply.split(',') {
+                pushed.add_option(option);
+            }
+        }
+        pushed
+    }
+}
+
+/// Tuning directives which may be given with --openvpn-option: MTU, logging, ciphers, timers and
+/// buffers. Others could run commands, or change what vopono or the killswitch depends on (the
+/// remotes, the device, routes, the files written).
+const ALLOWED_OPTIONS: [&str; 36] = [
+    "allow-compression",
+    "auth",
+    "auth-nocache",
+    "cipher",
+    "connect-retry",
+    "connect-timeout",
+    "data-ciphers",
+    "data-ciphers-fallback",
+    "explicit-exit-notify",
+    "fast-io",
+    "float",
+    "fragment",
+    "hand-window",
+    "keepalive",
+    "link-mtu",
+    "mssfix",
+    "mtu-disc",
+    "mute",
+    "mute-replay-warnings",
+    "nobind",
+    "persist-key",
+    "persist-tun",
+    "ping",
+    "ping-restart",
+    "rcvbuf",
+    "reneg-bytes",
+    "reneg-sec",
+    "replay-window",
+    "server-poll-timeout",
+    "sndbuf",
+    "tls-cipher",
+    "tls-ciphersuites",
+    "tls-timeout",
+    "tls-version-min",
+    "tun-mtu",
+    "verb",
+];
+
+/// Extra OpenVPN directive given with --openvpn-option, e.g. "tun-mtu 1400", passed on the command
+/// line after the config so it overrides the config's value
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(try_from = "String", into = "String")]
+pub struct OpenVpnOption {
+    pub name: String,
+    pub args: Vec<String>,
+}
+
+impl OpenVpnOption {
+    /// As command line arguments
+    pub fn to_args(&self) -> Vec<String> {
+        std::iter::once(format!("--{}", self.name))
+            .chain(self.args.iter().cloned())
+            .collect()
+    }
+}
+
+impl FromStr for OpenVpnOption {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut words = s.split_whitespace();
+        let name = words
+            .next()
+            .map(|x| x.trim_start_matches("--"))
+            .ok_or_else(|| anyhow!("Empty OpenVPN option"))?;
+        if name.is_empty()
+            || !name
+                .chars()
+                .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-')
+        {
+            return Err(anyhow!("Invalid OpenVPN option name: {s}"));
+        }
+        if !ALLOWED_OPTIONS.contains(&name) {
+            return Err(anyhow!(
+                "OpenVPN option {name} cannot be given with --openvpn-option, only tuning options can: {}",
+                ALLOWED_OPTIONS.join(", ")
+            ));
+        }
+        let args: Vec<String> = words.map(String::from).collect();
+        // Inline files (<ca> etc.) and quoting only work in config files
+        if args.iter().any(|x| x.starts_with('<')) {
+            return Err(anyhow!(
+                "Inline files are only supported in OpenVPN config files: {s}"
+            ));
+        }
+        if args.iter().any(|x| x.contains(['"', '\''])) {
+            return Err(anyhow!("Quotes are not supported in OpenVPN options: {s}"));
+        }
+        Ok(Self {
+            name: name.to_string(),
+            args,
+        })
+    }
+}
+
+impl TryFrom<String> for OpenVpnOption {
+    type Error = anyhow::Error;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl std::fmt::Display for OpenVpnOption {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.args.is_empty() {
+            write!(f, "{}", self.name)
+        } else {
+            write!(f, "{} {}", self.name, self.args.join(" "))
+        }
+    }
+}
+
+impl From<OpenVpnOption> for String {
+    fn from(value: OpenVpnOption) -> Self {
+        value.to_string()
+    }
+}
+
+/// Kernel modules of OpenVPN data channel offload: ovpn-dco-v2 of OpenVPN 2.6, and the ovpn
+/// module of Linux 6.16 used by OpenVPN 2.7
+const DCO_MODULES: [&str; 2] = ["ovpn_dco_v2", "ovpn"];
+
+/// Major and minor version from the first line of openvpn --version
+fn parse_openvpn_version(output: &str) -> Option<(u32, u32)> {
+    let version = output.split_whitespace().nth(1)?;
+    let mut parts = version.split('.').map(|x| x.parse::<u32>().ok());
+    Some((parts.next()??, parts.next()??))
+}
+
+/// Whether a DCO module is loaded, loading one if needed
+fn dco_module_loaded() -> bool {
+    let loaded = || {
+        DCO_MODULES
+            .iter()
+            .any(|x| Path::new("/sys/module").join(x).exists())
+    };
+    if !loaded() {
+        for module in DCO_MODULES {
+            std::process::Command::new("modprobe")
+                .args(["-q", module])
+                .status()
+                .ok();
+        }
+    }
+    loaded()
+}
+
+/// Up script saving the pushed options from its environment to the file given as its argument
+const UP_SCRIPT: &str = "#!/bin/sh\nenv | grep -E '^(foreign_option_|dns_)' > \"$1\"\n";
+
+/// Token of a management interface notification, sent when the server pushes auth-token
+fn auth_token(line: &str) -> Option<&str> {
+    line.trim_end()
+        .strip_prefix(">PASSWORD:Auth-Token:")
+        .filter(|x| !x.is_empty())
+}
+
+/// Type of a management interface password request, e.g. 'Private Key' from
+/// >PASSWORD:Need 'Private Key' password
+fn password_request(line: &str) -> Option<&str> {
+    line.strip_prefix(">PASSWORD:Need '")?
+        .split_once("' password")
+        .map(|x| x.0)
+}
+
+/// Reply to a password request of the management interface, quoted as in its commands
+fn password_reply(request: &str, password: &str) -> String {
+    let quote = |x: &str| x.replace('\\', "\\\\").replace('"', "\\\"");
+    format!("password \"{}\" \"{}\"\n", quote(request), quote(password))
+}
+
+/// Whether the OpenVPN log so far shows the connection succeeded or failed.
+/// OpenVPN exits ("process exiting") once it has tried each remote (--connect-retry-max 1), or
+/// on a fatal error. The log is then checked in order: AUTH_FAILED, which comes before the exit,
+/// gives OpenVpnAuthFailed for the retry with the password or new credentials, and otherwise a
+/// failure over UDP gives OpenVpnUdpTimeout for the retry over TCP (also when there was a single
+/// remote, so fewer than UDP_TIMEOUTS handshake failures). Over TCP the handshake failures are
+/// not counted, OpenVPN tries every remote before it exits.
+fn connection_finished(log: &str, udp: bool) -> bool {
+    log.contains("Initialization Sequence Completed")
+        || log.contains("AUTH_FAILED")
+        || log.contains("Options error")
+        || (udp && log.matches("TLS handshake failed").count() >= UDP_TIMEOUTS)
+        || log.contains("process exiting")
+}
+
+/// File name of the auth token saved for a config, as a token is only valid for the server it
+/// came from
+fn token_file_name(config_file: &Path) -> String {
+    let digest = Sha256::digest(config_file.as_os_str().as_encoded_bytes());
+    let hex: String = digest[..8].iter().map(|x| format!("{x:02x}")).collect();
+    format!("openvpn_auth_token_{hex}")
+}
+
+/// Client of the management interface, which saves the auth token pushed by the server to
+/// token_file (in the format of an auth file with the username, so a reconnect can authenticate
+/// with it instead of the password) and answers the requests for the passphrase of the client key
+/// or the PIN of the PKCS#11 token. It stops OpenVPN if the passphrase is rejected, setting
+/// rejected.
+/// The token is only seen if we connect to the management socket before it is pushed, otherwise
+/// the next connection authenticates with the password as before. OpenVPN waits for the
+/// passphrase.
+fn management_client(
+    socket: PathBuf,
+    token: Option<(PathBuf, String)>,
+    passphrase: Option<String>,
+    rejected: Arc<AtomicBool>,
+) {
+    std::thread::spawn(move || {
+        let stream = (0..100).find_map(|_| {
+            UnixStream::connect(&socket)
+                .inspect_err(|_| std::thread::sleep(Duration::from_millis(50)))
+                .ok()
+        });
+        let Some(mut stream) = stream else {
+            error!(
+                "Could not connect to OpenVPN management socket {}",
+                socket.display()
+            );
+            return;
+        };
+        let Ok(reader) = stream.try_clone() else {
+            return;
+        };
+        // Until OpenVPN exits, a new token may be pushed on renegotiation
+        for line in BufReader::new(reader).lines().map_while(Result::ok) {
+            if let (Some(new_token), Some((token_file, username))) =
+                (auth_token(&line), token.as_ref())
+            {
+                match std::fs::write(token_file, format!("{username}\n{new_token}\n")).and_then(
+                    |_| std::fs::set_permissions(token_file, PermissionsExt::from_mode(0o600)),
+                ) {
+                    Ok(_) => debug!("Saved OpenVPN auth token to {}", token_file.display()),
+                    Err(e) => warn!("Failed to save OpenVPN auth token: {e:?}"),
+                }
+            } else if line.starts_with(">PASSWORD:Verification Failed") {
+                error!("OpenVPN rejected the passphrase: {}", line.trim_end());
+                rejected.store(true, Ordering::Relaxed);
+                std::io::Write::write_all(&mut stream, b"signal SIGTERM\n").ok();
+            } else if let (Some(request), Some(passphrase)) =
+                (password_request(&line), passphrase.as_ref())
+            {
+                debug!("Answering OpenVPN request for {request} password");
+                std::io::Write::write_all(
+                    &mut stream,
+                    password_reply(request, passphrase).as_bytes(),
+                )
+                .ok();
+            } else if line.starts_with(">NEED-OK:") {
+                warn!(
+                    "OpenVPN: {}",
+                    line.trim_start_matches(">NEED-OK:").trim_end()
+                );
+            }
+        }
+    });
+}
+
+/// Client certificate and key given on the command line, replacing those of the config, as PEM
+/// files, a PKCS#12 file or a certificate on a PKCS#11 token
+#[derive(Clone, Default)]
+pub struct ClientCert {
+    pub cert: Option<PathBuf>,
+    pub key: Option<PathBuf>,
+    pub pkcs12: Option<PathBuf>,
+    /// PKCS#11 module of the token, e.g. /usr/lib/opensc-pkcs11.so
+    pub pkcs11_providers: Option<PathBuf>,
+    /// Serialized id of the certificate, see openvpn --show-pkcs11-ids
+    pub pkcs11_id: Option<String>,
+    /// Passphrase of the key or the PIN of the token, sent to the management interface
+    pub passphrase: Option<String>,
+}
+
+impl std::fmt::Debug for ClientCert {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ClientCert")
+            .field("cert", &self.cert)
+            .field("key", &self.key)
+            .field("pkcs12", &self.pkcs12)
+            .field("pkcs11_providers", &self.pkcs11_providers)
+            .field("pkcs11_id", &self.pkcs11_id)
+            .field("passphrase", &self.passphrase.as_ref().map(|_| "********"))
+            .finish()
+    }
+}
+
+impl ClientCert {
+    /// Whether to ask for a passphrase: the key is encrypted, a PKCS#12 file (which may be) or a
+    /// token (unless it has a PIN pad)
+    pub fn needs_passphrase(&self) -> bool {
+        self.pkcs12.is_some()
+            || self.pkcs11_id.is_some()
+            || self
+                .key
+                .as_ref()
+                .and_then(|x| std::fs::read_to_string(x).ok())
+                .is_some_and(|x| x.contains("ENCRYPTED"))
+    }
+
+    fn args(&self) -> Vec<String> {
+        let mut args = Vec::new();
+        let paths = [
+            ("--cert", &self.cert),
+            ("--key", &self.key),
+            ("--pkcs12", &self.pkcs12),
+            ("--pkcs11-providers", &self.pkcs11_providers),
+        ];
+        for (option, path) in paths {
+            if let Some(path) = path {
+                args.extend([option.to_string(), path.to_string_lossy().to_string()]);
+            }
+        }
+        if let Some(id) = self.pkcs11_id.as_ref() {
+            args.extend(["--pkcs11-id".to_string(), id.clone()]);
+        }
+        args
+    }
+}
+
+/// The config without its remotes, for a remote given on the command line to replace them
+pub fn strip_remotes(config: &str) -> String {
+    let mut connection = false;
+    config
+        .lines()
+        .filter(|x| {
+            let line = x.trim();
+            if connection {
+                connection = line != "</connection>";
+                return false;
+            }
+            if line == "<connection>" {
+                connection = true;
+                return false;
+            }
+            !matches!(
+                line.split_whitespace().next(),
+                Some("remote" | "proto" | "remote-random")
+            )
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// The config without its client certificate and key, as files or inline, which are given on the
+/// command line instead
+pub fn strip_client_cert(config: &str) -> String {
+    let mut inline = false;
+    config
+        .lines()
+        .filter(|x| {
+            let line = x.trim();
+            if inline {
+                inline = !["</cert>", "</key>", "</pkcs12>"].contains(&line);
+                return false;
+            }
+            if ["<cert>", "<key>", "<pkcs12>"].contains(&line) {
+                inline = true;
+                return false;
+            }
+            !matches!(
+                line.split_whitespace().next(),
+                Some("cert" | "key" | "pkcs12" | "pkcs11-providers" | "pkcs11-id")
+            )
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct OpenVpn {
     pid: u32,
-    pub openvpn_dns: Option<IpAddr>,
+    #[serde(default)]
+    pub pushed_dns: PushedDns,
     pub logfile: PathBuf,
+    /// Up script and the environment it saved, the TCP copy of the config and the management
+    /// socket
+    #[serde(default)]
+    up_files: Vec<PathBuf>,
     // pub distinct_remotes: Vec<String>, // Unique IP Addresses or hostnames
 }
 
@@ -33,6 +563,11 @@ impl OpenVpn {
         firewall: Firewall,
         disable_ipv6: bool,
         verbose: bool,
+        tcp: bool,
+        socks_proxy: Option<SocksProxy>,
+        disable_dco: bool,
+        extra_options: &[OpenVpnOption],
+        client_cert: Option<&ClientCert>,
     ) -> anyhow::Result<Self> {
         // TODO: Refactor this to separate functions
         // TODO: --status flag
@@ -53,11 +588,68 @@ impl OpenVpn {
         }
 
         let config_file_path = config_file.canonicalize().context("Invalid path given")?;
+        let token_name = token_file_name(&config_file_path);
         set_config_permissions()?;
+        // Relative paths in the config are still resolved from its own directory
+        let working_dir = PathBuf::from(config_file_path.parent().unwrap());
+        let config_file_path = if tcp {
+            tcp_config(&config_file_path, &netns.name)?
+        } else {
+            config_file_path
+        };
+        // Removed with the up files
+        let cert_copy = vopono_dir()?.join(format!("logs/{}_openvpn_cert.ovpn", &netns.name));
+        let config_file_path = if client_cert.is_some() {
+            let config = std::fs::read_to_string(&config_file_path)
+                .with_context(|| format!("Reading OpenVPN config file: {config_file_path:?}"))?;
+            std::fs::write(&cert_copy, strip_client_cert(&config))?;
+            std::fs::set_permissions(&cert_copy, PermissionsExt::from_mode(0o600))?;
+            cert_copy.clone()
+        } else {
+            config_file_path
+        };
+        // Removed with the up files
+        let remote_copy = vopono_dir()?.join(format!("logs/{}_openvpn_remote.ovpn", &netns.name));
+        let proxy_remote = socks_proxy.as_ref().and_then(|x| x.remote);
+        let config_file_path = if proxy_remote.is_some() {
+            let config = std::fs::read_to_string(&config_file_path)
+                .with_context(|| format!("Reading OpenVPN config file: {config_file_path:?}"))?;
+            std::fs::write(&remote_copy, strip_remotes(&config))?;
+            std::fs::set_permissions(&remote_copy, PermissionsExt::from_mode(0o600))?;
+            remote_copy.clone()
+        } else {
+            config_file_path
+        };
 
         // Check config file for up and down script entries and warn on their presence
-        warn_on_scripts_config(&config_file_path)?;
+        let config_scripts = warn_on_scripts_config(&config_file_path)?;
+        let up_script = vopono_dir()?.join(format!("logs/{}_openvpn_up.sh", &netns.name));
+        let up_env = vopono_dir()?.join(format!("logs/{}_openvpn_env", &netns.name));
+        std::fs::remove_file(&up_env).ok();
+        // Only if it does not replace an up script of the config
+        let up_command = if config_scripts {
+            None
+        } else {
+            std::fs::write(&up_script, UP_SCRIPT)?;
+            std::fs::set_permissions(&up_script, PermissionsExt::from_mode(0o700))?;
+            Some(format!("{} {}", up_script.display(), up_env.display()))
+        };
 
+        let socks_port = socks_proxy.as_ref().map(|x| x.port.to_string());
+        let socks_auth = socks_proxy
+            .as_ref()
+            .and_then(|x| x.auth_file.as_ref())
+            .map(|x| x.to_string_lossy().to_string());
+        let socks_route = match socks_proxy.as_ref().map(|x| x.server.ip()) {
+            Some(IpAddr::V6(ip)) => {
+                return Err(anyhow!(
+                    "IPv6 proxy server {ip} is not supported for OpenVPN"
+                ));
+            }
+            x => x.map(|ip| ip.to_string()),
+        };
+
+        let cert_args = client_cert.map(|x| x.args()).unwrap_or_default();
         info!("Launching OpenVPN...");
         let mut command_vec = ([
             "openvpn",
@@ -69,9 +661,54 @@ impl OpenVpn {
         ])
         .to_vec();
 
+        if let Some(cert) = client_cert {
+            command_vec.extend(cert_args.iter().map(String::as_str));
+            if cert.pkcs11_id.is_some() {
+                info!("Using the client certificate on the PKCS#11 token");
+            }
+        }
+
+        // A token saved by a previous connection to the same config for the same user, see
+        // management_client
+        let state_dir = netns_state_dir(&netns.name)?;
+        let token_file = state_dir.join(token_name);
+        let management_socket = state_dir.join("openvpn_management.sock");
+        let username = auth_file
+            .as_ref()
+            .and_then(|x| std::fs::read_to_string(x).ok())
+            .and_then(|x| x.lines().next().map(str::to_string));
+        let saved_token = username.as_ref().is_some_and(|user| {
+            std::fs::read_to_string(&token_file)
+                .is_ok_and(|x| x.lines().next() == Some(user.as_str()))
+        });
+        let token_str = token_file.to_string_lossy().to_string();
+        let management_str = management_socket.to_string_lossy().to_string();
         if let Some(af_ref) = auth_file.as_ref() {
             command_vec.push("--auth-user-pass");
-            command_vec.push(af_ref.as_os_str().to_str().unwrap());
+            if saved_token {
+                info!("Authenticating with the saved OpenVPN auth token");
+                command_vec.push(&token_str);
+            } else {
+                command_vec.push(af_ref.as_os_str().to_str().unwrap());
+            }
+        }
+        // Unless the config uses the management interface itself
+        let passphrase = client_cert.and_then(|x| x.passphrase.clone());
+        let config_management = std::fs::read_to_string(&config_file_path)?
+            .lines()
+            .any(|x| x.trim().starts_with("management "));
+        if config_management && passphrase.is_some() {
+            return Err(anyhow!(
+                "OpenVPN config has a management option, cannot give it the key passphrase"
+            ));
+        }
+        let use_management = (username.is_some() || passphrase.is_some()) && !config_management;
+        if use_management {
+            std::fs::remove_file(&management_socket).ok();
+            command_vec.extend(["--management", &management_str, "unix"]);
+            if passphrase.is_some() {
+                command_vec.push("--management-query-passwords");
+            }
         }
 
         let ipv6_disabled = std::fs::read_to_string("/sys/module/ipv6/parameters/disable")
@@ -84,9 +721,48 @@ impl OpenVpn {
             debug!("Detected IPv6 enabled in /sys/module/ipv6/parameters/disable");
         }
 
+        // Save the DNS options pushed by the server
+        if let Some(up_command) = up_command.as_ref() {
+            command_vec.extend(["--script-security", "2", "--up", up_command]);
+        }
+
+        // Through the proxy's server, which is reached outside the tunnel
+        if let (Some(port), Some(route)) = (socks_port.as_ref(), socks_route.as_ref()) {
+            command_vec.extend(["--socks-proxy", "127.0.0.1", port]);
+            if let Some(auth) = socks_auth.as_ref() {
+                command_vec.push(auth);
+            }
+            command_vec.extend(["--route", route, "255.255.255.255", "net_gateway"]);
+        }
+        let proxy_remote_host = proxy_remote.map(|x| x.ip().to_string());
+        let proxy_remote_port = proxy_remote.map(|x| x.port().to_string());
+        if let (Some(host), Some(port)) = (proxy_remote_host.as_ref(), proxy_remote_port.as_ref()) {
+            command_vec.extend(["--remote", host, port, "tcp-client"]);
+        }
+
         // Only try once for DNS resolution / remote host connection
         command_vec.push("--connect-retry-max");
         command_vec.push("1");
+        // OpenVPN 2.6 uses DCO by default when the module is loaded, and falls back to the tun
+        // device for options DCO does not support
+        let version = std::process::Command::new("openvpn")
+            .arg("--version")
+            .output()
+            .ok()
+            .and_then(|x| parse_openvpn_version(&String::from_utf8_lossy(&x.stdout)));
+        if version.is_some_and(|x| x >= (2, 6)) {
+            if disable_dco {
+                debug!("Disabling OpenVPN data channel offload");
+                command_vec.push("--disable-dco");
+            } else if dco_module_loaded() {
+                info!("Using OpenVPN data channel offload (ovpn-dco)");
+            } else {
+                debug!("No ovpn-dco kernel module, disabling OpenVPN data channel offload");
+                command_vec.push("--disable-dco");
+            }
+        } else if disable_dco {
+            debug!("OpenVPN older than 2.6 has no data channel offload");
+        }
         // Ignore Windows-specific command
         command_vec.push("--pull-filter");
         command_vec.push("ignore");
@@ -102,9 +778,31 @@ impl OpenVpn {
             command_vec.push("route-ipv6");
         }
 
-        let remotes = get_remotes_from_config(&config_file)?;
+        let extra_args: Vec<String> = extra_options.iter().flat_map(|x| x.to_args()).collect();
+        if !extra_args.is_empty() {
+            debug!("Extra OpenVPN options: {}", extra_args.join(" "));
+            command_vec.extend(extra_args.iter().map(String::as_str));
+        }
+
+        // Removed with the up files if it was written by tcp_config
+        let tcp_copy = vopono_dir()?.join(format!("logs/{}_openvpn_tcp.ovpn", &netns.name));
+        let mut remotes = if proxy_remote.is_some() {
+            Vec::new()
+        } else {
+            get_remotes_from_config(&config_file_path)?
+        };
         debug!("Found remotes: {:?}", &remotes);
-        let working_dir = PathBuf::from(config_file_path.parent().unwrap());
+        let udp = remotes.iter().any(|x| x.protocol == OpenVpnProtocol::UDP);
+        // Allowed by the killswitch, the remotes are reached through it
+        if let Some(server) = socks_proxy.map(|x| x.server) {
+            for protocol in [OpenVpnProtocol::TCP, OpenVpnProtocol::UDP] {
+                remotes.push(Remote {
+                    host: Host::from_str(&server.ip().to_string())?,
+                    port: server.port(),
+                    protocol,
+                });
+            }
+        }
 
         let handle = NetworkNamespace::exec_no_block(
             &netns.name,
@@ -117,15 +815,34 @@ impl OpenVpn {
             Some(working_dir),
         )
         .context("Failed to launch OpenVPN - is openvpn installed?")?;
-        let id = handle.id();
+        // OpenVPN is killed on drop if we return early due to an error below
+        let mut openvpn = Self {
+            pid: handle.id(),
+            pushed_dns: PushedDns::default(),
+            logfile: log_file_path,
+            up_files: vec![
+                up_script,
+                up_env.clone(),
+                tcp_copy,
+                cert_copy,
+                remote_copy,
+                management_socket.clone(),
+            ],
+        };
+        let passphrase_rejected = Arc::new(AtomicBool::new(false));
+        if use_management {
+            management_client(
+                management_socket,
+                username.map(|x| (token_file.clone(), x)),
+                passphrase,
+                passphrase_rejected.clone(),
+            );
+        }
         let mut buffer = String::with_capacity(16384);
 
         let mut logfile = BufReader::with_capacity(64, File::open(log_file_str)?);
         let mut pos: usize = 0;
 
-        // Parse DNS header from OpenVPN response
-        let dns_regex = Regex::new(r"dhcp-option DNS ([0-9.]+)").unwrap();
-        let mut openvpn_dns: Option<IpAddr> = None;
         // Tail OpenVPN log file
         loop {
             let x = logfile.read_line(&mut buffer)?;
@@ -136,23 +853,7 @@ impl OpenVpn {
 
             pos += x;
 
-            if let Some(cap) = dns_regex.captures(&buffer) {
-                if openvpn_dns.is_none() {
-                    if let Some(ipstr) = cap.get(1) {
-                        debug!("Found OpenVPN DNS response: {}", ipstr.as_str());
-                        let ipaddr = IpAddr::from_str(ipstr.as_str());
-                        if let Ok(ip) = ipaddr {
-                            openvpn_dns = Some(ip);
-                            debug!("Set OpenVPN DNS to: {ip:?}");
-                        }
-                    }
-                }
-            }
-
-            if buffer.contains("Initialization Sequence Completed")
-                || buffer.contains("AUTH_FAILED")
-                || buffer.contains("Options error")
-            {
+            if connection_finished(&buffer, udp) {
                 break;
             }
 
@@ -160,22 +861,35 @@ impl OpenVpn {
         }
 
         if buffer.contains("AUTH_FAILED") {
-            if auth_file.is_some() {
-                error!(
-                    "OpenVPN authentication failed, modify your username and/or password in {}",
-                    auth_file.as_ref().unwrap().display()
-                );
-                // std::fs::remove_file(auth_file.unwrap())?;
+            if saved_token {
+                // Expired, the next attempt uses the password
+                std::fs::remove_file(&token_file).ok();
+            } else {
+                error!("OpenVPN authentication failed, use -v for full log output");
+            }
+            return Err(OpenVpnAuthFailed {
+                auth_file,
+                auth_token: saved_token,
             }
+            .into());
+        }
+        if passphrase_rejected.load(Ordering::Relaxed) {
             return Err(anyhow!(
-                "OpenVPN authentication failed, use -v for full log output. Modify your username and/or password in {}",
-                auth_file.as_ref().unwrap().display()
+                "Wrong passphrase or PIN for the OpenVPN client key, use -v for full log output"
             ));
         }
         if buffer.contains("Options error") {
             error!("OpenVPN options error: {buffer}");
             return Err(anyhow!("OpenVPN options error, use -v for full log output"));
         }
+        if !buffer.contains("Initialization Sequence Completed") {
+            if udp {
+                return Err(OpenVpnUdpTimeout.into());
+            }
+            return Err(anyhow!(
+                "OpenVPN TLS handshake failed, use -v for full log output"
+            ));
+        }
 
         // Allow input to and output from open ports (for port forwarding in tunnel)
         if let Some(opens) = open_ports {
@@ -191,11 +905,14 @@ impl OpenVpn {
             killswitch(netns, dns, remotes.as_slice(), firewall, disable_ipv6)?;
         }
 
-        Ok(Self {
-            pid: id,
-            openvpn_dns,
-            logfile: log_file_path,
-        })
+        // The log only has the PUSH_REPLY from verb 3
+        openvpn.pushed_dns = std::fs::read_to_string(&up_env)
+            .map(|env| PushedDns::from_env(&env))
+            .ok()
+            .filter(|x| !x.servers.is_empty())
+            .unwrap_or_else(|| PushedDns::from_log(&buffer));
+        debug!("DNS pushed by OpenVPN: {:?}", openvpn.pushed_dns);
+        Ok(openvpn)
     }
 
     pub fn check_if_running(&self) -> bool {
@@ -224,6 +941,9 @@ impl Drop for OpenVpn {
                 e
             ),
         }
+        for path in self.up_files.iter() {
+            std::fs::remove_file(path).ok();
+        }
     }
 }
 
@@ -596,6 +1316,59 @@ pub fn warn_on_scripts_config(path: &Path) -> anyhow::Result<bool> {
     Ok(out)
 }
 
+/// TCP client protocol for a UDP one, keeping the address family
+fn tcp_proto(udp_proto: &str) -> &'static str {
+    match udp_proto {
+        "udp4" => "tcp4-client",
+        "udp6" => "tcp6-client",
+        _ => "tcp-client",
+    }
+}
+
+/// The config with its remotes switched to TCP, removing the UDP-only options
+pub fn rewrite_config_tcp(config: &str) -> String {
+    config
+        .lines()
+        .filter(|x| !x.trim().starts_with("explicit-exit-notify"))
+        .map(|x| {
+            let mut words = x.split_whitespace().collect::<Vec<_>>();
+            match words.as_slice() {
+                ["proto", proto] if proto.starts_with("udp") => {
+                    format!("proto {}", tcp_proto(proto))
+                }
+                ["remote", _, _, proto] if proto.starts_with("udp") => {
+                    words[3] = tcp_proto(proto);
+                    words.join(" ")
+                }
+                _ => x.to_string(),
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// TCP variant of the config: the provider's TCP config next to it if there is one (the file
+/// name with udp replaced by tcp), otherwise a copy with the remotes switched to TCP
+pub fn tcp_config(config_file: &Path, netns_name: &str) -> anyhow::Result<PathBuf> {
+    if let Some(name) = config_file.file_name().and_then(|x| x.to_str()) {
+        let variant = config_file.with_file_name(name.replace("udp", "tcp").replace("UDP", "TCP"));
+        if variant != config_file && variant.exists() {
+            info!("Using TCP config {}", variant.display());
+            return Ok(variant);
+        }
+    }
+    let config = std::fs::read_to_string(config_file)
+        .with_context(|| format!("Reading OpenVPN config file: {}", config_file.display()))?;
+    let path = vopono_dir()?.join(format!("logs/{netns_name}_openvpn_tcp.ovpn"));
+    std::fs::write(&path, rewrite_config_tcp(&config))?;
+    set_config_permissions()?;
+    warn!(
+        "No TCP variant of {}, using its remotes over TCP - the server may not accept TCP on the same ports",
+        config_file.display()
+    );
+    Ok(path)
+}
+
 pub fn get_remotes_from_config(path: &Path) -> anyhow::Result<Vec<Remote>> {
     let file_string =
         std::fs::read_to_string(path).context(format!("Reading OpenVPN config file: {path:?}"))?;
@@ -638,7 +1411,7 @@ pub fn get_remotes_from_config(path: &Path) -> anyhow::Result<Vec<Remote>> {
 
 #[derive(Debug)]
 pub struct Remote {
-    host: Host,
+    pub host: Host,
     pub port: u16,
     protocol: OpenVpnProtocol,
 }
@@ -650,6 +1423,16 @@ pub enum Host {
     Hostname(String),
 }
 
+impl std::fmt::Display for Host {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Host::IPv4(ip) => write!(f, "{ip}"),
+            Host::IPv6(ip) => write!(f, "{ip}"),
+            Host::Hostname(host) => write!(f, "{host}"),
+        }
+    }
+}
+
 impl FromStr for Host {
     type Err = anyhow::Error;
 
@@ -663,3 +1446,129 @@ impl FromStr for Host {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rewrite_remotes_to_tcp() {
+        let config = "client\nproto udp\nremote se.example.com 1194\nremote 1.2.3.4 1195 udp\nexplicit-exit-notify 1\nverb 3";
+        assert_eq!(
+            rewrite_config_tcp(config),
+            "client\nproto tcp-client\nremote se.example.com 1194\nremote 1.2.3.4 1195 tcp-client\nverb 3"
+        );
+        assert_eq!(
+            rewrite_config_tcp("proto udp6\nremote 2001:db8::1 1194 udp4"),
+            "proto tcp6-client\nremote 2001:db8::1 1194 tcp4-client"
+        );
+    }
+
+    #[test]
+    fn parse_pushed_dns() {
+        let log = "2025-01-01 PUSH: Received control message: 'PUSH_REPLY,redirect-gateway def1,dhcp-option DNS 10.8.0.1,dhcp-option DNS6 fd00::1,dhcp-option DOMAIN vpn.example,route-gateway 10.8.0.1'";
+        let expected = PushedDns {
+            servers: vec!["10.8.0.1".parse().unwrap(), "fd00::1".parse().unwrap()],
+            domains: vec!["vpn.example".to_string()],
+        };
+        assert_eq!(PushedDns::from_log(log), expected);
+
+        let env = "foreign_option_1=dhcp-option DNS 10.8.0.1\nforeign_option_2=dhcp-option DOMAIN vpn.example\ndns_server_0_address_1=[fd00::1]:53\n";
+        assert_eq!(PushedDns::from_env(env), expected);
+    }
+
+    #[test]
+    fn parse_version() {
+        assert_eq!(
+            parse_openvpn_version(
+                "OpenVPN 2.6.12 x86_64-pc-linux-gnu [SSL (OpenSSL)] [LZO] [LZ4] [EPOLL] [MH/PKTINFO] [AEAD] [DCO]"
+            ),
+            Some((2, 6))
+        );
+        assert_eq!(parse_openvpn_version("OpenVPN 2.5.9 x86_64"), Some((2, 5)));
+        assert_eq!(parse_openvpn_version(""), None);
+    }
+
+    #[test]
+    fn parse_openvpn_option() {
+        let option: OpenVpnOption = "--tun-mtu 1400".parse().unwrap();
+        assert_eq!(option.to_args(), vec!["--tun-mtu", "1400"]);
+        assert_eq!(option.to_string(), "tun-mtu 1400");
+        assert!("verb 4".parse::<OpenVpnOption>().is_ok());
+        assert!("up /tmp/script.sh".parse::<OpenVpnOption>().is_err());
+        assert!("Tun_MTU 1400".parse::<OpenVpnOption>().is_err());
+        assert!("setenv NAME \"a b\"".parse::<OpenVpnOption>().is_err());
+        assert!("route 0.0.0.0 0.0.0.0".parse::<OpenVpnOption>().is_err());
+        assert!(
+            "data-ciphers AES-256-GCM:CHACHA20-POLY1305"
+                .parse::<OpenVpnOption>()
+                .is_ok()
+        );
+        let inline = "tls-cipher <x>".parse::<OpenVpnOption>().unwrap_err();
+        assert!(inline.to_string().contains("Inline files"));
+        let quoted = "tls-cipher \"x\"".parse::<OpenVpnOption>().unwrap_err();
+        assert!(quoted.to_string().contains("Quotes"));
+    }
+
+    #[test]
+    fn end_of_connection_attempt() {
+        let tls = "TLS Error: TLS handshake failed\nTLS Error: TLS handshake failed\n";
+        assert!(connection_finished(tls, true));
+        // OpenVPN goes on with the next remote over TCP
+        assert!(!connection_finished(tls, false));
+        let single =
+            "TLS Error: TLS handshake failed\nSIGTERM[soft,tls-error] received, process exiting\n";
+        assert!(connection_finished(single, false));
+        assert!(connection_finished(single, true));
+        assert!(connection_finished(
+            "AUTH: Received control message: AUTH_FAILED\n",
+            false
+        ));
+        assert!(!connection_finished(
+            "TCP connection established with [AF_INET]1.2.3.4:443\n",
+            false
+        ));
+    }
+
+    #[test]
+    fn token_per_config() {
+        let name = token_file_name(Path::new("/c/se-got-ovpn-001.ovpn"));
+        assert!(name.starts_with("openvpn_auth_token_"));
+        assert_eq!(name.len(), "openvpn_auth_token_".len() + 16);
+        assert_eq!(name, token_file_name(Path::new("/c/se-got-ovpn-001.ovpn")));
+        assert_ne!(name, token_file_name(Path::new("/c/se-got-ovpn-002.ovpn")));
+    }
+
+    #[test]
+    fn bridge_replaces_remotes() {
+        let config = "client\nproto udp\nremote vpn.example.com 1194\nremote-random\n<connection>\nremote 1.2.3.4 443 tcp\n</connection>\nverb 3";
+        assert_eq!(strip_remotes(config), "client\nverb 3");
+    }
+
+    #[test]
+    fn client_cert_config_and_passphrase() {
+        let config = "client\nremote vpn.example.com 1194\ncert client.crt\nkey client.key\nkey-direction 1\n<cert>\n-----BEGIN CERTIFICATE-----\n</cert>\n<tls-auth>\nstatic key\n</tls-auth>";
+        assert_eq!(
+            strip_client_cert(config),
+            "client\nremote vpn.example.com 1194\nkey-direction 1\n<tls-auth>\nstatic key\n</tls-auth>"
+        );
+        assert_eq!(
+            password_request(">PASSWORD:Need 'Private Key' password"),
+            Some("Private Key")
+        );
+        assert_eq!(
+            password_reply("Private Key", "a\"b"),
+            "password \"Private Key\" \"a\\\"b\"\n"
+        );
+    }
+
+    #[test]
+    fn parse_auth_token() {
+        assert_eq!(
+            auth_token(">PASSWORD:Auth-Token:SESS_ID_AT_abc123==\r\n"),
+            Some("SESS_ID_AT_abc123==")
+        );
+        assert_eq!(auth_token(">PASSWORD:Auth-Token:"), None);
+        assert_eq!(auth_token(">STATE:1700000000,CONNECTED,SUCCESS"), None);
+    }
+}
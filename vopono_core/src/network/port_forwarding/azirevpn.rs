@@ -1,26 +1,60 @@
 // https://www.azirevpn.com/docs/api/portforwardings#create-portforwarding
 // AzireVPN Port Forwarding needs to send one request from *INSIDE* the network namespace
 // Then handle open port
+// Mappings expire, so the thread loop checks the mapping and creates a new one when it is close
+// to expiry (or has been removed), deleting the old one
 // Attempt to destroy port forwarding on Drop
 
 use std::net::IpAddr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU16, Ordering};
+use std::sync::mpsc::{self, Sender};
+use std::thread::JoinHandle;
 
 use crate::network::netns::NetworkNamespace;
 use anyhow::Context;
 use serde::Deserialize;
 
-use super::Forwarder;
+use super::{Forwarder, ThreadLoopForwarder, ThreadParameters};
+
+/// Lifetime of created port forwardings in days
+const EXPIRES_IN_DAYS: u32 = 30;
+/// Create a new port forwarding when the current one expires within this time
+const RENEW_BEFORE_SECS: u64 = 24 * 60 * 60;
 
 pub struct AzireVpnPortForwarding {
-    pub port: u16,
+    port: Arc<AtomicU16>,
     pub local_ip: IpAddr,
     pub access_token: String,
     pub netns_name: String,
+    loop_thread_handle: Option<JoinHandle<()>>,
+    send_channel: Sender<bool>,
     // TODO: We could run check endpoint but it means we need to temporarily listen on this port too
     // But it would confirm success and give us our remote IP
     // TODO: Do we want to look up remote IP from ifconfig.co?
 }
 
+pub struct ThreadParamsImpl {
+    pub netns_name: String,
+    pub callback: Option<String>,
+    pub access_token: String,
+    pub local_ip: IpAddr,
+    /// Shared with the forwarder so the current port is deleted on drop
+    port: Arc<AtomicU16>,
+}
+
+impl ThreadParameters for ThreadParamsImpl {
+    fn get_callback_command(&self) -> Option<String> {
+        self.callback.clone()
+    }
+    fn get_loop_delay(&self) -> u64 {
+        60 * 60
+    }
+    fn get_netns_name(&self) -> String {
+        self.netns_name.clone()
+    }
+}
+
 // Unused since we use curl here for now
 // #[derive(Serialize, Debug)]
 // struct RequestBody {
@@ -76,62 +110,120 @@ impl AzireVpnPortForwarding {
         netns: &NetworkNamespace,
         access_token: &str,
         local_ip: IpAddr,
+        callback: Option<&String>,
     ) -> anyhow::Result<Self> {
-        // Check if any port forwarding exists for current connection
         log::info!("Sleeping 10 seconds so connection is up before requesting port forwarding");
         std::thread::sleep(std::time::Duration::from_secs(10));
+
+        let port = Arc::new(AtomicU16::new(0));
+        let params = ThreadParamsImpl {
+            netns_name: netns.name.clone(),
+            callback: callback.cloned(),
+            access_token: access_token.to_string(),
+            local_ip,
+            port: port.clone(),
+        };
+        let forwarded_port = Self::refresh_port(&params)?;
+        Self::callback_command(&params, forwarded_port);
+
+        let (send, recv) = mpsc::channel::<bool>();
+        let handle = std::thread::spawn(move || Self::thread_loop(params, recv));
+
+        Ok(Self {
+            port,
+            local_ip,
+            access_token: access_token.to_string(),
+            netns_name: netns.name.clone(),
+            loop_thread_handle: Some(handle),
+            send_channel: send,
+        })
+    }
+
+    /// Returns the existing port forwarding for this connection if it is not close to expiry
+    fn existing_port(params: &ThreadParamsImpl) -> anyhow::Result<Option<u16>> {
         let cmd = [
             "curl",
             Box::leak(
-                format!("https://api.azirevpn.com/v3/portforwardings?internal_ipv4={local_ip}")
-                    .into_boxed_str(),
+                format!(
+                    "https://api.azirevpn.com/v3/portforwardings?internal_ipv4={}",
+                    params.local_ip
+                )
+                .into_boxed_str(),
             ),
             "-H",
-            Box::leak(format!("Authorization: Bearer {access_token}").into_boxed_str()),
+            Box::leak(format!("Authorization: Bearer {}", params.access_token).into_boxed_str()),
         ];
 
-        let output = NetworkNamespace::exec_with_output(&netns.name, &cmd)?;
+        let output = NetworkNamespace::exec_with_output(&params.netns_name, &cmd)?;
         let output_string = String::from_utf8(output.stdout.clone())?;
         log::debug!("AzireVPN Port forwarding list response: {output_string}");
 
-        // TODO: Distinguish error from no port forwarding existing vs. network error ?
-        let output_data_result: anyhow::Result<ListResponse> = serde_json::from_str(&output_string)
-            .with_context(|| "Failed to parse JSON response from listing AzireVPN Port Forwarding");
-
-        // If so, return that port
-        if let Ok(output_data) = output_data_result {
-            if !output_data.data.ports.is_empty() {
-                let port = output_data.data.ports[0].port;
-                log::info!("Port forwarding already enabled on port {port}");
-                return Ok(Self {
-                    port,
-                    local_ip,
-                    access_token: access_token.to_string(),
-                    netns_name: netns.name.clone(),
-                });
-            }
+        // A failed list is an error rather than no port forwarding, so we do not create another
+        // mapping on every failure
+        let output_data: ListResponse = serde_json::from_str(&output_string).with_context(
+            || "Failed to parse JSON response from listing AzireVPN Port Forwarding",
+        )?;
+
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)?
+            .as_secs();
+        Ok(Self::usable_port(&output_data.data.ports, now))
+    }
+
+    /// First port forwarding which is not close to expiry
+    fn usable_port(ports: &[PortData], now: u64) -> Option<u16> {
+        ports
+            .iter()
+            .find(|p| p.expires_at > now + RENEW_BEFORE_SECS)
+            .map(|p| p.port)
+    }
+
+    fn delete_port(netns_name: &str, access_token: &str, local_ip: IpAddr, port: u16) {
+        let cmd = [
+            "curl",
+            "-X",
+            "DELETE",
+            "https://api.azirevpn.com/v3/portforwardings",
+            "-H",
+            Box::leak(format!("Authorization: Bearer {access_token}").into_boxed_str()),
+            "--json",
+            Box::leak(
+                format!("{{\"internal_ipv4\": \"{local_ip}\", \"port\": {port}}}").into_boxed_str(),
+            ),
+        ];
+
+        // Note this must run BEFORE the network namespace is destroyed
+        match NetworkNamespace::exec_with_output(netns_name, &cmd) {
+            Ok(output) => log::info!(
+                "AzireVPN Port forwarding on port {port} destroyed: {}",
+                String::from_utf8_lossy(&output.stdout)
+            ),
+            Err(e) => log::error!("Failed to destroy AzireVPN Port Forwarding on port {port}: {e}"),
         }
+    }
 
-        // If not, create a new port forwarding
+    fn create_port(params: &ThreadParamsImpl) -> anyhow::Result<u16> {
         // Retry up to 3 times
-
         let mut i = 1;
         let data = loop {
             let cmd = [
                 "curl",
                 "https://api.azirevpn.com/v3/portforwardings",
                 "-H",
-                Box::leak(format!("Authorization: Bearer {access_token}").into_boxed_str()),
+                Box::leak(
+                    format!("Authorization: Bearer {}", params.access_token).into_boxed_str(),
+                ),
                 "--json",
                 Box::leak(
                     format!(
-                        "{{\"internal_ipv4\": \"{local_ip}\", \"hidden\": false, \"expires_in\": 30}}",
+                        "{{\"internal_ipv4\": \"{}\", \"hidden\": false, \"expires_in\": {EXPIRES_IN_DAYS}}}",
+                        params.local_ip
                     )
                     .into_boxed_str(),
                 ),
             ];
 
-            let output = NetworkNamespace::exec_with_output(&netns.name, &cmd)?;
+            let output = NetworkNamespace::exec_with_output(&params.netns_name, &cmd)?;
             let output_string = String::from_utf8(output.stdout.clone())?;
 
             log::debug!("AzireVPN Port forwarding creation response: {output_string}");
@@ -159,50 +251,79 @@ impl AzireVpnPortForwarding {
             "AzireVPN Port forwarding enabled on port {}",
             data.data.port
         );
-        Ok(Self {
-            port: data.data.port,
-            local_ip,
-            access_token: access_token.to_string(),
-            netns_name: netns.name.clone(),
-        })
+        Ok(data.data.port)
+    }
+}
+
+impl ThreadLoopForwarder for AzireVpnPortForwarding {
+    type ThreadParams = ThreadParamsImpl;
+
+    fn refresh_port(params: &Self::ThreadParams) -> anyhow::Result<u16> {
+        let port = match Self::existing_port(params)? {
+            Some(port) => {
+                if params.port.load(Ordering::SeqCst) != port {
+                    log::info!("Port forwarding already enabled on port {port}");
+                }
+                port
+            }
+            None => Self::create_port(params)?,
+        };
+        let old_port = params.port.swap(port, Ordering::SeqCst);
+        if old_port != 0 && old_port != port {
+            Self::delete_port(
+                &params.netns_name,
+                &params.access_token,
+                params.local_ip,
+                old_port,
+            );
+        }
+        Ok(port)
     }
 }
 
 impl Forwarder for AzireVpnPortForwarding {
     fn forwarded_port(&self) -> u16 {
-        self.port
+        self.port.load(Ordering::SeqCst)
     }
 }
 
 impl Drop for AzireVpnPortForwarding {
     fn drop(&mut self) {
-        let cmd = [
-            "curl",
-            "-X",
-            "DELETE",
-            "https://api.azirevpn.com/v3/portforwardings",
-            "-H",
-            Box::leak(format!("Authorization: Bearer {}", self.access_token).into_boxed_str()),
-            "--json",
-            Box::leak(
-                format!(
-                    "{{\"internal_ipv4\": \"{}\", \"port\": {}}}",
-                    self.local_ip, self.port
-                )
-                .into_boxed_str(),
-            ),
-        ];
+        let handle = self.loop_thread_handle.take();
+        if let Some(h) = handle {
+            self.send_channel.send(true).ok();
+            h.join().ok();
+        }
 
-        // Note this must run BEFORE the network namespace is destroyed
-        let output = std::process::Command::new("ip")
-            .arg("netns")
-            .arg("exec")
-            .arg(&self.netns_name)
-            .args(cmd)
-            .output()
-            .expect("Failed to destroy AzireVPN Port Forwarding");
-
-        let output_string = String::from_utf8(output.stdout.clone()).unwrap();
-        log::info!("AzireVPN Port forwarding destroyed: {output_string}");
+        Self::delete_port(
+            &self.netns_name,
+            &self.access_token,
+            self.local_ip,
+            self.forwarded_port(),
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn usable_port_skips_expiring_mappings() {
+        let now = 1_000_000;
+        let ports = vec![
+            PortData {
+                port: 1111,
+                hidden: false,
+                expires_at: now + 60,
+            },
+            PortData {
+                port: 2222,
+                hidden: false,
+                expires_at: now + 2 * RENEW_BEFORE_SECS,
+            },
+        ];
+        assert_eq!(AzireVpnPortForwarding::usable_port(&ports, now), Some(2222));
+        assert_eq!(AzireVpnPortForwarding::usable_port(&ports[..1], now), None);
     }
 }
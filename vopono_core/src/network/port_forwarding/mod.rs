@@ -1,12 +1,17 @@
 use std::sync::mpsc::Receiver;
 
 use super::netns::NetworkNamespace;
+use crate::util::events::{Event, emit};
 
 pub mod azirevpn;
 pub mod natpmpc;
 pub mod piapf;
 
-pub trait Forwarder {
+/// Consecutive failed refreshes after which the forwarder thread stops
+pub const MAX_REFRESH_FAILURES: u32 = 5;
+
+/// Send so the forwarder can be replaced from another thread when the tunnel is replaced
+pub trait Forwarder: Send {
     fn forwarded_port(&self) -> u16;
 }
 
@@ -26,6 +31,7 @@ pub trait ThreadLoopForwarder: Forwarder {
 
     /// Provided common implementation for thread loop
     fn thread_loop(params: Self::ThreadParams, recv: Receiver<bool>) {
+        let mut failures = 0;
         loop {
             let resp = recv.recv_timeout(std::time::Duration::from_secs(params.get_loop_delay()));
             if resp.is_ok() {
@@ -33,13 +39,36 @@ pub trait ThreadLoopForwarder: Forwarder {
                 return;
             } else {
                 let port = Self::refresh_port(&params);
+                let netns_name = params.get_netns_name();
+                // Counted in the lockfile for vopono metrics, then retried after the delay
                 match port {
                     Err(e) => {
                         log::error!("Thread failed to refresh port: {e:?}");
-                        return;
+                        NetworkNamespace::update_lockfile(&netns_name, |lock| {
+                            lock.refresh_failures += 1
+                        })
+                        .ok();
+                        failures += 1;
+                        if failures >= MAX_REFRESH_FAILURES {
+                            log::error!(
+                                "Giving up on refreshing the forwarded port after {failures} failures in a row"
+                            );
+                            return;
+                        }
                     }
                     Ok(p) => {
+                        failures = 0;
                         log::debug!("Thread refreshed port: {p}");
+                        NetworkNamespace::update_lockfile(&netns_name, |lock| {
+                            if lock.forwarded_port != Some(p) {
+                                emit(Event::PortForwarded {
+                                    namespace: &netns_name,
+                                    port: p,
+                                });
+                            }
+                            lock.forwarded_port = Some(p)
+                        })
+                        .ok();
                         Self::callback_command(&params, p);
                     }
                 }
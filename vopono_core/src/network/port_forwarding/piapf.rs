@@ -52,6 +52,19 @@ impl Piapf {
     ) -> anyhow::Result<Self> {
         let pia = PrivateInternetAccess {};
 
+        if let Some(region) = pia.region_for_config(protocol, config_file) {
+            if !region.port_forward {
+                log::error!(
+                    "PIA region {} does not support port forwarding",
+                    region.name
+                );
+                anyhow::bail!(
+                    "PIA region {} does not support port forwarding, choose a server with the PortForwarding feature from vopono servers pia",
+                    region.name
+                )
+            }
+        }
+
         if which("traceroute").is_err() {
             log::error!(
                 "The traceroute utility is necessary for PIA port forwarding. Please install traceroute."
@@ -0,0 +1,376 @@
+// Publish ports of services running in the network namespace on the host (like docker -p)
+// Connections to the host port (from the LAN or the host itself) are DNATed to the namespace
+// veth IP and masqueraded, so the replies return over the veth pair rather than the tunnel.
+
+use super::firewall::Firewall;
+use super::netns::NetworkNamespace;
+use crate::util::sudo_command;
+use anyhow::{Context, anyhow};
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+use std::fmt::Display;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PublishProtocol {
+    Tcp,
+    Udp,
+}
+
+impl Display for PublishProtocol {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Tcp => write!(f, "tcp"),
+            Self::Udp => write!(f, "udp"),
+        }
+    }
+}
+
+/// Port mapping given as [host_ip:]host_port:namespace_port[/tcp|/udp]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(try_from = "String")]
+pub struct PublishedPort {
+    pub host_ip: Option<IpAddr>,
+    pub host_port: u16,
+    pub namespace_port: u16,
+    pub protocol: PublishProtocol,
+}
+
+impl FromStr for PublishedPort {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (mapping, protocol) = match s.rsplit_once('/') {
+            Some((mapping, "tcp")) => (mapping, PublishProtocol::Tcp),
+            Some((mapping, "udp")) => (mapping, PublishProtocol::Udp),
+            Some((_, p)) => return Err(anyhow!("Invalid protocol {p} in published port {s}")),
+            None => (s, PublishProtocol::Tcp),
+        };
+        let (rest, namespace_port) = mapping
+            .rsplit_once(':')
+            .ok_or_else(|| anyhow!("Published port must be host_port:namespace_port: {s}"))?;
+        let (host_ip, host_port) = match rest.rsplit_once(':') {
+            Some((ip, port)) => (
+                Some(
+                    ip.parse::<IpAddr>()
+                        .with_context(|| format!("Invalid host IP in published port {s}"))?,
+                ),
+                port,
+            ),
+            None => (None, rest),
+        };
+        if host_ip.is_some_and(|ip| ip.is_ipv6()) {
+            return Err(anyhow!("Only IPv4 host addresses can be published: {s}"));
+        }
+        Ok(Self {
+            host_ip,
+            host_port: host_port
+                .parse()
+                .with_context(|| format!("Invalid host port in published port {s}"))?,
+            namespace_port: namespace_port
+                .parse()
+                .with_context(|| format!("Invalid namespace port in published port {s}"))?,
+            protocol,
+        })
+    }
+}
+
+impl TryFrom<String> for PublishedPort {
+    type Error = anyhow::Error;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl Display for PublishedPort {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if let Some(ip) = self.host_ip {
+            write!(f, "{ip}:")?;
+        }
+        write!(
+            f,
+            "{}:{}/{}",
+            self.host_port, self.namespace_port, self.protocol
+        )
+    }
+}
+
+/// DNAT rules on the host for published ports, removed on drop. These belong to the vopono
+/// instance that added them, so are removed even if the namespace is still in use.
+#[derive(Debug)]
+pub struct PortPublish {
+    ports: Vec<PublishedPort>,
+    namespace_ip: IpAddr,
+    host_veth: String,
+    firewall: Firewall,
+    nft_table: String,
+}
+
+impl PortPublish {
+    pub fn new(
+        netns: &NetworkNamespace,
+        ports: Vec<PublishedPort>,
+        firewall: Firewall,
+    ) -> anyhow::Result<Self> {
+        let namespace_ip = netns
+            .veth_pair_ips
+            .as_ref()
+            .context("Network namespace has no veth pair to publish ports on")?
+            .namespace_ip;
+        // The host side of the veth pair is a bridge port in bridge mode
+        let host_veth = match netns.bridge.as_ref() {
+            Some(bridge) => bridge.name.clone(),
+            None => netns
+                .veth_pair
+                .as_ref()
+                .context("Network namespace has no veth pair to publish ports on")?
+                .dest
+                .clone(),
+        };
+
+        // Allow the published ports through the namespace killswitch
+        for protocol in [PublishProtocol::Tcp, PublishProtocol::Udp] {
+            let ns_ports: Vec<u16> = ports
+                .iter()
+                .filter(|p| p.protocol == protocol)
+                .map(|p| p.namespace_port)
+                .collect();
+            if !ns_ports.is_empty() {
+                crate::util::open_ports_with_protocol(
+                    netns,
+                    &ns_ports,
+                    &protocol.to_string(),
+                    firewall,
+                )?;
+            }
+        }
+
+        let publish = Self {
+            ports,
+            namespace_ip,
+            host_veth,
+            firewall,
+            nft_table: format!("vopono_publish_{}", std::process::id()),
+        };
+
+        match firewall {
+            Firewall::IpTables => {
+                for port in publish.ports.iter() {
+                    for rule in publish.iptables_rules(port) {
+                        // Insert forward rule in case of an existing DROP rule (e.g. from ufw)
+                        let action = if rule.2 == "FORWARD" { "-I" } else { "-A" };
+                        let mut args = vec![rule.0, "-t", rule.1, action, rule.2];
+                        args.extend(rule.3.iter().map(|x| x.as_str()));
+                        sudo_command(&args).with_context(|| {
+                            format!("Failed to add iptables rule to publish port {port}")
+                        })?;
+                    }
+                }
+            }
+            Firewall::NfTables => publish.add_nftables_rules()?,
+        }
+        for port in publish.ports.iter() {
+            log::info!(
+                "Published port {} on host to {}:{}",
+                port,
+                publish.namespace_ip,
+                port.namespace_port
+            );
+        }
+        Ok(publish)
+    }
+
+    /// (command, table, chain, rule) for each iptables rule needed for the port
+    fn iptables_rules(
+        &self,
+        port: &PublishedPort,
+    ) -> Vec<(&'static str, &'static str, &'static str, Vec<String>)> {
+        let protocol = port.protocol.to_string();
+        let destination = format!("{}:{}", self.namespace_ip, port.namespace_port);
+        let mut dnat = vec![
+            "-p".to_string(),
+            protocol.clone(),
+            "--dport".to_string(),
+            port.host_port.to_string(),
+        ];
+        if let Some(ip) = port.host_ip {
+            dnat.extend(["-d".to_string(), ip.to_string()]);
+        }
+        dnat.extend([
+            "-j".to_string(),
+            "DNAT".to_string(),
+            "--to-destination".to_string(),
+            destination,
+        ]);
+        // Connections from the host itself to its own (non-loopback) addresses
+        let mut local_dnat = vec![
+            "-m".to_string(),
+            "addrtype".to_string(),
+            "--dst-type".to_string(),
+            "LOCAL".to_string(),
+        ];
+        if port.host_ip.is_none() {
+            local_dnat.extend(["!".to_string(), "-d".to_string(), "127.0.0.0/8".to_string()]);
+        }
+        local_dnat.extend(dnat.clone());
+
+        let to_namespace = vec![
+            "-o".to_string(),
+            self.host_veth.clone(),
+            "-d".to_string(),
+            self.namespace_ip.to_string(),
+            "-p".to_string(),
+            protocol,
+            "--dport".to_string(),
+            port.namespace_port.to_string(),
+        ];
+        let mut masquerade = to_namespace.clone();
+        masquerade.extend(["-j".to_string(), "MASQUERADE".to_string()]);
+        let mut forward = to_namespace;
+        forward.extend(["-j".to_string(), "ACCEPT".to_string()]);
+
+        vec![
+            ("iptables", "nat", "PREROUTING", dnat),
+            ("iptables", "nat", "OUTPUT", local_dnat),
+            ("iptables", "nat", "POSTROUTING", masquerade),
+            ("iptables", "filter", "FORWARD", forward),
+        ]
+    }
+
+    fn add_nftables_rules(&self) -> anyhow::Result<()> {
+        let table = self.nft_table.as_str();
+        sudo_command(&["nft", "add", "table", "ip", table])
+            .with_context(|| format!("Failed to create nft table {table}"))?;
+        for (chain, spec) in [
+            ("prerouting", "{ type nat hook prerouting priority -100 ; }"),
+            ("output", "{ type nat hook output priority -100 ; }"),
+            (
+                "postrouting",
+                "{ type nat hook postrouting priority 100 ; }",
+            ),
+            ("forward", "{ type filter hook forward priority -10 ; }"),
+        ] {
+            sudo_command(&["nft", "add", "chain", "ip", table, chain, spec])
+                .with_context(|| format!("Failed to create nft {chain} chain in {table}"))?;
+        }
+
+        for port in self.ports.iter() {
+            let protocol = port.protocol.to_string();
+            let host_port = port.host_port.to_string();
+            let namespace_port = port.namespace_port.to_string();
+            let namespace_ip = self.namespace_ip.to_string();
+            let destination = format!("{}:{}", self.namespace_ip, port.namespace_port);
+            let host_ip = port.host_ip.map(|ip| ip.to_string());
+            let mut dnat: Vec<&str> = Vec::new();
+            if let Some(ip) = host_ip.as_ref() {
+                dnat.extend(["ip", "daddr", ip]);
+            }
+            dnat.extend([
+                &protocol,
+                "dport",
+                &host_port,
+                "counter",
+                "dnat",
+                "to",
+                &destination,
+            ]);
+
+            let mut prerouting = vec!["nft", "add", "rule", "ip", table, "prerouting"];
+            prerouting.extend(dnat.iter());
+            let mut output = vec![
+                "nft",
+                "add",
+                "rule",
+                "ip",
+                table,
+                "output",
+                "fib",
+                "daddr",
+                "type",
+                "local",
+                "ip",
+                "daddr",
+                "!=",
+                "127.0.0.0/8",
+            ];
+            output.extend(dnat.iter());
+            let to_namespace = [
+                "oifname",
+                &self.host_veth,
+                "ip",
+                "daddr",
+                &namespace_ip,
+                &protocol,
+                "dport",
+                &namespace_port,
+                "counter",
+            ];
+            let mut postrouting = vec!["nft", "add", "rule", "ip", table, "postrouting"];
+            postrouting.extend(to_namespace.iter());
+            postrouting.push("masquerade");
+            let mut forward = vec!["nft", "add", "rule", "ip", table, "forward"];
+            forward.extend(to_namespace.iter());
+            forward.push("accept");
+
+            for rule in [prerouting, output, postrouting, forward] {
+                sudo_command(&rule).with_context(|| {
+                    format!("Failed to add nftables rule to publish port {port}")
+                })?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Drop for PortPublish {
+    fn drop(&mut self) {
+        match self.firewall {
+            Firewall::IpTables => {
+                for port in self.ports.iter() {
+                    for rule in self.iptables_rules(port) {
+                        let mut args = vec![rule.0, "-t", rule.1, "-D", rule.2];
+                        args.extend(rule.3.iter().map(|x| x.as_str()));
+                        if let Err(e) = sudo_command(&args) {
+                            warn!(
+                                "Failed to delete iptables rule for published port {port}: {e:?}"
+                            );
+                        }
+                    }
+                }
+            }
+            Firewall::NfTables => {
+                if let Err(e) = sudo_command(&["nft", "delete", "table", "ip", &self.nft_table]) {
+                    warn!("Failed to delete nft table {}: {:?}", self.nft_table, e);
+                }
+            }
+        }
+        debug!("Removed published ports: {:?}", self.ports);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_published_ports() {
+        let port: PublishedPort = "8080:80".parse().unwrap();
+        assert_eq!(
+            port,
+            PublishedPort {
+                host_ip: None,
+                host_port: 8080,
+                namespace_port: 80,
+                protocol: PublishProtocol::Tcp,
+            }
+        );
+        let port: PublishedPort = "192.168.1.2:5353:53/udp".parse().unwrap();
+        assert_eq!(port.host_ip, Some("192.168.1.2".parse().unwrap()));
+        assert_eq!(port.protocol, PublishProtocol::Udp);
+        assert_eq!(port.to_string(), "192.168.1.2:5353:53/udp");
+        assert!("8080".parse::<PublishedPort>().is_err());
+        assert!("8080:80/sctp".parse::<PublishedPort>().is_err());
+    }
+}
@@ -0,0 +1,131 @@
+// Bandwidth limit for a network namespace
+// A token bucket filter (tc tbf) is added to both ends of the veth pair: the namespace end
+// limits upload and the host end limits download. All traffic of the namespace, including the
+// encrypted tunnel traffic, passes through the veth pair. The qdiscs are removed with the veth
+// pair when the namespace is torn down.
+
+use super::netns::NetworkNamespace;
+use crate::util::sudo_command;
+use anyhow::{Context, anyhow};
+use log::info;
+use serde::{Deserialize, Serialize};
+use std::fmt::Display;
+use std::str::FromStr;
+
+/// Rate given with tc units, e.g. 10mbit, 500kbit or 2mbps (bytes per second)
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(try_from = "String")]
+pub struct RateLimit {
+    pub bits_per_second: u64,
+}
+
+impl FromStr for RateLimit {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let lower = s.trim().to_lowercase();
+        let split = lower
+            .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+            .ok_or_else(|| anyhow!("Rate limit needs a unit, e.g. 10mbit: {s}"))?;
+        let (value, unit) = lower.split_at(split);
+        let value: f64 = value
+            .parse()
+            .with_context(|| format!("Invalid rate limit: {s}"))?;
+        let multiplier: f64 = match unit {
+            "bit" => 1.0,
+            "kbit" => 1e3,
+            "mbit" => 1e6,
+            "gbit" => 1e9,
+            "bps" => 8.0,
+            "kbps" => 8e3,
+            "mbps" => 8e6,
+            "gbps" => 8e9,
+            _ => return Err(anyhow!("Invalid rate limit unit {unit} in {s}")),
+        };
+        let bits_per_second = (value * multiplier) as u64;
+        // tbf cannot shape below a few kbit/s
+        if bits_per_second < 8000 {
+            return Err(anyhow!("Rate limit must be at least 8kbit: {s}"));
+        }
+        Ok(Self { bits_per_second })
+    }
+}
+
+impl TryFrom<String> for RateLimit {
+    type Error = anyhow::Error;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl Display for RateLimit {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}bit", self.bits_per_second)
+    }
+}
+
+impl RateLimit {
+    /// Bucket size in bytes: 10ms of traffic at the rate, so the timer resolution of the
+    /// kernel does not reduce the achieved rate
+    fn burst(&self) -> u64 {
+        (self.bits_per_second / 8 / 100).max(16 * 1024)
+    }
+
+    pub fn apply(&self, netns: &NetworkNamespace) -> anyhow::Result<()> {
+        let veth = netns
+            .veth_pair
+            .as_ref()
+            .context("Network namespace has no veth pair to limit")?;
+        which::which("tc").context("tc (from iproute2) is required for --rate-limit")?;
+
+        let rate = self.to_string();
+        let burst = self.burst().to_string();
+        let tbf = [
+            "root", "tbf", "rate", &rate, "burst", &burst, "latency", "50ms",
+        ];
+        let mut upload = vec![
+            "ip",
+            "netns",
+            "exec",
+            &netns.name,
+            "tc",
+            "qdisc",
+            "add",
+            "dev",
+            &veth.source,
+        ];
+        upload.extend(tbf);
+        sudo_command(&upload)
+            .with_context(|| format!("Failed to add rate limit on {}", veth.source))?;
+        let mut download = vec!["tc", "qdisc", "add", "dev", &veth.dest];
+        download.extend(tbf);
+        sudo_command(&download)
+            .with_context(|| format!("Failed to add rate limit on {}", veth.dest))?;
+        info!(
+            "Limited network namespace {} to {} in each direction",
+            netns.name, self
+        );
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_rate_limits() {
+        assert_eq!(
+            "10mbit".parse::<RateLimit>().unwrap().bits_per_second,
+            10_000_000
+        );
+        assert_eq!(
+            "1.5MBps".parse::<RateLimit>().unwrap().bits_per_second,
+            12_000_000
+        );
+        assert!("10".parse::<RateLimit>().is_err());
+        assert!("10mb".parse::<RateLimit>().is_err());
+        assert!("1kbit".parse::<RateLimit>().is_err());
+    }
+}
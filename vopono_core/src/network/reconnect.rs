@@ -0,0 +1,183 @@
+// Automatic reconnection of a failed VPN tunnel, for --reconnect
+// A monitor thread checks the tunnel of the namespace at an interval. A Wireguard tunnel has
+// failed when its last handshake is older than the timeout (peers handshake every two minutes
+// while traffic flows, and vopono's keepalive keeps it flowing), an OpenVPN tunnel when the
+// OpenVPN process has exited or given up. The tunnel is then replaced by the given reconnect
+// function, which sets it up again as vopono exec did, in the same namespace so the application
+// keeps running. The killswitch on the veth stays in place meanwhile, so nothing leaks.
+
+use super::netns::NetworkNamespace;
+use super::status::tunnel_status;
+use crate::util::events::{Event, emit};
+use log::{debug, error, info, warn};
+use std::sync::mpsc::{Sender, channel};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::thread::JoinHandle;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReconnectPolicy {
+    /// Consecutive failed attempts before giving up
+    pub retries: u32,
+    pub interval: Duration,
+    pub handshake_timeout: Duration,
+}
+
+impl Default for ReconnectPolicy {
+    fn default() -> Self {
+        Self {
+            retries: 5,
+            interval: Duration::from_secs(30),
+            handshake_timeout: Duration::from_secs(180),
+        }
+    }
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|x| x.as_secs())
+        .unwrap_or_default()
+}
+
+/// Whether a Wireguard tunnel connected at connected_at has failed, as seconds since the epoch
+fn handshake_failure(
+    last_handshake: Option<u64>,
+    connected_at: u64,
+    now: u64,
+    timeout: u64,
+) -> Option<String> {
+    // A new tunnel gets the timeout for its first handshake
+    let since = last_handshake.unwrap_or(connected_at).max(connected_at);
+    let age = now.saturating_sub(since);
+    (age > timeout).then(|| match last_handshake {
+        Some(_) => format!("last Wireguard handshake was {age}s ago"),
+        None => format!("no Wireguard handshake in {age}s"),
+    })
+}
+
+/// Why the tunnel of the namespace has failed, if it has
+pub fn tunnel_failure(
+    ns: &NetworkNamespace,
+    connected_at: u64,
+    policy: &ReconnectPolicy,
+) -> Option<String> {
+    if let Some(openvpn) = ns.openvpn.as_ref() {
+        if !openvpn.check_if_running() {
+            return Some("OpenVPN has exited".to_string());
+        }
+        return match tunnel_status(ns).openvpn_state {
+            Some(state @ ("exited" | "authentication failed")) => {
+                Some(format!("OpenVPN state is {state}"))
+            }
+            _ => None,
+        };
+    }
+    if ns.wireguard.is_some() {
+        let status = tunnel_status(ns);
+        if status.interface.is_none() {
+            return Some("the Wireguard interface is gone".to_string());
+        }
+        return handshake_failure(
+            status.last_handshake,
+            connected_at,
+            now_secs(),
+            policy.handshake_timeout.as_secs(),
+        );
+    }
+    None
+}
+
+/// Thread replacing the tunnel when it fails, stopped when dropped
+pub struct ReconnectMonitor {
+    stop: Sender<()>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl ReconnectMonitor {
+    pub fn start<F>(
+        ns: Arc<Mutex<NetworkNamespace>>,
+        policy: ReconnectPolicy,
+        mut reconnect: F,
+    ) -> Self
+    where
+        F: FnMut(&mut NetworkNamespace) -> anyhow::Result<()> + Send + 'static,
+    {
+        let (stop, recv) = channel();
+        let handle = std::thread::spawn(move || {
+            let mut connected_at = now_secs();
+            let mut failures = 0;
+            // Returns once stopped
+            while recv.recv_timeout(policy.interval).is_err() {
+                let mut ns = ns.lock().unwrap_or_else(PoisonError::into_inner);
+                if failures == 0 {
+                    let Some(reason) = tunnel_failure(&ns, connected_at, &policy) else {
+                        continue;
+                    };
+                    warn!("VPN tunnel of {} failed: {reason}, reconnecting", ns.name);
+                    emit(Event::TunnelDown {
+                        namespace: &ns.name,
+                        reason,
+                    });
+                }
+                match reconnect(&mut ns) {
+                    Ok(()) => {
+                        info!("Reconnected VPN tunnel of {}", ns.name);
+                        failures = 0;
+                        connected_at = now_secs();
+                        NetworkNamespace::update_lockfile(&ns.name, |lock| lock.reconnects += 1)
+                            .ok();
+                    }
+                    Err(e) => {
+                        failures += 1;
+                        if failures >= policy.retries {
+                            error!(
+                                "Failed to reconnect VPN tunnel of {} after {failures} attempts, giving up: {e:?}",
+                                ns.name
+                            );
+                            return;
+                        }
+                        warn!(
+                            "Failed to reconnect VPN tunnel of {} (attempt {failures} of {}): {e:?}",
+                            ns.name, policy.retries
+                        );
+                    }
+                }
+            }
+            debug!("Reconnect monitor exiting");
+        });
+        Self {
+            stop,
+            handle: Some(handle),
+        }
+    }
+}
+
+impl Drop for ReconnectMonitor {
+    fn drop(&mut self) {
+        self.stop.send(()).ok();
+        if let Some(handle) = self.handle.take() {
+            handle.join().ok();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stale_wireguard_handshake() {
+        assert_eq!(handshake_failure(Some(1000), 900, 1100, 180), None);
+        assert_eq!(
+            handshake_failure(Some(1000), 900, 1200, 180).as_deref(),
+            Some("last Wireguard handshake was 200s ago")
+        );
+        // Handshakes before a reconnect do not count against the new tunnel
+        assert_eq!(handshake_failure(Some(500), 1000, 1100, 180), None);
+        assert_eq!(
+            handshake_failure(None, 1000, 1200, 180).as_deref(),
+            Some("no Wireguard handshake in 200s")
+        );
+    }
+}
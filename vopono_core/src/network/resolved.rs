@@ -0,0 +1,88 @@
+// systemd-resolved integration for the namespace DNS
+// With --systemd-resolved, the namespace keeps seeing the host's /etc/resolv.conf, which points
+// at resolved's stub listener on 127.0.0.53. vopono does not bind mount its own resolv.conf over
+// it; the stub resolver listens on 127.0.0.53 inside the namespace instead, where resolved itself
+// cannot be reached, and forwards through the tunnel. The host end of the veth pair is configured
+// in resolved over its D-Bus API with LLMNR and mDNS disabled, so resolved does not send multicast
+// queries into the namespace. No DNS servers are set for it: resolved's per-link DNS only applies
+// to host lookups, which must not go to the VPN DNS. The link settings are reverted on shutdown.
+
+use super::dns_config::read_nameservers;
+use anyhow::{Context, anyhow};
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+use std::net::{IpAddr, Ipv4Addr};
+use std::process::Command;
+
+/// Address of resolved's stub listener
+pub const RESOLVED_STUB_ADDRESS: IpAddr = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 53));
+
+/// Whether the host's /etc/resolv.conf uses the systemd-resolved stub listener
+pub fn host_uses_resolved() -> bool {
+    read_nameservers("/etc/resolv.conf") == [RESOLVED_STUB_ADDRESS]
+}
+
+/// Call a method of org.freedesktop.resolve1.Manager with busctl
+fn call_manager(method: &str, signature: &str, args: &[String]) -> anyhow::Result<()> {
+    let output = Command::new("busctl")
+        .args([
+            "call",
+            "org.freedesktop.resolve1",
+            "/org/freedesktop/resolve1",
+            "org.freedesktop.resolve1.Manager",
+            method,
+            signature,
+        ])
+        .args(args)
+        .output()
+        .context("Failed to run busctl")?;
+    if !output.status.success() {
+        return Err(anyhow!(
+            "systemd-resolved {method} failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    Ok(())
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ResolvedLink {
+    interface: String,
+    ifindex: u32,
+}
+
+impl ResolvedLink {
+    /// Disable multicast name resolution for the host end of the veth pair
+    pub fn new(interface: &str, ifindex: u32) -> anyhow::Result<Self> {
+        which::which("busctl")
+            .context("Cannot find busctl, which is needed to configure systemd-resolved")?;
+        // Reverted on drop if any of the settings fail
+        let link = Self {
+            interface: interface.to_string(),
+            ifindex,
+        };
+        let index = ifindex.to_string();
+        call_manager(
+            "SetLinkDefaultRoute",
+            "ib",
+            &[index.clone(), "false".to_string()],
+        )?;
+        call_manager("SetLinkLLMNR", "is", &[index.clone(), "no".to_string()])?;
+        call_manager("SetLinkMulticastDNS", "is", &[index, "no".to_string()])?;
+        debug!("Configured systemd-resolved link {interface}");
+        Ok(link)
+    }
+}
+
+impl Drop for ResolvedLink {
+    fn drop(&mut self) {
+        match call_manager("RevertLink", "i", &[self.ifindex.to_string()]) {
+            Ok(()) => debug!("Reverted systemd-resolved link {}", self.interface),
+            // The link is gone once the veth pair has been deleted
+            Err(e) => warn!(
+                "Failed to revert systemd-resolved link {}: {e:?}",
+                self.interface
+            ),
+        }
+    }
+}
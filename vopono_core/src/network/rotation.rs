@@ -0,0 +1,119 @@
+// Scheduled server rotation, for --rotate
+// A thread replaces the tunnel at each interval with one to another server matching the server
+// prefix and filters, by the function given by vopono exec, in the same namespace so the
+// application keeps running. Port forwarding is set up again on the new server. As with
+// --reconnect, the killswitch on the veth stays in place while the tunnel is replaced.
+
+use super::netns::NetworkNamespace;
+use anyhow::{Context, anyhow};
+use log::{debug, error, info};
+use serde::{Deserialize, Serialize};
+use std::str::FromStr;
+use std::sync::mpsc::{Sender, channel};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+/// Interval given in minutes or hours, e.g. 30m or 6h
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(try_from = "String")]
+pub struct RotationInterval(pub Duration);
+
+impl FromStr for RotationInterval {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let lower = s.trim().to_lowercase();
+        let split = lower
+            .find(|c: char| !c.is_ascii_digit())
+            .ok_or_else(|| anyhow!("Rotation interval needs a unit, e.g. 30m or 6h: {s}"))?;
+        let (value, unit) = lower.split_at(split);
+        let value: u64 = value
+            .parse()
+            .with_context(|| format!("Invalid rotation interval: {s}"))?;
+        let seconds = match unit {
+            "m" | "min" => value.checked_mul(60),
+            "h" => value.checked_mul(3600),
+            _ => return Err(anyhow!("Invalid rotation interval unit {unit} in {s}")),
+        }
+        .ok_or_else(|| anyhow!("Rotation interval is too long: {s}"))?;
+        if seconds == 0 {
+            return Err(anyhow!("Rotation interval must be at least 1m: {s}"));
+        }
+        Ok(Self(Duration::from_secs(seconds)))
+    }
+}
+
+impl TryFrom<String> for RotationInterval {
+    type Error = anyhow::Error;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+/// Thread moving the namespace to another server at the interval, stopped when dropped
+pub struct ServerRotation {
+    stop: Sender<()>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl ServerRotation {
+    pub fn start<F>(ns: Arc<Mutex<NetworkNamespace>>, interval: Duration, mut rotate: F) -> Self
+    where
+        F: FnMut(&mut NetworkNamespace) -> anyhow::Result<()> + Send + 'static,
+    {
+        let (stop, recv) = channel();
+        let handle = std::thread::spawn(move || {
+            // Returns once stopped
+            while recv.recv_timeout(interval).is_err() {
+                let mut ns = ns.lock().unwrap_or_else(PoisonError::into_inner);
+                info!("Rotating the server of {}", ns.name);
+                match rotate(&mut ns) {
+                    Ok(()) => info!("Rotated the server of {}", ns.name),
+                    // Tried again at the next interval
+                    Err(e) => error!("Failed to rotate the server of {}: {e:?}", ns.name),
+                }
+            }
+            debug!("Server rotation exiting");
+        });
+        Self {
+            stop,
+            handle: Some(handle),
+        }
+    }
+}
+
+impl Drop for ServerRotation {
+    fn drop(&mut self) {
+        self.stop.send(()).ok();
+        if let Some(handle) = self.handle.take() {
+            handle.join().ok();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_rotation_interval() {
+        assert_eq!(
+            "30m".parse::<RotationInterval>().unwrap().0,
+            Duration::from_secs(1800)
+        );
+        assert_eq!(
+            "6H".parse::<RotationInterval>().unwrap().0,
+            Duration::from_secs(21600)
+        );
+        assert!("30".parse::<RotationInterval>().is_err());
+        assert!("0m".parse::<RotationInterval>().is_err());
+        assert!("1d".parse::<RotationInterval>().is_err());
+        assert!(
+            format!("{}h", u64::MAX / 60)
+                .parse::<RotationInterval>()
+                .is_err()
+        );
+    }
+}
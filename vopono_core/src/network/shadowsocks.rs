@@ -8,22 +8,140 @@
 // -p port should come from remote config
 // -s should be random route IP from config
 // -k and -m can be fixed for now (Mullvad)
+//
+// A Shadowsocks server of the user (--obfuscation shadowsocks --obfuscation-server ss://...) is
+// run the same way for OpenVPN, with OpenVPN given --socks-proxy and a net_gateway route to the
+// server so the proxy's traffic is sent outside the tunnel (for Wireguard see obfuscation.rs):
+// ss-local -s <server> -p <port> -l 1080 -k <password> -m <method> -u
 use super::netns::NetworkNamespace;
 use super::openvpn::get_remotes_from_config;
 use anyhow::Context;
 use anyhow::anyhow;
+use base64::Engine;
+use base64::engine::general_purpose::{STANDARD_NO_PAD, URL_SAFE_NO_PAD};
 use log::{debug, error};
 use rand::seq::SliceRandom;
 use regex::Regex;
 use serde::{Deserialize, Serialize};
 use std::fs::read_to_string;
-use std::net::{IpAddr, Ipv4Addr};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, ToSocketAddrs};
 use std::path::Path;
 use std::str::FromStr;
 
+/// Local SOCKS port of ss-local for a Shadowsocks server of the user
+pub const SHADOWSOCKS_LOCAL_PORT: u16 = 1080;
+
+/// Shadowsocks server from an ss:// URL, in the SIP002 (ss://userinfo@host:port) or the legacy
+/// (ss://base64 of method:password@host:port) format
+#[derive(Clone, PartialEq, Eq)]
+pub struct ShadowsocksServer {
+    pub host: String,
+    pub port: u16,
+    pub method: String,
+    pub password: String,
+}
+
+impl std::fmt::Debug for ShadowsocksServer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ShadowsocksServer")
+            .field("host", &self.host)
+            .field("port", &self.port)
+            .field("method", &self.method)
+            .field("password", &"********".to_string())
+            .finish()
+    }
+}
+
+fn decode_base64(s: &str) -> Option<String> {
+    let s = s.trim_end_matches('=');
+    URL_SAFE_NO_PAD
+        .decode(s)
+        .or_else(|_| STANDARD_NO_PAD.decode(s))
+        .ok()
+        .and_then(|x| String::from_utf8(x).ok())
+}
+
+/// Decode the %XX escapes of a URL component
+fn percent_decode(s: &str) -> Option<String> {
+    let mut bytes = Vec::with_capacity(s.len());
+    let mut rest = s.as_bytes();
+    while let Some((&byte, tail)) = rest.split_first() {
+        if byte == b'%' {
+            let hex = std::str::from_utf8(tail.get(..2)?).ok()?;
+            bytes.push(u8::from_str_radix(hex, 16).ok()?);
+            rest = &tail[2..];
+        } else {
+            bytes.push(byte);
+            rest = tail;
+        }
+    }
+    String::from_utf8(bytes).ok()
+}
+
+impl FromStr for ShadowsocksServer {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || anyhow!("Invalid Shadowsocks URL, expected ss://...: {s}");
+        let rest = s.trim().strip_prefix("ss://").ok_or_else(invalid)?;
+        // Drop the #tag
+        let rest = rest.split('#').next().unwrap_or_default();
+        let (rest, query) = rest.split_once('?').unwrap_or((rest, ""));
+        // The server would not be reached without the plugin (e.g. obfs or v2ray)
+        if let Some(plugin) = query
+            .split('&')
+            .find_map(|x| x.strip_prefix("plugin="))
+            .filter(|x| !x.is_empty())
+        {
+            return Err(anyhow!(
+                "Shadowsocks plugins are not supported, use a server without {}: {s}",
+                percent_decode(plugin).unwrap_or_else(|| plugin.to_string())
+            ));
+        }
+        let rest = rest.trim_end_matches('/');
+        let (userinfo, address) = match rest.rsplit_once('@') {
+            Some((userinfo, address)) => (
+                match decode_base64(userinfo) {
+                    Some(userinfo) => userinfo,
+                    None => percent_decode(userinfo).ok_or_else(invalid)?,
+                },
+                address.to_string(),
+            ),
+            None => {
+                let decoded = decode_base64(rest).ok_or_else(invalid)?;
+                let (userinfo, address) = decoded.rsplit_once('@').ok_or_else(invalid)?;
+                (userinfo.to_string(), address.to_string())
+            }
+        };
+        let (method, password) = userinfo.split_once(':').ok_or_else(invalid)?;
+        let (host, port) = address.rsplit_once(':').ok_or_else(invalid)?;
+        Ok(Self {
+            host: host.trim_matches(['[', ']']).to_string(),
+            port: port
+                .parse()
+                .with_context(|| format!("Invalid port in Shadowsocks URL: {s}"))?,
+            method: method.to_string(),
+            password: password.to_string(),
+        })
+    }
+}
+
+impl ShadowsocksServer {
+    pub fn resolve(&self) -> anyhow::Result<SocketAddr> {
+        (self.host.as_str(), self.port)
+            .to_socket_addrs()
+            .with_context(|| format!("Failed to resolve Shadowsocks server {}", self.host))?
+            .next()
+            .ok_or_else(|| anyhow!("Failed to resolve Shadowsocks server {}", self.host))
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Shadowsocks {
     pid: u32,
+    /// Server of the user, to be reached outside the tunnel
+    #[serde(default)]
+    pub server: Option<SocketAddr>,
 }
 
 impl Shadowsocks {
@@ -77,7 +195,57 @@ impl Shadowsocks {
         )
         .context("Failed to launch Shadowsocks - is shadowsocks-libev installed?")?;
 
-        Ok(Self { pid: handle.id() })
+        Ok(Self {
+            pid: handle.id(),
+            server: None,
+        })
+    }
+
+    /// Run ss-local for a Shadowsocks server of the user, as a SOCKS proxy for OpenVPN
+    pub fn run_server(
+        netns: &NetworkNamespace,
+        server: &ShadowsocksServer,
+    ) -> anyhow::Result<Self> {
+        debug!("Launching Shadowsocks for server {}", server.host);
+        if let Err(x) = which::which("ss-local") {
+            return Err(anyhow!(
+                "Cannot find ss-local, is shadowsocks-libev installed?: {:?}",
+                x
+            ));
+        }
+        let addr = server.resolve()?;
+        let ip_str = addr.ip().to_string();
+        let port_str = addr.port().to_string();
+        let listen_port_str = SHADOWSOCKS_LOCAL_PORT.to_string();
+        let handle = NetworkNamespace::exec_no_block(
+            &netns.name,
+            &[
+                "ss-local",
+                "-s",
+                &ip_str,
+                "-p",
+                &port_str,
+                "-l",
+                &listen_port_str,
+                "-k",
+                &server.password,
+                "-m",
+                &server.method,
+                "-u",
+            ],
+            None,
+            None,
+            true,
+            false,
+            false,
+            None,
+        )
+        .context("Failed to launch Shadowsocks - is shadowsocks-libev installed?")?;
+
+        Ok(Self {
+            pid: handle.id(),
+            server: Some(addr),
+        })
     }
 }
 
@@ -137,3 +305,38 @@ impl Drop for Shadowsocks {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_shadowsocks_urls() {
+        let server: ShadowsocksServer =
+            "ss://Y2hhY2hhMjAtaWV0Zi1wb2x5MTMwNTpzZWNyZXQ@203.0.113.5:8388/?outline=1#home"
+                .parse()
+                .unwrap();
+        assert_eq!(server.host, "203.0.113.5");
+        assert_eq!(server.port, 8388);
+        assert_eq!(server.method, "chacha20-ietf-poly1305");
+        assert_eq!(server.password, "secret");
+        let plugin = "ss://Y2hhY2hhMjAtaWV0Zi1wb2x5MTMwNTpzZWNyZXQ@203.0.113.5:8388/?plugin=obfs-local%3Bobfs%3Dhttp";
+        let error = plugin.parse::<ShadowsocksServer>().unwrap_err();
+        assert!(error.to_string().contains("obfs-local;obfs=http"));
+        // Plain userinfo of Shadowsocks 2022 ciphers, percent-encoded
+        let server: ShadowsocksServer =
+            "ss://2022-blake3-aes-256-gcm:YctPZ6U7xPPcU%2Bgp3u%2BOwSyz%3D@192.0.2.1:8888"
+                .parse()
+                .unwrap();
+        assert_eq!(server.method, "2022-blake3-aes-256-gcm");
+        assert_eq!(server.password, "YctPZ6U7xPPcU+gp3u+OwSyz=");
+        // Legacy format, base64 of chacha20-ietf-poly1305:secret@ss.example.com:443
+        let server: ShadowsocksServer =
+            "ss://Y2hhY2hhMjAtaWV0Zi1wb2x5MTMwNTpzZWNyZXRAc3MuZXhhbXBsZS5jb206NDQz"
+                .parse()
+                .unwrap();
+        assert_eq!(server.host, "ss.example.com");
+        assert_eq!(server.port, 443);
+        assert!("http://example.com".parse::<ShadowsocksServer>().is_err());
+    }
+}
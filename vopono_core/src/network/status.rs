@@ -0,0 +1,112 @@
+// Connection status of running network namespaces, for vopono status
+// The tunnel is the interface the namespace routes internet traffic through. Its link state comes
+// from ip link, the last Wireguard handshake from wg show, and the OpenVPN state from the last
+// state change in the OpenVPN log of the namespace.
+
+use super::mtu::tunnel_interface;
+use super::netns::NetworkNamespace;
+
+pub struct TunnelStatus {
+    pub interface: Option<String>,
+    /// Link state, e.g. UP or UNKNOWN (Wireguard and tun devices have no carrier)
+    pub state: Option<String>,
+    /// Seconds since the Unix epoch, if a handshake has happened
+    pub last_handshake: Option<u64>,
+    pub openvpn_state: Option<&'static str>,
+}
+
+/// Link state in ip -o link show output
+fn parse_link_state(output: &str) -> Option<String> {
+    output
+        .split_whitespace()
+        .skip_while(|x| *x != "state")
+        .nth(1)
+        .map(|x| x.to_string())
+}
+
+/// Most recent handshake in wg show latest-handshakes output, 0 is never
+fn parse_latest_handshake(output: &str) -> Option<u64> {
+    output
+        .lines()
+        .filter_map(|line| line.split_whitespace().nth(1))
+        .filter_map(|x| x.parse::<u64>().ok())
+        .filter(|x| *x > 0)
+        .max()
+}
+
+/// State after the last state change in an OpenVPN log
+fn parse_openvpn_state(log: &str) -> &'static str {
+    log.lines()
+        .rev()
+        .find_map(|line| {
+            if line.contains("Initialization Sequence Completed") {
+                Some("connected")
+            } else if line.contains("AUTH_FAILED") {
+                Some("authentication failed")
+            } else if line.contains("SIGUSR1") || line.contains("Restart pause") {
+                Some("reconnecting")
+            } else if line.contains("SIGTERM") || line.contains("Exiting due to fatal error") {
+                Some("exited")
+            } else {
+                None
+            }
+        })
+        .unwrap_or("connecting")
+}
+
+pub fn tunnel_status(netns: &NetworkNamespace) -> TunnelStatus {
+    let interface = tunnel_interface(&netns.name).ok();
+    let state = interface.as_deref().and_then(|interface| {
+        NetworkNamespace::exec_with_output(
+            &netns.name,
+            &["ip", "-o", "link", "show", "dev", interface],
+        )
+        .ok()
+        .and_then(|output| parse_link_state(&String::from_utf8_lossy(&output.stdout)))
+    });
+    // Xray, redsocks and tor have no tunnel interface, the state is that of their process
+    let proxy_running = netns
+        .xray
+        .as_ref()
+        .map(|x| x.check_if_running())
+        .or_else(|| netns.redsocks.as_ref().map(|x| x.check_if_running()))
+        .or_else(|| netns.tor.as_ref().map(|x| x.check_if_running()));
+    let state = state
+        .or_else(|| proxy_running.map(|running| if running { "UP" } else { "DOWN" }.to_string()));
+    let last_handshake = netns.wireguard.as_ref().and_then(|wg| {
+        NetworkNamespace::exec_with_output(
+            &netns.name,
+            &[wg.tool(), "show", &wg.if_name, "latest-handshakes"],
+        )
+        .ok()
+        .and_then(|output| parse_latest_handshake(&String::from_utf8_lossy(&output.stdout)))
+    });
+    let openvpn_state = netns.openvpn.as_ref().map(|openvpn| {
+        std::fs::read_to_string(&openvpn.logfile)
+            .map(|log| parse_openvpn_state(&log))
+            .unwrap_or("unknown")
+    });
+    TunnelStatus {
+        interface,
+        state,
+        last_handshake,
+        openvpn_state,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_tunnel_status() {
+        let link = "5: wg0: <POINTOPOINT,NOARP,UP,LOWER_UP> mtu 1420 qdisc noqueue state UNKNOWN mode DEFAULT group default qlen 1000\\    link/none \n";
+        assert_eq!(parse_link_state(link).as_deref(), Some("UNKNOWN"));
+        let handshakes = "abc=\t1700000000\ndef=\t0\n";
+        assert_eq!(parse_latest_handshake(handshakes), Some(1700000000));
+        assert_eq!(parse_latest_handshake("abc=\t0\n"), None);
+        let log = "Initialization Sequence Completed\nSIGUSR1[soft,ping-restart] received, process restarting\nRestart pause, 5 second(s)\n";
+        assert_eq!(parse_openvpn_state(log), "reconnecting");
+        assert_eq!(parse_openvpn_state("TCP/UDP: Preserving\n"), "connecting");
+    }
+}
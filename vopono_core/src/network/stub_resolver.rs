@@ -0,0 +1,879 @@
+// DNS stub resolver for the network namespace
+// Listens on 127.0.0.1:53 inside the namespace (UDP and TCP) and forwards each query unchanged to
+// the configured upstreams over DNS-over-TLS or DNS-over-HTTPS, so DNS is encrypted inside the
+// tunnel even if the provider only offers plain DNS. With only --dns-cache, overrides or
+// forwarded domains it forwards to the plain DNS servers of the namespace instead. Every vopono
+// instance using the namespace runs its own stub with SO_REUSEPORT, so DNS keeps working while
+// any of them is running.
+// The host names of DoH servers are resolved once at setup with the plain DNS servers of the
+// namespace, over the tunnel, and the address is kept with the upstream. Queries for split DNS
+// domains are passed to a thread which stays in the host network namespace, so the host's own
+// resolver (e.g. systemd-resolved on 127.0.0.53) can answer them.
+
+use super::dns_config::read_nameservers;
+use super::dns_rules::{
+    DnsCache, DnsForward, DnsOverride, ForwardTarget, Question, forward_target, override_response,
+};
+use super::resolved::RESOLVED_STUB_ADDRESS;
+use crate::network::netns::NetworkNamespace;
+use crate::util::{run_in_netns, spawn_in_netns};
+use anyhow::{Context, anyhow};
+use log::{debug, info, warn};
+use rustls::pki_types::ServerName;
+use rustls::{ClientConfig, ClientConnection, RootCertStore, StreamOwned};
+use serde::{Deserialize, Serialize};
+use socket2::{Domain, Protocol, Socket, Type};
+use std::fmt::Display;
+use std::io::{Read, Write};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, TcpListener, TcpStream, UdpSocket};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{Sender, channel, sync_channel};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+pub const STUB_ADDRESS: IpAddr = IpAddr::V4(Ipv4Addr::LOCALHOST);
+const DOT_PORT: u16 = 853;
+const DNS_MESSAGE: &str = "application/dns-message";
+const TIMEOUT: Duration = Duration::from_secs(5);
+const POLL_INTERVAL: Duration = Duration::from_millis(200);
+/// Threads answering UDP queries, and queries waiting for one before new queries are dropped
+/// (the client retries them)
+const UDP_WORKERS: usize = 8;
+const UDP_QUEUE_LEN: usize = 64;
+// Largest UDP response for clients not advertising a larger size with EDNS
+const MAX_UDP_RESPONSE: usize = 512;
+const MAX_EDNS_RESPONSE: usize = 4096;
+pub(crate) const HEADER_LEN: usize = 12;
+pub const TYPE_A: u16 = 1;
+pub const TYPE_TXT: u16 = 16;
+pub const TYPE_AAAA: u16 = 28;
+pub const CLASS_IN: u16 = 1;
+pub const CLASS_CHAOS: u16 = 3;
+
+/// Upstream DNS server
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(try_from = "String", into = "String")]
+pub enum DnsUpstream {
+    /// DNS-over-TLS, given as IP[:port][#server name], e.g. 9.9.9.9#dns.quad9.net. The server
+    /// name is used for certificate verification, or the IP address if it is not given.
+    Tls {
+        address: SocketAddr,
+        server_name: Option<String>,
+    },
+    /// DNS-over-HTTPS, given as the URL of the server, e.g. https://dns.quad9.net/dns-query,
+    /// optionally followed by #IP to skip resolving its host name
+    Https {
+        url: String,
+        address: Option<IpAddr>,
+    },
+    /// Plain DNS over UDP, or TCP for truncated answers, given as udp://IP[:port]
+    Plain { address: SocketAddr },
+}
+
+impl DnsUpstream {
+    pub fn is_https(&self) -> bool {
+        matches!(self, Self::Https { .. })
+    }
+
+    pub fn is_tls(&self) -> bool {
+        matches!(self, Self::Tls { .. })
+    }
+}
+
+impl FromStr for DnsUpstream {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.starts_with("https://") {
+            let (url, address) = match s.split_once('#') {
+                Some((url, address)) => (
+                    url,
+                    Some(
+                        address
+                            .parse::<IpAddr>()
+                            .with_context(|| format!("Invalid DoH server address in {s}"))?,
+                    ),
+                ),
+                None => (s, None),
+            };
+            let parsed = reqwest::Url::parse(url)
+                .with_context(|| format!("Invalid DNS-over-HTTPS URL: {url}"))?;
+            let host = parsed
+                .host_str()
+                .ok_or_else(|| anyhow!("No host in DNS-over-HTTPS URL: {url}"))?;
+            // IPv6 hosts are in brackets
+            let address = address.or(host
+                .trim_start_matches('[')
+                .trim_end_matches(']')
+                .parse()
+                .ok());
+            return Ok(Self::Https {
+                url: url.to_string(),
+                address,
+            });
+        }
+        if let Some(address) = s.strip_prefix("udp://") {
+            return Ok(Self::Plain {
+                address: parse_socket_address(address, 53)
+                    .ok_or_else(|| anyhow!("Invalid plain DNS upstream: {s}"))?,
+            });
+        }
+        let (address, server_name) = match s.split_once('#') {
+            Some((address, name)) if !name.is_empty() => (address, Some(name.to_string())),
+            Some(_) => return Err(anyhow!("Empty server name in DNS upstream: {s}")),
+            None => (s, None),
+        };
+        let address = parse_socket_address(address, DOT_PORT).ok_or_else(|| {
+            anyhow!("Invalid DNS-over-TLS upstream, expected IP[:port][#name]: {s}")
+        })?;
+        Ok(Self::Tls {
+            address,
+            server_name,
+        })
+    }
+}
+
+/// IP[:port], with the default port if none is given
+fn parse_socket_address(s: &str, default_port: u16) -> Option<SocketAddr> {
+    s.parse::<SocketAddr>().ok().or_else(|| {
+        s.trim_matches(|c| c == '[' || c == ']')
+            .parse::<IpAddr>()
+            .ok()
+            .map(|ip| SocketAddr::new(ip, default_port))
+    })
+}
+
+impl TryFrom<String> for DnsUpstream {
+    type Error = anyhow::Error;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl Display for DnsUpstream {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Tls {
+                address,
+                server_name: Some(name),
+            } => write!(f, "{address}#{name}"),
+            Self::Tls { address, .. } => write!(f, "{address}"),
+            Self::Https {
+                url,
+                address: Some(address),
+            } => write!(f, "{url}#{address}"),
+            Self::Https { url, .. } => write!(f, "{url}"),
+            Self::Plain { address } => write!(f, "udp://{address}"),
+        }
+    }
+}
+
+impl From<DnsUpstream> for String {
+    fn from(value: DnsUpstream) -> Self {
+        value.to_string()
+    }
+}
+
+type TlsStream = StreamOwned<ClientConnection, TcpStream>;
+type HostRequest = (Vec<u8>, Sender<anyhow::Result<Vec<u8>>>);
+
+/// Sends queries to the name servers of the host from a thread in the host network namespace
+struct HostResolver {
+    requests: Sender<HostRequest>,
+}
+
+impl HostResolver {
+    /// Must be called from a thread in the host network namespace. The thread stops once the
+    /// resolver is dropped.
+    fn start() -> Self {
+        let (requests, queue) = channel::<HostRequest>();
+        std::thread::spawn(move || {
+            for (query, reply) in queue {
+                // Threads spawned here stay in the host network namespace
+                std::thread::spawn(move || {
+                    let servers = read_nameservers("/etc/resolv.conf");
+                    let mut result = Err(anyhow!("No name servers in host /etc/resolv.conf"));
+                    for server in servers {
+                        result = exchange_plain(SocketAddr::new(server, 53), &query);
+                        if result.is_ok() {
+                            break;
+                        }
+                    }
+                    reply.send(result).ok();
+                });
+            }
+        });
+        Self { requests }
+    }
+
+    fn resolve(&self, query: &[u8]) -> anyhow::Result<Vec<u8>> {
+        let (reply, response) = channel();
+        self.requests.send((query.to_vec(), reply))?;
+        response.recv_timeout(TIMEOUT * 2)?
+    }
+}
+
+/// Answers overridden names and cached questions, and sends other queries to the forwarded
+/// domain's server or the upstreams in order until one answers, reusing idle connections
+struct Forwarder {
+    upstreams: Vec<DnsUpstream>,
+    overrides: Vec<DnsOverride>,
+    forwards: Vec<DnsForward>,
+    host: Option<HostResolver>,
+    cache: DnsCache,
+    tls_config: Arc<ClientConfig>,
+    idle: Mutex<Vec<(usize, TlsStream)>>,
+    /// Must be created in the namespace, as its connections are made on its own thread
+    http: Option<reqwest::blocking::Client>,
+}
+
+impl Forwarder {
+    fn new(
+        upstreams: Vec<DnsUpstream>,
+        overrides: Vec<DnsOverride>,
+        forwards: Vec<DnsForward>,
+        host: Option<HostResolver>,
+    ) -> anyhow::Result<Self> {
+        let roots = RootCertStore {
+            roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+        };
+        let tls_config =
+            ClientConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+                .with_safe_default_protocol_versions()?
+                .with_root_certificates(roots)
+                .with_no_client_auth();
+        let http = if upstreams.iter().any(|x| x.is_https()) {
+            let mut builder = reqwest::blocking::Client::builder()
+                .https_only(true)
+                .timeout(TIMEOUT);
+            // The host resolver of this process is not usable in the namespace
+            for upstream in upstreams.iter() {
+                if let DnsUpstream::Https {
+                    url,
+                    address: Some(address),
+                } = upstream
+                {
+                    let url = reqwest::Url::parse(url)?;
+                    if let Some(host) = url.host_str() {
+                        builder = builder.resolve(
+                            host,
+                            SocketAddr::new(*address, url.port_or_known_default().unwrap_or(443)),
+                        );
+                    }
+                }
+            }
+            Some(builder.build()?)
+        } else {
+            None
+        };
+        Ok(Self {
+            upstreams,
+            overrides,
+            forwards,
+            host,
+            cache: DnsCache::default(),
+            tls_config: Arc::new(tls_config),
+            idle: Mutex::new(Vec::new()),
+            http,
+        })
+    }
+
+    fn connect_tls(
+        &self,
+        address: SocketAddr,
+        server_name: Option<&str>,
+    ) -> anyhow::Result<TlsStream> {
+        let name = match server_name {
+            Some(name) => ServerName::try_from(name.to_string())?,
+            None => ServerName::IpAddress(address.ip().into()),
+        };
+        let tcp = TcpStream::connect_timeout(&address, TIMEOUT)?;
+        tcp.set_read_timeout(Some(TIMEOUT))?;
+        tcp.set_write_timeout(Some(TIMEOUT))?;
+        let connection = ClientConnection::new(self.tls_config.clone(), name)?;
+        Ok(StreamOwned::new(connection, tcp))
+    }
+
+    fn exchange_tls(stream: &mut TlsStream, query: &[u8]) -> anyhow::Result<Vec<u8>> {
+        write_framed(stream, query)?;
+        read_framed(stream)
+    }
+
+    fn take_idle(&self, upstream: usize) -> Option<TlsStream> {
+        let mut idle = self.idle.lock().ok()?;
+        let position = idle.iter().position(|(i, _)| *i == upstream)?;
+        Some(idle.swap_remove(position).1)
+    }
+
+    fn put_idle(&self, upstream: usize, stream: TlsStream) {
+        if let Ok(mut idle) = self.idle.lock() {
+            idle.push((upstream, stream));
+        }
+    }
+
+    fn resolve_tls(
+        &self,
+        upstream: usize,
+        address: SocketAddr,
+        server_name: Option<&str>,
+        query: &[u8],
+    ) -> anyhow::Result<Vec<u8>> {
+        // Servers close idle connections, so retry once on a new one
+        if let Some(mut stream) = self.take_idle(upstream) {
+            if let Ok(response) = Self::exchange_tls(&mut stream, query) {
+                self.put_idle(upstream, stream);
+                return Ok(response);
+            }
+        }
+        let mut stream = self.connect_tls(address, server_name)?;
+        let response = Self::exchange_tls(&mut stream, query)?;
+        self.put_idle(upstream, stream);
+        Ok(response)
+    }
+
+    fn resolve_https(&self, url: &str, query: &[u8]) -> anyhow::Result<Vec<u8>> {
+        let http = self.http.as_ref().context("No HTTP client")?;
+        let response = http
+            .post(url)
+            .header(reqwest::header::CONTENT_TYPE, DNS_MESSAGE)
+            .header(reqwest::header::ACCEPT, DNS_MESSAGE)
+            .body(query.to_vec())
+            .send()?
+            .error_for_status()?;
+        Ok(response.bytes()?.to_vec())
+    }
+
+    fn resolve(&self, query: &[u8]) -> anyhow::Result<Vec<u8>> {
+        let Some(question) = Question::parse(query) else {
+            return self.resolve_upstream(query);
+        };
+        if let Some(response) = override_response(&self.overrides, query, &question) {
+            return Ok(response);
+        }
+        if let Some(response) = self.cache.get(query, &question) {
+            return Ok(response);
+        }
+        let response = match forward_target(&self.forwards, &question.name) {
+            Some(ForwardTarget::Server(server)) => exchange_plain(server, query)
+                .with_context(|| format!("DNS server {server} for {} failed", question.name))?,
+            Some(ForwardTarget::Host) => self
+                .host
+                .as_ref()
+                .context("No host resolver")?
+                .resolve(query)
+                .with_context(|| format!("Host resolver failed for {}", question.name))?,
+            None => self.resolve_upstream(query)?,
+        };
+        self.cache.insert(query, &question, &response);
+        Ok(response)
+    }
+
+    fn resolve_upstream(&self, query: &[u8]) -> anyhow::Result<Vec<u8>> {
+        for (i, upstream) in self.upstreams.iter().enumerate() {
+            let result = match upstream {
+                DnsUpstream::Tls {
+                    address,
+                    server_name,
+                } => self.resolve_tls(i, *address, server_name.as_deref(), query),
+                DnsUpstream::Https { url, .. } => self.resolve_https(url, query),
+                DnsUpstream::Plain { address } => exchange_plain(*address, query),
+            };
+            match result {
+                Ok(response) => return Ok(response),
+                Err(e) => debug!("DNS upstream {upstream} failed: {e:?}"),
+            }
+        }
+        Err(anyhow!("No DNS upstream answered"))
+    }
+}
+
+fn write_framed(stream: &mut impl Write, message: &[u8]) -> anyhow::Result<()> {
+    let len = u16::try_from(message.len()).context("DNS message too long")?;
+    let mut framed = len.to_be_bytes().to_vec();
+    framed.extend_from_slice(message);
+    stream.write_all(&framed)?;
+    stream.flush()?;
+    Ok(())
+}
+
+fn read_framed(stream: &mut impl Read) -> anyhow::Result<Vec<u8>> {
+    let mut len = [0u8; 2];
+    stream.read_exact(&mut len)?;
+    let mut message = vec![0u8; u16::from_be_bytes(len) as usize];
+    stream.read_exact(&mut message)?;
+    Ok(message)
+}
+
+/// Send a query to a plain DNS server, retrying over TCP if the UDP answer is truncated
+fn exchange_plain(server: SocketAddr, query: &[u8]) -> anyhow::Result<Vec<u8>> {
+    let socket = UdpSocket::bind(match server {
+        SocketAddr::V4(_) => SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)),
+        SocketAddr::V6(_) => SocketAddr::from((std::net::Ipv6Addr::UNSPECIFIED, 0)),
+    })?;
+    socket.set_read_timeout(Some(TIMEOUT))?;
+    socket.connect(server)?;
+    socket.send(query)?;
+    let mut buf = [0u8; MAX_EDNS_RESPONSE];
+    let response = loop {
+        let len = socket.recv(&mut buf)?;
+        // Ignore late answers to earlier queries
+        if len >= HEADER_LEN && buf[..2] == query[..2] {
+            break &buf[..len];
+        }
+    };
+    if response[2] & 0x02 == 0 {
+        return Ok(response.to_vec());
+    }
+    let mut stream = TcpStream::connect_timeout(&server, TIMEOUT)?;
+    stream.set_read_timeout(Some(TIMEOUT))?;
+    write_framed(&mut stream, query)?;
+    read_framed(&mut stream)
+}
+
+/// End of the question section of a query (queries do not use name compression)
+pub(crate) fn question_end(query: &[u8]) -> Option<usize> {
+    let mut i = HEADER_LEN;
+    loop {
+        let len = *query.get(i)? as usize;
+        i += 1;
+        if len == 0 {
+            break;
+        }
+        i += len;
+    }
+    (i + 4 <= query.len()).then_some(i + 4)
+}
+
+/// Fit a response into a UDP reply. Too large responses are replaced with an empty truncated one
+/// so the client retries over TCP.
+pub fn udp_response(query: &[u8], response: Vec<u8>) -> Option<Vec<u8>> {
+    if query.len() < HEADER_LEN || response.len() < HEADER_LEN {
+        return None;
+    }
+    // Any additional record in a query is in practice an EDNS OPT record
+    let limit = if query[10..12] != [0, 0] {
+        MAX_EDNS_RESPONSE
+    } else {
+        MAX_UDP_RESPONSE
+    };
+    if response.len() <= limit {
+        return Some(response);
+    }
+    let end = question_end(query)?;
+    let mut truncated = response[..4].to_vec();
+    // TC flag
+    truncated[2] |= 0x02;
+    truncated.extend_from_slice(&query[4..6]);
+    truncated.extend_from_slice(&[0; 6]);
+    truncated.extend_from_slice(&query[HEADER_LEN..end]);
+    Some(truncated)
+}
+
+/// Query for a name with recursion desired
+pub fn build_query(id: u16, name: &str, record_type: u16, class: u16) -> Vec<u8> {
+    let mut query = id.to_be_bytes().to_vec();
+    // Recursion desired, one question
+    query.extend_from_slice(&[0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0]);
+    for label in name.trim_end_matches('.').split('.') {
+        query.push(label.len() as u8);
+        query.extend_from_slice(label.as_bytes());
+    }
+    query.push(0);
+    query.extend_from_slice(&record_type.to_be_bytes());
+    query.extend_from_slice(&class.to_be_bytes());
+    query
+}
+
+fn address_query(id: u16, name: &str, record_type: u16) -> Vec<u8> {
+    build_query(id, name, record_type, CLASS_IN)
+}
+
+/// Skip a possibly compressed name, returning the index after it
+pub(crate) fn skip_name(message: &[u8], mut i: usize) -> Option<usize> {
+    loop {
+        let len = *message.get(i)?;
+        match len {
+            0 => return Some(i + 1),
+            // Compression pointer ends the name
+            x if x & 0xC0 == 0xC0 => return Some(i + 2),
+            x => i += 1 + x as usize,
+        }
+    }
+}
+
+/// Type and data of the records in the answer section of a response
+pub fn response_records(response: &[u8], id: u16) -> Vec<(u16, Vec<u8>)> {
+    let mut records = Vec::new();
+    // Wrong ID or error response code
+    if response.len() < HEADER_LEN || response[..2] != id.to_be_bytes() || response[3] & 0x0F != 0 {
+        return records;
+    }
+    let questions = u16::from_be_bytes([response[4], response[5]]);
+    let answers = u16::from_be_bytes([response[6], response[7]]);
+    let mut i = HEADER_LEN;
+    for _ in 0..questions {
+        let Some(end) = skip_name(response, i) else {
+            return records;
+        };
+        i = end + 4;
+    }
+    for _ in 0..answers {
+        let Some(end) = skip_name(response, i) else {
+            break;
+        };
+        let Some(fixed) = response.get(end..end + 10) else {
+            break;
+        };
+        let record_type = u16::from_be_bytes([fixed[0], fixed[1]]);
+        let len = u16::from_be_bytes([fixed[8], fixed[9]]) as usize;
+        let Some(data) = response.get(end + 10..end + 10 + len) else {
+            break;
+        };
+        records.push((record_type, data.to_vec()));
+        i = end + 10 + len;
+    }
+    records
+}
+
+/// A and AAAA records in the answer section of a response
+pub fn response_addresses(response: &[u8], id: u16) -> Vec<IpAddr> {
+    response_records(response, id)
+        .into_iter()
+        .filter_map(|(record_type, data)| match (record_type, data.len()) {
+            (TYPE_A, 4) => Some(IpAddr::from(<[u8; 4]>::try_from(data).ok()?)),
+            (TYPE_AAAA, 16) => Some(IpAddr::from(<[u8; 16]>::try_from(data).ok()?)),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Strings of the TXT records in the answer section of a response
+pub fn response_texts(response: &[u8], id: u16) -> Vec<String> {
+    let mut texts = Vec::new();
+    for (_, data) in response_records(response, id)
+        .into_iter()
+        .filter(|(record_type, _)| *record_type == TYPE_TXT)
+    {
+        let mut i = 0;
+        while let Some(len) = data.get(i).map(|x| *x as usize) {
+            let Some(text) = data.get(i + 1..i + 1 + len) else {
+                break;
+            };
+            texts.push(String::from_utf8_lossy(text).into_owned());
+            i += 1 + len;
+        }
+    }
+    texts
+}
+
+/// Send a single query to a plain DNS server, returning the ID used with the response
+pub fn lookup(
+    server: SocketAddr,
+    name: &str,
+    record_type: u16,
+    class: u16,
+) -> anyhow::Result<(u16, Vec<u8>)> {
+    let id = rand::random::<u16>();
+    let response = exchange_plain(server, &build_query(id, name, record_type, class))?;
+    Ok((id, response))
+}
+
+/// Resolve a host name with plain DNS, called from the namespace so it goes through the tunnel
+fn bootstrap_lookup(host: &str, servers: &[IpAddr]) -> anyhow::Result<IpAddr> {
+    for server in servers {
+        let socket = UdpSocket::bind(match server {
+            IpAddr::V4(_) => SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)),
+            IpAddr::V6(_) => SocketAddr::from((std::net::Ipv6Addr::UNSPECIFIED, 0)),
+        })?;
+        socket.set_read_timeout(Some(TIMEOUT))?;
+        for (id, record_type) in [(0x5650, TYPE_A), (0x5651, TYPE_AAAA)] {
+            if socket
+                .send_to(&address_query(id, host, record_type), (*server, 53))
+                .is_err()
+            {
+                break;
+            }
+            let mut buf = [0u8; MAX_EDNS_RESPONSE];
+            let Ok(len) = socket.recv(&mut buf) else {
+                continue;
+            };
+            if let Some(address) = response_addresses(&buf[..len], id).first() {
+                return Ok(*address);
+            }
+        }
+    }
+    Err(anyhow!(
+        "Could not resolve DNS-over-HTTPS server {host}, give its address as URL#IP"
+    ))
+}
+
+/// Resolve DoH server host names in the namespace via the given plain DNS servers
+pub fn bootstrap_upstreams(
+    netns_name: &str,
+    upstreams: Vec<DnsUpstream>,
+    servers: &[IpAddr],
+) -> anyhow::Result<Vec<DnsUpstream>> {
+    upstreams
+        .into_iter()
+        .map(|upstream| match upstream {
+            DnsUpstream::Https { url, address: None } => {
+                let host = reqwest::Url::parse(&url)?
+                    .host_str()
+                    .context("No host in DNS-over-HTTPS URL")?
+                    .to_string();
+                let servers = servers.to_vec();
+                let address = run_in_netns(netns_name, move || bootstrap_lookup(&host, &servers))?;
+                debug!("Resolved DNS-over-HTTPS server {url} to {address}");
+                Ok(DnsUpstream::Https {
+                    url,
+                    address: Some(address),
+                })
+            }
+            x => Ok(x),
+        })
+        .collect()
+}
+
+fn reuse_socket(address: IpAddr, domain_type: Type, protocol: Protocol) -> anyhow::Result<Socket> {
+    let socket = Socket::new(Domain::IPV4, domain_type, Some(protocol))?;
+    socket.set_reuse_address(true)?;
+    socket.set_reuse_port(true)?;
+    socket.bind(&SocketAddr::new(address, 53).into())?;
+    Ok(socket)
+}
+
+fn serve_udp(socket: UdpSocket, forwarder: Arc<Forwarder>, stop: Arc<AtomicBool>) {
+    let socket = Arc::new(socket);
+    let (send, recv) = sync_channel::<(Vec<u8>, SocketAddr)>(UDP_QUEUE_LEN);
+    let recv = Arc::new(Mutex::new(recv));
+    for _ in 0..UDP_WORKERS {
+        let (socket, forwarder, recv) = (socket.clone(), forwarder.clone(), recv.clone());
+        std::thread::spawn(move || {
+            loop {
+                // The lock is only held while waiting, not while resolving. This ends once
+                // serve_udp returns and drops the sender.
+                let next = recv.lock().unwrap_or_else(|e| e.into_inner()).recv();
+                let Ok((query, client)) = next else {
+                    return;
+                };
+                match forwarder.resolve(&query) {
+                    Ok(response) => {
+                        if let Some(response) = udp_response(&query, response) {
+                            socket.send_to(&response, client).ok();
+                        }
+                    }
+                    Err(e) => debug!("DNS query failed: {e:?}"),
+                }
+            }
+        });
+    }
+    let mut buf = [0u8; 4096];
+    while !stop.load(Ordering::Relaxed) {
+        let Ok((len, client)) = socket.recv_from(&mut buf) else {
+            continue;
+        };
+        if send.try_send((buf[..len].to_vec(), client)).is_err() {
+            debug!("DNS query queue full, dropping query from {client}");
+        }
+    }
+}
+
+fn serve_tcp_client(mut stream: TcpStream, forwarder: &Forwarder) -> anyhow::Result<()> {
+    stream.set_nonblocking(false)?;
+    stream.set_read_timeout(Some(Duration::from_secs(10)))?;
+    loop {
+        let query = match read_framed(&mut stream) {
+            Ok(query) => query,
+            // Client closed the connection
+            Err(_) => return Ok(()),
+        };
+        let response = forwarder.resolve(&query)?;
+        write_framed(&mut stream, &response)?;
+    }
+}
+
+fn serve_tcp(listener: TcpListener, forwarder: Arc<Forwarder>, stop: Arc<AtomicBool>) {
+    while !stop.load(Ordering::Relaxed) {
+        match listener.accept() {
+            Ok((stream, _)) => {
+                let forwarder = forwarder.clone();
+                std::thread::spawn(move || {
+                    if let Err(e) = serve_tcp_client(stream, &forwarder) {
+                        debug!("DNS over TCP query failed: {e:?}");
+                    }
+                });
+            }
+            Err(_) => std::thread::sleep(POLL_INTERVAL),
+        }
+    }
+}
+
+pub struct StubResolver {
+    stop: Arc<AtomicBool>,
+    threads: Vec<JoinHandle<anyhow::Result<()>>>,
+}
+
+impl StubResolver {
+    /// Start the stub resolver if the namespace uses it
+    pub fn start(netns: &NetworkNamespace) -> anyhow::Result<Option<Self>> {
+        if netns.dns_upstreams.is_empty() {
+            return Ok(None);
+        }
+        let upstreams = netns.dns_upstreams.clone();
+        let overrides = netns.dns_overrides.clone();
+        let forwards = netns.dns_forwards.clone();
+        // Started here, as the calling thread is in the host network namespace
+        let host = forwards
+            .iter()
+            .any(|x| x.target == ForwardTarget::Host)
+            .then(HostResolver::start);
+        let forwarder = Arc::new(run_in_netns(&netns.name, move || {
+            Forwarder::new(upstreams, overrides, forwards, host)
+        })?);
+        let stop = Arc::new(AtomicBool::new(false));
+        // In place of resolved's stub listener, which the host resolv.conf points at
+        let address = if netns.resolved_link.is_some() {
+            RESOLVED_STUB_ADDRESS
+        } else {
+            STUB_ADDRESS
+        };
+
+        let (udp_forwarder, udp_stop) = (forwarder.clone(), stop.clone());
+        let udp = spawn_in_netns(&netns.name, move || {
+            let socket: UdpSocket = reuse_socket(address, Type::DGRAM, Protocol::UDP)
+                .context("Failed to bind DNS stub resolver on UDP port 53")?
+                .into();
+            socket.set_read_timeout(Some(POLL_INTERVAL))?;
+            serve_udp(socket, udp_forwarder, udp_stop);
+            Ok(())
+        })?;
+        let (tcp_forwarder, tcp_stop) = (forwarder, stop.clone());
+        let tcp = spawn_in_netns(&netns.name, move || {
+            let socket = reuse_socket(address, Type::STREAM, Protocol::TCP)
+                .context("Failed to bind DNS stub resolver on TCP port 53")?;
+            socket.listen(128)?;
+            let listener: TcpListener = socket.into();
+            listener.set_nonblocking(true)?;
+            serve_tcp(listener, tcp_forwarder, tcp_stop);
+            Ok(())
+        })?;
+
+        info!(
+            "DNS stub resolver listening on {address}:53 in {}, forwarding to {}",
+            netns.name,
+            netns
+                .dns_upstreams
+                .iter()
+                .map(|x| x.to_string())
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+        Ok(Some(Self {
+            stop,
+            threads: vec![udp, tcp],
+        }))
+    }
+}
+
+impl Drop for StubResolver {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        for thread in self.threads.drain(..) {
+            match thread.join() {
+                Ok(Err(e)) => warn!("DNS stub resolver failed: {e:?}"),
+                Err(_) => warn!("DNS stub resolver thread panicked"),
+                Ok(Ok(())) => {}
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_tls_upstreams() {
+        assert_eq!(
+            "9.9.9.9#dns.quad9.net".parse::<DnsUpstream>().unwrap(),
+            DnsUpstream::Tls {
+                address: "9.9.9.9:853".parse().unwrap(),
+                server_name: Some("dns.quad9.net".to_string())
+            }
+        );
+        assert_eq!(
+            "[2606:4700::1111]:8853".parse::<DnsUpstream>().unwrap(),
+            DnsUpstream::Tls {
+                address: "[2606:4700::1111]:8853".parse().unwrap(),
+                server_name: None
+            }
+        );
+        assert!("dns.quad9.net".parse::<DnsUpstream>().is_err());
+        assert_eq!(
+            "udp://10.64.0.1"
+                .parse::<DnsUpstream>()
+                .unwrap()
+                .to_string(),
+            "udp://10.64.0.1:53"
+        );
+    }
+
+    #[test]
+    fn parse_https_upstreams() {
+        let upstream = "https://dns.quad9.net/dns-query#9.9.9.9"
+            .parse::<DnsUpstream>()
+            .unwrap();
+        assert_eq!(
+            upstream,
+            DnsUpstream::Https {
+                url: "https://dns.quad9.net/dns-query".to_string(),
+                address: Some("9.9.9.9".parse().unwrap())
+            }
+        );
+        // Round trip through the lockfile
+        assert_eq!(
+            upstream.to_string().parse::<DnsUpstream>().unwrap(),
+            upstream
+        );
+        assert_eq!(
+            "https://1.1.1.1/dns-query".parse::<DnsUpstream>().unwrap(),
+            DnsUpstream::Https {
+                url: "https://1.1.1.1/dns-query".to_string(),
+                address: Some("1.1.1.1".parse().unwrap())
+            }
+        );
+    }
+
+    #[test]
+    fn parse_address_answers() {
+        let mut response = address_query(0x5650, "example.com", 1);
+        response[2] = 0x81;
+        response[3] = 0x80;
+        response[7] = 1;
+        // Answer with a pointer to the question name
+        response.extend_from_slice(&[0xC0, 0x0C, 0, 1, 0, 1, 0, 0, 0x0E, 0x10, 0, 4]);
+        response.extend_from_slice(&[93, 184, 215, 14]);
+        assert_eq!(
+            response_addresses(&response, 0x5650),
+            vec!["93.184.215.14".parse::<IpAddr>().unwrap()]
+        );
+        assert!(response_addresses(&response, 0x1234).is_empty());
+    }
+
+    #[test]
+    fn truncate_large_udp_responses() {
+        // Query for example.com A without EDNS
+        let mut query = vec![0x12, 0x34, 0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0];
+        query.extend_from_slice(b"\x07example\x03com\x00\x00\x01\x00\x01");
+        let mut response = query.clone();
+        response[2] = 0x81;
+        response.resize(600, 0);
+        let truncated = udp_response(&query, response).unwrap();
+        assert_eq!(truncated.len(), query.len());
+        assert_eq!(truncated[2], 0x83);
+        assert_eq!(truncated[HEADER_LEN..], query[HEADER_LEN..]);
+    }
+}
@@ -9,6 +9,20 @@ impl SysCtl {
             .with_context(|| "Failed to enable ipv4 forwarding via sysctl")?;
         Ok(Self {})
     }
+
+    /// Enable IPv6 forwarding, keeping SLAAC working on the host's outgoing interface
+    /// (router advertisements are ignored by default once forwarding is enabled)
+    pub fn enable_ipv6_forwarding(interface: &str) -> anyhow::Result<Self> {
+        sudo_command(&[
+            "sysctl",
+            "-q",
+            &format!("net.ipv6.conf.{interface}.accept_ra=2"),
+        ])
+        .with_context(|| format!("Failed to set accept_ra for {interface} via sysctl"))?;
+        sudo_command(&["sysctl", "-q", "net.ipv6.conf.all.forwarding=1"])
+            .with_context(|| "Failed to enable ipv6 forwarding via sysctl")?;
+        Ok(Self {})
+    }
 }
 
 // TODO: Do not overwrite if ipv4 forwarding was enabled to begin with
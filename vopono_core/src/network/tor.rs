@@ -0,0 +1,211 @@
+// Tor transparent proxy, for --protocol tor
+// vopono runs its own tor client in the namespace with a TransPort and a DNSPort, and the
+// namespace's TCP and DNS are redirected to them (see transparent_proxy.rs), so the application is
+// fully torified without proxy support. A Tor client on the host cannot be used, as the TransPort
+// only works for connections redirected on the same host. Streams to different destinations use
+// different circuits (IsolateDestAddr, IsolateDestPort). tor drops its privileges to the tor
+// system user, whose connections bypass the redirection. Other UDP is rejected: Tor relays TCP
+// only. .onion names are mapped to addresses in VirtualAddrNetworkIPv4 on resolve. The data
+// directory is a fresh private directory owned by the tor user, and the torrc is in another one
+// only root can read (see private_files.rs).
+
+use super::firewall::Firewall;
+use super::netns::NetworkNamespace;
+use super::transparent_proxy::{ProxyBypass, ProxyRedirect, redirect, remove_redirect};
+use crate::util::private_files::{private_temp_dir, write_private_file};
+use anyhow::{Context, anyhow};
+use log::{debug, error, info};
+use serde::{Deserialize, Serialize};
+use std::io::{BufRead, BufReader};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{RecvTimeoutError, channel};
+use std::time::Duration;
+
+const TOR_TRANS_PORT: u16 = 9040;
+const TOR_DNS_PORT: u16 = 5353;
+pub const TOR_SOCKS_PORT: u16 = 9050;
+const TOR_USERS: [&str; 3] = ["debian-tor", "tor", "_tor"];
+const BOOTSTRAP_TIMEOUT: Duration = Duration::from_secs(120);
+/// Addresses for .onion names, not overlapping the default veth range 10.200.0.0/16 as tor's
+/// default 10.192.0.0/10 does
+const TOR_VIRTUAL_NETWORK: &str = "10.128.0.0/10";
+
+fn torrc(data_dir: &Path, user: &str) -> String {
+    format!(
+        "DataDirectory {}\nUser {user}\nLog notice stdout\n\
+        SocksPort 127.0.0.1:{TOR_SOCKS_PORT} IsolateDestAddr\n\
+        TransPort 127.0.0.1:{TOR_TRANS_PORT} IsolateDestAddr IsolateDestPort\n\
+        DNSPort 127.0.0.1:{TOR_DNS_PORT}\n\
+        AutomapHostsOnResolve 1\nVirtualAddrNetworkIPv4 {TOR_VIRTUAL_NETWORK}\n",
+        data_dir.display()
+    )
+}
+
+/// Percentage of a "Bootstrapped 45% (...)" log line of tor
+fn bootstrap_progress(line: &str) -> Option<u8> {
+    let (_, rest) = line.split_once("Bootstrapped ")?;
+    rest.split_once('%')?.0.parse().ok()
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Tor {
+    pid: u32,
+    data_dir: PathBuf,
+    /// Private directory of the torrc
+    #[serde(default)]
+    config_dir: PathBuf,
+    ns_name: String,
+    firewall: Firewall,
+}
+
+impl Tor {
+    pub fn run(
+        netns: &NetworkNamespace,
+        open_ports: Option<&Vec<u16>>,
+        firewall: Firewall,
+    ) -> anyhow::Result<Self> {
+        which::which("tor").map_err(|e| anyhow!("Cannot find tor, is tor installed?: {e:?}"))?;
+        let user = TOR_USERS
+            .into_iter()
+            .find_map(|x| nix::unistd::User::from_name(x).ok().flatten())
+            .ok_or_else(|| {
+                anyhow!(
+                    "No tor system user found (one of {}), is tor installed?",
+                    TOR_USERS.join(", ")
+                )
+            })?;
+        // New directories, so no other user can have created or replaced them
+        let data_dir = private_temp_dir(&format!("{}_tor", netns.name))?;
+        let config_dir = match private_temp_dir(&format!("{}_torrc", netns.name)) {
+            Ok(dir) => dir,
+            Err(e) => {
+                std::fs::remove_dir_all(&data_dir).ok();
+                return Err(e);
+            }
+        };
+        // Removed on drop if we return early due to an error below
+        let mut tor = Self {
+            pid: 0,
+            data_dir,
+            config_dir,
+            ns_name: netns.name.clone(),
+            firewall,
+        };
+        nix::unistd::chown(&tor.data_dir, Some(user.uid), Some(user.gid)).with_context(|| {
+            format!("Failed to give {} to {}", tor.data_dir.display(), user.name)
+        })?;
+        // Read by tor before it drops its privileges
+        let torrc_file = tor.config_dir.join("torrc");
+        write_private_file(&torrc_file, torrc(&tor.data_dir, &user.name).as_bytes())?;
+
+        info!("Launching tor...");
+        let torrc_str = torrc_file.to_string_lossy().to_string();
+        let mut handle = NetworkNamespace::exec_no_block(
+            &netns.name,
+            &["tor", "-f", &torrc_str],
+            None,
+            None,
+            false,
+            true,
+            false,
+            None,
+        )
+        .context("Failed to launch tor")?;
+        // tor is killed on drop if we return early due to an error below
+        tor.pid = handle.id();
+        let stdout = handle
+            .stdout
+            .take()
+            .ok_or_else(|| anyhow!("No output from tor"))?;
+        let (send, recv) = channel();
+        // Keeps reading so tor can still write to its output
+        std::thread::spawn(move || {
+            for line in BufReader::new(stdout).lines().map_while(Result::ok) {
+                debug!("tor: {line}");
+                if let Some(progress) = bootstrap_progress(&line) {
+                    send.send(progress).ok();
+                }
+            }
+        });
+        loop {
+            match recv.recv_timeout(BOOTSTRAP_TIMEOUT) {
+                Ok(100) => break,
+                Ok(progress) => info!("Tor bootstrapped {progress}%"),
+                Err(RecvTimeoutError::Timeout) => {
+                    return Err(anyhow!(
+                        "Tor did not bootstrap within {} seconds",
+                        BOOTSTRAP_TIMEOUT.as_secs()
+                    ));
+                }
+                Err(RecvTimeoutError::Disconnected) => {
+                    return Err(anyhow!(
+                        "tor exited before bootstrapping (use -v for its log)"
+                    ));
+                }
+            }
+        }
+
+        redirect(
+            netns,
+            firewall,
+            "tor",
+            ProxyRedirect {
+                tcp_port: TOR_TRANS_PORT,
+                udp_dns_port: TOR_DNS_PORT,
+                tcp_dns_port: None,
+                bypass: ProxyBypass::Owner(user.uid.as_raw()),
+            },
+        )?;
+        if let Some(opens) = open_ports {
+            crate::util::open_ports(netns, opens.as_slice(), firewall)?;
+        }
+        info!("Tor running in {} (pid {})", netns.name, tor.pid);
+        Ok(tor)
+    }
+
+    pub fn check_if_running(&self) -> bool {
+        crate::util::check_process_running(self.pid)
+    }
+}
+
+impl Drop for Tor {
+    fn drop(&mut self) {
+        // Not launched yet
+        if self.pid != 0 {
+            match nix::sys::signal::kill(
+                nix::unistd::Pid::from_raw(self.pid as i32),
+                nix::sys::signal::Signal::SIGTERM,
+            ) {
+                Ok(_) => debug!("Stopped tor (pid: {})", self.pid),
+                Err(e) => error!("Failed to stop tor (pid: {}): {:?}", self.pid, e),
+            }
+        }
+        std::fs::remove_dir_all(&self.data_dir).ok();
+        if !self.config_dir.as_os_str().is_empty() {
+            std::fs::remove_dir_all(&self.config_dir).ok();
+        }
+        remove_redirect(&self.ns_name, self.firewall, "tor");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn torrc_and_bootstrap() {
+        let torrc = torrc(Path::new("/run/vopono/vo_c_tor_tor_1a2b3c4d"), "debian-tor");
+        assert!(torrc.contains("TransPort 127.0.0.1:9040 IsolateDestAddr IsolateDestPort\n"));
+        assert!(torrc.contains("User debian-tor\n"));
+        let virtual_network: ipnet::Ipv4Net = TOR_VIRTUAL_NETWORK.parse().unwrap();
+        let veth_range: ipnet::Ipv4Net = "10.200.0.0/16".parse().unwrap();
+        assert!(!virtual_network.contains(&veth_range) && !veth_range.contains(&virtual_network));
+        assert_eq!(
+            bootstrap_progress(
+                "Oct 14 10:00:00.000 [notice] Bootstrapped 45% (requesting_descriptors): Asking for relay descriptors"
+            ),
+            Some(45)
+        );
+        assert_eq!(bootstrap_progress("[notice] Opening Socks listener"), None);
+    }
+}
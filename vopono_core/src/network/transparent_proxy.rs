@@ -0,0 +1,199 @@
+// Transparent redirection of the namespace's traffic to a local proxy, for the Xray, Tor and
+// upstream proxy protocols
+// TCP is redirected to the proxy's redirect port with a nat OUTPUT rule, and DNS over UDP (and
+// over TCP, if the proxy has a DNS port for it) to its DNS port. The proxy's own connections to
+// its server bypass the rules, by their socket mark, user or by the server's address, and the
+// killswitch rejects anything else leaving the namespace, as there is no tunnel interface to
+// route it through. Only IPv4 traffic is redirected, other IPv6 traffic is rejected.
+
+use super::firewall::Firewall;
+use super::netns::NetworkNamespace;
+use anyhow::Context;
+use log::debug;
+use std::net::Ipv4Addr;
+
+/// How the proxy's connections to its server are told apart
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProxyBypass {
+    Mark(u32),
+    /// User the proxy runs as
+    Owner(u32),
+    Destination(Ipv4Addr),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProxyRedirect {
+    pub tcp_port: u16,
+    pub udp_dns_port: u16,
+    /// DNS over TCP is sent to tcp_port with the rest of TCP if not given
+    pub tcp_dns_port: Option<u16>,
+    pub bypass: ProxyBypass,
+}
+
+/// nftables table of the rules, also deleted with the namespace
+fn table_name(netns_name: &str, proxy: &str) -> String {
+    format!("{netns_name}_{proxy}")
+}
+
+pub fn redirect(
+    netns: &NetworkNamespace,
+    firewall: Firewall,
+    proxy: &str,
+    rules: ProxyRedirect,
+) -> anyhow::Result<()> {
+    debug!("Setting {proxy} redirection and killswitch....");
+    let tcp_port = rules.tcp_port.to_string();
+    let udp_dns_port = rules.udp_dns_port.to_string();
+    match firewall {
+        Firewall::IpTables => {
+            let nat = |rule: &[&str]| -> anyhow::Result<()> {
+                let mut command = vec!["iptables", "-t", "nat", "-A", "OUTPUT"];
+                command.extend(rule);
+                NetworkNamespace::exec(&netns.name, &command).context("Executing iptables")
+            };
+            let bypass = match rules.bypass {
+                ProxyBypass::Mark(mark) => ["-m", "mark", "--mark", &mark.to_string()]
+                    .map(String::from)
+                    .to_vec(),
+                ProxyBypass::Owner(uid) => ["-m", "owner", "--uid-owner", &uid.to_string()]
+                    .map(String::from)
+                    .to_vec(),
+                ProxyBypass::Destination(ip) => ["-d", &ip.to_string()].map(String::from).to_vec(),
+            };
+            let bypass: Vec<&str> = bypass.iter().map(String::as_str).collect();
+            let mut rule = bypass.clone();
+            rule.extend(["-j", "RETURN"]);
+            nat(&rule)?;
+            nat(&["-d", "127.0.0.0/8", "-j", "RETURN"])?;
+            let mut dns = vec![("udp", udp_dns_port.clone())];
+            if let Some(port) = rules.tcp_dns_port {
+                dns.push(("tcp", port.to_string()));
+            }
+            for (protocol, port) in dns.iter() {
+                nat(&[
+                    "-p",
+                    protocol,
+                    "--dport",
+                    "53",
+                    "-j",
+                    "REDIRECT",
+                    "--to-ports",
+                    port,
+                ])?;
+            }
+            nat(&["-p", "tcp", "-j", "REDIRECT", "--to-ports", &tcp_port])?;
+
+            for iptables in ["iptables", "ip6tables"] {
+                // Unless it has a server address, the proxy may reach its server over IPv6
+                if iptables == "iptables" || !matches!(rules.bypass, ProxyBypass::Destination(_)) {
+                    let mut accept = vec![iptables, "-A", "OUTPUT"];
+                    accept.extend(&bypass);
+                    accept.extend(["-j", "ACCEPT"]);
+                    NetworkNamespace::exec(&netns.name, &accept)
+                        .with_context(|| format!("Executing {iptables}"))?;
+                }
+                NetworkNamespace::exec(
+                    &netns.name,
+                    &[
+                        iptables,
+                        "-A",
+                        "OUTPUT",
+                        "-m",
+                        "addrtype",
+                        "!",
+                        "--dst-type",
+                        "LOCAL",
+                        "-j",
+                        "REJECT",
+                    ],
+                )
+                .with_context(|| format!("Executing {iptables}"))?;
+            }
+        }
+        Firewall::NfTables => {
+            let nft = |args: &[&str]| -> anyhow::Result<()> {
+                let mut command = vec!["nft", "add"];
+                command.extend(args);
+                NetworkNamespace::exec(&netns.name, &command).context("Executing nft")
+            };
+            let table = table_name(&netns.name, proxy);
+            let bypass = match rules.bypass {
+                ProxyBypass::Mark(mark) => format!("mark {mark}"),
+                ProxyBypass::Owner(uid) => format!("meta skuid {uid}"),
+                ProxyBypass::Destination(ip) => format!("ip daddr {ip}"),
+            };
+            let bypass: Vec<&str> = bypass.split_whitespace().collect();
+            let rule = |chain: &str, expression: &[&str]| -> anyhow::Result<()> {
+                let mut args = vec!["rule", "inet", &table, chain];
+                args.extend(expression);
+                nft(&args)
+            };
+            nft(&["table", "inet", &table])?;
+            nft(&[
+                "chain",
+                "inet",
+                &table,
+                "nat",
+                "{ type nat hook output priority -100 ; policy accept; }",
+            ])?;
+            rule("nat", &[bypass.as_slice(), &["return"]].concat())?;
+            rule("nat", &["ip", "daddr", "127.0.0.0/8", "return"])?;
+            let udp_dns_target = format!(":{udp_dns_port}");
+            rule(
+                "nat",
+                &[
+                    "udp",
+                    "dport",
+                    "53",
+                    "redirect",
+                    "to",
+                    udp_dns_target.as_str(),
+                ],
+            )?;
+            if let Some(port) = rules.tcp_dns_port {
+                let target = format!(":{port}");
+                rule(
+                    "nat",
+                    &["tcp", "dport", "53", "redirect", "to", target.as_str()],
+                )?;
+            }
+            let tcp_target = format!(":{tcp_port}");
+            rule(
+                "nat",
+                &[
+                    "meta",
+                    "nfproto",
+                    "ipv4",
+                    "meta",
+                    "l4proto",
+                    "tcp",
+                    "redirect",
+                    "to",
+                    tcp_target.as_str(),
+                ],
+            )?;
+            // After the nat chain, so redirected traffic has a local destination
+            nft(&[
+                "chain",
+                "inet",
+                &table,
+                "output",
+                "{ type filter hook output priority 0 ; policy accept; }",
+            ])?;
+            rule("output", &[bypass.as_slice(), &["accept"]].concat())?;
+            rule(
+                "output",
+                &["fib", "daddr", "type", "!=", "local", "counter", "reject"],
+            )?;
+        }
+    }
+    Ok(())
+}
+
+/// Remove the nftables rules, the iptables rules go with the namespace
+pub fn remove_redirect(netns_name: &str, firewall: Firewall, proxy: &str) {
+    if firewall == Firewall::NfTables {
+        let table = table_name(netns_name, proxy);
+        NetworkNamespace::exec(netns_name, &["nft", "delete", "table", "inet", &table]).ok();
+    }
+}
@@ -1,102 +1,72 @@
+use super::firewall::Firewall;
+use super::netlink::Netlink;
 use super::netns::NetworkNamespace;
 use crate::util::sudo_command;
-use anyhow::Context;
+use anyhow::{Context, anyhow};
 use log::{debug, warn};
 use serde::{Deserialize, Serialize};
-use std::fs::OpenOptions;
-use std::io::Write;
 use std::path::PathBuf;
-use std::str::FromStr;
+use std::process::{Command, Stdio};
+
+/// nft table and iptables rule comment of the traffic counters in the namespace
+const TRAFFIC_COUNTER_NAME: &str = "vopono_traffic";
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct VethPair {
     pub source: String,
     pub dest: String,
     pub nm_unmanaged: Option<NetworkManagerUnmanaged>,
+    #[serde(default)]
+    pub networkd_unmanaged: Option<NetworkdUnmanaged>,
 }
 
+/// Runtime NetworkManager config marking the host veth as unmanaged
 #[derive(Serialize, Deserialize, Debug)]
 pub struct NetworkManagerUnmanaged {
-    pub backup_file: Option<PathBuf>,
+    pub config_file: PathBuf,
+}
+
+/// Runtime systemd-networkd config marking the host veth as unmanaged
+#[derive(Serialize, Deserialize, Debug)]
+pub struct NetworkdUnmanaged {
+    pub config_file: PathBuf,
+}
+
+/// Longest interface name the kernel accepts (IFNAMSIZ - 1)
+pub const MAX_INTERFACE_NAME_LEN: usize = 15;
+
+/// Check an interface name is one the kernel accepts
+pub fn validate_interface_name(name: &str) -> anyhow::Result<()> {
+    if name.is_empty() || name.len() > MAX_INTERFACE_NAME_LEN {
+        return Err(anyhow!(
+            "Interface name must be 1 to {MAX_INTERFACE_NAME_LEN} characters: {name}"
+        ));
+    }
+    if name == "."
+        || name == ".."
+        || name
+            .chars()
+            .any(|c| c == '/' || c == ':' || c.is_whitespace() || !c.is_ascii())
+    {
+        return Err(anyhow!(
+            "Interface name must not contain '/', ':' or whitespace: {name}"
+        ));
+    }
+    Ok(())
 }
 
-// ifname must be less <= 15 chars
 impl VethPair {
     pub fn new(source: String, dest: String, netns: &NetworkNamespace) -> anyhow::Result<Self> {
-        assert!(source.len() <= 15, "ifname must be <= 15 chars: {source}");
-        assert!(dest.len() <= 15, "ifname must be <= 15 chars: {dest}");
-
-        // NetworkManager device management
-        // If NetworkManager used, add destination veth to unmanaged devices
-        // Avoids NM overriding our IP assignment
-        // TODO: Check with systemd instead of nmcli directly?
-        let nm_path = PathBuf::from_str("/etc/NetworkManager")?;
-        let nm_running = if which::which("nmcli").is_ok() {
-            std::process::Command::new("nmcli")
-                .arg("general")
-                .arg("status")
-                .status()
-                .map(|x| x.success())
-                .unwrap_or(false)
-        } else {
-            false
-        };
-
-        if nm_running {
-            debug!("Detected NetworkManager running");
-        } else {
-            debug!("NetworkManager not detected running");
+        validate_interface_name(&source)?;
+        validate_interface_name(&dest)?;
+        if source == dest {
+            return Err(anyhow!("veth interface names must differ: {source}"));
         }
 
-        let nm_unmanaged = if nm_path.exists() && nm_running {
-            debug!(
-                "NetworkManager detected, adding {} to unmanaged devices",
-                &dest
-            );
-            let mut nm_config_path = nm_path.clone();
-            nm_config_path.push("conf.d");
-            std::fs::create_dir_all(&nm_config_path)?;
-            nm_config_path.push("unmanaged.conf");
-
-            let backup_file = if nm_config_path.exists() {
-                // Backup existing unmanaged.conf
-                let mut backup_path = nm_path;
-                backup_path.push("conf.d/unmanaged.conf.vopono.bak");
-                std::fs::copy(&nm_config_path, &backup_path)?;
-                Some(backup_path)
-            } else {
-                None
-            };
-
-            {
-                let mut file = if nm_config_path.exists() {
-                    debug!(
-                        "Appending to existing NetworkManager config file: {}",
-                        nm_config_path.as_path().to_string_lossy()
-                    );
-                    OpenOptions::new().append(true).open(nm_config_path)?
-                } else {
-                    debug!(
-                        "Creating new NetworkManager config file: {}",
-                        nm_config_path.as_path().to_string_lossy()
-                    );
-                    std::fs::File::create(nm_config_path)?
-                };
-
-                write!(file, "[keyfile]\nunmanaged-devices=interface-name:{dest}\n")?;
-            }
-
-            if let Err(e) = sudo_command(&["nmcli", "connection", "reload"])
-                .context("Failed to reload NetworkManager configuration")
-            {
-                warn!(
-                    "Tried but failed to reload NetworkManager configuration - is NetworkManager running? : {e}"
-                );
-            }
-            Some(NetworkManagerUnmanaged { backup_file })
-        } else {
-            None
-        };
+        // Host network managers must not configure the host end, or they override our IP
+        // assignment and routes. Runtime config is used so nothing persists across reboots.
+        let nm_unmanaged = NetworkManagerUnmanaged::new(&dest)?;
+        let networkd_unmanaged = NetworkdUnmanaged::new(&dest)?;
 
         // systemd firewalld device management
         let firewalld_running = if which::which("firewall-cmd").is_ok() {
@@ -134,66 +104,395 @@ impl VethPair {
             }
         }
 
-        sudo_command(&[
-            "ip",
-            "link",
-            "add",
-            dest.as_str(),
-            "type",
-            "veth",
-            "peer",
-            "name",
-            source.as_str(),
-        ])
-        .with_context(|| format!("Failed to create veth pair {}, {}", &source, &dest))?;
-
-        sudo_command(&["ip", "link", "set", dest.as_str(), "up"])
+        let netlink = Netlink::host()?;
+        netlink.add_veth_pair(&dest, &source)?;
+        netlink
+            .set_up(&dest)
             .with_context(|| format!("Failed to bring up destination veth: {}", &dest))?;
+        netlink.move_to_namespace(&source, &netns.name)?;
+        Netlink::in_namespace(&netns.name)?
+            .set_up(&source)
+            .with_context(|| format!("Failed to bring up source veth: {}", &source))?;
 
-        sudo_command(&[
-            "ip",
-            "link",
-            "set",
-            source.as_str(),
-            "netns",
-            &netns.name,
-            "up",
-        ])
-        .with_context(|| format!("Failed to bring up source veth: {}", &dest))?;
+        if nm_unmanaged.is_some() {
+            // In case NetworkManager grabbed the device before reloading its config
+            if let Err(e) = sudo_command(&["nmcli", "device", "set", &dest, "managed", "no"]) {
+                debug!("Failed to set {dest} unmanaged with nmcli: {e:?}");
+            }
+        }
 
         Ok(Self {
             source,
             dest,
             nm_unmanaged,
+            networkd_unmanaged,
         })
     }
+
+    /// Add byte counters for the namespace end to the namespace firewall. The rules only count
+    /// (they never accept or drop), and go away with the namespace.
+    pub fn add_traffic_counters(&self, netns: &NetworkNamespace) -> anyhow::Result<()> {
+        match netns.firewall {
+            Firewall::NfTables => {
+                NetworkNamespace::exec(
+                    &netns.name,
+                    &["nft", "add", "table", "inet", TRAFFIC_COUNTER_NAME],
+                )?;
+                for (chain, direction) in [("input", "iifname"), ("output", "oifname")] {
+                    NetworkNamespace::exec(
+                        &netns.name,
+                        &[
+                            "nft",
+                            "add",
+                            "chain",
+                            "inet",
+                            TRAFFIC_COUNTER_NAME,
+                            chain,
+                            &format!(
+                                "{{ type filter hook {chain} priority -300 ; policy accept ; }}"
+                            ),
+                        ],
+                    )?;
+                    NetworkNamespace::exec(
+                        &netns.name,
+                        &[
+                            "nft",
+                            "add",
+                            "rule",
+                            "inet",
+                            TRAFFIC_COUNTER_NAME,
+                            chain,
+                            direction,
+                            &self.source,
+                            "counter",
+                        ],
+                    )?;
+                }
+            }
+            Firewall::IpTables => {
+                for iptables in ["iptables", "ip6tables"] {
+                    if iptables == "ip6tables" && which::which(iptables).is_err() {
+                        continue;
+                    }
+                    for (chain, direction) in [("INPUT", "-i"), ("OUTPUT", "-o")] {
+                        NetworkNamespace::exec(
+                            &netns.name,
+                            &[
+                                iptables,
+                                "-t",
+                                "mangle",
+                                "-I",
+                                chain,
+                                "1",
+                                direction,
+                                &self.source,
+                                "-m",
+                                "comment",
+                                "--comment",
+                                TRAFFIC_COUNTER_NAME,
+                            ],
+                        )?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Bytes counted by the namespace firewall, which needs root. Without root (or for
+    /// namespaces without the counters), the kernel counters of the host end are used instead.
+    pub fn traffic(&self, netns: &NetworkNamespace) -> Option<TrafficCounters> {
+        self.firewall_traffic(netns)
+            .or_else(|| self.interface_traffic())
+    }
+
+    fn firewall_traffic(&self, netns: &NetworkNamespace) -> Option<TrafficCounters> {
+        let run = |command: &[&str]| -> Option<String> {
+            let output = Command::new("ip")
+                .args(["netns", "exec", &netns.name])
+                .args(command)
+                .stderr(Stdio::null())
+                .output()
+                .ok()?;
+            output
+                .status
+                .success()
+                .then(|| String::from_utf8_lossy(&output.stdout).to_string())
+        };
+        match netns.firewall {
+            Firewall::NfTables => parse_nft_counters(&run(&[
+                "nft",
+                "list",
+                "table",
+                "inet",
+                TRAFFIC_COUNTER_NAME,
+            ])?),
+            Firewall::IpTables => ["iptables-save", "ip6tables-save"]
+                .iter()
+                .filter_map(|x| parse_iptables_counters(&run(&[x, "-c", "-t", "mangle"])?))
+                .reduce(|a, b| a + b),
+        }
+    }
+
+    /// Kernel byte counters of the host end, which are readable without root. What the host
+    /// end receives is uploaded by the namespace and vice versa.
+    fn interface_traffic(&self) -> Option<TrafficCounters> {
+        let statistics = PathBuf::from("/sys/class/net")
+            .join(&self.dest)
+            .join("statistics");
+        let read = |name: &str| -> Option<u64> {
+            std::fs::read_to_string(statistics.join(name))
+                .ok()?
+                .trim()
+                .parse()
+                .ok()
+        };
+        Some(TrafficCounters {
+            download: read("tx_bytes")?,
+            upload: read("rx_bytes")?,
+        })
+    }
+}
+
+/// Bytes moved by a network namespace since its veth pair was created, including VPN tunnel
+/// overhead and traffic to the host or LAN
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct TrafficCounters {
+    pub download: u64,
+    pub upload: u64,
+}
+
+impl std::ops::Add for TrafficCounters {
+    type Output = Self;
+    fn add(self, other: Self) -> Self {
+        Self {
+            download: self.download + other.download,
+            upload: self.upload + other.upload,
+        }
+    }
+}
+
+/// Byte count with binary units, e.g. 1.5GiB
+pub fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{bytes}B")
+    } else {
+        format!("{value:.1}{}", UNITS[unit])
+    }
+}
+
+/// Counters of the nft table rules for the namespace end, from nft list table output
+fn parse_nft_counters(output: &str) -> Option<TrafficCounters> {
+    let mut counters = None;
+    for line in output.lines().map(|x| x.trim()) {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let Some(bytes) = words
+            .iter()
+            .position(|x| *x == "bytes")
+            .and_then(|i| words.get(i + 1)?.parse::<u64>().ok())
+        else {
+            continue;
+        };
+        let entry: &mut TrafficCounters = counters.get_or_insert_default();
+        match words.first() {
+            Some(&"iifname") => entry.download += bytes,
+            Some(&"oifname") => entry.upload += bytes,
+            _ => {}
+        }
+    }
+    counters
+}
+
+/// Counters of the marked mangle rules, from iptables-save -c output
+fn parse_iptables_counters(output: &str) -> Option<TrafficCounters> {
+    let mut counters = None;
+    for line in output.lines() {
+        if !line.contains(&format!("--comment {TRAFFIC_COUNTER_NAME}")) {
+            continue;
+        }
+        let Some(bytes) = line
+            .strip_prefix('[')
+            .and_then(|x| x.split(']').next())
+            .and_then(|x| x.split(':').nth(1))
+            .and_then(|x| x.parse::<u64>().ok())
+        else {
+            continue;
+        };
+        let entry: &mut TrafficCounters = counters.get_or_insert_default();
+        if line.contains("-A INPUT ") {
+            entry.download += bytes;
+        } else if line.contains("-A OUTPUT ") {
+            entry.upload += bytes;
+        }
+    }
+    counters
 }
 
 impl Drop for VethPair {
     fn drop(&mut self) {
-        sudo_command(&["ip", "link", "delete", &self.dest])
-            .unwrap_or_else(|_| panic!("Failed to delete veth pair: {}", &self.dest));
+        Netlink::host()
+            .and_then(|netlink| netlink.delete_link(&self.dest))
+            .unwrap_or_else(|e| panic!("Failed to delete veth pair: {}: {e:?}", &self.dest));
+    }
+}
+
+fn service_active(unit: &str) -> bool {
+    which::which("systemctl").is_ok()
+        && std::process::Command::new("systemctl")
+            .args(["is-active", "--quiet", unit])
+            .status()
+            .is_ok_and(|x| x.success())
+}
+
+impl NetworkManagerUnmanaged {
+    pub fn new(dest: &str) -> anyhow::Result<Option<Self>> {
+        let nm_running = which::which("nmcli").is_ok()
+            && std::process::Command::new("nmcli")
+                .args(["general", "status"])
+                .stdout(Stdio::null())
+                .status()
+                .is_ok_and(|x| x.success());
+        if !nm_running {
+            debug!("NetworkManager not detected running");
+            return Ok(None);
+        }
+        debug!("NetworkManager detected, adding {dest} to unmanaged devices");
+        let dir = PathBuf::from("/run/NetworkManager/conf.d");
+        std::fs::create_dir_all(&dir)?;
+        let config_file = dir.join(format!("vopono-{dest}.conf"));
+        // += appends to unmanaged-devices set by other config files
+        std::fs::write(
+            &config_file,
+            format!("[keyfile]\nunmanaged-devices+=interface-name:{dest}\n"),
+        )
+        .with_context(|| format!("Failed to write {}", config_file.display()))?;
+        reload_network_manager();
+        Ok(Some(Self { config_file }))
+    }
+}
+
+fn reload_network_manager() {
+    if let Err(e) = sudo_command(&["nmcli", "general", "reload", "conf"]) {
+        warn!(
+            "Tried but failed to reload NetworkManager configuration - is NetworkManager running? : {e}"
+        );
+    }
+}
+
+impl NetworkdUnmanaged {
+    pub fn new(dest: &str) -> anyhow::Result<Option<Self>> {
+        if !service_active("systemd-networkd") {
+            debug!("systemd-networkd not detected running");
+            return Ok(None);
+        }
+        debug!("systemd-networkd detected, marking {dest} as unmanaged");
+        let dir = PathBuf::from("/run/systemd/network");
+        std::fs::create_dir_all(&dir)?;
+        // Sorts before distribution and user configs, so it is matched first
+        let config_file = dir.join(format!("00-vopono-{dest}.network"));
+        std::fs::write(
+            &config_file,
+            format!("[Match]\nName={dest}\n\n[Link]\nUnmanaged=yes\n"),
+        )
+        .with_context(|| format!("Failed to write {}", config_file.display()))?;
+        reload_networkd();
+        Ok(Some(Self { config_file }))
+    }
+}
+
+fn reload_networkd() {
+    if let Err(e) = sudo_command(&["networkctl", "reload"]) {
+        warn!("Failed to reload systemd-networkd configuration: {e}");
     }
 }
 
 impl Drop for NetworkManagerUnmanaged {
     fn drop(&mut self) {
-        // Only restore settings if there are no other active namespaces
-        let namespaces = crate::util::get_lock_namespaces();
-        if namespaces.is_ok() && namespaces.unwrap().is_empty() {
-            let nm_path = PathBuf::from_str("/etc/NetworkManager/conf.d/unmanaged.conf")
-                .expect("Failed to build path");
-            if self.backup_file.is_some() {
-                std::fs::copy(self.backup_file.as_ref().unwrap(), &nm_path)
-                    .expect("Failed to restore backup of NetworkManager unmanaged.conf");
-                std::fs::remove_file(self.backup_file.as_ref().unwrap())
-                    .expect("Failed to delete backup of NetworkManager unmanaged.conf");
-            } else {
-                std::fs::remove_file(&nm_path)
-                    .expect("Failed to delete NetworkManager unmanaged.conf");
-            }
-            sudo_command(&["nmcli", "connection", "reload"])
-                .expect("Failed to reload NetworkManager configuration");
+        if let Err(e) = std::fs::remove_file(&self.config_file) {
+            warn!(
+                "Failed to delete NetworkManager config {}: {e}",
+                self.config_file.display()
+            );
         }
+        reload_network_manager();
+    }
+}
+
+impl Drop for NetworkdUnmanaged {
+    fn drop(&mut self) {
+        if let Err(e) = std::fs::remove_file(&self.config_file) {
+            warn!(
+                "Failed to delete systemd-networkd config {}: {e}",
+                self.config_file.display()
+            );
+        }
+        reload_networkd();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn interface_name_limits() {
+        assert!(validate_interface_name("vo_mv_se_d").is_ok());
+        assert!(validate_interface_name("vopono_host_15c").is_ok());
+        assert!(validate_interface_name("vopono_host_16ch").is_err());
+        assert!(validate_interface_name("").is_err());
+        assert!(validate_interface_name("eth0:1").is_err());
+        assert!(validate_interface_name("a/b").is_err());
+    }
+
+    #[test]
+    fn traffic_from_firewall_counters() {
+        let nft = "table inet vopono_traffic {\n\
+            \tchain input {\n\
+            \t\ttype filter hook input priority -300; policy accept;\n\
+            \t\tiifname \"vo_mv_se_s\" counter packets 10 bytes 1234\n\
+            \t}\n\
+            \tchain output {\n\
+            \t\ttype filter hook output priority -300; policy accept;\n\
+            \t\toifname \"vo_mv_se_s\" counter packets 5 bytes 567\n\
+            \t}\n\
+            }\n";
+        assert_eq!(
+            parse_nft_counters(nft),
+            Some(TrafficCounters {
+                download: 1234,
+                upload: 567
+            })
+        );
+        assert_eq!(parse_nft_counters(""), None);
+
+        let iptables = "*mangle\n\
+            :INPUT ACCEPT [20:3000]\n\
+            [10:1234] -A INPUT -i vo_mv_se_s -m comment --comment vopono_traffic\n\
+            [5:567] -A OUTPUT -o vo_mv_se_s -m comment --comment vopono_traffic\n\
+            [7:700] -A OUTPUT -o lo -j ACCEPT\n\
+            COMMIT\n";
+        assert_eq!(
+            parse_iptables_counters(iptables),
+            Some(TrafficCounters {
+                download: 1234,
+                upload: 567
+            })
+        );
+        assert_eq!(parse_iptables_counters("*mangle\nCOMMIT\n"), None);
+    }
+
+    #[test]
+    fn format_bytes_units() {
+        assert_eq!(format_bytes(512), "512B");
+        assert_eq!(format_bytes(1536), "1.5KiB");
+        assert_eq!(format_bytes(48 * 1024 * 1024 + 300 * 1024), "48.3MiB");
+        assert_eq!(format_bytes(u64::MAX), "16777216.0TiB");
     }
 }
@@ -0,0 +1,195 @@
+// Health watchdog pinging a target through the tunnel, for --watchdog
+// A thread pings the target from inside the namespace at an interval. After the given number of
+// consecutive failed pings the connection is considered broken and the action is taken: the
+// tunnel is reconnected (to the same or another server, by the function given by vopono exec),
+// a callback command is run on the host, or every process in the namespace is killed so nothing
+// keeps running on a setup that may be exposing traffic. The count starts over after the action.
+
+use super::netns::NetworkNamespace;
+use crate::util::events::{Event, emit};
+use crate::util::get_pids_in_namespace;
+use crate::util::hooks::{Hook, hook_command};
+use log::{debug, error, info, warn};
+use nix::sys::signal::{Signal, kill};
+use nix::unistd::Pid;
+use serde::{Deserialize, Serialize};
+use std::sync::mpsc::{Sender, channel};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::thread::JoinHandle;
+use std::time::Duration;
+use strum_macros::{Display, EnumIter};
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Display, EnumIter)]
+pub enum WatchdogAction {
+    Reconnect,
+    /// Reconnect to another server with the same prefix
+    SwitchServer,
+    Callback,
+    Kill,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WatchdogPolicy {
+    /// Host or IP to ping
+    pub target: String,
+    pub interval: Duration,
+    /// Consecutive failed pings before taking the action
+    pub failures: u32,
+    pub action: WatchdogAction,
+    /// Command run on the host for the callback action
+    pub callback: Option<String>,
+}
+
+impl Default for WatchdogPolicy {
+    fn default() -> Self {
+        Self {
+            target: "1.1.1.1".to_string(),
+            interval: Duration::from_secs(30),
+            failures: 3,
+            action: WatchdogAction::Reconnect,
+            callback: None,
+        }
+    }
+}
+
+/// Whether the target answers a ping from inside the namespace
+pub fn ping_through_tunnel(netns_name: &str, target: &str) -> bool {
+    NetworkNamespace::exec_with_output(netns_name, &["ping", "-c", "1", "-W", "5", target])
+        .is_ok_and(|x| x.status.success())
+}
+
+/// Consecutive failures after this ping result, and whether the action is due
+fn count_failure(failures: u32, success: bool, threshold: u32) -> (u32, bool) {
+    if success {
+        (0, false)
+    } else if failures + 1 >= threshold {
+        (0, true)
+    } else {
+        (failures + 1, false)
+    }
+}
+
+/// Kill every process in the namespace, the application included
+fn kill_namespace_processes(netns_name: &str) -> anyhow::Result<()> {
+    for pid in get_pids_in_namespace(netns_name)? {
+        debug!("Killing {pid} in {netns_name}");
+        kill(Pid::from_raw(pid), Signal::SIGKILL).ok();
+    }
+    Ok(())
+}
+
+fn run_callback(ns: &NetworkNamespace, policy: &WatchdogPolicy) -> anyhow::Result<()> {
+    let command = policy
+        .callback
+        .as_deref()
+        .ok_or_else(|| anyhow::anyhow!("No watchdog callback command given"))?;
+    let mut cmd = hook_command(
+        Hook::Watchdog,
+        command,
+        ns.predown_user.as_deref(),
+        ns.predown_group.as_deref(),
+    )?;
+    ns.add_env_vars_to_cmd(&mut cmd);
+    cmd.env("VOPONO_WATCHDOG_TARGET", &policy.target);
+    cmd.spawn()?;
+    Ok(())
+}
+
+/// Thread pinging through the tunnel and acting when it breaks, stopped when dropped
+pub struct Watchdog {
+    stop: Sender<()>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl Watchdog {
+    /// reconnect sets up the tunnel again, with another server if its argument is true
+    pub fn start<F>(
+        ns: Arc<Mutex<NetworkNamespace>>,
+        policy: WatchdogPolicy,
+        mut reconnect: F,
+    ) -> Self
+    where
+        F: FnMut(&mut NetworkNamespace, bool) -> anyhow::Result<()> + Send + 'static,
+    {
+        let (stop, recv) = channel();
+        let netns_name = ns
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .name
+            .clone();
+        let handle = std::thread::spawn(move || {
+            let mut failures = 0;
+            // Returns once stopped
+            while recv.recv_timeout(policy.interval).is_err() {
+                let success = ping_through_tunnel(&netns_name, &policy.target);
+                let due;
+                (failures, due) = count_failure(failures, success, policy.failures);
+                if !success {
+                    debug!(
+                        "Watchdog ping of {} from {netns_name} failed",
+                        policy.target
+                    );
+                }
+                if !due {
+                    continue;
+                }
+                warn!(
+                    "{} did not answer {} pings from {netns_name}, watchdog action: {}",
+                    policy.target, policy.failures, policy.action
+                );
+                emit(Event::WatchdogTriggered {
+                    namespace: &netns_name,
+                    target: &policy.target,
+                    action: format!("{:?}", policy.action),
+                });
+                let mut ns = ns.lock().unwrap_or_else(PoisonError::into_inner);
+                let result = match policy.action {
+                    WatchdogAction::Reconnect => reconnect(&mut ns, false),
+                    WatchdogAction::SwitchServer => reconnect(&mut ns, true),
+                    WatchdogAction::Callback => run_callback(&ns, &policy),
+                    WatchdogAction::Kill => {
+                        error!("Killing the processes in {netns_name}, the tunnel is broken");
+                        if let Err(e) = kill_namespace_processes(&netns_name) {
+                            error!("Failed to kill the processes in {netns_name}: {e:?}");
+                        }
+                        return;
+                    }
+                };
+                match result {
+                    Ok(()) => info!("Watchdog action {} done for {netns_name}", policy.action),
+                    Err(e) => error!(
+                        "Watchdog action {} failed for {netns_name}: {e:?}",
+                        policy.action
+                    ),
+                }
+            }
+            debug!("Watchdog exiting");
+        });
+        Self {
+            stop,
+            handle: Some(handle),
+        }
+    }
+}
+
+impl Drop for Watchdog {
+    fn drop(&mut self) {
+        self.stop.send(()).ok();
+        if let Some(handle) = self.handle.take() {
+            handle.join().ok();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn act_after_consecutive_failures() {
+        assert_eq!(count_failure(0, false, 3), (1, false));
+        assert_eq!(count_failure(1, true, 3), (0, false));
+        assert_eq!(count_failure(2, false, 3), (0, true));
+        assert_eq!(count_failure(0, false, 1), (0, true));
+    }
+}
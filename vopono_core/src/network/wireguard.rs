@@ -1,6 +1,6 @@
 use super::firewall::Firewall;
-use super::netns::NetworkNamespace;
-use super::trojan::trojan_config::TrojanConfig;
+use super::netns::{MultihopServers, NetworkNamespace};
+use crate::util::private_files::{private_temp_dir, remove_private_temp_file, write_private_file};
 use crate::util::sudo_command;
 use anyhow::{Context, anyhow};
 use ipnet::IpNet;
@@ -8,13 +8,166 @@ use log::{debug, error, warn};
 use regex::Regex;
 use serde::{Deserialize, Serialize};
 use std::fmt::Display;
-use std::io::Write;
+use std::io::{ErrorKind, Write};
 use std::net::SocketAddr;
 use std::net::ToSocketAddrs;
 use std::net::{IpAddr, Ipv4Addr};
+use std::os::unix::fs::FileTypeExt;
 use std::path::Path;
 use std::path::PathBuf;
+use std::process::Child;
 use std::str::FromStr;
+use std::time::{Duration, Instant};
+use strum_macros::{Display, EnumIter};
+
+/// Where the Wireguard tunnel runs: the kernel module, or a userspace process for hosts without
+/// it (containers, old kernels). Userspace uses boringtun, or wireguard-go if boringtun is not
+/// installed. Kernel falls back to these if the module is missing.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default, Display, EnumIter)]
+pub enum WireguardImplementation {
+    #[default]
+    Kernel,
+    Userspace,
+    WireguardGo,
+}
+
+/// Interface keys of AmneziaWG configs, for the junk packets and header obfuscation against DPI
+const AMNEZIA_KEYS: [&str; 9] = ["Jc", "Jmin", "Jmax", "S1", "S2", "H1", "H2", "H3", "H4"];
+
+/// Whether the config sets AmneziaWG parameters, so needs amneziawg instead of plain Wireguard
+pub fn is_amnezia_config(config_string: &str) -> bool {
+    config_string.lines().any(|x| {
+        x.split_once('=')
+            .is_some_and(|(key, _)| AMNEZIA_KEYS.contains(&key.trim()))
+    })
+}
+
+/// Userspace program to run for the implementation, the first one installed
+fn userspace_program(
+    implementation: WireguardImplementation,
+    amnezia: bool,
+) -> anyhow::Result<&'static str> {
+    let candidates: &[&'static str] = match implementation {
+        _ if amnezia => &["amneziawg-go"],
+        WireguardImplementation::WireguardGo => &["wireguard-go"],
+        _ => &["boringtun-cli", "wireguard-go"],
+    };
+    candidates
+        .iter()
+        .find(|x| which::which(x).is_ok())
+        .copied()
+        .ok_or_else(|| {
+            anyhow!(
+                "{} not found, is a userspace Wireguard implementation installed and on PATH?",
+                candidates.join(" or ")
+            )
+        })
+}
+
+/// boringtun, wireguard-go or amneziawg-go process providing the interface, configured by wg (or
+/// awg) over its UAPI socket
+#[derive(Serialize, Deserialize, Debug)]
+pub struct UserspaceWireguard {
+    pid: u32,
+    #[serde(default)]
+    program: String,
+    /// The process, waited on when it stops so its pid is not reused while we signal it
+    #[serde(skip)]
+    child: Option<Child>,
+}
+
+/// Whether the UAPI socket is there
+fn socket_ready(socket: &Path) -> bool {
+    std::fs::symlink_metadata(socket).is_ok_and(|x| x.file_type().is_socket())
+}
+
+/// Remove the socket of a previous process, which would count as ready before the new process
+/// listens
+fn remove_stale_socket(socket: &Path) -> anyhow::Result<()> {
+    match std::fs::remove_file(socket) {
+        Err(e) if e.kind() != ErrorKind::NotFound => {
+            Err(e).with_context(|| format!("Failed to remove stale {}", socket.display()))
+        }
+        _ => Ok(()),
+    }
+}
+
+impl UserspaceWireguard {
+    fn run(ns_name: &str, if_name: &str, program: &str) -> anyhow::Result<Self> {
+        let command: &[&str] = match program {
+            "wireguard-go" | "amneziawg-go" => &[program, "-f", if_name],
+            _ => &[
+                program,
+                "--foreground",
+                "--disable-drop-privileges",
+                if_name,
+            ],
+        };
+        let socket_dir = match program {
+            "amneziawg-go" => "amneziawg",
+            _ => "wireguard",
+        };
+        let socket = PathBuf::from(format!("/var/run/{socket_dir}/{if_name}.sock"));
+        remove_stale_socket(&socket)?;
+        let handle = NetworkNamespace::exec_no_block(
+            ns_name, command, None, None, true, false, false, None,
+        )?;
+        let mut userspace = Self {
+            pid: handle.id(),
+            program: program.to_string(),
+            child: Some(handle),
+        };
+        // wg can configure the interface once the socket is there
+        for _ in 0..50 {
+            if let Some(status) = userspace.child.as_mut().and_then(|x| x.try_wait().ok()?) {
+                return Err(anyhow!("{program} exited during startup: {status}"));
+            }
+            if socket_ready(&socket) {
+                debug!("{program} running for {if_name} (pid {})", userspace.pid);
+                return Ok(userspace);
+            }
+            std::thread::sleep(Duration::from_millis(100));
+        }
+        Err(anyhow!(
+            "{program} did not create {} in time",
+            socket.display()
+        ))
+    }
+}
+
+impl Drop for UserspaceWireguard {
+    fn drop(&mut self) {
+        // Already reaped, so the pid may belong to another process
+        if let Some(child) = self.child.as_mut()
+            && !matches!(child.try_wait(), Ok(None))
+        {
+            return;
+        }
+        // The interface is removed when the process exits
+        match nix::sys::signal::kill(
+            nix::unistd::Pid::from_raw(self.pid as i32),
+            nix::sys::signal::Signal::SIGTERM,
+        ) {
+            Ok(_) => debug!("Stopped {} (pid: {})", self.program, self.pid),
+            Err(e) => warn!("Failed to stop {} (pid: {}): {e:?}", self.program, self.pid),
+        }
+        if let Some(mut child) = self.child.take() {
+            let start = Instant::now();
+            while matches!(child.try_wait(), Ok(None)) {
+                if start.elapsed() > Duration::from_secs(5) {
+                    warn!(
+                        "{} (pid: {}) did not exit, killing it",
+                        self.program, self.pid
+                    );
+                    child.kill().ok();
+                    child.wait().ok();
+                    break;
+                }
+                std::thread::sleep(Duration::from_millis(50));
+            }
+        }
+    }
+}
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Wireguard {
@@ -23,9 +176,19 @@ pub struct Wireguard {
     pub firewall: Firewall,
     pub if_name: String,
     pub interface_addresses: Vec<IpAddr>,
+    #[serde(default)]
+    pub userspace: Option<UserspaceWireguard>,
+    /// AmneziaWG tunnel, managed with awg instead of wg
+    #[serde(default)]
+    pub amnezia: bool,
 }
 
 impl Wireguard {
+    /// wg, or awg for AmneziaWG
+    pub fn tool(&self) -> &'static str {
+        if self.amnezia { "awg" } else { "wg" }
+    }
+
     pub fn config_from_file(config_file: &Path) -> anyhow::Result<WireguardConfig> {
         let config_string = std::fs::read_to_string(config_file)
             .context(format!("Reading Wireguard config file: {:?}", &config_file))?;
@@ -33,6 +196,31 @@ impl Wireguard {
         WireguardConfig::from_str(&config_string)
     }
 
+    /// Write a config for port-based multihop: the exit server's peer (public key) is reached
+    /// via the entry server's IP on the exit server's multihop port
+    pub fn write_multihop_config(
+        provider_alias: &str,
+        entry_config: &Path,
+        exit_config: &Path,
+        multihop_port: u16,
+        hops: &MultihopServers,
+    ) -> anyhow::Result<PathBuf> {
+        let entry = Self::config_from_file(entry_config)?;
+        let mut config = Self::config_from_file(exit_config)?;
+        config.peer.endpoint = WireguardEndpoint::IpWithPort(SocketAddr::new(
+            entry.peer.endpoint.resolve_ip()?,
+            multihop_port,
+        ));
+
+        // With the private key, removed with the interface on drop
+        let path = private_temp_dir(&format!("{provider_alias}_multihop"))?
+            .join(format!("{}_{}.conf", hops.entry, hops.exit));
+        let config_string: String = config.try_into()?;
+        write_private_file(&path, config_string.as_bytes())
+            .with_context(|| format!("Failed to write multihop config: {}", path.display()))?;
+        Ok(path)
+    }
+
     #[allow(clippy::too_many_arguments)]
     pub fn run(
         namespace: &mut NetworkNamespace,
@@ -45,32 +233,41 @@ impl Wireguard {
         dns: Option<&Vec<IpAddr>>,
         hosts_entries: Option<&Vec<String>>,
         allow_host_access: bool,
-        trojan_config: Option<TrojanConfig>,
+        endpoint_override: Option<SocketAddr>,
+        implementation: WireguardImplementation,
     ) -> anyhow::Result<Self> {
-        if let Err(x) = which::which("wg") {
-            error!("wg binary not found. Is wireguard-tools installed and on PATH?");
+        let mut config_string = std::fs::read_to_string(&config_file)
+            .context(format!("Reading Wireguard config file: {:?}", &config_file))?;
+
+        let amnezia = is_amnezia_config(&config_string);
+        let (tool, tools_package) = if amnezia {
+            debug!("Using AmneziaWG for {}", config_file.display());
+            ("awg", "amneziawg-tools")
+        } else {
+            ("wg", "wireguard-tools")
+        };
+        if let Err(x) = which::which(tool) {
+            error!("{tool} binary not found. Is {tools_package} installed and on PATH?");
             return Err(anyhow!(
-                "wg binary not found. Is wireguard-tools installed and on PATH?: {:?}",
+                "{tool} binary not found. Is {tools_package} installed and on PATH?: {:?}",
                 x
             ));
         }
 
-        let mut config_string = std::fs::read_to_string(&config_file)
-            .context(format!("Reading Wireguard config file: {:?}", &config_file))?;
-
-        // Replace Endpoint with Trojan server for Wireguard forwarding
-        if let Some(tc) = trojan_config.as_ref() {
+        // Replace Endpoint with local Trojan or obfuscation proxy for Wireguard forwarding
+        if let Some(new_endpoint) = endpoint_override {
             let re = Regex::new(r"Endpoint\s*=\s*(?:\[([^\]]+)\]|([^:\s]+)):(\d+)")?;
-            let new_endpoint = tc.get_local_socketaddr()?;
             config_string = re
                 .replace_all(&config_string, format!("Endpoint = {new_endpoint}"))
                 .to_string();
         }
-        // Create temp conf file
-        {
+        let config = Self::config_from_file(&config_file)?;
+        // Config for wg setconf
+        let wg_config = {
             // TODO: Maybe properly parse ini format
 
-            // Valid keys for wireguard config (see wg(8):CONFIGURATION FILE FORMAT)
+            // Valid keys for wireguard config (see wg(8):CONFIGURATION FILE FORMAT), with the
+            // AmneziaWG parameters for awg
             let allow_keys = [
                 "PrivateKey",
                 "ListenPort",
@@ -82,23 +279,20 @@ impl Wireguard {
                 "PersistentKeepalive",
             ];
 
-            let mut f = std::fs::File::create("/tmp/vopono_wg.conf")
-                .context("Creating file: /tmp/vopono_wg.conf")?;
-            write!(
-                f,
-                "{}",
-                config_string
-                    .split('\n')
-                    .filter(|x| x
-                        .split_once('=')
-                        .map(|(key, _)| allow_keys.contains(&key.trim()))
+            config_string
+                .split('\n')
+                .filter(|x| {
+                    x.split_once('=')
+                        .map(|(key, _)| {
+                            allow_keys.contains(&key.trim())
+                                || (amnezia && AMNEZIA_KEYS.contains(&key.trim()))
+                        })
                         // If line doesn't include an =, don't filter it out
-                        .unwrap_or(true))
-                    .collect::<Vec<&str>>()
-                    .join("\n")
-            )?;
-        }
-        let config = Self::config_from_file(&config_file)?;
+                        .unwrap_or(true)
+                })
+                .collect::<Vec<&str>>()
+                .join("\n")
+        };
 
         // TODO: Use bs58 here?
         let if_name = namespace.name
@@ -106,22 +300,66 @@ impl Wireguard {
             .to_string();
         assert!(if_name.len() <= 15, "ifname must be <= 15 chars: {if_name}");
 
-        NetworkNamespace::exec(
-            &namespace.name,
-            &["ip", "link", "add", &if_name, "type", "wireguard"],
-        )?;
+        let link_type = if amnezia { "amneziawg" } else { "wireguard" };
+        let userspace = match implementation {
+            WireguardImplementation::Kernel => {
+                let output = NetworkNamespace::exec_with_output(
+                    &namespace.name,
+                    &["ip", "link", "add", &if_name, "type", link_type],
+                )?;
+                let stderr = String::from_utf8_lossy(&output.stderr);
+                if output.status.success() {
+                    None
+                } else if let Ok(program) =
+                    userspace_program(WireguardImplementation::Userspace, amnezia)
+                {
+                    warn!(
+                        "Failed to create a kernel Wireguard interface ({}), using {program}",
+                        stderr.trim()
+                    );
+                    Some(UserspaceWireguard::run(&namespace.name, &if_name, program)?)
+                } else {
+                    let userspace = if amnezia {
+                        "amneziawg-go"
+                    } else {
+                        "boringtun or wireguard-go"
+                    };
+                    return Err(anyhow!(
+                        "Failed to create a kernel {link_type} interface: {} - is the {link_type} kernel module available? Without it, install {userspace}",
+                        stderr.trim()
+                    ));
+                }
+            }
+            _ => Some(UserspaceWireguard::run(
+                &namespace.name,
+                &if_name,
+                userspace_program(implementation, amnezia)?,
+            )?),
+        };
 
-        NetworkNamespace::exec(
+        // Holds the private key and any PresharedKey, so it is written to a private directory
+        // (not shared with the setup of other namespaces) only for as long as setconf runs
+        let wg_conf = private_temp_dir("wireguard")?.join("wg.conf");
+        write_private_file(&wg_conf, wg_config.as_bytes())?;
+        let setconf = NetworkNamespace::exec(
             &namespace.name,
-            &["wg", "setconf", &if_name, "/tmp/vopono_wg.conf"],
-        )
-        .context("Failed to run wg setconf - is wireguard-tools installed?")?;
-        std::fs::remove_file("/tmp/vopono_wg.conf")
-            .context("Deleting file: /tmp/vopono_wg.conf")
+            &[tool, "setconf", &if_name, &wg_conf.to_string_lossy()],
+        );
+        remove_private_temp_file(&wg_conf)
+            .with_context(|| format!("Deleting file: {}", wg_conf.display()))
             .ok();
+        setconf.with_context(|| match userspace {
+            None if amnezia => {
+                "Failed to run awg setconf - is amneziawg-tools installed? Without the amneziawg kernel module, install amneziawg-go".to_string()
+            }
+            None => {
+                "Failed to run wg setconf - is wireguard-tools installed? Without the wireguard kernel module, install boringtun or wireguard-go".to_string()
+            }
+            Some(_) => format!("Failed to run {tool} setconf - is {tools_package} installed?"),
+        })?;
         let mut interface_addresses: Vec<IpAddr> = Vec::new();
         // Extract addresses
-        for address in config.interface.address.iter() {
+        for address in tunnel_addresses(&config.interface.address, disable_ipv6) {
             match address {
                 IpNet::V6(address) => {
                     interface_addresses.push(IpAddr::V6(address.addr()));
@@ -198,7 +436,7 @@ impl Wireguard {
         namespace.dns_config(&dns, &[], hosts_entries, allow_host_access)?;
         // TODO: Here we hardcode default Wireguard port of 51820
         let fwmark = "51820";
-        NetworkNamespace::exec(&namespace.name, &["wg", "set", &if_name, "fwmark", fwmark])?;
+        NetworkNamespace::exec(&namespace.name, &[tool, "set", &if_name, "fwmark", fwmark])?;
 
         // IPv4 routes
         NetworkNamespace::exec(
@@ -442,10 +680,27 @@ impl Wireguard {
             firewall,
             if_name,
             interface_addresses,
+            userspace,
+            amnezia,
         })
     }
 }
 
+/// Addresses to add to the tunnel interface, without IPv6 ones if it is disabled
+fn tunnel_addresses(addresses: &[IpNet], disable_ipv6: bool) -> Vec<IpNet> {
+    addresses
+        .iter()
+        .filter(|address| {
+            let skip = disable_ipv6 && matches!(address, IpNet::V6(_));
+            if skip {
+                debug!("IPv6 disabled, not adding Wireguard address {address}");
+            }
+            !skip
+        })
+        .copied()
+        .collect()
+}
+
 pub fn killswitch(
     ifname: &str,
     fwmark: &str,
@@ -550,22 +805,32 @@ pub fn killswitch(
 
 impl Drop for Wireguard {
     fn drop(&mut self) {
-        match sudo_command(&[
-            "ip",
-            "netns",
-            "exec",
-            &self.ns_name,
-            "ip",
-            "link",
-            "del",
-            &self.if_name,
-        ]) {
-            Ok(_) => {}
-            Err(e) => warn!(
-                "Failed to delete ip link {}, {}: {:?}",
-                &self.ns_name, &self.if_name, e
-            ),
-        };
+        // Generated configs (multihop, ephemeral keys)
+        if let Err(e) = remove_private_temp_file(&self.config_file) {
+            warn!(
+                "Failed to remove Wireguard config {}: {e:?}",
+                self.config_file.display()
+            );
+        }
+        // Userspace implementations remove their interface when stopped, after this
+        if self.userspace.is_none() {
+            match sudo_command(&[
+                "ip",
+                "netns",
+                "exec",
+                &self.ns_name,
+                "ip",
+                "link",
+                "del",
+                &self.if_name,
+            ]) {
+                Ok(_) => {}
+                Err(e) => warn!(
+                    "Failed to delete ip link {}, {}: {:?}",
+                    &self.ns_name, &self.if_name, e
+                ),
+            };
+        }
 
         if let Firewall::NfTables = self.firewall {
             match sudo_command(&[
@@ -807,3 +1072,59 @@ where
         ))),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::net::UnixListener;
+
+    #[test]
+    fn stale_socket_is_not_ready() {
+        let dir = std::env::temp_dir().join(format!("vopono_uapi_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let socket = dir.join("wg0.sock");
+        std::fs::write(&socket, "").unwrap();
+        assert!(!socket_ready(&socket));
+        remove_stale_socket(&socket).unwrap();
+        assert!(!socket.exists());
+        remove_stale_socket(&socket).unwrap();
+        let _listener = UnixListener::bind(&socket).unwrap();
+        assert!(socket_ready(&socket));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn userspace_process_is_reaped() {
+        let child = std::process::Command::new("sleep")
+            .arg("30")
+            .spawn()
+            .unwrap();
+        let pid = nix::unistd::Pid::from_raw(child.id() as i32);
+        drop(UserspaceWireguard {
+            pid: child.id(),
+            program: "sleep".to_string(),
+            child: Some(child),
+        });
+        // No zombie left, the pid is gone
+        assert!(nix::sys::signal::kill(pid, None).is_err());
+    }
+
+    #[test]
+    fn detect_amnezia_config() {
+        let config = "[Interface]\nPrivateKey = abc\nJc = 4\nJmin = 40\nH1 = 1234\n\n[Peer]\nPublicKey = def\n";
+        assert!(is_amnezia_config(config));
+        assert!(!is_amnezia_config(
+            "[Interface]\nPrivateKey = abc\n\n[Peer]\nPublicKey = def\n"
+        ));
+    }
+
+    #[test]
+    fn tunnel_addresses_without_ipv6() {
+        let addresses: Vec<IpNet> = vec![
+            "10.64.0.2/32".parse().unwrap(),
+            "fc00:bbbb::2/128".parse().unwrap(),
+        ];
+        assert_eq!(tunnel_addresses(&addresses, false), addresses);
+        assert_eq!(tunnel_addresses(&addresses, true), addresses[..1]);
+    }
+}
@@ -0,0 +1,268 @@
+// Xray/V2Ray outbound as the upstream of the namespace, for --protocol xray with --custom
+// The custom config is an Xray outbound (VLESS, VMess, Trojan, Shadowsocks), or a full Xray
+// config whose first outbound is used. vopono writes a config with that outbound and two
+// dokodemo-door inbounds, and runs xray (or v2ray) in the namespace. TCP from the namespace is
+// redirected to the first inbound with a nat OUTPUT rule, and DNS to the second, which forwards
+// it to the DNS server through the outbound. The outbound's sockets are marked so they are not
+// redirected (see transparent_proxy.rs). A server given by host name is resolved on the host
+// first, as xray's own lookup in the namespace would go through the DNS inbound and so through
+// the outbound itself. UDP other than DNS does not work, as with a SOCKS proxy.
+
+use super::firewall::Firewall;
+use super::netns::NetworkNamespace;
+use super::transparent_proxy::{ProxyBypass, ProxyRedirect, redirect, remove_redirect};
+use crate::util::{check_process_running, vopono_dir};
+use anyhow::{Context, anyhow};
+use log::{debug, error, info};
+use serde::{Deserialize, Serialize};
+use serde_json::{Value, json};
+use std::net::{IpAddr, ToSocketAddrs};
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+const XRAY_REDIRECT_PORT: u16 = 12345;
+const XRAY_DNS_PORT: u16 = 5353;
+/// Mark of the outbound's sockets
+const XRAY_MARK: u32 = 255;
+const OUTBOUND_PROTOCOLS: [&str; 4] = ["vless", "vmess", "trojan", "shadowsocks"];
+
+/// Replace the host names of the outbound's servers with their address from resolve, keeping the
+/// name as the TLS server name
+fn resolve_servers(
+    outbound: &mut Value,
+    resolve: impl Fn(&str, u16) -> anyhow::Result<IpAddr>,
+) -> anyhow::Result<()> {
+    let mut names = Vec::new();
+    for key in ["vnext", "servers"] {
+        let Some(servers) = outbound["settings"][key].as_array_mut() else {
+            continue;
+        };
+        for server in servers {
+            let Some(host) = server["address"].as_str().map(str::to_string) else {
+                continue;
+            };
+            if host.parse::<IpAddr>().is_ok() {
+                continue;
+            }
+            let port = server["port"].as_u64().unwrap_or(443) as u16;
+            let ip = resolve(&host, port)?;
+            debug!("Resolved Xray server {host} to {ip}");
+            server["address"] = json!(ip.to_string());
+            names.push(host);
+        }
+    }
+    if let Some(name) = names.first() {
+        for settings in ["tlsSettings", "realitySettings"] {
+            let settings = &mut outbound["streamSettings"][settings];
+            if settings.is_object() && settings["serverName"].is_null() {
+                settings["serverName"] = json!(name);
+            }
+        }
+        if outbound["streamSettings"]["security"] == "tls"
+            && !outbound["streamSettings"]["tlsSettings"].is_object()
+        {
+            outbound["streamSettings"]["tlsSettings"] = json!({ "serverName": name });
+        }
+    }
+    Ok(())
+}
+
+/// Address of the server on the host
+fn resolve_on_host(host: &str, port: u16) -> anyhow::Result<IpAddr> {
+    (host, port)
+        .to_socket_addrs()
+        .with_context(|| format!("Failed to resolve Xray server {host}"))?
+        .map(|x| x.ip())
+        .min_by_key(|x| x.is_ipv6())
+        .ok_or_else(|| anyhow!("Failed to resolve Xray server {host}"))
+}
+
+/// Config for xray with the outbound of the custom config, its servers resolved with resolve
+pub fn xray_config(
+    custom: &str,
+    dns: IpAddr,
+    resolve: impl Fn(&str, u16) -> anyhow::Result<IpAddr>,
+) -> anyhow::Result<Value> {
+    let value: Value = serde_json::from_str(custom).context("Invalid Xray config JSON")?;
+    let mut outbound = match value.get("outbounds") {
+        Some(outbounds) => outbounds
+            .get(0)
+            .cloned()
+            .ok_or_else(|| anyhow!("No outbounds in Xray config"))?,
+        None => value,
+    };
+    let protocol = outbound["protocol"].as_str().unwrap_or_default();
+    if !OUTBOUND_PROTOCOLS.contains(&protocol) {
+        return Err(anyhow!(
+            "Xray outbound protocol must be one of {}, not {protocol:?}",
+            OUTBOUND_PROTOCOLS.join(", ")
+        ));
+    }
+    outbound["tag"] = json!("proxy");
+    if !outbound["streamSettings"].is_object() {
+        outbound["streamSettings"] = json!({});
+    }
+    resolve_servers(&mut outbound, resolve)?;
+    if !outbound["streamSettings"]["sockopt"].is_object() {
+        outbound["streamSettings"]["sockopt"] = json!({});
+    }
+    outbound["streamSettings"]["sockopt"]["mark"] = json!(XRAY_MARK);
+    Ok(json!({
+        "log": { "loglevel": "warning" },
+        "inbounds": [
+            {
+                "tag": "redirect",
+                "listen": "127.0.0.1",
+                "port": XRAY_REDIRECT_PORT,
+                "protocol": "dokodemo-door",
+                "settings": { "network": "tcp", "followRedirect": true },
+                "sniffing": { "enabled": true, "destOverride": ["http", "tls"] }
+            },
+            {
+                "tag": "dns",
+                "listen": "127.0.0.1",
+                "port": XRAY_DNS_PORT,
+                "protocol": "dokodemo-door",
+                "settings": { "address": dns.to_string(), "port": 53, "network": "tcp,udp" }
+            }
+        ],
+        "outbounds": [outbound]
+    }))
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Xray {
+    pid: u32,
+    config_file: PathBuf,
+    ns_name: String,
+    firewall: Firewall,
+}
+
+impl Xray {
+    pub fn run(
+        netns: &NetworkNamespace,
+        custom_config: &Path,
+        dns: IpAddr,
+        open_ports: Option<&Vec<u16>>,
+        firewall: Firewall,
+        verbose: bool,
+    ) -> anyhow::Result<Self> {
+        let binary = ["xray", "v2ray"]
+            .into_iter()
+            .find(|x| which::which(x).is_ok())
+            .ok_or_else(|| anyhow!("Cannot find xray or v2ray, is Xray installed and on PATH?"))?;
+        let custom = std::fs::read_to_string(custom_config)
+            .with_context(|| format!("Reading Xray config file: {}", custom_config.display()))?;
+        let config = xray_config(&custom, dns, resolve_on_host)?;
+        std::fs::create_dir_all(vopono_dir()?.join("logs"))?;
+        let config_file = vopono_dir()?.join(format!("logs/{}_xray.json", netns.name));
+        std::fs::write(&config_file, serde_json::to_string_pretty(&config)?)?;
+        std::fs::set_permissions(&config_file, PermissionsExt::from_mode(0o600))?;
+
+        info!("Launching {binary}...");
+        let config_str = config_file.to_string_lossy().to_string();
+        let handle = NetworkNamespace::exec_no_block(
+            &netns.name,
+            &[binary, "run", "-c", &config_str],
+            None,
+            None,
+            !verbose,
+            false,
+            false,
+            None,
+        )
+        .with_context(|| format!("Failed to launch {binary}"))?;
+        // xray is killed on drop if we return early due to an error below
+        let xray = Self {
+            pid: handle.id(),
+            config_file,
+            ns_name: netns.name.clone(),
+            firewall,
+        };
+        // Exits at once on an invalid config
+        std::thread::sleep(Duration::from_millis(500));
+        if !xray.check_if_running() {
+            return Err(anyhow!(
+                "{binary} exited after starting, check the outbound in {} (use -v for its output)",
+                custom_config.display()
+            ));
+        }
+
+        redirect(
+            netns,
+            firewall,
+            "xray",
+            ProxyRedirect {
+                tcp_port: XRAY_REDIRECT_PORT,
+                udp_dns_port: XRAY_DNS_PORT,
+                tcp_dns_port: Some(XRAY_DNS_PORT),
+                bypass: ProxyBypass::Mark(XRAY_MARK),
+            },
+        )?;
+        if let Some(opens) = open_ports {
+            crate::util::open_ports(netns, opens.as_slice(), firewall)?;
+        }
+        info!("{binary} running in {} (pid {})", netns.name, xray.pid);
+        Ok(xray)
+    }
+
+    pub fn check_if_running(&self) -> bool {
+        check_process_running(self.pid)
+    }
+}
+
+impl Drop for Xray {
+    fn drop(&mut self) {
+        match nix::sys::signal::kill(
+            nix::unistd::Pid::from_raw(self.pid as i32),
+            nix::sys::signal::Signal::SIGKILL,
+        ) {
+            Ok(_) => debug!("Killed xray (pid: {})", self.pid),
+            Err(e) => error!("Failed to kill xray (pid: {}): {:?}", self.pid, e),
+        }
+        std::fs::remove_file(&self.config_file).ok();
+        remove_redirect(&self.ns_name, self.firewall, "xray");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn xray_config_from_outbound() {
+        let dns = IpAddr::from([1, 1, 1, 1]);
+        let resolve = |host: &str, _port: u16| -> anyhow::Result<IpAddr> {
+            match host {
+                "proxy.example.com" => Ok(IpAddr::from([203, 0, 113, 5])),
+                _ => Err(anyhow!("Failed to resolve Xray server {host}")),
+            }
+        };
+        let outbound = r#"{"protocol":"vless","settings":{"vnext":[{"address":"proxy.example.com","port":443,"users":[{"id":"uuid"}]}]},"streamSettings":{"network":"ws","security":"tls"}}"#;
+        let config = xray_config(outbound, dns, resolve).unwrap();
+        assert_eq!(config["outbounds"][0]["protocol"], "vless");
+        assert_eq!(config["outbounds"][0]["streamSettings"]["network"], "ws");
+        // Not looked up by xray, through its own DNS inbound
+        assert_eq!(
+            config["outbounds"][0]["settings"]["vnext"][0]["address"],
+            "203.0.113.5"
+        );
+        assert_eq!(
+            config["outbounds"][0]["streamSettings"]["tlsSettings"]["serverName"],
+            "proxy.example.com"
+        );
+        assert_eq!(
+            config["outbounds"][0]["streamSettings"]["sockopt"]["mark"],
+            255
+        );
+        assert_eq!(config["inbounds"][1]["settings"]["address"], "1.1.1.1");
+        let full = r#"{"outbounds":[{"protocol":"trojan","settings":{}},{"protocol":"freedom"}]}"#;
+        let config = xray_config(full, dns, resolve).unwrap();
+        assert_eq!(config["outbounds"].as_array().unwrap().len(), 1);
+        assert_eq!(config["outbounds"][0]["protocol"], "trojan");
+        assert!(xray_config(r#"{"protocol":"freedom"}"#, dns, resolve).is_err());
+        let unknown = r#"{"protocol":"trojan","settings":{"servers":[{"address":"other.example.com","port":443}]}}"#;
+        assert!(xray_config(unknown, dns, resolve).is_err());
+    }
+}
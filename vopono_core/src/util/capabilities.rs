@@ -0,0 +1,184 @@
+// Running without sudo using file capabilities
+// If the vopono binary has been given the capabilities below with setcap, it runs as the calling
+// user instead of re-executing itself with sudo. The capabilities are raised to the ambient set so
+// the ip, nft/iptables and VPN client processes inherit them, and are dropped again for the
+// application and host scripts, which run as the user without any extra privileges.
+
+use anyhow::anyhow;
+use log::debug;
+use std::os::unix::process::CommandExt;
+use std::process::Command;
+
+// Capability numbers from linux/capability.h
+const CAP_DAC_OVERRIDE: u32 = 1;
+const CAP_KILL: u32 = 5;
+const CAP_NET_ADMIN: u32 = 12;
+const CAP_NET_RAW: u32 = 13;
+const CAP_SYS_ADMIN: u32 = 21;
+
+/// Capabilities needed to create namespaces, veth pairs and firewall rules: writing to
+/// /run/netns and /etc/netns, mounting namespaces, configuring interfaces and netfilter, and
+/// stopping VPN clients which changed user
+pub const REQUIRED_CAPABILITIES: &[(u32, &str)] = &[
+    (CAP_DAC_OVERRIDE, "cap_dac_override"),
+    (CAP_KILL, "cap_kill"),
+    (CAP_NET_ADMIN, "cap_net_admin"),
+    (CAP_NET_RAW, "cap_net_raw"),
+    (CAP_SYS_ADMIN, "cap_sys_admin"),
+];
+
+const LINUX_CAPABILITY_VERSION_3: u32 = 0x2008_0522;
+
+#[repr(C)]
+struct CapHeader {
+    version: u32,
+    pid: libc::c_int,
+}
+
+#[repr(C)]
+#[derive(Default, Clone, Copy)]
+struct CapData {
+    effective: u32,
+    permitted: u32,
+    inheritable: u32,
+}
+
+fn capget() -> anyhow::Result<[CapData; 2]> {
+    let mut header = CapHeader {
+        version: LINUX_CAPABILITY_VERSION_3,
+        pid: 0,
+    };
+    let mut data = [CapData::default(); 2];
+    let result = unsafe { libc::syscall(libc::SYS_capget, &mut header, data.as_mut_ptr()) };
+    if result != 0 {
+        return Err(anyhow!(
+            "capget failed: {}",
+            std::io::Error::last_os_error()
+        ));
+    }
+    Ok(data)
+}
+
+/// Permitted capability set from the CapPrm line of /proc/<pid>/status
+fn parse_permitted(status: &str) -> Option<u64> {
+    status
+        .lines()
+        .find_map(|line| line.strip_prefix("CapPrm:"))
+        .and_then(|x| u64::from_str_radix(x.trim(), 16).ok())
+}
+
+fn missing_from(permitted: u64) -> Vec<&'static str> {
+    REQUIRED_CAPABILITIES
+        .iter()
+        .filter(|(cap, _)| permitted & (1 << cap) == 0)
+        .map(|(_, name)| *name)
+        .collect()
+}
+
+/// Capabilities from REQUIRED_CAPABILITIES missing from the permitted set of this process
+pub fn missing_capabilities() -> anyhow::Result<Vec<&'static str>> {
+    let status = std::fs::read_to_string("/proc/self/status")?;
+    let permitted =
+        parse_permitted(&status).ok_or_else(|| anyhow!("No CapPrm in /proc/self/status"))?;
+    Ok(missing_from(permitted))
+}
+
+/// Whether vopono is running unprivileged with file capabilities instead of as root
+pub fn capability_mode() -> bool {
+    !nix::unistd::geteuid().is_root()
+        && missing_capabilities().is_ok_and(|missing| missing.is_empty())
+}
+
+/// Add the required capabilities to the inheritable and ambient sets, so the commands vopono runs
+/// get them too
+pub fn raise_ambient_capabilities() -> anyhow::Result<()> {
+    let mut data = capget()?;
+    for (cap, _) in REQUIRED_CAPABILITIES {
+        data[(cap / 32) as usize].inheritable |= 1 << (cap % 32);
+    }
+    let mut header = CapHeader {
+        version: LINUX_CAPABILITY_VERSION_3,
+        pid: 0,
+    };
+    let result = unsafe { libc::syscall(libc::SYS_capset, &mut header, data.as_ptr()) };
+    if result != 0 {
+        return Err(anyhow!(
+            "capset failed: {}",
+            std::io::Error::last_os_error()
+        ));
+    }
+    for (cap, name) in REQUIRED_CAPABILITIES {
+        let result = unsafe {
+            libc::prctl(
+                libc::PR_CAP_AMBIENT,
+                libc::PR_CAP_AMBIENT_RAISE,
+                *cap as libc::c_ulong,
+                0,
+                0,
+            )
+        };
+        if result != 0 {
+            return Err(anyhow!(
+                "Failed to raise ambient capability {}: {}",
+                name,
+                std::io::Error::last_os_error()
+            ));
+        }
+    }
+    debug!("Raised ambient capabilities for child processes");
+    Ok(())
+}
+
+/// Clear the ambient capabilities for a command run on the host, e.g. hook scripts
+pub fn drop_capabilities(cmd: &mut Command) {
+    if !capability_mode() {
+        return;
+    }
+    unsafe {
+        cmd.pre_exec(|| {
+            if libc::prctl(
+                libc::PR_CAP_AMBIENT,
+                libc::PR_CAP_AMBIENT_CLEAR_ALL,
+                0 as libc::c_ulong,
+                0,
+                0,
+            ) != 0
+            {
+                return Err(std::io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+}
+
+/// Prefix for commands run in the namespace via ip netns exec (which needs the capabilities
+/// itself), so the application does not inherit them
+pub fn drop_capabilities_prefix() -> Vec<&'static str> {
+    if capability_mode() {
+        vec!["setpriv", "--inh-caps=-all", "--ambient-caps=-all", "--"]
+    } else {
+        Vec::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const STATUS: &str = "Name:\tvopono\n\
+        Umask:\t0022\n\
+        CapInh:\t0000000000000000\n\
+        CapPrm:\t0000000000203002\n\
+        CapEff:\t0000000000000000\n\
+        CapBnd:\t000001ffffffffff\n";
+
+    #[test]
+    fn permitted_capabilities_from_status() {
+        // cap_dac_override, cap_net_admin, cap_net_raw and cap_sys_admin, without cap_kill
+        assert_eq!(parse_permitted(STATUS), Some(0x203002));
+        assert_eq!(missing_from(0x203002), vec!["cap_kill"]);
+        assert!(missing_from(0x1ff_ffff_ffff).is_empty());
+        assert_eq!(missing_from(0).len(), REQUIRED_CAPABILITIES.len());
+        assert_eq!(parse_permitted("Name:\tvopono\n"), None);
+    }
+}
@@ -0,0 +1,319 @@
+// Garbage collection of state left behind by vopono instances which died without tearing down
+// Every instance writes a lockfile named after its PID, holding the namespace state. If all
+// instances using a namespace have exited, the namespace is torn down from the recorded state
+// (firewall rules, veth pair, DNS config) as the last instance would have done. Namespaces
+// without any lockfiles, host nftables tables of exited instances and an unused bridge with its
+// rules are removed as well.
+
+use super::{config_dir, get_existing_namespaces, get_pids_in_namespace, sudo_command};
+use crate::network::bridge::{BRIDGE_NAME, Bridge};
+use crate::network::netlink;
+use crate::network::netns::{Lockfile, NamespaceSetupLock};
+use anyhow::Context;
+use log::{debug, info, warn};
+use std::collections::BTreeMap;
+use std::fmt::Display;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// Host nftables tables shared by all namespaces, only orphaned once no instance is running
+const SHARED_NFT_TABLES: &[(&str, &str)] = &[
+    ("inet", "vopono_nat"),
+    ("inet", "vopono_bridge"),
+    ("ip", "vopono_bridge_nat"),
+];
+const PUBLISH_NFT_TABLE_PREFIX: &str = "vopono_publish_";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Orphan {
+    /// Lockfiles of exited instances. If no running instance uses the namespace it is torn down.
+    Instances {
+        namespace: String,
+        lockfiles: Vec<PathBuf>,
+        in_use: bool,
+    },
+    /// vopono namespace with no lockfiles and no processes
+    Namespace(String),
+    /// Host nftables table (family, name)
+    NftTable(String, String),
+    /// Host bridge for --bridge with no namespaces attached
+    Bridge,
+}
+
+impl Display for Orphan {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Instances {
+                namespace,
+                lockfiles,
+                in_use: true,
+            } => write!(
+                f,
+                "{} lockfiles of exited instances using namespace {}",
+                lockfiles.len(),
+                namespace
+            ),
+            Self::Instances { namespace, .. } => {
+                write!(f, "namespace {namespace} (all vopono instances exited)")
+            }
+            Self::Namespace(name) => write!(f, "namespace {name} (no lockfiles)"),
+            Self::NftTable(family, name) => write!(f, "nftables table {family} {name}"),
+            Self::Bridge => write!(f, "bridge {BRIDGE_NAME} (no attached namespaces)"),
+        }
+    }
+}
+
+impl Orphan {
+    /// Whether the orphan was left by the given instances of the namespace, so a forced stop only
+    /// cleans up after them. Shared tables and the bridge are left to vopono cleanup.
+    pub fn belongs_to(&self, name: &str, pids: &[u32]) -> bool {
+        match self {
+            Self::Instances { namespace, .. } | Self::Namespace(namespace) => namespace == name,
+            Self::NftTable(_, table) => table
+                .strip_prefix(PUBLISH_NFT_TABLE_PREFIX)
+                .and_then(|pid| pid.parse::<u32>().ok())
+                .is_some_and(|pid| pids.contains(&pid)),
+            Self::Bridge => false,
+        }
+    }
+}
+
+pub fn pid_running(pid: u32) -> bool {
+    Path::new("/proc").join(pid.to_string()).exists()
+}
+
+/// PID of the instance owning the lockfile, from its file name
+pub fn lockfile_pid(path: &Path) -> Option<u32> {
+    path.file_name()?.to_str()?.parse().ok()
+}
+
+#[derive(Debug, Default)]
+struct NamespaceLocks {
+    running: Vec<PathBuf>,
+    exited: Vec<PathBuf>,
+}
+
+fn lockfiles_by_namespace() -> anyhow::Result<BTreeMap<String, NamespaceLocks>> {
+    let dir = config_dir()?.join("vopono/locks");
+    let mut namespaces: BTreeMap<String, NamespaceLocks> = BTreeMap::new();
+    if !dir.exists() {
+        return Ok(namespaces);
+    }
+    for ns_dir in dir.read_dir()?.flatten().filter(|x| x.path().is_dir()) {
+        let name = ns_dir.file_name().to_string_lossy().to_string();
+        let entry = namespaces.entry(name).or_default();
+        for lockfile in ns_dir.path().read_dir()?.flatten() {
+            let path = lockfile.path();
+            match lockfile_pid(&path) {
+                Some(pid) if pid_running(pid) => entry.running.push(path),
+                Some(_) => entry.exited.push(path),
+                None => debug!(
+                    "Ignoring unknown file in locks directory: {}",
+                    path.display()
+                ),
+            }
+        }
+    }
+    Ok(namespaces)
+}
+
+fn nft_tables() -> Vec<(String, String)> {
+    let Ok(output) = Command::new("nft").args(["list", "tables"]).output() else {
+        return Vec::new();
+    };
+    String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .filter_map(
+            |line| match line.split_whitespace().collect::<Vec<_>>()[..] {
+                ["table", family, name] => Some((family.to_string(), name.to_string())),
+                _ => None,
+            },
+        )
+        .collect()
+}
+
+/// Find state left behind by vopono instances which are no longer running
+pub fn find_orphans() -> anyhow::Result<Vec<Orphan>> {
+    let mut orphans = Vec::new();
+    let locks = lockfiles_by_namespace()?;
+    let existing = get_existing_namespaces()?;
+
+    for (namespace, ns_locks) in locks.iter() {
+        if !ns_locks.exited.is_empty() {
+            orphans.push(Orphan::Instances {
+                namespace: namespace.clone(),
+                lockfiles: ns_locks.exited.clone(),
+                in_use: !ns_locks.running.is_empty(),
+            });
+        }
+    }
+
+    // Only namespaces vopono created, identified by the default name or the host veth
+    for namespace in existing.iter().filter(|x| !locks.contains_key(*x)) {
+        let vopono_created = namespace.starts_with("vo_")
+            || Path::new("/sys/class/net")
+                .join(format!("{namespace}_d"))
+                .exists();
+        if vopono_created && get_pids_in_namespace(namespace)?.is_empty() {
+            orphans.push(Orphan::Namespace(namespace.clone()));
+        }
+    }
+
+    let any_running = locks.values().any(|x| !x.running.is_empty());
+    for (family, name) in nft_tables() {
+        let shared = SHARED_NFT_TABLES.contains(&(family.as_str(), name.as_str()));
+        let exited_publish = name
+            .strip_prefix(PUBLISH_NFT_TABLE_PREFIX)
+            .and_then(|pid| pid.parse::<u32>().ok())
+            .is_some_and(|pid| !pid_running(pid));
+        if (shared && !any_running) || exited_publish {
+            orphans.push(Orphan::NftTable(family, name));
+        }
+    }
+    if !any_running && Bridge::is_orphaned() {
+        orphans.push(Orphan::Bridge);
+    }
+    Ok(orphans)
+}
+
+/// Remove the orphaned state, tearing down namespaces no longer used by any instance
+pub fn remove_orphan(orphan: &Orphan) -> anyhow::Result<()> {
+    match orphan {
+        Orphan::Instances {
+            namespace,
+            lockfiles,
+            in_use,
+        } => {
+            let _lock = NamespaceSetupLock::acquire(namespace)?;
+            // Another instance may have attached to it since it was found
+            let in_use = *in_use
+                || lockfiles_by_namespace()?
+                    .get(namespace)
+                    .is_some_and(|x| !x.running.is_empty());
+            // Read the state before removing the lockfiles, so the teardown sees no instances
+            let state: Option<Lockfile> = if in_use {
+                None
+            } else {
+                lockfiles.iter().find_map(|path| {
+                    File::open(path)
+                        .ok()
+                        .and_then(|f| ron::de::from_reader(f).ok())
+                })
+            };
+            for path in lockfiles {
+                std::fs::remove_file(path)
+                    .with_context(|| format!("Failed to remove lockfile: {}", path.display()))?;
+            }
+            if let Some(lock) = state {
+                teardown(lock);
+            } else if !in_use {
+                warn!("Could not read state of namespace {namespace}, deleting namespace only");
+                delete_namespace(namespace)?;
+            }
+        }
+        Orphan::Namespace(name) => {
+            let _lock = NamespaceSetupLock::acquire(name)?;
+            // It may have been mid-setup by another instance when found
+            if lockfiles_by_namespace()?.contains_key(name) {
+                debug!("Namespace {name} is now in use, skipping cleanup");
+                return Ok(());
+            }
+            delete_namespace(name)?;
+        }
+        Orphan::NftTable(family, name) => {
+            // May already have been removed by tearing down a namespace
+            if nft_tables().contains(&(family.clone(), name.clone())) {
+                sudo_command(&["nft", "delete", "table", family, name])
+                    .with_context(|| format!("Failed to delete nft table {family} {name}"))?;
+            }
+        }
+        Orphan::Bridge => Bridge::remove_orphaned()
+            .with_context(|| format!("Failed to remove bridge {BRIDGE_NAME}"))?,
+    }
+    Ok(())
+}
+
+fn delete_namespace(name: &str) -> anyhow::Result<()> {
+    std::fs::remove_dir_all(format!("/etc/netns/{name}")).ok();
+    if get_existing_namespaces()?.iter().any(|x| x == name) {
+        netlink::delete_namespace(name)?;
+    }
+    Ok(())
+}
+
+/// Kill what is left running in the namespace and drop the recorded state, which removes the
+/// firewall rules, veth pair and namespace
+fn teardown(lock: Lockfile) {
+    let mut ns = lock.ns;
+    let name = ns.name.clone();
+    if !get_existing_namespaces()
+        .map(|x| x.contains(&name))
+        .unwrap_or(false)
+    {
+        // The veth pair went with the namespace
+        std::mem::forget(ns.veth_pair.take());
+        std::mem::forget(ns.wireguard.take());
+    }
+    for pid in get_pids_in_namespace(&name).unwrap_or_default() {
+        debug!("Killing process {pid} left in namespace {name}");
+        nix::sys::signal::kill(
+            nix::unistd::Pid::from_raw(pid),
+            nix::sys::signal::Signal::SIGKILL,
+        )
+        .ok();
+    }
+    ns.forget_processes();
+    // Drop implementations of the individual parts panic on some failures, e.g. rules which
+    // were already removed by hand, which must not stop the rest of the cleanup
+    if std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| drop(ns))).is_err() {
+        warn!("Some state of namespace {name} could not be removed");
+    }
+}
+
+/// Find and remove orphaned state, returning the number of orphans removed
+pub fn clean_orphans() -> anyhow::Result<usize> {
+    let orphans = find_orphans()?;
+    let mut removed = 0;
+    for orphan in orphans.iter() {
+        info!("Cleaning up {orphan}");
+        match remove_orphan(orphan) {
+            Ok(()) => removed += 1,
+            Err(e) => warn!("Failed to clean up {orphan}: {e:?}"),
+        }
+    }
+    Ok(removed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lockfile_pid_from_file_name() {
+        assert_eq!(
+            lockfile_pid(Path::new("/home/user/.config/vopono/locks/vo_mv_se/1234")),
+            Some(1234)
+        );
+        assert_eq!(lockfile_pid(Path::new("/tmp/locks/vo_mv_se/notes")), None);
+        assert!(pid_running(std::process::id()));
+    }
+
+    #[test]
+    fn orphans_of_namespace() {
+        let instances = Orphan::Instances {
+            namespace: "vo_mv_se".to_string(),
+            lockfiles: Vec::new(),
+            in_use: false,
+        };
+        assert!(instances.belongs_to("vo_mv_se", &[]));
+        assert!(!instances.belongs_to("vo_pia_us", &[]));
+        assert!(Orphan::Namespace("vo_mv_se".to_string()).belongs_to("vo_mv_se", &[]));
+        let publish = Orphan::NftTable("inet".to_string(), "vopono_publish_1234".to_string());
+        assert!(publish.belongs_to("vo_mv_se", &[1234]));
+        assert!(!publish.belongs_to("vo_mv_se", &[4321]));
+        let nat = Orphan::NftTable("inet".to_string(), "vopono_nat".to_string());
+        assert!(!nat.belongs_to("vo_mv_se", &[1234]));
+        assert!(!Orphan::Bridge.belongs_to("vo_mv_se", &[1234]));
+    }
+}
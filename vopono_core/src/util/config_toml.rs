@@ -0,0 +1,66 @@
+// Writing settings to the vopono config.toml, for vopono setup
+// Top-level keys are replaced in place, or added before the first table, so comments and
+// [profile.<name>] sections of an existing file are kept as they are.
+
+/// Top-level key set on a line, e.g. server in server = "se"
+fn line_key(line: &str) -> Option<&str> {
+    let (key, _) = line.split_once('=')?;
+    let key = key.trim().trim_matches('"');
+    (!key.is_empty() && !line.trim_start().starts_with('#')).then_some(key)
+}
+
+/// The config file contents with the given top-level keys set to the string values
+pub fn set_top_level_keys(existing: &str, values: &[(&str, String)]) -> String {
+    let mut lines: Vec<String> = existing.lines().map(|x| x.to_string()).collect();
+    // Top-level keys end at the first table header
+    let mut top_end = lines
+        .iter()
+        .position(|x| x.trim_start().starts_with('['))
+        .unwrap_or(lines.len());
+    for (key, value) in values {
+        let line = format!("{key} = {}", toml::Value::from(value.as_str()));
+        match lines[..top_end]
+            .iter()
+            .position(|x| line_key(x) == Some(key))
+        {
+            Some(i) => lines[i] = line,
+            None => {
+                // Before the blank lines separating the first table
+                let mut i = top_end;
+                while i > 0 && lines[i - 1].trim().is_empty() {
+                    i -= 1;
+                }
+                lines.insert(i, line);
+                top_end += 1;
+            }
+        }
+    }
+    let mut contents = lines.join("\n");
+    contents.push('\n');
+    contents
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn set_config_keys() {
+        let existing = "# my defaults\nprovider = \"ProtonVPN\"\nuser = \"alice\"\n\n[profile.torrent]\nserver = \"ch\"\n";
+        let contents = set_top_level_keys(
+            existing,
+            &[
+                ("provider", "Mullvad".to_string()),
+                ("server", "se".to_string()),
+            ],
+        );
+        assert_eq!(
+            contents,
+            "# my defaults\nprovider = \"Mullvad\"\nuser = \"alice\"\nserver = \"se\"\n\n[profile.torrent]\nserver = \"ch\"\n"
+        );
+        assert_eq!(
+            set_top_level_keys("", &[("firewall", "NfTables".to_string())]),
+            "firewall = \"NfTables\"\n"
+        );
+    }
+}
@@ -0,0 +1,74 @@
+// Credentials supplied at exec time
+// Provider credentials can be given as VOPONO_{PROVIDER}_USERNAME / VOPONO_{PROVIDER}_PASSWORD
+// environment variables (using the provider alias, e.g. VOPONO_PIA_USERNAME) or entered at a
+// hidden prompt with vopono exec --prompt-credentials. Either way they are only held in memory
+// and take precedence over the system keyring and the auth files in the config directory.
+
+use super::keyring;
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+static SESSION_CREDENTIALS: Mutex<Option<HashMap<String, (String, String)>>> = Mutex::new(None);
+
+/// Use the given credentials for the provider for the rest of this process
+pub fn set_session_credentials(provider: &str, user: &str, pass: &str) {
+    let mut session = SESSION_CREDENTIALS
+        .lock()
+        .unwrap_or_else(|e| e.into_inner());
+    session
+        .get_or_insert_with(HashMap::new)
+        .insert(provider.to_string(), (user.to_string(), pass.to_string()));
+}
+
+/// Names of the username and password environment variables for the provider alias
+pub fn env_var_names(provider: &str) -> (String, String) {
+    let name: String = provider
+        .to_uppercase()
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect();
+    (
+        format!("VOPONO_{name}_USERNAME"),
+        format!("VOPONO_{name}_PASSWORD"),
+    )
+}
+
+/// Credentials from the hidden prompt or environment variables, if given
+pub fn supplied_credentials(provider: &str) -> Option<(String, String)> {
+    let session = SESSION_CREDENTIALS
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .as_ref()
+        .and_then(|x| x.get(provider).cloned());
+    session.or_else(|| {
+        let (user_var, pass_var) = env_var_names(provider);
+        Some((std::env::var(user_var).ok()?, std::env::var(pass_var).ok()?))
+    })
+}
+
+/// Credentials which are not read from a plaintext file: supplied at exec time or stored in
+/// the system keyring
+pub fn load_credentials(provider: &str) -> Option<(String, String)> {
+    supplied_credentials(provider).or_else(|| keyring::load_credentials(provider))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn session_credentials_and_env_var_names() {
+        assert_eq!(
+            env_var_names("pia"),
+            (
+                "VOPONO_PIA_USERNAME".to_string(),
+                "VOPONO_PIA_PASSWORD".to_string()
+            )
+        );
+        set_session_credentials("test-provider", "user", "pass");
+        assert_eq!(
+            supplied_credentials("test-provider"),
+            Some(("user".to_string(), "pass".to_string()))
+        );
+    }
+}
@@ -0,0 +1,157 @@
+// Desktop entries launching applications through vopono, for vopono desktop
+// The name and icon are taken from the application's own desktop entry where there is one, so
+// the launcher looks like the original in the application menu. The Exec line runs vopono exec
+// with sudo's askpass program (or file capabilities), as there is no terminal to prompt in.
+
+use std::fmt::Display;
+use std::path::{Path, PathBuf};
+
+/// Name and icon from the [Desktop Entry] group of a desktop entry
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct AppEntry {
+    pub name: Option<String>,
+    pub icon: Option<String>,
+}
+
+pub fn parse_desktop_entry(contents: &str) -> AppEntry {
+    let mut entry = AppEntry::default();
+    let mut in_group = false;
+    for line in contents.lines().map(str::trim) {
+        if line.starts_with('[') {
+            in_group = line == "[Desktop Entry]";
+            continue;
+        }
+        if !in_group {
+            continue;
+        }
+        // Untranslated keys only, e.g. Name= but not Name[de]=
+        match line.split_once('=') {
+            Some((key, value)) if key.trim() == "Name" => {
+                entry.name = Some(value.trim().to_string())
+            }
+            Some((key, value)) if key.trim() == "Icon" => {
+                entry.icon = Some(value.trim().to_string())
+            }
+            _ => {}
+        }
+    }
+    entry
+}
+
+/// Directories searched for desktop entries, per the XDG base directory spec
+fn application_dirs() -> Vec<PathBuf> {
+    let mut dirs = Vec::new();
+    if let Ok(home) = std::env::var("HOME") {
+        dirs.push(Path::new(&home).join(".local/share"));
+    }
+    let data_dirs = std::env::var("XDG_DATA_DIRS")
+        .ok()
+        .filter(|x| !x.is_empty())
+        .unwrap_or_else(|| "/usr/local/share:/usr/share".to_string());
+    dirs.extend(data_dirs.split(':').map(PathBuf::from));
+    dirs.push(PathBuf::from("/var/lib/flatpak/exports/share"));
+    dirs.into_iter().map(|x| x.join("applications")).collect()
+}
+
+/// Desktop entry of an installed application, by desktop file id or executable name
+pub fn find_app_entry(app: &str) -> Option<AppEntry> {
+    let id = app.strip_suffix(".desktop").unwrap_or(app);
+    application_dirs()
+        .iter()
+        .map(|dir| dir.join(format!("{id}.desktop")))
+        .find_map(|path| std::fs::read_to_string(path).ok())
+        .map(|x| parse_desktop_entry(&x))
+}
+
+/// Quote an argument of the Exec key, which is then escaped as a string value
+fn quote_exec_arg(arg: &str) -> String {
+    const RESERVED: &[char] = &[
+        ' ', '\t', '\n', '"', '\'', '\\', '>', '<', '~', '|', '&', ';', '$', '*', '?', '#', '(',
+        ')', '`',
+    ];
+    let arg = arg.replace('%', "%%");
+    let quoted = if arg.is_empty() || arg.contains(RESERVED) {
+        let mut quoted = String::from("\"");
+        for c in arg.chars() {
+            if matches!(c, '"' | '`' | '$' | '\\') {
+                quoted.push('\\');
+            }
+            quoted.push(c);
+        }
+        quoted.push('"');
+        quoted
+    } else {
+        arg
+    };
+    quoted.replace('\\', "\\\\")
+}
+
+pub struct DesktopEntry {
+    pub name: String,
+    pub comment: String,
+    pub icon: Option<String>,
+    /// Command line of the launcher, starting with the vopono executable
+    pub exec: Vec<String>,
+}
+
+impl Display for DesktopEntry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let exec = self
+            .exec
+            .iter()
+            .map(|x| quote_exec_arg(x))
+            .collect::<Vec<_>>()
+            .join(" ");
+        writeln!(f, "[Desktop Entry]")?;
+        writeln!(f, "Type=Application")?;
+        writeln!(f, "Name={}", self.name)?;
+        writeln!(f, "Comment={}", self.comment)?;
+        writeln!(f, "Exec={exec}")?;
+        if let Some(icon) = &self.icon {
+            writeln!(f, "Icon={icon}")?;
+        }
+        writeln!(f, "Terminal=false")?;
+        writeln!(f, "Categories=Network;")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn render_desktop_entry() {
+        let app = parse_desktop_entry(
+            "[Desktop Entry]\nName=Firefox\nName[de]=Firefox-Browser\nIcon=firefox\n\n[Desktop Action new-window]\nName=New Window\n",
+        );
+        assert_eq!(
+            app,
+            AppEntry {
+                name: Some("Firefox".to_string()),
+                icon: Some("firefox".to_string()),
+            }
+        );
+        let entry = DesktopEntry {
+            name: "Firefox (via Mullvad DE)".to_string(),
+            comment: "Run Firefox through vopono".to_string(),
+            icon: app.icon,
+            exec: [
+                "/usr/bin/vopono",
+                "-A",
+                "exec",
+                "--server",
+                "de",
+                "firefox -P \"vpn\"",
+            ]
+            .iter()
+            .map(|x| x.to_string())
+            .collect(),
+        };
+        assert!(
+            entry.to_string().contains(
+                "Exec=/usr/bin/vopono -A exec --server de \"firefox -P \\\\\"vpn\\\\\"\"\n"
+            )
+        );
+        assert_eq!(quote_exec_arg("100%"), "100%%");
+    }
+}
@@ -0,0 +1,60 @@
+// Recording of planned commands for vopono exec --dry-run
+// With dry-run enabled, commands which would be run through NetworkNamespace::exec and
+// sudo_command are recorded instead of executed, so the firewall code paths can print the exact
+// rules they would add. Namespace, link and route setup goes over netlink, which is described
+// with the equivalent ip commands by the caller.
+
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+static DRY_RUN: AtomicBool = AtomicBool::new(false);
+static PLAN: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+pub fn set_dry_run(enabled: bool) {
+    DRY_RUN.store(enabled, Ordering::SeqCst);
+}
+
+pub fn is_dry_run() -> bool {
+    DRY_RUN.load(Ordering::SeqCst)
+}
+
+/// Command line as it would be typed in a shell
+pub fn command_line(command: &[&str]) -> String {
+    shell_words::join(command)
+}
+
+pub fn record(command: String) {
+    PLAN.lock()
+        .expect("Dry-run plan lock poisoned")
+        .push(command);
+}
+
+/// Commands recorded since the last call
+pub fn take_plan() -> Vec<String> {
+    std::mem::take(&mut *PLAN.lock().expect("Dry-run plan lock poisoned"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn record_planned_commands() {
+        record(command_line(&[
+            "nft",
+            "add",
+            "chain",
+            "inet",
+            "vopono_egress",
+            "output",
+            "{ type filter hook output priority -500 ; policy accept; }",
+        ]));
+        assert_eq!(
+            take_plan(),
+            [
+                "nft add chain inet vopono_egress output '{ type filter hook output priority -500 ; policy accept; }'"
+            ]
+        );
+        assert!(take_plan().is_empty());
+    }
+}
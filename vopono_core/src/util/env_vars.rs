@@ -22,12 +22,8 @@ pub fn set_env_vars(ns: &NetworkNamespace, forwarder: Option<&dyn Forwarder>, cm
         debug!("pactl not found, will not set PULSE_SERVER");
     }
 
-    if let Some(ref ns_ip) = ns.veth_pair_ips {
-        cmd.env("VOPONO_NS_IP", ns_ip.namespace_ip.to_string());
-        cmd.env("VOPONO_HOST_IP", ns_ip.host_ip.to_string());
-    }
-
-    cmd.env("VOPONO_NS", &ns.name);
+    // The same as for the hooks
+    ns.add_env_vars_to_cmd(cmd);
 
     // TODO: Do we want to provide -o open ports too?
     if let Some(f) = forwarder.as_ref() {
@@ -0,0 +1,118 @@
+// Newline-delimited JSON events, for --log-format json
+// Each line is a JSON object with the event name in "event" and an RFC 3339 "timestamp", so
+// wrappers can follow what vopono is doing without parsing the log messages. Log messages are
+// formatted the same way, as "log" events. The event and field names are kept stable.
+
+use serde::Serialize;
+use std::io::Write;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+static JSON_EVENTS: AtomicBool = AtomicBool::new(false);
+
+pub fn set_json_events(enabled: bool) {
+    JSON_EVENTS.store(enabled, Ordering::Relaxed);
+}
+
+pub fn json_events() -> bool {
+    JSON_EVENTS.load(Ordering::Relaxed)
+}
+
+#[derive(Serialize, Debug)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event<'a> {
+    NamespaceCreated {
+        namespace: &'a str,
+        provider: String,
+        protocol: String,
+    },
+    NamespaceAttached {
+        namespace: &'a str,
+    },
+    TunnelUp {
+        namespace: &'a str,
+        protocol: String,
+        interface: Option<String>,
+    },
+    TunnelDown {
+        namespace: &'a str,
+        reason: String,
+    },
+    WatchdogTriggered {
+        namespace: &'a str,
+        target: &'a str,
+        action: String,
+    },
+    PortForwarded {
+        namespace: &'a str,
+        port: u16,
+    },
+    ApplicationStarted {
+        namespace: &'a str,
+        application: &'a str,
+        pid: u32,
+    },
+    ApplicationExited {
+        namespace: &'a str,
+        application: &'a str,
+        pid: u32,
+        exit_code: Option<i32>,
+    },
+    NamespaceDestroyed {
+        namespace: &'a str,
+    },
+    /// vopono exited with this error
+    Error {
+        message: String,
+    },
+    Log {
+        level: String,
+        target: &'a str,
+        message: String,
+    },
+}
+
+/// The event as a JSON line, timestamped now
+pub fn event_line(event: &Event) -> String {
+    let mut value = serde_json::to_value(event).unwrap_or_default();
+    if let Some(object) = value.as_object_mut() {
+        object.insert(
+            "timestamp".to_string(),
+            chrono::Utc::now()
+                .to_rfc3339_opts(chrono::SecondsFormat::Millis, true)
+                .into(),
+        );
+    }
+    value.to_string()
+}
+
+/// Write the event to stderr, with the log output, if JSON events are enabled
+pub fn emit(event: Event) {
+    if json_events() {
+        let _ = writeln!(std::io::stderr().lock(), "{}", event_line(&event));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn serialize_events() {
+        let line = event_line(&Event::PortForwarded {
+            namespace: "vo_pr_ch",
+            port: 51413,
+        });
+        let value: serde_json::Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(value["event"], "port_forwarded");
+        assert_eq!(value["namespace"], "vo_pr_ch");
+        assert_eq!(value["port"], 51413);
+        assert!(value["timestamp"].as_str().unwrap().ends_with('Z'));
+        let line = event_line(&Event::ApplicationExited {
+            namespace: "vo_pr_ch",
+            application: "firefox",
+            pid: 42,
+            exit_code: None,
+        });
+        assert!(line.contains("\"exit_code\":null"));
+    }
+}
@@ -0,0 +1,79 @@
+// Lifecycle hook scripts run on the host
+// PreUp runs once the network namespace and veth pair exist but before the VPN is started, PostUp
+// once the namespace is fully set up, PreDown before the namespace is torn down and PostDown after
+// it has been deleted. Hooks run as the target user (if set) with the namespace metadata in the
+// environment, and $VOPONO_HOOK set to the hook name so one script can handle every hook point.
+// The watchdog callback (see network/watchdog.rs) runs the same way.
+
+use super::capabilities::drop_capabilities;
+use super::parse_command_str;
+use anyhow::anyhow;
+use std::fmt::Display;
+use std::process::Command;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Hook {
+    PreUp,
+    PostUp,
+    PreDown,
+    PostDown,
+    Watchdog,
+}
+
+impl Display for Hook {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::PreUp => write!(f, "preup"),
+            Self::PostUp => write!(f, "postup"),
+            Self::PreDown => write!(f, "predown"),
+            Self::PostDown => write!(f, "postdown"),
+            Self::Watchdog => write!(f, "watchdog"),
+        }
+    }
+}
+
+/// Command for the hook script (with arguments), run via sudo as the given user and group if set.
+/// The caller adds the namespace environment variables and spawns it.
+pub fn hook_command(
+    hook: Hook,
+    command: &str,
+    user: Option<&str>,
+    group: Option<&str>,
+) -> anyhow::Result<Command> {
+    let parsed = parse_command_str(command)?;
+    let program = parsed
+        .first()
+        .ok_or_else(|| anyhow!("Empty {hook} command"))?;
+
+    let mut sudo_args = Vec::new();
+    if let Some(user) = user {
+        sudo_args.extend(["--user", user]);
+    }
+    if let Some(group) = group {
+        sudo_args.extend(["--group", group]);
+    }
+
+    let mut cmd = if sudo_args.is_empty() {
+        let mut cmd = Command::new(program);
+        cmd.args(parsed[1..].iter());
+        cmd
+    } else {
+        let mut cmd = Command::new("sudo");
+        cmd.arg("--preserve-env")
+            .args(sudo_args)
+            .args(parsed.iter());
+        cmd
+    };
+    cmd.env("VOPONO_HOOK", hook.to_string());
+    drop_capabilities(&mut cmd);
+    Ok(cmd)
+}
+
+/// Run the hook and wait for it to finish, failing if it exits unsuccessfully
+pub fn run_blocking(mut cmd: Command, hook: Hook) -> anyhow::Result<()> {
+    let status = cmd.status()?;
+    if !status.success() {
+        return Err(anyhow!("{hook} script failed: {status}"));
+    }
+    Ok(())
+}
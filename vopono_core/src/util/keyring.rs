@@ -0,0 +1,163 @@
+// System keyring credential storage
+// Provider credentials and API tokens are stored in the Secret Service (GNOME Keyring, KWallet,
+// KeePassXC etc.) via secret-tool from libsecret, with the attributes service=vopono,
+// provider={alias} and key={name}. vopono runs as root, so secret-tool is run as the invoking
+// user to reach their session bus. On headless systems without a keyring, credentials are
+// kept in the plaintext files under the config directory as before.
+
+use super::get_username;
+use super::private_files::{is_private_temp_file, private_temp_dir, write_private_file};
+use anyhow::{Context, anyhow};
+use log::debug;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output, Stdio};
+use which::which;
+
+const SERVICE: &str = "vopono";
+const USERNAME_KEY: &str = "username";
+const PASSWORD_KEY: &str = "password";
+
+/// Whether secret-tool is installed and a session bus is available to reach the keyring
+pub fn keyring_available() -> bool {
+    which("secret-tool").is_ok() && std::env::var("DBUS_SESSION_BUS_ADDRESS").is_ok()
+}
+
+fn secret_tool(args: &[&str], stdin: Option<&str>) -> anyhow::Result<Output> {
+    let mut cmd = if nix::unistd::getuid().is_root() && std::env::var("SUDO_USER").is_ok() {
+        // The keyring belongs to the user that ran vopono, not root
+        let user = get_username()?;
+        let mut cmd = Command::new("sudo");
+        cmd.args([
+            "-u",
+            &user,
+            "--preserve-env=DBUS_SESSION_BUS_ADDRESS,XDG_RUNTIME_DIR",
+            "secret-tool",
+        ]);
+        cmd
+    } else {
+        Command::new("secret-tool")
+    };
+    cmd.args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped());
+
+    let mut child = cmd.spawn().context("Failed to run secret-tool")?;
+    if let Some(input) = stdin {
+        child
+            .stdin
+            .take()
+            .context("No stdin for secret-tool")?
+            .write_all(input.as_bytes())?;
+    }
+    // Dropping stdin closes it so secret-tool does not wait for input
+    drop(child.stdin.take());
+    Ok(child.wait_with_output()?)
+}
+
+pub fn store_secret(provider: &str, key: &str, secret: &str) -> anyhow::Result<()> {
+    let label = format!("vopono {provider} {key}");
+    let output = secret_tool(
+        &[
+            "store", "--label", &label, "service", SERVICE, "provider", provider, "key", key,
+        ],
+        Some(secret),
+    )?;
+    if !output.status.success() {
+        return Err(anyhow!(
+            "secret-tool store failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    Ok(())
+}
+
+pub fn lookup_secret(provider: &str, key: &str) -> Option<String> {
+    if !keyring_available() {
+        return None;
+    }
+    let output = secret_tool(
+        &[
+            "lookup", "service", SERVICE, "provider", provider, "key", key,
+        ],
+        None,
+    );
+    match output {
+        Ok(output) if output.status.success() && !output.stdout.is_empty() => {
+            Some(String::from_utf8_lossy(&output.stdout).trim().to_string())
+        }
+        Ok(_) => None,
+        Err(e) => {
+            debug!("Keyring lookup for {provider} {key} failed: {e:?}");
+            None
+        }
+    }
+}
+
+pub fn clear_secret(provider: &str, key: &str) -> anyhow::Result<()> {
+    secret_tool(
+        &[
+            "clear", "service", SERVICE, "provider", provider, "key", key,
+        ],
+        None,
+    )?;
+    Ok(())
+}
+
+pub fn store_credentials(provider: &str, user: &str, pass: &str) -> anyhow::Result<()> {
+    store_secret(provider, USERNAME_KEY, user)?;
+    store_secret(provider, PASSWORD_KEY, pass)
+}
+
+pub fn load_credentials(provider: &str) -> Option<(String, String)> {
+    Some((
+        lookup_secret(provider, USERNAME_KEY)?,
+        lookup_secret(provider, PASSWORD_KEY)?,
+    ))
+}
+
+const RUNTIME_AUTH_FILE: &str = "auth.txt";
+
+/// Write credentials from the keyring to a temporary auth file for OpenVPN (readable by root
+/// only), in a new private directory. The file should be removed when the namespace is shut down.
+pub fn write_runtime_auth_file(provider: &str, user: &str, pass: &str) -> anyhow::Result<PathBuf> {
+    let path = private_temp_dir(&format!("{provider}_auth"))?.join(RUNTIME_AUTH_FILE);
+    write_private_file(&path, format!("{user}\n{pass}").as_bytes())
+        .with_context(|| format!("Failed to write auth file: {}", path.display()))?;
+    Ok(path)
+}
+
+pub fn is_runtime_auth_file(path: &Path) -> bool {
+    is_private_temp_file(path) && path.file_name().is_some_and(|x| x == RUNTIME_AUTH_FILE)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::private_files::remove_private_temp_file;
+    use std::os::unix::fs::PermissionsExt;
+
+    #[test]
+    fn runtime_auth_file_is_private() {
+        let path = write_runtime_auth_file("test", "user", "pass").unwrap();
+        assert!(is_runtime_auth_file(&path));
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "user\npass");
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        let dir_mode = std::fs::metadata(path.parent().unwrap())
+            .unwrap()
+            .permissions()
+            .mode();
+        assert_eq!(dir_mode & 0o777, 0o700);
+        // Each call gets a new directory rather than a predictable path
+        let other = write_runtime_auth_file("test", "user", "pass").unwrap();
+        assert_ne!(path, other);
+        remove_private_temp_file(&path).unwrap();
+        remove_private_temp_file(&other).unwrap();
+        assert!(!path.exists());
+        assert!(!is_runtime_auth_file(Path::new(
+            "/tmp/vopono_test_auth_1.txt"
+        )));
+    }
+}
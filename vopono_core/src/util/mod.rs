@@ -1,9 +1,27 @@
+pub mod capabilities;
+pub mod cleanup;
+pub mod config_toml;
 pub mod country_map;
+pub mod credentials;
+pub mod desktop_entry;
+pub mod dry_run;
 pub mod env_vars;
+pub mod events;
+pub mod hooks;
+pub mod keyring;
 pub mod open_hosts;
 pub mod open_ports;
+pub mod parallel;
+pub mod private_files;
 pub mod pulseaudio;
+pub mod server_cache;
+pub mod server_filter;
+pub mod service_unit;
+pub mod stop;
+pub mod sync_cache;
+pub mod sync_filter;
 pub mod unix;
+pub mod verify_configs;
 pub mod wireguard;
 
 extern crate shell_words as shellwords;
@@ -15,8 +33,8 @@ use directories_next::BaseDirs;
 use ipnet::Ipv4Net;
 use log::{debug, info, warn};
 use nix::unistd::{Group, User};
-pub use open_hosts::open_hosts;
-pub use open_ports::open_ports;
+pub use open_hosts::{open_hosts, open_subnets};
+pub use open_ports::{open_ports, open_ports_with_protocol};
 use rand::seq::SliceRandom;
 use regex::Regex;
 use std::collections::HashMap;
@@ -26,10 +44,10 @@ use std::net::{IpAddr, Ipv4Addr, ToSocketAddrs};
 use std::path::{Path, PathBuf};
 use std::process::Command;
 use std::str::FromStr;
+use std::sync::atomic::{AtomicU32, Ordering};
 use sysinfo::{ProcessRefreshKind, RefreshKind, System};
 use users::{get_current_uid, get_user_by_uid};
 use walkdir::WalkDir;
-use which::which;
 
 pub fn config_dir() -> anyhow::Result<PathBuf> {
     let path: Option<PathBuf> = None
@@ -115,6 +133,14 @@ pub fn vopono_dir() -> anyhow::Result<PathBuf> {
     Ok(config_dir()?.join("vopono"))
 }
 
+/// Directory of state kept between runs of the namespace, e.g. the OpenVPN auth token
+pub fn netns_state_dir(ns_name: &str) -> anyhow::Result<PathBuf> {
+    let dir = vopono_dir()?.join("state").join(ns_name);
+    fs::create_dir_all(&dir)?;
+    fs::set_permissions(&dir, std::os::unix::fs::PermissionsExt::from_mode(0o700))?;
+    Ok(dir)
+}
+
 // TODO: DRY with above
 pub fn get_username() -> anyhow::Result<String> {
     if let Ok(user) = std::env::var("SUDO_USER") {
@@ -165,10 +191,7 @@ pub fn set_config_permissions() -> anyhow::Result<()> {
 }
 
 pub fn get_allocated_ip_addresses() -> anyhow::Result<Vec<Ipv4Net>> {
-    let output = Command::new("ip")
-        .args(["addr", "show", "type", "veth"])
-        .output()?
-        .stdout;
+    let output = Command::new("ip").args(["addr", "show"]).output()?.stdout;
     let output = std::str::from_utf8(&output)?;
     debug!("Existing interfaces: {output}");
 
@@ -181,29 +204,80 @@ pub fn get_allocated_ip_addresses() -> anyhow::Result<Vec<Ipv4Net>> {
     Ok(ips)
 }
 
+/// Destination prefixes of IPv4 routes on the host, in all routing tables (excluding default
+/// routes, and default-style routes like the 0.0.0.0/1 and 128.0.0.0/1 pair added by OpenVPN)
+pub fn get_host_routes() -> anyhow::Result<Vec<Ipv4Net>> {
+    let output = Command::new("ip")
+        .args(["-4", "route", "show", "table", "all"])
+        .output()?
+        .stdout;
+    let routes = parse_route_prefixes(std::str::from_utf8(&output)?);
+    debug!("Existing host routes: {:?}", &routes);
+    Ok(routes)
+}
+
+/// Routes with shorter prefixes than this cover the whole veth range, so are not collisions
+const MIN_ROUTE_PREFIX_LEN: u8 = 8;
+
+fn parse_route_prefixes(output: &str) -> Vec<Ipv4Net> {
+    const ROUTE_TYPES: [&str; 9] = [
+        "unicast",
+        "local",
+        "broadcast",
+        "multicast",
+        "anycast",
+        "blackhole",
+        "unreachable",
+        "prohibit",
+        "throw",
+    ];
+    output
+        .lines()
+        .filter_map(|line| {
+            let mut words = line.split_whitespace();
+            let mut prefix = words.next()?;
+            if ROUTE_TYPES.contains(&prefix) {
+                prefix = words.next()?;
+            }
+            Ipv4Net::from_str(prefix)
+                .ok()
+                .or_else(|| Ipv4Addr::from_str(prefix).ok().map(Ipv4Net::from))
+        })
+        .filter(|x| x.prefix_len() >= MIN_ROUTE_PREFIX_LEN)
+        .collect()
+}
+
 pub fn get_existing_namespaces() -> anyhow::Result<Vec<String>> {
-    let output = Command::new("ip").args(["netns", "list"]).output()?.stdout;
-    let output = std::str::from_utf8(&output)?
-        .split('\n')
-        .map(|x| x.split_whitespace().next())
-        .filter(|x| x.is_some())
-        .map(|x| String::from(x.unwrap()))
-        .collect();
+    let dir = Path::new(crate::network::netlink::NETNS_RUN_DIR);
+    let mut output: Vec<String> = if dir.exists() {
+        dir.read_dir()?
+            .flatten()
+            .map(|x| x.file_name().to_string_lossy().to_string())
+            .collect()
+    } else {
+        Vec::new()
+    };
+    output.sort();
     debug!("Existing namespaces: {output:?}");
 
     Ok(output)
 }
 
+/// PIDs of processes whose network namespace is the named one, compared by inode as ip netns pids
+/// does
 pub fn get_pids_in_namespace(ns_name: &str) -> anyhow::Result<Vec<i32>> {
-    let output = Command::new("ip")
-        .args(["netns", "pids", ns_name])
-        .output()?
-        .stdout;
-    let output = std::str::from_utf8(&output)?
-        .split('\n')
-        .filter_map(|x| x.split_whitespace().next())
-        .filter_map(|x| x.parse::<i32>().ok())
+    use std::os::unix::fs::MetadataExt;
+    let ns = std::fs::metadata(crate::network::netlink::namespace_path(ns_name))
+        .with_context(|| format!("Failed to read network namespace {ns_name}"))?;
+    let mut output: Vec<i32> = std::fs::read_dir("/proc")?
+        .flatten()
+        .filter_map(|x| x.file_name().to_str()?.parse::<i32>().ok())
+        .filter(|pid| {
+            std::fs::metadata(format!("/proc/{pid}/ns/net"))
+                .is_ok_and(|x| x.dev() == ns.dev() && x.ino() == ns.ino())
+        })
         .collect();
+    output.sort();
     debug!("PIDs active in {}: {:?}", &ns_name, output);
 
     Ok(output)
@@ -233,26 +307,64 @@ pub fn get_all_running_process_names() -> Vec<String> {
         .collect()
 }
 
+/// Default range the /24 veth subnets of network namespaces are assigned from
+pub const DEFAULT_VETH_SUBNET: Ipv4Addr = Ipv4Addr::new(10, 200, 0, 0);
+
+static VETH_SUBNET: AtomicU32 = AtomicU32::new(DEFAULT_VETH_SUBNET.to_bits());
+
+/// Set the /16 range used for veth subnets, e.g. to avoid collisions with Docker networks or
+/// other VPNs using 10.200.0.0/16
+pub fn set_veth_subnet(subnet: Ipv4Net) -> anyhow::Result<()> {
+    if subnet.prefix_len() != 16 {
+        return Err(anyhow!(
+            "Veth subnet must be a /16 range (e.g. 10.200.0.0/16), got: {subnet}"
+        ));
+    }
+    VETH_SUBNET.store(subnet.trunc().addr().to_bits(), Ordering::Relaxed);
+    Ok(())
+}
+
+/// Address of the given host in the target veth subnet, e.g. 10.200.{target_subnet}.{host}
+pub fn veth_address(target_subnet: u8, host: u8) -> Ipv4Addr {
+    let [a, b, _, _] = Ipv4Addr::from_bits(VETH_SUBNET.load(Ordering::Relaxed)).octets();
+    Ipv4Addr::new(a, b, target_subnet, host)
+}
+
 pub fn get_target_subnet() -> anyhow::Result<u8> {
+    // Skip any subnet overlapping an existing interface address or route (from other vopono
+    // namespaces, Docker networks, other VPNs etc.)
+    let mut used = get_allocated_ip_addresses()?;
+    used.extend(get_host_routes()?);
+    first_free_subnet(&used).ok_or_else(|| {
+        let [a, b, _, _] = veth_address(0, 0).octets();
+        anyhow!(
+            "Could not find a free subnet of form {a}.{b}.xxx.0/24 which does not collide with existing host routes or addresses, choose another range with --veth-subnet"
+        )
+    })
+}
+
+/// First veth subnet index whose /24 does not overlap any of the used prefixes
+fn first_free_subnet(used: &[Ipv4Net]) -> Option<u8> {
     // TODO: Fix hard limit of <254 vopono instances
-    let assigned_ips = get_allocated_ip_addresses()?;
-    let mut target_ip = 1;
-    while target_ip <= 254 {
-        let ip = Ipv4Net::new(Ipv4Addr::new(10, 200, target_ip, 1), 24)?;
-        if assigned_ips.contains(&ip) {
-            target_ip += 1;
-        } else {
-            return Ok(target_ip);
+    (1..=254).find(|&target| {
+        let subnet = Ipv4Net::new(veth_address(target, 0), 24).expect("Invalid veth subnet");
+        let collision = used
+            .iter()
+            .find(|x| x.contains(&subnet.network()) || subnet.contains(&x.network()));
+        if let Some(x) = collision {
+            debug!("Skipping veth subnet {subnet} which collides with {x}");
         }
-    }
-    Err(anyhow!(
-        "Could not find free subnet of form: 10.200.xxx.1/24"
-    ))
+        collision.is_none()
+    })
 }
 
 // TODO: Fix deprecated name
 pub fn sudo_command(command: &[&str]) -> anyhow::Result<()> {
     debug!("{}", command.join(" "));
+    if dry_run::is_dry_run() {
+        dry_run::record(dry_run::command_line(command));
+        return Ok(());
+    }
 
     let (start_command, args) = command
         .split_first()
@@ -270,79 +382,16 @@ pub fn sudo_command(command: &[&str]) -> anyhow::Result<()> {
     }
 }
 
-// TODO: Clean this up (can we combine maps and filters?)
-pub fn clean_dead_locks() -> anyhow::Result<()> {
-    let running_processes = get_all_running_pids();
-    let mut lockfile_path = config_dir()?;
-    lockfile_path.push("vopono/locks");
-
-    if lockfile_path.exists() && lockfile_path.read_dir()?.next().is_some() {
-        debug!("Cleaning dead lock files...");
-        // Delete files if their PIDs are no longer running
-        std::fs::create_dir_all(&lockfile_path)?;
-        WalkDir::new(&lockfile_path)
-            .into_iter()
-            .filter_map(|x| x.ok())
-            .filter(|x| x.path().is_file())
-            .map(|x| {
-                (
-                    x.clone(),
-                    x.file_name()
-                        .to_str()
-                        .expect("Failed to parse file name")
-                        .parse::<u32>()
-                        .ok(),
-                )
-            })
-            .filter(|x| x.1.is_some())
-            .map(|x| (x.0, running_processes.contains(&x.1.unwrap())))
-            .filter(|x| !x.1)
-            .try_for_each(|x| {
-                debug!("Removing lockfile: {}", x.0.path().display());
-                std::fs::remove_file(x.0.path())
-            })?;
-
-        // Delete subdirectories if they contain no locks (ignore errors)
-        WalkDir::new(&lockfile_path)
-            .into_iter()
-            .filter_map(|x| x.ok())
-            .filter(|x| x.path().is_dir())
-            .try_for_each(|x| std::fs::remove_dir(x.path()))
-            .ok();
-        std::thread::sleep(std::time::Duration::from_secs(1));
-    }
-    Ok(())
-}
-
-pub fn clean_dead_namespaces() -> anyhow::Result<()> {
-    let lock_namespaces = get_lock_namespaces()?;
-    let existing_namespaces = get_existing_namespaces()?;
-
-    existing_namespaces
-        .into_iter()
-        .filter(|x| {
-            !lock_namespaces.contains_key(x) && get_pids_in_namespace(x).unwrap().is_empty()
-        })
-        .try_for_each(|x| {
-            debug!("Removing dead namespace: {x}");
-            let path = format!("/etc/netns/{x}");
-            std::fs::remove_dir_all(path).ok();
-
-            sudo_command(&["ip", "netns", "delete", x.as_str()])
-        })?;
-
-    // TODO - deserialize to struct without Drop instead
-    std::mem::forget(lock_namespaces);
-    Ok(())
-}
-
 pub fn elevate_privileges(askpass: bool) -> anyhow::Result<()> {
     use signal_hook::{consts::SIGINT, flag};
     use std::sync::Arc;
     use std::sync::atomic::{AtomicBool, Ordering};
 
     // Check if already running as root
-    if nix::unistd::getuid().as_raw() != 0 {
+    if nix::unistd::getuid().as_raw() != 0 && capabilities::capability_mode() {
+        info!("Running with file capabilities instead of sudo");
+        capabilities::raise_ambient_capabilities()?;
+    } else if nix::unistd::getuid().as_raw() != 0 {
         info!("Calling sudo for elevated privileges, current user will be used as default user");
         let args: Vec<String> = std::env::args().collect();
 
@@ -426,9 +475,34 @@ pub fn get_configs_from_alias(list_path: &Path, alias: &str) -> Vec<PathBuf> {
         .collect::<Vec<PathBuf>>()
 }
 
+/// Time since the most recently written config file in the directory was modified
+pub fn configs_age(list_path: &Path) -> Option<std::time::Duration> {
+    WalkDir::new(list_path)
+        .into_iter()
+        .filter_map(|x| x.ok())
+        .filter(|x| {
+            x.path()
+                .extension()
+                .is_some_and(|ext| ext == "conf" || ext == "ovpn")
+        })
+        .filter_map(|x| x.metadata().ok()?.modified().ok()?.elapsed().ok())
+        .min()
+}
+
 pub fn get_config_from_alias(list_path: &Path, alias: &str) -> anyhow::Result<PathBuf> {
     let paths = get_configs_from_alias(list_path, alias);
-    if paths.is_empty() {
+    choose_config(&paths, alias)
+}
+
+/// Randomly choose one of the given config files, or the one named exactly like the alias
+pub fn choose_config(paths: &[PathBuf], alias: &str) -> anyhow::Result<PathBuf> {
+    if let Some(exact) = paths
+        .iter()
+        .find(|x| x.file_stem().is_some_and(|stem| stem == alias))
+    {
+        info!("Chosen config: {}", exact.display());
+        Ok(exact.clone())
+    } else if paths.is_empty() {
         Err(anyhow!("Could not find config file for alias {}", &alias))
     } else {
         let config = paths
@@ -451,6 +525,8 @@ pub fn get_config_file_protocol(config_file: &Path) -> anyhow::Result<Protocol>
 
     if content.contains("[Interface]") {
         Ok(Protocol::Wireguard)
+    } else if content.trim_start().starts_with('{') {
+        Ok(Protocol::Xray)
     } else {
         // TODO: Don't always assume OpenVPN
         Ok(Protocol::OpenVpn)
@@ -458,13 +534,7 @@ pub fn get_config_file_protocol(config_file: &Path) -> anyhow::Result<Protocol>
 }
 
 pub fn get_firewall() -> anyhow::Result<Firewall> {
-    if which("nft").is_ok() {
-        Ok(Firewall::NfTables)
-    } else if which("iptables").is_ok() {
-        Ok(Firewall::IpTables)
-    } else {
-        Err(anyhow!("Neither nftables nor iptables is installed!"))
-    }
+    Firewall::detect()
 }
 
 pub fn get_lock_namespaces() -> anyhow::Result<HashMap<String, Vec<Lockfile>>> {
@@ -477,6 +547,8 @@ pub fn get_lock_namespaces() -> anyhow::Result<HashMap<String, Vec<Lockfile>>> {
         .into_iter()
         .filter(|x| x.is_ok() && x.as_ref().unwrap().path().is_file())
         .map(|x| x.unwrap())
+        // Skip lockfiles of exited instances, which are cleaned up separately
+        .filter(|x| cleanup::lockfile_pid(x.path()).is_some_and(cleanup::pid_running))
         .try_for_each(|x| -> anyhow::Result<()> {
             let lockfile = File::open(x.path())?;
             let lock: Lockfile = ron::de::from_reader(lockfile)?;
@@ -505,3 +577,79 @@ pub fn hostname_to_ip(hostname: &str) -> anyhow::Result<Vec<IpAddr>> {
 
     Ok(ip_addrs)
 }
+
+/// Run a closure on a new thread which has joined the given network namespace
+/// Threads spawned from within the closure (e.g. by reqwest) inherit the network namespace
+pub fn run_in_netns<T, F>(ns_name: &str, f: F) -> anyhow::Result<T>
+where
+    T: Send + 'static,
+    F: FnOnce() -> anyhow::Result<T> + Send + 'static,
+{
+    let ns_path = PathBuf::from("/var/run/netns").join(ns_name);
+    std::thread::spawn(move || {
+        let ns_file = File::open(&ns_path)
+            .with_context(|| format!("Failed to open network namespace: {}", ns_path.display()))?;
+        nix::sched::setns(ns_file, nix::sched::CloneFlags::CLONE_NEWNET)
+            .with_context(|| format!("Failed to enter network namespace: {}", ns_path.display()))?;
+        f()
+    })
+    .join()
+    .map_err(|_| anyhow!("Thread in network namespace panicked"))?
+}
+
+/// Spawn a long running thread in the network namespace. Threads it spawns are in the namespace
+/// too.
+pub fn spawn_in_netns<T, F>(
+    ns_name: &str,
+    f: F,
+) -> anyhow::Result<std::thread::JoinHandle<anyhow::Result<T>>>
+where
+    T: Send + 'static,
+    F: FnOnce() -> anyhow::Result<T> + Send + 'static,
+{
+    let ns_file = File::open(PathBuf::from("/var/run/netns").join(ns_name))
+        .with_context(|| format!("Failed to open network namespace: {ns_name}"))?;
+    Ok(std::thread::spawn(move || {
+        nix::sched::setns(ns_file, nix::sched::CloneFlags::CLONE_NEWNET)
+            .context("Failed to enter network namespace")?;
+        f()
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn veth_subnet_skips_collisions() {
+        let routes = parse_route_prefixes(
+            "default via 192.168.1.1 dev wlan0 proto dhcp\n\
+             10.200.1.0/24 dev vopono_d proto kernel scope link src 10.200.1.1\n\
+             local 10.200.2.5 dev docker0 table local proto kernel scope host src 10.200.2.5\n\
+             broadcast 192.168.1.255 dev wlan0 table local proto kernel scope link\n\
+             0.0.0.0/1 via 10.8.0.1 dev tun0\n\
+             128.0.0.0/1 via 10.8.0.1 dev tun0\n",
+        );
+        assert_eq!(
+            routes,
+            vec![
+                "10.200.1.0/24".parse::<Ipv4Net>().unwrap(),
+                "10.200.2.5/32".parse().unwrap(),
+                "192.168.1.255/32".parse().unwrap(),
+            ]
+        );
+        assert_eq!(first_free_subnet(&routes), Some(3));
+        assert_eq!(first_free_subnet(&["10.0.0.0/8".parse().unwrap()]), None);
+    }
+
+    #[test]
+    fn exact_config_name_is_chosen() {
+        let paths = [
+            PathBuf::from("/tmp/usa-usatl101.conf"),
+            PathBuf::from("/tmp/usa-usatl1010.conf"),
+        ];
+        for _ in 0..8 {
+            assert_eq!(choose_config(&paths, "usa-usatl101").unwrap(), paths[0]);
+        }
+    }
+}
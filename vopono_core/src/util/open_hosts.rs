@@ -1,40 +1,39 @@
 use crate::network::firewall::Firewall;
 use crate::network::netns::NetworkNamespace;
+use ipnet::IpNet;
 use std::net::IpAddr;
 
 pub fn open_hosts(netns_name: &str, hosts: &[IpAddr], firewall: Firewall) -> anyhow::Result<()> {
-    for host in hosts {
+    let subnets: Vec<IpNet> = hosts.iter().copied().map(IpNet::from).collect();
+    open_subnets(netns_name, &subnets, firewall)
+}
+
+/// Allow traffic from the network namespace to the given subnets (e.g. for --allow-lan)
+pub fn open_subnets(netns_name: &str, subnets: &[IpNet], firewall: Firewall) -> anyhow::Result<()> {
+    for subnet in subnets {
+        let (ipcmd, family) = match subnet {
+            IpNet::V4(_) => ("iptables", "ip"),
+            IpNet::V6(_) => ("ip6tables", "ip6"),
+        };
+        // Single hosts are given without the /32 prefix
+        let subnet = if subnet.prefix_len() == subnet.max_prefix_len() {
+            subnet.addr().to_string()
+        } else {
+            subnet.to_string()
+        };
         match firewall {
             Firewall::IpTables => {
                 NetworkNamespace::exec(
                     netns_name,
-                    &[
-                        "iptables",
-                        "-I",
-                        "OUTPUT",
-                        "1",
-                        "-d",
-                        &host.to_string(),
-                        "-j",
-                        "ACCEPT",
-                    ],
+                    &[ipcmd, "-I", "OUTPUT", "1", "-d", &subnet, "-j", "ACCEPT"],
                 )?;
             }
             Firewall::NfTables => {
                 NetworkNamespace::exec(
                     netns_name,
                     &[
-                        "nft",
-                        "insert",
-                        "rule",
-                        "inet",
-                        netns_name,
-                        "output",
-                        "ip",
-                        "daddr",
-                        &host.to_string(),
-                        "counter",
-                        "accept",
+                        "nft", "insert", "rule", "inet", netns_name, "output", family, "daddr",
+                        &subnet, "counter", "accept",
                     ],
                 )?;
             }
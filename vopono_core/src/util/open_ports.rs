@@ -1,4 +1,4 @@
-use crate::network::firewall::Firewall;
+use crate::network::firewall::{Firewall, allow_inbound_port};
 use crate::network::netns::NetworkNamespace;
 
 pub fn open_ports(
@@ -7,6 +7,16 @@ pub fn open_ports(
     firewall: Firewall,
 ) -> anyhow::Result<()> {
     // TODO: Allow UDP port forwarding?
+    open_ports_with_protocol(netns, ports, "tcp", firewall)
+}
+
+/// Open ports in the network namespace for the given protocol ("tcp" or "udp")
+pub fn open_ports_with_protocol(
+    netns: &NetworkNamespace,
+    ports: &[u16],
+    protocol: &str,
+    firewall: Firewall,
+) -> anyhow::Result<()> {
     // IPv6 forwarding?
     for port in ports {
         match firewall {
@@ -18,7 +28,7 @@ pub fn open_ports(
                         "-I",
                         "INPUT",
                         "-p",
-                        "tcp",
+                        protocol,
                         "--dport",
                         &port.to_string(),
                         "-j",
@@ -32,7 +42,7 @@ pub fn open_ports(
                         "-I",
                         "OUTPUT",
                         "-p",
-                        "tcp",
+                        protocol,
                         "--sport",
                         &port.to_string(),
                         "-j",
@@ -41,6 +51,7 @@ pub fn open_ports(
                 )?;
             }
             Firewall::NfTables => {
+                allow_inbound_port(netns, *port, protocol)?;
                 NetworkNamespace::exec(
                     &netns.name,
                     &[
@@ -50,7 +61,7 @@ pub fn open_ports(
                         "inet",
                         &netns.name,
                         "input",
-                        "tcp",
+                        protocol,
                         "dport",
                         &port.to_string(),
                         "counter",
@@ -78,7 +89,7 @@ pub fn open_ports(
                         "inet",
                         &netns.name,
                         "output",
-                        "tcp",
+                        protocol,
                         "sport",
                         &port.to_string(),
                         "counter",
@@ -0,0 +1,148 @@
+// Bounded parallelism for provider sync
+// Configs are fetched on a small pool of scoped threads which pull work items from a shared
+// counter, so results keep the order of the input. Progress is drawn on stderr when it is a
+// terminal, and failures are collected so a single bad server does not abort the whole sync.
+
+use log::warn;
+use std::io::{IsTerminal, Write};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+pub const DEFAULT_SYNC_JOBS: usize = 8;
+
+static SYNC_JOBS: AtomicUsize = AtomicUsize::new(DEFAULT_SYNC_JOBS);
+
+/// Set the maximum number of concurrent requests used during sync
+pub fn set_sync_jobs(jobs: usize) {
+    SYNC_JOBS.store(jobs.max(1), Ordering::Relaxed);
+}
+
+pub fn sync_jobs() -> usize {
+    SYNC_JOBS.load(Ordering::Relaxed)
+}
+
+pub struct Progress {
+    label: String,
+    total: usize,
+    done: AtomicUsize,
+    draw: Option<Mutex<()>>,
+}
+
+impl Progress {
+    const WIDTH: usize = 30;
+
+    pub fn new(label: &str, total: usize) -> Self {
+        let progress = Self {
+            label: label.to_string(),
+            total,
+            done: AtomicUsize::new(0),
+            draw: std::io::stderr().is_terminal().then(|| Mutex::new(())),
+        };
+        progress.render(0);
+        progress
+    }
+
+    pub fn inc(&self) {
+        let done = self.done.fetch_add(1, Ordering::Relaxed) + 1;
+        self.render(done);
+    }
+
+    fn render(&self, done: usize) {
+        if let Some(lock) = &self.draw {
+            let _guard = lock.lock().unwrap_or_else(|e| e.into_inner());
+            let filled = (done * Self::WIDTH)
+                .checked_div(self.total)
+                .unwrap_or(Self::WIDTH);
+            let mut stderr = std::io::stderr().lock();
+            write!(
+                stderr,
+                "\r{} [{}{}] {}/{}",
+                self.label,
+                "#".repeat(filled),
+                " ".repeat(Self::WIDTH - filled),
+                done,
+                self.total
+            )
+            .ok();
+            if done >= self.total {
+                writeln!(stderr).ok();
+            }
+            stderr.flush().ok();
+        }
+    }
+}
+
+/// Apply f to every item using at most sync_jobs() threads, returning results in input order
+pub fn parallel_map<T, R, F>(items: &[T], label: &str, f: F) -> Vec<anyhow::Result<R>>
+where
+    T: Sync,
+    R: Send,
+    F: Fn(&T) -> anyhow::Result<R> + Sync,
+{
+    let progress = Progress::new(label, items.len());
+    let next = AtomicUsize::new(0);
+    let results: Mutex<Vec<Option<anyhow::Result<R>>>> =
+        Mutex::new((0..items.len()).map(|_| None).collect());
+    let workers = sync_jobs().min(items.len());
+
+    std::thread::scope(|s| {
+        for _ in 0..workers {
+            s.spawn(|| {
+                loop {
+                    let i = next.fetch_add(1, Ordering::Relaxed);
+                    let Some(item) = items.get(i) else {
+                        break;
+                    };
+                    let result = f(item);
+                    results.lock().unwrap_or_else(|e| e.into_inner())[i] = Some(result);
+                    progress.inc();
+                }
+            });
+        }
+    });
+
+    results
+        .into_inner()
+        .unwrap_or_else(|e| e.into_inner())
+        .into_iter()
+        .map(|x| x.expect("Sync worker did not return a result"))
+        .collect()
+}
+
+/// Log a summary of the items which failed to sync
+pub fn report_failures(what: &str, failures: &[(String, anyhow::Error)]) {
+    if failures.is_empty() {
+        return;
+    }
+    warn!("Failed to sync {} {what}:", failures.len());
+    for (name, e) in failures {
+        warn!("  {name}: {e:#}");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parallel_map_keeps_order_and_errors() {
+        let items: Vec<u32> = (0..50).collect();
+        let results = parallel_map(&items, "test", |x| {
+            if x % 7 == 0 {
+                Err(anyhow::anyhow!("bad {x}"))
+            } else {
+                Ok(x * 2)
+            }
+        });
+        assert_eq!(results.len(), 50);
+        for (x, r) in items.iter().zip(results.iter()) {
+            match r {
+                Ok(v) => assert_eq!(*v, x * 2),
+                Err(e) => {
+                    assert_eq!(x % 7, 0);
+                    assert_eq!(e.to_string(), format!("bad {x}"));
+                }
+            }
+        }
+    }
+}
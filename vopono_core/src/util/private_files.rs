@@ -0,0 +1,133 @@
+// Private files for secrets (keys, credentials, generated configs)
+// vopono runs as root, so files it writes to a predictable path in a world-writable directory
+// (/tmp) can be pre-created or replaced with a symlink by any local user. Secrets are instead
+// written to new files (create_new, mode 0600) in a fresh directory (mode 0700, like mkdtemp) in
+// /run/vopono, which only root can write to. Others may traverse /run/vopono (mode 0711) but not
+// list it, so a directory given to a process which drops its privileges (tor) can be reached.
+// Without root, the vopono directory is used.
+
+use super::vopono_dir;
+use anyhow::{Context, anyhow};
+use std::fs::{DirBuilder, OpenOptions};
+use std::io::{ErrorKind, Write};
+use std::os::unix::fs::{DirBuilderExt, MetadataExt, OpenOptionsExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+
+const ROOT_RUNTIME_DIR: &str = "/run/vopono";
+
+fn runtime_dir_path() -> anyhow::Result<PathBuf> {
+    if nix::unistd::geteuid().is_root() {
+        Ok(PathBuf::from(ROOT_RUNTIME_DIR))
+    } else {
+        Ok(vopono_dir()?.join("run"))
+    }
+}
+
+/// Directory of the private directories, created if needed, which must be a directory owned by
+/// us and not accessible by others
+pub fn runtime_dir() -> anyhow::Result<PathBuf> {
+    let dir = runtime_dir_path()?;
+    if let Some(parent) = dir.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    match DirBuilder::new().mode(0o711).create(&dir) {
+        Ok(_) => {}
+        Err(e) if e.kind() == ErrorKind::AlreadyExists => {}
+        Err(e) => return Err(e).with_context(|| format!("Creating {}", dir.display())),
+    }
+    // Not following symlinks
+    let meta = std::fs::symlink_metadata(&dir)?;
+    if !meta.is_dir() || meta.uid() != nix::unistd::geteuid().as_raw() || meta.mode() & 0o066 != 0 {
+        return Err(anyhow!(
+            "Refusing to use {}: not a private directory owned by this user",
+            dir.display()
+        ));
+    }
+    // Created with mode 0700 by older versions
+    if meta.mode() & 0o011 != 0o011 {
+        std::fs::set_permissions(&dir, PermissionsExt::from_mode(0o711))?;
+    }
+    Ok(dir)
+}
+
+/// New private directory in the runtime directory, named {prefix}_{random}
+pub fn private_temp_dir(prefix: &str) -> anyhow::Result<PathBuf> {
+    let parent = runtime_dir()?;
+    for _ in 0..16 {
+        let dir = parent.join(format!("{prefix}_{:08x}", rand::random::<u32>()));
+        match DirBuilder::new().mode(0o700).create(&dir) {
+            Ok(_) => return Ok(dir),
+            Err(e) if e.kind() == ErrorKind::AlreadyExists => continue,
+            Err(e) => return Err(e).with_context(|| format!("Creating {}", dir.display())),
+        }
+    }
+    Err(anyhow!(
+        "Failed to create a private directory in {}",
+        parent.display()
+    ))
+}
+
+/// Writes a new file only readable by us, failing if the path exists
+pub fn write_private_file(path: &Path, contents: &[u8]) -> anyhow::Result<()> {
+    let mut f = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(path)
+        .with_context(|| format!("Creating private file: {}", path.display()))?;
+    f.write_all(contents)?;
+    Ok(())
+}
+
+/// Replaces a file with a new one only readable by us, e.g. a cache rewritten on each update.
+/// The old file (or a symlink planted in its place) is removed first, never written through
+pub fn replace_private_file(path: &Path, contents: &[u8]) -> anyhow::Result<()> {
+    match std::fs::remove_file(path) {
+        Err(e) if e.kind() != ErrorKind::NotFound => {
+            return Err(e).with_context(|| format!("Removing {}", path.display()));
+        }
+        _ => {}
+    }
+    write_private_file(path, contents)
+}
+
+/// Whether the file is in a private directory from private_temp_dir
+pub fn is_private_temp_file(path: &Path) -> bool {
+    let dir = path.parent();
+    runtime_dir_path().is_ok_and(|runtime| dir.and_then(Path::parent) == Some(runtime.as_path()))
+}
+
+/// Removes a file and its directory if it is in a private directory, and does nothing otherwise
+pub fn remove_private_temp_file(path: &Path) -> std::io::Result<()> {
+    match path.parent().filter(|_| is_private_temp_file(path)) {
+        Some(dir) => match std::fs::remove_dir_all(dir) {
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(()),
+            x => x,
+        },
+        None => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn private_file_is_new_and_owner_only() {
+        let dir = std::env::temp_dir().join(format!("vopono_test_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("secret");
+        std::fs::remove_file(&path).ok();
+        write_private_file(&path, b"key").unwrap();
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        // A pre-created file (or symlink) is never written to
+        assert!(write_private_file(&path, b"other").is_err());
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "key");
+        replace_private_file(&path, b"other").unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "other");
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
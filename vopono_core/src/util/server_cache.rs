@@ -0,0 +1,193 @@
+// Server list caching
+// Provider server lists (the raw JSON API responses) are cached in
+// {provider_dir}/.server_cache/{name}.json with the time they were fetched. Lists younger than
+// the TTL are reused without contacting the provider API, and a stale list is used (with a
+// warning) if the API cannot be reached, so sync and exec keep working offline.
+
+use anyhow::Context;
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+use std::fs::create_dir_all;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Cached server lists younger than this are reused during sync
+pub const DEFAULT_SERVER_LIST_TTL: Duration = Duration::from_secs(60 * 60);
+/// vopono exec warns if the provider's server list is older than this
+pub const SERVER_LIST_STALE_AGE: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+static SERVER_LIST_TTL_SECS: AtomicU64 = AtomicU64::new(DEFAULT_SERVER_LIST_TTL.as_secs());
+
+/// Set the TTL for cached server lists (zero always fetches from the provider API)
+pub fn set_server_list_ttl(ttl: Duration) {
+    SERVER_LIST_TTL_SECS.store(ttl.as_secs(), Ordering::Relaxed);
+}
+
+pub fn server_list_ttl() -> Duration {
+    Duration::from_secs(SERVER_LIST_TTL_SECS.load(Ordering::Relaxed))
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct CachedServerList {
+    fetched_at: u64,
+    data: serde_json::Value,
+}
+
+impl CachedServerList {
+    fn age(&self) -> Duration {
+        now_secs()
+            .checked_sub(self.fetched_at)
+            .map(Duration::from_secs)
+            .unwrap_or_default()
+    }
+}
+
+fn cache_dir(provider_dir: &Path) -> PathBuf {
+    provider_dir.join(".server_cache")
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|x| x.as_secs())
+        .unwrap_or_default()
+}
+
+fn read_cache(path: &Path) -> Option<CachedServerList> {
+    std::fs::read_to_string(path)
+        .ok()
+        .and_then(|x| serde_json::from_str(&x).ok())
+}
+
+/// Return the cached server list if it is within the TTL, otherwise call fetch and cache the result.
+/// Falls back to the stale cached list if fetch fails.
+pub fn cached_server_list<F>(
+    provider_dir: &Path,
+    name: &str,
+    fetch: F,
+) -> anyhow::Result<serde_json::Value>
+where
+    F: FnOnce() -> anyhow::Result<serde_json::Value>,
+{
+    let dir = cache_dir(provider_dir);
+    let path = dir.join(format!("{name}.json"));
+    let cached = read_cache(&path);
+    let ttl = server_list_ttl();
+
+    if let Some(cached) = cached.as_ref().filter(|x| x.age() < ttl) {
+        debug!(
+            "Using cached {name} server list fetched {}s ago",
+            cached.age().as_secs()
+        );
+        return Ok(cached.data.clone());
+    }
+
+    match fetch() {
+        Ok(data) => {
+            create_dir_all(&dir)?;
+            let entry = CachedServerList {
+                fetched_at: now_secs(),
+                data,
+            };
+            std::fs::write(&path, serde_json::to_string(&entry)?)
+                .with_context(|| format!("Writing server list cache: {}", path.display()))?;
+            Ok(entry.data)
+        }
+        Err(e) => match cached {
+            Some(cached) => {
+                warn!(
+                    "Failed to fetch {name} server list ({e:#}), using cached list from {} ago",
+                    format_age(cached.age())
+                );
+                Ok(cached.data)
+            }
+            None => Err(e),
+        },
+    }
+}
+
+/// Return the cached server list regardless of its age, without contacting the provider
+pub fn read_server_list(provider_dir: &Path, name: &str) -> Option<serde_json::Value> {
+    read_cache(&cache_dir(provider_dir).join(format!("{name}.json"))).map(|x| x.data)
+}
+
+/// Age of the most recently fetched server list for the provider, if any are cached
+pub fn server_list_age(provider_dir: &Path) -> Option<Duration> {
+    std::fs::read_dir(cache_dir(provider_dir))
+        .ok()?
+        .filter_map(|x| x.ok())
+        .filter_map(|x| read_cache(&x.path()))
+        .map(|x| x.age())
+        .min()
+}
+
+/// Warn if the provider's cached server list is older than SERVER_LIST_STALE_AGE
+pub fn warn_if_stale(provider_dir: &Path, provider: &str) {
+    if let Some(age) = server_list_age(provider_dir) {
+        if age > SERVER_LIST_STALE_AGE {
+            warn!(
+                "{provider} server list was last synced {} ago, run vopono sync to refresh it",
+                format_age(age)
+            );
+        }
+    }
+}
+
+fn format_age(age: Duration) -> String {
+    let secs = age.as_secs();
+    match secs {
+        0..3600 => format!("{} minutes", secs / 60),
+        3600..86400 => format!("{} hours", secs / 3600),
+        _ => format!("{} days", secs / 86400),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cached_server_list_ttl_and_offline_fallback() {
+        let dir = std::env::temp_dir().join(format!("vopono_server_cache_{}", std::process::id()));
+        std::fs::remove_dir_all(&dir).ok();
+        let offline = || Err(anyhow::anyhow!("offline"));
+
+        // Nothing cached and offline
+        assert!(cached_server_list(&dir, "servers", offline).is_err());
+
+        // Stale list is used when the API cannot be reached
+        create_dir_all(cache_dir(&dir)).unwrap();
+        let stale = CachedServerList {
+            fetched_at: 0,
+            data: serde_json::json!(["stale"]),
+        };
+        std::fs::write(
+            cache_dir(&dir).join("servers.json"),
+            serde_json::to_string(&stale).unwrap(),
+        )
+        .unwrap();
+        let data = cached_server_list(&dir, "servers", offline).unwrap();
+        assert_eq!(data, serde_json::json!(["stale"]));
+        assert!(server_list_age(&dir).unwrap() > SERVER_LIST_STALE_AGE);
+
+        // Stale list is refreshed, and the fresh list is then reused without fetching
+        let data =
+            cached_server_list(&dir, "servers", || Ok(serde_json::json!(["fresh"]))).unwrap();
+        assert_eq!(data, serde_json::json!(["fresh"]));
+        let data = cached_server_list(&dir, "servers", || panic!("fetched within TTL")).unwrap();
+        assert_eq!(data, serde_json::json!(["fresh"]));
+        assert_eq!(
+            read_server_list(&dir, "servers"),
+            Some(serde_json::json!(["fresh"]))
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn format_age_units() {
+        assert_eq!(format_age(Duration::from_secs(120)), "2 minutes");
+        assert_eq!(format_age(Duration::from_secs(7200)), "2 hours");
+        assert_eq!(format_age(Duration::from_secs(3 * 86400)), "3 days");
+    }
+}
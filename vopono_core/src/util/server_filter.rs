@@ -0,0 +1,211 @@
+// Server selection filters for vopono exec
+// Candidate configs for the server prefix are narrowed down by location (country and city, as
+// for sync filters, with cities also matched against the city the provider publishes) and by
+// the features recorded for each server at sync time. With --random-server one of the remaining
+// configs is picked at random, avoiding the one picked last time so consecutive sessions use a
+// different server. Servers can also be narrowed down by load, using current loads from the
+// provider API where it publishes them and the loads recorded at sync time otherwise.
+
+use super::sync_filter::SyncFilter;
+use super::vopono_dir;
+use crate::config::providers::{Provider, ServerFeature, ServerInfo};
+use anyhow::anyhow;
+use log::{debug, info, warn};
+use rand::seq::SliceRandom;
+use std::path::{Path, PathBuf};
+
+const LAST_RANDOM_SERVER_FILE: &str = "last_random_server";
+/// With --prefer-low-load, servers up to this many percentage points above the least loaded one
+/// are kept, so that a random choice still has a few to pick from
+const LOW_LOAD_MARGIN: u8 = 10;
+
+#[derive(Debug, Default, Clone)]
+pub struct ServerFilter {
+    pub location: SyncFilter,
+    pub features: Vec<ServerFeature>,
+    /// Maximum acceptable load in percent
+    pub max_load: Option<u8>,
+    pub prefer_low_load: bool,
+}
+
+impl ServerFilter {
+    pub fn is_empty(&self) -> bool {
+        self.location.is_empty() && self.features.is_empty() && !self.uses_load()
+    }
+
+    fn uses_load(&self) -> bool {
+        self.max_load.is_some() || self.prefer_low_load
+    }
+
+    /// Drop servers above the maximum load and, with prefer_low_load, all but the least loaded
+    /// ones. Servers with unknown load are only kept if no load is known for any of them.
+    fn filter_by_load(&self, servers: Vec<ServerInfo>) -> Vec<ServerInfo> {
+        let Some(min_load) = servers.iter().filter_map(|x| x.load).min() else {
+            if self.uses_load() && !servers.is_empty() {
+                warn!("No server load is known, ignoring the load filters");
+            }
+            return servers;
+        };
+        let limit = if self.prefer_low_load {
+            min_load.saturating_add(LOW_LOAD_MARGIN)
+        } else {
+            u8::MAX
+        };
+        let limit = self.max_load.map_or(limit, |max| max.min(limit));
+        servers
+            .into_iter()
+            .filter(|x| x.load.is_some_and(|load| load <= limit))
+            .collect()
+    }
+
+    pub fn matches(&self, info: &ServerInfo) -> bool {
+        let stem = info
+            .config_file
+            .file_stem()
+            .and_then(|x| x.to_str())
+            .unwrap_or_default();
+        let city_ok = |city: &str| {
+            let city = city.to_lowercase().replace(' ', "");
+            info.city
+                .as_ref()
+                .is_some_and(|x| x.to_lowercase().replace(' ', "").contains(&city))
+        };
+        let location_ok = self.location.matches(stem)
+            || (!self.location.cities.is_empty()
+                && self.location.cities.iter().any(|x| city_ok(x))
+                && SyncFilter {
+                    countries: self.location.countries.clone(),
+                    cities: Vec::new(),
+                }
+                .matches(stem));
+        location_ok && self.features.iter().all(|x| info.has_feature(*x))
+    }
+
+    /// The configs matching the filter
+    pub fn apply(&self, provider: &dyn Provider, configs: Vec<PathBuf>) -> Vec<PathBuf> {
+        if self.is_empty() {
+            return configs;
+        }
+        let total = configs.len();
+        let mut servers = provider.server_info(&configs);
+        if self.uses_load() {
+            match provider.live_server_load(&configs) {
+                Ok(loads) => {
+                    for server in servers.iter_mut() {
+                        if let Some(load) = loads.get(&server.config_file) {
+                            server.load = Some(*load);
+                        }
+                    }
+                }
+                Err(e) => warn!("Using server loads from the last sync: {e}"),
+            }
+        }
+        servers.retain(|x| self.matches(x));
+        let matching: Vec<PathBuf> = self
+            .filter_by_load(servers)
+            .into_iter()
+            .map(|x| x.config_file)
+            .collect();
+        debug!(
+            "{} of {total} configs match the server filters",
+            matching.len()
+        );
+        matching
+    }
+}
+
+/// Randomly choose one of the configs, other than the previous one if there is a choice
+pub fn choose_new_config(paths: &[PathBuf], previous: Option<&Path>) -> anyhow::Result<PathBuf> {
+    let candidates: Vec<&PathBuf> = paths
+        .iter()
+        .filter(|x| paths.len() == 1 || Some(x.as_path()) != previous)
+        .collect();
+    candidates
+        .choose(&mut rand::thread_rng())
+        .map(|x| (*x).clone())
+        .ok_or_else(|| anyhow!("No servers match the given server and filters"))
+}
+
+/// Pick a random server from the configs and remember it for next time
+pub fn choose_random_server(paths: &[PathBuf]) -> anyhow::Result<PathBuf> {
+    let last_path = vopono_dir()?.join(LAST_RANDOM_SERVER_FILE);
+    let previous = std::fs::read_to_string(&last_path).ok().map(PathBuf::from);
+    let config = choose_new_config(paths, previous.as_deref())?;
+    info!(
+        "Randomly chose server {} of {} matching",
+        config.display(),
+        paths.len()
+    );
+    std::fs::write(&last_path, config.to_string_lossy().as_bytes())?;
+    Ok(config)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn filter_and_choose_servers() {
+        let info = ServerInfo {
+            config_file: PathBuf::from("usa-usatl101.conf"),
+            country: Some("usa".to_string()),
+            city: Some("Atlanta, GA".to_string()),
+            features: vec![ServerFeature::Owned],
+            ..Default::default()
+        };
+        let mut filter = ServerFilter {
+            location: SyncFilter {
+                countries: vec!["usa".to_string()],
+                cities: vec!["atlanta".to_string()],
+            },
+            features: vec![ServerFeature::Owned],
+            ..Default::default()
+        };
+        assert!(filter.matches(&info));
+        filter.features.push(ServerFeature::PortForwarding);
+        assert!(!filter.matches(&info));
+
+        let loaded = |name: &str, load: Option<u8>| ServerInfo {
+            config_file: PathBuf::from(name),
+            load,
+            ..Default::default()
+        };
+        let servers = vec![
+            loaded("a.conf", Some(20)),
+            loaded("b.conf", Some(28)),
+            loaded("c.conf", Some(65)),
+            loaded("d.conf", None),
+        ];
+        let names = |filter: &ServerFilter, servers: Vec<ServerInfo>| -> Vec<PathBuf> {
+            filter
+                .filter_by_load(servers)
+                .into_iter()
+                .map(|x| x.config_file)
+                .collect()
+        };
+        let mut filter = ServerFilter {
+            max_load: Some(50),
+            ..Default::default()
+        };
+        assert_eq!(names(&filter, servers.clone()).len(), 2);
+        filter.max_load = Some(25);
+        filter.prefer_low_load = true;
+        assert_eq!(names(&filter, servers.clone()), [PathBuf::from("a.conf")]);
+        filter.max_load = None;
+        assert_eq!(names(&filter, servers).len(), 2);
+        assert_eq!(names(&filter, vec![loaded("d.conf", None)]).len(), 1);
+
+        let paths = [PathBuf::from("a.conf"), PathBuf::from("b.conf")];
+        for _ in 0..8 {
+            assert_eq!(
+                choose_new_config(&paths, Some(Path::new("a.conf"))).unwrap(),
+                paths[1]
+            );
+        }
+        assert_eq!(
+            choose_new_config(&paths[..1], Some(Path::new("a.conf"))).unwrap(),
+            paths[0]
+        );
+        assert!(choose_new_config(&[], None).is_err());
+    }
+}
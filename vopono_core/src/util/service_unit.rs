@@ -0,0 +1,166 @@
+// systemd units for running an application in a vopono namespace at boot
+// The service runs vopono exec with a profile from the config file, as root with the user's
+// config directory so the profile and synced configs are found. It is ordered after
+// network-online.target and stopped with SIGINT, which vopono handles by tearing the namespace
+// down. It gets no private mount namespace (PrivateTmp, ProtectSystem and the like), as the
+// namespace's bind mount in /run/netns would then not be visible on the host. With a socket port, a socket unit listens on the host and activates a proxy service which
+// runs systemd-socket-proxyd inside the namespace, forwarding connections to the application.
+
+use std::path::PathBuf;
+
+pub struct ServiceUnit {
+    /// Unit name without the .service suffix
+    pub name: String,
+    pub vopono: PathBuf,
+    pub profile: String,
+    pub application: String,
+    /// User the application runs as, whose config directory is used
+    pub user: String,
+    pub home: PathBuf,
+    pub netns_name: String,
+    pub socket_port: Option<u16>,
+    pub ip: PathBuf,
+    pub socket_proxyd: PathBuf,
+}
+
+/// Quote an argument for ExecStart, where % starts a specifier and $ a variable
+fn quote(arg: &str) -> String {
+    let escaped = arg
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('%', "%%")
+        .replace('$', "$$");
+    format!("\"{escaped}\"")
+}
+
+impl ServiceUnit {
+    pub fn proxy_name(&self) -> String {
+        format!("{}-proxy", self.name)
+    }
+
+    pub fn service(&self) -> String {
+        let exec = [
+            self.vopono.to_string_lossy().as_ref(),
+            "exec",
+            "--profile",
+            &self.profile,
+            "--custom-netns-name",
+            &self.netns_name,
+            "--user",
+            &self.user,
+            &self.application,
+        ]
+        .iter()
+        .map(|x| quote(x))
+        .collect::<Vec<_>>()
+        .join(" ");
+        format!(
+            "[Unit]
+Description=vopono {profile}: {application}
+Wants=network-online.target
+After=network-online.target nss-lookup.target
+
+[Service]
+Type=simple
+Environment=HOME={home}
+ExecStartPre=+/usr/bin/mkdir -p /etc/netns
+ExecStart={exec}
+KillSignal=SIGINT
+TimeoutStopSec=30
+Restart=on-failure
+RestartSec=10
+# No mount sandboxing, the namespace is mounted in /run/netns for the host
+ProtectClock=yes
+ProtectHostname=yes
+LockPersonality=yes
+RestrictRealtime=yes
+RestrictNamespaces=net mnt
+RestrictAddressFamilies=AF_UNIX AF_INET AF_INET6 AF_NETLINK
+SystemCallArchitectures=native
+
+[Install]
+WantedBy=multi-user.target
+",
+            profile = self.profile,
+            application = self.application.replace('%', "%%"),
+            home = self.home.display(),
+        )
+    }
+
+    pub fn socket(&self) -> Option<String> {
+        let port = self.socket_port?;
+        Some(format!(
+            "[Unit]
+Description=Socket for vopono {profile}: {application}
+
+[Socket]
+ListenStream={port}
+
+[Install]
+WantedBy=sockets.target
+",
+            profile = self.profile,
+            application = self.application.replace('%', "%%"),
+        ))
+    }
+
+    pub fn proxy_service(&self) -> Option<String> {
+        let port = self.socket_port?;
+        let netns = &self.netns_name;
+        Some(format!(
+            "[Unit]
+Description=Proxy into vopono {profile}: {application}
+Requires={name}.service
+After={name}.service
+
+[Service]
+Type=notify
+ExecStartPre=/bin/sh -c 'for i in $$(seq 60); do [ -e /run/netns/{netns} ] && exit 0; sleep 1; done; exit 1'
+ExecStart={ip} netns exec {netns} {proxyd} 127.0.0.1:{port}
+PrivateTmp=yes
+ProtectSystem=strict
+ProtectHome=yes
+",
+            profile = self.profile,
+            application = self.application.replace('%', "%%"),
+            name = self.name,
+            ip = self.ip.display(),
+            proxyd = self.socket_proxyd.display(),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn render_service_units() {
+        let mut unit = ServiceUnit {
+            name: "vopono-torrent".to_string(),
+            vopono: PathBuf::from("/usr/bin/vopono"),
+            profile: "torrent".to_string(),
+            application: "transmission-daemon -f --config-dir \"$HOME/tr\"".to_string(),
+            user: "alice".to_string(),
+            home: PathBuf::from("/home/alice"),
+            netns_name: "vo_sv_torrent".to_string(),
+            socket_port: None,
+            ip: PathBuf::from("/usr/bin/ip"),
+            socket_proxyd: PathBuf::from("/usr/lib/systemd/systemd-socket-proxyd"),
+        };
+        let service = unit.service();
+        assert!(service.contains(
+            "ExecStart=\"/usr/bin/vopono\" \"exec\" \"--profile\" \"torrent\" \"--custom-netns-name\" \"vo_sv_torrent\" \"--user\" \"alice\" \"transmission-daemon -f --config-dir \\\"$$HOME/tr\\\"\"\n"
+        ));
+        assert!(service.contains("After=network-online.target"));
+        // The namespace must be mounted in the host's mount namespace
+        assert!(!service.contains("PrivateTmp"));
+        assert!(!service.contains("ProtectSystem"));
+        assert!(unit.socket().is_none());
+        unit.socket_port = Some(9091);
+        assert!(unit.socket().unwrap().contains("ListenStream=9091\n"));
+        assert!(unit.proxy_service().unwrap().contains(
+            "ExecStart=/usr/bin/ip netns exec vo_sv_torrent /usr/lib/systemd/systemd-socket-proxyd 127.0.0.1:9091\n"
+        ));
+    }
+}
@@ -0,0 +1,139 @@
+// Graceful shutdown of a running namespace from another terminal
+// Each vopono instance using the namespace is stopped the way it is from its own terminal: its
+// application is sent SIGTERM, after which the instance tears down as usual (port forwarders,
+// firewall rules, veth pair, namespace). Instances kept alive for a daemon or by --keep-alive
+// are sent SIGINT, as with Ctrl+C. Instances still running after the timeout are killed with
+// --force, and the state they leave behind in this namespace is removed as by vopono cleanup.
+// PIDs from the lockfiles may have been reused since, so an instance is only killed if it is
+// still vopono and an application only if it is still in the namespace.
+
+use super::cleanup::{find_orphans, lockfile_pid, pid_running, remove_orphan};
+use super::{config_dir, get_pids_in_namespace};
+use crate::network::netns::Lockfile;
+use anyhow::anyhow;
+use log::{debug, info, warn};
+use nix::sys::signal::{Signal, kill};
+use nix::unistd::Pid;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+/// Interval between SIGINTs to instances without a running application
+const INTERRUPT_INTERVAL: Duration = Duration::from_secs(1);
+
+fn signal(pid: u32, signal: Signal) {
+    debug!("Sending {signal} to {pid}");
+    kill(Pid::from_raw(pid as i32), signal).ok();
+}
+
+/// Whether the process name (/proc/<pid>/comm) is that of vopono
+fn is_vopono_comm(comm: &str) -> bool {
+    comm.trim_end() == "vopono"
+}
+
+fn is_vopono_instance(pid: u32) -> bool {
+    std::fs::read_to_string(format!("/proc/{pid}/comm")).is_ok_and(|comm| is_vopono_comm(&comm))
+}
+
+/// Running application of the instance, none while it is still setting up or only kept alive,
+/// or if its PID is now used by a process outside the namespace
+fn application_pid(name: &str, lockfile: &Path) -> Option<u32> {
+    let pid = Lockfile::read_application_pid(lockfile).filter(|x| pid_running(*x))?;
+    if get_pids_in_namespace(name).is_ok_and(|pids| pids.contains(&(pid as i32))) {
+        Some(pid)
+    } else {
+        warn!("PID {pid} is no longer the application in namespace {name}, not signalling it");
+        None
+    }
+}
+
+/// Lockfiles of the running instances using the namespace, ignoring reused PIDs
+fn running_instances(name: &str) -> anyhow::Result<Vec<(u32, PathBuf)>> {
+    let dir = config_dir()?.join("vopono/locks").join(name);
+    if !dir.exists() {
+        return Ok(Vec::new());
+    }
+    Ok(dir
+        .read_dir()?
+        .flatten()
+        .filter_map(|x| lockfile_pid(&x.path()).map(|pid| (pid, x.path())))
+        .filter(|(pid, _)| pid_running(*pid) && is_vopono_instance(*pid))
+        .collect())
+}
+
+/// Stop every vopono instance using the namespace and wait for them to tear it down
+pub fn stop_namespace(name: &str, timeout: Duration, force: bool) -> anyhow::Result<()> {
+    let mut instances = running_instances(name)?;
+    if instances.is_empty() {
+        return Err(anyhow!(
+            "No running vopono namespace named {name}, see vopono list namespaces"
+        ));
+    }
+    info!(
+        "Stopping {} vopono instances using namespace {name}",
+        instances.len()
+    );
+    let deadline = Instant::now() + timeout;
+    let mut terminated = Vec::new();
+    let mut last_interrupt: Option<Instant> = None;
+    while !instances.is_empty() {
+        if Instant::now() >= deadline {
+            if !force {
+                return Err(anyhow!(
+                    "{} vopono instances using namespace {name} are still running after {}s, use --force to kill them",
+                    instances.len(),
+                    timeout.as_secs()
+                ));
+            }
+            for (pid, lockfile) in instances.iter() {
+                if let Some(app) = application_pid(name, lockfile) {
+                    signal(app, Signal::SIGKILL);
+                }
+                if is_vopono_instance(*pid) {
+                    signal(*pid, Signal::SIGKILL);
+                }
+            }
+            std::thread::sleep(Duration::from_millis(250));
+            let pids: Vec<u32> = instances.iter().map(|(pid, _)| *pid).collect();
+            for orphan in find_orphans()?.iter().filter(|x| x.belongs_to(name, &pids)) {
+                info!("Cleaning up {orphan}");
+                if let Err(e) = remove_orphan(orphan) {
+                    warn!("Failed to clean up {orphan}: {e:?}");
+                }
+            }
+            return Ok(());
+        }
+        let interrupt = last_interrupt.is_none_or(|x| x.elapsed() >= INTERRUPT_INTERVAL);
+        for (pid, lockfile) in instances.iter() {
+            match application_pid(name, lockfile) {
+                Some(app) if !terminated.contains(&app) => {
+                    signal(app, Signal::SIGTERM);
+                    terminated.push(app);
+                }
+                Some(_) => {}
+                None if interrupt => signal(*pid, Signal::SIGINT),
+                None => {}
+            }
+        }
+        if interrupt {
+            last_interrupt = Some(Instant::now());
+        }
+        std::thread::sleep(Duration::from_millis(250));
+        instances.retain(|(pid, _)| pid_running(*pid) && is_vopono_instance(*pid));
+    }
+    info!("Namespace {name} stopped");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn vopono_process_name() {
+        assert!(is_vopono_comm("vopono\n"));
+        assert!(!is_vopono_comm("vopono_core-1f2e"));
+        assert!(!is_vopono_comm("firefox\n"));
+        // The test binary is vopono_core-{hash}
+        assert!(!is_vopono_instance(std::process::id()));
+    }
+}
@@ -0,0 +1,185 @@
+// Incremental sync
+// Downloaded config archives are cached in {provider_dir}/.sync_cache/ together with an index of
+// their ETag / Last-Modified headers and SHA-256 hash. Subsequent syncs send conditional
+// requests, so unchanged archives are read from disk (304 Not Modified) instead of re-downloaded.
+
+use anyhow::Context;
+use log::{debug, info, warn};
+use reqwest::StatusCode;
+use reqwest::blocking::RequestBuilder;
+use reqwest::header::{ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fs::create_dir_all;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct CacheEntry {
+    etag: Option<String>,
+    last_modified: Option<String>,
+    sha256: String,
+}
+
+pub struct SyncCache {
+    dir: PathBuf,
+    index: Mutex<HashMap<String, CacheEntry>>,
+}
+
+impl SyncCache {
+    const INDEX_FILE: &'static str = "index.json";
+
+    pub fn open(provider_dir: &Path) -> anyhow::Result<Self> {
+        let dir = provider_dir.join(".sync_cache");
+        create_dir_all(&dir)?;
+        let index = std::fs::read_to_string(dir.join(Self::INDEX_FILE))
+            .ok()
+            .and_then(|x| serde_json::from_str(&x).ok())
+            .unwrap_or_default();
+        Ok(Self {
+            dir,
+            index: Mutex::new(index),
+        })
+    }
+
+    /// Send the request for the given URL, returning the cached body if the server reports
+    /// it is unchanged since the last sync
+    pub fn fetch(&self, url: &str, request: RequestBuilder) -> anyhow::Result<Vec<u8>> {
+        let body_path = self.body_path(url);
+        let cached = self.cached_entry(url);
+
+        let mut request = request;
+        if let Some(entry) = &cached {
+            if let Some(etag) = &entry.etag {
+                request = request.header(IF_NONE_MATCH, etag);
+            }
+            if let Some(last_modified) = &entry.last_modified {
+                request = request.header(IF_MODIFIED_SINCE, last_modified);
+            }
+        }
+
+        let response = request
+            .send()
+            .with_context(|| format!("Failed to download {url}"))?;
+        if response.status() == StatusCode::NOT_MODIFIED && cached.is_some() {
+            info!("{url} unchanged since last sync, using cached copy");
+            return std::fs::read(&body_path)
+                .with_context(|| format!("Reading cached file: {}", body_path.display()));
+        }
+        let response = response.error_for_status()?;
+        let header = |name| {
+            response
+                .headers()
+                .get(name)
+                .and_then(|x| x.to_str().ok())
+                .map(|x| x.to_string())
+        };
+        let etag = header(ETAG);
+        let last_modified = header(LAST_MODIFIED);
+        let body = response.bytes()?.to_vec();
+
+        let sha256 = hex_digest(&body);
+        if cached.as_ref().map(|x| &x.sha256) == Some(&sha256) {
+            info!("{url} unchanged since last sync");
+        }
+        if etag.is_none() && last_modified.is_none() {
+            debug!("{url} has no ETag or Last-Modified header, it will be re-downloaded next sync");
+        }
+        std::fs::write(&body_path, &body)
+            .with_context(|| format!("Writing cached file: {}", body_path.display()))?;
+        self.lock_index().insert(
+            url.to_string(),
+            CacheEntry {
+                etag,
+                last_modified,
+                sha256,
+            },
+        );
+        self.save();
+        Ok(body)
+    }
+
+    /// Index entry for the URL, only if the cached body still matches its hash
+    fn cached_entry(&self, url: &str) -> Option<CacheEntry> {
+        self.lock_index()
+            .get(url)
+            .cloned()
+            .filter(|entry| hash_file(&self.body_path(url)).as_ref() == Some(&entry.sha256))
+    }
+
+    fn lock_index(&self) -> std::sync::MutexGuard<'_, HashMap<String, CacheEntry>> {
+        self.index.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn body_path(&self, url: &str) -> PathBuf {
+        self.dir.join(format!("{}.bin", hex_digest(url.as_bytes())))
+    }
+
+    fn save(&self) {
+        let index = self.lock_index();
+        let result = serde_json::to_string_pretty(&*index)
+            .map_err(anyhow::Error::from)
+            .and_then(|x| Ok(std::fs::write(self.dir.join(Self::INDEX_FILE), x)?));
+        if let Err(e) = result {
+            warn!("Failed to save sync cache index: {e:?}");
+        }
+    }
+}
+
+fn hex_digest(data: &[u8]) -> String {
+    Sha256::digest(data)
+        .iter()
+        .map(|x| format!("{x:02x}"))
+        .collect()
+}
+
+fn hash_file(path: &Path) -> Option<String> {
+    std::fs::read(path).ok().map(|x| hex_digest(&x))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hex_digest_is_sha256() {
+        assert_eq!(
+            hex_digest(b"abc"),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+    }
+
+    #[test]
+    fn cached_entry_persists_and_requires_matching_body() {
+        let dir = std::env::temp_dir().join(format!("vopono_sync_cache_{}", std::process::id()));
+        std::fs::remove_dir_all(&dir).ok();
+        let url = "https://example.com/configs.zip";
+
+        let cache = SyncCache::open(&dir).unwrap();
+        std::fs::write(cache.body_path(url), b"configs").unwrap();
+        cache.lock_index().insert(
+            url.to_string(),
+            CacheEntry {
+                etag: Some("\"v1\"".to_string()),
+                last_modified: None,
+                sha256: hex_digest(b"configs"),
+            },
+        );
+        cache.save();
+
+        let cache = SyncCache::open(&dir).unwrap();
+        let entry = cache.cached_entry(url).unwrap();
+        assert_eq!(entry.etag.as_deref(), Some("\"v1\""));
+        assert!(
+            cache
+                .cached_entry("https://example.com/other.zip")
+                .is_none()
+        );
+
+        // A modified cached body is never sent as a conditional request
+        std::fs::write(cache.body_path(url), b"tampered").unwrap();
+        assert!(cache.cached_entry(url).is_none());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
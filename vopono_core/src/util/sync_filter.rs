@@ -0,0 +1,138 @@
+// Sync-time server filters
+// Providers always generate every config they offer, so filters are applied afterwards by
+// removing the config files which do not match. Filenames follow the {country}-{...} form used
+// for server aliases, where the country may be a name (united_states) or a code (us).
+// The filter is saved in the config directory so automatic re-syncs apply it again.
+
+use super::country_map::{code_to_country_map, country_to_code_map};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SyncFilter {
+    pub countries: Vec<String>,
+    pub cities: Vec<String>,
+}
+
+impl SyncFilter {
+    const SAVE_FILE: &'static str = ".sync_filter.json";
+
+    /// Filter used for the last sync of the config directory (empty if none was saved)
+    pub fn load(dir: &Path) -> Self {
+        std::fs::read_to_string(dir.join(Self::SAVE_FILE))
+            .ok()
+            .and_then(|x| serde_json::from_str(&x).ok())
+            .unwrap_or_default()
+    }
+
+    pub fn save(&self, dir: &Path) {
+        let result = serde_json::to_string(self)
+            .map_err(anyhow::Error::from)
+            .and_then(|x| Ok(std::fs::write(dir.join(Self::SAVE_FILE), x)?));
+        if let Err(e) = result {
+            warn!("Failed to save sync filter in {}: {e:?}", dir.display());
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.countries.is_empty() && self.cities.is_empty()
+    }
+
+    /// Whether the config filename (without extension) matches the filter
+    pub fn matches(&self, name: &str) -> bool {
+        let name = name.to_lowercase();
+        let tokens: Vec<&str> = name.split('-').collect();
+
+        let country_ok = self.countries.is_empty()
+            || self.countries.iter().any(|country| {
+                let country = country.to_lowercase().replace(' ', "_");
+                let code_to_country = code_to_country_map();
+                let country_to_code = country_to_code_map();
+                let aliases = [
+                    Some(country.as_str()),
+                    code_to_country.get(country.as_str()).copied(),
+                    country_to_code.get(country.as_str()).copied(),
+                ];
+                // The country is always within the first two tokens (e.g. united_states-us-...)
+                tokens
+                    .iter()
+                    .take(2)
+                    .any(|t| aliases.iter().flatten().any(|a| a == t))
+            });
+
+        let city_ok = self.cities.is_empty()
+            || self.cities.iter().any(|city| {
+                let city = city.to_lowercase().replace(' ', "");
+                tokens.iter().skip(1).any(|t| t.contains(&city))
+            });
+
+        country_ok && city_ok
+    }
+
+    /// Remove the config files in dir which do not match the filter, returning the number kept
+    pub fn apply(&self, dir: &Path) -> anyhow::Result<usize> {
+        let mut kept = 0;
+        let mut removed = 0;
+        for entry in dir.read_dir()?.flatten() {
+            let path = entry.path();
+            let is_config = path.extension().is_some_and(|x| x == "conf" || x == "ovpn");
+            if !is_config {
+                continue;
+            }
+            let stem = path
+                .file_stem()
+                .and_then(|x| x.to_str())
+                .unwrap_or_default();
+            if self.matches(stem) {
+                kept += 1;
+            } else {
+                std::fs::remove_file(&path)?;
+                removed += 1;
+            }
+        }
+        info!(
+            "Kept {kept} configs matching sync filters in {} (removed {removed})",
+            dir.display()
+        );
+        Ok(kept)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn filter_matches_country_names_codes_and_cities() {
+        let filter = SyncFilter {
+            countries: vec!["us".to_string(), "germany".to_string()],
+            cities: vec![],
+        };
+        assert!(filter.matches("united_states-us-ch_us_1"));
+        assert!(filter.matches("us-nyc-nyc1"));
+        assert!(filter.matches("de-fra1"));
+        assert!(!filter.matches("sweden-se-got"));
+
+        let filter = SyncFilter {
+            countries: vec!["us".to_string()],
+            cities: vec!["losangeles".to_string()],
+        };
+        assert!(filter.matches("usa-us-losangeles-ca"));
+        assert!(!filter.matches("usa-us-newyork-ny"));
+    }
+
+    #[test]
+    fn filter_save_and_load() {
+        let dir = std::env::temp_dir().join(format!("vopono_sync_filter_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        assert_eq!(SyncFilter::load(&dir), SyncFilter::default());
+        let filter = SyncFilter {
+            countries: vec!["se".to_string()],
+            cities: vec!["gothenburg".to_string()],
+        };
+        filter.save(&dir);
+        assert_eq!(SyncFilter::load(&dir), filter);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
